@@ -68,6 +68,13 @@
         # all of that work (e.g. via cachix) when running in CI
         cargoArtifacts = craneLib.buildDepsOnly commonArgs;
 
+        # Without the nusb backend, like environments that have neither
+        # USB devices nor libusb/udev.
+        noBackendArgs = commonArgs // {
+          cargoExtraArgs = "--locked --no-default-features";
+        };
+        cargoArtifactsNoBackend = craneLib.buildDepsOnly noBackendArgs;
+
         # Build the actual crate itself, reusing the dependency
         # artifacts from above.
         usbvfiod = craneLib.buildPackage (commonArgs // {
@@ -139,6 +146,19 @@
             partitionType = "count";
             cargoNextestPartitionsExtraArgs = "--no-tests=pass";
           });
+
+          # Lint and run the controller tests against the mock device only.
+          usbvfiod-clippy-no-backend = craneLib.cargoClippy (noBackendArgs // {
+            cargoArtifacts = cargoArtifactsNoBackend;
+            cargoClippyExtraArgs = "--all-targets -- --deny warnings";
+          });
+
+          usbvfiod-nextest-no-backend = craneLib.cargoNextest (noBackendArgs // {
+            cargoArtifacts = cargoArtifactsNoBackend;
+            partitions = 1;
+            partitionType = "count";
+            cargoNextestPartitionsExtraArgs = "--no-tests=pass";
+          });
         } // (import ./nix/tests.nix {
           inherit lib pkgs;
           usbvfiod = self.packages.default;
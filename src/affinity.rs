@@ -0,0 +1,475 @@
+//! Pin threads to a set of host CPUs and adjust their priority.
+//!
+//! Latency-sensitive endpoints (isochronous and interrupt) suffer when
+//! their worker threads migrate between CPUs, share a CPU with the guest's
+//! vCPUs, or are scheduled behind bulk transfers. The CPU set is given on
+//! the command line in the format of the kernel's CPU lists, e.g., `2,4-7`,
+//! the priority as a nice value. Both may be given per type of endpoint,
+//! see `WorkerPolicy`.
+
+use std::{
+    fmt, io,
+    mem::MaybeUninit,
+    str::FromStr,
+    thread::{self, JoinHandle},
+};
+
+use clap::ValueEnum;
+use tracing::{debug, warn};
+
+/// A non-empty set of host CPUs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet {
+    /// The CPU numbers, sorted and without duplicates.
+    cpus: Vec<usize>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ParseCpuSetError {
+    #[error("Empty CPU list")]
+    Empty,
+    #[error("Invalid CPU number: {0:?}")]
+    InvalidCpu(String),
+    #[error("CPU {cpu} exceeds the maximum CPU number {max}")]
+    CpuTooLarge { cpu: usize, max: usize },
+    #[error("Invalid CPU range: {first}-{last}")]
+    InvalidRange { first: usize, last: usize },
+}
+
+/// The number of CPUs a `cpu_set_t` can describe.
+const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+
+fn parse_cpu(cpu: &str) -> Result<usize, ParseCpuSetError> {
+    let cpu = cpu
+        .trim()
+        .parse()
+        .map_err(|_| ParseCpuSetError::InvalidCpu(cpu.to_string()))?;
+    if cpu >= MAX_CPUS {
+        return Err(ParseCpuSetError::CpuTooLarge {
+            cpu,
+            max: MAX_CPUS - 1,
+        });
+    }
+    Ok(cpu)
+}
+
+impl FromStr for CpuSet {
+    type Err = ParseCpuSetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = vec![];
+        for item in s.split(',').filter(|item| !item.trim().is_empty()) {
+            match item.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse_cpu(first)?, parse_cpu(last)?);
+                    if first > last {
+                        return Err(ParseCpuSetError::InvalidRange { first, last });
+                    }
+                    cpus.extend(first..=last);
+                }
+                None => cpus.push(parse_cpu(item)?),
+            }
+        }
+
+        if cpus.is_empty() {
+            return Err(ParseCpuSetError::Empty);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self { cpus })
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus: Vec<_> = self.cpus.iter().map(ToString::to_string).collect();
+        write!(f, "{}", cpus.join(","))
+    }
+}
+
+impl CpuSet {
+    /// Restrict the calling thread to the CPUs in the set.
+    pub fn pin_current_thread(&self) -> io::Result<()> {
+        let mut set = MaybeUninit::<libc::cpu_set_t>::zeroed();
+        // SAFETY: An all-zero cpu_set_t is the empty set, and all CPU
+        // numbers were checked against CPU_SETSIZE when parsing.
+        let set = unsafe {
+            for &cpu in &self.cpus {
+                libc::CPU_SET(cpu, &mut *set.as_mut_ptr());
+            }
+            set.assume_init()
+        };
+
+        // SAFETY: The set is initialized and its size is passed along. A
+        // pid of 0 refers to the calling thread.
+        match unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// A nice value, from -20 (most favorable scheduling) to 19.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nice(i32);
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ParseNiceError {
+    #[error("Invalid nice value: {0:?}")]
+    Invalid(String),
+    #[error("Nice value {0} is outside of -20 to 19")]
+    OutOfRange(i32),
+}
+
+impl FromStr for Nice {
+    type Err = ParseNiceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nice = s
+            .trim()
+            .parse()
+            .map_err(|_| ParseNiceError::Invalid(s.to_string()))?;
+        match nice {
+            -20..=19 => Ok(Self(nice)),
+            _ => Err(ParseNiceError::OutOfRange(nice)),
+        }
+    }
+}
+
+impl fmt::Display for Nice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The types of endpoints whose workers can be scheduled differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WorkerClass {
+    Bulk,
+    Interrupt,
+    Isoch,
+}
+
+/// A scheduling setting for the workers of one type of endpoint, or of
+/// all endpoints, e.g., `interrupt=-5` or `-5`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerSetting<T> {
+    /// The type of endpoint the setting is limited to, if any.
+    pub class: Option<WorkerClass>,
+    pub value: T,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ParseWorkerSettingError<E> {
+    #[error("Unknown endpoint type: {0:?}")]
+    UnknownClass(String),
+    #[error(transparent)]
+    Value(E),
+}
+
+impl<T: FromStr> FromStr for WorkerSetting<T> {
+    type Err = ParseWorkerSettingError<T::Err>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, value) = match s.split_once('=') {
+            Some((class, value)) => {
+                let class = WorkerClass::from_str(class.trim(), true)
+                    .map_err(|_| ParseWorkerSettingError::UnknownClass(class.to_string()))?;
+                (Some(class), value)
+            }
+            None => (None, s),
+        };
+        Ok(Self {
+            class,
+            value: value.parse().map_err(ParseWorkerSettingError::Value)?,
+        })
+    }
+}
+
+/// Where and how urgently a thread runs.
+///
+/// Without a setting, the thread inherits it from the thread that spawned
+/// it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ThreadPolicy {
+    pub cpus: Option<CpuSet>,
+    pub nice: Option<Nice>,
+}
+
+/// The scheduling calls a [`ThreadPolicy`] makes for the calling thread.
+///
+/// Tests replace the host's scheduler, as changing the scheduling of a
+/// thread may need privileges.
+pub trait Scheduler {
+    fn set_affinity(&self, cpus: &CpuSet) -> io::Result<()>;
+    fn set_nice(&self, nice: Nice) -> io::Result<()>;
+}
+
+/// The scheduler of the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostScheduler;
+
+impl Scheduler for HostScheduler {
+    fn set_affinity(&self, cpus: &CpuSet) -> io::Result<()> {
+        cpus.pin_current_thread()
+    }
+
+    fn set_nice(&self, nice: Nice) -> io::Result<()> {
+        // SAFETY: gettid has no preconditions. On Linux, the priority of a
+        // process ID that is a thread ID only applies to that thread.
+        let result =
+            unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice.0) };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl ThreadPolicy {
+    /// Apply the policy to the calling thread, which has the given name.
+    ///
+    /// Failures are not fatal, the thread then keeps its scheduling. Raising
+    /// the priority, for example, requires `CAP_SYS_NICE`.
+    pub fn apply(&self, scheduler: &impl Scheduler, name: &str) {
+        if let Some(cpus) = &self.cpus {
+            match scheduler.set_affinity(cpus) {
+                Ok(()) => debug!("pinned thread {name} to CPUs {cpus}"),
+                Err(error) => warn!("failed to pin thread {name} to CPUs {cpus}: {error}"),
+            }
+        }
+        if let Some(nice) = self.nice {
+            match scheduler.set_nice(nice) {
+                Ok(()) => debug!("set nice value of thread {name} to {nice}"),
+                Err(error) => warn!("failed to set nice value of thread {name} to {nice}: {error}"),
+            }
+        }
+    }
+}
+
+/// The scheduling of endpoint worker threads.
+///
+/// A setting for a type of endpoint takes precedence over one for all
+/// endpoints, and among those, the last one given counts.
+#[cfg(any(feature = "nusb-backend", test))]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WorkerPolicy {
+    affinities: Vec<WorkerSetting<CpuSet>>,
+    priorities: Vec<WorkerSetting<Nice>>,
+}
+
+#[cfg(any(feature = "nusb-backend", test))]
+impl WorkerPolicy {
+    pub const fn new(
+        affinities: Vec<WorkerSetting<CpuSet>>,
+        priorities: Vec<WorkerSetting<Nice>>,
+    ) -> Self {
+        Self {
+            affinities,
+            priorities,
+        }
+    }
+
+    /// The policy of the workers of a type of endpoint. Without a type,
+    /// only the settings for all endpoints apply, e.g., to a thread that
+    /// serves all of them.
+    pub fn for_class(&self, class: Option<WorkerClass>) -> ThreadPolicy {
+        ThreadPolicy {
+            cpus: select(&self.affinities, class),
+            nice: select(&self.priorities, class),
+        }
+    }
+}
+
+/// The value of the setting that applies to `class`.
+#[cfg(any(feature = "nusb-backend", test))]
+fn select<T: Clone>(settings: &[WorkerSetting<T>], class: Option<WorkerClass>) -> Option<T> {
+    let last_for = |class| {
+        settings
+            .iter()
+            .rev()
+            .find(|setting| setting.class == class)
+            .map(|setting| setting.value.clone())
+    };
+    class
+        .and_then(|class| last_for(Some(class)))
+        .or_else(|| last_for(None))
+}
+
+/// Spawn a named thread that runs with the given policy.
+///
+/// The thread applies the policy to itself before it runs `f`, see
+/// [`ThreadPolicy::apply`]. The thread logs within the caller's span.
+pub fn spawn_thread<F, T>(name: String, policy: ThreadPolicy, f: F) -> io::Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    thread::Builder::new().name(name).spawn(move || {
+        let _span = span.entered();
+        let name = thread::current().name().unwrap_or_default().to_string();
+        policy.apply(&HostScheduler, &name);
+        f()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpus(s: &str) -> Result<Vec<usize>, ParseCpuSetError> {
+        s.parse::<CpuSet>().map(|set| set.cpus)
+    }
+
+    #[test]
+    fn parse_cpu_lists() {
+        assert_eq!(cpus("3"), Ok(vec![3]));
+        assert_eq!(cpus("2,4-7"), Ok(vec![2, 4, 5, 6, 7]));
+        // Duplicates and order do not matter.
+        assert_eq!(cpus("5,1-3, 2,"), Ok(vec![1, 2, 3, 5]));
+        assert_eq!(cpus("1023"), Ok(vec![1023]));
+
+        assert_eq!("0-2,8".parse::<CpuSet>().unwrap().to_string(), "0,1,2,8");
+    }
+
+    #[test]
+    fn reject_invalid_cpu_lists() {
+        assert_eq!(cpus(""), Err(ParseCpuSetError::Empty));
+        assert_eq!(cpus(","), Err(ParseCpuSetError::Empty));
+        assert_eq!(
+            cpus("1,x"),
+            Err(ParseCpuSetError::InvalidCpu("x".to_string()))
+        );
+        assert_eq!(cpus("-1"), Err(ParseCpuSetError::InvalidCpu(String::new())));
+        assert_eq!(
+            cpus("3-1"),
+            Err(ParseCpuSetError::InvalidRange { first: 3, last: 1 })
+        );
+        assert_eq!(
+            cpus("1024"),
+            Err(ParseCpuSetError::CpuTooLarge {
+                cpu: 1024,
+                max: 1023
+            })
+        );
+    }
+
+    #[test]
+    fn parse_worker_settings() {
+        assert_eq!(
+            "interrupt=-5".parse::<WorkerSetting<Nice>>(),
+            Ok(WorkerSetting {
+                class: Some(WorkerClass::Interrupt),
+                value: Nice(-5)
+            })
+        );
+        assert_eq!(
+            "2,4-5".parse::<WorkerSetting<CpuSet>>().map(|s| s.class),
+            Ok(None)
+        );
+        assert_eq!(
+            "control=1".parse::<WorkerSetting<Nice>>(),
+            Err(ParseWorkerSettingError::UnknownClass("control".to_string()))
+        );
+        assert_eq!(
+            "bulk=20".parse::<WorkerSetting<Nice>>(),
+            Err(ParseWorkerSettingError::Value(ParseNiceError::OutOfRange(
+                20
+            )))
+        );
+        assert_eq!(
+            "x".parse::<Nice>(),
+            Err(ParseNiceError::Invalid("x".to_string()))
+        );
+    }
+
+    fn setting<T: FromStr>(s: &str) -> WorkerSetting<T>
+    where
+        T::Err: fmt::Debug,
+    {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn endpoint_type_settings_take_precedence() {
+        let policy = WorkerPolicy::new(
+            vec![setting("isoch=3"), setting("0-1"), setting("2")],
+            vec![setting("interrupt=-10"), setting("5")],
+        );
+
+        let bulk = policy.for_class(Some(WorkerClass::Bulk));
+        assert_eq!(bulk.cpus, Some("2".parse().unwrap()));
+        assert_eq!(bulk.nice, Some(Nice(5)));
+
+        let interrupt = policy.for_class(Some(WorkerClass::Interrupt));
+        assert_eq!(interrupt.cpus, Some("2".parse().unwrap()));
+        assert_eq!(interrupt.nice, Some(Nice(-10)));
+
+        let isoch = policy.for_class(Some(WorkerClass::Isoch));
+        assert_eq!(isoch.cpus, Some("3".parse().unwrap()));
+
+        assert_eq!(policy.for_class(None), bulk);
+        assert_eq!(
+            WorkerPolicy::default().for_class(Some(WorkerClass::Bulk)),
+            ThreadPolicy::default()
+        );
+    }
+
+    /// A scheduler that records its calls and fails as told.
+    #[derive(Default)]
+    struct MockScheduler {
+        calls: std::sync::Mutex<Vec<String>>,
+        deny: bool,
+    }
+
+    impl Scheduler for MockScheduler {
+        fn set_affinity(&self, cpus: &CpuSet) -> io::Result<()> {
+            self.calls.lock().unwrap().push(format!("affinity {cpus}"));
+            match self.deny {
+                true => Err(io::ErrorKind::PermissionDenied.into()),
+                false => Ok(()),
+            }
+        }
+
+        fn set_nice(&self, nice: Nice) -> io::Result<()> {
+            self.calls.lock().unwrap().push(format!("nice {nice}"));
+            match self.deny {
+                true => Err(io::ErrorKind::PermissionDenied.into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn policies_apply_despite_failures() {
+        let policy = ThreadPolicy {
+            cpus: Some("1-2".parse().unwrap()),
+            nice: Some(Nice(-5)),
+        };
+        for deny in [false, true] {
+            let scheduler = MockScheduler {
+                deny,
+                ..MockScheduler::default()
+            };
+            policy.apply(&scheduler, "worker");
+            assert_eq!(
+                *scheduler.calls.lock().unwrap(),
+                ["affinity 1,2", "nice -5"]
+            );
+        }
+
+        let scheduler = MockScheduler::default();
+        ThreadPolicy::default().apply(&scheduler, "worker");
+        assert!(scheduler.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn spawn_thread_without_affinity() {
+        let handle = spawn_thread("unpinned".to_string(), ThreadPolicy::default(), || {
+            thread::current().name().map(ToString::to_string)
+        })
+        .unwrap();
+        assert_eq!(handle.join().unwrap().as_deref(), Some("unpinned"));
+    }
+}
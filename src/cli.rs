@@ -4,12 +4,28 @@
 //! to the vfio-user [Backend Program
 //! Conventions](https://github.com/nutanix/libvfio-user/blob/master/docs/vfio-user.rst#backend-program-conventions).
 use std::{
+    num::{NonZeroUsize, ParseIntError},
     os::fd::RawFd,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use clap::Parser;
 
+#[cfg(any(feature = "nusb-backend", test))]
+use crate::{affinity::WorkerPolicy, device::pci::realdevice::InterfaceClaim};
+use crate::{
+    affinity::{CpuSet, Nice, WorkerSetting},
+    device::pci::{
+        commands::{CommandPolicy, PartialCommand},
+        config_space::{PciIdentity, PciIdentityError},
+        erdp_watch::DEFAULT_STUCK_ERDP_TIMEOUT,
+        event_sink::DEFAULT_MAX_DEFERRED_EVENTS,
+        virtual_device::VirtualDeviceKind,
+        xhci::DEFAULT_PCI_IDENTITY,
+    },
+};
+
 #[derive(Parser, Debug)]
 #[command(
     name = env!("CARGO_PKG_NAME"),
@@ -44,6 +60,225 @@ pub struct Cli {
     /// See the documentation for how to identify devices.
     #[arg(long = "device", value_name = "PATH")]
     pub devices: Vec<PathBuf>,
+
+    /// Attach a device that is emulated in software instead of a real
+    /// one, e.g., for demos and CI. Can be specified multiple times.
+    ///
+    /// Virtual devices take ports like real devices, after the devices
+    /// given with --device.
+    #[arg(long = "virtual-device", value_name = "KIND")]
+    pub virtual_devices: Vec<VirtualDeviceKind>,
+
+    /// The maximum number of bulk transfers that may be outstanding at
+    /// the same time on each physical host bus.
+    ///
+    /// Interrupt and control transfers are not limited. Without this
+    /// option, bulk transfers are not limited either.
+    #[arg(long, value_name = "N")]
+    pub max_outstanding_bulk: Option<NonZeroUsize>,
+
+    /// The number of transfers each Bulk IN endpoint keeps in flight.
+    ///
+    /// Deeper queues keep fast devices busy while usbvfiod reports the
+    /// previous transfer to the guest. Endpoints with streams always use
+    /// a single transfer.
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub bulk_in_queue_depth: NonZeroUsize,
+
+    /// Service all endpoints from a single thread using asynchronous
+    /// transfers.
+    ///
+    /// By default, every enabled endpoint gets a dedicated worker
+    /// thread.
+    #[arg(long)]
+    pub async_endpoints: bool,
+
+    /// Coalesce Transfer Events of an endpoint that complete within
+    /// this many microseconds and signal them with a single interrupt.
+    ///
+    /// Events are always sent once an endpoint runs out of work. Without
+    /// this option, every Transfer Event raises its own interrupt.
+    #[arg(long, value_name = "MICROSECONDS")]
+    pub event_coalescing_us: Option<u64>,
+
+    /// The maximum number of events that wait for the guest driver to
+    /// make space on a full Event Ring.
+    ///
+    /// Further events are dropped and the driver is notified with an
+    /// Event Ring Full Error.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_DEFERRED_EVENTS)]
+    pub max_deferred_events: NonZeroUsize,
+
+    /// How long the guest driver may leave a mostly occupied Event Ring
+    /// unprocessed before usbvfiod warns that the driver does not seem to
+    /// receive interrupts.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = DEFAULT_STUCK_ERDP_TIMEOUT.as_millis() as u64)]
+    pub stuck_erdp_timeout_ms: u64,
+
+    /// Count the guest's accesses to each controller register and the
+    /// time spent handling them.
+    ///
+    /// The summary is logged on exit and whenever usbvfiod receives
+    /// SIGUSR1.
+    #[arg(long)]
+    pub mmio_profile: bool,
+
+    /// Record the guest's register accesses and the guest memory the
+    /// controller accesses to this file, for replaying them in tests.
+    ///
+    /// Recordings are appended to the file. Recording slows down every
+    /// access, so only use it to capture a bug.
+    #[arg(long, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Check every access to guest memory against the largest access
+    /// its origin can make, and abort on violations.
+    ///
+    /// This catches bugs that would otherwise silently corrupt the
+    /// guest, at the cost of some overhead. Debug builds always check.
+    #[arg(long)]
+    pub paranoid_dma: bool,
+
+    /// Run endpoint worker threads only on these host CPUs, e.g.,
+    /// `2,4-7`.
+    ///
+    /// Prefix the CPUs with an endpoint type to only pin the workers of
+    /// that type, e.g., `interrupt=3`, which takes precedence over CPUs
+    /// for all workers. May be given multiple times. With
+    /// --async-endpoints, only CPUs for all workers apply, to the executor
+    /// thread. Without this option, the threads may run on any CPU.
+    #[arg(
+        long,
+        alias = "endpoint-cpus",
+        value_name = "[TYPE=]CPUS",
+        value_parser = parse_worker_setting::<CpuSet>
+    )]
+    pub worker_affinity: Vec<WorkerSetting<CpuSet>>,
+
+    /// Run endpoint worker threads with this nice value, from -20 to 19.
+    ///
+    /// Like --worker-affinity, the value may be limited to an endpoint
+    /// type, e.g., `interrupt=-10`. Raising the priority needs
+    /// CAP_SYS_NICE; if setting it fails, the workers run with the
+    /// priority of usbvfiod.
+    #[arg(
+        long,
+        value_name = "[TYPE=]NICE",
+        allow_hyphen_values = true,
+        value_parser = parse_worker_setting::<Nice>
+    )]
+    pub worker_priority: Vec<WorkerSetting<Nice>>,
+
+    /// Allow transfer ring segments to span multiple contiguous pages.
+    ///
+    /// By default, a segment that does not end with a Link TRB within its
+    /// page is considered broken and the endpoint gets a TRB Error.
+    /// Segments may never cross a 64 KiB boundary.
+    #[arg(long)]
+    pub multi_page_transfer_rings: bool,
+
+    /// The maximum number of TRBs an endpoint processes per doorbell
+    /// ring before it waits for the next one.
+    ///
+    /// This bounds the damage a broken transfer ring can do, but may
+    /// stall drivers that queue more TRBs at once. Without this option,
+    /// endpoints process TRBs until their ring is empty.
+    #[arg(long, value_name = "N")]
+    pub max_trbs_per_doorbell: Option<NonZeroUsize>,
+
+    /// Do not detach host kernel drivers from the devices.
+    ///
+    /// Attaching a device fails if one of its interfaces is in use on
+    /// the host. By default, usbvfiod takes the interfaces from their
+    /// drivers.
+    #[arg(long)]
+    pub no_detach: bool,
+
+    /// Poll Interrupt IN endpoints as fast as the devices respond.
+    ///
+    /// By default, usbvfiod polls these endpoints at the interval the
+    /// guest driver configured, which is what the devices expect. Some
+    /// devices answer faster than they claim and need this quirk.
+    #[arg(long)]
+    pub no_interrupt_pacing: bool,
+
+    /// Forward the guest's CLEAR_FEATURE(ENDPOINT_HALT) requests to the
+    /// devices.
+    ///
+    /// By default, usbvfiod clears the halt of the endpoint on the host
+    /// instead, which sends the request itself and also resets the host's
+    /// data toggle. Devices that expect the request twice need this quirk.
+    #[arg(long)]
+    pub forward_clear_halt: bool,
+
+    /// Fail this partially implemented command with a TRB Error instead
+    /// of completing it successfully. Can be specified multiple times.
+    ///
+    /// This is for trying guest drivers that rely on the full semantics
+    /// of the command.
+    #[arg(long = "strict-command", value_name = "COMMAND")]
+    pub strict_commands: Vec<PartialCommand>,
+
+    /// A name that tells this instance apart from others, e.g., the VM
+    /// it serves.
+    ///
+    /// The label is attached to all log messages and reported to the
+    /// guest in a vendor-specific PCI capability, truncated to 24
+    /// characters.
+    #[arg(long, value_name = "STRING")]
+    pub label: Option<String>,
+
+    /// The PCI vendor ID of the controller in hex, e.g., `1b36`.
+    ///
+    /// Guest drivers may no longer recognize the controller, so this
+    /// requires --i-know-what-i-am-doing-pci-id.
+    #[arg(long, value_name = "ID", value_parser = parse_hex_u16, requires = "i_know_what_i_am_doing_pci_id")]
+    pci_vendor_id: Option<u16>,
+
+    /// The PCI device ID of the controller in hex, e.g., `000d`.
+    ///
+    /// Guest drivers may no longer recognize the controller, so this
+    /// requires --i-know-what-i-am-doing-pci-id.
+    #[arg(long, value_name = "ID", value_parser = parse_hex_u16, requires = "i_know_what_i_am_doing_pci_id")]
+    pci_device_id: Option<u16>,
+
+    /// Allow changing the PCI vendor and device ID of the controller.
+    #[arg(long)]
+    i_know_what_i_am_doing_pci_id: bool,
+
+    /// The PCI subsystem vendor ID of the controller in hex.
+    ///
+    /// Defaults to the vendor ID.
+    #[arg(long, value_name = "ID", value_parser = parse_hex_u16)]
+    pci_subsystem_vendor_id: Option<u16>,
+
+    /// The PCI subsystem ID of the controller in hex.
+    ///
+    /// Defaults to the device ID.
+    #[arg(long, value_name = "ID", value_parser = parse_hex_u16)]
+    pci_subsystem_id: Option<u16>,
+
+    /// The PCI revision ID of the controller in hex.
+    #[arg(long, value_name = "REVISION", value_parser = parse_hex_u8, default_value = "0")]
+    pci_revision: u8,
+}
+
+/// Parse a hexadecimal number with optional `0x` prefix.
+fn parse_hex_u16(s: &str) -> Result<u16, ParseIntError> {
+    u16::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16)
+}
+
+/// Parse a hexadecimal number with optional `0x` prefix.
+fn parse_worker_setting<T>(s: &str) -> Result<WorkerSetting<T>, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    s.parse().map_err(|error| format!("{error}"))
+}
+
+fn parse_hex_u8(s: &str) -> Result<u8, ParseIntError> {
+    u8::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16)
 }
 
 /// The location of the server socket for the vfio-user client connection.
@@ -58,6 +293,51 @@ pub enum ServerSocket<'a> {
 }
 
 impl Cli {
+    /// The window in which Transfer Events are coalesced, if enabled.
+    pub fn event_coalescing(&self) -> Option<Duration> {
+        self.event_coalescing_us.map(Duration::from_micros)
+    }
+
+    /// How long the guest driver may leave the Event Ring alone.
+    pub const fn stuck_erdp_timeout(&self) -> Duration {
+        Duration::from_millis(self.stuck_erdp_timeout_ms)
+    }
+
+    /// How devices are taken from the host.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub const fn interface_claim(&self) -> InterfaceClaim {
+        if self.no_detach {
+            InterfaceClaim::NoDetach
+        } else {
+            InterfaceClaim::Detach
+        }
+    }
+
+    /// How the controller completes partially implemented commands.
+    pub fn command_policy(&self) -> CommandPolicy {
+        CommandPolicy::strict(self.strict_commands.iter().copied())
+    }
+
+    /// How endpoint worker threads are scheduled.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub fn worker_policy(&self) -> WorkerPolicy {
+        WorkerPolicy::new(self.worker_affinity.clone(), self.worker_priority.clone())
+    }
+
+    /// The IDs the controller presents in its PCI Configuration Space.
+    pub fn pci_identity(&self) -> Result<PciIdentity, PciIdentityError> {
+        let vendor_id = self.pci_vendor_id.unwrap_or(DEFAULT_PCI_IDENTITY.vendor_id);
+        let device_id = self.pci_device_id.unwrap_or(DEFAULT_PCI_IDENTITY.device_id);
+        let identity = PciIdentity {
+            subsystem_vendor_id: self.pci_subsystem_vendor_id.unwrap_or(vendor_id),
+            subsystem_id: self.pci_subsystem_id.unwrap_or(device_id),
+            revision: self.pci_revision,
+            ..PciIdentity::new(vendor_id, device_id)
+        };
+        identity.validate()?;
+        Ok(identity)
+    }
+
     pub fn server_socket(&self) -> ServerSocket<'_> {
         self.socket_path.as_ref().map_or_else(
             || unreachable!(),
@@ -65,3 +345,63 @@ impl Cli {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::affinity::WorkerClass;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(
+            ["usbvfiod", "--socket-path", "/tmp/usbvfiod.sock"]
+                .iter()
+                .chain(args),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn no_detach_claims_interfaces_without_detaching() {
+        assert_eq!(parse(&[]).interface_claim(), InterfaceClaim::Detach);
+        assert_eq!(
+            parse(&["--no-detach"]).interface_claim(),
+            InterfaceClaim::NoDetach
+        );
+    }
+
+    #[test]
+    fn worker_settings_apply_per_endpoint_type() {
+        let policy = parse(&[
+            "--endpoint-cpus",
+            "0-1",
+            "--worker-affinity",
+            "interrupt=2",
+            "--worker-priority",
+            "-5",
+        ])
+        .worker_policy();
+        let interrupt = policy.for_class(Some(WorkerClass::Interrupt));
+        assert_eq!(interrupt.cpus, "2".parse().ok());
+        assert_eq!(interrupt.nice, "-5".parse().ok());
+        assert_eq!(
+            policy.for_class(Some(WorkerClass::Bulk)).cpus,
+            "0-1".parse().ok()
+        );
+
+        assert!(Cli::try_parse_from([
+            "usbvfiod",
+            "--socket-path",
+            "/tmp/usbvfiod.sock",
+            "--worker-priority",
+            "control=0"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn interrupt_pacing_is_on_by_default() {
+        assert!(!parse(&[]).no_interrupt_pacing);
+        assert!(parse(&["--no-interrupt-pacing"]).no_interrupt_pacing);
+    }
+}
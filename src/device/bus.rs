@@ -13,7 +13,7 @@ use std::{
     sync::Arc,
     vec::Vec,
 };
-use tracing::{debug, warn};
+use tracing::debug;
 
 use crate::device::interval::Interval;
 
@@ -214,6 +214,20 @@ pub trait BusDevice: Debug {
         }
     }
 
+    /// Read large amounts of data from the bus and report how many bytes
+    /// were served by actual devices.
+    ///
+    /// This behaves like [`read_bulk`](Self::read_bulk), but allows callers
+    /// to detect when (parts of) the data was filled in by a bus's default
+    /// device, because no device claims the accessed addresses. The
+    /// default implementation assumes that the device serves all of its
+    /// addresses.
+    #[cfg(any(feature = "nusb-backend", test))]
+    fn try_read_bulk(&self, offset: u64, data: &mut [u8]) -> usize {
+        self.read_bulk(offset, data);
+        data.len()
+    }
+
     /// Write large amounts of data to the bus.
     ///
     /// Bulk writes are not atomic and reads can see intermediate
@@ -239,28 +253,26 @@ pub trait BusDevice: Debug {
         }
     }
 
-    /// Compare and exchange a value atomically.
-    ///
-    /// Some [`BusDevice`] implementations might have an efficient implementation
-    /// for this. The default implementation issues a warning and falls back
-    /// to non-atomic read/write cycles. If you hit this warning, you should
-    /// implement compare_exchange on your [`BusDevice`].
+    /// Write large amounts of data to the bus and report how many bytes
+    /// reached actual devices.
     ///
-    /// The access width of the operation is encoded in the `size`-field of a [`Request`].
+    /// This is the write counterpart of
+    /// `try_read_bulk`. Bytes that no device claims
+    /// go to the bus's default device, which usually drops them.
+    fn try_write_bulk(&self, offset: u64, data: &[u8]) -> usize {
+        self.write_bulk(offset, data);
+        data.len()
+    }
+
+    /// Whether actual devices serve all `len` bytes from `offset`, rather
+    /// than a bus's default device.
     ///
-    /// See [`std::sync::atomic::AtomicU64::compare_exchange`].
-    fn compare_exchange_request(&self, req: Request, current: u64, new: u64) -> Result<u64, u64> {
-        warn!(
-            "Atomic compare-exchange executed non-atomically for access to {:016x}",
-            req.addr
-        );
-        let old = self.read(req);
-        if old == current {
-            self.write(req, new);
-            Ok(current)
-        } else {
-            Err(old)
-        }
+    /// Callers use this to find out which part of a failed
+    /// `try_read_bulk` or
+    /// [`try_write_bulk`](Self::try_write_bulk) missed. The default
+    /// implementation assumes that the device serves all of its addresses.
+    fn is_mapped(&self, _offset: u64, _len: u64) -> bool {
+        true
     }
 }
 
@@ -301,29 +313,44 @@ impl<T: SingleThreadedBusDevice + Debug + Send> BusDevice for std::sync::Mutex<T
 /// [`Bus::new()`].
 ///
 /// The usual semantics is to return all bits set for reads and ignore
-/// writes.
-#[derive(Debug, Clone, Default)]
+/// writes. Devices built with [`with_fill`](Self::with_fill) return a
+/// different byte pattern instead.
+#[derive(Debug, Clone)]
 pub struct DefaultDevice {
     /// The size of the default device in bytes.
     size: u64,
     name: &'static str,
+    /// The value of every byte that is read.
+    fill: u8,
+}
+
+impl Default for DefaultDevice {
+    fn default() -> Self {
+        Self::new_with_size("", 0)
+    }
 }
 
 impl DefaultDevice {
     /// Construct a default device that spans the complete address space.
     #[must_use]
-    #[allow(unused)]
     pub const fn new(name: &'static str) -> Self {
+        Self::new_with_size(name, u64::MAX)
+    }
+
+    /// Construct a default device that spans a specific size in bytes.
+    #[must_use]
+    pub const fn new_with_size(name: &'static str, size: u64) -> Self {
         Self {
-            size: u64::MAX,
+            size,
             name,
+            fill: 0xff,
         }
     }
 
-    /// Construct a default device that spans a specific size in bytes.
+    /// Return `fill` for every byte that is read.
     #[must_use]
-    pub const fn new_with_size(name: &'static str, size: u64) -> Self {
-        Self { size, name }
+    pub const fn with_fill(self, fill: u8) -> Self {
+        Self { fill, ..self }
     }
 }
 
@@ -342,7 +369,7 @@ impl BusDevice for DefaultDevice {
         );
     }
 
-    /// Return a "all-bits-set" value for the given request size.
+    /// Return the fill byte in every byte of the given request size.
     fn read(&self, req: Request) -> u64 {
         let bytes: u8 = req.size.into();
         let empty_bits = u64::BITS - u8::BITS * u32::from(bytes);
@@ -356,7 +383,72 @@ impl BusDevice for DefaultDevice {
             u64::from(req.size)
         );
 
-        !0 >> empty_bits
+        (u64::MAX / 0xff * u64::from(self.fill)) >> empty_bits
+    }
+}
+
+/// A bus device backed by a buffer of its own instead of guest memory.
+///
+/// The controller hands it to devices in place of the DMA bus when it has
+/// to look at data before the guest does. Accesses beyond the buffer read
+/// zeros and are dropped.
+#[derive(Debug)]
+pub struct ScratchMemory {
+    data: std::sync::Mutex<Vec<u8>>,
+}
+
+impl ScratchMemory {
+    /// Create a zeroed buffer of `size` bytes.
+    #[must_use]
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: std::sync::Mutex::new(vec![0; size]),
+        }
+    }
+
+    /// A copy of the content of the buffer.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl BusDevice for ScratchMemory {
+    fn size(&self) -> u64 {
+        self.data.lock().unwrap().len() as u64
+    }
+
+    fn read(&self, req: Request) -> u64 {
+        let mut bytes = [0; 8];
+        let size = u64::from(req.size) as usize;
+        self.read_bulk(req.addr, &mut bytes[..size]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write(&self, req: Request, value: u64) {
+        let size = u64::from(req.size) as usize;
+        self.write_bulk(req.addr, &value.to_le_bytes()[..size]);
+    }
+
+    fn read_bulk(&self, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        let buffer = self.data.lock().unwrap();
+        let available = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| buffer.get(offset..))
+            .unwrap_or_default();
+        let len = available.len().min(data.len());
+        data[..len].copy_from_slice(&available[..len]);
+    }
+
+    fn write_bulk(&self, offset: u64, data: &[u8]) {
+        let mut buffer = self.data.lock().unwrap();
+        if let Some(available) = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| buffer.get_mut(offset..))
+        {
+            let len = available.len().min(data.len());
+            available[..len].copy_from_slice(&data[..len]);
+        }
     }
 }
 
@@ -460,6 +552,9 @@ struct BulkRequestChunk<'a> {
 
     /// The range in the original data slice.
     data_range: Range<usize>,
+
+    /// Whether a device claims the range or the request goes to the default device.
+    mapped: bool,
 }
 
 /// An iterator to split bulk requests.
@@ -530,6 +625,7 @@ impl<'a> Iterator for BulkRequestIterator<'a> {
                     device: entry.device.as_ref(),
                     device_offset,
                     data_range: data_offset..(data_offset + chunk_size),
+                    mapped: true,
                 }
             } else {
                 // If no device matches, we fall back to byte-to-byte accesses to the default device.
@@ -541,6 +637,7 @@ impl<'a> Iterator for BulkRequestIterator<'a> {
                     device: self.bus.default.as_ref(),
                     device_offset: self.cur_offset,
                     data_range: data_offset..(data_offset + 1),
+                    mapped: false,
                 }
             };
 
@@ -668,18 +765,52 @@ impl BusDevice for Bus {
         });
     }
 
+    #[cfg(any(feature = "nusb-backend", test))]
+    fn try_read_bulk(&self, offset: u64, data: &mut [u8]) -> usize {
+        self.iter_bulk_request(offset, data)
+            .map(|breq| {
+                if breq.mapped {
+                    breq.device
+                        .try_read_bulk(breq.device_offset, &mut data[breq.data_range])
+                } else {
+                    breq.device
+                        .read_bulk(breq.device_offset, &mut data[breq.data_range]);
+                    0
+                }
+            })
+            .sum()
+    }
+
     fn write_bulk(&self, offset: u64, data: &[u8]) {
         self.iter_bulk_request(offset, data).for_each(|breq| {
             breq.device
                 .write_bulk(breq.device_offset, &data[breq.data_range])
         });
     }
+
+    fn try_write_bulk(&self, offset: u64, data: &[u8]) -> usize {
+        self.iter_bulk_request(offset, data)
+            .map(|breq| {
+                if breq.mapped {
+                    breq.device
+                        .try_write_bulk(breq.device_offset, &data[breq.data_range])
+                } else {
+                    breq.device
+                        .write_bulk(breq.device_offset, &data[breq.data_range]);
+                    0
+                }
+            })
+            .sum()
+    }
 }
 
 #[cfg(test)]
 pub mod testutils {
     use super::*;
-    use std::sync::Mutex;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
 
     #[derive(Debug, Default)]
     pub struct TestBusDevice {
@@ -733,10 +864,8 @@ pub mod testutils {
         }
 
         fn write(&self, req: Request, value: u64) {
-            if req.size != RequestSize::Size8 {
-                panic!("Only supporting 8-byte writes");
-            }
-            self.write_bulk(req.addr, &value.to_le_bytes());
+            let size = u64::from(req.size) as usize;
+            self.write_bulk(req.addr, &value.to_le_bytes()[..size]);
         }
 
         fn read_bulk(&self, offset: u64, data: &mut [u8]) {
@@ -749,14 +878,62 @@ pub mod testutils {
             self.data.lock().unwrap()[offset..(offset + data.len())].copy_from_slice(data)
         }
     }
+
+    /// A [`TestBusDevice`] that counts the requests it serves.
+    #[derive(Debug, Default)]
+    pub struct CountingBusDevice {
+        pub memory: TestBusDevice,
+        operations: AtomicUsize,
+    }
+
+    impl CountingBusDevice {
+        pub fn new(data: &[u8]) -> Self {
+            Self {
+                memory: TestBusDevice::new(data),
+                operations: AtomicUsize::new(0),
+            }
+        }
+
+        /// The number of requests since the last call.
+        pub fn take_operations(&self) -> usize {
+            self.operations.swap(0, Ordering::Relaxed)
+        }
+
+        fn count(&self) {
+            self.operations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl BusDevice for CountingBusDevice {
+        fn size(&self) -> u64 {
+            self.memory.size()
+        }
+
+        fn read(&self, req: Request) -> u64 {
+            self.count();
+            self.memory.read(req)
+        }
+
+        fn write(&self, req: Request, value: u64) {
+            self.count();
+            self.memory.write(req, value)
+        }
+
+        fn read_bulk(&self, offset: u64, data: &mut [u8]) {
+            self.count();
+            self.memory.read_bulk(offset, data)
+        }
+
+        fn write_bulk(&self, offset: u64, data: &[u8]) {
+            self.count();
+            self.memory.write_bulk(offset, data)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::{
-        atomic::{AtomicU64, Ordering::SeqCst},
-        Mutex,
-    };
+    use std::sync::Mutex;
 
     use super::*;
     use proptest::prelude::*;
@@ -845,6 +1022,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn default_device_can_fill_reads_with_other_bytes() {
+        let zero = DefaultDevice::new("test").with_fill(0);
+        assert_eq!(zero.read(Request::new(0, RequestSize::Size1)), 0);
+        assert_eq!(zero.read(Request::new(0, RequestSize::Size8)), 0);
+
+        let pattern = DefaultDevice::new("test").with_fill(0xa5);
+        assert_eq!(pattern.read(Request::new(0, RequestSize::Size2)), 0xa5a5);
+        assert_eq!(
+            pattern.read(Request::new(0, RequestSize::Size8)),
+            0xa5a5_a5a5_a5a5_a5a5
+        );
+
+        // Bulk reads of gaps go to the default device byte by byte.
+        let bus = Bus::new_with_default("test", Arc::new(pattern));
+        let mut data = [0u8; 3];
+        bus.read_bulk(0x1000, &mut data);
+        assert_eq!(data, [0xa5; 3]);
+    }
+
+    #[test]
+    fn scratch_memory_keeps_what_fits() {
+        let scratch = ScratchMemory::new(4);
+        scratch.write(Request::new(0, RequestSize::Size2), 0x1234);
+        scratch.write_bulk(2, &[0x56, 0x78, 0x9a]);
+        scratch.write(Request::new(8, RequestSize::Size1), 0xff);
+        assert_eq!(scratch.to_bytes(), [0x34, 0x12, 0x56, 0x78]);
+
+        assert_eq!(scratch.read(Request::new(2, RequestSize::Size4)), 0x7856);
+        let mut data = [0xffu8; 2];
+        scratch.read_bulk(u64::MAX, &mut data);
+        assert_eq!(data, [0; 2]);
+    }
+
     #[test]
     fn unmatched_requests_are_handled_by_default() {
         let bus = Bus::default();
@@ -1117,109 +1328,4 @@ mod tests {
 
         Ok(())
     }
-
-    /// A "device" that stores and returns the last value written.
-    impl BusDevice for Arc<AtomicU64> {
-        fn size(&self) -> u64 {
-            0
-        }
-
-        fn read(&self, req: Request) -> u64 {
-            let res = self.load(SeqCst);
-            match req.size {
-                RequestSize::Size8 => res,
-                RequestSize::Size4 => res as u32 as u64,
-                RequestSize::Size2 => res as u16 as u64,
-                RequestSize::Size1 => res as u8 as u64,
-            }
-        }
-
-        fn write(&self, req: Request, value: u64) {
-            let value = match req.size {
-                RequestSize::Size8 => value,
-                RequestSize::Size4 => value as u32 as u64,
-                RequestSize::Size2 => value as u16 as u64,
-                RequestSize::Size1 => value as u8 as u64,
-            };
-            self.store(value, SeqCst);
-        }
-
-        // Note: we deliberately do not implement compare_exchange
-        // to test the default implementation.
-    }
-
-    #[test]
-    fn compare_exchange_returns_ok_current_on_correct_value_and_updates() {
-        let current: u64 = 0x0123_4567_89ab_cdef;
-        let device = Arc::new(AtomicU64::new(current));
-        let addr = 0;
-
-        // Successful cmpxchg returns the supplied current value for 64 bit
-        let new = current as u32 as u64;
-        assert_eq!(
-            device.compare_exchange_request(
-                Request {
-                    addr,
-                    size: RequestSize::Size8
-                },
-                current,
-                new
-            ),
-            Ok(current)
-        );
-
-        // Successful cmpxchg returns the supplied current value for 32 bit
-        let current = new;
-        let new = current as u16 as u64;
-        assert_eq!(
-            device.compare_exchange_request(
-                Request {
-                    addr,
-                    size: RequestSize::Size4
-                },
-                current,
-                new
-            ),
-            Ok(current)
-        );
-
-        // Value got updated
-        assert_eq!(device.load(SeqCst), new);
-    }
-
-    #[test]
-    fn compare_exchange_returns_err_current_on_wrong_value_and_bails() {
-        let current: u64 = 0x0123_4567_89ab_cdef;
-        let device = Arc::new(AtomicU64::new(current));
-        let addr = 0;
-
-        // Unsuccessful cmpxchg returns the actual current value as Err for 64 bit
-        assert_eq!(
-            device.compare_exchange_request(
-                Request {
-                    addr,
-                    size: RequestSize::Size8
-                },
-                !current,
-                0
-            ),
-            Err(current)
-        );
-
-        // Unsuccessful cmpxchg returns the actual current value as Err for 32 bit
-        assert_eq!(
-            device.compare_exchange_request(
-                Request {
-                    addr,
-                    size: RequestSize::Size4
-                },
-                !current,
-                0
-            ),
-            Err(current as u32 as u64)
-        );
-
-        // The value did not get updated
-        assert_eq!(device.load(SeqCst), current);
-    }
 }
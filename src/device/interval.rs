@@ -8,7 +8,7 @@ use std::ops::Range;
 /// A simple trait for intervals math.
 ///
 /// We use this to extend [Range] with useful interval functionality.
-pub trait Interval: PartialEq {
+pub trait Interval: PartialEq + Sized {
     /// The underlying numerical type.
     type Element: Copy + Ord;
 
@@ -21,6 +21,12 @@ pub trait Interval: PartialEq {
 
     /// Return true, if the two intervals have overlapping parts.
     fn overlaps(&self, other: &Self) -> bool;
+
+    /// Return the union of two intervals, if it is an interval itself.
+    ///
+    /// This is the case if the intervals overlap or touch each
+    /// other. Empty intervals merge with anything.
+    fn merge(&self, other: &Self) -> Option<Self>;
 }
 
 impl<T: Copy + Ord + Default> Interval for Range<T> {
@@ -37,6 +43,18 @@ impl<T: Copy + Ord + Default> Interval for Range<T> {
     fn overlaps(&self, other: &Self) -> bool {
         !self.is_empty() && !self.intersection(other).is_empty()
     }
+
+    fn merge(&self, other: &Self) -> Option<Self> {
+        if other.is_empty() {
+            Some(self.clone())
+        } else if self.is_empty() {
+            Some(other.clone())
+        } else if self.start <= other.end && other.start <= self.end {
+            Some(self.start.min(other.start)..self.end.max(other.end))
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(test)]
@@ -108,6 +126,39 @@ mod tests {
             assert!(!empty.overlaps(&ivl));
         }
 
+        #[test]
+        fn merge_semantics(v: u64, ivl1: Ivl, ivl2: Ivl) {
+            if let Some(merged) = ivl1.merge(&ivl2) {
+                assert_eq!(ivl1.contains(&v) || ivl2.contains(&v), merged.contains(&v));
+            }
+        }
+
+        #[test]
+        fn merge_with_empty(ivl: Ivl) {
+            assert!(ivl_equal(ivl.merge(&Default::default()).unwrap(), ivl));
+        }
+
+        #[test]
+        fn merge_is_commutative(ivl1: Ivl, ivl2: Ivl) {
+            assert_eq!(ivl1.merge(&ivl2).is_some(), ivl2.merge(&ivl1).is_some());
+            if let (Some(a), Some(b)) = (ivl1.merge(&ivl2), ivl2.merge(&ivl1)) {
+                assert!(ivl_equal(a, b));
+            }
+        }
+
+        #[test]
+        fn overlapping_intervals_merge(ivl1: Ivl, ivl2: Ivl) {
+            assert!(implies(ivl1.overlaps(&ivl2), ivl1.merge(&ivl2).is_some()));
+        }
+
+        #[test]
+        fn merge_contains_both(ivl1: Ivl, ivl2: Ivl) {
+            if let Some(merged) = ivl1.merge(&ivl2) {
+                assert!(merged.contains_interval(&ivl1) || ivl1.is_empty());
+                assert!(merged.contains_interval(&ivl2) || ivl2.is_empty());
+            }
+        }
+
         #[test]
         fn contains_symmetric_for_identical_values(ivl1: Ivl, ivl2: Ivl) {
             // If two intervals contain each other, they are
@@ -163,4 +214,20 @@ mod tests {
         assert!(first_ivl.contains_interval(&contained_ivl));
         assert!(!second_ivl.contains_interval(&contained_ivl));
     }
+
+    #[test]
+    fn interval_merge() {
+        let first_ivl = Ivl { start: 10, end: 20 };
+        let second_ivl = Ivl { start: 15, end: 25 };
+        let adjacent_ivl = Ivl { start: 20, end: 30 };
+        let unrelated_ivl = Ivl {
+            start: 80,
+            end: 100,
+        };
+
+        assert_eq!(first_ivl.merge(&second_ivl), Some(10..25));
+        assert_eq!(first_ivl.merge(&adjacent_ivl), Some(10..30));
+        assert_eq!(first_ivl.merge(&unrelated_ivl), None);
+        assert_eq!(first_ivl.merge(&Ivl::default()), Some(first_ivl));
+    }
 }
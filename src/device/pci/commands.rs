@@ -0,0 +1,180 @@
+//! # Command Results
+//!
+//! Every command on the Command Ring ends with a Command Completion Event,
+//! whether the controller carried it out or not. The command handlers of
+//! the controller return a [`CommandResult`], and the controller turns
+//! both cases into the event: a [`CommandOutcome`] completes the command
+//! successfully, a [`CommandError`] maps to the completion code that tells
+//! the driver what went wrong.
+//!
+//! Errors are the driver's fault (or a limitation of ours), so they must
+//! never bring down the controller.
+//!
+//! Some commands are only carried out in part, but complete successfully
+//! anyway, which is what drivers have worked with so far. A
+//! [`CommandPolicy`] can fail them instead, for trying stricter guests.
+
+use clap::ValueEnum;
+use thiserror::Error;
+
+use super::{
+    dci::Dci,
+    trb::{CommandTrbVariant, CompletionCode},
+};
+
+/// The result of handling a command.
+pub type CommandResult = Result<CommandOutcome, CommandError>;
+
+/// The fields of a successful Command Completion Event that depend on the
+/// command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandOutcome {
+    /// The Command Completion Parameter. No command we support uses it so
+    /// far, so it is always 0.
+    pub parameter: u32,
+    /// The Slot ID the command refers to, or the slot an Enable Slot
+    /// Command enabled.
+    pub slot_id: u8,
+}
+
+impl CommandOutcome {
+    /// A successful completion for the given slot.
+    pub const fn new(slot_id: u8) -> Self {
+        Self {
+            parameter: 0,
+            slot_id,
+        }
+    }
+}
+
+/// Why the controller did not carry out a command.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("{0} is not supported")]
+    UnsupportedCommand(String),
+    #[error("slot {0} is not enabled")]
+    SlotNotEnabled(u8),
+    #[error("slot {0} is not in a state that allows the command")]
+    InvalidSlotState(u8),
+    #[error("EP{endpoint_id} of slot {slot_id} is not in a state that allows the command")]
+    InvalidEndpointState { slot_id: u8, endpoint_id: Dci },
+    #[error("invalid command parameter: {0}")]
+    ParameterError(String),
+    #[error("no slots available")]
+    NoSlotsAvailable,
+    #[error("out of {0}")]
+    ResourceError(&'static str),
+}
+
+impl CommandError {
+    /// The completion code that reports the error to the driver.
+    ///
+    /// See Section 4.6 of the XHCI specification for the codes each
+    /// command may complete with.
+    pub const fn completion_code(&self) -> CompletionCode {
+        match self {
+            Self::UnsupportedCommand(_) => CompletionCode::TrbError,
+            Self::SlotNotEnabled(_) => CompletionCode::SlotNotEnabledError,
+            Self::InvalidSlotState(_) | Self::InvalidEndpointState { .. } => {
+                CompletionCode::ContextStateError
+            }
+            Self::ParameterError(_) => CompletionCode::ParameterError,
+            Self::NoSlotsAvailable => CompletionCode::NoSlotsAvailableError,
+            Self::ResourceError(_) => CompletionCode::ResourceError,
+        }
+    }
+}
+
+/// The commands the controller only carries out in part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PartialCommand {
+    /// Disable Slot releases the USB address and the device context, but
+    /// leaves the endpoints of the device running.
+    DisableSlot,
+    /// Reset Device resets the device and the device context, but does not
+    /// check the slot state.
+    ResetDevice,
+}
+
+impl PartialCommand {
+    /// The partially implemented command a TRB is, if any.
+    pub const fn of(variant: &CommandTrbVariant) -> Option<Self> {
+        match variant {
+            CommandTrbVariant::DisableSlot(_) => Some(Self::DisableSlot),
+            CommandTrbVariant::ResetDevice(_) => Some(Self::ResetDevice),
+            _ => None,
+        }
+    }
+}
+
+/// How the controller completes the commands it only carries out in part.
+///
+/// By default, they complete successfully. Strict commands are not carried
+/// out at all and fail like unsupported ones.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CommandPolicy {
+    strict: Vec<PartialCommand>,
+}
+
+impl CommandPolicy {
+    /// A policy that fails the given commands.
+    pub fn strict(commands: impl IntoIterator<Item = PartialCommand>) -> Self {
+        Self {
+            strict: commands.into_iter().collect(),
+        }
+    }
+
+    /// Check whether the controller may carry out a command.
+    pub fn check(&self, variant: &CommandTrbVariant) -> Result<(), CommandError> {
+        match PartialCommand::of(variant) {
+            Some(command) if self.strict.contains(&command) => {
+                Err(CommandError::UnsupportedCommand(variant.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_completion_codes() {
+        for (error, completion_code) in [
+            (
+                CommandError::UnsupportedCommand("Force Header Command".to_string()),
+                CompletionCode::TrbError,
+            ),
+            (
+                CommandError::SlotNotEnabled(3),
+                CompletionCode::SlotNotEnabledError,
+            ),
+            (
+                CommandError::InvalidSlotState(1),
+                CompletionCode::ContextStateError,
+            ),
+            (
+                CommandError::InvalidEndpointState {
+                    slot_id: 1,
+                    endpoint_id: Dci::new(2).unwrap(),
+                },
+                CompletionCode::ContextStateError,
+            ),
+            (
+                CommandError::ParameterError("root hub port 0".to_string()),
+                CompletionCode::ParameterError,
+            ),
+            (
+                CommandError::NoSlotsAvailable,
+                CompletionCode::NoSlotsAvailableError,
+            ),
+            (
+                CommandError::ResourceError("USB device addresses"),
+                CompletionCode::ResourceError,
+            ),
+        ] {
+            assert_eq!(error.completion_code(), completion_code, "{error}");
+        }
+    }
+}
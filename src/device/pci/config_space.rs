@@ -11,7 +11,7 @@ use crate::device::{
 use super::{
     constants::config_space::{
         self, command, header_type, mask::CAPABILITIES_POINTER as CAPABILITY_POINTER_MASK, offset,
-        status, MAX_BARS,
+        status, vendor, MAX_BARS,
     },
     traits::RequestKind,
 };
@@ -27,11 +27,81 @@ pub struct BarInfo {
 
     /// The type of requests this BAR matches.
     pub kind: RequestKind,
+
+    /// Whether the BAR is a 64-bit memory BAR, which also occupies the
+    /// following BAR for the upper half of its address.
+    pub is_64bit: bool,
+
+    /// Whether reads from the region have no side effects, so the host may
+    /// prefetch and merge accesses.
+    pub prefetchable: bool,
 }
 
 impl BarInfo {
+    /// Describe a non-prefetchable 32-bit BAR.
     const fn new(size: u32, kind: RequestKind) -> Self {
-        Self { size, kind }
+        Self {
+            size,
+            kind,
+            is_64bit: false,
+            prefetchable: false,
+        }
+    }
+}
+
+/// The IDs a PCI device identifies itself with.
+///
+/// Guest drivers bind to devices based on these IDs, so changing the vendor
+/// or device ID may leave the device without a driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciIdentity {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub revision: u8,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum PciIdentityError {
+    #[error("Invalid vendor ID {0:#06x}")]
+    InvalidVendorId(u16),
+    #[error("Invalid subsystem vendor ID {0:#06x}")]
+    InvalidSubsystemVendorId(u16),
+}
+
+impl PciIdentity {
+    /// Create an identity whose subsystem IDs repeat the main IDs, with
+    /// revision 0.
+    pub const fn new(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id,
+            device_id,
+            subsystem_vendor_id: vendor_id,
+            subsystem_id: device_id,
+            revision: 0,
+        }
+    }
+
+    /// Check that the vendor IDs are assignable.
+    ///
+    /// Vendor ID `0xFFFF` is what software reads for an absent function, so
+    /// a device with this ID would be invisible. Vendor ID 0 is not
+    /// assigned to any vendor.
+    pub const fn validate(&self) -> Result<(), PciIdentityError> {
+        const fn valid(vendor_id: u16) -> bool {
+            vendor_id != 0 && vendor_id != vendor::INVALID
+        }
+
+        if !valid(self.vendor_id) {
+            return Err(PciIdentityError::InvalidVendorId(self.vendor_id));
+        }
+        if !valid(self.subsystem_vendor_id) {
+            return Err(PciIdentityError::InvalidSubsystemVendorId(
+                self.subsystem_vendor_id,
+            ));
+        }
+        Ok(())
     }
 }
 
@@ -125,7 +195,6 @@ impl ConfigSpaceBuilder {
     ///
     /// When not specified, the revision defaults to 0.
     #[must_use]
-    #[allow(unused)]
     pub const fn revision(mut self, revision: u8) -> Self {
         self.revision = revision;
 
@@ -137,7 +206,6 @@ impl ConfigSpaceBuilder {
     /// The `subsystem_vendor_id` uses the same values as the normal PCI device [vendor
     /// ID](super::constants::config_space::vendor).
     #[must_use]
-    #[allow(unused)]
     pub fn subsystem(mut self, subsystem_vendor_id: u16, subsystem_id: u16) -> Self {
         self.reg_builder
             .u16_le_ro_at(offset::SUBSYSTEM_VENDOR_ID, subsystem_vendor_id)
@@ -214,6 +282,7 @@ impl ConfigSpaceBuilder {
 
         assert!(index < MAX_BARS);
         assert_eq!(self.bars[index], None);
+        assert!(!self.is_upper_bar_half(index));
 
         assert!(size.is_power_of_two());
         assert!(size >= 16);
@@ -225,6 +294,54 @@ impl ConfigSpaceBuilder {
         self
     }
 
+    /// Add a Base Address Register (BAR) for a prefetchable 64-bit memory region.
+    ///
+    /// The BAR occupies the BARs `index` and `index + 1`, the latter holds the upper half of the
+    /// address. Only regions without read side effects may be prefetchable, so this does not fit
+    /// MMIO registers.
+    ///
+    /// Size must be a power of 2 and at least 16 bytes. See
+    /// [`mem32_nonprefetchable_bar`](Self::mem32_nonprefetchable_bar) for why 4 KiB is the
+    /// recommended minimum.
+    #[cfg(test)]
+    #[must_use]
+    pub fn mem64_prefetchable_bar(mut self, index: u8, size: u32) -> Self {
+        let index: usize = index.into();
+
+        assert!(index + 1 < MAX_BARS);
+        assert_eq!(self.bars[index], None);
+        assert_eq!(self.bars[index + 1], None);
+        assert!(!self.is_upper_bar_half(index));
+
+        assert!(size.is_power_of_two());
+        assert!(size >= 16);
+
+        let flags = (config_space::mask::MMIO_BAR_64_BIT
+            | config_space::mask::MMIO_BAR_PREFETCHABLE) as u32;
+        self.reg_builder
+            .u32_le_at(
+                config_space::offset::BAR_0 + index * 4,
+                flags,
+                !(size - 1) & config_space::mask::MMIO_BAR_ADDRESS as u32,
+            )
+            .u32_le_at(config_space::offset::BAR_0 + (index + 1) * 4, 0, !0);
+
+        self.bars[index] = Some(BarInfo {
+            is_64bit: true,
+            prefetchable: true,
+            ..BarInfo::new(size, RequestKind::Memory)
+        });
+        self
+    }
+
+    /// Whether BAR `index` holds the upper half of a 64-bit BAR.
+    fn is_upper_bar_half(&self, index: usize) -> bool {
+        index
+            .checked_sub(1)
+            .and_then(|lower| self.bars[lower])
+            .is_some_and(|bar| bar.is_64bit)
+    }
+
     /// Add a PCI capability to the Configuration Space.
     ///
     /// The given `regs` must not contain the generic PCI Capability header (ID and next
@@ -270,7 +387,7 @@ impl ConfigSpaceBuilder {
 
     /// Check whether there is a configured BAR of the right kind and with at least the given size.
     fn has_bar(&self, bar_no: u8, required_kind: RequestKind, minimum_size: u32) -> bool {
-        if let Some(BarInfo { size, kind }) = self.bars[usize::from(bar_no)] {
+        if let Some(BarInfo { size, kind, .. }) = self.bars[usize::from(bar_no)] {
             kind == required_kind && size >= minimum_size
         } else {
             false
@@ -387,16 +504,6 @@ impl ConfigSpaceBuilder {
     }
 }
 
-/// The result of matching a request against a BAR.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct BarMatch {
-    /// A request relative to the BAR itself.
-    pub request: Request,
-
-    /// The index of the BAR that matched.
-    pub bar_no: u8,
-}
-
 /// The Configuration Space of a PCI device.
 ///
 /// Use [`ConfigSpaceBuilder`] to construct this.
@@ -449,11 +556,17 @@ impl ConfigSpace {
         self.config_space.read(req)
     }
 
+    /// Update a register regardless of its writability.
+    ///
+    /// This is how the device itself changes read-only registers.
+    pub fn write_direct(&mut self, req: Request, value: u64) {
+        self.config_space.write_direct(req, value);
+    }
+
     /// Iterate over all capabilities of the Configuration Space.
     ///
     /// The resulting iterator returns the Configuration Space offset of each standard PCI
     /// capability.
-    #[allow(unused)]
     pub fn iter_capability_offsets(&self) -> impl Iterator<Item = u8> + '_ {
         CapabilityIterator {
             config_space: self,
@@ -463,10 +576,38 @@ impl ConfigSpace {
         }
     }
 
+    /// Whether the driver set the Function Mask of the MSI-X capability.
+    ///
+    /// Returns `false` if there is no MSI-X capability.
+    pub fn msix_function_masked(&self) -> bool {
+        self.iter_capability_offsets()
+            .map(u64::from)
+            .find(|&cap| {
+                self.read(Request::new(cap, RequestSize::Size1))
+                    == u64::from(config_space::capability_id::MSI_X)
+            })
+            .is_some_and(|cap| {
+                let control = self.read(Request::new(
+                    cap + config_space::msix::CONTROL,
+                    RequestSize::Size2,
+                ));
+                control & u64::from(config_space::msix::control::FUNCTION_MASK) != 0
+            })
+    }
+
     /// Retrieve information about a specific BAR.
     pub fn bar(&self, bar_no: u8) -> Option<BarInfo> {
         self.bars.get(usize::from(bar_no)).and_then(|&b| b)
     }
+
+    /// All configured BARs with their numbers, in order.
+    ///
+    /// This allows callers to check that the regions they place in BARs fit.
+    pub fn bar_layout(&self) -> Vec<(u8, BarInfo)> {
+        (0..MAX_BARS as u8)
+            .filter_map(|bar_no| Some((bar_no, self.bar(bar_no)?)))
+            .collect()
+    }
 }
 
 impl SingleThreadedBusDevice for ConfigSpace {
@@ -547,6 +688,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn default_subsystem_ids_repeat_main_ids() {
+        let identity = PciIdentity::new(0x1b36, 0x000d);
+
+        assert_eq!(identity.subsystem_vendor_id, 0x1b36);
+        assert_eq!(identity.subsystem_id, 0x000d);
+        assert_eq!(identity.revision, 0);
+        assert_eq!(identity.validate(), Ok(()));
+    }
+
+    #[test]
+    fn unassignable_vendor_ids_are_rejected() {
+        for vendor_id in [0, vendor::INVALID] {
+            assert_eq!(
+                PciIdentity::new(vendor_id, 0x000d).validate(),
+                Err(PciIdentityError::InvalidVendorId(vendor_id))
+            );
+            assert_eq!(
+                PciIdentity {
+                    subsystem_vendor_id: vendor_id,
+                    ..PciIdentity::new(0x1b36, 0x000d)
+                }
+                .validate(),
+                Err(PciIdentityError::InvalidSubsystemVendorId(vendor_id))
+            );
+        }
+    }
+
     #[test]
     fn create_single_function_device_by_default() {
         let cfg_space: ConfigSpace = ConfigSpaceBuilder::new(0, 0).config_space();
@@ -768,6 +937,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn msix_function_mask_is_visible() {
+        let mut cfg_space = ConfigSpaceBuilder::new(0, 0)
+            .mem32_nonprefetchable_bar(1, 0x2000)
+            .msix_capability(1, 1, 0, 1, 0x1000)
+            .config_space();
+        assert!(!cfg_space.msix_function_masked());
+
+        let msix_ptr = cfg_space.iter_capability_offsets().next().unwrap();
+        let control = u64::from(msix_ptr) + config_space::msix::CONTROL;
+        cfg_space.write(
+            Request::new(control, RequestSize::Size2),
+            config_space::msix::control::FUNCTION_MASK.into(),
+        );
+        assert!(cfg_space.msix_function_masked());
+
+        assert!(!ConfigSpaceBuilder::new(0, 0)
+            .config_space()
+            .msix_function_masked());
+    }
+
     #[test]
     fn capability_iterator_works() {
         let no_cap_cfg_space = ConfigSpaceBuilder::new(0, 0).config_space();
@@ -807,9 +997,79 @@ mod tests {
             cfg_space.bar(0),
             Some(BarInfo {
                 size: 0x8000_0000,
-                kind: RequestKind::Memory
+                kind: RequestKind::Memory,
+                is_64bit: false,
+                prefetchable: false,
             })
         );
         assert_eq!(cfg_space.bar(1), None);
     }
+
+    #[test]
+    fn bar_layout_lists_configured_bars() {
+        let cfg_space = ConfigSpaceBuilder::new(0, 0)
+            .mem32_nonprefetchable_bar(3, 0x2000)
+            .mem64_prefetchable_bar(0, 0x4000)
+            .config_space();
+
+        let layout: Vec<_> = cfg_space
+            .bar_layout()
+            .into_iter()
+            .map(|(bar_no, bar)| (bar_no, bar.size, bar.kind))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (0, 0x4000, RequestKind::Memory),
+                (3, 0x2000, RequestKind::Memory)
+            ]
+        );
+        assert!(ConfigSpaceBuilder::new(0, 0)
+            .config_space()
+            .bar_layout()
+            .is_empty());
+    }
+
+    #[test]
+    fn can_query_prefetchable_bars() {
+        let cfg_space = ConfigSpaceBuilder::new(0, 0)
+            .mem64_prefetchable_bar(2, 0x4000)
+            .config_space();
+
+        let bar = cfg_space.bar(2).unwrap();
+        assert_eq!(bar.size, 0x4000);
+        assert_eq!(bar.kind, RequestKind::Memory);
+        assert!(bar.is_64bit);
+        assert!(bar.prefetchable);
+        // The upper half of the address is no BAR of its own.
+        assert_eq!(cfg_space.bar(3), None);
+    }
+
+    #[test]
+    fn prefetchable_bar_sizing_works() {
+        let mut cfg_space = ConfigSpaceBuilder::new(0, 0)
+            .mem64_prefetchable_bar(0, 0x4000)
+            .config_space();
+
+        // The type bits survive sizing and the upper half takes any address.
+        for offset in [offset::BAR_0, offset::BAR_1] {
+            cfg_space.write(Request::new(offset as u64, RequestSize::Size4), 0xFFFF_FFFF);
+        }
+        assert_eq!(
+            cfg_space.read(Request::new(offset::BAR_0 as u64, RequestSize::Size4)),
+            0xFFFF_C00C
+        );
+        assert_eq!(
+            cfg_space.read(Request::new(offset::BAR_1 as u64, RequestSize::Size4)),
+            0xFFFF_FFFF
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn upper_half_of_64bit_bar_is_taken() {
+        let _ = ConfigSpaceBuilder::new(0, 0)
+            .mem64_prefetchable_bar(0, 0x4000)
+            .mem32_nonprefetchable_bar(1, 0x1000);
+    }
 }
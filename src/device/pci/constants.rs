@@ -40,6 +40,7 @@ pub mod config_space {
         pub const PIO_BAR_ADDRESS: u64 = 0xffff_fffc;
         pub const MMIO_BAR_TYPE: u64 = 0x6;
         pub const MMIO_BAR_64_BIT: u64 = 0x4;
+        pub const MMIO_BAR_PREFETCHABLE: u64 = 0x8;
         pub const MMIO_BAR_ADDRESS: u64 = 0xffff_fff0;
     }
 
@@ -202,6 +203,33 @@ pub mod config_space {
             pub const WRITABLE_BITS: u16 = ENABLE | FUNCTION_MASK;
         }
     }
+
+    /// Constants for our vendor-specific capability that identifies the
+    /// server instance and the device it passes through.
+    ///
+    /// Strings are ASCII, truncated to [`STRING_SIZE`] bytes and padded
+    /// with zeros.
+    pub mod identification {
+        /// The size of the capability in bytes, including the header.
+        pub const SIZE: usize = 56;
+        /// The size of the string fields in bytes.
+        pub const STRING_SIZE: usize = 24;
+        /// The layout version we report in the [`VERSION`] field.
+        pub const CURRENT_VERSION: u8 = 1;
+
+        /// The offset of the capability length field.
+        pub const LENGTH: u64 = 2;
+        /// The offset of the layout version.
+        pub const VERSION: u64 = 3;
+        /// The offset of the label given on the command line.
+        pub const LABEL: u64 = 4;
+        /// The offset of the USB vendor ID of the attached device.
+        pub const VENDOR_ID: u64 = 28;
+        /// The offset of the USB product ID of the attached device.
+        pub const PRODUCT_ID: u64 = 30;
+        /// The offset of the serial number of the attached device.
+        pub const SERIAL: u64 = 32;
+    }
 }
 
 /// Constants related to the XHCI MMIO space.
@@ -229,6 +257,16 @@ pub mod xhci {
     /// Current value allows up to 2^15 = 32768 segments.
     pub const MAX_ERST_SIZE_EXP: u64 = 15;
 
+    /// The BAR that contains the MSI-X structures.
+    pub mod msix_bar {
+        /// The number of the BAR.
+        pub const NUMBER: u8 = 3;
+        /// Offset of the MSI-X table in the BAR.
+        pub const TABLE_OFFSET: u32 = 0;
+        /// Offset of the Pending Bit Array in the BAR.
+        pub const PBA_OFFSET: u32 = 0x1000;
+    }
+
     /// Offsets of various fields from the start of the XHCI MMIO region.
     pub mod offset {
         /// Capability Register Offsets
@@ -244,8 +282,10 @@ pub mod xhci {
 
         /// Extended Capabilities
         pub const SUPPORTED_PROTOCOLS: u64 = 0x20;
+        pub const SUPPORTED_PROTOCOLS_NAME: u64 = 0x24;
         pub const SUPPORTED_PROTOCOLS_CONFIG: u64 = 0x28;
         pub const SUPPORTED_PROTOCOLS_USB2: u64 = 0x30;
+        pub const SUPPORTED_PROTOCOLS_USB2_NAME: u64 = 0x34;
         pub const SUPPORTED_PROTOCOLS_USB2_CONFIG: u64 = 0x38;
 
         /// Operational Register Offsets
@@ -292,7 +332,29 @@ pub mod xhci {
         pub const HCSPARAMS1: u64 =
             (super::MAX_PORTS << 24) | (super::MAX_INTRS << 8) | super::MAX_SLOTS;
         pub const HCSPARAMS2: u64 = super::MAX_ERST_SIZE_EXP << 4;
-        pub const HCCPARAMS1: u64 = super::offset::SUPPORTED_PROTOCOLS << 14;
+        /// The worst-case U1 Device Exit Latency in microseconds. The
+        /// specification allows at most 10.
+        pub const U1_DEVICE_EXIT_LATENCY: u64 = 0xa;
+        /// The worst-case U2 Device Exit Latency in microseconds. The
+        /// specification allows at most 2047.
+        pub const U2_DEVICE_EXIT_LATENCY: u64 = 0x200;
+        /// We do not enter U1 or U2 ourselves, but drivers compute the
+        /// link power management timeouts from these latencies, so they
+        /// should look like those of real hardware.
+        pub const HCSPARAMS3: u64 = U1_DEVICE_EXIT_LATENCY | (U2_DEVICE_EXIT_LATENCY << 16);
+        /// Port Power Control (PPC): the driver can switch the power of
+        /// each port with PORTSC.PP.
+        pub const PPC: u64 = 1 << 3;
+        /// Light HC Reset Capability (LHRC): the driver can reset the
+        /// controller without resetting the ports with USBCMD.LHCRST.
+        pub const LHRC: u64 = 1 << 5;
+        /// MaxPSASize is 0, i.e., we do not advertise streams yet. We can
+        /// walk Stream Context Arrays, but nusb cannot use streams on the
+        /// real device, so UAS drivers would bind and then fail.
+        pub const HCCPARAMS1: u64 = (super::offset::SUPPORTED_PROTOCOLS << 14) | LHRC | PPC;
+
+        /// The Name String of both Supported Protocol Capabilities, "USB ".
+        pub const PROTOCOL_NAME: u64 = u32::from_le_bytes(*b"USB ") as u64;
 
         pub mod supported_protocols {
             const ID: u64 = 2;
@@ -326,29 +388,114 @@ pub mod xhci {
             pub const CRR: u64 = 0x8;
         }
 
+        /// Fields of the 32-bit Port Status and Control Register (xHCI
+        /// 5.4.8).
         pub mod portsc {
-            pub const CCS: u64 = 0x1;
-            pub const PED: u64 = 0x2;
-            pub const OCA: u64 = 0x8;
-            pub const PR: u64 = 0x10;
-            pub const PLS: u64 = 0x1e0;
-            pub const PP: u64 = 0x200;
-            pub const PORT_SPEED: u64 = 0x3c00;
-            pub const PIC: u64 = 0xc000;
-            pub const LWS: u64 = 0x10000;
-            pub const CSC: u64 = 0x20000;
-            pub const PEC: u64 = 0x40000;
-            pub const WRC: u64 = 0x80000;
-            pub const OCC: u64 = 0x100000;
-            pub const PRC: u64 = 0x200000;
-            pub const PLC: u64 = 0x400000;
-            pub const CEC: u64 = 0x800000;
-            pub const CAS: u64 = 0x1000000;
-            pub const WCE: u64 = 0x2000000;
-            pub const WDE: u64 = 0x4000000;
-            pub const WOE: u64 = 0x8000000;
-            pub const DR: u64 = 0x40000000;
-            pub const WPR: u64 = 0x80000000;
+            pub use bits::*;
+
+            /// The PORTSC fields and the access class the specification
+            /// assigns to each of them. Sticky variants (RWS, RW1CS, ...)
+            /// count as their plain class, as we have no auxiliary power.
+            pub mod bits {
+                /// Current Connect Status (CCS), RO
+                pub const CCS: u32 = 0x1;
+                /// Port Enabled/Disabled (PED), RW1C
+                pub const PED: u32 = 0x2;
+                /// Over-current Active (OCA), RO
+                pub const OCA: u32 = 0x8;
+                /// Port Reset (PR), RW1S
+                pub const PR: u32 = 0x10;
+                /// Port Link State (PLS), RW
+                pub const PLS: u32 = 0x1e0;
+                pub const PLS_SHIFT: u32 = 5;
+                /// Port Power (PP), RW
+                pub const PP: u32 = 0x200;
+                /// Port Speed, RO
+                pub const PORT_SPEED: u32 = 0x3c00;
+                pub const PORT_SPEED_SHIFT: u32 = 10;
+                /// Port Indicator Control (PIC), RW
+                pub const PIC: u32 = 0xc000;
+                /// Port Link State Write Strobe (LWS), RW, always reads 0
+                pub const LWS: u32 = 0x10000;
+                /// Connect Status Change (CSC), RW1C
+                pub const CSC: u32 = 0x20000;
+                /// Port Enabled/Disabled Change (PEC), RW1C
+                pub const PEC: u32 = 0x40000;
+                /// Warm Port Reset Change (WRC), RW1C
+                pub const WRC: u32 = 0x80000;
+                /// Over-current Change (OCC), RW1C
+                pub const OCC: u32 = 0x100000;
+                /// Port Reset Change (PRC), RW1C
+                pub const PRC: u32 = 0x200000;
+                /// Port Link State Change (PLC), RW1C
+                pub const PLC: u32 = 0x400000;
+                /// Port Config Error Change (CEC), RW1C
+                pub const CEC: u32 = 0x800000;
+                /// Cold Attach Status (CAS), RO
+                pub const CAS: u32 = 0x1000000;
+                /// Wake on Connect Enable (WCE), RW
+                pub const WCE: u32 = 0x2000000;
+                /// Wake on Disconnect Enable (WDE), RW
+                pub const WDE: u32 = 0x4000000;
+                /// Wake on Over-current Enable (WOE), RW
+                pub const WOE: u32 = 0x8000000;
+                /// Device Removable (DR), RO
+                pub const DR: u32 = 0x40000000;
+                /// Warm Port Reset (WPR), RW1S
+                pub const WPR: u32 = 0x80000000;
+
+                pub const RO: u32 = CCS | OCA | PORT_SPEED | CAS | DR;
+                pub const RW: u32 = PLS | PP | PIC | LWS | WCE | WDE | WOE;
+                pub const RW1C: u32 = PED | CSC | PEC | WRC | OCC | PRC | PLC | CEC;
+                pub const RW1S: u32 = PR | WPR;
+                /// The reserved bits, which always read 0. PORTSC has no
+                /// RsvdP bits.
+                pub const RSVDZ: u32 = 0x3000_0004;
+            }
+
+            /// The bits that report changes of the port. Each of them
+            /// causes a Port Status Change Event when it gets set.
+            pub const CHANGE_BITS: u32 = CSC | PEC | WRC | OCC | PRC | PLC | CEC;
+
+            /// Values of the Port Link State field, shifted by
+            /// [`PLS_SHIFT`].
+            pub mod link_state {
+                /// The link is up.
+                pub const U0: u32 = 0;
+            }
+        }
+
+        /// Fields of the Port Power Management Status and Control
+        /// Register. Its layout depends on the protocol of the port.
+        pub mod portpmsc {
+            pub mod usb3 {
+                pub const U1_TIMEOUT: u64 = 0xff;
+                pub const U2_TIMEOUT: u64 = 0xff00;
+                /// Force Link PM Accept (FLA)
+                pub const FLA: u64 = 0x10000;
+                pub const WRITABLE: u64 = U1_TIMEOUT | U2_TIMEOUT | FLA;
+            }
+
+            pub mod usb2 {
+                /// L1 Status (L1S), read-only
+                pub const L1S: u64 = 0x7;
+                /// Remote Wake Enable (RWE)
+                pub const RWE: u64 = 0x8;
+                /// Host Initiated Resume Duration (HIRD)
+                pub const HIRD: u64 = 0xf0;
+                pub const L1_DEVICE_SLOT: u64 = 0xff00;
+                /// Hardware LPM Enable (HLE)
+                pub const HLE: u64 = 0x10000;
+                pub const PORT_TEST_CONTROL: u64 = 0xf000_0000;
+                pub const WRITABLE: u64 = RWE | HIRD | L1_DEVICE_SLOT | HLE | PORT_TEST_CONTROL;
+            }
+        }
+
+        pub mod usbcmd {
+            pub const RS: u64 = 0x1;
+            pub const HCRST: u64 = 0x2;
+            pub const INTE: u64 = 0x4;
+            pub const LHCRST: u64 = 0x80;
         }
 
         pub mod usbsts {
@@ -367,7 +514,37 @@ pub mod xhci {
     /// Constants for the runtime registers.
     pub mod runtime {
         /// The default minimum interrupt interval of ~1ms (4000 * 250ns).
-        pub const IMOD_DEFAULT: u64 = 4000;
+        pub const IMOD_DEFAULT: u16 = 4000;
+
+        /// Fields of the Interrupter Moderation Register (IMOD).
+        pub mod imod {
+            use std::time::Duration;
+
+            /// Interrupt Moderation Interval (IMODI).
+            pub const IMODI: u64 = 0xffff;
+            /// Interrupt Moderation Counter (IMODC).
+            pub const IMODC: u64 = 0xffff_0000;
+            /// The time unit of both fields.
+            pub const TICK: Duration = Duration::from_nanos(250);
+        }
+
+        /// Bits of the Interrupter Management Register (IMAN).
+        pub mod iman {
+            /// Interrupt Pending (RW1C).
+            pub const IP: u64 = 0x1;
+            /// Interrupt Enable.
+            pub const IE: u64 = 0x2;
+        }
+
+        /// Bits of the Event Ring Dequeue Pointer Register (ERDP).
+        pub mod erdp {
+            /// Dequeue ERST Segment Index (DESI): the low bits of the index
+            /// of the segment the dequeue pointer points into.
+            pub const DESI: u64 = 0x7;
+            /// Event Handler Busy (RW1C).
+            pub const EHB: u64 = 0x8;
+            pub const DEQUEUE_POINTER_MASK: u64 = !0xfu64;
+        }
     }
 
     /// Constants for the rings
@@ -425,6 +602,9 @@ pub mod xhci {
 
     /// Constants specific to device slots and their context structures
     pub mod device_slots {
+        /// The highest USB device address. Address 0 is the default address
+        /// of devices that have not been addressed yet.
+        pub const MAX_USB_DEVICE_ADDRESS: u8 = 127;
         /// The slot state encoded in the slot context
         pub mod slot_state {
             pub const DISABLED_ENABLED: u8 = 0;
@@ -440,5 +620,9 @@ pub mod xhci {
             pub const STOPPED: u8 = 3;
             pub const ERROR: u8 = 4;
         }
+        /// The Stream Context Type (SCT) encoded in stream contexts
+        pub mod stream_context_type {
+            pub const PRIMARY_TRANSFER_RING: u8 = 1;
+        }
     }
 }
@@ -0,0 +1,157 @@
+//! # Device Context Index
+//!
+//! The XHCI specification names the endpoints of a device by their Device
+//! Context Index (DCI), the index of their context in the device context:
+//! 1 for the Default Control Endpoint, 2n for EPn OUT, and 2n + 1 for EPn
+//! IN. Doorbells, endpoint commands, and Transfer Events all carry DCIs,
+//! which the specification calls Endpoint IDs.
+//!
+//! USB devices know their endpoints by number and direction instead, which
+//! the endpoint address combines. [`Dci`] converts between the two, so
+//! they cannot be mixed up.
+
+use std::fmt;
+
+/// The direction of a USB endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the host to the device.
+    Out,
+    /// From the device to the host.
+    In,
+}
+
+/// The Device Context Index of an endpoint, from 1 to 31.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dci(u8);
+
+impl Dci {
+    /// The Default Control Endpoint.
+    pub const CONTROL: Self = Self(1);
+
+    /// The DCI with the value `dci`, if it is one.
+    pub const fn new(dci: u8) -> Option<Self> {
+        match dci {
+            1..=31 => Some(Self(dci)),
+            _ => None,
+        }
+    }
+
+    /// The DCI of endpoint `number` in `direction`.
+    ///
+    /// Endpoint 0 is the Default Control Endpoint in both directions.
+    pub const fn from_endpoint(number: u8, direction: Direction) -> Option<Self> {
+        match (number, direction) {
+            (0, _) => Some(Self::CONTROL),
+            (1..=15, Direction::Out) => Some(Self(2 * number)),
+            (1..=15, Direction::In) => Some(Self(2 * number + 1)),
+            _ => None,
+        }
+    }
+
+    /// The DCI of the endpoint with the USB endpoint `address`, which has
+    /// the number in bits 3:0 and bit 7 set for IN endpoints.
+    pub const fn from_address(address: u8) -> Option<Self> {
+        if address & 0x70 != 0 {
+            return None;
+        }
+        let direction = if address & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        };
+        Self::from_endpoint(address & 0x0f, direction)
+    }
+
+    /// All DCIs other than the Default Control Endpoint, in ascending order.
+    pub fn non_control() -> impl Iterator<Item = Self> {
+        (2..=31).map(Self)
+    }
+
+    /// The value of the DCI.
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// The endpoint number.
+    pub const fn number(self) -> u8 {
+        self.0 / 2
+    }
+
+    /// The direction of the endpoint, or `None` for the bidirectional
+    /// Default Control Endpoint.
+    pub const fn direction(self) -> Option<Direction> {
+        match self.0 {
+            1 => None,
+            dci if dci % 2 == 0 => Some(Direction::Out),
+            _ => Some(Direction::In),
+        }
+    }
+
+    /// The USB endpoint address.
+    pub const fn address(self) -> u8 {
+        match self.direction() {
+            Some(Direction::In) => 0x80 | self.number(),
+            _ => self.number(),
+        }
+    }
+}
+
+impl From<Dci> for u8 {
+    fn from(dci: Dci) -> Self {
+        dci.0
+    }
+}
+
+impl fmt::Display for Dci {
+    /// Print the DCI as a number, which is how the specification and our
+    /// logs name endpoints.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_context_indices_of_endpoints_are_dcis() {
+        assert_eq!(Dci::new(0), None);
+        assert_eq!(Dci::new(1), Some(Dci::CONTROL));
+        assert_eq!(Dci::new(31).map(Dci::get), Some(31));
+        assert_eq!(Dci::new(32), None);
+        assert_eq!(Dci::non_control().count(), 30);
+    }
+
+    #[test]
+    fn endpoints_convert_to_dcis_and_back() {
+        for number in 1..=15 {
+            for (direction, dci) in [
+                (Direction::Out, 2 * number),
+                (Direction::In, 2 * number + 1),
+            ] {
+                let converted = Dci::from_endpoint(number, direction).unwrap();
+                assert_eq!(converted.get(), dci);
+                assert_eq!(converted.number(), number);
+                assert_eq!(converted.direction(), Some(direction));
+                assert_eq!(Dci::from_address(converted.address()), Some(converted));
+            }
+        }
+        assert_eq!(Dci::from_endpoint(16, Direction::In), None);
+    }
+
+    #[test]
+    fn endpoint_addresses_map_to_dcis() {
+        assert_eq!(Dci::from_address(0x01).map(Dci::get), Some(2));
+        assert_eq!(Dci::from_address(0x81).map(Dci::get), Some(3));
+        assert_eq!(Dci::from_address(0x8f).map(Dci::get), Some(31));
+        // Both directions of endpoint 0 are the Default Control Endpoint.
+        assert_eq!(Dci::from_address(0x00), Some(Dci::CONTROL));
+        assert_eq!(Dci::from_address(0x80), Some(Dci::CONTROL));
+        assert_eq!(Dci::CONTROL.address(), 0);
+        assert_eq!(Dci::CONTROL.direction(), None);
+        // Bits 6:4 are reserved.
+        assert_eq!(Dci::from_address(0x11), None);
+    }
+}
@@ -0,0 +1,713 @@
+//! # USB Descriptors
+//!
+//! A USB device describes itself and its endpoints with descriptors. The
+//! driver reads them with GET_DESCRIPTOR requests, which we forward to the
+//! device, and builds the endpoint contexts from them. The controller
+//! parses the descriptors as well, so it can check what the driver
+//! configures against what the device offers.
+//!
+//! Only the fields the controller needs are kept. Class-specific
+//! descriptors, e.g., HID descriptors, are skipped.
+//!
+//! ## SuperSpeed Devices on USB 2 Ports
+//!
+//! A SuperSpeed device that we attach to a USB 2 port shows up as a High
+//! Speed device. Its descriptors still say otherwise: `bcdUSB` is 3.x, the
+//! Default Control Endpoint has a max packet size of 512 bytes, bulk
+//! endpoints have 1024 bytes, and every endpoint is followed by a
+//! SuperSpeed Endpoint Companion descriptor. [`usb2_device_descriptor`] and
+//! [`usb2_configuration_descriptor`] rewrite them into the descriptors of a
+//! well-formed High Speed device.
+
+use thiserror::Error;
+
+use super::{
+    dci::{Dci, Direction},
+    realdevice::EndpointType,
+};
+
+/// The descriptor types in `bDescriptorType`, which GET_DESCRIPTOR
+/// requests carry in the high byte of `wValue`.
+pub mod descriptor_type {
+    pub const DEVICE: u8 = 0x01;
+    pub const CONFIGURATION: u8 = 0x02;
+    pub const STRING: u8 = 0x03;
+    pub const INTERFACE: u8 = 0x04;
+    pub const ENDPOINT: u8 = 0x05;
+    pub const HID: u8 = 0x21;
+    pub const REPORT: u8 = 0x22;
+    pub const SUPERSPEED_ENDPOINT_COMPANION: u8 = 0x30;
+    pub const SUPERSPEEDPLUS_ISOCHRONOUS_ENDPOINT_COMPANION: u8 = 0x31;
+}
+
+/// The highest USB version a device on a USB 2 port reports in `bcdUSB`.
+const USB2_VERSION: u16 = 0x0210;
+
+/// The max packet size of the Default Control Endpoint of High Speed
+/// devices.
+const USB2_MAX_PACKET_SIZE_0: u8 = 64;
+
+/// The length of a device descriptor.
+const DEVICE_DESCRIPTOR_LEN: usize = 18;
+
+/// The length of a configuration descriptor without the descriptors that
+/// follow it.
+const CONFIGURATION_DESCRIPTOR_LEN: usize = 9;
+
+/// The length of an interface descriptor.
+const INTERFACE_DESCRIPTOR_LEN: usize = 9;
+
+/// The length of an endpoint descriptor.
+const ENDPOINT_DESCRIPTOR_LEN: usize = 7;
+
+/// Why descriptor bytes could not be parsed.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    #[error("descriptor at offset {offset} needs {needed} bytes, but only {available} are left")]
+    Truncated {
+        offset: usize,
+        needed: usize,
+        available: usize,
+    },
+    #[error("descriptor at offset {offset} has type {found:#04x} instead of {expected:#04x}")]
+    UnexpectedType {
+        offset: usize,
+        expected: u8,
+        found: u8,
+    },
+    #[error("descriptor at offset {offset} is too short with bLength {length}")]
+    InvalidLength { offset: usize, length: u8 },
+    #[error("endpoint descriptor at offset {0} precedes all interface descriptors")]
+    EndpointOutsideInterface(usize),
+}
+
+/// The descriptor of type `expected` at `offset` in `bytes`, which has to
+/// be at least `min_len` bytes long.
+fn descriptor_at(
+    bytes: &[u8],
+    offset: usize,
+    expected: u8,
+    min_len: usize,
+) -> Result<&[u8], DescriptorError> {
+    let descriptor = next_descriptor(bytes, offset)?;
+    if descriptor[1] != expected {
+        return Err(DescriptorError::UnexpectedType {
+            offset,
+            expected,
+            found: descriptor[1],
+        });
+    }
+    if descriptor.len() < min_len {
+        return Err(DescriptorError::InvalidLength {
+            offset,
+            length: descriptor[0],
+        });
+    }
+    Ok(descriptor)
+}
+
+/// The descriptor of any type at `offset` in `bytes`, as long as its
+/// `bLength` says.
+fn next_descriptor(bytes: &[u8], offset: usize) -> Result<&[u8], DescriptorError> {
+    let rest = bytes.get(offset..).unwrap_or_default();
+    let length = *rest.first().ok_or(DescriptorError::Truncated {
+        offset,
+        needed: 2,
+        available: 0,
+    })?;
+    if length < 2 {
+        return Err(DescriptorError::InvalidLength { offset, length });
+    }
+    rest.get(..usize::from(length))
+        .ok_or_else(|| DescriptorError::Truncated {
+            offset,
+            needed: length.into(),
+            available: rest.len(),
+        })
+}
+
+const fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// The device descriptor, which identifies the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// The USB version in BCD (`bcdUSB`), e.g., 0x0200 for USB 2.0.
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// The max packet size of the Default Control Endpoint in bytes, or
+    /// its exponent for SuperSpeed devices.
+    pub max_packet_size_0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The device release number in BCD (`bcdDevice`).
+    pub device_version: u16,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    /// Parse the device descriptor at the start of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, DescriptorError> {
+        let bytes = descriptor_at(bytes, 0, descriptor_type::DEVICE, DEVICE_DESCRIPTOR_LEN)?;
+        Ok(Self {
+            usb_version: le16(bytes, 2),
+            class: bytes[4],
+            subclass: bytes[5],
+            protocol: bytes[6],
+            max_packet_size_0: bytes[7],
+            vendor_id: le16(bytes, 8),
+            product_id: le16(bytes, 10),
+            device_version: le16(bytes, 12),
+            num_configurations: bytes[17],
+        })
+    }
+}
+
+/// How an endpoint transfers data, from bits 1:0 of `bmAttributes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+/// An endpoint of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    /// The endpoint number in bits 3:0 and the direction in bit 7
+    /// (`bEndpointAddress`).
+    pub address: u8,
+    /// The transfer type and, for isochronous endpoints, synchronization
+    /// and usage (`bmAttributes`).
+    pub attributes: u8,
+    /// The raw `wMaxPacketSize`, which holds the additional transactions
+    /// per microframe of High Speed endpoints in bits 12:11.
+    pub max_packet_size: u16,
+    /// The service interval, in frames or as an exponent depending on
+    /// speed and transfer type (`bInterval`).
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            address: bytes[2],
+            attributes: bytes[3],
+            max_packet_size: le16(bytes, 4),
+            interval: bytes[6],
+        }
+    }
+
+    /// The DCI of the endpoint, if its address is valid.
+    pub const fn dci(&self) -> Option<Dci> {
+        Dci::from_address(self.address)
+    }
+
+    /// The direction data moves on the endpoint.
+    pub const fn direction(&self) -> Direction {
+        if self.address & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+
+    pub const fn transfer_type(&self) -> TransferType {
+        match self.attributes & 0x3 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+
+    /// The size of a packet in bytes, without additional transactions.
+    pub const fn packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7ff
+    }
+
+    /// The largest packet size High Speed allows for the transfer type of
+    /// the endpoint.
+    pub const fn usb2_max_packet_size(&self) -> u16 {
+        match self.transfer_type() {
+            TransferType::Control => 64,
+            TransferType::Bulk => 512,
+            TransferType::Isochronous | TransferType::Interrupt => 1024,
+        }
+    }
+
+    /// The type of the endpoint as an endpoint context would encode it.
+    pub const fn endpoint_type(&self) -> EndpointType {
+        match (self.transfer_type(), self.direction()) {
+            (TransferType::Control, _) => EndpointType::Control,
+            (TransferType::Isochronous, Direction::Out) => EndpointType::IsochOut,
+            (TransferType::Isochronous, Direction::In) => EndpointType::IsochIn,
+            (TransferType::Bulk, Direction::Out) => EndpointType::BulkOut,
+            (TransferType::Bulk, Direction::In) => EndpointType::BulkIn,
+            (TransferType::Interrupt, Direction::Out) => EndpointType::InterruptOut,
+            (TransferType::Interrupt, Direction::In) => EndpointType::InterruptIn,
+        }
+    }
+}
+
+/// An alternate setting of an interface and its endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+/// A configuration of a device with all its interfaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationDescriptor {
+    /// The value that selects the configuration in SET_CONFIGURATION
+    /// (`bConfigurationValue`).
+    pub configuration_value: u8,
+    /// Whether the device is self-powered and supports remote wakeup
+    /// (`bmAttributes`).
+    pub attributes: u8,
+    /// The maximum power draw in units of 2 mA, or 8 mA for SuperSpeed
+    /// devices (`bMaxPower`).
+    pub max_power: u8,
+    /// All alternate settings of all interfaces, in the order the device
+    /// describes them.
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl ConfigurationDescriptor {
+    /// Parse a configuration descriptor with the descriptors that follow
+    /// it, as returned by GET_DESCRIPTOR.
+    ///
+    /// Bytes beyond `wTotalLength` are ignored.
+    pub fn parse(bytes: &[u8]) -> Result<Self, DescriptorError> {
+        let header = descriptor_at(
+            bytes,
+            0,
+            descriptor_type::CONFIGURATION,
+            CONFIGURATION_DESCRIPTOR_LEN,
+        )?;
+        let total_length = usize::from(le16(header, 2));
+        let bytes = bytes
+            .get(..total_length)
+            .ok_or(DescriptorError::Truncated {
+                offset: 0,
+                needed: total_length,
+                available: bytes.len(),
+            })?;
+
+        let mut interfaces: Vec<InterfaceDescriptor> = Vec::new();
+        let mut offset = header.len();
+        while offset < bytes.len() {
+            let descriptor = next_descriptor(bytes, offset)?;
+            match descriptor[1] {
+                descriptor_type::INTERFACE => {
+                    let descriptor = descriptor_at(
+                        bytes,
+                        offset,
+                        descriptor_type::INTERFACE,
+                        INTERFACE_DESCRIPTOR_LEN,
+                    )?;
+                    interfaces.push(InterfaceDescriptor {
+                        interface_number: descriptor[2],
+                        alternate_setting: descriptor[3],
+                        class: descriptor[5],
+                        subclass: descriptor[6],
+                        protocol: descriptor[7],
+                        endpoints: Vec::new(),
+                    });
+                }
+                descriptor_type::ENDPOINT => {
+                    let descriptor = descriptor_at(
+                        bytes,
+                        offset,
+                        descriptor_type::ENDPOINT,
+                        ENDPOINT_DESCRIPTOR_LEN,
+                    )?;
+                    interfaces
+                        .last_mut()
+                        .ok_or(DescriptorError::EndpointOutsideInterface(offset))?
+                        .endpoints
+                        .push(EndpointDescriptor::parse(descriptor));
+                }
+                _ => {}
+            }
+            offset += descriptor.len();
+        }
+
+        Ok(Self {
+            configuration_value: header[5],
+            attributes: header[7],
+            max_power: header[8],
+            interfaces,
+        })
+    }
+
+    /// The endpoint with the DCI `endpoint_id` in any interface.
+    ///
+    /// Alternate settings of an interface may describe the same endpoint
+    /// differently, in which case the first one wins.
+    pub fn endpoint(&self, endpoint_id: Dci) -> Option<&EndpointDescriptor> {
+        self.interfaces
+            .iter()
+            .flat_map(|interface| &interface.endpoints)
+            .find(|endpoint| endpoint.dci() == Some(endpoint_id))
+    }
+}
+
+/// Rewrite the device descriptor at the start of `bytes` for a device on a
+/// USB 2 port.
+///
+/// `bcdUSB` is clamped to 2.10. Devices that reported USB 3 give the max
+/// packet size of the Default Control Endpoint as an exponent, which
+/// becomes the 64 bytes of High Speed devices.
+pub fn usb2_device_descriptor(bytes: &[u8]) -> Result<Vec<u8>, DescriptorError> {
+    let mut descriptor =
+        descriptor_at(bytes, 0, descriptor_type::DEVICE, DEVICE_DESCRIPTOR_LEN)?.to_vec();
+    let usb_version = le16(&descriptor, 2);
+    if usb_version > USB2_VERSION {
+        descriptor[2..4].copy_from_slice(&USB2_VERSION.to_le_bytes());
+    }
+    if usb_version >= 0x0300 {
+        descriptor[7] = USB2_MAX_PACKET_SIZE_0;
+    }
+    Ok(descriptor)
+}
+
+/// Rewrite a configuration descriptor with the descriptors that follow it
+/// for a device on a USB 2 port.
+///
+/// SuperSpeed endpoint companion descriptors are dropped, endpoints get
+/// packet sizes High Speed allows, and `wTotalLength` covers what is left.
+/// Bytes beyond `wTotalLength` are ignored, like in
+/// [`ConfigurationDescriptor::parse`].
+pub fn usb2_configuration_descriptor(bytes: &[u8]) -> Result<Vec<u8>, DescriptorError> {
+    let header = descriptor_at(
+        bytes,
+        0,
+        descriptor_type::CONFIGURATION,
+        CONFIGURATION_DESCRIPTOR_LEN,
+    )?;
+    let total_length = usize::from(le16(header, 2));
+    let bytes = bytes
+        .get(..total_length)
+        .ok_or(DescriptorError::Truncated {
+            offset: 0,
+            needed: total_length,
+            available: bytes.len(),
+        })?;
+
+    let mut rewritten = header.to_vec();
+    let mut offset = header.len();
+    while offset < bytes.len() {
+        let descriptor = next_descriptor(bytes, offset)?;
+        match descriptor[1] {
+            descriptor_type::SUPERSPEED_ENDPOINT_COMPANION
+            | descriptor_type::SUPERSPEEDPLUS_ISOCHRONOUS_ENDPOINT_COMPANION => {}
+            descriptor_type::ENDPOINT => {
+                let descriptor = descriptor_at(
+                    bytes,
+                    offset,
+                    descriptor_type::ENDPOINT,
+                    ENDPOINT_DESCRIPTOR_LEN,
+                )?;
+                let endpoint = EndpointDescriptor::parse(descriptor);
+                // SuperSpeed endpoints keep their bursts in the companion,
+                // so there are no additional transactions to carry over.
+                let max_packet_size = endpoint.packet_size().min(endpoint.usb2_max_packet_size());
+                let start = rewritten.len();
+                rewritten.extend_from_slice(descriptor);
+                rewritten[start + 4..start + 6].copy_from_slice(&max_packet_size.to_le_bytes());
+            }
+            _ => rewritten.extend_from_slice(descriptor),
+        }
+        offset += descriptor.len();
+    }
+
+    // The rewritten descriptors are never longer than the original ones.
+    let total_length = u16::try_from(rewritten.len()).unwrap();
+    rewritten[2..4].copy_from_slice(&total_length.to_le_bytes());
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration with a HID interface that has an Interrupt IN
+    /// endpoint, and a second interface with two alternate settings, the
+    /// second of which has bulk endpoints.
+    const CONFIGURATION: [u8; 59] = [
+        // Configuration
+        9, 0x02, 59, 0, 2, 1, 0, 0xa0, 50, //
+        // Interface 0
+        9, 0x04, 0, 0, 1, 0x03, 0x01, 0x02, 0, //
+        // HID
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 52, 0, //
+        // EP1 IN, Interrupt, 8 bytes, every 10 frames
+        7, 0x05, 0x81, 0x03, 8, 0, 10, //
+        // Interface 1, alternate setting 0
+        9, 0x04, 1, 0, 0, 0x08, 0x06, 0x50, 0, //
+        // Interface 1, alternate setting 1
+        9, 0x04, 1, 1, 2, 0x08, 0x06, 0x50, 0, //
+        // EP2 OUT, Bulk, 512 bytes
+        7, 0x05, 0x02, 0x02, 0x00, 0x02, 0, //
+    ];
+
+    /// A SuperSpeed mass storage device with bulk endpoints and an
+    /// Interrupt IN endpoint, each followed by its companion.
+    const SUPERSPEED_CONFIGURATION: [u8; 57] = [
+        // Configuration
+        9, 0x02, 57, 0, 1, 1, 0, 0x80, 112, //
+        // Interface 0
+        9, 0x04, 0, 0, 3, 0x08, 0x06, 0x62, 0, //
+        // EP1 IN, Bulk, 1024 bytes, bursts of 4
+        7, 0x05, 0x81, 0x02, 0x00, 0x04, 0, //
+        6, 0x30, 3, 0, 0, 0, //
+        // EP2 OUT, Bulk, 1024 bytes, bursts of 4, 32 streams
+        7, 0x05, 0x02, 0x02, 0x00, 0x04, 0, //
+        6, 0x30, 3, 5, 0, 0, //
+        // EP3 IN, Interrupt, 16 bytes, every 2^(4-1) microframes
+        7, 0x05, 0x83, 0x03, 16, 0, 4, //
+        6, 0x30, 0, 0, 16, 0, //
+    ];
+
+    #[test]
+    fn device_descriptors_are_parsed() {
+        let descriptor = DeviceDescriptor::parse(&[
+            18, 0x01, 0x10, 0x02, 0, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 1,
+        ])
+        .unwrap();
+        assert_eq!(descriptor.usb_version, 0x0210);
+        assert_eq!(descriptor.max_packet_size_0, 64);
+        assert_eq!(
+            (descriptor.vendor_id, descriptor.product_id),
+            (0x1234, 0x5678)
+        );
+        assert_eq!(descriptor.device_version, 0x0100);
+        assert_eq!(descriptor.num_configurations, 1);
+    }
+
+    #[test]
+    fn malformed_device_descriptors_are_rejected() {
+        assert_eq!(
+            DeviceDescriptor::parse(&[18, 0x01, 0x00, 0x02]),
+            Err(DescriptorError::Truncated {
+                offset: 0,
+                needed: 18,
+                available: 4
+            })
+        );
+        assert_eq!(
+            DeviceDescriptor::parse(&CONFIGURATION),
+            Err(DescriptorError::UnexpectedType {
+                offset: 0,
+                expected: descriptor_type::DEVICE,
+                found: descriptor_type::CONFIGURATION
+            })
+        );
+        assert_eq!(
+            DeviceDescriptor::parse(&[9, 0x01, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DescriptorError::InvalidLength {
+                offset: 0,
+                length: 9
+            })
+        );
+        assert!(DeviceDescriptor::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn configurations_collect_interfaces_and_endpoints() {
+        let configuration = ConfigurationDescriptor::parse(&CONFIGURATION).unwrap();
+        assert_eq!(configuration.configuration_value, 1);
+        assert_eq!(configuration.max_power, 50);
+
+        let settings: Vec<_> = configuration
+            .interfaces
+            .iter()
+            .map(|interface| {
+                (
+                    interface.interface_number,
+                    interface.alternate_setting,
+                    interface.endpoints.len(),
+                )
+            })
+            .collect();
+        assert_eq!(settings, [(0, 0, 1), (1, 0, 0), (1, 1, 1)]);
+
+        // The HID descriptor between interface and endpoint is skipped.
+        let interrupt_in = configuration.endpoint(Dci::new(3).unwrap()).unwrap();
+        assert_eq!(interrupt_in.transfer_type(), TransferType::Interrupt);
+        assert_eq!(interrupt_in.endpoint_type(), EndpointType::InterruptIn);
+        assert_eq!(interrupt_in.interval, 10);
+
+        let bulk_out = configuration.endpoint(Dci::new(4).unwrap()).unwrap();
+        assert_eq!(bulk_out.direction(), Direction::Out);
+        assert_eq!(bulk_out.endpoint_type(), EndpointType::BulkOut);
+        assert_eq!(bulk_out.packet_size(), 512);
+
+        assert_eq!(configuration.endpoint(Dci::new(5).unwrap()), None);
+    }
+
+    #[test]
+    fn isochronous_and_interrupt_out_endpoints_have_endpoint_types() {
+        let endpoint = |address, attributes| EndpointDescriptor {
+            address,
+            attributes,
+            max_packet_size: 64,
+            interval: 1,
+        };
+        assert_eq!(
+            endpoint(0x03, 0x03).endpoint_type(),
+            EndpointType::InterruptOut
+        );
+        assert_eq!(endpoint(0x84, 0x01).endpoint_type(), EndpointType::IsochIn);
+        assert_eq!(endpoint(0x05, 0x01).endpoint_type(), EndpointType::IsochOut);
+        assert_eq!(
+            endpoint(0x84, 0x01).transfer_type(),
+            TransferType::Isochronous
+        );
+        assert_eq!(endpoint(0x70, 0x02).dci(), None);
+        // High Speed high-bandwidth endpoints keep the additional
+        // transactions out of the packet size.
+        let high_bandwidth = EndpointDescriptor {
+            max_packet_size: 2 << 11 | 1024,
+            ..endpoint(0x81, 0x03)
+        };
+        assert_eq!(high_bandwidth.packet_size(), 1024);
+    }
+
+    #[test]
+    fn malformed_configurations_are_rejected() {
+        // wTotalLength beyond the data.
+        assert!(matches!(
+            ConfigurationDescriptor::parse(&CONFIGURATION[..40]),
+            Err(DescriptorError::Truncated { offset: 0, .. })
+        ));
+
+        // A descriptor with bLength 0 would never end.
+        let mut zero_length = CONFIGURATION;
+        zero_length[9] = 0;
+        assert_eq!(
+            ConfigurationDescriptor::parse(&zero_length),
+            Err(DescriptorError::InvalidLength {
+                offset: 9,
+                length: 0
+            })
+        );
+
+        // An endpoint without an interface.
+        let mut orphan = [0u8; 16];
+        orphan[..9].copy_from_slice(&[9, 0x02, 16, 0, 0, 1, 0, 0x80, 50]);
+        orphan[9..].copy_from_slice(&[7, 0x05, 0x81, 0x03, 8, 0, 10]);
+        assert_eq!(
+            ConfigurationDescriptor::parse(&orphan),
+            Err(DescriptorError::EndpointOutsideInterface(9))
+        );
+
+        // Bytes beyond wTotalLength are not part of the configuration.
+        let mut trailing = CONFIGURATION.to_vec();
+        trailing.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(
+            ConfigurationDescriptor::parse(&trailing),
+            ConfigurationDescriptor::parse(&CONFIGURATION)
+        );
+    }
+
+    #[test]
+    fn superspeed_device_descriptors_become_high_speed() {
+        let superspeed = [
+            18, 0x01, 0x20, 0x03, 0, 0, 0, 9, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 1,
+        ];
+        assert_eq!(
+            usb2_device_descriptor(&superspeed).unwrap(),
+            [18, 0x01, 0x10, 0x02, 0, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 1]
+        );
+
+        // USB 2 devices keep their descriptor, trailing bytes are cut off.
+        let mut high_speed = superspeed.to_vec();
+        high_speed[2..4].copy_from_slice(&[0x00, 0x02]);
+        high_speed[7] = 64;
+        let mut trailing = high_speed.clone();
+        trailing.extend_from_slice(&[0; 8]);
+        assert_eq!(usb2_device_descriptor(&trailing).unwrap(), high_speed);
+
+        assert!(usb2_device_descriptor(&superspeed[..8]).is_err());
+    }
+
+    #[test]
+    fn superspeed_configurations_lose_their_companions() {
+        let rewritten = usb2_configuration_descriptor(&SUPERSPEED_CONFIGURATION).unwrap();
+        assert_eq!(
+            rewritten,
+            [
+                // Configuration with the new wTotalLength
+                9, 0x02, 39, 0, 1, 1, 0, 0x80, 112, //
+                // Interface 0
+                9, 0x04, 0, 0, 3, 0x08, 0x06, 0x62, 0, //
+                // EP1 IN, Bulk, 512 bytes
+                7, 0x05, 0x81, 0x02, 0x00, 0x02, 0, //
+                // EP2 OUT, Bulk, 512 bytes
+                7, 0x05, 0x02, 0x02, 0x00, 0x02, 0, //
+                // EP3 IN, Interrupt, 16 bytes
+                7, 0x05, 0x83, 0x03, 16, 0, 4, //
+            ]
+        );
+
+        // The result is a well-formed configuration with the same
+        // endpoints.
+        let configuration = ConfigurationDescriptor::parse(&rewritten).unwrap();
+        let original = ConfigurationDescriptor::parse(&SUPERSPEED_CONFIGURATION).unwrap();
+        assert_eq!(configuration.interfaces.len(), 1);
+        let endpoints: Vec<_> = configuration.interfaces[0]
+            .endpoints
+            .iter()
+            .map(|endpoint| (endpoint.address, endpoint.packet_size()))
+            .collect();
+        assert_eq!(endpoints, [(0x81, 512), (0x02, 512), (0x83, 16)]);
+        assert_eq!(
+            configuration.endpoint(Dci::new(7).unwrap()),
+            original.endpoint(Dci::new(7).unwrap())
+        );
+    }
+
+    #[test]
+    fn usb2_configurations_stay_the_same() {
+        assert_eq!(
+            usb2_configuration_descriptor(&CONFIGURATION).unwrap(),
+            CONFIGURATION
+        );
+        // Bytes beyond wTotalLength are not part of the configuration.
+        let mut trailing = CONFIGURATION.to_vec();
+        trailing.extend_from_slice(&[0; 16]);
+        assert_eq!(
+            usb2_configuration_descriptor(&trailing).unwrap(),
+            CONFIGURATION
+        );
+    }
+
+    #[test]
+    fn malformed_configurations_are_not_rewritten() {
+        assert!(matches!(
+            usb2_configuration_descriptor(&SUPERSPEED_CONFIGURATION[..40]),
+            Err(DescriptorError::Truncated { offset: 0, .. })
+        ));
+
+        let mut short_endpoint = SUPERSPEED_CONFIGURATION;
+        short_endpoint[18] = 4;
+        assert_eq!(
+            usb2_configuration_descriptor(&short_endpoint),
+            Err(DescriptorError::InvalidLength {
+                offset: 18,
+                length: 4
+            })
+        );
+    }
+}
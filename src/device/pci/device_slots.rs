@@ -2,15 +2,32 @@
 //!
 //! This module offers an abstraction for device slots.
 
-use tracing::debug;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{debug, warn};
 
 use crate::device::{
     bus::{BusDeviceRef, Request, RequestSize},
-    pci::constants::xhci::device_slots::{endpoint_state, slot_state},
+    pci::constants::xhci::device_slots::{
+        endpoint_state, slot_state, stream_context_type, MAX_USB_DEVICE_ADDRESS,
+    },
 };
 
 use super::{
-    constants::xhci::device_slots::endpoint_state::*, realdevice::EndpointType, rings::TransferRing,
+    commands::CommandError,
+    constants::xhci::device_slots::endpoint_state::*,
+    dci::Dci,
+    paranoid_dma::{self, DmaOrigin},
+    realdevice::{EndpointType, Speed},
+    rings::{EndpointRing, TransferRing, TransferRingError},
+    transfer_unit::TransferUnit,
+    trb::TransferTrb,
 };
 
 /// Abstraction for Device Slots.
@@ -27,18 +44,73 @@ use super::{
 /// HCSPARAMS1 register. For device initialization, the driver requests a slot
 /// ID using the Enable Slot Command. The `DeviceSlotManager` is responsible
 /// of tracking which slot IDs are currently in use.
-#[derive(Debug, Clone)]
+///
+/// The manager also assigns the USB device addresses. The controller, not
+/// the driver, picks the address of a device on the Address Device Command,
+/// so we need to track which addresses the slots hold.
+///
+/// The DCBAA entries rarely change after Address Device, so the manager
+/// caches the device context pointers instead of reading the DCBAA on every
+/// command and doorbell. See [`DeviceContextCache`].
+#[derive(Debug)]
 pub struct DeviceSlotManager {
     /// Number of available slots.
     pub num_slots: u64,
     /// Slots that are currently in use.
     used_slots: Vec<u64>,
+    /// USB device addresses that are currently in use, as pairs of slot ID
+    /// and address.
+    usb_addresses: Vec<(u8, u8)>,
     /// DMA address of the device context base address array.
     dcbaap: u64,
+    /// The device context pointers read from the DCBAA.
+    context_cache: Mutex<DeviceContextCache>,
     /// Reference to the guest memory.
     dma_bus: BusDeviceRef,
 }
 
+/// How many device context lookups pass before a cached pointer is
+/// compared against the DCBAA again.
+///
+/// Drivers are free to move device contexts by rewriting the DCBAA entry.
+/// Nobody does in practice, so an occasional check is enough to notice.
+const DEVICE_CONTEXT_REVALIDATION_INTERVAL: u32 = 64;
+
+/// Cached DCBAA entries.
+#[derive(Debug)]
+struct DeviceContextCache {
+    /// Pairs of slot ID and device context pointer.
+    pointers: Vec<(u8, u64)>,
+    /// The number of lookups, to revalidate every
+    /// [`DEVICE_CONTEXT_REVALIDATION_INTERVAL`] lookups.
+    lookups: u32,
+}
+
+impl DeviceContextCache {
+    const fn new() -> Self {
+        Self {
+            pointers: Vec::new(),
+            lookups: 0,
+        }
+    }
+
+    fn get(&self, slot_id: u8) -> Option<u64> {
+        self.pointers
+            .iter()
+            .find(|(id, _)| *id == slot_id)
+            .map(|&(_, pointer)| pointer)
+    }
+
+    fn insert(&mut self, slot_id: u8, pointer: u64) {
+        self.remove(slot_id);
+        self.pointers.push((slot_id, pointer));
+    }
+
+    fn remove(&mut self, slot_id: u8) {
+        self.pointers.retain(|(id, _)| *id != slot_id);
+    }
+}
+
 impl DeviceSlotManager {
     /// Construct a new instance.
     ///
@@ -54,7 +126,9 @@ impl DeviceSlotManager {
         Self {
             num_slots,
             used_slots: Vec::new(),
+            usb_addresses: Vec::new(),
             dcbaap: 0,
+            context_cache: Mutex::new(DeviceContextCache::new()),
             dma_bus,
         }
     }
@@ -62,8 +136,9 @@ impl DeviceSlotManager {
     /// Set the address to the DCBAA.
     ///
     /// Call this function on writes to the DCBAAP MMIO register.
-    pub const fn set_dcbaap(&mut self, dcbaap: u64) {
+    pub fn set_dcbaap(&mut self, dcbaap: u64) {
         self.dcbaap = dcbaap;
+        self.invalidate_all_device_contexts();
     }
 
     pub const fn get_dcbaap(&self) -> u64 {
@@ -88,6 +163,57 @@ impl DeviceSlotManager {
         available_slot_id
     }
 
+    /// Check whether any slot is in use.
+    pub const fn has_reserved_slots(&self) -> bool {
+        !self.used_slots.is_empty()
+    }
+
+    /// Release all slots along with their USB device addresses and cached
+    /// device contexts.
+    pub fn release_all_slots(&mut self) {
+        self.used_slots.clear();
+        self.release_all_usb_addresses();
+        self.invalidate_all_device_contexts();
+    }
+
+    /// Check whether a slot ID was handed out by [`Self::reserve_slot`].
+    pub fn is_reserved(&self, slot_id: u8) -> bool {
+        self.used_slots.contains(&u64::from(slot_id))
+    }
+
+    /// Assign a USB device address to a slot.
+    ///
+    /// Returns the lowest free address in `1..=127`, or the address the slot
+    /// already holds. Returns `Option::None` if all addresses are in use.
+    ///
+    /// Like slot reservation, this has linear time complexity.
+    pub fn assign_usb_address(&mut self, slot_id: u8) -> Option<u8> {
+        if let Some(&(_, address)) = self.usb_addresses.iter().find(|(id, _)| *id == slot_id) {
+            return Some(address);
+        }
+
+        let available_address = (1..=MAX_USB_DEVICE_ADDRESS)
+            .find(|address| !self.usb_addresses.iter().any(|(_, a)| a == address));
+
+        if let Some(address) = available_address {
+            self.usb_addresses.push((slot_id, address));
+        }
+
+        available_address
+    }
+
+    /// Release the USB device address of a slot, if it holds one.
+    pub fn release_usb_address(&mut self, slot_id: u8) {
+        self.usb_addresses.retain(|(id, _)| *id != slot_id);
+    }
+
+    /// Release the USB device addresses of all slots.
+    ///
+    /// Call this function on controller reset.
+    pub fn release_all_usb_addresses(&mut self) {
+        self.usb_addresses.clear();
+    }
+
     /// Retrieve a device context abstraction.
     ///
     /// Device context are referenced by the DCBAA and indexed by the slot ID.
@@ -100,17 +226,108 @@ impl DeviceSlotManager {
     /// - slot_id: the slot ID for which the DeviceContext is requested.
     pub fn get_device_context(&self, slot_id: u8) -> DeviceContext {
         assert!(
-            self.used_slots.contains(&(slot_id as u64)),
+            self.is_reserved(slot_id),
             "requested DeviceContext for unassigned slot_id"
         );
+        let device_context_address = self.device_context_address(slot_id);
+
+        DeviceContext::new(device_context_address, self.dma_bus.clone())
+    }
+
+    /// Look up the address of a device context, preferably in the cache.
+    ///
+    /// Null pointers are not cached, as the driver has not set up the
+    /// device context yet.
+    fn device_context_address(&self, slot_id: u8) -> u64 {
+        let mut cache = self.context_cache.lock().unwrap();
+        cache.lookups = cache.lookups.wrapping_add(1);
+        let revalidate = cache
+            .lookups
+            .is_multiple_of(DEVICE_CONTEXT_REVALIDATION_INTERVAL);
+        let cached = cache.get(slot_id);
+        if let (Some(address), false) = (cached, revalidate) {
+            return address;
+        }
+
         // lookup address of device context in device context base address array
         let device_context_address = self.dma_bus.read(Request::new(
             self.dcbaap.wrapping_add(slot_id as u64 * 8),
             RequestSize::Size8,
         ));
+        if cached.is_some_and(|address| address != device_context_address) {
+            warn!(
+                "device context of slot {} moved from {:#x} to {:#x} without notice",
+                slot_id,
+                cached.unwrap(),
+                device_context_address
+            );
+        }
 
-        DeviceContext::new(device_context_address, self.dma_bus.clone())
+        if device_context_address == 0 {
+            cache.remove(slot_id);
+        } else {
+            cache.insert(slot_id, device_context_address);
+        }
+        device_context_address
+    }
+
+    /// Forget the cached device context pointer of a slot.
+    ///
+    /// Call this function when the driver may hand over a new device
+    /// context, i.e., on Address Device, Reset Device and Disable Slot.
+    pub fn invalidate_device_context(&self, slot_id: u8) {
+        self.context_cache.lock().unwrap().remove(slot_id);
     }
+
+    /// Forget all cached device context pointers.
+    ///
+    /// Call this function on controller reset.
+    pub fn invalidate_all_device_contexts(&self) {
+        self.context_cache.lock().unwrap().pointers.clear();
+    }
+}
+
+/// The largest MaxPStreams of a Linear Stream Array. Larger values are
+/// reserved.
+const MAX_LINEAR_PRIMARY_STREAMS: u8 = 15;
+
+/// The LSA (Linear Stream Array) bit of the first dword of an endpoint
+/// context.
+const LINEAR_STREAM_ARRAY: u32 = 1 << 15;
+
+/// Check the stream fields in the first dword of an endpoint context.
+///
+/// We walk Linear Stream Arrays only, i.e., MaxPStreams of 1 to 15 with
+/// LSA set. Secondary Stream Arrays are not supported, and the values
+/// above 15 are reserved for Linear Stream Arrays.
+fn check_stream_fields(endpoint_id: Dci, dword0: u32) -> Result<(), CommandError> {
+    let max_primary_streams = (dword0 >> 10) & 0x1f;
+    if max_primary_streams == 0 {
+        return Ok(());
+    }
+    if dword0 & LINEAR_STREAM_ARRAY == 0 {
+        return Err(CommandError::ParameterError(format!(
+            "EP{endpoint_id} uses Secondary Stream Arrays, which we do not support"
+        )));
+    }
+    if max_primary_streams > u32::from(MAX_LINEAR_PRIMARY_STREAMS) {
+        return Err(CommandError::ParameterError(format!(
+            "EP{endpoint_id} has the reserved MaxPStreams {max_primary_streams}"
+        )));
+    }
+    Ok(())
+}
+
+/// Decode the Interval field of an endpoint context.
+///
+/// The field holds the exponent of the service interval in 125 µs units,
+/// regardless of the device's speed: drivers convert the bInterval of
+/// full- and low-speed devices, which counts frames, to the closest
+/// smaller power of two. Values above 15 are reserved; we treat them as
+/// the longest interval.
+pub const fn service_interval(interval: u8) -> Duration {
+    let interval = if interval > 15 { 15 } else { interval };
+    Duration::from_micros(125 << interval)
 }
 
 /// A wrapper around DMA accesses to device context structures.
@@ -147,48 +364,203 @@ impl DeviceContext {
     /// Call this function on AddressDeviceCommand. The command contains a
     /// pointer to an input context (which is this function's parameter).
     /// The XHCI controller is supposed to validate the values and copy the
-    /// data to the device context---we only do the latter and assume the
-    /// input is fine.
+    /// data to the device context---we copy the data and only check what
+    /// we could not handle otherwise.
     ///
     /// The input context starts with an input control context, which indicates
     /// which following entries have to be considered.
     /// We assume that exactly the slot context and the default control
-    /// endpoint get initialized and panic otherwise.
+    /// endpoint get initialized and fail with a parameter error otherwise.
     ///
     /// Additional to copying the input context, we have to set the slot state
-    /// in the slot context to "addressed" and the state in the endpoint
-    /// context to running.
+    /// and the USB device address in the slot context and the state in the
+    /// endpoint context to running. With an address, the slot is
+    /// "addressed". Without one, i.e., when the driver set BSR in the
+    /// command, the slot stays in the "default" state with address 0.
+    ///
+    /// Drivers may provision the max packet size of the default control
+    /// endpoint with a placeholder (e.g., 8 bytes for a Full Speed device)
+    /// until they read the device descriptor. We replace values below the
+    /// default of the port speed with that default, so the first
+    /// GET_DESCRIPTOR works. Drivers set the real value with an Evaluate
+    /// Context Command later.
     ///
     /// # Parameters
     ///
     /// - addr_input_context: address of the input context used for
     ///   initialization.
+    /// - usb_device_address: the USB device address assigned to the slot,
+    ///   if any.
+    /// - port_speed: gives the speed of the device on a root hub port, if
+    ///   any.
     ///
     /// # Return value
     ///
-    /// The root hub port number as reported in the slot context.
-    pub fn initialize(&self, addr_input_context: u64) -> u8 {
+    /// The root hub port number as reported in the slot context, or the
+    /// error to complete the command with.
+    pub fn initialize(
+        &self,
+        addr_input_context: u64,
+        usb_device_address: Option<u8>,
+        port_speed: impl FnOnce(u8) -> Option<Speed>,
+    ) -> Result<u8, CommandError> {
         let add_drop_flags = self
             .dma_bus
             .read(Request::new(addr_input_context, RequestSize::Size8));
-        assert!(
-            add_drop_flags == 0x300000000,
-            "expected only A0 and A1 flags to be set"
-        );
+        if add_drop_flags != 0x300000000 {
+            return Err(CommandError::ParameterError(format!(
+                "expected only A0 and A1 flags in the input context, got {add_drop_flags:#x}"
+            )));
+        }
 
         // read full input context
         let mut input_context = [0; 1056];
         self.dma_bus
             .read_bulk(addr_input_context, &mut input_context);
 
-        input_context[32 + 15] = slot_state::ADDRESSED << 3;
+        // The USB Device Address shares dword 3 with the slot state.
+        input_context[32 + 12] = usb_device_address.unwrap_or(0);
+        input_context[32 + 15] = if usb_device_address.is_some() {
+            slot_state::ADDRESSED
+        } else {
+            slot_state::DEFAULT
+        } << 3;
         input_context[64] = endpoint_state::RUNNING;
 
+        let root_hub_port_number = input_context[32 + 6];
+        let max_packet_size = u16::from_le_bytes([input_context[64 + 6], input_context[64 + 7]]);
+        if let Some(speed) = port_speed(root_hub_port_number) {
+            let default = speed.max_packet_size_ep0();
+            if max_packet_size < default {
+                debug!(
+                    "EP0 max packet size {} looks provisional for {}, using {}",
+                    max_packet_size, speed, default
+                );
+                input_context[64 + 6..64 + 8].copy_from_slice(&default.to_le_bytes());
+            }
+        }
+
         // fill slot context and ep0 context (as indicated by flags A0 and A1)
         self.dma_bus
             .write_bulk(self.address, &input_context[32..96]);
 
-        input_context[32 + 6]
+        Ok(root_hub_port_number)
+    }
+
+    /// Return the slot to the "default" state with USB device address 0.
+    ///
+    /// Call this function on Reset Device Command. The device forgets its
+    /// address on a reset, so the driver has to address it again.
+    pub fn reset(&self) {
+        self.dma_bus.write(
+            Request::new(self.address.wrapping_add(12), RequestSize::Size1),
+            0,
+        );
+        self.dma_bus.write(
+            Request::new(self.address.wrapping_add(15), RequestSize::Size1),
+            (slot_state::DEFAULT << 3).into(),
+        );
+    }
+
+    /// Update the device context with an input context on an Evaluate
+    /// Context Command.
+    ///
+    /// Only some fields are evaluated: the Max Exit Latency and Interrupter
+    /// Target of the slot context and the max packet size of the default
+    /// control endpoint. Drivers use the command to set the latter once
+    /// they know it from the device descriptor.
+    ///
+    /// # Parameters
+    ///
+    /// - addr_input_context: address of the input context to evaluate.
+    pub fn evaluate(&self, addr_input_context: u64) {
+        let add_flags = self.dma_bus.read(Request::new(
+            addr_input_context.wrapping_add(4),
+            RequestSize::Size4,
+        ));
+
+        if add_flags & 0x1 != 0 {
+            let input_slot_context = addr_input_context.wrapping_add(32);
+            let max_exit_latency = self.dma_bus.read(Request::new(
+                input_slot_context.wrapping_add(4),
+                RequestSize::Size2,
+            ));
+            self.dma_bus.write(
+                Request::new(self.address.wrapping_add(4), RequestSize::Size2),
+                max_exit_latency,
+            );
+
+            // The Interrupter Target occupies bits 31:22 of dword 2.
+            let interrupter_target = self.dma_bus.read(Request::new(
+                input_slot_context.wrapping_add(8),
+                RequestSize::Size4,
+            )) & 0xffc0_0000;
+            let dword2 = self.dma_bus.read(Request::new(
+                self.address.wrapping_add(8),
+                RequestSize::Size4,
+            ));
+            self.dma_bus.write(
+                Request::new(self.address.wrapping_add(8), RequestSize::Size4),
+                dword2 & 0x003f_ffff | interrupter_target,
+            );
+        }
+
+        if add_flags & 0x2 != 0 {
+            self.update_control_endpoint(addr_input_context);
+        }
+
+        if add_flags & !0x3 != 0 {
+            debug!(
+                "Evaluate Context: ignoring endpoint contexts in add flags {:#x}",
+                add_flags
+            );
+        }
+    }
+
+    /// Take the parameters of the default control endpoint from an input
+    /// context.
+    ///
+    /// Only the max packet size can change. The endpoint keeps its state and
+    /// transfer ring.
+    fn update_control_endpoint(&self, addr_input_context: u64) {
+        let input_ep0_context =
+            EndpointContext::new(addr_input_context.wrapping_add(64), self.dma_bus.clone());
+        let max_packet_size = input_ep0_context.get_max_packet_size();
+        let context = self.get_control_endpoint_context();
+        debug!(
+            "EP0 max packet size {} -> {}",
+            context.get_max_packet_size(),
+            max_packet_size
+        );
+        context.set_max_packet_size(max_packet_size);
+    }
+
+    /// The contexts of the endpoints that the input context at
+    /// `addr_input_context` adds, in ascending order.
+    ///
+    /// The contexts are read from the input context, so the controller can
+    /// check them before [`configure_endpoints`](Self::configure_endpoints)
+    /// changes the device context. The default control endpoint is not part
+    /// of the result.
+    pub fn added_endpoint_contexts(&self, addr_input_context: u64) -> Vec<(Dci, EndpointContext)> {
+        let add_flags = self.dma_bus.read(Request::new(
+            addr_input_context.wrapping_add(4),
+            RequestSize::Size4,
+        ));
+        Dci::non_control()
+            .filter(|endpoint_id| add_flags & (1 << endpoint_id.get()) != 0)
+            .map(|endpoint_id| {
+                // The endpoint contexts follow the input control context and
+                // the slot context.
+                let address = addr_input_context
+                    .wrapping_add(32)
+                    .wrapping_add(u64::from(endpoint_id.get()) * 32);
+                (
+                    endpoint_id,
+                    EndpointContext::new(address, self.dma_bus.clone()),
+                )
+            })
+            .collect()
     }
 
     /// Update the device context with an input context.
@@ -196,38 +568,89 @@ impl DeviceContext {
     /// Call this function on ConfigureEndpointCommand. The command contains a
     /// pointer to an input context (which is this function's parameter).
     /// The XHCI controller is supposed to validate the values and copy the
-    /// data to the device context---we only do the latter and assume the
-    /// input is fine.
+    /// data to the device context---we copy the data and only check what
+    /// we could not handle otherwise.
+    ///
+    /// The function returns the enabled endpoints with the type from the
+    /// EP Type field the driver programmed, so that the same endpoints can
+    /// be configured on the real device without guessing their type from
+    /// its descriptors. Unsupported types have to be refused before, see
+    /// [`added_endpoint_contexts`](Self::added_endpoint_contexts). Added
+    /// endpoints with the invalid EP Type 0 or stream fields we cannot
+    /// handle fail the command before the device context changes, see
+    /// [`check_stream_fields`].
+    /// Dropped endpoints
+    /// are passed to `drop_endpoint` before their contexts are disabled,
+    /// so that their workers are gone before the driver reuses their
+    /// transfer rings.
     ///
-    /// The function returns the enabled endpoints, so that the same
-    /// endpoints can be configured on the real device.
+    /// Some drivers set A1 to change the parameters of the default control
+    /// endpoint. We treat it like on an Evaluate Context Command. The
+    /// endpoint is not part of the result, as control transfers do not need
+    /// an endpoint worker.
     ///
     /// # Parameters
     ///
     /// - addr_input_context: address of the input context used for
     ///   initialization.
-    pub fn configure_endpoints(&self, addr_input_context: u64) -> Vec<(u8, EndpointType)> {
+    /// - drop_endpoint: shuts down the worker of a dropped endpoint.
+    pub fn configure_endpoints(
+        &self,
+        addr_input_context: u64,
+        mut drop_endpoint: impl FnMut(Dci),
+    ) -> Result<Vec<(Dci, EndpointType)>, CommandError> {
         let drop_flags = self
             .dma_bus
             .read(Request::new(addr_input_context, RequestSize::Size4));
-        let add_flags = self
-            .dma_bus
-            .read(Request::new(addr_input_context + 4, RequestSize::Size4));
+        let add_flags = self.dma_bus.read(Request::new(
+            addr_input_context.wrapping_add(4),
+            RequestSize::Size4,
+        ));
+
+        if add_flags & 0x1 == 0 {
+            return Err(CommandError::ParameterError(format!(
+                "expected the A0 flag in the input context, got add flags {add_flags:#x}"
+            )));
+        }
 
         // read slot and endpoint contexts
         let mut input_context = [0; 1024];
         self.dma_bus
             .read_bulk(addr_input_context.wrapping_add(32), &mut input_context);
 
+        // The driver may change the input context at any time, so we check
+        // the copy that we are going to use.
+        for endpoint_id in Dci::non_control() {
+            let i = endpoint_id.get();
+            if add_flags & (1 << i) == 0 {
+                continue;
+            }
+            let ep_context_offset = usize::from(i) * 32;
+            let dword0 = u32::from_le_bytes(
+                input_context[ep_context_offset..ep_context_offset + 4]
+                    .try_into()
+                    .unwrap(),
+            );
+            check_stream_fields(endpoint_id, dword0)?;
+            if EndpointType::from_context_value(input_context[ep_context_offset + 4] >> 3).is_none()
+            {
+                return Err(CommandError::ParameterError(format!(
+                    "EP{endpoint_id} has an invalid endpoint type"
+                )));
+            }
+        }
+
         // disable dropped endpoints
-        for i in 2..=31 {
+        for endpoint_id in Dci::non_control() {
+            let i = endpoint_id.get();
             if drop_flags & (1 << i) == 0 {
                 continue;
             }
 
             debug!("Configure Endpoint: D{} is set", i);
+            drop_endpoint(endpoint_id);
 
-            let ep_context_offset = i * 32;
+            let ep_context_offset = u64::from(i) * 32;
             self.dma_bus.write(
                 Request::new(
                     self.address.wrapping_add(ep_context_offset),
@@ -237,15 +660,21 @@ impl DeviceContext {
             );
         }
 
+        if add_flags & 0x2 != 0 {
+            debug!("Configure Endpoint: A1 is set");
+            self.update_control_endpoint(addr_input_context);
+        }
+
         let mut enabled_endpoints = vec![];
 
         // copy context of added endpoints and enable
-        for i in 1..=31 {
+        for endpoint_id in Dci::non_control() {
+            let i = endpoint_id.get();
             if add_flags & (1 << i) == 0 {
                 continue;
             }
 
-            let ep_context_offset = i * 32;
+            let ep_context_offset = usize::from(i) * 32;
 
             input_context[ep_context_offset] = 1;
             self.dma_bus.write_bulk(
@@ -253,34 +682,49 @@ impl DeviceContext {
                 &input_context[ep_context_offset..ep_context_offset + 32],
             );
 
-            let ep_type = match (input_context[ep_context_offset + 4] >> 3) & 0x7 {
-                2 => EndpointType::BulkOut,
-                6 => EndpointType::BulkIn,
-                4 => EndpointType::Control,
-                7 => EndpointType::InterruptIn,
-                val => todo!("encountered unsupported endpoint type: {}", val),
+            // Checked above.
+            let Some(ep_type) =
+                EndpointType::from_context_value(input_context[ep_context_offset + 4] >> 3)
+            else {
+                continue;
             };
-            enabled_endpoints.push((i as u8, ep_type));
+            enabled_endpoints.push((endpoint_id, ep_type));
             debug!(
                 "Configure Endpoint: A{} is set. Configured as {:?} endpoint",
                 i, ep_type
             );
         }
 
-        // copy slot context
-        assert_eq!(add_flags & 0x1, 1, "Flag A0 should always be set");
-
+        // copy slot context (A0 is checked above)
         input_context[15] = slot_state::CONFIGURED << 3;
 
         self.dma_bus.write_bulk(self.address, &input_context[0..32]);
 
-        enabled_endpoints
+        Ok(enabled_endpoints)
+    }
+
+    /// The IDs of all endpoints that are not disabled, in ascending order.
+    ///
+    /// The endpoint states are read from guest memory, so the result
+    /// reflects the last command that changed them. The default control
+    /// endpoint (ID 1) is part of the result once the device is addressed.
+    pub fn enabled_endpoints(&self) -> Vec<Dci> {
+        std::iter::once(Dci::CONTROL)
+            .chain(Dci::non_control())
+            .filter(|&endpoint_id| self.is_endpoint_enabled(endpoint_id))
+            .collect()
+    }
+
+    /// Whether the endpoint is not disabled, read from guest memory like
+    /// [`enabled_endpoints`](Self::enabled_endpoints).
+    pub fn is_endpoint_enabled(&self, endpoint_id: Dci) -> bool {
+        self.get_endpoint_context(endpoint_id).get_state() != DISABLED
     }
 
-    pub fn set_endpoint_state(&self, endpoint_id: u8, state: u8) {
+    pub fn set_endpoint_state(&self, endpoint_id: Dci, state: u8) {
         self.dma_bus.write(
             Request::new(
-                self.address.wrapping_add(endpoint_id as u64 * 32),
+                self.address.wrapping_add(u64::from(endpoint_id.get()) * 32),
                 RequestSize::Size1,
             ),
             state as u64,
@@ -311,6 +755,11 @@ impl DeviceContext {
         EndpointContext::new(addr, self.dma_bus.clone())
     }
 
+    /// Give access to the context of an endpoint.
+    pub fn get_endpoint_context(&self, endpoint_id: Dci) -> EndpointContext {
+        self.get_endpoint_context_internal(endpoint_id.get().into())
+    }
+
     /// Give access to context of the default control endpoint.
     ///
     /// Endpoint 0 is a special endpoint. It always exists and it is bi-directional.
@@ -323,19 +772,67 @@ impl DeviceContext {
     ///
     /// Endpoint 0 is a special endpoint. It always exists and it is bi-directional.
     pub fn get_control_transfer_ring(&self) -> TransferRing {
-        TransferRing::new(self.get_control_endpoint_context(), self.dma_bus.clone())
+        TransferRing::new(
+            self.get_control_endpoint_context(),
+            paranoid_dma::tag(&self.dma_bus, DmaOrigin::TransferRing),
+        )
     }
 
-    pub fn get_transfer_ring(&self, endpoint_index: u64) -> TransferRing {
-        let endpoint_context = self.get_endpoint_context_internal(endpoint_index);
+    /// Give access to the TRBs of an endpoint.
+    ///
+    /// For endpoints with streams, the endpoint context points to a Stream
+    /// Context Array, which holds one transfer ring per stream.
+    ///
+    /// The transfer rings expect their segments to end with a Link TRB
+    /// before crossing a multiple of `segment_boundary`.
+    ///
+    /// Returns `Option::None` if the endpoint is disabled, e.g., because the
+    /// driver rang the doorbell before configuring the endpoint.
+    pub fn get_endpoint_ring(
+        &self,
+        endpoint_id: Dci,
+        segment_boundary: u64,
+    ) -> Option<EndpointRing> {
+        let endpoint_context = self.get_endpoint_context(endpoint_id);
         match endpoint_context.get_state() {
             DISABLED => {
-                panic!("requested transferring for disabled EP{}", endpoint_index)
+                debug!("requested transfer ring of disabled EP{}", endpoint_id);
+                return None;
             }
             RUNNING => {}
             _ => endpoint_context.set_state(RUNNING),
         };
-        TransferRing::new(endpoint_context, self.dma_bus.clone())
+
+        let ring = match endpoint_context.get_max_primary_streams() {
+            0 => EndpointRing::Single(
+                TransferRing::new(
+                    endpoint_context,
+                    paranoid_dma::tag(&self.dma_bus, DmaOrigin::TransferRing),
+                )
+                .with_segment_boundary(segment_boundary),
+            ),
+            max_primary_streams => {
+                // The driver owns the memory of the device context, so the
+                // fields may have changed since the Configure Endpoint
+                // Command checked them.
+                if let Err(error) = endpoint_context.check_streams(endpoint_id) {
+                    warn!("EP{} has no transfer ring: {}", endpoint_id, error);
+                    return None;
+                }
+                let (address, _) = endpoint_context.get_dequeue_pointer_and_cycle_state();
+                debug!(
+                    "EP{} uses streams with a Stream Context Array at {:#x}",
+                    endpoint_id, address
+                );
+                EndpointRing::Streams(Arc::new(StreamContextArray::new(
+                    address,
+                    max_primary_streams,
+                    segment_boundary,
+                    self.dma_bus.clone(),
+                )?))
+            }
+        };
+        Some(ring)
     }
 }
 
@@ -396,10 +893,324 @@ impl EndpointContext {
             .read(Request::new(self.address, RequestSize::Size1)) as u8
     }
 
+    /// The MaxPStreams field. Endpoints with streams have a value above 0.
+    fn get_max_primary_streams(&self) -> u8 {
+        let dword0 = self
+            .dma_bus
+            .read(Request::new(self.address, RequestSize::Size4));
+        ((dword0 >> 10) & 0x1f) as u8
+    }
+
+    /// Check the MaxPStreams and LSA fields, see [`check_stream_fields`].
+    fn check_streams(&self, endpoint_id: Dci) -> Result<(), CommandError> {
+        let dword0 = self
+            .dma_bus
+            .read(Request::new(self.address, RequestSize::Size4));
+        check_stream_fields(endpoint_id, dword0 as u32)
+    }
+
     fn set_state(&self, state: u8) {
         self.dma_bus
             .write(Request::new(self.address, RequestSize::Size1), state as u64);
     }
+
+    /// The EP Type field, unless it holds the invalid type 0.
+    pub fn get_endpoint_type(&self) -> Option<EndpointType> {
+        let dword1 = self.dma_bus.read(Request::new(
+            self.address.wrapping_add(4),
+            RequestSize::Size1,
+        ));
+        EndpointType::from_context_value((dword1 >> 3) as u8)
+    }
+
+    /// The Max Packet Size field.
+    pub fn get_max_packet_size(&self) -> u16 {
+        self.dma_bus.read(Request::new(
+            self.address.wrapping_add(6),
+            RequestSize::Size2,
+        )) as u16
+    }
+
+    /// The Max Burst Size field.
+    pub fn get_max_burst_size(&self) -> u8 {
+        self.dma_bus.read(Request::new(
+            self.address.wrapping_add(5),
+            RequestSize::Size1,
+        )) as u8
+    }
+
+    /// The Max ESIT Payload of a periodic endpoint, combined from the Max
+    /// ESIT Payload Hi and Lo fields.
+    pub fn get_max_esit_payload(&self) -> u32 {
+        let hi = self.dma_bus.read(Request::new(
+            self.address.wrapping_add(3),
+            RequestSize::Size1,
+        ));
+        let lo = self.dma_bus.read(Request::new(
+            self.address.wrapping_add(0x12),
+            RequestSize::Size2,
+        ));
+        (hi << 16 | lo) as u32
+    }
+
+    /// The packet and burst sizes of the endpoint.
+    pub fn get_transfer_unit(&self) -> TransferUnit {
+        TransferUnit {
+            max_packet_size: self.get_max_packet_size(),
+            max_burst_size: self.get_max_burst_size(),
+            max_esit_payload: self.get_max_esit_payload(),
+        }
+    }
+
+    /// The service interval of a periodic endpoint, decoded from the
+    /// Interval field.
+    pub fn get_interval(&self) -> Duration {
+        let interval = self.dma_bus.read(Request::new(
+            self.address.wrapping_add(2),
+            RequestSize::Size1,
+        ));
+        service_interval(interval as u8)
+    }
+
+    fn set_max_packet_size(&self, max_packet_size: u16) {
+        self.dma_bus.write(
+            Request::new(self.address.wrapping_add(6), RequestSize::Size2),
+            max_packet_size.into(),
+        );
+    }
+}
+
+/// A wrapper around DMA accesses to stream context structures.
+///
+/// The structure is explained in the XHCI spec 6.2.4.1. A stream context has
+/// a size of 16 bytes and holds the dequeue pointer and cycle state of the
+/// transfer ring of a stream.
+#[derive(Debug)]
+pub struct StreamContext {
+    /// The address of the stream context in guest memory.
+    address: u64,
+    /// Reference to the guest memory.
+    dma_bus: BusDeviceRef,
+}
+
+impl StreamContext {
+    fn read_dword0_1(&self) -> u64 {
+        self.dma_bus
+            .read(Request::new(self.address, RequestSize::Size8))
+    }
+
+    /// The Stream Context Type (SCT) field.
+    fn get_type(&self) -> u8 {
+        ((self.read_dword0_1() >> 1) & 0x7) as u8
+    }
+
+    /// DMA read the dequeue pointer and consumer cycle state of the stream's
+    /// transfer ring.
+    pub fn get_dequeue_pointer_and_cycle_state(&self) -> (u64, bool) {
+        let bytes = self.read_dword0_1();
+        (bytes & !0xf, bytes & 0x1 != 0)
+    }
+
+    /// DMA write the dequeue pointer and consumer cycle state of the stream's
+    /// transfer ring.
+    ///
+    /// The Stream Context Type is preserved.
+    pub fn set_dequeue_pointer_and_cycle_state(&self, dequeue_pointer: u64, cycle_state: bool) {
+        assert!(
+            dequeue_pointer & 0xf == 0,
+            "dequeue_pointer has to be aligned to 16 bytes"
+        );
+        let context_type = self.read_dword0_1() & 0xe;
+        self.dma_bus.write(
+            Request::new(self.address, RequestSize::Size8),
+            dequeue_pointer | context_type | cycle_state as u64,
+        )
+    }
+}
+
+/// The context that holds the dequeue pointer of a transfer ring.
+#[derive(Debug)]
+pub enum TransferRingContext {
+    /// The only transfer ring of an endpoint without streams.
+    Endpoint(EndpointContext),
+    /// The transfer ring of one stream of an endpoint.
+    Stream(StreamContext),
+}
+
+impl TransferRingContext {
+    /// DMA read the dequeue pointer and consumer cycle state of the transfer
+    /// ring.
+    pub fn get_dequeue_pointer_and_cycle_state(&self) -> (u64, bool) {
+        match self {
+            Self::Endpoint(context) => context.get_dequeue_pointer_and_cycle_state(),
+            Self::Stream(context) => context.get_dequeue_pointer_and_cycle_state(),
+        }
+    }
+
+    /// DMA write the dequeue pointer and consumer cycle state of the transfer
+    /// ring.
+    pub fn set_dequeue_pointer_and_cycle_state(&self, dequeue_pointer: u64, cycle_state: bool) {
+        match self {
+            Self::Endpoint(context) => {
+                context.set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state)
+            }
+            Self::Stream(context) => {
+                context.set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state)
+            }
+        }
+    }
+}
+
+impl From<EndpointContext> for TransferRingContext {
+    fn from(context: EndpointContext) -> Self {
+        Self::Endpoint(context)
+    }
+}
+
+impl From<StreamContext> for TransferRingContext {
+    fn from(context: StreamContext) -> Self {
+        Self::Stream(context)
+    }
+}
+
+/// The Stream Context Array of an endpoint with streams.
+///
+/// With streams, the driver queues TRBs on one transfer ring per stream and
+/// names the stream when it rings the doorbell. See XHCI spec 4.12.
+///
+/// We only support Linear Stream Arrays: Every entry of the array is the
+/// stream context of a Primary Stream.
+#[derive(Debug)]
+pub struct StreamContextArray {
+    /// The address of the array in guest memory.
+    address: u64,
+    /// Reference to the guest memory.
+    dma_bus: BusDeviceRef,
+    /// The segment boundary of the streams' transfer rings, see
+    /// [`TransferRing::with_segment_boundary`].
+    segment_boundary: u64,
+    /// The streams the driver rang the doorbell for since we last found
+    /// their transfer rings empty, indexed by stream ID.
+    pending: Box<[AtomicBool]>,
+    /// The stream to look at first for the next TRB. Serving the streams
+    /// round-robin keeps a busy stream from starving the others.
+    next_stream: AtomicUsize,
+}
+
+impl StreamContextArray {
+    /// Create a new instance.
+    ///
+    /// # Parameters
+    ///
+    /// - address: the address of the array in guest memory.
+    /// - max_primary_streams: the MaxPStreams field of the endpoint context.
+    ///   The array has 2^(MaxPStreams + 1) entries.
+    /// - segment_boundary: the segment boundary of the transfer rings.
+    /// - dma_bus: reference to the guest memory.
+    ///
+    /// Returns `None` if MaxPStreams is not in `1..=15`.
+    pub fn new(
+        address: u64,
+        max_primary_streams: u8,
+        segment_boundary: u64,
+        dma_bus: BusDeviceRef,
+    ) -> Option<Self> {
+        if !(1..=MAX_LINEAR_PRIMARY_STREAMS).contains(&max_primary_streams) {
+            return None;
+        }
+        let entries = 1 << (max_primary_streams + 1);
+        Some(Self {
+            address,
+            dma_bus,
+            segment_boundary,
+            pending: (0..entries).map(|_| AtomicBool::new(false)).collect(),
+            next_stream: AtomicUsize::new(1),
+        })
+    }
+
+    /// Give access to the stream context of a stream.
+    ///
+    /// Returns `None` for stream ID 0, which is reserved, and IDs beyond the
+    /// end of the array.
+    pub fn get_stream_context(&self, stream_id: u16) -> Option<StreamContext> {
+        let index = usize::from(stream_id);
+        (1..self.pending.len())
+            .contains(&index)
+            .then(|| StreamContext {
+                address: self.address.wrapping_add(16 * u64::from(stream_id)),
+                dma_bus: self.dma_bus.clone(),
+            })
+    }
+
+    /// Give access to the transfer ring of a stream.
+    ///
+    /// Returns `None` if the stream ID is invalid or the stream context does
+    /// not point to a transfer ring.
+    pub fn get_transfer_ring(&self, stream_id: u16) -> Option<TransferRing> {
+        let context = self.get_stream_context(stream_id)?;
+        match context.get_type() {
+            stream_context_type::PRIMARY_TRANSFER_RING => Some(
+                TransferRing::new(
+                    context,
+                    paranoid_dma::tag(&self.dma_bus, DmaOrigin::TransferRing),
+                )
+                .with_segment_boundary(self.segment_boundary),
+            ),
+            context_type => {
+                warn!(
+                    "stream {} has unsupported Stream Context Type {}",
+                    stream_id, context_type
+                );
+                None
+            }
+        }
+    }
+
+    /// Note that the driver rang the doorbell for a stream.
+    ///
+    /// Returns whether the stream ID is valid.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub fn notify(&self, stream_id: u16) -> bool {
+        let valid = self.get_stream_context(stream_id).is_some();
+        if valid {
+            self.pending[usize::from(stream_id)].store(true, Ordering::SeqCst);
+        } else {
+            warn!("doorbell for invalid stream {}", stream_id);
+        }
+        valid
+    }
+
+    /// Retrieve the next TRB from any stream the driver rang the doorbell
+    /// for.
+    ///
+    /// Returns the stream ID along with the TRB, or `None` if the transfer
+    /// rings of all such streams are empty. A stream whose transfer ring is
+    /// broken is only looked at again after the next doorbell for it.
+    pub fn next_transfer_trb(&self) -> Option<(u16, Result<TransferTrb, TransferRingError>)> {
+        let streams = self.pending.len();
+        let first = self.next_stream.load(Ordering::Relaxed);
+
+        (0..streams - 1)
+            // Stream IDs start at 1.
+            .map(|offset| (first - 1 + offset) % (streams - 1) + 1)
+            .find_map(|index| {
+                // Clear the flag before looking at the ring, so a doorbell
+                // for a TRB we miss sets it again.
+                if !self.pending[index].swap(false, Ordering::SeqCst) {
+                    return None;
+                }
+                let stream_id = index as u16;
+                let trb = self.get_transfer_ring(stream_id)?.next_transfer_trb()?;
+
+                // The stream may have more TRBs.
+                if trb.is_ok() {
+                    self.pending[index].store(true, Ordering::SeqCst);
+                }
+                self.next_stream
+                    .store(index % (streams - 1) + 1, Ordering::Relaxed);
+                Some((stream_id, trb))
+            })
+    }
 }
 
 #[cfg(test)]
@@ -407,14 +1218,536 @@ mod tests {
 
     use std::sync::Arc;
 
-    use crate::device::bus::testutils::TestBusDevice;
+    use crate::device::{
+        bus::{
+            testutils::{CountingBusDevice, TestBusDevice},
+            BusDevice,
+        },
+        pci::{
+            constants::xhci::{rings::trb_types, MAX_SLOTS},
+            rings::PAGE_SEGMENT_BOUNDARY,
+        },
+    };
 
     use super::*;
 
+    /// The address of the Stream Context Array in the stream tests.
+    const STREAM_ARRAY: u64 = 0x100;
+
+    fn dci(dci: u8) -> Dci {
+        Dci::new(dci).unwrap()
+    }
+
+    /// Write a stream context that points to a transfer ring.
+    fn write_stream_context(ram: &TestBusDevice, stream_id: u16, ring: u64, context_type: u8) {
+        ram.write(
+            Request::new(STREAM_ARRAY + 16 * u64::from(stream_id), RequestSize::Size8),
+            ring | u64::from(context_type) << 1 | 1,
+        );
+    }
+
+    fn write_normal_trb(ram: &TestBusDevice, address: u64) {
+        let mut trb = [0; 16];
+        trb[12] = 1;
+        trb[13] = trb_types::NORMAL << 2;
+        ram.write_bulk(address, &trb);
+    }
+
+    /// Guest memory with a Stream Context Array for streams 1 to 3. Stream 1
+    /// and 2 have transfer rings at 0x1000 and 0x2000.
+    fn ram_with_streams() -> (Arc<TestBusDevice>, StreamContextArray) {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x3000]));
+        write_stream_context(&ram, 1, 0x1000, stream_context_type::PRIMARY_TRANSFER_RING);
+        write_stream_context(&ram, 2, 0x2000, stream_context_type::PRIMARY_TRANSFER_RING);
+        // A Secondary Transfer Ring, which is not valid in a Linear Stream
+        // Array.
+        write_stream_context(&ram, 3, 0x2800, 0);
+
+        // MaxPStreams of 1 means 4 entries, of which stream ID 0 is reserved.
+        let streams =
+            StreamContextArray::new(STREAM_ARRAY, 1, PAGE_SEGMENT_BOUNDARY, ram.clone()).unwrap();
+        (ram, streams)
+    }
+
+    fn next_trb_address(streams: &StreamContextArray) -> Option<(u16, u64)> {
+        streams
+            .next_transfer_trb()
+            .map(|(stream_id, trb)| (stream_id, trb.unwrap().address))
+    }
+
     #[test]
-    fn device_slot_reservation() {
-        use crate::device::pci::constants::xhci::MAX_SLOTS;
+    fn stream_context_resolution() {
+        let (ram, streams) = ram_with_streams();
+
+        assert!(streams.get_stream_context(0).is_none());
+        assert!(streams.get_stream_context(4).is_none());
+        assert!(streams.get_transfer_ring(3).is_none());
+        assert!(streams.get_transfer_ring(1).is_some());
+
+        let context = streams.get_stream_context(2).unwrap();
+        assert_eq!(
+            context.get_dequeue_pointer_and_cycle_state(),
+            (0x2000, true)
+        );
+
+        // Advancing the ring keeps the Stream Context Type.
+        context.set_dequeue_pointer_and_cycle_state(0x2010, false);
+        assert_eq!(
+            ram.read(Request::new(STREAM_ARRAY + 0x20, RequestSize::Size8)),
+            0x2010 | u64::from(stream_context_type::PRIMARY_TRANSFER_RING) << 1
+        );
 
+        assert!(!streams.notify(0));
+        assert!(!streams.notify(4));
+        assert!(streams.notify(3));
+        // The invalid stream yields no TRB.
+        assert_eq!(next_trb_address(&streams), None);
+    }
+
+    #[test]
+    fn disabled_endpoint_has_no_ring() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+
+        assert!(device_context
+            .get_endpoint_ring(dci(2), PAGE_SEGMENT_BOUNDARY)
+            .is_none());
+        // Asking does not change the state of the endpoint.
+        assert_eq!(
+            ram.read(Request::new(0x40, RequestSize::Size1)),
+            u64::from(DISABLED)
+        );
+    }
+
+    #[test]
+    fn endpoint_ring_of_endpoint_with_streams() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+
+        // EP1 OUT: running, without streams.
+        ram.write(Request::new(0x40, RequestSize::Size4), u64::from(RUNNING));
+        ram.write(Request::new(0x48, RequestSize::Size8), 0x1001);
+        // EP1 IN: running, MaxPStreams 1, LSA, Stream Context Array at 0x100.
+        ram.write(
+            Request::new(0x60, RequestSize::Size4),
+            u64::from(RUNNING) | 1 << 10 | 1 << 15,
+        );
+        ram.write(Request::new(0x68, RequestSize::Size8), STREAM_ARRAY);
+
+        assert!(matches!(
+            device_context.get_endpoint_ring(dci(2), PAGE_SEGMENT_BOUNDARY),
+            Some(EndpointRing::Single(_))
+        ));
+        let Some(EndpointRing::Streams(streams)) =
+            device_context.get_endpoint_ring(dci(3), PAGE_SEGMENT_BOUNDARY)
+        else {
+            panic!("expected an endpoint with streams");
+        };
+        write_stream_context(&ram, 1, 0x180, stream_context_type::PRIMARY_TRANSFER_RING);
+        assert_eq!(
+            streams
+                .get_stream_context(1)
+                .unwrap()
+                .get_dequeue_pointer_and_cycle_state(),
+            (0x180, true)
+        );
+    }
+
+    /// The address of the input context in the context tests.
+    const INPUT_CONTEXT: u64 = 0x400;
+
+    /// Write an input context with the given add flags, root hub port 1 and
+    /// EP0 max packet size.
+    fn write_input_context(ram: &TestBusDevice, add_flags: u32, max_packet_size: u16) {
+        ram.write(
+            Request::new(INPUT_CONTEXT + 4, RequestSize::Size4),
+            add_flags.into(),
+        );
+        ram.write(Request::new(INPUT_CONTEXT + 32 + 6, RequestSize::Size1), 1);
+        ram.write(
+            Request::new(INPUT_CONTEXT + 64 + 6, RequestSize::Size2),
+            max_packet_size.into(),
+        );
+    }
+
+    fn control_max_packet_size(ram: &TestBusDevice) -> u64 {
+        ram.read(Request::new(32 + 6, RequestSize::Size2))
+    }
+
+    #[test]
+    fn provisional_control_max_packet_size_is_replaced_until_evaluated() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+
+        write_input_context(&ram, 0b11, 8);
+        let port = device_context
+            .initialize(INPUT_CONTEXT, Some(1), |port| {
+                assert_eq!(port, 1);
+                Some(Speed::Full)
+            })
+            .unwrap();
+        assert_eq!(port, 1);
+        assert_eq!(control_max_packet_size(&ram), 64);
+
+        // The driver read the device descriptor and learned the real value.
+        write_input_context(&ram, 0b10, 16);
+        device_context.evaluate(INPUT_CONTEXT);
+        assert_eq!(control_max_packet_size(&ram), 16);
+    }
+
+    #[test]
+    fn plausible_control_max_packet_size_is_kept() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+
+        write_input_context(&ram, 0b11, 8);
+        device_context
+            .initialize(INPUT_CONTEXT, Some(1), |_| Some(Speed::Low))
+            .unwrap();
+        assert_eq!(control_max_packet_size(&ram), 8);
+
+        write_input_context(&ram, 0b11, 512);
+        device_context
+            .initialize(INPUT_CONTEXT, Some(1), |_| Some(Speed::Super))
+            .unwrap();
+        assert_eq!(control_max_packet_size(&ram), 512);
+
+        // Without a device on the port, there is nothing to go by.
+        write_input_context(&ram, 0b11, 0);
+        device_context
+            .initialize(INPUT_CONTEXT, Some(1), |_| None)
+            .unwrap();
+        assert_eq!(control_max_packet_size(&ram), 0);
+    }
+
+    #[test]
+    fn service_intervals() {
+        for (interval, micros) in [
+            // A high-speed mouse with bInterval 4: 2^(4-1) microframes.
+            (3, 1_000),
+            // The shortest interval of a high-speed endpoint.
+            (0, 125),
+            // A full-speed keyboard with bInterval 10 frames, rounded down
+            // to 8 ms.
+            (6, 8_000),
+            // A low-speed device with bInterval 255 frames, rounded down
+            // to 128 ms.
+            (10, 128_000),
+            // A SuperSpeed hub's status endpoint with bInterval 12.
+            (11, 256_000),
+            (15, 4_096_000),
+            // Reserved values.
+            (16, 4_096_000),
+            (255, 4_096_000),
+        ] {
+            assert_eq!(
+                service_interval(interval),
+                Duration::from_micros(micros),
+                "Interval {interval}"
+            );
+        }
+    }
+
+    #[test]
+    fn endpoint_context_interval() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        ram.write(Request::new(3 * 32 + 2, RequestSize::Size1), 6);
+
+        assert_eq!(
+            device_context.get_endpoint_context(dci(3)).get_interval(),
+            Duration::from_millis(8)
+        );
+    }
+
+    #[test]
+    fn endpoint_context_type_and_max_packet_size() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        // EP1 IN (ID 3) is a bulk endpoint with 512 byte packets.
+        ram.write(Request::new(3 * 32 + 4, RequestSize::Size1), 6 << 3);
+        ram.write(Request::new(3 * 32 + 6, RequestSize::Size2), 512);
+        // EP1 OUT (ID 2) is isochronous.
+        ram.write(Request::new(2 * 32 + 4, RequestSize::Size1), 1 << 3 | 0x6);
+        ram.write(Request::new(2 * 32 + 6, RequestSize::Size2), 1024);
+
+        let bulk_in = device_context.get_endpoint_context(dci(3));
+        assert_eq!(bulk_in.get_endpoint_type(), Some(EndpointType::BulkIn));
+        assert_eq!(bulk_in.get_max_packet_size(), 512);
+
+        let isoch_out = device_context.get_endpoint_context(dci(2));
+        assert_eq!(isoch_out.get_endpoint_type(), Some(EndpointType::IsochOut));
+        assert_eq!(isoch_out.get_max_packet_size(), 1024);
+    }
+
+    #[test]
+    fn endpoint_context_transfer_unit() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        // EP1 IN (ID 3) is a SuperSpeed bulk endpoint with bursts of 16
+        // packets.
+        ram.write(Request::new(3 * 32 + 5, RequestSize::Size1), 15);
+        ram.write(Request::new(3 * 32 + 6, RequestSize::Size2), 1024);
+        // EP2 IN (ID 5) is an interrupt endpoint with a Max ESIT Payload of
+        // 0x1_0400 bytes.
+        ram.write(Request::new(5 * 32 + 3, RequestSize::Size1), 0x01);
+        ram.write(Request::new(5 * 32 + 0x12, RequestSize::Size2), 0x0400);
+
+        assert_eq!(
+            device_context
+                .get_endpoint_context(dci(3))
+                .get_transfer_unit(),
+            TransferUnit {
+                max_packet_size: 1024,
+                max_burst_size: 15,
+                max_esit_payload: 0,
+            }
+        );
+        assert_eq!(
+            device_context
+                .get_endpoint_context(dci(5))
+                .get_max_esit_payload(),
+            0x1_0400
+        );
+    }
+
+    #[test]
+    fn configure_endpoint_updates_control_endpoint_on_a1() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        write_input_context(&ram, 0b11, 8);
+        device_context
+            .initialize(INPUT_CONTEXT, Some(1), |_| None)
+            .unwrap();
+        let (dequeue_pointer, _) = device_context
+            .get_control_endpoint_context()
+            .get_dequeue_pointer_and_cycle_state();
+
+        // A1 with a new max packet size and a different dequeue pointer,
+        // plus EP1 OUT as a bulk endpoint.
+        write_input_context(&ram, 0b111, 64);
+        ram.write(
+            Request::new(INPUT_CONTEXT + 64 + 8, RequestSize::Size8),
+            0x801,
+        );
+        ram.write(
+            Request::new(INPUT_CONTEXT + 96 + 4, RequestSize::Size1),
+            2 << 3,
+        );
+        let enabled_endpoints = device_context
+            .configure_endpoints(INPUT_CONTEXT, |_| unreachable!())
+            .unwrap();
+
+        assert_eq!(enabled_endpoints, [(dci(2), EndpointType::BulkOut)]);
+        assert_eq!(control_max_packet_size(&ram), 64);
+        let context = device_context.get_control_endpoint_context();
+        assert_eq!(context.get_state(), RUNNING);
+        assert_eq!(
+            context.get_dequeue_pointer_and_cycle_state().0,
+            dequeue_pointer
+        );
+    }
+
+    #[test]
+    fn configured_endpoints_have_the_programmed_type() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        let endpoints = [
+            (2, 2, EndpointType::BulkOut),
+            (3, 7, EndpointType::InterruptIn),
+            (5, 6, EndpointType::BulkIn),
+            (8, 2, EndpointType::BulkOut),
+        ];
+        let mut add_flags = 0b1;
+        for (endpoint_id, ep_type, _) in endpoints {
+            add_flags |= 1 << endpoint_id;
+            ram.write(
+                Request::new(
+                    INPUT_CONTEXT + 32 + 32 * endpoint_id + 4,
+                    RequestSize::Size1,
+                ),
+                ep_type << 3,
+            );
+        }
+        ram.write(
+            Request::new(INPUT_CONTEXT + 4, RequestSize::Size4),
+            add_flags,
+        );
+
+        let enabled_endpoints = device_context
+            .configure_endpoints(INPUT_CONTEXT, |_| unreachable!())
+            .unwrap();
+
+        assert_eq!(
+            enabled_endpoints,
+            endpoints
+                .map(|(endpoint_id, _, endpoint_type)| (dci(endpoint_id as u8), endpoint_type))
+        );
+        // The device context holds the same type.
+        for (endpoint_id, _, endpoint_type) in endpoints {
+            assert_eq!(
+                device_context
+                    .get_endpoint_context(dci(endpoint_id as u8))
+                    .get_endpoint_type(),
+                Some(endpoint_type)
+            );
+        }
+    }
+
+    #[test]
+    fn endpoints_of_invalid_type_fail_the_command() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        // Add EP1 OUT with EP Type 0 (Not Valid).
+        ram.write(Request::new(INPUT_CONTEXT + 4, RequestSize::Size4), 0b101);
+
+        assert!(matches!(
+            device_context.configure_endpoints(INPUT_CONTEXT, |_| unreachable!()),
+            Err(CommandError::ParameterError(_))
+        ));
+        assert!(device_context.enabled_endpoints().is_empty());
+    }
+
+    #[test]
+    fn configure_endpoint_without_a0_fails_the_command() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        // Add EP1 OUT as a bulk endpoint, but not the slot context.
+        ram.write(Request::new(INPUT_CONTEXT + 4, RequestSize::Size4), 0b100);
+        ram.write(
+            Request::new(INPUT_CONTEXT + 96 + 4, RequestSize::Size1),
+            2 << 3,
+        );
+
+        assert!(matches!(
+            device_context.configure_endpoints(INPUT_CONTEXT, |_| unreachable!()),
+            Err(CommandError::ParameterError(_))
+        ));
+        assert!(device_context.enabled_endpoints().is_empty());
+    }
+
+    #[test]
+    fn endpoints_with_unsupported_streams_fail_the_command() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        // Add EP1 OUT as a bulk endpoint.
+        ram.write(Request::new(INPUT_CONTEXT + 4, RequestSize::Size4), 0b101);
+        ram.write(
+            Request::new(INPUT_CONTEXT + 96 + 4, RequestSize::Size1),
+            2 << 3,
+        );
+
+        // MaxPStreams 1 without LSA, and the reserved MaxPStreams 16.
+        for dword0 in [1 << 10, 16 << 10 | LINEAR_STREAM_ARRAY] {
+            ram.write(
+                Request::new(INPUT_CONTEXT + 96, RequestSize::Size4),
+                dword0.into(),
+            );
+            assert!(matches!(
+                device_context.configure_endpoints(INPUT_CONTEXT, |_| unreachable!()),
+                Err(CommandError::ParameterError(_))
+            ));
+            assert!(device_context.enabled_endpoints().is_empty());
+        }
+
+        // The driver changes the fields after the command succeeded.
+        ram.write(
+            Request::new(INPUT_CONTEXT + 96, RequestSize::Size4),
+            (1 << 10 | LINEAR_STREAM_ARRAY).into(),
+        );
+        device_context
+            .configure_endpoints(INPUT_CONTEXT, |_| unreachable!())
+            .unwrap();
+        ram.write(
+            Request::new(0x40, RequestSize::Size4),
+            u64::from(RUNNING) | 31 << 10 | u64::from(LINEAR_STREAM_ARRAY),
+        );
+        assert!(device_context
+            .get_endpoint_ring(dci(2), PAGE_SEGMENT_BOUNDARY)
+            .is_none());
+    }
+
+    #[test]
+    fn dropped_endpoints_are_shut_down_before_they_are_disabled() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        device_context.set_endpoint_state(dci(2), RUNNING);
+        device_context.set_endpoint_state(dci(3), RUNNING);
+        ram.write(Request::new(INPUT_CONTEXT, RequestSize::Size4), 1 << 3);
+        ram.write(Request::new(INPUT_CONTEXT + 4, RequestSize::Size4), 0b1);
+
+        let mut dropped = vec![];
+        let enabled_endpoints = device_context
+            .configure_endpoints(INPUT_CONTEXT, |endpoint_id| {
+                assert_eq!(
+                    device_context.enabled_endpoints(),
+                    [dci(2), dci(3)],
+                    "endpoint disabled before its worker"
+                );
+                dropped.push(endpoint_id);
+            })
+            .unwrap();
+
+        assert!(enabled_endpoints.is_empty());
+        assert_eq!(dropped, [dci(3)]);
+        assert_eq!(device_context.enabled_endpoints(), [dci(2)]);
+    }
+
+    #[test]
+    fn enabled_endpoints_are_read_from_endpoint_states() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x400]));
+        let device_context = DeviceContext::new(0x0, ram);
+        assert!(device_context.enabled_endpoints().is_empty());
+
+        for (endpoint_id, state) in [
+            (1, endpoint_state::RUNNING),
+            (3, endpoint_state::HALTED),
+            (4, endpoint_state::STOPPED),
+            (5, endpoint_state::DISABLED),
+            (31, endpoint_state::ERROR),
+        ] {
+            device_context.set_endpoint_state(dci(endpoint_id), state);
+        }
+        assert_eq!(device_context.enabled_endpoints(), [1, 3, 4, 31].map(dci));
+
+        device_context.set_endpoint_state(dci(3), endpoint_state::DISABLED);
+        assert_eq!(device_context.enabled_endpoints(), [1, 4, 31].map(dci));
+    }
+
+    #[test]
+    fn stream_trbs_are_consumed_per_stream() {
+        let (ram, streams) = ram_with_streams();
+        for address in [0x1000, 0x1010, 0x2000, 0x2010] {
+            write_normal_trb(&ram, address);
+        }
+
+        // Nothing happens before the doorbell.
+        assert_eq!(next_trb_address(&streams), None);
+
+        // Only the stream named by the doorbell is served.
+        streams.notify(2);
+        assert_eq!(next_trb_address(&streams), Some((2, 0x2000)));
+        assert_eq!(next_trb_address(&streams), Some((2, 0x2010)));
+        assert_eq!(next_trb_address(&streams), None);
+
+        // The dequeue pointer of each stream is tracked separately.
+        write_normal_trb(&ram, 0x2020);
+        streams.notify(1);
+        streams.notify(2);
+        assert_eq!(next_trb_address(&streams), Some((1, 0x1000)));
+        assert_eq!(next_trb_address(&streams), Some((2, 0x2020)));
+        assert_eq!(next_trb_address(&streams), Some((1, 0x1010)));
+        assert_eq!(next_trb_address(&streams), None);
+
+        let context = streams.get_stream_context(1).unwrap();
+        assert_eq!(
+            context.get_dequeue_pointer_and_cycle_state(),
+            (0x1020, true)
+        );
+    }
+
+    #[test]
+    fn device_slot_reservation() {
         let mut device_slot_manager =
             DeviceSlotManager::new(MAX_SLOTS, Arc::new(TestBusDevice::default()));
 
@@ -423,4 +1756,127 @@ mod tests {
         }
         assert_eq!(device_slot_manager.reserve_slot(), None);
     }
+
+    /// A slot manager with slot 1 reserved, whose DCBAA at 0x800 points
+    /// to a device context at 0x100.
+    fn counting_slot_manager() -> (Arc<CountingBusDevice>, DeviceSlotManager) {
+        let ram = Arc::new(CountingBusDevice::new(&[0; 0x1000]));
+        ram.write(Request::new(0x808, RequestSize::Size8), 0x100);
+        let mut device_slot_manager = DeviceSlotManager::new(MAX_SLOTS, ram.clone());
+        device_slot_manager.set_dcbaap(0x800);
+        device_slot_manager.reserve_slot();
+        ram.take_operations();
+        (ram, device_slot_manager)
+    }
+
+    #[test]
+    fn device_context_pointer_is_cached() {
+        let (ram, device_slot_manager) = counting_slot_manager();
+
+        assert_eq!(device_slot_manager.get_device_context(1).address, 0x100);
+        assert_eq!(ram.take_operations(), 1);
+        assert_eq!(device_slot_manager.get_device_context(1).address, 0x100);
+        assert_eq!(ram.take_operations(), 0);
+    }
+
+    #[test]
+    fn device_context_cache_is_invalidated() {
+        let (ram, mut device_slot_manager) = counting_slot_manager();
+        let invalidations: [&dyn Fn(&mut DeviceSlotManager); 3] = [
+            &|manager| manager.invalidate_device_context(1),
+            &|manager| manager.invalidate_all_device_contexts(),
+            &|manager| manager.set_dcbaap(0x800),
+        ];
+
+        for invalidate in invalidations {
+            device_slot_manager.get_device_context(1);
+            ram.write(Request::new(0x808, RequestSize::Size8), 0x200);
+            invalidate(&mut device_slot_manager);
+            assert_eq!(device_slot_manager.get_device_context(1).address, 0x200);
+            ram.write(Request::new(0x808, RequestSize::Size8), 0x100);
+            invalidate(&mut device_slot_manager);
+        }
+    }
+
+    #[test]
+    fn null_device_context_pointer_is_not_cached() {
+        let (ram, device_slot_manager) = counting_slot_manager();
+        ram.write(Request::new(0x808, RequestSize::Size8), 0);
+
+        assert_eq!(device_slot_manager.get_device_context(1).address, 0);
+        ram.write(Request::new(0x808, RequestSize::Size8), 0x100);
+        assert_eq!(device_slot_manager.get_device_context(1).address, 0x100);
+    }
+
+    #[test]
+    fn moved_device_context_is_noticed_eventually() {
+        let (ram, device_slot_manager) = counting_slot_manager();
+        device_slot_manager.get_device_context(1);
+
+        // The driver moves the context behind our back.
+        ram.write(Request::new(0x808, RequestSize::Size8), 0x200);
+        let addresses: Vec<u64> = (0..DEVICE_CONTEXT_REVALIDATION_INTERVAL)
+            .map(|_| device_slot_manager.get_device_context(1).address)
+            .collect();
+
+        assert_eq!(addresses[0], 0x100);
+        assert_eq!(*addresses.last().unwrap(), 0x200);
+    }
+
+    #[test]
+    fn usb_address_assignment() {
+        let mut device_slot_manager =
+            DeviceSlotManager::new(MAX_SLOTS, Arc::new(TestBusDevice::default()));
+
+        assert_eq!(device_slot_manager.assign_usb_address(1), Some(1));
+        assert_eq!(device_slot_manager.assign_usb_address(2), Some(2));
+        // Addressing a slot again keeps its address.
+        assert_eq!(device_slot_manager.assign_usb_address(1), Some(1));
+
+        device_slot_manager.release_usb_address(1);
+        assert_eq!(device_slot_manager.assign_usb_address(3), Some(1));
+        assert_eq!(device_slot_manager.assign_usb_address(1), Some(3));
+
+        device_slot_manager.release_all_usb_addresses();
+        assert_eq!(device_slot_manager.assign_usb_address(2), Some(1));
+    }
+
+    #[test]
+    fn usb_addresses_run_out_after_127_devices() {
+        let mut device_slot_manager =
+            DeviceSlotManager::new(MAX_SLOTS, Arc::new(TestBusDevice::default()));
+
+        for slot_id in 1..=MAX_USB_DEVICE_ADDRESS {
+            assert_eq!(
+                device_slot_manager.assign_usb_address(slot_id),
+                Some(slot_id)
+            );
+        }
+        assert_eq!(device_slot_manager.assign_usb_address(128), None);
+
+        device_slot_manager.release_usb_address(64);
+        assert_eq!(device_slot_manager.assign_usb_address(128), Some(64));
+    }
+
+    #[test]
+    fn address_device_writes_usb_address_and_slot_state() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let device_context = DeviceContext::new(0x0, ram.clone());
+        let slot_dword3 = || ram.read(Request::new(12, RequestSize::Size4));
+
+        // With BSR set, the slot stays in the default state.
+        write_input_context(&ram, 0b11, 64);
+        device_context
+            .initialize(INPUT_CONTEXT, None, |_| None)
+            .unwrap();
+        assert_eq!(slot_dword3(), u64::from(slot_state::DEFAULT) << 27);
+
+        device_context
+            .initialize(INPUT_CONTEXT, Some(5), |_| None)
+            .unwrap();
+        assert_eq!(slot_dword3(), u64::from(slot_state::ADDRESSED) << 27 | 5);
+
+        device_context.reset();
+        assert_eq!(slot_dword3(), u64::from(slot_state::DEFAULT) << 27);
+    }
 }
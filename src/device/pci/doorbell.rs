@@ -0,0 +1,129 @@
+//! # Doorbell Registers
+//!
+//! The driver rings a doorbell to tell the controller about new work: the
+//! doorbell of the controller for commands, and the doorbell of a slot for
+//! transfers of one of its endpoints. The value names the endpoint in the
+//! DB Target field and, for endpoints with streams, the stream in the DB
+//! Stream ID field.
+//!
+//! The doorbell array is plain MMIO, so a broken driver can write anything
+//! into it. We ignore doorbells that name no valid target and count them,
+//! instead of acting on them.
+
+use std::fmt;
+
+use tracing::warn;
+
+/// A value written to a doorbell register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DoorbellValue(u32);
+
+impl DoorbellValue {
+    /// The DB Target of the Host Controller Doorbell that announces new
+    /// commands. All other targets are reserved for it.
+    pub const COMMAND_TARGET: u8 = 0;
+
+    /// Decode the value written to a doorbell register.
+    pub const fn new(value: u32) -> Self {
+        Self(value)
+    }
+
+    /// The DB Target field in bits 7:0.
+    pub const fn target(self) -> u8 {
+        (self.0 & 0xff) as u8
+    }
+
+    /// The DB Stream ID field in bits 31:16.
+    pub const fn stream_id(self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+}
+
+impl fmt::Display for DoorbellValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "target {} stream {}", self.target(), self.stream_id())
+    }
+}
+
+/// Why we ignored a doorbell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoredDoorbell {
+    /// The DB Target is reserved for the rung doorbell.
+    ReservedTarget,
+    /// The slot is beyond the slots the driver enabled with MaxSlotsEn.
+    SlotNotEnabled,
+    /// The slot is not enabled and addressed, or its port has no power.
+    InactiveSlot,
+}
+
+impl fmt::Display for IgnoredDoorbell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ReservedTarget => "reserved DB Target",
+            Self::SlotNotEnabled => "slot beyond MaxSlotsEn",
+            Self::InactiveSlot => "slot not enabled and addressed",
+        })
+    }
+}
+
+/// The doorbells we ignored.
+///
+/// A driver that writes garbage tends to do so in a loop, so we warn about
+/// the first ignored doorbell and then only whenever the count doubled.
+#[derive(Debug, Default)]
+pub struct IgnoredDoorbells {
+    count: u64,
+}
+
+impl IgnoredDoorbells {
+    /// Account a doorbell for `slot_id` that we ignored for `reason`.
+    ///
+    /// Returns whether the doorbell was reported with a warning.
+    pub fn record(&mut self, slot_id: u8, value: DoorbellValue, reason: IgnoredDoorbell) -> bool {
+        self.count += 1;
+        let report = self.count.is_power_of_two();
+        if report {
+            warn!(
+                "ignoring doorbell of slot {} with {}: {} ({} ignored so far)",
+                slot_id, value, reason, self.count
+            );
+        }
+        report
+    }
+
+    /// The number of doorbells ignored so far.
+    pub const fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doorbell_values_split_into_target_and_stream() {
+        let value = DoorbellValue::new(0x1234_5603);
+        assert_eq!(value.target(), 3);
+        assert_eq!(value.stream_id(), 0x1234);
+        assert_eq!(value.to_string(), "target 3 stream 4660");
+
+        // Bits 15:8 are reserved and do not leak into either field.
+        let value = DoorbellValue::new(0xff01);
+        assert_eq!(value.target(), 1);
+        assert_eq!(value.stream_id(), 0);
+    }
+
+    #[test]
+    fn ignored_doorbells_are_reported_at_powers_of_two() {
+        let mut ignored = IgnoredDoorbells::default();
+        let mut reported = Vec::new();
+        for _ in 0..9 {
+            if ignored.record(1, DoorbellValue::new(0), IgnoredDoorbell::ReservedTarget) {
+                reported.push(ignored.count());
+            }
+        }
+        assert_eq!(reported, [1, 2, 4, 8]);
+        assert_eq!(ignored.count(), 9);
+    }
+}
@@ -0,0 +1,206 @@
+//! # Per-Endpoint Transfer Statistics
+//!
+//! Throughput problems usually concern a single endpoint, e.g., a bulk
+//! endpoint that moves far fewer bytes per TRB than expected or one that
+//! keeps failing transfers. The TD engine of every endpoint counts its work
+//! in an [`EndpointStats`], and the controller keeps the counters of all
+//! endpoints in an [`EndpointStatsTable`].
+//!
+//! The counters are relaxed atomics, so workers update them without
+//! synchronizing with each other or with readers.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tracing::info;
+
+use super::{dci::Dci, trb::CompletionCode};
+
+/// The counters of one endpoint at a point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EndpointCounters {
+    /// The bytes moved between guest memory and the device.
+    pub bytes: u64,
+    /// The TRBs fetched from the transfer ring.
+    pub trbs: u64,
+    /// The Transfer Events sent to the driver.
+    pub transfer_events: u64,
+    /// The Transfer Events that report an error.
+    pub errors: u64,
+    /// How often the transfer ring ran empty while the endpoint was busy,
+    /// i.e., the device had to wait for the driver to queue more TDs.
+    pub starved: u64,
+}
+
+/// The work one endpoint did.
+#[derive(Debug, Default)]
+pub struct EndpointStats {
+    bytes: AtomicU64,
+    trbs: AtomicU64,
+    transfer_events: AtomicU64,
+    errors: AtomicU64,
+    starved: AtomicU64,
+}
+
+impl EndpointStats {
+    /// Account a TRB fetched from the transfer ring.
+    pub fn record_trb(&self) {
+        self.trbs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account data moved between guest memory and the device.
+    pub fn record_bytes(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Account a Transfer Event with the given completion code.
+    ///
+    /// Short packets and stops are part of normal operation, and ring
+    /// underruns and overruns are only informational. Every other code
+    /// except success counts as an error.
+    pub fn record_transfer_event(&self, completion_code: CompletionCode) {
+        self.transfer_events.fetch_add(1, Ordering::Relaxed);
+        if !matches!(
+            completion_code,
+            CompletionCode::Success
+                | CompletionCode::ShortPacket
+                | CompletionCode::Stopped
+                | CompletionCode::RingUnderrun
+                | CompletionCode::RingOverrun
+        ) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Account a transfer ring that ran empty while the endpoint was busy.
+    pub fn record_starved(&self) {
+        self.starved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The current values of the counters.
+    pub fn counters(&self) -> EndpointCounters {
+        EndpointCounters {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            trbs: self.trbs.load(Ordering::Relaxed),
+            transfer_events: self.transfer_events.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            starved: self.starved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The statistics of all endpoints of the controller, by slot ID and
+/// endpoint ID.
+///
+/// An endpoint keeps its counters when the driver configures it again, so
+/// they cover the whole lifetime of the controller.
+#[derive(Debug, Default)]
+pub struct EndpointStatsTable {
+    endpoints: Mutex<BTreeMap<(u8, Dci), Arc<EndpointStats>>>,
+}
+
+impl EndpointStatsTable {
+    /// The statistics of an endpoint, which are created on first use.
+    pub fn endpoint(&self, slot_id: u8, endpoint_id: Dci) -> Arc<EndpointStats> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .entry((slot_id, endpoint_id))
+            .or_default()
+            .clone()
+    }
+
+    /// The counters of all endpoints as `(slot ID, endpoint ID, counters)`,
+    /// ordered by slot and endpoint.
+    pub fn snapshot(&self) -> Vec<(u8, Dci, EndpointCounters)> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(slot_id, endpoint_id), stats)| (slot_id, endpoint_id, stats.counters()))
+            .collect()
+    }
+
+    /// Log the [`snapshot`](Self::snapshot).
+    pub fn log_summary(&self) {
+        let snapshot = self.snapshot();
+        info!("Endpoint statistics ({} endpoints):", snapshot.len());
+        for (slot_id, endpoint_id, counters) in snapshot {
+            info!(
+                "  slot {slot_id} EP{endpoint_id}: {} bytes, {} TRBs, {} Transfer Events, {} errors, starved {} times",
+                counters.bytes,
+                counters.trbs,
+                counters.transfer_events,
+                counters.errors,
+                counters.starved
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_failures_count_as_errors() {
+        let stats = EndpointStats::default();
+        for completion_code in [
+            CompletionCode::Success,
+            CompletionCode::ShortPacket,
+            CompletionCode::Stopped,
+            CompletionCode::RingUnderrun,
+            CompletionCode::RingOverrun,
+            CompletionCode::TrbError,
+            CompletionCode::DataBufferError,
+        ] {
+            stats.record_transfer_event(completion_code);
+        }
+
+        assert_eq!(
+            stats.counters(),
+            EndpointCounters {
+                transfer_events: 7,
+                errors: 2,
+                ..EndpointCounters::default()
+            }
+        );
+    }
+
+    #[test]
+    fn endpoints_keep_their_counters() {
+        let bulk_in = Dci::new(3).unwrap();
+        let bulk_out = Dci::new(4).unwrap();
+        let table = EndpointStatsTable::default();
+        table.endpoint(2, bulk_in).record_trb();
+        table.endpoint(1, bulk_out).record_bytes(0x200);
+        table.endpoint(2, bulk_in).record_trb();
+
+        assert_eq!(
+            table.snapshot(),
+            [
+                (
+                    1,
+                    bulk_out,
+                    EndpointCounters {
+                        bytes: 0x200,
+                        ..EndpointCounters::default()
+                    }
+                ),
+                (
+                    2,
+                    bulk_in,
+                    EndpointCounters {
+                        trbs: 2,
+                        ..EndpointCounters::default()
+                    }
+                ),
+            ]
+        );
+    }
+}
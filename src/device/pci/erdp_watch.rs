@@ -0,0 +1,208 @@
+//! # Stuck Event Ring Detection
+//!
+//! A guest whose interrupts do not arrive, e.g., because of a wrong MSI-X
+//! routing or a masked Interrupter, does not show it right away: events
+//! pile up on the Event Ring while the driver never advances ERDP, and the
+//! controller stalls once the ring is full. [`ErdpWatch`] notices this
+//! early. It tracks since when the driver has events to process without
+//! advancing ERDP, and raises an [`ErdpAlarm`] once the ring stays mostly
+//! occupied for too long.
+//!
+//! The watch does not read the clock itself. Callers pass the current time,
+//! so tests can simulate a driver that does not make progress.
+
+use std::time::{Duration, Instant};
+
+use super::rings::EventRingOccupancy;
+
+/// How long the driver may leave ERDP unchanged before we suspect that it
+/// does not see our interrupts.
+pub const DEFAULT_STUCK_ERDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The share of the Event Ring, in percent, that unprocessed events have to
+/// occupy before we suspect the driver.
+const OCCUPANCY_THRESHOLD_PERCENT: u64 = 75;
+
+/// Why the driver looks stuck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErdpAlarm {
+    /// The ring is mostly occupied and ERDP did not move for the timeout.
+    Stuck,
+    /// The ring is full and ERDP did not move for the timeout.
+    Full,
+}
+
+/// Watches whether the driver advances ERDP while it has events to process.
+#[derive(Debug)]
+pub struct ErdpWatch {
+    /// How long ERDP may stand still before we raise an alarm.
+    timeout: Duration,
+    /// Since when the driver has unprocessed events without advancing
+    /// ERDP. `None` while it has processed all events.
+    waiting_since: Option<Instant>,
+    /// Whether we warned about the current wait already.
+    warned: bool,
+    /// Whether we reported the full ring of the current wait already.
+    reported_full: bool,
+    /// The number of waits we raised an alarm for.
+    stalls: u64,
+}
+
+impl ErdpWatch {
+    pub const fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            waiting_since: None,
+            warned: false,
+            reported_full: false,
+            stalls: 0,
+        }
+    }
+
+    /// Check the ring after events were posted at `now`.
+    ///
+    /// `occupancy` is only asked for once ERDP stood still for the timeout,
+    /// as it takes reading the segment table. Every wait raises at most
+    /// one [`ErdpAlarm::Stuck`] and one [`ErdpAlarm::Full`], in this order
+    /// unless the ring is full already.
+    pub fn posted(
+        &mut self,
+        now: Instant,
+        occupancy: impl FnOnce() -> Option<EventRingOccupancy>,
+    ) -> Option<ErdpAlarm> {
+        let since = *self.waiting_since.get_or_insert(now);
+        if self.reported_full || now.saturating_duration_since(since) < self.timeout {
+            return None;
+        }
+        let occupancy = occupancy()?;
+        let alarm = if occupancy.is_full() {
+            self.reported_full = true;
+            ErdpAlarm::Full
+        } else if !self.warned && occupancy.percent() >= OCCUPANCY_THRESHOLD_PERCENT {
+            ErdpAlarm::Stuck
+        } else {
+            return None;
+        };
+        if !self.warned {
+            self.warned = true;
+            self.stalls += 1;
+        }
+        Some(alarm)
+    }
+
+    /// The driver moved ERDP at `now`. With an `empty` ring, it has
+    /// processed all events.
+    pub fn advanced(&mut self, now: Instant, empty: bool) {
+        self.waiting_since = (!empty).then_some(now);
+        self.warned = false;
+        self.reported_full = false;
+    }
+
+    /// Forget the current wait, e.g., on controller reset. The statistics
+    /// survive.
+    pub const fn reset(&mut self) {
+        *self = Self {
+            stalls: self.stalls,
+            ..Self::new(self.timeout)
+        };
+    }
+
+    /// How long the driver has had unprocessed events without advancing
+    /// ERDP, if it has any.
+    pub fn age(&self, now: Instant) -> Option<Duration> {
+        self.waiting_since
+            .map(|since| now.saturating_duration_since(since))
+    }
+
+    /// The number of times the driver looked stuck.
+    pub const fn stalls(&self) -> u64 {
+        self.stalls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    const fn occupancy(used: u32) -> Option<EventRingOccupancy> {
+        Some(EventRingOccupancy { used, capacity: 16 })
+    }
+
+    #[test]
+    fn mostly_occupied_ring_warns_once_after_the_timeout() {
+        let start = Instant::now();
+        let mut watch = ErdpWatch::new(TIMEOUT);
+
+        // The ring fills up, but the driver still has time.
+        assert_eq!(watch.posted(start, || occupancy(4)), None);
+        assert_eq!(
+            watch.posted(start + Duration::from_secs(4), || occupancy(12)),
+            None
+        );
+        assert_eq!(
+            watch.age(start + Duration::from_secs(4)),
+            Some(Duration::from_secs(4))
+        );
+
+        let late = start + TIMEOUT;
+        assert_eq!(watch.posted(late, || occupancy(12)), Some(ErdpAlarm::Stuck));
+        assert_eq!(watch.posted(late, || occupancy(13)), None);
+        assert_eq!(watch.stalls(), 1);
+
+        // Becoming full escalates once.
+        assert_eq!(watch.posted(late, || occupancy(15)), Some(ErdpAlarm::Full));
+        assert_eq!(watch.posted(late, || occupancy(15)), None);
+        assert_eq!(watch.stalls(), 1);
+    }
+
+    #[test]
+    fn mostly_empty_ring_does_not_warn() {
+        let start = Instant::now();
+        let mut watch = ErdpWatch::new(TIMEOUT);
+
+        watch.posted(start, || occupancy(1));
+        assert_eq!(watch.posted(start + 2 * TIMEOUT, || occupancy(11)), None);
+        assert_eq!(watch.stalls(), 0);
+    }
+
+    #[test]
+    fn advancing_erdp_restarts_the_wait() {
+        let start = Instant::now();
+        let mut watch = ErdpWatch::new(TIMEOUT);
+
+        watch.posted(start, || occupancy(12));
+        watch.advanced(start + Duration::from_secs(4), false);
+        assert_eq!(watch.posted(start + TIMEOUT, || occupancy(12)), None);
+
+        // A driver that processed everything does not wait for anything,
+        // so the next wait starts with the next event.
+        watch.advanced(start + TIMEOUT, true);
+        assert_eq!(watch.age(start + 2 * TIMEOUT), None);
+        let later = start + 3 * TIMEOUT;
+        assert_eq!(watch.posted(later, || occupancy(15)), None);
+        assert_eq!(
+            watch.posted(later + TIMEOUT, || occupancy(15)),
+            Some(ErdpAlarm::Full)
+        );
+        assert_eq!(watch.stalls(), 1);
+
+        // After the driver caught up, a new wait warns again.
+        watch.advanced(later + TIMEOUT, false);
+        assert_eq!(
+            watch.posted(later + 2 * TIMEOUT, || occupancy(12)),
+            Some(ErdpAlarm::Stuck)
+        );
+        assert_eq!(watch.stalls(), 2);
+    }
+
+    #[test]
+    fn unknown_occupancy_raises_no_alarm() {
+        let start = Instant::now();
+        let mut watch = ErdpWatch::new(TIMEOUT);
+
+        watch.posted(start, || None);
+        assert_eq!(watch.posted(start + TIMEOUT, || None), None);
+    }
+}
@@ -0,0 +1,203 @@
+//! # Transfer Event Coalescing
+//!
+//! Endpoint workers report every completed TRB that has the IOC flag set
+//! with a Transfer Event. Raising an interrupt for each of these events is
+//! expensive when a bulk endpoint streams many small TRBs.
+//!
+//! A [`TransferEventBatch`] collects the Transfer Events of one worker and
+//! posts them to the [`EventSink`] in one go, followed by a single
+//! interrupt. Workers flush the batch before they go to sleep, i.e., at the
+//! end of each doorbell burst, whenever the oldest pending event exceeds
+//! the coalescing window, and when [`MAX_IN_FLIGHT_EVENTS`] events are
+//! pending. The latter bounds how many events a worker can add to a
+//! congested [`EventSink`] before it notices the congestion and stops.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use super::{event_sink::EventSink, trb::EventTrb};
+
+/// The maximum number of events a batch holds back.
+pub const MAX_IN_FLIGHT_EVENTS: usize = 32;
+
+/// Pending Transfer Events of one endpoint worker.
+#[derive(Debug)]
+pub struct TransferEventBatch {
+    /// The sink to post transfer events to.
+    event_sink: Arc<EventSink>,
+    /// The maximum time an event may be held back. `None` disables
+    /// coalescing, i.e., every event is sent right away.
+    window: Option<Duration>,
+    /// Events that have not been enqueued yet.
+    pending: Vec<EventTrb>,
+    /// When the oldest pending event was added.
+    oldest: Option<Instant>,
+}
+
+impl TransferEventBatch {
+    /// Create a new, empty batch.
+    ///
+    /// # Parameters
+    ///
+    /// - `event_sink`: the sink to post the events to.
+    /// - `window`: the coalescing window, or `None` to disable coalescing.
+    pub const fn new(event_sink: Arc<EventSink>, window: Option<Duration>) -> Self {
+        Self {
+            event_sink,
+            window,
+            pending: vec![],
+            oldest: None,
+        }
+    }
+
+    /// Add a Transfer Event to the batch.
+    ///
+    /// Without coalescing, the event is sent immediately. Otherwise, the
+    /// batch is flushed once the coalescing window of the oldest event
+    /// expired or the batch is full.
+    pub fn push(&mut self, event: EventTrb) {
+        self.pending.push(event);
+        self.oldest.get_or_insert_with(Instant::now);
+
+        if self.pending.len() >= MAX_IN_FLIGHT_EVENTS
+            || self
+                .deadline()
+                .is_none_or(|deadline| deadline <= Instant::now())
+        {
+            self.flush();
+        }
+    }
+
+    /// The point in time at which the pending events have to be sent.
+    ///
+    /// Returns `None` if there are no pending events or coalescing is
+    /// disabled. Workers use the deadline to bound how long they block.
+    pub fn deadline(&self) -> Option<Instant> {
+        Some(self.oldest? + self.window?)
+    }
+
+    /// Post all pending events with a single interrupt.
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        self.event_sink.post_batch(&self.pending);
+        debug!("sent {} Transfer Event(s)", self.pending.len());
+
+        self.pending.clear();
+        self.oldest = None;
+    }
+}
+
+impl Drop for TransferEventBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{
+        bus::{testutils::TestBusDevice, BusDevice, Request, RequestSize},
+        pci::{
+            constants::xhci::runtime::iman,
+            dci::Dci,
+            event_sink::testutils::{event_sink, CountingInterruptLine},
+            trb::CompletionCode,
+        },
+    };
+
+    use super::*;
+
+    /// Create a batch on an enabled event sink in `ram`.
+    fn batch(
+        ram: Arc<TestBusDevice>,
+        window: Option<Duration>,
+    ) -> (TransferEventBatch, Arc<CountingInterruptLine>) {
+        let sink = event_sink(ram);
+        let interrupt_line = Arc::new(CountingInterruptLine::default());
+        sink.connect_irq(interrupt_line.clone());
+        sink.write_iman(iman::IE);
+        (
+            TransferEventBatch::new(Arc::new(sink), window),
+            interrupt_line,
+        )
+    }
+
+    fn transfer_event(trb_pointer: u64) -> EventTrb {
+        EventTrb::new_transfer_event_trb(
+            trb_pointer,
+            0,
+            CompletionCode::Success,
+            false,
+            Dci::new(2).unwrap(),
+            1,
+        )
+    }
+
+    #[test]
+    fn coalesced_events_raise_one_interrupt() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let (mut batch, interrupt_line) = batch(ram.clone(), Some(Duration::from_secs(60)));
+
+        for i in 0..4 {
+            batch.push(transfer_event(0x1000 + i * 0x10));
+        }
+        assert_eq!(interrupt_line.count(), 0);
+        assert!(batch.deadline().is_some());
+
+        batch.flush();
+        assert_eq!(interrupt_line.count(), 1);
+        assert_eq!(batch.deadline(), None);
+        // All four events went into the ring.
+        let cycle_bit = |addr: u64| ram.read(Request::new(addr + 12, RequestSize::Size1)) & 0x1;
+        assert_eq!(
+            [0x100, 0x110, 0x120, 0x130, 0x140].map(cycle_bit),
+            [1, 1, 1, 1, 0]
+        );
+
+        // Flushing an empty batch does not raise another interrupt.
+        batch.flush();
+        assert_eq!(interrupt_line.count(), 1);
+    }
+
+    #[test]
+    fn events_are_sent_immediately_without_coalescing() {
+        let (mut batch, interrupt_line) = batch(Arc::new(TestBusDevice::new(&[0; 0x200])), None);
+
+        for i in 0..3 {
+            batch.push(transfer_event(0x1000 + i * 0x10));
+        }
+        assert_eq!(interrupt_line.count(), 3);
+    }
+
+    #[test]
+    fn expired_window_flushes_on_push() {
+        let (mut batch, interrupt_line) = batch(
+            Arc::new(TestBusDevice::new(&[0; 0x200])),
+            Some(Duration::ZERO),
+        );
+
+        batch.push(transfer_event(0x1000));
+        assert_eq!(interrupt_line.count(), 1);
+    }
+
+    #[test]
+    fn full_batch_is_flushed() {
+        let (mut batch, interrupt_line) = batch(
+            Arc::new(TestBusDevice::new(&[0; 0x200])),
+            Some(Duration::from_secs(60)),
+        );
+
+        for i in 0..MAX_IN_FLIGHT_EVENTS as u64 {
+            batch.push(transfer_event(0x1000 + i * 0x10));
+        }
+        assert_eq!(interrupt_line.count(), 1);
+        assert_eq!(batch.deadline(), None);
+    }
+}
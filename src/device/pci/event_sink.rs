@@ -0,0 +1,1066 @@
+//! # Event Posting
+//!
+//! The controller and all endpoint workers report events to the driver via
+//! the Event Ring of the single Interrupter. Posting an event involves more
+//! than enqueueing a TRB:
+//!
+//! 1. Data the event refers to (e.g., DMA writes of IN transfers) must be
+//!    visible to the driver before the event is, so a release fence precedes
+//!    every enqueue.
+//! 2. The event interrupt status (`USBSTS.EINT`) is set.
+//! 3. An interrupt is only asserted when the driver enabled the Interrupter
+//!    (`IMAN.IE`). Otherwise, the interrupt stays pending (`IMAN.IP`) and is
+//!    asserted once the driver enables the Interrupter.
+//! 4. Event Handler Busy (`ERDP.EHB`) is set with the interrupt and stays
+//!    set until the driver clears it with its next ERDP write. We do not
+//!    hold back interrupts while it is set, as drivers only clear it at the
+//!    end of their handler and catch up with new events in the next one.
+//!
+//! [`EventSink`] bundles these steps, so that all places that post events
+//! follow the same discipline.
+//!
+//! ## Full Event Ring
+//!
+//! When the driver does not keep up, the Event Ring fills up. The sink then
+//! defers further events until the driver frees space by advancing ERDP.
+//! The number of deferred events is bounded. Beyond the bound, events are
+//! dropped and counted. A Port Status Change Event for a port that already
+//! has one deferred is always redundant and skipped, because the driver
+//! reads the port's status from PORTSC anyway.
+//!
+//! Ordering guarantees:
+//!
+//! - Events reach the Event Ring in the order they were posted. A deferred
+//!   event is never overtaken by a later one.
+//! - If events were dropped, the driver gets a single Host Controller Event
+//!   with an Event Ring Full Error once space opens up, followed by the
+//!   retained events in order. The dropped events were posted after all
+//!   retained ones.
+//!
+//! ## Unconfigured Event Ring
+//!
+//! Until the driver programs ERSTSZ and ERSTBA, the Event Ring has no
+//! place in guest memory. The controller posts events before that, e.g.,
+//! Port Status Change Events for devices attached at startup when the
+//! driver sets USBCMD.R/S right after the Interrupter registers. Such
+//! events are deferred like on a full ring and reach the Event Ring once
+//! the driver configured it.
+//!
+//! ## Backpressure
+//!
+//! Deferring only helps against short bursts. Endpoint workers produce
+//! events as fast as the device completes transfers, so a slow driver would
+//! still end up losing events. As the specification demands in §4.9.4,
+//! workers therefore stop fetching TRBs from their Transfer Rings while the
+//! sink is congested, i.e., the Event Ring is full or events are deferred,
+//! and resume once the driver advances ERDP. See
+//! [`wait_for_space`](EventSink::wait_for_space).
+//!
+//! ## Stuck Drivers
+//!
+//! A driver that never advances ERDP usually does not receive our
+//! interrupts. The sink watches for this with an
+//! [`ErdpWatch`](super::erdp_watch::ErdpWatch) and warns with the state of
+//! the Interrupter long before the ring is full.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{fence, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    task::Waker,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "nusb-backend")]
+use std::{future::Future, task::Poll};
+
+use tracing::{error, trace, warn};
+
+use crate::device::{
+    bus::BusDeviceRef,
+    interrupt_line::{DummyInterruptLine, InterruptLine},
+    pci::constants::xhci::{
+        operational::usbsts,
+        runtime::{erdp, iman},
+    },
+};
+
+use super::{
+    erdp_watch::{ErdpAlarm, ErdpWatch},
+    registers::ImodRegister,
+    rings::{EventRing, EventRingOccupancy},
+    trb::{CompletionCode, EventTrb},
+};
+
+/// The default bound for events waiting for space on the Event Ring.
+pub const DEFAULT_MAX_DEFERRED_EVENTS: NonZeroUsize = NonZeroUsize::new(256).unwrap();
+
+/// The single place through which events reach the driver.
+#[derive(Debug)]
+pub struct EventSink {
+    /// The Event Ring of the Interrupter.
+    event_ring: Mutex<EventRing>,
+    /// Events waiting for space on the Event Ring.
+    ///
+    /// Always lock `event_ring` first.
+    deferred: Mutex<DeferredEvents>,
+    /// Signaled when the sink stops being congested.
+    space: Condvar,
+    /// Interrupt status and the line to signal interrupts on.
+    interrupter: Mutex<Interrupter>,
+    /// Whether the driver keeps up with the events.
+    ///
+    /// Always lock `event_ring` first.
+    watch: Mutex<ErdpWatch>,
+}
+
+/// The state of the Event Ring, for diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventRingStatus {
+    /// How much of the ring holds unprocessed events, if known.
+    pub occupancy: Option<EventRingOccupancy>,
+    /// How long the driver has had unprocessed events without advancing
+    /// ERDP, if it has any.
+    pub last_advance_age: Option<Duration>,
+    /// How often the driver looked stuck.
+    pub stalls: u64,
+    /// How many ERDP writes were ignored because they pointed outside the
+    /// Event Ring.
+    pub rejected_dequeue_pointers: u64,
+    /// How many events were dropped because the driver had not set up the
+    /// Event Ring yet.
+    pub unconfigured_drops: u64,
+    /// How many events were dropped because the Event Ring was full and
+    /// too many events were deferred already.
+    pub dropped_events: u64,
+}
+
+#[derive(Debug)]
+struct Interrupter {
+    /// The interrupt line triggered to signal events.
+    interrupt_line: Arc<dyn InterruptLine>,
+    /// Interrupt Enable (`IMAN.IE`).
+    enabled: bool,
+    /// Interrupt Pending (`IMAN.IP`).
+    ///
+    /// Only set while the Interrupter is disabled. With MSI-X, the flag is
+    /// cleared automatically when the interrupt is asserted.
+    pending: bool,
+    /// Event Interrupt (`USBSTS.EINT`).
+    event_interrupt: bool,
+    /// Interrupter Moderation (`IMOD`).
+    moderation: ImodRegister,
+    /// Event Handler Busy (`ERDP.EHB`).
+    handler_busy: bool,
+}
+
+impl Interrupter {
+    /// Assert an interrupt, or keep it pending if the Interrupter is
+    /// disabled.
+    fn assert(&mut self) {
+        self.event_interrupt = true;
+        self.handler_busy = true;
+        if self.enabled {
+            self.signal();
+        } else {
+            trace!("interrupter disabled; keeping interrupt pending");
+            self.pending = true;
+        }
+    }
+
+    /// Trigger the interrupt line. Interrupt Pending clears right away, so
+    /// the moderation counter starts over.
+    fn signal(&mut self) {
+        self.interrupt_line.interrupt();
+        self.moderation.reload(Instant::now());
+    }
+}
+
+#[derive(Debug)]
+struct DeferredEvents {
+    /// The events in posting order.
+    events: VecDeque<EventTrb>,
+    /// The maximum number of deferred events.
+    capacity: NonZeroUsize,
+    /// Whether events were dropped since the driver was last told with an
+    /// Event Ring Full Error.
+    lost: bool,
+    /// The number of dropped events since the controller was created.
+    dropped: u64,
+    /// Whether the Event Ring is full or events are deferred. Updated
+    /// whenever the Event Ring or the deferred events change.
+    congested: bool,
+    /// Endpoint futures waiting for the sink to become uncongested.
+    waiters: Vec<Waker>,
+}
+
+impl DeferredEvents {
+    const fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            capacity,
+            lost: false,
+            dropped: 0,
+            congested: false,
+            waiters: vec![],
+        }
+    }
+
+    /// Whether later events have to queue up behind deferred ones.
+    fn is_pending(&self) -> bool {
+        self.lost || !self.events.is_empty()
+    }
+
+    fn push(&mut self, trb: &EventTrb) {
+        if let EventTrb::PortStatusChange(data) = trb {
+            let redundant = self.events.iter().any(
+                |deferred| matches!(deferred, EventTrb::PortStatusChange(d) if d.port_id() == data.port_id()),
+            );
+            if redundant {
+                trace!("skipping redundant deferred event {:?}", trb);
+                return;
+            }
+        }
+
+        if self.events.len() < self.capacity.get() {
+            self.events.push_back(trb.clone());
+            return;
+        }
+
+        if !self.lost {
+            warn!(
+                "dropping events, the driver does not process the event ring ({} events deferred)",
+                self.events.len()
+            );
+        }
+        self.lost = true;
+        self.dropped += 1;
+        trace!("dropped event {:?}", trb);
+    }
+
+    /// Move deferred events to the Event Ring as long as there is space.
+    ///
+    /// Returns whether any event was enqueued.
+    fn drain(&mut self, event_ring: &mut EventRing) -> bool {
+        let mut enqueued = false;
+        while self.is_pending() && !event_ring.is_full() {
+            if self.lost {
+                self.lost = false;
+                event_ring.enqueue(&EventTrb::new_host_controller_event_trb(
+                    CompletionCode::EventRingFullError,
+                ));
+            } else if let Some(trb) = self.events.pop_front() {
+                event_ring.enqueue(&trb);
+            }
+            enqueued = true;
+        }
+        enqueued
+    }
+
+    /// Recompute whether the sink is congested.
+    ///
+    /// Returns whether the congestion cleared, i.e., waiters have to be
+    /// woken up.
+    fn update_congestion(&mut self, event_ring: &EventRing) -> bool {
+        let was_congested = self.congested;
+        self.congested = self.is_pending() || event_ring.is_full();
+        if was_congested && !self.congested {
+            self.waiters.drain(..).for_each(Waker::wake);
+            return true;
+        }
+        false
+    }
+}
+
+impl EventSink {
+    /// Create a new sink with an unconfigured Event Ring.
+    ///
+    /// Interrupts go nowhere until an interrupt line is connected with
+    /// [`connect_irq`](Self::connect_irq).
+    ///
+    /// # Parameters
+    ///
+    /// - `dma_bus`: access to guest memory, where the Event Ring lives.
+    /// - `max_deferred_events`: how many events may wait for space on a
+    ///   full Event Ring before further events are dropped.
+    /// - `stuck_erdp_timeout`: how long the driver may leave a mostly
+    ///   occupied Event Ring alone before we warn about it.
+    pub fn new(
+        dma_bus: BusDeviceRef,
+        max_deferred_events: NonZeroUsize,
+        stuck_erdp_timeout: Duration,
+    ) -> Self {
+        Self {
+            event_ring: Mutex::new(EventRing::new(dma_bus)),
+            deferred: Mutex::new(DeferredEvents::new(max_deferred_events)),
+            space: Condvar::new(),
+            interrupter: Mutex::new(Interrupter {
+                interrupt_line: Arc::new(DummyInterruptLine::default()),
+                enabled: false,
+                pending: false,
+                event_interrupt: false,
+                moderation: ImodRegister::new(Instant::now()),
+                handler_busy: false,
+            }),
+            watch: Mutex::new(ErdpWatch::new(stuck_erdp_timeout)),
+        }
+    }
+
+    /// Configure the interrupt line to signal events on.
+    pub fn connect_irq(&self, interrupt_line: Arc<dyn InterruptLine>) {
+        self.interrupter.lock().unwrap().interrupt_line = interrupt_line;
+    }
+
+    /// Return the Event Ring and the Interrupter to their state after
+    /// controller reset. The interrupt line stays connected.
+    pub fn reset(&self) {
+        let mut event_ring = self.event_ring();
+        event_ring.reset();
+        self.watch.lock().unwrap().reset();
+        let mut deferred = self.deferred.lock().unwrap();
+        deferred.events.clear();
+        deferred.lost = false;
+        if deferred.update_congestion(&event_ring) {
+            self.space.notify_all();
+        }
+        drop(deferred);
+        drop(event_ring);
+
+        let mut interrupter = self.interrupter.lock().unwrap();
+        interrupter.enabled = false;
+        interrupter.pending = false;
+        interrupter.event_interrupt = false;
+        interrupter.moderation = ImodRegister::new(Instant::now());
+        interrupter.handler_busy = false;
+    }
+
+    /// Access the Event Ring, e.g., to handle register accesses.
+    ///
+    /// Do not enqueue events directly, use [`post`](Self::post) instead.
+    /// Handle ERDP writes with
+    /// [`update_dequeue_pointer`](Self::update_dequeue_pointer).
+    pub fn event_ring(&self) -> MutexGuard<'_, EventRing> {
+        self.event_ring.lock().unwrap()
+    }
+
+    /// Post a single event to the driver.
+    pub fn post(&self, trb: EventTrb) {
+        self.post_batch(&[trb]);
+    }
+
+    /// Post multiple events to the driver with a single interrupt.
+    pub fn post_batch(&self, trbs: &[EventTrb]) {
+        if trbs.is_empty() {
+            return;
+        }
+
+        // Event producers might have performed stores to guest memory that
+        // the events refer to. The stores have to be finished before the
+        // events are written (essentially releasing the data to the driver).
+        fence(Ordering::Release);
+        let mut event_ring = self.event_ring();
+        let configured = event_ring.is_configured();
+        let mut deferred = self.deferred.lock().unwrap();
+        let mut enqueued = false;
+        for trb in trbs {
+            if deferred.is_pending() || event_ring.is_full() {
+                deferred.push(trb);
+            } else {
+                event_ring.enqueue(trb);
+                enqueued = true;
+            }
+        }
+        deferred.update_congestion(&event_ring);
+        drop(deferred);
+        // An unconfigured ring cannot be processed yet.
+        let alarm = configured
+            .then(|| {
+                self.watch
+                    .lock()
+                    .unwrap()
+                    .posted(Instant::now(), || event_ring.occupancy())
+            })
+            .flatten();
+        let occupancy = alarm.and_then(|_| event_ring.occupancy());
+        drop(event_ring);
+
+        let mut interrupter = self.interrupter.lock().unwrap();
+        if enqueued {
+            interrupter.assert();
+        } else if configured {
+            // The driver has unprocessed events and gets to the deferred
+            // ones once it frees space.
+            interrupter.event_interrupt = true;
+        }
+        drop(interrupter);
+
+        if let (Some(alarm), Some(occupancy)) = (alarm, occupancy) {
+            self.report_stuck_driver(alarm, occupancy);
+        }
+    }
+
+    /// Tell the user that the driver does not process its events, with the
+    /// state that decides whether it receives interrupts.
+    fn report_stuck_driver(&self, alarm: ErdpAlarm, occupancy: EventRingOccupancy) {
+        let age = self
+            .watch
+            .lock()
+            .unwrap()
+            .age(Instant::now())
+            .unwrap_or_default();
+        let iman = self.read_iman();
+        let imod = self.read_imod();
+        let interrupt_line = self.interrupter.lock().unwrap().interrupt_line.clone();
+        match alarm {
+            ErdpAlarm::Stuck => warn!(
+                "the driver did not advance ERDP for {:?} with {} of the event ring occupied; it may not receive interrupts (IMAN {:#x}, IMOD {:#x}, interrupt line {:?})",
+                age, occupancy, iman, imod, interrupt_line
+            ),
+            ErdpAlarm::Full => error!(
+                "the event ring is full and the driver did not advance ERDP for {:?}; events wait or get lost from now on (IMAN {:#x}, IMOD {:#x}, interrupt line {:?})",
+                age, iman, imod, interrupt_line
+            ),
+        }
+    }
+
+    /// Handle writes to the Event Ring Segment Table Size (ERSTSZ).
+    ///
+    /// If the write completes the configuration of the Event Ring, deferred
+    /// events move to it.
+    pub fn set_segment_table_size(&self, erstsz: u32) {
+        let mut event_ring = self.event_ring();
+        event_ring.set_erst_size(erstsz);
+        self.after_ring_update(event_ring);
+    }
+
+    /// Handle writes to the Event Ring Segment Table Base Address (ERSTBA).
+    ///
+    /// When the driver relocates the segment table or the write completes
+    /// the configuration, deferred events move to the empty ring.
+    pub fn configure_segment_table(&self, erstba: u64) {
+        {
+            let mut event_ring = self.event_ring();
+            event_ring.configure(erstba);
+            self.after_ring_update(event_ring);
+        }
+        // The driver starts over on the new ring.
+        self.note_progress();
+    }
+
+    /// Handle reads of the Event Ring Dequeue Pointer (ERDP), which
+    /// includes the Event Handler Busy flag.
+    pub fn read_erdp(&self) -> u64 {
+        let dequeue_pointer = self.event_ring().read_dequeue_pointer();
+        let busy = self.interrupter.lock().unwrap().handler_busy;
+        dequeue_pointer | (u64::from(busy) * erdp::EHB)
+    }
+
+    /// Handle writes to the Event Ring Dequeue Pointer (ERDP).
+    ///
+    /// Writing 1 to EHB clears it. Deferred events move to the space the
+    /// driver freed.
+    pub fn update_dequeue_pointer(&self, erdp: u64) {
+        if erdp & erdp::EHB != 0 {
+            self.interrupter.lock().unwrap().handler_busy = false;
+        }
+        let advanced = {
+            let mut event_ring = self.event_ring();
+            let previous = event_ring.read_dequeue_pointer();
+            event_ring.update_dequeue_pointer(erdp);
+            let advanced = event_ring.read_dequeue_pointer() != previous;
+            self.after_ring_update(event_ring);
+            advanced
+        };
+        // Deferred events may have filled the freed space already.
+        if advanced {
+            self.note_progress();
+        }
+    }
+
+    /// Restart the wait for the driver, which made progress on the ring.
+    fn note_progress(&self) {
+        let event_ring = self.event_ring();
+        self.watch
+            .lock()
+            .unwrap()
+            .advanced(Instant::now(), event_ring.is_empty());
+    }
+
+    /// The state of the Event Ring and of the driver processing it.
+    pub fn event_ring_status(&self) -> EventRingStatus {
+        let dropped_events = self.dropped_events();
+        let event_ring = self.event_ring();
+        let watch = self.watch.lock().unwrap();
+        EventRingStatus {
+            occupancy: event_ring.occupancy(),
+            last_advance_age: watch.age(Instant::now()),
+            stalls: watch.stalls(),
+            rejected_dequeue_pointers: event_ring.rejected_dequeue_pointers(),
+            unconfigured_drops: event_ring.unconfigured_drops(),
+            dropped_events,
+        }
+    }
+
+    /// Move deferred events to the space the Event Ring has after the
+    /// driver changed it.
+    fn after_ring_update(&self, mut event_ring: MutexGuard<'_, EventRing>) {
+        let mut deferred = self.deferred.lock().unwrap();
+        let enqueued = deferred.drain(&mut event_ring);
+        if deferred.update_congestion(&event_ring) {
+            self.space.notify_all();
+        }
+        drop(deferred);
+        drop(event_ring);
+
+        if enqueued {
+            self.interrupter.lock().unwrap().assert();
+        }
+    }
+
+    /// Whether events posted now would not fit on the Event Ring.
+    pub fn is_congested(&self) -> bool {
+        self.deferred.lock().unwrap().congested
+    }
+
+    /// Block until the sink is no longer congested.
+    ///
+    /// Endpoint workers call this before they fetch the next TRB, so that
+    /// they only produce events the driver has space for. Any events the
+    /// worker holds back have to be posted before, otherwise the driver
+    /// might never get to the point of freeing space.
+    pub fn wait_for_space(&self) {
+        let deferred = self.deferred.lock().unwrap();
+        drop(
+            self.space
+                .wait_while(deferred, |deferred| deferred.congested)
+                .unwrap(),
+        );
+    }
+
+    /// The async counterpart of [`wait_for_space`](Self::wait_for_space).
+    #[cfg(feature = "nusb-backend")]
+    pub fn space(&self) -> impl Future<Output = ()> + '_ {
+        std::future::poll_fn(|cx| {
+            let mut deferred = self.deferred.lock().unwrap();
+            if !deferred.congested {
+                return Poll::Ready(());
+            }
+            if !deferred.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                deferred.waiters.push(cx.waker().clone());
+            }
+            drop(deferred);
+            Poll::Pending
+        })
+    }
+
+    /// The number of events dropped because the Event Ring was full and
+    /// too many events were deferred already.
+    pub fn dropped_events(&self) -> u64 {
+        self.deferred.lock().unwrap().dropped
+    }
+
+    /// Handle reads of the Interrupter Management Register (IMAN).
+    pub fn read_iman(&self) -> u64 {
+        let interrupter = self.interrupter.lock().unwrap();
+        (u64::from(interrupter.pending) * iman::IP) | (u64::from(interrupter.enabled) * iman::IE)
+    }
+
+    /// Handle writes to the Interrupter Management Register (IMAN).
+    ///
+    /// Enabling the Interrupter asserts a pending interrupt.
+    pub fn write_iman(&self, value: u64) {
+        let mut interrupter = self.interrupter.lock().unwrap();
+        if value & iman::IP != 0 && interrupter.pending {
+            interrupter.pending = false;
+            interrupter.moderation.reload(Instant::now());
+        }
+        interrupter.enabled = value & iman::IE != 0;
+
+        if interrupter.enabled && interrupter.pending {
+            interrupter.pending = false;
+            interrupter.signal();
+        }
+    }
+
+    /// Handle reads of the Interrupter Moderation Register (IMOD).
+    pub fn read_imod(&self) -> u64 {
+        self.interrupter
+            .lock()
+            .unwrap()
+            .moderation
+            .read(Instant::now())
+    }
+
+    /// Handle writes to the Interrupter Moderation Register (IMOD).
+    pub fn write_imod(&self, value: u64) {
+        self.interrupter
+            .lock()
+            .unwrap()
+            .moderation
+            .write(value, Instant::now());
+    }
+
+    /// The `USBSTS` bits maintained by the sink.
+    pub fn usbsts(&self) -> u64 {
+        u64::from(self.interrupter.lock().unwrap().event_interrupt) * usbsts::EINT
+    }
+
+    /// Handle writes to `USBSTS`, of which the sink owns the RW1C bit
+    /// `EINT`.
+    pub fn write_usbsts(&self, value: u64) {
+        if value & usbsts::EINT != 0 {
+            self.interrupter.lock().unwrap().event_interrupt = false;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod testutils {
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::device::{
+        bus::testutils::TestBusDevice, pci::erdp_watch::DEFAULT_STUCK_ERDP_TIMEOUT,
+    };
+
+    use super::*;
+
+    /// An interrupt line that counts how often it was triggered.
+    #[derive(Debug, Default)]
+    pub struct CountingInterruptLine {
+        count: AtomicUsize,
+    }
+
+    impl InterruptLine for CountingInterruptLine {
+        fn interrupt(&self) {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl CountingInterruptLine {
+        pub fn count(&self) -> usize {
+            self.count.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Create a sink whose Event Ring has a single segment of 16 TRBs at
+    /// 0x100 in `ram`.
+    pub fn event_sink(ram: Arc<TestBusDevice>) -> EventSink {
+        event_sink_with_deferral_bound(ram, DEFAULT_MAX_DEFERRED_EVENTS.get())
+    }
+
+    /// Like [`event_sink`], but with a custom bound for deferred events.
+    pub fn event_sink_with_deferral_bound(
+        ram: Arc<TestBusDevice>,
+        max_deferred_events: usize,
+    ) -> EventSink {
+        // segment_base = 0x100, trb_count = 16
+        ram.write_bulk(
+            0x0,
+            &[0x00, 0x01, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let sink = EventSink::new(
+            ram,
+            NonZeroUsize::new(max_deferred_events).unwrap(),
+            DEFAULT_STUCK_ERDP_TIMEOUT,
+        );
+        {
+            let mut ring = sink.event_ring();
+            ring.set_erst_size(1);
+            ring.configure(0x0);
+            ring.update_dequeue_pointer(0x100);
+        }
+        sink
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicBool, mpsc},
+        thread,
+        time::Duration,
+    };
+
+    use crate::device::{
+        bus::{
+            testutils::{CountingBusDevice, TestBusDevice},
+            BusDevice, Request, RequestSize,
+        },
+        pci::{
+            constants::xhci::{
+                rings::trb_types,
+                runtime::{erdp, imod, IMOD_DEFAULT},
+            },
+            dci::Dci,
+            erdp_watch::DEFAULT_STUCK_ERDP_TIMEOUT,
+            trb::CompletionCode,
+        },
+    };
+
+    use super::{testutils::*, *};
+
+    fn transfer_event(trb_pointer: u64) -> EventTrb {
+        EventTrb::new_transfer_event_trb(
+            trb_pointer,
+            0,
+            CompletionCode::Success,
+            false,
+            Dci::new(2).unwrap(),
+            1,
+        )
+    }
+
+    fn cycle_bit(ram: &TestBusDevice, addr: u64) -> u64 {
+        ram.read(Request::new(addr + 12, RequestSize::Size1)) & 0x1
+    }
+
+    /// An interrupt line that checks that the first event is visible in
+    /// guest memory when the interrupt arrives.
+    #[derive(Debug)]
+    struct CheckingInterruptLine {
+        ram: Arc<TestBusDevice>,
+        checked: AtomicBool,
+    }
+
+    impl InterruptLine for CheckingInterruptLine {
+        fn interrupt(&self) {
+            assert_eq!(cycle_bit(&self.ram, 0x100), 1, "interrupt before event");
+            self.checked.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn event_is_written_before_interrupt() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let sink = event_sink(ram.clone());
+        let line = Arc::new(CheckingInterruptLine {
+            ram,
+            checked: AtomicBool::new(false),
+        });
+        sink.connect_irq(line.clone());
+        sink.write_iman(iman::IE);
+
+        sink.post(transfer_event(0x1000));
+        assert!(line.checked.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn batch_raises_one_interrupt_and_sets_eint() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let sink = event_sink(ram.clone());
+        let line = Arc::new(CountingInterruptLine::default());
+        sink.connect_irq(line.clone());
+        sink.write_iman(iman::IE);
+
+        sink.post_batch(&[transfer_event(0x1000), transfer_event(0x1010)]);
+        assert_eq!(line.count(), 1);
+        assert_eq!([0x100, 0x110, 0x120].map(|a| cycle_bit(&ram, a)), [1, 1, 0]);
+
+        assert_eq!(sink.usbsts(), usbsts::EINT);
+        sink.write_usbsts(usbsts::EINT);
+        assert_eq!(sink.usbsts(), 0);
+
+        // An empty batch neither enqueues nor interrupts.
+        sink.post_batch(&[]);
+        assert_eq!(line.count(), 1);
+        assert_eq!(sink.usbsts(), 0);
+    }
+
+    #[test]
+    fn disabled_interrupter_keeps_interrupt_pending() {
+        let sink = event_sink(Arc::new(TestBusDevice::new(&[0; 0x200])));
+        let line = Arc::new(CountingInterruptLine::default());
+        sink.connect_irq(line.clone());
+
+        sink.post(transfer_event(0x1000));
+        sink.post(transfer_event(0x1010));
+        assert_eq!(line.count(), 0);
+        assert_eq!(sink.read_iman(), iman::IP);
+        assert_eq!(sink.usbsts(), usbsts::EINT);
+
+        // Enabling the interrupter delivers the pending interrupt once.
+        sink.write_iman(iman::IE);
+        assert_eq!(line.count(), 1);
+        assert_eq!(sink.read_iman(), iman::IE);
+
+        sink.post(transfer_event(0x1020));
+        assert_eq!(line.count(), 2);
+    }
+
+    #[test]
+    fn events_wait_for_the_event_ring_to_be_configured() {
+        let ram = Arc::new(CountingBusDevice::new(&[0; 0x200]));
+        let sink = EventSink::new(
+            ram.clone(),
+            DEFAULT_MAX_DEFERRED_EVENTS,
+            DEFAULT_STUCK_ERDP_TIMEOUT,
+        );
+        let line = Arc::new(CountingInterruptLine::default());
+        sink.connect_irq(line.clone());
+        sink.write_iman(iman::IE);
+
+        sink.post(EventTrb::new_port_status_change_event_trb(1));
+        sink.post(transfer_event(0x1000));
+        // The enqueue pointer of the unconfigured ring is 0, but nothing
+        // reaches guest memory.
+        assert_eq!(ram.take_operations(), 0);
+        assert_eq!(line.count(), 0);
+        assert_eq!(sink.usbsts(), 0);
+        assert!(sink.is_congested());
+
+        // segment_base = 0x100, trb_count = 16
+        ram.memory.write_bulk(
+            0x0,
+            &[0x00, 0x01, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0],
+        );
+        sink.configure_segment_table(0x0);
+        assert_eq!(line.count(), 0, "ERSTSZ is still missing");
+
+        sink.set_segment_table_size(1);
+        assert_eq!(line.count(), 1);
+        assert_eq!(sink.usbsts(), usbsts::EINT);
+        assert_eq!(
+            trb_type(&ram.memory, 0x100),
+            u64::from(trb_types::PORT_STATUS_CHANGE_EVENT)
+        );
+        assert_eq!(trb_pointer(&ram.memory, 0x110), 0x1000);
+        assert!(!sink.is_congested());
+        assert_eq!(sink.event_ring().unconfigured_drops(), 0);
+    }
+
+    #[test]
+    fn interrupts_set_event_handler_busy_until_the_driver_clears_it() {
+        let sink = event_sink(Arc::new(TestBusDevice::new(&[0; 0x200])));
+        sink.connect_irq(Arc::new(CountingInterruptLine::default()));
+        sink.write_iman(iman::IE);
+        assert_eq!(sink.read_erdp(), 0x100);
+
+        sink.post(transfer_event(0x1000));
+        assert_eq!(sink.read_erdp(), 0x100 | erdp::EHB);
+
+        // Without EHB, the write leaves the flag alone.
+        sink.update_dequeue_pointer(0x110);
+        assert_eq!(sink.read_erdp(), 0x110 | erdp::EHB);
+
+        // EHB is not part of the dequeue pointer.
+        sink.update_dequeue_pointer(0x110 | erdp::EHB);
+        assert_eq!(sink.read_erdp(), 0x110);
+        assert_eq!(sink.event_ring().read_dequeue_pointer(), 0x110);
+    }
+
+    #[test]
+    fn interrupts_restart_the_moderation_counter() {
+        let sink = event_sink(Arc::new(TestBusDevice::new(&[0; 0x200])));
+        sink.connect_irq(Arc::new(CountingInterruptLine::default()));
+        sink.write_iman(iman::IE);
+        assert_eq!(sink.read_imod(), u64::from(IMOD_DEFAULT));
+
+        // A stopped counter keeps both fields as written.
+        sink.write_imod(0x0fa0);
+        assert_eq!(sink.read_imod(), 0x0fa0);
+
+        // The interrupt loads the counter with the interval, from where it
+        // counts down.
+        sink.post(transfer_event(0x1000));
+        let imod = sink.read_imod();
+        assert_eq!(imod & imod::IMODI, 0x0fa0);
+        assert!(imod >> 16 <= 0x0fa0);
+
+        sink.reset();
+        assert_eq!(sink.read_imod(), u64::from(IMOD_DEFAULT));
+    }
+
+    fn trb_type(ram: &TestBusDevice, addr: u64) -> u64 {
+        ram.read(Request::new(addr + 13, RequestSize::Size1)) >> 2
+    }
+
+    fn trb_pointer(ram: &TestBusDevice, addr: u64) -> u64 {
+        ram.read(Request::new(addr, RequestSize::Size8))
+    }
+
+    /// Post events until the 16-TRB ring of [`event_sink`] is full.
+    fn fill_ring(sink: &EventSink) {
+        for i in 0..15 {
+            sink.post(transfer_event(0x1000 + i * 0x10));
+        }
+    }
+
+    #[test]
+    fn full_ring_defers_events_and_drops_beyond_bound() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let sink = event_sink_with_deferral_bound(ram.clone(), 3);
+        let line = Arc::new(CountingInterruptLine::default());
+        sink.connect_irq(line.clone());
+        sink.write_iman(iman::IE);
+
+        fill_ring(&sink);
+        assert_eq!(line.count(), 15);
+        sink.write_usbsts(usbsts::EINT);
+
+        sink.post(transfer_event(0x2000));
+        sink.post(EventTrb::new_port_status_change_event_trb(1));
+        // The driver learns about the port from PORTSC anyway.
+        sink.post(EventTrb::new_port_status_change_event_trb(1));
+        sink.post(transfer_event(0x2010));
+        // Exceeds the bound.
+        sink.post(transfer_event(0x2020));
+        sink.post(transfer_event(0x2030));
+
+        assert_eq!(sink.dropped_events(), 2);
+        // Nothing new reached the ring, but EINT reports outstanding events.
+        assert_eq!(line.count(), 15);
+        assert_eq!(sink.usbsts(), usbsts::EINT);
+        assert_eq!(trb_pointer(&ram, 0x100), 0x1000);
+        assert_eq!(cycle_bit(&ram, 0x1f0), 0);
+    }
+
+    #[test]
+    fn lost_events_are_reported_before_retained_ones() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let sink = event_sink_with_deferral_bound(ram.clone(), 2);
+        let line = Arc::new(CountingInterruptLine::default());
+        sink.connect_irq(line.clone());
+        sink.write_iman(iman::IE);
+
+        fill_ring(&sink);
+        sink.post_batch(&[
+            transfer_event(0x2000),
+            transfer_event(0x2010),
+            transfer_event(0x2020),
+        ]);
+        assert_eq!(sink.dropped_events(), 1);
+
+        // The driver processed all events.
+        sink.update_dequeue_pointer(0x1f0);
+        assert_eq!(line.count(), 16);
+
+        assert_eq!(
+            trb_type(&ram, 0x1f0),
+            u64::from(trb_types::HOST_CONTROLLER_EVENT)
+        );
+        assert_eq!(
+            ram.read(Request::new(0x1f0 + 11, RequestSize::Size1)),
+            CompletionCode::EventRingFullError as u64
+        );
+        assert_eq!(cycle_bit(&ram, 0x1f0), 1);
+
+        // The retained events follow after wrapping around.
+        assert_eq!(trb_pointer(&ram, 0x100), 0x2000);
+        assert_eq!(trb_pointer(&ram, 0x110), 0x2010);
+        assert_eq!([0x100, 0x110].map(|a| cycle_bit(&ram, a)), [0, 0]);
+        assert_eq!(cycle_bit(&ram, 0x120), 1, "dropped event was written");
+
+        // Events flow directly again.
+        sink.post(transfer_event(0x2030));
+        assert_eq!(trb_pointer(&ram, 0x120), 0x2030);
+        assert_eq!(cycle_bit(&ram, 0x120), 0);
+        assert_eq!(sink.dropped_events(), 1);
+    }
+
+    #[test]
+    fn clearing_pending_interrupt_drops_it() {
+        let sink = event_sink(Arc::new(TestBusDevice::new(&[0; 0x200])));
+        let line = Arc::new(CountingInterruptLine::default());
+        sink.connect_irq(line.clone());
+
+        sink.post(transfer_event(0x1000));
+        // IP is RW1C; the driver acknowledges it while enabling IE.
+        sink.write_iman(iman::IP | iman::IE);
+        assert_eq!(line.count(), 0);
+        assert_eq!(sink.read_iman(), iman::IE);
+    }
+
+    #[test]
+    fn full_ring_congests_until_erdp_advances() {
+        let sink = Arc::new(event_sink(Arc::new(TestBusDevice::new(&[0; 0x200]))));
+        assert!(!sink.is_congested());
+        fill_ring(&sink);
+        assert!(sink.is_congested());
+
+        let (done_sender, done) = mpsc::channel();
+        let waiter = sink.clone();
+        thread::spawn(move || {
+            waiter.wait_for_space();
+            done_sender.send(()).unwrap();
+        });
+        assert!(done.recv_timeout(Duration::from_millis(50)).is_err());
+
+        sink.update_dequeue_pointer(0x110);
+        assert!(!sink.is_congested());
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn slow_consumer_loses_no_events() {
+        const EVENTS: u64 = 100;
+
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let sink = Arc::new(event_sink_with_deferral_bound(ram.clone(), 1));
+
+        // The producer behaves like an endpoint worker and only produces
+        // events while the sink is not congested.
+        let producer_sink = sink.clone();
+        let producer = thread::spawn(move || {
+            for i in 0..EVENTS {
+                producer_sink.wait_for_space();
+                producer_sink.post(transfer_event(0x1000 + i * 0x10));
+            }
+        });
+
+        // The driver processes one event at a time and takes its time.
+        let (mut dequeue_pointer, mut cycle_state) = (0x100, 1);
+        let mut received = vec![];
+        while received.len() < EVENTS as usize {
+            if cycle_bit(&ram, dequeue_pointer) != cycle_state {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            received.push(trb_pointer(&ram, dequeue_pointer));
+            dequeue_pointer += 0x10;
+            if dequeue_pointer == 0x200 {
+                dequeue_pointer = 0x100;
+                cycle_state ^= 1;
+            }
+            thread::sleep(Duration::from_micros(200));
+            sink.update_dequeue_pointer(dequeue_pointer);
+        }
+        producer.join().unwrap();
+
+        assert_eq!(sink.dropped_events(), 0);
+        assert_eq!(
+            received,
+            (0..EVENTS).map(|i| 0x1000 + i * 0x10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn status_reports_events_the_driver_did_not_process() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let sink = event_sink(ram);
+        let status = sink.event_ring_status();
+        assert_eq!(status.occupancy.unwrap().used, 0);
+        assert_eq!(status.last_advance_age, None);
+
+        for i in 0..12 {
+            sink.post(transfer_event(0x1000 + i * 0x10));
+        }
+        let status = sink.event_ring_status();
+        assert_eq!(status.occupancy.unwrap().to_string(), "12/16 TRBs (75%)");
+        assert!(status.last_advance_age.is_some());
+        assert_eq!(status.stalls, 0);
+
+        // Progress without catching up keeps the driver waiting.
+        sink.update_dequeue_pointer(0x100 + 4 * 0x10);
+        let status = sink.event_ring_status();
+        assert_eq!(status.occupancy.unwrap().used, 8);
+        assert!(status.last_advance_age.is_some());
+
+        sink.update_dequeue_pointer(0x100 + 12 * 0x10);
+        let status = sink.event_ring_status();
+        assert_eq!(status.occupancy.unwrap().used, 0);
+        assert_eq!(status.last_advance_age, None);
+    }
+}
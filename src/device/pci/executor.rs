@@ -0,0 +1,220 @@
+//! # Endpoint Executor
+//!
+//! A minimal single-threaded executor to drive the transfers of many
+//! endpoints from one thread.
+//!
+//! By default, every enabled endpoint is serviced by a dedicated worker
+//! thread. This does not scale well to many devices or endpoints. As nusb
+//! offers an async API for transfers, endpoint workers can also be written
+//! as futures and all of them can be polled by a single [`Executor`].
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+use tracing::debug;
+
+use crate::affinity::{spawn_thread, ThreadPolicy};
+
+type BoxedTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A spawned future together with the means to reschedule it.
+struct Task {
+    /// The future of the task. `None` once the future completed.
+    future: Mutex<Option<BoxedTask>>,
+    /// The run queue of the executor that owns the task.
+    run_queue: Sender<Arc<Self>>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        // When the executor is gone, there is nobody left to poll the task
+        // and we can silently drop the wakeup.
+        let _ = self.run_queue.clone().send(self);
+    }
+}
+
+/// A single-threaded executor for endpoint futures.
+///
+/// All futures spawned on one executor are polled on the same thread.
+#[derive(Debug)]
+pub struct Executor {
+    run_queue: Mutex<Sender<Arc<Task>>>,
+}
+
+impl Executor {
+    /// Create a new executor and start its thread.
+    ///
+    /// # Parameters
+    ///
+    /// - `name`: the name of the executor thread.
+    /// - `policy`: how the executor thread is scheduled.
+    pub fn new(name: &str, policy: ThreadPolicy) -> Self {
+        let (sender, receiver) = mpsc::channel::<Arc<Task>>();
+
+        spawn_thread(name.to_string(), policy, move || {
+            // The loop ends once all senders are gone, i.e., the executor
+            // was dropped and all tasks have completed.
+            for task in receiver {
+                let mut future = task.future.lock().unwrap();
+                let Some(pending) = future.as_mut() else {
+                    // The task completed, but there was a stale wakeup.
+                    continue;
+                };
+
+                let waker = Waker::from(task.clone());
+                if pending
+                    .as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_ready()
+                {
+                    *future = None;
+                }
+            }
+            debug!("endpoint executor exits");
+        })
+        .unwrap_or_else(|_| panic!("Failed to launch executor thread {name}"));
+
+        Self {
+            run_queue: Mutex::new(sender),
+        }
+    }
+
+    /// Run a future to completion on the executor thread.
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let run_queue = self.run_queue.lock().unwrap().clone();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            run_queue: run_queue.clone(),
+        });
+
+        // The executor thread only exits when all senders are dropped, so
+        // this cannot fail while we hold one.
+        run_queue.send(task).unwrap();
+    }
+}
+
+/// An asynchronous wakeup signal for an endpoint future.
+///
+/// This is the async counterpart to the channel that wakes up endpoint
+/// worker threads when the driver rings the doorbell. Multiple rings
+/// before the endpoint waits again are collapsed into one wakeup.
+#[derive(Debug, Default)]
+pub struct Doorbell {
+    state: Mutex<DoorbellState>,
+}
+
+#[derive(Debug, Default)]
+struct DoorbellState {
+    /// Whether the doorbell was rung since the last wait.
+    rung: bool,
+    /// The waker of the future currently waiting for the doorbell.
+    waker: Option<Waker>,
+}
+
+impl Doorbell {
+    /// Create a new doorbell that was not rung yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ring the doorbell and wake up the waiting future, if any.
+    pub fn ring(&self) {
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.rung = true;
+            state.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Forget the rings since the last wait.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().rung = false;
+    }
+
+    /// Wait until the doorbell is rung.
+    pub fn wait(&self) -> impl Future<Output = ()> + '_ {
+        std::future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.rung {
+                state.rung = false;
+                Poll::Ready(())
+            } else {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{self, Sender},
+        thread::{self, ThreadId},
+        time::Duration,
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// An endpoint future that reports the thread it runs on for every
+    /// wakeup.
+    async fn endpoint(doorbell: Arc<Doorbell>, wakeups: usize, log: Sender<(usize, ThreadId)>) {
+        for i in 0..wakeups {
+            doorbell.wait().await;
+            log.send((i, thread::current().id())).unwrap();
+        }
+    }
+
+    #[test]
+    fn two_endpoints_share_one_thread() {
+        let executor = Executor::new("test executor", ThreadPolicy::default());
+        let (log_sender, log) = mpsc::channel();
+
+        let doorbell_a = Arc::new(Doorbell::new());
+        let doorbell_b = Arc::new(Doorbell::new());
+        executor.spawn(endpoint(doorbell_a.clone(), 2, log_sender.clone()));
+        executor.spawn(endpoint(doorbell_b.clone(), 2, log_sender));
+
+        let mut threads = vec![];
+        for doorbell in [&doorbell_a, &doorbell_b, &doorbell_b, &doorbell_a] {
+            doorbell.ring();
+            threads.push(log.recv_timeout(TIMEOUT).unwrap().1);
+        }
+
+        assert!(
+            threads.iter().all(|&id| id == threads[0]),
+            "both endpoints should be serviced by the same thread"
+        );
+        assert_ne!(threads[0], thread::current().id());
+
+        // Both endpoint futures completed and dropped their log senders.
+        assert!(log.recv_timeout(TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn doorbell_rings_are_not_lost() {
+        let executor = Executor::new("test executor", ThreadPolicy::default());
+        let (log_sender, log) = mpsc::channel();
+
+        let doorbell = Arc::new(Doorbell::new());
+        // Ring before the endpoint waits for the first time.
+        doorbell.ring();
+        executor.spawn(endpoint(doorbell, 1, log_sender));
+
+        assert_eq!(log.recv_timeout(TIMEOUT).unwrap().0, 0);
+    }
+}
@@ -0,0 +1,237 @@
+//! # Isochronous Scheduling
+//!
+//! Isochronous transfers happen in fixed (micro)frames. The driver either
+//! asks for a specific 1ms frame with the Frame ID of an Isoch TRB, or sets
+//! SIA (Start Isoch ASAP) to let the controller pick the next available
+//! frame.
+//!
+//! The controller's notion of time is the Microframe Index register
+//! (`MFINDEX`), which counts 125µs microframes while the controller runs
+//! and wraps after 2^14 microframes. The frame number is `MFINDEX / 8`
+//! and wraps after 2^11 frames.
+//!
+//! This module provides the [`MicroframeClock`] backing `MFINDEX` and the
+//! per-endpoint [`IsochScheduler`], which decides when an Isoch TRB is due
+//! and whether its frame was already missed.
+
+use std::time::{Duration, Instant};
+
+use super::{
+    dci::Dci,
+    trb::{CompletionCode, EventTrb, IsochTrbData, TransferTrb},
+};
+
+/// The length of a microframe.
+const MICROFRAME: Duration = Duration::from_micros(125);
+
+/// `MFINDEX` wraps after 2^14 microframes.
+const MFINDEX_MASK: u64 = 0x3fff;
+
+/// Frame IDs wrap after 2^11 frames.
+const FRAME_MASK: u16 = 0x7ff;
+
+/// Frame IDs more than half of the frame space ahead are considered to lie
+/// in the past.
+const MAX_FRAMES_AHEAD: u16 = 1024;
+
+/// The time base for `MFINDEX`.
+#[derive(Debug, Default)]
+pub struct MicroframeClock {
+    /// When the controller started running. `None` while halted.
+    started: Option<Instant>,
+}
+
+impl MicroframeClock {
+    /// Start counting microframes from 0.
+    pub fn start(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    /// Stop counting microframes.
+    pub const fn stop(&mut self) {
+        self.started = None;
+    }
+
+    /// The current value of the `MFINDEX` register.
+    ///
+    /// The index does not advance while the controller is halted.
+    pub fn mfindex(&self) -> u16 {
+        self.started
+            .map_or(0, |started| mfindex_after(started.elapsed()))
+    }
+}
+
+/// The `MFINDEX` value after the controller ran for `elapsed`.
+const fn mfindex_after(elapsed: Duration) -> u16 {
+    ((elapsed.as_nanos() / MICROFRAME.as_nanos()) as u64 & MFINDEX_MASK) as u16
+}
+
+/// The frame that contains the microframe `mfindex`.
+const fn frame_of(mfindex: u16) -> u16 {
+    (mfindex >> 3) & FRAME_MASK
+}
+
+/// When to perform an isochronous transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsochSchedule {
+    /// The transfer is due in the given frame.
+    Frame(u16),
+    /// The frame of the transfer already passed. The TRB has to be
+    /// completed with a Missed Service Error.
+    Missed,
+}
+
+/// Schedules the Isoch TRBs of one endpoint.
+// Isoch endpoints are not forwarded to real devices yet.
+#[allow(unused)]
+#[derive(Debug, Default)]
+pub struct IsochScheduler {
+    /// The frame after the most recently scheduled transfer. SIA transfers
+    /// continue here to keep the stream contiguous.
+    next_frame: Option<u16>,
+}
+
+#[allow(unused)]
+impl IsochScheduler {
+    /// Decide when to perform the transfer of an Isoch TRB.
+    ///
+    /// # Parameters
+    ///
+    /// - `trb`: the Isoch TRB to schedule.
+    /// - `mfindex`: the current value of `MFINDEX`.
+    pub fn schedule(&mut self, trb: &IsochTrbData, mfindex: u16) -> IsochSchedule {
+        let current_frame = frame_of(mfindex);
+
+        let frame = if trb.start_isoch_asap {
+            // Continue the stream if it is still ahead of us, otherwise
+            // start in the current frame.
+            self.next_frame
+                .filter(|&frame| frames_ahead(current_frame, frame) < MAX_FRAMES_AHEAD)
+                .unwrap_or(current_frame)
+        } else if frames_ahead(current_frame, trb.frame_id) < MAX_FRAMES_AHEAD {
+            trb.frame_id
+        } else {
+            return IsochSchedule::Missed;
+        };
+
+        self.next_frame = Some((frame + 1) & FRAME_MASK);
+        IsochSchedule::Frame(frame)
+    }
+}
+
+/// How many frames `frame` lies ahead of `current_frame`, modulo the frame
+/// space.
+const fn frames_ahead(current_frame: u16, frame: u16) -> u16 {
+    frame.wrapping_sub(current_frame) & FRAME_MASK
+}
+
+/// Create the Transfer Event for an Isoch TRB whose frame was missed.
+///
+/// No data was transferred, so the residual length is the full transfer
+/// length.
+#[allow(unused)]
+pub const fn missed_service_event(
+    trb: &TransferTrb,
+    data: &IsochTrbData,
+    endpoint_id: Dci,
+    slot_id: u8,
+) -> EventTrb {
+    EventTrb::new_transfer_event_trb(
+        trb.address,
+        data.transfer_length,
+        CompletionCode::MissedServiceError,
+        false,
+        endpoint_id,
+        slot_id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::pci::trb::TransferTrbVariant;
+
+    use super::*;
+
+    fn isoch_trb(frame_id: u16, start_isoch_asap: bool) -> IsochTrbData {
+        IsochTrbData {
+            data_pointer: 0x1000,
+            transfer_length: 0x200,
+            chain: false,
+            interrupt_on_completion: true,
+            frame_id,
+            start_isoch_asap,
+        }
+    }
+
+    #[test]
+    fn mfindex_counts_microframes_and_wraps() {
+        assert_eq!(mfindex_after(Duration::ZERO), 0);
+        assert_eq!(mfindex_after(Duration::from_micros(124)), 0);
+        assert_eq!(mfindex_after(Duration::from_millis(1)), 8);
+        // 2^14 microframes are 2.048s.
+        assert_eq!(mfindex_after(Duration::from_millis(2048)), 0);
+        assert_eq!(mfindex_after(Duration::from_millis(2049)), 8);
+
+        assert_eq!(MicroframeClock::default().mfindex(), 0);
+    }
+
+    #[test]
+    fn frame_id_is_honored() {
+        let mut scheduler = IsochScheduler::default();
+        // MFINDEX 0x80 is frame 0x10.
+        assert_eq!(
+            scheduler.schedule(&isoch_trb(0x10, false), 0x80),
+            IsochSchedule::Frame(0x10)
+        );
+        assert_eq!(
+            scheduler.schedule(&isoch_trb(0x20, false), 0x80),
+            IsochSchedule::Frame(0x20)
+        );
+        // Frame IDs wrap around.
+        assert_eq!(
+            scheduler.schedule(&isoch_trb(0x2, false), 0x3ff8),
+            IsochSchedule::Frame(0x2)
+        );
+    }
+
+    #[test]
+    fn sia_starts_asap_and_continues_the_stream() {
+        let mut scheduler = IsochScheduler::default();
+        assert_eq!(
+            scheduler.schedule(&isoch_trb(0x0, true), 0x80),
+            IsochSchedule::Frame(0x10)
+        );
+        assert_eq!(
+            scheduler.schedule(&isoch_trb(0x0, true), 0x80),
+            IsochSchedule::Frame(0x11)
+        );
+
+        // Once the stream fell behind, SIA restarts at the current frame
+        // instead of reporting a missed frame.
+        assert_eq!(
+            scheduler.schedule(&isoch_trb(0x0, true), 0x100),
+            IsochSchedule::Frame(0x20)
+        );
+    }
+
+    #[test]
+    fn past_frame_id_yields_missed_service_error() {
+        let mut scheduler = IsochScheduler::default();
+        let data = isoch_trb(0x0f, false);
+
+        // MFINDEX 0x80 is frame 0x10, so frame 0x0f already passed.
+        assert_eq!(scheduler.schedule(&data, 0x80), IsochSchedule::Missed);
+
+        let trb = TransferTrb {
+            address: 0x2000,
+            variant: TransferTrbVariant::Isoch(isoch_trb(0x0f, false)),
+        };
+        let event = missed_service_event(&trb, &data, Dci::new(3).unwrap(), 1).to_bytes(true);
+        assert_eq!(event[0..8], 0x2000u64.to_le_bytes());
+        // The full transfer length remains.
+        assert_eq!(event[8..11], [0x00, 0x02, 0x00]);
+        assert_eq!(event[11], CompletionCode::MissedServiceError as u8);
+        assert_eq!(event[14], 3);
+        assert_eq!(event[15], 1);
+    }
+}
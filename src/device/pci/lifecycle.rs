@@ -0,0 +1,336 @@
+//! # Controller Lifecycle
+//!
+//! The controller halts, resets, and resumes on behalf of several callers:
+//! the driver through USBCMD, the VMM through a device reset, and the
+//! server when the VMM goes away. While that happens, endpoint workers may
+//! still fetch TDs and post Transfer Events. Done ad hoc, every caller
+//! would tear the controller down in a slightly different order.
+//!
+//! [`ControllerLifecycle`] tracks the state of the controller and hands out
+//! the [`Step`]s of each transition in the order they have to happen:
+//!
+//! 1. Quiesce the workers, so they do not touch rings anymore.
+//! 2. Reset the Event Ring, so no event lands on a ring the driver
+//!    abandoned.
+//! 3. Reset the rings and registers the [`ResetKind`] covers.
+//! 4. Release or retain the slots, depending on the [`ResetKind`].
+//!
+//! The controller carries out the steps and reports the outcome in USBSTS:
+//! HCH follows [`ControllerLifecycle::is_halted`], CNR follows
+//! [`ControllerLifecycle::is_ready`], and a reset clears HCE before the
+//! workers shut down, so workers that do not exit raise it again.
+
+use std::sync::Arc;
+
+use tracing::warn;
+
+use super::run_state::RunState;
+
+/// The states of the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleState {
+    /// The controller is not set up yet.
+    Uninitialized,
+    /// The controller came out of power-on or reset and waits for the
+    /// driver to start it.
+    Ready,
+    /// The driver set USBCMD.R/S.
+    Running,
+    /// The driver cleared USBCMD.R/S, but workers still fetch TDs.
+    Halting,
+    /// The controller stopped and all ring processing ended.
+    Halted,
+    /// A reset is in progress.
+    Resetting,
+}
+
+/// The ways to reset the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    /// USBCMD.HCRST: the driver starts over, but the slots stay enabled.
+    /// Devices need a new USB device address.
+    Soft,
+    /// USBCMD.LHCRST: like [`ResetKind::Soft`], but the ports and the USB
+    /// device addresses are not affected.
+    Light,
+    /// A reset of the whole PCI function, e.g., by the VMM. All slots are
+    /// released and the ports show their devices as if newly attached.
+    Function,
+}
+
+/// A step of a transition, which the controller carries out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Stop the workers from fetching TDs.
+    StopWorkers,
+    /// Clear USBSTS.HCE.
+    ClearHostControllerError,
+    /// Shut down the workers of all slots.
+    DisableWorkers,
+    /// Drop the Event Ring and the events that wait for space on it.
+    ResetEventRing,
+    /// Forget the doorbells that rang while the controller was stopped.
+    ForgetDoorbells,
+    /// Power on the ports the driver switched off, and clear USBSTS.PCD.
+    PowerOnPorts,
+    /// Show every port as after power-on, and clear USBSTS.PCD.
+    ReconnectPorts,
+    /// Release the USB device addresses of all slots.
+    ReleaseUsbAddresses,
+    /// Forget the cached device contexts.
+    InvalidateDeviceContexts,
+    /// Release all slots with their USB device addresses and device
+    /// contexts.
+    ReleaseSlots,
+    /// Let the workers fetch TDs.
+    StartWorkers,
+    /// Post Port Status Change Events for changes the driver has not seen.
+    ReportPortChanges,
+    /// Ring the doorbells again that rang while the controller was stopped.
+    ReplayDoorbells,
+}
+
+impl ResetKind {
+    /// The steps of a reset of this kind, in order.
+    const fn steps(self) -> &'static [Step] {
+        use Step::*;
+
+        match self {
+            Self::Soft => &[
+                StopWorkers,
+                ClearHostControllerError,
+                ResetEventRing,
+                ForgetDoorbells,
+                PowerOnPorts,
+                ReleaseUsbAddresses,
+                InvalidateDeviceContexts,
+            ],
+            Self::Light => &[
+                StopWorkers,
+                ClearHostControllerError,
+                ResetEventRing,
+                ForgetDoorbells,
+                InvalidateDeviceContexts,
+            ],
+            Self::Function => &[
+                StopWorkers,
+                ClearHostControllerError,
+                DisableWorkers,
+                ResetEventRing,
+                ForgetDoorbells,
+                ReconnectPorts,
+                ReleaseSlots,
+            ],
+        }
+    }
+}
+
+/// The state of the controller and the gate for its endpoint workers.
+#[derive(Debug)]
+pub struct ControllerLifecycle {
+    /// The state as of the last transition. Halting turns into Halted on
+    /// its own once the workers are done.
+    state: LifecycleState,
+    run_state: Arc<RunState>,
+}
+
+impl Default for ControllerLifecycle {
+    fn default() -> Self {
+        Self {
+            state: LifecycleState::Uninitialized,
+            run_state: Arc::default(),
+        }
+    }
+}
+
+impl ControllerLifecycle {
+    /// The current state.
+    pub fn state(&self) -> LifecycleState {
+        match self.state {
+            LifecycleState::Halting if self.run_state.is_halted() => LifecycleState::Halted,
+            state => state,
+        }
+    }
+
+    /// The Run/Stop state that endpoint workers check before they fetch
+    /// TDs.
+    pub const fn run_state(&self) -> &Arc<RunState> {
+        &self.run_state
+    }
+
+    /// Whether the driver set USBCMD.R/S.
+    pub fn is_running(&self) -> bool {
+        self.state == LifecycleState::Running
+    }
+
+    /// Whether the controller stopped and all ring processing ended
+    /// (USBSTS.HCH).
+    pub fn is_halted(&self) -> bool {
+        self.run_state.is_halted()
+    }
+
+    /// Whether the controller accepts register writes (USBSTS.CNR clear).
+    pub const fn is_ready(&self) -> bool {
+        !matches!(
+            self.state,
+            LifecycleState::Uninitialized | LifecycleState::Resetting
+        )
+    }
+
+    /// The controller is set up after power-on.
+    pub fn initialized(&mut self) {
+        if self.state == LifecycleState::Uninitialized {
+            self.state = LifecycleState::Ready;
+        }
+    }
+
+    /// Stop the controller. Workers finish fetching the TD at hand.
+    ///
+    /// Returns the steps to carry out, which are none unless the
+    /// controller runs.
+    pub fn halt(&mut self) -> &'static [Step] {
+        if self.state() != LifecycleState::Running {
+            return &[];
+        }
+        self.state = LifecycleState::Halting;
+        &[Step::StopWorkers]
+    }
+
+    /// Start the controller after reset or halt.
+    ///
+    /// Returns the steps to carry out, which are none if the controller
+    /// runs already or cannot run yet.
+    pub fn resume(&mut self) -> &'static [Step] {
+        match self.state() {
+            LifecycleState::Ready | LifecycleState::Halting | LifecycleState::Halted => {
+                self.state = LifecycleState::Running;
+                &[
+                    Step::StartWorkers,
+                    Step::ReportPortChanges,
+                    Step::ReplayDoorbells,
+                ]
+            }
+            LifecycleState::Running => &[],
+            state @ (LifecycleState::Uninitialized | LifecycleState::Resetting) => {
+                warn!("cannot start the controller in state {state:?}");
+                &[]
+            }
+        }
+    }
+
+    /// Begin a reset. The controller is halted after the reset, even if it
+    /// ran before.
+    ///
+    /// Returns the steps to carry out, after which the caller has to call
+    /// [`Self::reset_done`].
+    pub const fn reset(&mut self, kind: ResetKind) -> &'static [Step] {
+        self.state = LifecycleState::Resetting;
+        kind.steps()
+    }
+
+    /// Finish a reset. The controller is ready for the driver again.
+    pub fn reset_done(&mut self) {
+        debug_assert_eq!(self.state, LifecycleState::Resetting);
+        self.state = LifecycleState::Ready;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Carry out the steps that concern the workers, like the controller.
+    fn carry_out(lifecycle: &ControllerLifecycle, steps: &[Step]) {
+        for step in steps {
+            match step {
+                Step::StopWorkers => lifecycle.run_state().stop(),
+                Step::StartWorkers => drop(lifecycle.run_state().start()),
+                _ => (),
+            }
+        }
+    }
+
+    fn ready() -> ControllerLifecycle {
+        let mut lifecycle = ControllerLifecycle::default();
+        assert!(!lifecycle.is_ready());
+        lifecycle.initialized();
+        assert_eq!(lifecycle.state(), LifecycleState::Ready);
+        lifecycle
+    }
+
+    #[test]
+    fn halt_waits_for_the_workers() {
+        let mut lifecycle = ready();
+        assert!(lifecycle.is_halted());
+        assert_eq!(lifecycle.halt(), []);
+
+        let steps = lifecycle.resume();
+        assert_eq!(steps[0], Step::StartWorkers);
+        carry_out(&lifecycle, steps);
+        assert_eq!(lifecycle.state(), LifecycleState::Running);
+        assert!(!lifecycle.is_halted());
+        assert_eq!(lifecycle.resume(), []);
+
+        let access = lifecycle.run_state().clone();
+        let access = access.enter(1, 2).unwrap();
+        let steps = lifecycle.halt();
+        assert_eq!(steps, [Step::StopWorkers]);
+        carry_out(&lifecycle, steps);
+        assert_eq!(lifecycle.state(), LifecycleState::Halting);
+        assert!(!lifecycle.is_halted());
+        drop(access);
+        assert_eq!(lifecycle.state(), LifecycleState::Halted);
+        assert!(lifecycle.is_halted());
+    }
+
+    #[test]
+    fn resets_quiesce_workers_before_they_touch_the_event_ring() {
+        for kind in [ResetKind::Soft, ResetKind::Light, ResetKind::Function] {
+            let mut lifecycle = ready();
+            let steps = lifecycle.resume();
+            carry_out(&lifecycle, steps);
+
+            let steps = lifecycle.reset(kind);
+            assert_eq!(lifecycle.state(), LifecycleState::Resetting);
+            assert!(!lifecycle.is_ready());
+            carry_out(&lifecycle, steps);
+            lifecycle.reset_done();
+            assert_eq!(lifecycle.state(), LifecycleState::Ready);
+            assert!(lifecycle.is_halted());
+
+            let position = |step| steps.iter().position(|&s| s == step);
+            let event_ring = position(Step::ResetEventRing).unwrap();
+            assert_eq!(position(Step::StopWorkers), Some(0));
+            assert!(position(Step::ClearHostControllerError) < Some(event_ring));
+            if let Some(disable) = position(Step::DisableWorkers) {
+                assert!(disable < event_ring);
+            }
+            assert!(position(Step::ForgetDoorbells) > Some(event_ring));
+        }
+    }
+
+    #[test]
+    fn reset_kinds_differ_in_ports_and_slots() {
+        let steps = |kind: ResetKind| kind.steps();
+        let affects = |kind, step| steps(kind).contains(&step);
+
+        assert!(affects(ResetKind::Soft, Step::PowerOnPorts));
+        assert!(affects(ResetKind::Soft, Step::ReleaseUsbAddresses));
+        assert!(!affects(ResetKind::Soft, Step::ReleaseSlots));
+
+        assert!(!affects(ResetKind::Light, Step::PowerOnPorts));
+        assert!(!affects(ResetKind::Light, Step::ReleaseUsbAddresses));
+        assert!(affects(ResetKind::Light, Step::InvalidateDeviceContexts));
+
+        assert!(affects(ResetKind::Function, Step::DisableWorkers));
+        assert!(affects(ResetKind::Function, Step::ReconnectPorts));
+        assert!(affects(ResetKind::Function, Step::ReleaseSlots));
+    }
+
+    #[test]
+    fn uninitialized_controller_does_not_start() {
+        let mut lifecycle = ControllerLifecycle::default();
+        assert_eq!(lifecycle.resume(), []);
+        assert_eq!(lifecycle.state(), LifecycleState::Uninitialized);
+    }
+}
@@ -0,0 +1,219 @@
+//! # MMIO Access Profiling
+//!
+//! Guest drivers that spin on registers show up as a high rate of MMIO
+//! accesses, each of which is a round trip through the vfio-user client.
+//! With profiling enabled, the controller counts the accesses and the time
+//! spent handling them per register, so such patterns stand out in a
+//! summary.
+//!
+//! The counters live in a table that is allocated up front. When profiling
+//! is disabled, the only cost is a relaxed load of a flag per access.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+/// The granularity of the profile. XHCI registers are 32 bit wide.
+const REGISTER_SIZE: u64 = 4;
+
+/// The size of the profiled region, which covers BAR 0 with all XHCI
+/// registers.
+const PROFILED_SIZE: u64 = 0x4000;
+
+/// One bucket per register, plus one for all accesses outside of the
+/// profiled region.
+const BUCKETS: usize = (PROFILED_SIZE / REGISTER_SIZE) as usize + 1;
+
+/// The bucket for accesses outside of the profiled region.
+const OTHER_BUCKET: usize = BUCKETS - 1;
+
+/// The kind of a register access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioAccess {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    /// Cumulative handler latency in nanoseconds.
+    nanos: AtomicU64,
+}
+
+/// The aggregated accesses to a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterProfile {
+    /// The offset of the register in BAR 0. `None` stands for all accesses
+    /// outside of BAR 0.
+    pub offset: Option<u64>,
+    pub reads: u64,
+    pub writes: u64,
+    /// The time spent handling all accesses.
+    pub latency: Duration,
+}
+
+impl RegisterProfile {
+    /// The total number of accesses.
+    pub const fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/// Per-register access counts and handler latencies.
+#[derive(Debug)]
+pub struct MmioProfile {
+    enabled: AtomicBool,
+    buckets: Box<[Bucket]>,
+}
+
+impl Default for MmioProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MmioProfile {
+    /// Create a disabled profile.
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            buckets: (0..BUCKETS).map(|_| Bucket::default()).collect(),
+        }
+    }
+
+    /// Start recording accesses.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Take the start time of an access, if profiling is enabled.
+    ///
+    /// Pass the result to [`record`](Self::record) once the access is
+    /// handled.
+    pub fn start(&self) -> Option<Instant> {
+        self.enabled.load(Ordering::Relaxed).then(Instant::now)
+    }
+
+    /// Account an access that started at `start`.
+    ///
+    /// Does nothing if `start` is `None`, i.e., profiling was disabled when
+    /// the access started.
+    pub fn record(&self, access: MmioAccess, region: u32, offset: u64, start: Option<Instant>) {
+        let Some(start) = start else {
+            return;
+        };
+        let latency = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+
+        let bucket = &self.buckets[bucket_index(region, offset)];
+        match access {
+            MmioAccess::Read => bucket.reads.fetch_add(1, Ordering::Relaxed),
+            MmioAccess::Write => bucket.writes.fetch_add(1, Ordering::Relaxed),
+        };
+        bucket.nanos.fetch_add(latency, Ordering::Relaxed);
+    }
+
+    /// All registers that were accessed, the most frequently accessed
+    /// first.
+    pub fn summary(&self) -> Vec<RegisterProfile> {
+        let mut summary: Vec<_> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| RegisterProfile {
+                offset: (index != OTHER_BUCKET).then_some(index as u64 * REGISTER_SIZE),
+                reads: bucket.reads.load(Ordering::Relaxed),
+                writes: bucket.writes.load(Ordering::Relaxed),
+                latency: Duration::from_nanos(bucket.nanos.load(Ordering::Relaxed)),
+            })
+            .filter(|profile| profile.accesses() > 0)
+            .collect();
+        summary.sort_by(|a, b| {
+            b.accesses()
+                .cmp(&a.accesses())
+                .then(b.latency.cmp(&a.latency))
+        });
+        summary
+    }
+
+    /// Log the [`summary`](Self::summary).
+    pub fn log_summary(&self) {
+        let summary = self.summary();
+        info!("MMIO profile ({} registers accessed):", summary.len());
+        for profile in summary {
+            let register = profile
+                .offset
+                .map_or_else(|| "other".to_string(), |offset| format!("{offset:#06x}"));
+            info!(
+                "  {register}: {} reads, {} writes, {:?} total, {:?} per access",
+                profile.reads,
+                profile.writes,
+                profile.latency,
+                profile.latency / u32::try_from(profile.accesses()).unwrap_or(u32::MAX)
+            );
+        }
+    }
+}
+
+/// The bucket that accounts accesses to `offset` in `region`.
+const fn bucket_index(region: u32, offset: u64) -> usize {
+    if region == 0 && offset < PROFILED_SIZE {
+        (offset / REGISTER_SIZE) as usize
+    } else {
+        OTHER_BUCKET
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accesses_are_bucketed_per_register() {
+        assert_eq!(bucket_index(0, 0x0), 0);
+        assert_eq!(bucket_index(0, 0x3), 0);
+        assert_eq!(bucket_index(0, 0x44), 0x11);
+        assert_eq!(bucket_index(0, PROFILED_SIZE - 1), OTHER_BUCKET - 1);
+        assert_eq!(bucket_index(0, PROFILED_SIZE), OTHER_BUCKET);
+        assert_eq!(bucket_index(3, 0x0), OTHER_BUCKET);
+    }
+
+    #[test]
+    fn disabled_profile_records_nothing() {
+        let profile = MmioProfile::new();
+        let start = profile.start();
+        assert_eq!(start, None);
+        profile.record(MmioAccess::Read, 0, 0x44, start);
+        assert!(profile.summary().is_empty());
+    }
+
+    #[test]
+    fn summary_aggregates_and_sorts_by_accesses() {
+        let profile = MmioProfile::new();
+        profile.enable();
+        let access =
+            |access, region, offset| profile.record(access, region, offset, profile.start());
+
+        access(MmioAccess::Write, 0, 0x40);
+        for _ in 0..3 {
+            access(MmioAccess::Read, 0, 0x44);
+        }
+        access(MmioAccess::Write, 0, 0x46);
+        access(MmioAccess::Read, 3, 0x1000);
+        access(MmioAccess::Write, 3, 0x0c);
+
+        let summary = profile.summary();
+        let counts: Vec<_> = summary
+            .iter()
+            .map(|p| (p.offset, p.reads, p.writes))
+            .collect();
+        assert_eq!(
+            counts,
+            [(Some(0x44), 3, 1), (None, 1, 1), (Some(0x40), 0, 1)]
+        );
+    }
+}
@@ -3,15 +3,44 @@
 //! The PCI Local Bus is the central component for attaching devices
 //! to a virtual machine. This module contains the generic PCI
 //! emulation logic for the configuration space.
+pub mod commands;
 pub mod config_space;
 pub mod constants;
+pub mod dci;
+pub mod descriptors;
 pub mod device_slots;
+pub mod doorbell;
+pub mod endpoint_stats;
+pub mod erdp_watch;
+pub mod event_batch;
+pub mod event_sink;
+#[cfg(feature = "nusb-backend")]
+pub mod executor;
+pub mod isoch;
+pub mod lifecycle;
+pub mod mmio_profile;
+pub mod msix_pba;
 pub mod msix_table;
+#[cfg(feature = "nusb-backend")]
 pub mod nusb;
+pub mod paranoid_dma;
+#[cfg(any(feature = "nusb-backend", test))]
+pub mod pipes;
 pub mod realdevice;
 pub mod registers;
+#[cfg(test)]
+pub mod replay;
 pub mod rings;
+pub mod run_state;
+#[cfg(feature = "nusb-backend")]
+pub mod scheduler;
+pub mod td_engine;
+pub mod trace;
 pub mod traits;
+pub mod transfer_unit;
 pub mod trb;
+pub mod trb_fields;
 pub mod usbrequest;
+pub mod virtual_device;
+pub mod vmm_signals;
 pub mod xhci;
@@ -0,0 +1,220 @@
+//! # MSI-X Pending Bit Array
+//!
+//! While an MSI-X vector or the whole function is masked, the device must not
+//! send the vector's message. Instead, it sets the vector's bit in the Pending
+//! Bit Array (PBA) and sends the message once the vector is unmasked. See
+//! Section 6.8.2 of the PCI Local Bus 3.0 specification.
+//!
+//! Interrupts are raised from endpoint workers, while the mask bits change on
+//! MMIO and configuration space writes of the driver. [`PendingBitArray`]
+//! keeps both in atomics, so neither path needs the controller lock.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::device::{bus::Request, interrupt_line::InterruptLine};
+
+/// The pending and mask state of all MSI-X vectors of a function.
+#[derive(Debug)]
+pub struct PendingBitArray {
+    /// The number of vectors.
+    vector_count: u16,
+    /// The Pending Bits. Bit N of word N / 64 belongs to vector N, which is
+    /// also the layout the driver sees.
+    pending: Box<[AtomicU64]>,
+    /// The Mask Bits of the vectors, in the same layout as `pending`.
+    masked: Box<[AtomicU64]>,
+    /// The Function Mask of the MSI-X capability.
+    function_masked: AtomicBool,
+}
+
+/// The word and the bit within the word that belong to a vector.
+const fn word_and_bit(vector: u16) -> (usize, u64) {
+    ((vector / 64) as usize, 1 << (vector % 64))
+}
+
+impl PendingBitArray {
+    /// Create the PBA for `vector_count` vectors.
+    ///
+    /// The vectors start out unmasked: A client that emulates the MSI-X
+    /// table itself never forwards table writes, so we only mask vectors
+    /// once the driver programs them through our table.
+    pub fn new(vector_count: u16) -> Self {
+        let words = usize::from(vector_count).div_ceil(64);
+        Self {
+            vector_count,
+            pending: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            masked: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            function_masked: AtomicBool::new(false),
+        }
+    }
+
+    fn is_masked(&self, vector: u16) -> bool {
+        let (word, bit) = word_and_bit(vector);
+        self.function_masked.load(Ordering::SeqCst)
+            || self.masked[word].load(Ordering::SeqCst) & bit != 0
+    }
+
+    /// Clear the Pending Bit of a vector and return whether it was set.
+    fn take_pending(&self, vector: u16) -> bool {
+        let (word, bit) = word_and_bit(vector);
+        self.pending[word].fetch_and(!bit, Ordering::SeqCst) & bit != 0
+    }
+
+    /// Deliver a pending message if the vector is not masked (anymore).
+    fn take_deliverable(&self, vector: u16) -> bool {
+        !self.is_masked(vector) && self.take_pending(vector)
+    }
+
+    /// Signal an interrupt on a vector.
+    ///
+    /// Returns whether the message has to be sent now. Otherwise, the
+    /// vector is marked pending.
+    pub fn raise(&self, vector: u16) -> bool {
+        if !self.is_masked(vector) {
+            return true;
+        }
+
+        let (word, bit) = word_and_bit(vector);
+        self.pending[word].fetch_or(bit, Ordering::SeqCst);
+        // The vector might have been unmasked after the check above, but
+        // before the unmasking side looked for pending messages.
+        self.take_deliverable(vector)
+    }
+
+    /// Update the Mask Bit of a vector.
+    ///
+    /// Returns whether a pending message has to be sent now.
+    pub fn set_vector_masked(&self, vector: u16, masked: bool) -> bool {
+        let (word, bit) = word_and_bit(vector);
+        if masked {
+            self.masked[word].fetch_or(bit, Ordering::SeqCst);
+        } else {
+            self.masked[word].fetch_and(!bit, Ordering::SeqCst);
+        }
+        self.take_deliverable(vector)
+    }
+
+    /// Update the Function Mask.
+    ///
+    /// Returns the vectors whose pending messages have to be sent now.
+    pub fn set_function_masked(&self, masked: bool) -> Vec<u16> {
+        self.function_masked.store(masked, Ordering::SeqCst);
+        (0..self.vector_count)
+            .filter(|&vector| self.take_deliverable(vector))
+            .collect()
+    }
+
+    /// Handle reads of the PBA.
+    ///
+    /// `req.addr` is relative to the start of the PBA. The PBA is read-only,
+    /// so there is no counterpart for writes.
+    pub fn read(&self, req: Request) -> u64 {
+        let word = usize::try_from(req.addr / 8)
+            .ok()
+            .and_then(|word| self.pending.get(word))
+            .map_or(0, |word| word.load(Ordering::SeqCst));
+        let value = word >> ((req.addr % 8) * 8);
+
+        match u64::from(req.size) {
+            8 => value,
+            bytes => value & ((1 << (bytes * 8)) - 1),
+        }
+    }
+}
+
+/// An interrupt line that honors the mask state of its MSI-X vector.
+#[derive(Debug)]
+pub struct MaskableInterruptLine {
+    vector: u16,
+    pending_bits: Arc<PendingBitArray>,
+    line: Arc<dyn InterruptLine>,
+}
+
+impl MaskableInterruptLine {
+    /// Send the interrupts of `vector` to `line`, unless the vector is
+    /// masked in `pending_bits`.
+    pub const fn new(
+        vector: u16,
+        pending_bits: Arc<PendingBitArray>,
+        line: Arc<dyn InterruptLine>,
+    ) -> Self {
+        Self {
+            vector,
+            pending_bits,
+            line,
+        }
+    }
+}
+
+impl InterruptLine for MaskableInterruptLine {
+    fn interrupt(&self) {
+        if self.pending_bits.raise(self.vector) {
+            self.line.interrupt();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::bus::RequestSize;
+
+    use super::*;
+
+    #[test]
+    fn unmasked_vectors_are_delivered_directly() {
+        let pba = PendingBitArray::new(1);
+        assert!(pba.raise(0));
+        assert_eq!(pba.read(Request::new(0, RequestSize::Size8)), 0);
+    }
+
+    #[test]
+    fn pending_bits_use_pci_bit_order() {
+        let pba = PendingBitArray::new(128);
+        for vector in [1, 64 + 2, 64 + 40] {
+            pba.set_vector_masked(vector, true);
+            assert!(!pba.raise(vector));
+        }
+
+        assert_eq!(pba.read(Request::new(0x0, RequestSize::Size8)), 0b10);
+        assert_eq!(
+            pba.read(Request::new(0x8, RequestSize::Size8)),
+            (1 << 40) | 0b100
+        );
+        // Smaller reads see parts of the qwords.
+        assert_eq!(pba.read(Request::new(0x8, RequestSize::Size4)), 0b100);
+        assert_eq!(pba.read(Request::new(0xc, RequestSize::Size4)), 1 << 8);
+        assert_eq!(pba.read(Request::new(0xd, RequestSize::Size1)), 1);
+        // Beyond the last vector, the PBA reads as zero.
+        assert_eq!(pba.read(Request::new(0x10, RequestSize::Size8)), 0);
+    }
+
+    #[test]
+    fn unmasking_delivers_pending_message_once() {
+        let pba = PendingBitArray::new(2);
+        pba.set_vector_masked(1, true);
+        assert!(!pba.raise(1));
+        assert!(!pba.raise(1));
+
+        // Vector 0 is unaffected.
+        assert!(!pba.set_vector_masked(0, false));
+        assert!(pba.set_vector_masked(1, false));
+        assert_eq!(pba.read(Request::new(0, RequestSize::Size8)), 0);
+        assert!(!pba.set_vector_masked(1, false));
+    }
+
+    #[test]
+    fn function_mask_holds_back_all_vectors() {
+        let pba = PendingBitArray::new(3);
+        pba.set_vector_masked(2, true);
+        assert!(pba.set_function_masked(true).is_empty());
+        assert!(!pba.raise(0));
+        assert!(!pba.raise(2));
+
+        // Vector 2 stays masked on its own.
+        assert_eq!(pba.set_function_masked(false), [0]);
+        assert_eq!(pba.read(Request::new(0, RequestSize::Size8)), 0b100);
+    }
+}
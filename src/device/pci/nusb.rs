@@ -1,29 +1,64 @@
+use nusb::descriptors::{DeviceDescriptor, TransferType};
 use nusb::transfer::{
-    Buffer, Bulk, BulkOrInterrupt, ControlIn, ControlOut, ControlType, In, Interrupt, Out,
-    Recipient,
+    Buffer, Bulk, BulkOrInterrupt, Completion, ControlIn, ControlOut, ControlType,
+    EndpointDirection, In, Interrupt, Out, Recipient, TransferError,
 };
-use nusb::MaybeFuture;
-use tracing::{debug, trace, warn};
+use nusb::{ActiveConfigurationError, MaybeFuture};
+use thiserror::Error;
+use tracing::{debug, trace, warn, Instrument};
 
+use crate::affinity::{spawn_thread, WorkerClass, WorkerPolicy};
 use crate::device::bus::BusDeviceRef;
-use crate::device::pci::trb::{CompletionCode, EventTrb};
+use crate::device::pci::trb::CompletionCode;
 
-use super::realdevice::{EndpointType, EndpointWorkerInfo, Speed};
-use super::trb::{NormalTrbData, TransferTrb, TransferTrbVariant};
+use super::dci::{Dci, Direction};
+use super::descriptors::{self, ConfigurationDescriptor};
+use super::device_slots::StreamContextArray;
+use super::executor::{Doorbell, Executor};
+use super::pipes::{PipeReturn, Pipes};
+use super::realdevice::{
+    DeviceIdentification, DeviceIdentity, EndpointType, EndpointWorkerInfo, HostLocation,
+    InterfaceClaim, Speed,
+};
+use super::td_engine::{
+    read_out_data, write_in_data, InFlightTds, IntervalPacer, TdEngine, TdOutcome,
+};
+use super::transfer_unit::{split_td, TransferUnit};
 use super::{realdevice::RealDevice, usbrequest::UsbRequest};
-use std::cmp::Ordering::*;
+use std::future::Future;
+use std::num::NonZeroUsize;
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
 use std::thread;
 use std::{
     fmt::Debug,
-    sync::atomic::{fence, Ordering},
-    time::Duration,
+    sync::atomic::{fence, AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
 pub struct NusbDeviceWrapper {
     device: nusb::Device,
+    /// Read once when the device is wrapped, as the serial number takes a
+    /// control transfer.
+    identity: Arc<DeviceIdentity>,
     interfaces: Vec<nusb::Interface>,
-    endpoints: [Option<Sender<()>>; 30],
+    worker_model: WorkerModel,
+    /// Whether Interrupt IN endpoints are polled at the interval the driver
+    /// configured.
+    interrupt_pacing: bool,
+    /// The number of transfers Bulk IN endpoints keep in flight.
+    bulk_in_queue_depth: NonZeroUsize,
+    /// The workers of the enabled endpoints, indexed by [`handle_index`].
+    endpoints: [Option<EndpointHandle>; 30],
+    /// The pipes of the endpoints, which outlive their workers.
+    pipes: Pipes<HostPipe>,
+}
+
+/// The index of the worker of a non-control endpoint in
+/// [`NusbDeviceWrapper::endpoints`].
+const fn handle_index(endpoint_id: Dci) -> usize {
+    endpoint_id.get() as usize - 2
 }
 
 impl Debug for NusbDeviceWrapper {
@@ -32,57 +67,92 @@ impl Debug for NusbDeviceWrapper {
         // for unconfigured devices. There is no I/O for this.
         f.debug_struct("NusbDeviceWrapper")
             .field("device", &self.device.active_configuration())
+            .field("identity", &self.identity)
+            .field("worker_model", &self.worker_model)
             .finish()
     }
 }
 
 impl NusbDeviceWrapper {
-    pub fn new(device: nusb::Device) -> Self {
-        // Claim all interfaces
-        let mut interfaces = vec![];
-        // when we cannot get the active configuration, i.e., not properly talk
-        // to the device, panicking is currently the desired behavior to
-        // identify the situation in which the problem occurred.
-        let desc = device.active_configuration().unwrap();
-        for interface in desc.interfaces() {
-            let interface_number = interface.interface_number();
-            debug!("Enabling interface {}", interface_number);
-            // when we cannot claim an interface of the device, panicking is
-            // currently the desired behavior to identify the situation in which
-            // the problem occurred.
-            interfaces.push(
-                device
-                    .detach_and_claim_interface(interface_number)
-                    .wait()
-                    .unwrap(),
-            );
-        }
+    /// Wrap a device and claim all interfaces of its active configuration.
+    ///
+    /// Fails if an interface cannot be claimed, e.g., because it is in use
+    /// and `interface_claim` does not allow detaching its driver. Without
+    /// `interrupt_pacing`, Interrupt IN endpoints are polled as fast as the
+    /// device completes transfers. Bulk IN endpoints without streams keep
+    /// up to `bulk_in_queue_depth` transfers in flight.
+    pub fn new(
+        device: nusb::Device,
+        location: HostLocation,
+        worker_model: WorkerModel,
+        interface_claim: InterfaceClaim,
+        interrupt_pacing: bool,
+        bulk_in_queue_depth: NonZeroUsize,
+    ) -> Result<Self, DeviceError> {
+        let interface_numbers = device
+            .active_configuration()?
+            .interfaces()
+            .map(|interface| interface.interface_number())
+            .collect::<Vec<_>>();
+        let interfaces =
+            claim_interfaces(
+                interface_numbers,
+                |interface_number| match interface_claim {
+                    InterfaceClaim::Detach => {
+                        device.detach_and_claim_interface(interface_number).wait()
+                    }
+                    InterfaceClaim::NoDetach => device.claim_interface(interface_number).wait(),
+                },
+            )
+            .map_err(|(interface_number, source)| DeviceError::ClaimInterface {
+                interface_number,
+                source,
+            })?;
 
-        Self {
+        let identity = Arc::new(DeviceIdentity::new(location, read_identification(&device)));
+
+        Ok(Self {
             device,
+            identity,
             interfaces,
+            worker_model,
+            interrupt_pacing,
+            bulk_in_queue_depth,
             endpoints: std::array::from_fn(|_| None),
-        }
+            pipes: Pipes::default(),
+        })
     }
 
-    fn extract_recipient_and_type(request_type: u8) -> (Recipient, ControlType) {
+    const fn extract_recipient_and_type(
+        request_type: u8,
+    ) -> Result<(Recipient, ControlType), InvalidRequestType> {
         let recipient = match request_type & 0x1f {
             0 => Recipient::Device,
             1 => Recipient::Interface,
             2 => Recipient::Endpoint,
-            val => panic!("invalid recipient {}", val),
+            3 => Recipient::Other,
+            val => return Err(InvalidRequestType::Recipient(val)),
         };
         let control_type = match (request_type >> 5) & 0x3 {
             0 => ControlType::Standard,
             1 => ControlType::Class,
             2 => ControlType::Vendor,
-            val => panic!("invalid type {}", val),
+            val => return Err(InvalidRequestType::Type(val)),
         };
-        (recipient, control_type)
+        Ok((recipient, control_type))
     }
 
-    fn control_transfer_device_to_host(&self, request: &UsbRequest, dma_bus: &BusDeviceRef) {
-        let (recipient, control_type) = Self::extract_recipient_and_type(request.request_type);
+    fn control_transfer_device_to_host(
+        &self,
+        request: &UsbRequest,
+        recipient: Recipient,
+        control_type: ControlType,
+        dma_bus: &BusDeviceRef,
+    ) -> CompletionCode {
+        let Some(buffer) = request.data else {
+            warn!("control in request without Data Stage; reporting Stall Error");
+            return CompletionCode::StallError;
+        };
         let control = ControlIn {
             control_type,
             recipient,
@@ -110,20 +180,46 @@ impl NusbDeviceWrapper {
 
         // TODO: ideally the control transfer targets the right location for us and we get rid
         // of the additional DMA write here.
-        dma_bus.write_bulk(request.data.unwrap(), &data);
+        let written = write_in_data(dma_bus, buffer.pointer, &data, buffer.length.into());
 
         // Ensure the data copy to guest memory completes before the subsequent
         // transfer event write completes.
         fence(Ordering::Release);
+
+        match written {
+            Ok(_) => CompletionCode::Success,
+            Err(unmapped) => {
+                warn!(
+                    "control in buffer is not fully backed by guest memory (unmapped: {:#x}..{:#x}); reporting Data Buffer Error",
+                    unmapped.start, unmapped.end
+                );
+                CompletionCode::DataBufferError
+            }
+        }
     }
 
-    fn control_transfer_host_to_device(&self, request: &UsbRequest, dma_bus: &BusDeviceRef) {
-        let data = request.data.map_or_else(Vec::new, |addr| {
-            let mut data = vec![0; request.length as usize];
-            dma_bus.read_bulk(addr, &mut data);
-            data
-        });
-        let (recipient, control_type) = Self::extract_recipient_and_type(request.request_type);
+    fn control_transfer_host_to_device(
+        &self,
+        request: &UsbRequest,
+        recipient: Recipient,
+        control_type: ControlType,
+        dma_bus: &BusDeviceRef,
+    ) -> CompletionCode {
+        let data = match request.data.map_or_else(
+            || Ok(Vec::new()),
+            |buffer| read_out_data(dma_bus, buffer.pointer, buffer.length.into()),
+        ) {
+            Ok(data) => data,
+            Err(unmapped) => {
+                // Like for OUT TDs, we don't send the default device's fill
+                // pattern to the real device.
+                warn!(
+                    "control out buffer is not fully backed by guest memory (unmapped: {:#x}..{:#x}); reporting Data Buffer Error",
+                    unmapped.start, unmapped.end
+                );
+                return CompletionCode::DataBufferError;
+            }
+        };
         let control = ControlOut {
             control_type,
             recipient,
@@ -142,6 +238,7 @@ impl NusbDeviceWrapper {
             Ok(_) => debug!("control out success"),
             Err(error) => warn!("control out request failed: {:?}", error),
         }
+        CompletionCode::Success
     }
 
     fn get_interface_number_containing_endpoint(&self, endpoint_id: u8) -> Option<usize> {
@@ -153,6 +250,108 @@ impl NusbDeviceWrapper {
                 .any(|ep| ep.address() == endpoint_id)
         })
     }
+
+    /// Lend the pipe of an endpoint to a new worker, opening it with `open`
+    /// unless the endpoint still has a pipe of type `E`.
+    fn lend_pipe<E: Pipe>(
+        &mut self,
+        endpoint_id: Dci,
+        open: impl FnOnce(&nusb::Interface, u8) -> Result<E, nusb::Error>,
+    ) -> (E, PipeReturn<HostPipe>) {
+        let (pipe, pipe_return) = self.pipes.lend(endpoint_id);
+        // A pipe of another type is dropped here, before we open the new
+        // one, as nusb only opens an endpoint once.
+        if let Some(pipe) = pipe.and_then(E::from_pipe) {
+            debug!("reusing the pipe of EP{}", endpoint_id);
+            return (pipe, pipe_return);
+        }
+        let endpoint_address = endpoint_id.address();
+        // The controller only enables endpoints that the device describes
+        // with the configured type. unwrap can still fail when the driver
+        // switched interfaces to alternate modes, which could enable
+        // endpoint that we are currently not aware of (TODO). We cannot
+        // reasonably continue then and want to see what we encountered, so
+        // panicking is the intended behavior.
+        let interface_of_endpoint = &self.interfaces[self
+            .get_interface_number_containing_endpoint(endpoint_address)
+            .unwrap()];
+        let pipe = open(interface_of_endpoint, endpoint_address).unwrap();
+        (pipe, pipe_return)
+    }
+}
+
+/// Read the IDs and the serial number of a device.
+///
+/// A serial number that cannot be read is reported as missing, as it is
+/// only informational.
+fn read_identification(device: &nusb::Device) -> DeviceIdentification {
+    let descriptor = device.device_descriptor();
+    let serial = descriptor.serial_number_string_index().and_then(|index| {
+        device
+            .get_string_descriptor(
+                index,
+                nusb::descriptors::language_id::US_ENGLISH,
+                Duration::from_millis(200),
+            )
+            .wait()
+            .inspect_err(|error| debug!("failed to read serial number: {}", error))
+            .ok()
+    });
+
+    identification_of(&descriptor, serial)
+}
+
+/// The identification of a device with `descriptor` and `serial` number.
+fn identification_of(
+    descriptor: &DeviceDescriptor,
+    serial: Option<String>,
+) -> DeviceIdentification {
+    DeviceIdentification {
+        vendor_id: descriptor.vendor_id(),
+        product_id: descriptor.product_id(),
+        serial,
+    }
+}
+
+/// Why a device cannot be passed through.
+#[derive(Error, Debug)]
+pub enum DeviceError {
+    /// The device is not configured, so we do not know its interfaces.
+    #[error("cannot read the active configuration: {0}")]
+    ActiveConfiguration(#[from] ActiveConfigurationError),
+    /// An interface is in use, or we lack the permission to claim it.
+    #[error("cannot claim interface {interface_number}: {source}")]
+    ClaimInterface {
+        interface_number: u8,
+        source: nusb::Error,
+    },
+}
+
+/// Claim the interfaces with the given numbers in order.
+///
+/// Stops at the first interface that cannot be claimed and returns its
+/// number along with the error. The interfaces claimed until then are
+/// released when they are dropped.
+fn claim_interfaces<I, E>(
+    interface_numbers: impl IntoIterator<Item = u8>,
+    mut claim: impl FnMut(u8) -> Result<I, E>,
+) -> Result<Vec<I>, (u8, E)> {
+    interface_numbers
+        .into_iter()
+        .map(|interface_number| {
+            debug!("Enabling interface {}", interface_number);
+            claim(interface_number).map_err(|error| (interface_number, error))
+        })
+        .collect()
+}
+
+/// The reserved bit patterns in the `bmRequestType` of a control request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InvalidRequestType {
+    /// The recipient bits (4..0) hold a reserved value.
+    Recipient(u8),
+    /// The type bits (6..5) hold the reserved value.
+    Type(u8),
 }
 
 impl From<nusb::Speed> for Speed {
@@ -173,36 +372,172 @@ impl RealDevice for NusbDeviceWrapper {
         self.device.speed().map(|speed| speed.into())
     }
 
-    fn control_transfer(&self, request: &UsbRequest, dma_bus: &BusDeviceRef) {
+    fn identity(&self) -> &Arc<DeviceIdentity> {
+        &self.identity
+    }
+
+    fn device_descriptor(&self) -> descriptors::DeviceDescriptor {
+        // nusb checked the descriptor when it opened the device.
+        descriptors::DeviceDescriptor::parse(self.device.device_descriptor().as_bytes())
+            .expect("nusb only opens devices with a valid device descriptor")
+    }
+
+    fn configuration_descriptors(&self) -> Vec<ConfigurationDescriptor> {
+        // nusb caches the descriptors, so this does no I/O.
+        self.device
+            .configurations()
+            .filter_map(|configuration| {
+                ConfigurationDescriptor::parse(configuration.as_bytes())
+                    .inspect_err(|error| {
+                        warn!("ignoring configuration descriptor: {}", error);
+                    })
+                    .ok()
+            })
+            .collect()
+    }
+
+    fn control_transfer(&self, request: &UsbRequest, dma_bus: &BusDeviceRef) -> CompletionCode {
+        let (recipient, control_type) = match Self::extract_recipient_and_type(request.request_type)
+        {
+            Ok(recipient_and_type) => recipient_and_type,
+            Err(error) => {
+                // A device stalls requests it cannot make sense of, so we
+                // do the same instead of forwarding them.
+                warn!("rejecting control request: {:?}", error);
+                return CompletionCode::StallError;
+            }
+        };
+
         let direction = request.request_type & 0x80 != 0;
         match direction {
-            true => self.control_transfer_device_to_host(request, dma_bus),
-            false => self.control_transfer_host_to_device(request, dma_bus),
+            true => self.control_transfer_device_to_host(request, recipient, control_type, dma_bus),
+            false => {
+                self.control_transfer_host_to_device(request, recipient, control_type, dma_bus)
+            }
         }
     }
 
-    fn transfer(&mut self, endpoint_id: u8) {
-        // transfer requires targeted endpoint to be enabled, panic if not
-        match self.endpoints[endpoint_id as usize - 2].as_mut() {
-            // Currently we start an endpoint worker once and never stop it,
-            // so sending should never fail. When the worker has panicked, it
-            // makes sense for us to panic as well.
-            Some(sender) => {
+    fn transfer(&mut self, endpoint_id: Dci, stream_id: u16) {
+        // transfer requires targeted endpoint to be enabled
+        match self.endpoints[handle_index(endpoint_id)].as_ref() {
+            Some(handle) => {
+                // Stream ID 0 only wakes up the worker, e.g., when the
+                // controller starts again after a stop.
+                if let Some(streams) = &handle.streams {
+                    if stream_id != 0 && !streams.notify(stream_id) {
+                        return;
+                    }
+                }
                 trace!("Sending wake up to worker of ep {}", endpoint_id);
-                sender.send(()).unwrap();
+                handle.wakeup.wake();
+            }
+            // The controller answers doorbells for disabled endpoints
+            // itself, so this one raced with disabling the endpoint.
+            None => debug!("ignoring transfer for disabled EP{}", endpoint_id),
+        };
+    }
+
+    fn reset(&mut self) {
+        // nusb::Device::reset performs a port reset, after which the device
+        // has to be opened again, invalidating our claimed interfaces and
+        // the endpoints of running workers. Clearing the halts of all
+        // endpoints we transferred on gets the device back into a usable
+        // state without that, and resets their toggles on both sides.
+        for endpoint_id in Dci::non_control() {
+            if self.endpoints[handle_index(endpoint_id)].is_some() {
+                self.clear_halt(endpoint_id);
+            } else if let Some(pipe) = self.pipes.discard(endpoint_id) {
+                pipe.clear_halt(endpoint_id);
+            }
+        }
+    }
+
+    fn clear_halt(&mut self, endpoint_id: Dci) {
+        if endpoint_id == Dci::CONTROL {
+            // The control endpoint recovers from a stall with the next
+            // SETUP packet.
+            return;
+        }
+        match self.endpoints[handle_index(endpoint_id)].as_ref() {
+            Some(handle) => {
+                debug!("requesting worker of EP{} to clear halt", endpoint_id);
+                handle.requests.clear_halt.request();
+                handle.wakeup.wake();
+                // Clearing the halt resets the toggle, so the next worker
+                // may start on a new pipe.
+                self.pipes.discard(endpoint_id);
             }
-            None => panic!("transfer for uninitialized endpoint (EP{})", endpoint_id),
+            None => match self.pipes.discard(endpoint_id) {
+                // Nobody transfers on the pipe of a disabled endpoint, so
+                // we can clear its halt right away.
+                Some(pipe) => pipe.clear_halt(endpoint_id),
+                // Without a pipe, we never transferred anything on the
+                // endpoint, so there is no halt on our side.
+                None => debug!("ignoring clear halt of disabled EP{}", endpoint_id),
+            },
+        }
+    }
+
+    fn stop_endpoint(&mut self, endpoint_id: Dci, timeout: Duration) -> bool {
+        if endpoint_id == Dci::CONTROL {
+            // Control transfers complete before the doorbell write that
+            // started them, so nothing can be in flight.
+            return true;
+        }
+        // Without a worker, nothing is in flight on the endpoint.
+        let Some(handle) = self.endpoints[handle_index(endpoint_id)].as_ref() else {
+            return true;
         };
+        debug!("requesting worker of EP{} to stop", endpoint_id);
+        let acknowledgment = handle.requests.stop.request(false, || handle.wakeup.wake());
+        acknowledgment.recv_timeout(timeout).is_ok()
     }
 
-    fn enable_endpoint(&mut self, worker_info: EndpointWorkerInfo, endpoint_type: EndpointType) {
+    fn disable_endpoint(&mut self, endpoint_id: Dci, timeout: Duration) -> bool {
+        // Without a worker, there is nothing to shut down.
+        let Some(handle) = self.endpoints[handle_index(endpoint_id)].take() else {
+            return true;
+        };
+        debug!("requesting worker of EP{} to exit", endpoint_id);
+        let acknowledgment = handle.requests.stop.request(true, || handle.wakeup.wake());
+        if acknowledgment.recv_timeout(timeout).is_err() {
+            return false;
+        }
+        // The worker gives back its pipe right after it acknowledged.
+        if !self.pipes.collect(endpoint_id, timeout) {
+            debug!("worker of EP{} did not give back its pipe", endpoint_id);
+        }
+        true
+    }
+
+    fn release(&mut self, timeout: Duration) {
+        for endpoint_id in Dci::non_control() {
+            if !self.disable_endpoint(endpoint_id, timeout) {
+                warn!(
+                    "worker of EP{} did not exit within {:?}; its interface stays claimed",
+                    endpoint_id, timeout
+                );
+            }
+        }
+        // nusb releases an interface once the last handle to it is gone,
+        // including the pipes to its endpoints, and attaches the kernel
+        // driver again if it detached it.
+        self.pipes.discard_all();
+        self.interfaces.clear();
+        debug!("released device at {}", self.identity.location);
+    }
+
+    fn enable_endpoint(
+        &mut self,
+        mut worker_info: EndpointWorkerInfo,
+        endpoint_type: EndpointType,
+    ) {
         let endpoint_id = worker_info.endpoint_id;
-        assert!(
-            (2..=31).contains(&endpoint_id),
-            "request to enable invalid endpoint id on nusb device. endpoint_id = {}",
-            endpoint_id
-        );
-        if self.endpoints[endpoint_id as usize - 2].is_some() {
+        // The controller handles the Default Control Endpoint itself.
+        let Some(direction) = endpoint_id.direction() else {
+            panic!("request to enable the control endpoint on nusb device");
+        };
+        if self.endpoints[handle_index(endpoint_id)].is_some() {
             // endpoint is already enabled.
             //
             // The Linux kernel configures and directly afterwards reconfigures
@@ -211,106 +546,426 @@ impl RealDevice for NusbDeviceWrapper {
             return;
         }
 
-        let endpoint_index = endpoint_id / 2;
-        let is_out_endpoint = endpoint_id % 2 == 0;
-        let name = format!(
-            "worker Slot {} Endpoint {} (EP{} {}, {:?})",
+        if !self.interrupt_pacing {
+            worker_info.polling_interval = None;
+        }
+        // Transfers that never started go back to the transfer ring, which
+        // an endpoint with streams does not have a single one of.
+        if endpoint_type == EndpointType::BulkIn && worker_info.engine.streams().is_none() {
+            worker_info.queue_depth = self.bulk_in_queue_depth;
+        }
+        debug!(
+            "starting worker of slot {} endpoint {} (EP{} {}, {:?}, {}) of {}",
             worker_info.slot_id,
             endpoint_id,
-            endpoint_index,
-            if is_out_endpoint { "OUT" } else { "IN" },
+            endpoint_id.number(),
+            if direction == Direction::Out {
+                "OUT"
+            } else {
+                "IN"
+            },
             endpoint_type,
+            worker_info.transfer_unit,
+            self.identity,
         );
-        let endpoint_sender = match is_out_endpoint {
-            true => {
-                // unwrap can fail when
-                // - driver asks for invalid endpoint (driver's fault)
-                // - driver switched interfaces to alternate modes, which could
-                //   enable endpoint that we are currently not aware of (TODO)
-                // In both cases, we cannot reasonably continue and want to see
-                // what we encountered, so panicking is the intended behavior.
-                let interface_of_endpoint = &self.interfaces[self
-                    .get_interface_number_containing_endpoint(endpoint_index)
-                    .unwrap()];
-                let endpoint = interface_of_endpoint
-                    .endpoint::<Bulk, Out>(endpoint_index)
-                    .unwrap();
-                let (sender, receiver) = mpsc::channel();
-                thread::Builder::new()
-                    .name(name.clone())
-                    .spawn(move || transfer_out_worker(endpoint, worker_info, receiver))
-                    .unwrap_or_else(|_| panic!("Failed to launch endpoint worker thread {name}"));
-                sender
+        let endpoint_handle = match direction {
+            Direction::Out => {
+                let (endpoint, pipe_return) = self.lend_pipe(endpoint_id, |interface, address| {
+                    interface.endpoint::<Bulk, Out>(address)
+                });
+                self.worker_model.start(
+                    endpoint,
+                    pipe_return,
+                    endpoint_type,
+                    worker_info,
+                    transfer_out_worker,
+                    transfer_out_task,
+                )
             }
-            false => {
-                let endpoint_index = 0x80 | endpoint_index;
-                // unwrap can fail when
-                // - driver asks for invalid endpoint (driver's fault)
-                // - driver switched interfaces to alternate modes, which could
-                //   enable endpoint that we are currently not aware of (TODO)
-                // In both cases, we cannot reasonably continue and want to see
-                // what we encountered, so panicking is the intended behavior.
-                let interface_of_endpoint = &self.interfaces[self
-                    .get_interface_number_containing_endpoint(endpoint_index)
-                    .unwrap()];
-                let (sender, receiver) = mpsc::channel();
-                match endpoint_type {
-                    EndpointType::BulkIn => {
-                        let endpoint = interface_of_endpoint
-                            .endpoint::<Bulk, In>(endpoint_index)
-                            .unwrap();
-                        thread::Builder::new()
-                            .name(name.clone())
-                            .spawn(move || {
-                                transfer_in_worker::<Bulk>(endpoint, worker_info, receiver)
-                            })
-                            .unwrap_or_else(|_| {
-                                panic!("Failed to launch endpoint worker thread {name}")
-                            });
-                    }
-                    EndpointType::InterruptIn => {
-                        let endpoint = interface_of_endpoint
-                            .endpoint::<Interrupt, In>(endpoint_index)
-                            .unwrap();
-                        thread::Builder::new()
-                            .name(name.clone())
-                            .spawn(move || {
-                                transfer_in_worker::<Interrupt>(endpoint, worker_info, receiver)
-                            })
-                            .unwrap_or_else(|_| {
-                                panic!("Failed to launch endpoint worker thread {name}")
-                            });
-                    }
-                    _ => {
-                        panic!(
-                            "Unexpected endpoint type for IN endpoint: {:?}",
-                            endpoint_type
-                        );
-                    }
+            Direction::In => match endpoint_type {
+                EndpointType::BulkIn => {
+                    let (endpoint, pipe_return) = self
+                        .lend_pipe(endpoint_id, |interface, address| {
+                            interface.endpoint::<Bulk, In>(address)
+                        });
+                    self.worker_model.start(
+                        endpoint,
+                        pipe_return,
+                        endpoint_type,
+                        worker_info,
+                        transfer_in_worker,
+                        transfer_in_task,
+                    )
                 }
-                sender
-            }
+                EndpointType::InterruptIn => {
+                    let (endpoint, pipe_return) = self
+                        .lend_pipe(endpoint_id, |interface, address| {
+                            interface.endpoint::<Interrupt, In>(address)
+                        });
+                    self.worker_model.start(
+                        endpoint,
+                        pipe_return,
+                        endpoint_type,
+                        worker_info,
+                        transfer_in_worker,
+                        transfer_in_task,
+                    )
+                }
+                _ => {
+                    panic!(
+                        "Unexpected endpoint type for IN endpoint: {:?}",
+                        endpoint_type
+                    );
+                }
+            },
         };
-        self.endpoints[endpoint_id as usize - 2] = Some(endpoint_sender);
+        self.endpoints[handle_index(endpoint_id)] = Some(endpoint_handle);
         debug!("enabled EP{} on real device", endpoint_id);
     }
 }
 
+/// The scheduling class of the workers of an endpoint type.
+const fn worker_class(endpoint_type: EndpointType) -> Option<WorkerClass> {
+    match endpoint_type {
+        EndpointType::BulkIn | EndpointType::BulkOut => Some(WorkerClass::Bulk),
+        EndpointType::InterruptIn => Some(WorkerClass::Interrupt),
+        EndpointType::Control
+        | EndpointType::IsochOut
+        | EndpointType::IsochIn
+        | EndpointType::InterruptOut => None,
+    }
+}
+
+/// The pipe to an endpoint of the device, see [`Pipes`].
+#[derive(Debug)]
+enum HostPipe {
+    BulkIn(nusb::Endpoint<Bulk, In>),
+    BulkOut(nusb::Endpoint<Bulk, Out>),
+    InterruptIn(nusb::Endpoint<Interrupt, In>),
+}
+
+impl HostPipe {
+    /// Clear the halt of the endpoint of an idle pipe.
+    fn clear_halt(self, endpoint_id: Dci) {
+        let result = match self {
+            Self::BulkIn(mut endpoint) => endpoint.clear_halt().wait(),
+            Self::BulkOut(mut endpoint) => endpoint.clear_halt().wait(),
+            Self::InterruptIn(mut endpoint) => endpoint.clear_halt().wait(),
+        };
+        match result {
+            Ok(()) => debug!("cleared halt of disabled EP{}", endpoint_id),
+            Err(error) => warn!(
+                "clearing halt of disabled EP{} failed: {:?}",
+                endpoint_id, error
+            ),
+        }
+    }
+}
+
+/// The nusb endpoints that workers borrow as [`HostPipe`].
+trait Pipe: Sized + Send + 'static {
+    /// The endpoint of `pipe`, if it is of this type.
+    fn from_pipe(pipe: HostPipe) -> Option<Self>;
+
+    fn into_pipe(self) -> HostPipe;
+}
+
+impl Pipe for nusb::Endpoint<Bulk, In> {
+    fn from_pipe(pipe: HostPipe) -> Option<Self> {
+        match pipe {
+            HostPipe::BulkIn(endpoint) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    fn into_pipe(self) -> HostPipe {
+        HostPipe::BulkIn(self)
+    }
+}
+
+impl Pipe for nusb::Endpoint<Bulk, Out> {
+    fn from_pipe(pipe: HostPipe) -> Option<Self> {
+        match pipe {
+            HostPipe::BulkOut(endpoint) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    fn into_pipe(self) -> HostPipe {
+        HostPipe::BulkOut(self)
+    }
+}
+
+impl Pipe for nusb::Endpoint<Interrupt, In> {
+    fn from_pipe(pipe: HostPipe) -> Option<Self> {
+        match pipe {
+            HostPipe::InterruptIn(endpoint) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    fn into_pipe(self) -> HostPipe {
+        HostPipe::InterruptIn(self)
+    }
+}
+
+/// How the transfers of enabled endpoints are driven.
+#[derive(Debug, Clone)]
+pub enum WorkerModel {
+    /// Every endpoint is serviced by a dedicated worker thread that uses
+    /// nusb's blocking API. The threads are scheduled according to the
+    /// policy for their type of endpoint.
+    Threads(WorkerPolicy),
+    /// Every endpoint is serviced by a future that uses nusb's async API.
+    /// All futures are polled by the given executor.
+    Async(Arc<Executor>),
+}
+
+impl WorkerModel {
+    /// Start servicing an endpoint and return the means to control it.
+    ///
+    /// Only one of `worker` and `task` is used, depending on the worker
+    /// model. Both return the endpoint when they exit, which then goes
+    /// back with `pipe_return`.
+    fn start<E, W, T, F>(
+        &self,
+        endpoint: E,
+        pipe_return: PipeReturn<HostPipe>,
+        endpoint_type: EndpointType,
+        worker_info: EndpointWorkerInfo,
+        worker: W,
+        task: T,
+    ) -> EndpointHandle
+    where
+        E: Pipe,
+        W: FnOnce(E, EndpointWorkerInfo, Arc<EndpointRequests>, Receiver<()>) -> E + Send + 'static,
+        T: FnOnce(E, EndpointWorkerInfo, Arc<EndpointRequests>, Arc<Doorbell>) -> F,
+        F: Future<Output = E> + Send + 'static,
+    {
+        let requests = Arc::new(EndpointRequests::default());
+        let worker_requests = requests.clone();
+        let streams = worker_info.engine.streams();
+        let span = worker_info.span();
+        let wakeup = match self {
+            Self::Threads(policy) => {
+                let (sender, receiver) = mpsc::channel();
+                let name = worker_info
+                    .device
+                    .worker_thread_name(worker_info.endpoint_id);
+                let policy = policy.for_class(worker_class(endpoint_type));
+                spawn_thread(name.clone(), policy, move || {
+                    let _span = span.entered();
+                    let endpoint = worker(endpoint, worker_info, worker_requests, receiver);
+                    pipe_return.give_back(endpoint.into_pipe());
+                })
+                .unwrap_or_else(|_| panic!("Failed to launch endpoint worker thread {name}"));
+                EndpointWakeup::Thread(sender)
+            }
+            Self::Async(executor) => {
+                let doorbell = Arc::new(Doorbell::new());
+                let task = task(endpoint, worker_info, worker_requests, doorbell.clone());
+                executor.spawn(
+                    async move { pipe_return.give_back(task.await.into_pipe()) }.instrument(span),
+                );
+                EndpointWakeup::Async(doorbell)
+            }
+        };
+        EndpointHandle {
+            wakeup,
+            requests,
+            streams,
+        }
+    }
+}
+
+/// The controller's side of a running endpoint worker.
+#[derive(Debug)]
+struct EndpointHandle {
+    wakeup: EndpointWakeup,
+    requests: Arc<EndpointRequests>,
+    /// The streams of the endpoint, which the doorbell marks as pending.
+    streams: Option<Arc<StreamContextArray>>,
+}
+
+/// Requests of the controller that only the worker of an endpoint can
+/// serve, because it owns the nusb endpoint.
+#[derive(Debug, Default)]
+struct EndpointRequests {
+    clear_halt: ClearHaltRequest,
+    stop: StopRequest,
+}
+
+/// Asks the worker of an endpoint to clear the endpoint's halt condition.
+///
+/// Only the worker owns the nusb endpoint, so it serves the request before
+/// it fetches the next TRB. At that point, no transfer is pending on the
+/// endpoint, as nusb requires for clearing a halt.
+#[derive(Debug, Default)]
+struct ClearHaltRequest(AtomicBool);
+
+impl ClearHaltRequest {
+    fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a clear was requested since the last call.
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Asks the worker of an endpoint to stop on behalf of a Stop Endpoint
+/// Command.
+///
+/// The worker cancels its in-flight transfer, if any, reports the
+/// interrupted TRB as stopped and acknowledges once it has posted all its
+/// Transfer Events. The command waits for the acknowledgment, so these
+/// events precede its Command Completion Event. Afterwards, the worker
+/// sleeps until the driver rings the doorbell again, or exits if the
+/// endpoint was disabled.
+#[derive(Debug, Default)]
+struct StopRequest(Mutex<StopState>);
+
+#[derive(Debug, Default)]
+struct StopState {
+    /// Where to acknowledge the pending stop, if any.
+    acknowledgment: Option<Sender<()>>,
+    /// Whether the worker exits after the pending stop.
+    exit: bool,
+    /// The waker of the task that currently waits for a transfer.
+    waker: Option<Waker>,
+}
+
+impl StopRequest {
+    /// Request a stop and wake up the worker with `wake`. With `exit`, the
+    /// worker exits once it stopped.
+    ///
+    /// Returns the receiver of the worker's acknowledgment.
+    fn request(&self, exit: bool, wake: impl FnOnce()) -> Receiver<()> {
+        let (sender, receiver) = mpsc::channel();
+        let mut state = self.0.lock().unwrap();
+        state.acknowledgment = Some(sender);
+        state.exit |= exit;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        // Waking up the worker while holding the lock guarantees that the
+        // wakeup is pending by the time the worker acknowledges and
+        // discards it. Otherwise, it could restart the endpoint.
+        wake();
+        drop(state);
+        receiver
+    }
+
+    fn is_requested(&self) -> bool {
+        self.0.lock().unwrap().acknowledgment.is_some()
+    }
+
+    /// Like [`Self::is_requested`], but wakes up the task of `cx` once a
+    /// stop is requested.
+    fn poll_requested(&self, cx: &Context<'_>) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.acknowledgment.is_some() {
+            return true;
+        }
+        if !state
+            .waker
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            state.waker = Some(cx.waker().clone());
+        }
+        false
+    }
+
+    /// Acknowledge the pending stop, if any.
+    ///
+    /// `discard_wakeups` drops the doorbell rings that arrived before the
+    /// stop, so only a later ring restarts the endpoint.
+    ///
+    /// Returns whether the worker has to exit.
+    fn acknowledge(&self, discard_wakeups: impl FnOnce()) -> bool {
+        let mut state = self.0.lock().unwrap();
+        discard_wakeups();
+        if let Some(acknowledgment) = state.acknowledgment.take() {
+            // The controller may have given up waiting already. It has
+            // failed the command then, so there is nobody left to tell.
+            let _ = acknowledgment.send(());
+        }
+        state.exit
+    }
+}
+
+/// The means to wake up the worker of an endpoint when the driver rang the
+/// doorbell.
+#[derive(Debug)]
+enum EndpointWakeup {
+    Thread(Sender<()>),
+    Async(Arc<Doorbell>),
+}
+
+impl EndpointWakeup {
+    fn wake(&self) {
+        match self {
+            // Workers only exit after we dropped their handle, so sending
+            // should never fail. When the worker has panicked, it makes
+            // sense for us to panic as well.
+            Self::Thread(sender) => sender.send(()).unwrap(),
+            Self::Async(doorbell) => doorbell.ring(),
+        }
+    }
+}
+
 // cognitive complexity required because of the high cost of trace! messages
 #[allow(clippy::cognitive_complexity)]
 fn transfer_in_worker<EpType: BulkOrInterrupt>(
     mut endpoint: nusb::Endpoint<EpType, In>,
-    worker_info: EndpointWorkerInfo,
+    mut worker_info: EndpointWorkerInfo,
+    requests: Arc<EndpointRequests>,
     wakeup: Receiver<()>,
-) {
+) -> nusb::Endpoint<EpType, In> {
+    let mut pacer = IntervalPacer::new(worker_info.polling_interval);
+    let mut in_flight = InFlightTds::new(
+        worker_info.queue_depth,
+        transfer_unit_of(&endpoint, &worker_info),
+    );
     loop {
-        let trb = match worker_info.transfer_ring.next_transfer_trb() {
-            Some(trb) => trb,
-            None => {
+        // nusb only clears a halt without pending transfers.
+        if in_flight.is_empty() && requests.clear_halt.take() {
+            log_clear_halt(&worker_info, endpoint.clear_halt().wait());
+        }
+        if requests.stop.is_requested() {
+            while !in_flight.is_empty() {
+                let completion =
+                    wait_next_complete(&mut endpoint, &mut worker_info.engine, &requests.stop);
+                complete_in(
+                    &mut endpoint,
+                    &mut in_flight,
+                    &mut worker_info.engine,
+                    &completion,
+                );
+            }
+            if !stop_worker(&mut worker_info, &requests.stop, &wakeup) {
+                return endpoint;
+            }
+            worker_info.engine.refill();
+            continue;
+        }
+        if in_flight.is_empty() {
+            if let Some(delay) = pacer.delay(Instant::now()) {
+                // Sleep in slices to notice stop requests.
+                worker_info.engine.flush_events();
+                thread::sleep(delay.min(STOP_POLL_INTERVAL));
+                continue;
+            }
+            worker_info.engine.wait_for_event_space();
+            let Some(td) = worker_info.engine.next_td() else {
                 trace!(
                     "worker thread ep {}: No TRB on transfer ring, going to sleep",
                     worker_info.endpoint_id
                 );
+                pacer.ran_dry();
+                worker_info.engine.flush_events();
                 // We currently assume that the main thread always keeps the
                 // channel open, so unwrap is safe.
                 wakeup.recv().unwrap();
@@ -318,88 +973,39 @@ fn transfer_in_worker<EpType: BulkOrInterrupt>(
                     "worker thread ep {}: Received wake up",
                     worker_info.endpoint_id
                 );
+                worker_info.engine.refill();
                 continue;
-            }
-        };
-        assert!(
-            matches!(trb.variant, TransferTrbVariant::Normal(_)),
-            "Expected Normal TRB but got {:?}",
-            trb
-        );
-
-        // The assertion above guarantees that the TRB is a normal TRB. A wrong
-        // TRB type is the only reason the unwrap can fail.
-        let normal_data = extract_normal_trb_data(&trb).unwrap();
-        let transfer_length = normal_data.transfer_length as usize;
+            };
 
-        let buffer_size = determine_buffer_size(transfer_length, endpoint.max_packet_size());
-        let buffer = Buffer::new(buffer_size);
-        endpoint.submit(buffer);
-        // We do not want to time out on requests. We should probably use async
-        // because nusb supports either async requests or synchronous variants
-        // with timeouts. Manually implementing polling seems overkill here.
-        let buffer = endpoint.wait_next_complete(Duration::MAX).unwrap();
-        let byte_count_dma = match buffer.actual_len.cmp(&transfer_length) {
-            Greater => {
-                // Got more data than requested. We must not write more data than
-                // the guest driver requested with the transfer length, otherwise
-                // we might write out of the buffer.
-                //
-                // Why does this case happen? Sometimes the driver asks for, e.g.,
-                // 36 bytes. We have to request max_packet_size (e.g., 1024 bytes).
-                // The real device then provides 1024 bytes of data (looks like
-                // zero padding).
-                transfer_length
-            }
-            Less => {
-                // Got less data than requested. That case happens for example when
-                // the driver sends a Mode Sense(6) SCSI command. The response size
-                // is variable, so the driver asks for 192 bytes but is also fine
-                // with less.
-                //
-                // We copy all the data over that we got.
-                // TODO: currently, we just report success and 0 residual bytes,
-                // even though we probably should report something like short
-                // packet and the difference between requested and actual byte
-                // count. We get away with the simplified handling for now.
-                // The Mode Sense(6) response encodes the size of the response in
-                // the first byte, so the driver is not unhappy that we reported
-                // 192 bytes but only deliver, e.g., 36 bytes.
-                buffer.actual_len
-            }
-            Equal => {
-                // We got exactly the right amount of bytes.
-                transfer_length
-            }
-        };
-        worker_info
-            .dma_bus
-            .write_bulk(normal_data.data_pointer, &buffer.buffer[..byte_count_dma]);
-
-        if !normal_data.interrupt_on_completion {
-            trace!("Processed TRB without IOC flag; sending no transfer event");
-            continue;
+            // Only bulk transfers compete for the host bus; interrupt
+            // transfers are always admitted.
+            let permit =
+                (EpType::TYPE == TransferType::Bulk).then(|| worker_info.bulk_permits.acquire());
+            in_flight.push(td, permit, |size| endpoint.submit(Buffer::new(size)));
         }
+        // Queue further transfers behind the first, but do not wait for
+        // permits while holding one.
+        in_flight.top_up(
+            &mut worker_info.engine,
+            || match EpType::TYPE {
+                TransferType::Bulk => worker_info.bulk_permits.try_acquire().map(Some),
+                _ => Some(None),
+            },
+            |size| endpoint.submit(Buffer::new(size)),
+        );
 
-        let (completion_code, residual_bytes) = (CompletionCode::Success, 0);
-
-        let transfer_event = EventTrb::new_transfer_event_trb(
-            trb.address,
-            residual_bytes,
-            completion_code,
-            false,
-            worker_info.endpoint_id,
-            worker_info.slot_id,
+        let completion = wait_next_complete(&mut endpoint, &mut worker_info.engine, &requests.stop);
+        check_disconnected(&worker_info, &completion);
+        pacer.completed(
+            completion.status.is_ok() && completion.actual_len > 0,
+            Instant::now(),
+        );
+        complete_in(
+            &mut endpoint,
+            &mut in_flight,
+            &mut worker_info.engine,
+            &completion,
         );
-        // Mutex lock unwrap fails only if other threads panicked while holding
-        // the lock. In that case it is reasonable we also panic.
-        worker_info
-            .event_ring
-            .lock()
-            .unwrap()
-            .enqueue(&transfer_event);
-        worker_info.interrupt_line.interrupt();
-        debug!("sent Transfer Event and signaled interrupt");
     }
 }
 
@@ -407,86 +1013,556 @@ fn transfer_in_worker<EpType: BulkOrInterrupt>(
 #[allow(clippy::cognitive_complexity)]
 fn transfer_out_worker(
     mut endpoint: nusb::Endpoint<Bulk, Out>,
-    worker_info: EndpointWorkerInfo,
+    mut worker_info: EndpointWorkerInfo,
+    requests: Arc<EndpointRequests>,
     wakeup: Receiver<()>,
-) {
+) -> nusb::Endpoint<Bulk, Out> {
     loop {
-        let trb = match worker_info.transfer_ring.next_transfer_trb() {
-            Some(trb) => trb,
-            None => {
-                trace!(
-                    "worker thread ep {}: No TRB on transfer ring, going to sleep",
-                    worker_info.endpoint_id
+        if requests.clear_halt.take() {
+            log_clear_halt(&worker_info, endpoint.clear_halt().wait());
+        }
+        if requests.stop.is_requested() {
+            if !stop_worker(&mut worker_info, &requests.stop, &wakeup) {
+                return endpoint;
+            }
+            worker_info.engine.refill();
+            continue;
+        }
+        worker_info.engine.wait_for_event_space();
+        let Some(td) = worker_info.engine.next_td() else {
+            trace!(
+                "worker thread ep {}: No TRB on transfer ring, going to sleep",
+                worker_info.endpoint_id
+            );
+            worker_info.engine.flush_events();
+            // We currently assume that the main thread always keeps the
+            // channel open, so unwrap is safe.
+            wakeup.recv().unwrap();
+            trace!(
+                "worker thread ep {}: Received wake up",
+                worker_info.endpoint_id
+            );
+            worker_info.engine.refill();
+            continue;
+        };
+
+        let Some(data) = worker_info.engine.out_data(&td) else {
+            continue;
+        };
+        let permit = worker_info.bulk_permits.acquire();
+        let outcome = wait_send_out(&mut endpoint, &mut worker_info, &requests.stop, data);
+        drop(permit);
+
+        worker_info.engine.complete_td(td, outcome);
+    }
+}
+
+/// The async counterpart of [`transfer_in_worker`].
+///
+/// Our executor has no timers, so Interrupt IN endpoints are not paced.
+async fn transfer_in_task<EpType: BulkOrInterrupt>(
+    mut endpoint: nusb::Endpoint<EpType, In>,
+    mut worker_info: EndpointWorkerInfo,
+    requests: Arc<EndpointRequests>,
+    doorbell: Arc<Doorbell>,
+) -> nusb::Endpoint<EpType, In> {
+    let mut in_flight = InFlightTds::new(
+        worker_info.queue_depth,
+        transfer_unit_of(&endpoint, &worker_info),
+    );
+    loop {
+        if in_flight.is_empty() && requests.clear_halt.take() {
+            log_clear_halt(&worker_info, endpoint.clear_halt().await);
+        }
+        if requests.stop.is_requested() {
+            while !in_flight.is_empty() {
+                let completion =
+                    next_complete(&mut endpoint, &mut worker_info.engine, &requests.stop).await;
+                complete_in(
+                    &mut endpoint,
+                    &mut in_flight,
+                    &mut worker_info.engine,
+                    &completion,
                 );
-                // We currently assume that the main thread always keeps the
-                // channel open, so unwrap is safe.
-                wakeup.recv().unwrap();
+            }
+            if !stop_task(&mut worker_info, &requests.stop, &doorbell).await {
+                return endpoint;
+            }
+            worker_info.engine.refill();
+            continue;
+        }
+        if in_flight.is_empty() {
+            worker_info.engine.event_space().await;
+            let Some(td) = worker_info.engine.next_td() else {
                 trace!(
-                    "worker thread ep {}: Received wake up",
+                    "endpoint task ep {}: No TRB on transfer ring, waiting for doorbell",
                     worker_info.endpoint_id
                 );
+                worker_info.engine.flush_events();
+                doorbell.wait().await;
+                worker_info.engine.refill();
                 continue;
+            };
+
+            // Only bulk transfers compete for the host bus; interrupt
+            // transfers are always admitted.
+            let permit = match EpType::TYPE {
+                TransferType::Bulk => Some(worker_info.bulk_permits.acquire_async().await),
+                _ => None,
+            };
+            in_flight.push(td, permit, |size| endpoint.submit(Buffer::new(size)));
+        }
+        in_flight.top_up(
+            &mut worker_info.engine,
+            || match EpType::TYPE {
+                TransferType::Bulk => worker_info.bulk_permits.try_acquire().map(Some),
+                _ => Some(None),
+            },
+            |size| endpoint.submit(Buffer::new(size)),
+        );
+
+        let completion =
+            next_complete(&mut endpoint, &mut worker_info.engine, &requests.stop).await;
+        check_disconnected(&worker_info, &completion);
+        complete_in(
+            &mut endpoint,
+            &mut in_flight,
+            &mut worker_info.engine,
+            &completion,
+        );
+    }
+}
+
+/// The async counterpart of [`transfer_out_worker`].
+async fn transfer_out_task(
+    mut endpoint: nusb::Endpoint<Bulk, Out>,
+    mut worker_info: EndpointWorkerInfo,
+    requests: Arc<EndpointRequests>,
+    doorbell: Arc<Doorbell>,
+) -> nusb::Endpoint<Bulk, Out> {
+    loop {
+        if requests.clear_halt.take() {
+            log_clear_halt(&worker_info, endpoint.clear_halt().await);
+        }
+        if requests.stop.is_requested() {
+            if !stop_task(&mut worker_info, &requests.stop, &doorbell).await {
+                return endpoint;
             }
+            worker_info.engine.refill();
+            continue;
+        }
+        worker_info.engine.event_space().await;
+        let Some(td) = worker_info.engine.next_td() else {
+            trace!(
+                "endpoint task ep {}: No TRB on transfer ring, waiting for doorbell",
+                worker_info.endpoint_id
+            );
+            worker_info.engine.flush_events();
+            doorbell.wait().await;
+            worker_info.engine.refill();
+            continue;
         };
-        assert!(
-            matches!(trb.variant, TransferTrbVariant::Normal(_)),
-            "Expected Normal TRB but got {:?}",
-            trb
-        );
 
-        // The assertion above guarantees that the TRB is a normal TRB. A wrong
-        // TRB type is the only reason the unwrap can fail.
-        let normal_data = extract_normal_trb_data(&trb).unwrap();
+        let Some(data) = worker_info.engine.out_data(&td) else {
+            continue;
+        };
+        let permit = worker_info.bulk_permits.acquire_async().await;
+        let outcome = send_out(&mut endpoint, &mut worker_info, &requests.stop, data).await;
+        drop(permit);
 
-        let mut data = vec![0; normal_data.transfer_length as usize];
+        worker_info.engine.complete_td(td, outcome);
+    }
+}
+
+/// Report the outcome of clearing the halt condition of an endpoint.
+fn log_clear_halt(worker_info: &EndpointWorkerInfo, result: Result<(), nusb::Error>) {
+    match result {
+        Ok(()) => debug!("cleared halt of EP{}", worker_info.endpoint_id),
+        Err(error) => warn!(
+            "clearing halt of EP{} failed: {:?}",
+            worker_info.endpoint_id, error
+        ),
+    }
+}
+
+/// Ask the VMM to release the controller if `completion` shows that the
+/// device is gone from the host, which we cannot recover from.
+fn check_disconnected(worker_info: &EndpointWorkerInfo, completion: &Completion) {
+    if matches!(completion.status, Err(TransferError::Disconnected)) {
         worker_info
-            .dma_bus
-            .read_bulk(normal_data.data_pointer, &mut data);
-        if normal_data.transfer_length == 31 {
-            debug!("OUT data: {:?}", data);
+            .vmm_signals
+            .request_release(&format!("{} disappeared from the host", worker_info.device));
+    }
+}
+
+/// Serve a stop request and sleep until the driver rings the doorbell.
+///
+/// Returns `false` if the worker has to exit instead.
+fn stop_worker(
+    worker_info: &mut EndpointWorkerInfo,
+    stop: &StopRequest,
+    wakeup: &Receiver<()>,
+) -> bool {
+    worker_info.engine.flush_events();
+    if stop.acknowledge(|| while wakeup.try_recv().is_ok() {}) {
+        debug!("worker ep {}: Disabled", worker_info.endpoint_id);
+        return false;
+    }
+    debug!("worker ep {}: Stopped", worker_info.endpoint_id);
+    // We currently assume that the main thread always keeps the channel
+    // open, so unwrap is safe.
+    wakeup.recv().unwrap();
+    true
+}
+
+/// The async counterpart of [`stop_worker`].
+async fn stop_task(
+    worker_info: &mut EndpointWorkerInfo,
+    stop: &StopRequest,
+    doorbell: &Doorbell,
+) -> bool {
+    worker_info.engine.flush_events();
+    if stop.acknowledge(|| doorbell.clear()) {
+        debug!("endpoint task ep {}: Disabled", worker_info.endpoint_id);
+        return false;
+    }
+    debug!("endpoint task ep {}: Stopped", worker_info.endpoint_id);
+    doorbell.wait().await;
+    true
+}
+
+/// The transfer unit the driver configured for `endpoint`, but with the
+/// Max Packet Size of the device, which decides how it packs its data.
+fn transfer_unit_of<EpType: BulkOrInterrupt, Dir: EndpointDirection>(
+    endpoint: &nusb::Endpoint<EpType, Dir>,
+    worker_info: &EndpointWorkerInfo,
+) -> TransferUnit {
+    TransferUnit {
+        max_packet_size: u16::try_from(endpoint.max_packet_size()).unwrap_or(u16::MAX),
+        ..worker_info.transfer_unit
+    }
+}
+
+/// The buffers of the host submissions that send the data of an OUT TD.
+///
+/// Without data, usbfs sends a zero-length packet.
+fn out_buffers(data: Vec<u8>, unit: TransferUnit) -> Vec<Buffer> {
+    let lengths = split_td(data.len(), unit);
+    if lengths.len() == 1 {
+        return vec![data.into()];
+    }
+    let mut rest = data.as_slice();
+    lengths
+        .into_iter()
+        .map(|length| {
+            let (submission, tail) = rest.split_at(length);
+            rest = tail;
+            submission.to_vec().into()
+        })
+        .collect()
+}
+
+/// Send the data of an OUT TD and wait until the device took it.
+///
+/// Once a submission ends early, the ones behind it are cancelled, so the
+/// device never sees a gap in the data.
+fn wait_send_out(
+    endpoint: &mut nusb::Endpoint<Bulk, Out>,
+    worker_info: &mut EndpointWorkerInfo,
+    stop: &StopRequest,
+    data: Vec<u8>,
+) -> TdOutcome<'static> {
+    let buffers = out_buffers(data, transfer_unit_of(endpoint, worker_info));
+    let mut sent = OutProgress::new(buffers.len());
+    for buffer in buffers {
+        endpoint.submit(buffer);
+    }
+    while sent.pending > 0 {
+        let completion = wait_next_complete(endpoint, &mut worker_info.engine, stop);
+        check_disconnected(worker_info, &completion);
+        if sent.completed(&completion) {
+            endpoint.cancel_all();
         }
-        endpoint.submit(data.into());
-        // Timeout indicates device unresponsive - no reasonable recovery possible
-        endpoint.wait_next_complete(Duration::MAX).unwrap();
+    }
+    sent.outcome()
+}
 
-        if !normal_data.interrupt_on_completion {
-            trace!("Processed TRB without IOC flag; sending no transfer event");
-            continue;
+/// The async counterpart of [`wait_send_out`].
+async fn send_out(
+    endpoint: &mut nusb::Endpoint<Bulk, Out>,
+    worker_info: &mut EndpointWorkerInfo,
+    stop: &StopRequest,
+    data: Vec<u8>,
+) -> TdOutcome<'static> {
+    let buffers = out_buffers(data, transfer_unit_of(endpoint, worker_info));
+    let mut sent = OutProgress::new(buffers.len());
+    for buffer in buffers {
+        endpoint.submit(buffer);
+    }
+    while sent.pending > 0 {
+        let completion = next_complete(endpoint, &mut worker_info.engine, stop).await;
+        check_disconnected(worker_info, &completion);
+        if sent.completed(&completion) {
+            endpoint.cancel_all();
         }
+    }
+    sent.outcome()
+}
 
-        let (completion_code, residual_bytes) = (CompletionCode::Success, 0);
+/// The submissions of an OUT TD that completed so far.
+#[derive(Debug)]
+struct OutProgress {
+    /// The submissions still in flight.
+    pending: usize,
+    sent: usize,
+    stopped: bool,
+    /// Whether a submission ended early, so the rest was cancelled.
+    ended: bool,
+}
 
-        let transfer_event = EventTrb::new_transfer_event_trb(
-            trb.address,
-            residual_bytes,
-            completion_code,
-            false,
-            worker_info.endpoint_id,
-            worker_info.slot_id,
-        );
-        // Mutex lock unwrap fails only if other threads panicked while holding
-        // the lock. In that case it is reasonable we also panic.
-        worker_info
-            .event_ring
-            .lock()
-            .unwrap()
-            .enqueue(&transfer_event);
-        worker_info.interrupt_line.interrupt();
-        debug!("sent Transfer Event and signaled interrupt");
+impl OutProgress {
+    const fn new(submissions: usize) -> Self {
+        Self {
+            pending: submissions,
+            sent: 0,
+            stopped: false,
+            ended: false,
+        }
+    }
+
+    /// Account the oldest submission, which produced `completion`.
+    ///
+    /// Returns whether the submissions behind it have to be cancelled.
+    fn completed(&mut self, completion: &Completion) -> bool {
+        self.pending -= 1;
+        if self.ended {
+            return false;
+        }
+        let TdOutcome::Out { sent, stopped } = out_outcome(completion) else {
+            unreachable!();
+        };
+        self.sent += sent;
+        self.stopped = stopped;
+        self.ended = completion.status.is_err();
+        self.ended && self.pending > 0
+    }
+
+    const fn outcome(&self) -> TdOutcome<'static> {
+        TdOutcome::Out {
+            sent: self.sent,
+            stopped: self.stopped,
+        }
+    }
+}
+
+/// Account the oldest IN submission in flight, which produced
+/// `completion`.
+///
+/// If the transfer ended early, the transfers queued behind it are
+/// cancelled.
+fn complete_in<EpType: BulkOrInterrupt, P>(
+    endpoint: &mut nusb::Endpoint<EpType, In>,
+    in_flight: &mut InFlightTds<P>,
+    engine: &mut TdEngine,
+    completion: &Completion,
+) {
+    if in_flight.complete_oldest(engine, in_outcome(completion), completion.status.is_err()) {
+        endpoint.cancel_all();
+    }
+}
+
+/// The outcome of an IN transfer for the [`TdEngine`].
+fn in_outcome(completion: &Completion) -> TdOutcome<'_> {
+    TdOutcome::In {
+        data: &completion.buffer[..completion.actual_len],
+        stopped: is_stopped(completion),
+    }
+}
+
+/// The outcome of an OUT transfer for the [`TdEngine`].
+const fn out_outcome(completion: &Completion) -> TdOutcome<'_> {
+    TdOutcome::Out {
+        sent: completion.actual_len,
+        stopped: is_stopped(completion),
     }
 }
 
-const fn extract_normal_trb_data(trb: &TransferTrb) -> Option<&NormalTrbData> {
-    match &trb.variant {
-        TransferTrbVariant::Normal(data) => Some(data),
-        _ => None,
+/// Whether a transfer was cancelled because the endpoint was stopped.
+///
+/// Workers only cancel transfers to serve a stop request. A transfer that
+/// completed before the cancellation took effect is not stopped.
+const fn is_stopped(completion: &Completion) -> bool {
+    matches!(completion.status, Err(TransferError::Cancelled))
+}
+
+/// How often a worker thread checks for a stop request while it waits for
+/// a transfer.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Block until the next transfer on `endpoint` completes.
+///
+/// Pending Transfer Events are flushed when their coalescing window expires
+/// while we wait. When a stop is requested, the transfer is cancelled, so
+/// the completion might be partial.
+fn wait_next_complete<EpType: BulkOrInterrupt, Dir: EndpointDirection>(
+    endpoint: &mut nusb::Endpoint<EpType, Dir>,
+    engine: &mut TdEngine,
+    stop: &StopRequest,
+) -> Completion {
+    // We do not want to time out on requests. A timeout would indicate an
+    // unresponsive device, from which there is no reasonable recovery. We
+    // only wake up periodically to look for stop requests.
+    loop {
+        let deadline = engine.event_deadline();
+        let timeout = deadline.map_or(STOP_POLL_INTERVAL, |deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .min(STOP_POLL_INTERVAL)
+        });
+        if let Some(completion) = endpoint.wait_next_complete(timeout) {
+            return completion;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            engine.flush_events();
+        }
+        if stop.is_requested() {
+            endpoint.cancel_all();
+            // nusb completes cancelled transfers as well.
+            return endpoint.wait_next_complete(Duration::MAX).unwrap();
+        }
     }
 }
 
-const fn determine_buffer_size(guest_transfer_length: usize, max_packet_size: usize) -> usize {
-    if guest_transfer_length <= max_packet_size {
-        max_packet_size
-    } else {
-        guest_transfer_length.div_ceil(max_packet_size) * max_packet_size
+/// The async counterpart of [`wait_next_complete`].
+///
+/// Our executor has no timers, so pending Transfer Events are flushed as
+/// soon as the task would have to wait for the device.
+async fn next_complete<EpType: BulkOrInterrupt, Dir: EndpointDirection>(
+    endpoint: &mut nusb::Endpoint<EpType, Dir>,
+    engine: &mut TdEngine,
+    stop: &StopRequest,
+) -> Completion {
+    let mut cancelled = false;
+    std::future::poll_fn(|cx| {
+        if !cancelled && stop.poll_requested(cx) {
+            endpoint.cancel_all();
+            cancelled = true;
+        }
+        let poll = endpoint.poll_next_complete(cx);
+        if poll.is_pending() {
+            engine.flush_events();
+        }
+        poll
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identification_comes_from_the_device_descriptor() {
+        let descriptor = DeviceDescriptor::new(&[
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01,
+            0x01, 0x02, 0x03, 0x01,
+        ])
+        .unwrap();
+        let identity = DeviceIdentity::new(
+            HostLocation {
+                bus_number: 1,
+                device_address: 4,
+            },
+            identification_of(&descriptor, Some("0042".to_string())),
+        );
+
+        assert_eq!(
+            identity.identification,
+            DeviceIdentification {
+                vendor_id: 0x1234,
+                product_id: 0x5678,
+                serial: Some("0042".to_string()),
+            }
+        );
+        assert_eq!(
+            identity.to_string(),
+            "bus 001 device 004 (1234:5678 serial 0042)"
+        );
+        assert_eq!(
+            identity.worker_thread_name(Dci::new(2).unwrap()),
+            "001-004 ep2"
+        );
+    }
+
+    #[test]
+    fn all_interfaces_are_claimed() {
+        let mut claimed = vec![];
+        let interfaces = claim_interfaces([0, 1, 2], |interface_number| {
+            claimed.push(interface_number);
+            Ok::<_, ()>(interface_number * 10)
+        });
+
+        assert_eq!(interfaces, Ok(vec![0, 10, 20]));
+        assert_eq!(claimed, [0, 1, 2]);
+    }
+
+    #[test]
+    fn claiming_stops_at_busy_interface() {
+        let mut claimed = vec![];
+        let interfaces = claim_interfaces([0, 1, 2], |interface_number| {
+            claimed.push(interface_number);
+            match interface_number {
+                1 => Err("busy"),
+                _ => Ok(interface_number),
+            }
+        });
+
+        assert_eq!(interfaces, Err((1, "busy")));
+        assert_eq!(claimed, [0, 1]);
+    }
+
+    #[test]
+    fn valid_request_types_are_extracted() {
+        // Device-to-host bit set: GET_DESCRIPTOR.
+        assert_eq!(
+            NusbDeviceWrapper::extract_recipient_and_type(0x80),
+            Ok((Recipient::Device, ControlType::Standard))
+        );
+        // Class request to an interface, e.g., HID SET_IDLE.
+        assert_eq!(
+            NusbDeviceWrapper::extract_recipient_and_type(0x21),
+            Ok((Recipient::Interface, ControlType::Class))
+        );
+        // Class request to a hub port.
+        assert_eq!(
+            NusbDeviceWrapper::extract_recipient_and_type(0xa3),
+            Ok((Recipient::Other, ControlType::Class))
+        );
+        assert_eq!(
+            NusbDeviceWrapper::extract_recipient_and_type(0x42),
+            Ok((Recipient::Endpoint, ControlType::Vendor))
+        );
+    }
+
+    #[test]
+    fn invalid_recipients_are_rejected() {
+        for recipient in 4..=0x1f {
+            assert_eq!(
+                NusbDeviceWrapper::extract_recipient_and_type(0x80 | recipient),
+                Err(InvalidRequestType::Recipient(recipient))
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_type_is_rejected() {
+        assert_eq!(
+            NusbDeviceWrapper::extract_recipient_and_type(0x60),
+            Err(InvalidRequestType::Type(3))
+        );
+        assert_eq!(
+            NusbDeviceWrapper::extract_recipient_and_type(0xe1),
+            Err(InvalidRequestType::Type(3))
+        );
     }
 }
@@ -0,0 +1,255 @@
+//! # DMA Validation
+//!
+//! Bugs in the code that accesses guest memory, e.g., an off-by-one in a
+//! context offset or a write past a TD's buffer, silently corrupt the
+//! guest. To catch them where they happen, every component accesses guest
+//! memory through a view of the DMA bus that is tagged with its
+//! [`DmaOrigin`], see [`tag`].
+//!
+//! With validation enabled, the view checks each access against the
+//! largest one its origin can legitimately make. Guest-controlled sizes
+//! are bounded by the fields that carry them, so a violation is always a
+//! bug in usbvfiod. Violations are logged and abort the process.
+//!
+//! Validation is always enabled in debug builds. Release builds enable it
+//! with `--paranoid-dma`. Otherwise, [`tag`] returns the DMA bus as is and
+//! validation costs nothing.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tracing::error;
+
+use crate::device::bus::{BusDevice, BusDeviceRef, Request};
+
+use super::constants::xhci::rings::TRB_SIZE;
+
+/// Whether DMA validation was requested at runtime.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable DMA validation for all DMA bus views tagged from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`tag`] returns validating views.
+pub fn is_enabled() -> bool {
+    cfg!(debug_assertions) || ENABLED.load(Ordering::Relaxed)
+}
+
+/// The component on whose behalf guest memory is accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaOrigin {
+    /// TRBs fetched from the Command Ring.
+    CommandRing,
+    /// TRBs posted to the Event Ring and its Event Ring Segment Table.
+    EventRing,
+    /// TRBs fetched from transfer rings.
+    TransferRing,
+    /// The Device Context Base Address Array, device contexts, input
+    /// contexts, and Stream Context Arrays.
+    DeviceContext,
+    /// The data buffers of the TDs that endpoint workers process.
+    TdData,
+    /// The Data Stage buffers of control transfers.
+    ControlData,
+}
+
+impl DmaOrigin {
+    /// The name of the origin in violation reports.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::CommandRing => "command_ring",
+            Self::EventRing => "event_ring",
+            Self::TransferRing => "transfer_ring",
+            Self::DeviceContext => "device_context",
+            Self::TdData => "ep_worker.td_data",
+            Self::ControlData => "control_transfer.data",
+        }
+    }
+
+    /// The largest access in bytes this origin can legitimately make.
+    pub const fn max_access(self) -> u64 {
+        match self {
+            // A single TRB or Event Ring Segment Table entry.
+            Self::CommandRing | Self::EventRing => TRB_SIZE as u64,
+            // The TRBs of a control transfer are read at once.
+            Self::TransferRing => 3 * TRB_SIZE as u64,
+            // An input context with 32-byte contexts: the Input Control
+            // Context, the Slot Context, and 31 Endpoint Contexts.
+            Self::DeviceContext => 33 * 32,
+            // The TRB Transfer Length field has 17 bits.
+            Self::TdData => 0x1_ffff,
+            // The wLength field of the Setup Stage.
+            Self::ControlData => u16::MAX as u64,
+        }
+    }
+}
+
+impl Display for DmaOrigin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Give `origin` its view of `dma_bus`.
+///
+/// The view validates the accesses of `origin` if validation is enabled.
+/// Otherwise, this is `dma_bus` itself.
+pub fn tag(dma_bus: &BusDeviceRef, origin: DmaOrigin) -> BusDeviceRef {
+    if is_enabled() {
+        Arc::new(ParanoidDma {
+            inner: dma_bus.clone(),
+            origin,
+        })
+    } else {
+        dma_bus.clone()
+    }
+}
+
+/// An access that its origin can never legitimately make.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmaViolation {
+    pub origin: DmaOrigin,
+    pub range: Range<u64>,
+}
+
+impl Display for DmaViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} accessed {:#x}..{:#x} ({} bytes), but never accesses more than {} bytes at once",
+            self.origin,
+            self.range.start,
+            self.range.end,
+            self.range.end.wrapping_sub(self.range.start),
+            self.origin.max_access()
+        )
+    }
+}
+
+/// A view of the DMA bus that validates the accesses of one origin.
+#[derive(Debug)]
+pub struct ParanoidDma {
+    inner: BusDeviceRef,
+    origin: DmaOrigin,
+}
+
+impl ParanoidDma {
+    /// Check an access of `len` bytes at `addr`.
+    pub const fn check(&self, addr: u64, len: usize) -> Result<(), DmaViolation> {
+        let len = len as u64;
+        if len <= self.origin.max_access() {
+            return Ok(());
+        }
+        Err(DmaViolation {
+            origin: self.origin,
+            range: addr..addr.wrapping_add(len),
+        })
+    }
+
+    /// Abort on an access that violates the limits of the origin.
+    fn validate(&self, addr: u64, len: usize) {
+        if let Err(violation) = self.check(addr, len) {
+            error!("DMA violation: {}", violation);
+            panic!("DMA violation: {violation}");
+        }
+    }
+}
+
+impl BusDevice for ParanoidDma {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read(&self, req: Request) -> u64 {
+        self.validate(req.addr, u64::from(req.size) as usize);
+        self.inner.read(req)
+    }
+
+    fn write(&self, req: Request, value: u64) {
+        self.validate(req.addr, u64::from(req.size) as usize);
+        self.inner.write(req, value)
+    }
+
+    fn read_bulk(&self, offset: u64, data: &mut [u8]) {
+        self.validate(offset, data.len());
+        self.inner.read_bulk(offset, data)
+    }
+
+    #[cfg(any(feature = "nusb-backend", test))]
+    fn try_read_bulk(&self, offset: u64, data: &mut [u8]) -> usize {
+        self.validate(offset, data.len());
+        self.inner.try_read_bulk(offset, data)
+    }
+
+    fn write_bulk(&self, offset: u64, data: &[u8]) {
+        self.validate(offset, data.len());
+        self.inner.write_bulk(offset, data)
+    }
+
+    fn try_write_bulk(&self, offset: u64, data: &[u8]) -> usize {
+        self.validate(offset, data.len());
+        self.inner.try_write_bulk(offset, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::bus::{testutils::TestBusDevice, RequestSize};
+
+    use super::*;
+
+    fn view(origin: DmaOrigin) -> ParanoidDma {
+        ParanoidDma {
+            inner: Arc::new(TestBusDevice::new(&[0; 0x1000])),
+            origin,
+        }
+    }
+
+    #[test]
+    fn accesses_within_the_limit_pass() {
+        let event_ring = view(DmaOrigin::EventRing);
+        assert_eq!(event_ring.check(0x100, 16), Ok(()));
+
+        let device_context = view(DmaOrigin::DeviceContext);
+        assert_eq!(device_context.check(0x200, 1056), Ok(()));
+    }
+
+    #[test]
+    fn oversized_accesses_are_violations() {
+        let event_ring = view(DmaOrigin::EventRing);
+        assert_eq!(
+            event_ring.check(0x100, 32),
+            Err(DmaViolation {
+                origin: DmaOrigin::EventRing,
+                range: 0x100..0x120,
+            })
+        );
+
+        let device_context = view(DmaOrigin::DeviceContext);
+        assert!(device_context.check(0x200, 1057).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "DMA violation: command_ring accessed 0x0..0x20")]
+    fn violations_abort() {
+        view(DmaOrigin::CommandRing).read_bulk(0, &mut [0; 32]);
+    }
+
+    #[test]
+    fn valid_accesses_reach_guest_memory() {
+        let transfer_ring = view(DmaOrigin::TransferRing);
+        transfer_ring.write(Request::new(0x10, RequestSize::Size4), 0xdead_beef);
+
+        let mut data = [0; 4];
+        transfer_ring.read_bulk(0x10, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xdead_beef);
+    }
+}
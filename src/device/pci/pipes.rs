@@ -0,0 +1,185 @@
+//! # Host Pipes of Endpoints
+//!
+//! The host controller keeps the data toggle (USB 2) or sequence number
+//! (USB 3) of an endpoint with the pipe we opened to it. The device keeps
+//! its own copy, which only a reset of the endpoint or the device clears.
+//! If we closed the pipe whenever an endpoint worker exits, e.g., because
+//! the driver reconfigures the endpoint, the reopened pipe would start over
+//! while the device does not, and the device silently drops the next packet.
+//!
+//! [`Pipes`] therefore keeps the pipe of an endpoint across workers: a
+//! worker borrows it when it starts and gives it back when it exits. Only
+//! clearing the halt of the endpoint or resetting the device discards the
+//! pipe, as both reset the toggle on the device as well.
+
+use std::{
+    collections::BTreeMap,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    time::Duration,
+};
+
+use tracing::debug;
+
+use super::dci::Dci;
+
+/// The pipes of the endpoints of a device, lent to their workers.
+#[derive(Debug)]
+pub struct Pipes<P> {
+    /// The pipes of endpoints without a worker.
+    idle: BTreeMap<Dci, P>,
+    /// Where the workers of the other endpoints give their pipe back.
+    lent: BTreeMap<Dci, Receiver<P>>,
+}
+
+impl<P> Default for Pipes<P> {
+    fn default() -> Self {
+        Self {
+            idle: BTreeMap::new(),
+            lent: BTreeMap::new(),
+        }
+    }
+}
+
+impl<P> Pipes<P> {
+    /// Lend the pipe of an endpoint to a new worker.
+    ///
+    /// Returns the pipe, if the endpoint still has one, and the means for
+    /// the worker to give back the pipe it ends up using. A pipe that is
+    /// still lent to an earlier worker is forgotten, as that worker did not
+    /// exit in time.
+    pub fn lend(&mut self, endpoint_id: Dci) -> (Option<P>, PipeReturn<P>) {
+        self.try_collect(endpoint_id);
+        if self.lent.remove(&endpoint_id).is_some() {
+            debug!("pipe of EP{} was not given back in time", endpoint_id);
+        }
+        let pipe = self.idle.remove(&endpoint_id);
+        let (sender, receiver) = mpsc::channel();
+        self.lent.insert(endpoint_id, receiver);
+        (pipe, PipeReturn(sender))
+    }
+
+    /// Wait up to `timeout` for the worker of an endpoint to give back its
+    /// pipe.
+    ///
+    /// Returns whether the endpoint has an idle pipe afterwards.
+    pub fn collect(&mut self, endpoint_id: Dci, timeout: Duration) -> bool {
+        if let Some(receiver) = self.lent.get(&endpoint_id) {
+            match receiver.recv_timeout(timeout) {
+                Ok(pipe) => {
+                    self.lent.remove(&endpoint_id);
+                    self.idle.insert(endpoint_id, pipe);
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                // The worker dropped the pipe, e.g., because it panicked.
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.lent.remove(&endpoint_id);
+                }
+            }
+        }
+        self.idle.contains_key(&endpoint_id)
+    }
+
+    /// Like [`Self::collect`], but does not wait.
+    fn try_collect(&mut self, endpoint_id: Dci) {
+        if let Some(receiver) = self.lent.get(&endpoint_id) {
+            match receiver.try_recv() {
+                Ok(pipe) => {
+                    self.lent.remove(&endpoint_id);
+                    self.idle.insert(endpoint_id, pipe);
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => {
+                    self.lent.remove(&endpoint_id);
+                }
+            }
+        }
+    }
+
+    /// Forget the pipe of an endpoint, so that its next worker opens a new
+    /// one.
+    ///
+    /// A running worker keeps using its pipe, but drops it when it exits.
+    /// Returns the pipe if it was idle, for a last use by the caller.
+    pub fn discard(&mut self, endpoint_id: Dci) -> Option<P> {
+        self.try_collect(endpoint_id);
+        self.lent.remove(&endpoint_id);
+        self.idle.remove(&endpoint_id)
+    }
+
+    /// Forget the pipes of all endpoints.
+    pub fn discard_all(&mut self) {
+        self.idle.clear();
+        self.lent.clear();
+    }
+}
+
+/// The means for a worker to give back the pipe it borrowed from
+/// [`Pipes`].
+#[derive(Debug)]
+pub struct PipeReturn<P>(Sender<P>);
+
+impl<P> PipeReturn<P> {
+    /// Give back the pipe. It is dropped if it was discarded meanwhile.
+    pub fn give_back(self, pipe: P) {
+        // The pipes may have forgotten the loan, in which case the pipe
+        // goes with the send error.
+        let _ = self.0.send(pipe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const EP: Dci = Dci::new(3).unwrap();
+
+    #[test]
+    fn pipes_outlive_their_workers() {
+        let mut pipes = Pipes::default();
+
+        let (pipe, pipe_return) = pipes.lend(EP);
+        assert_eq!(pipe, None);
+        let worker = thread::spawn(move || pipe_return.give_back(1));
+        worker.join().unwrap();
+        assert!(pipes.collect(EP, Duration::ZERO));
+
+        let (pipe, pipe_return) = pipes.lend(EP);
+        assert_eq!(pipe, Some(1));
+        pipe_return.give_back(1);
+        // The next worker finds the pipe even without a collect.
+        assert_eq!(pipes.lend(EP).0, Some(1));
+    }
+
+    #[test]
+    fn discarded_pipes_are_dropped() {
+        let mut pipes = Pipes::default();
+
+        let (_, pipe_return) = pipes.lend(EP);
+        pipe_return.give_back(1);
+        assert!(pipes.collect(EP, Duration::ZERO));
+        assert_eq!(pipes.discard(EP), Some(1));
+        assert_eq!(pipes.lend(EP).0, None);
+
+        // A worker that still runs during the discard gives back its pipe
+        // to nobody.
+        let (_, pipe_return) = pipes.lend(EP);
+        pipes.discard_all();
+        pipe_return.give_back(2);
+        assert!(!pipes.collect(EP, Duration::ZERO));
+        assert_eq!(pipes.lend(EP).0, None);
+    }
+
+    #[test]
+    fn pipes_of_workers_that_do_not_exit_are_forgotten() {
+        let mut pipes = Pipes::<u32>::default();
+
+        let (_, stuck) = pipes.lend(EP);
+        assert!(!pipes.collect(EP, Duration::from_millis(1)));
+        let (pipe, _) = pipes.lend(EP);
+        assert_eq!(pipe, None);
+        stuck.give_back(1);
+        assert!(!pipes.collect(EP, Duration::ZERO));
+    }
+}
@@ -1,14 +1,30 @@
-use crate::device::{bus::BusDeviceRef, interrupt_line::InterruptLine};
+use crate::device::bus::BusDeviceRef;
 
 use super::{
-    rings::{EventRing, TransferRing},
+    dci::Dci,
+    descriptors::{ConfigurationDescriptor, DeviceDescriptor},
+    td_engine::TdEngine,
+    trb::CompletionCode,
     usbrequest::UsbRequest,
 };
+#[cfg(feature = "nusb-backend")]
+use super::{scheduler::BulkPermits, transfer_unit::TransferUnit, vmm_signals::VmmSignals};
+#[cfg(feature = "nusb-backend")]
+use std::num::NonZeroUsize;
 use std::{
     fmt::{self, Debug},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use tracing::{info_span, Span};
+
+// Without the backend, only virtual devices construct speeds, and they run
+// at Full or High Speed.
+#[cfg_attr(not(feature = "nusb-backend"), allow(dead_code))]
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Speed {
@@ -23,6 +39,24 @@ impl Speed {
     pub const fn is_usb2_speed(self) -> bool {
         self as u8 <= 3
     }
+
+    /// The Protocol Speed ID (PSIV) of the speed, as reported in PORTSC.
+    pub const fn raw(self) -> u8 {
+        self as u8
+    }
+
+    /// The max packet size of the Default Control Endpoint at this speed.
+    ///
+    /// Full-speed devices may use 8, 16, or 32 bytes as well. Drivers find
+    /// out by reading the device descriptor and then evaluate the control
+    /// endpoint's context, so 64 bytes is only the initial guess.
+    pub const fn max_packet_size_ep0(self) -> u16 {
+        match self {
+            Self::Low => 8,
+            Self::Full | Self::High => 64,
+            Self::Super | Self::SuperPlus => 512,
+        }
+    }
 }
 
 impl fmt::Display for Speed {
@@ -38,35 +72,692 @@ impl fmt::Display for Speed {
     }
 }
 
+/// What a device identifies as in its device descriptor.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeviceIdentification {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The serial number string, if the device has one.
+    pub serial: Option<String>,
+}
+
+/// Where a device is on the host, as in its path `/dev/bus/usb/BBB/DDD`.
+///
+/// All devices on a host bus share the bus number, the device address
+/// tells them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostLocation {
+    pub bus_number: u8,
+    pub device_address: u8,
+}
+
+impl fmt::Display for HostLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bus {:03} device {:03}",
+            self.bus_number, self.device_address
+        )
+    }
+}
+
+/// How we take the interfaces of a device from the host.
+#[cfg(any(feature = "nusb-backend", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterfaceClaim {
+    /// Detach the host's kernel driver from each interface before claiming
+    /// it.
+    Detach,
+    /// Claim each interface as it is, failing if a driver is bound to it.
+    NoDetach,
+}
+
+/// Who a device is, on the host and in the guest.
+///
+/// The identity is created when the device is wrapped for attaching and
+/// shared with the endpoint workers, so that their threads and log
+/// messages tell which physical device they belong to. The guest port and
+/// slot are filled in as the controller assigns them.
+#[derive(Debug)]
+pub struct DeviceIdentity {
+    /// Where the device is on the host.
+    pub location: HostLocation,
+    /// The IDs and serial number of the device.
+    pub identification: DeviceIdentification,
+    /// The Port ID of the guest port, 0 while the device is not attached.
+    port: AtomicU8,
+    /// The guest slot of the device, 0 while it has none.
+    slot: AtomicU8,
+}
+
+impl DeviceIdentity {
+    pub const fn new(location: HostLocation, identification: DeviceIdentification) -> Self {
+        Self {
+            location,
+            identification,
+            port: AtomicU8::new(0),
+            slot: AtomicU8::new(0),
+        }
+    }
+
+    /// The Port ID of the guest port the device is attached to.
+    pub fn port(&self) -> Option<u8> {
+        Some(self.port.load(Ordering::Relaxed)).filter(|&port| port != 0)
+    }
+
+    pub fn set_port(&self, port: Option<u8>) {
+        self.port.store(port.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// The guest slot the driver addressed the device in.
+    pub fn slot(&self) -> Option<u8> {
+        Some(self.slot.load(Ordering::Relaxed)).filter(|&slot| slot != 0)
+    }
+
+    pub fn set_slot(&self, slot: Option<u8>) {
+        self.slot.store(slot.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// The span the controller logs in while it acts on the device.
+    pub fn span(&self) -> Span {
+        info_span!("device", device = %self)
+    }
+
+    /// The name of the thread that services `endpoint_id`.
+    ///
+    /// Linux truncates thread names to 15 bytes, so the name only has the
+    /// host location, which is unique, and the Endpoint ID.
+    pub fn worker_thread_name(&self, endpoint_id: Dci) -> String {
+        format!(
+            "{:03}-{:03} ep{}",
+            self.location.bus_number, self.location.device_address, endpoint_id
+        )
+    }
+}
+
+impl fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:04x}:{:04x}",
+            self.location, self.identification.vendor_id, self.identification.product_id
+        )?;
+        if let Some(serial) = &self.identification.serial {
+            write!(f, " serial {serial}")?;
+        }
+        if let Some(port) = self.port() {
+            write!(f, ", port {port}")?;
+        }
+        if let Some(slot) = self.slot() {
+            write!(f, ", slot {slot}")?;
+        }
+        write!(f, ")")
+    }
+}
+
 pub trait RealDevice: Debug {
     fn speed(&self) -> Option<Speed>;
-    fn control_transfer(&self, request: &UsbRequest, dma_bus: &BusDeviceRef);
+    /// Who the device is on the host and in the guest.
+    fn identity(&self) -> &Arc<DeviceIdentity>;
+    /// The device descriptor the device reports.
+    fn device_descriptor(&self) -> DeviceDescriptor;
+    /// The configuration descriptors the device reports, with their
+    /// interfaces and endpoints.
+    ///
+    /// Configurations whose descriptors cannot be parsed are missing.
+    fn configuration_descriptors(&self) -> Vec<ConfigurationDescriptor>;
+    /// Forward a request on the Default Control Endpoint to the device.
+    ///
+    /// Returns the completion code for the Transfer Event of the request.
+    fn control_transfer(&self, request: &UsbRequest, dma_bus: &BusDeviceRef) -> CompletionCode;
     fn enable_endpoint(&mut self, worker_info: EndpointWorkerInfo, endpoint_type: EndpointType);
-    fn transfer(&mut self, endpoint_id: u8);
+    /// Notify the worker of an endpoint about new TRBs.
+    ///
+    /// `stream_id` is the stream the driver rang the doorbell for. It is 0
+    /// for endpoints without streams.
+    fn transfer(&mut self, endpoint_id: Dci, stream_id: u16);
+    /// Reset the device on behalf of a Reset Device Command.
+    ///
+    /// Afterwards, no endpoint of the device is halted.
+    fn reset(&mut self);
+    /// Clear the halt condition of an endpoint on behalf of a Reset
+    /// Endpoint Command or a CLEAR_FEATURE(ENDPOINT_HALT) request.
+    ///
+    /// This also resets the data toggle of the endpoint on the host.
+    fn clear_halt(&mut self, endpoint_id: Dci);
+    /// Stop an endpoint on behalf of a Stop Endpoint Command.
+    ///
+    /// Blocks until the endpoint's worker has quiesced and posted the
+    /// Transfer Event for the transfer it interrupted, so the event precedes
+    /// the Command Completion Event. Returns `false` if the worker did not
+    /// acknowledge the stop within `timeout`.
+    fn stop_endpoint(&mut self, endpoint_id: Dci, timeout: Duration) -> bool;
+    /// Shut down the worker of an endpoint that the driver dropped with a
+    /// Configure Endpoint Command.
+    ///
+    /// Like [`stop_endpoint`](Self::stop_endpoint), but the worker exits
+    /// afterwards, and doorbells for the endpoint are ignored until it is
+    /// enabled again. Returns `false` if the worker did not acknowledge
+    /// within `timeout`.
+    fn disable_endpoint(&mut self, endpoint_id: Dci, timeout: Duration) -> bool;
+    /// Give the device back to the host when the controller goes away.
+    ///
+    /// All endpoint workers are shut down, waiting up to `timeout` for
+    /// each, and the host's drivers may take the device again.
+    fn release(&mut self, timeout: Duration);
 }
 
+/// The transfer type and direction of an endpoint, as the EP Type field
+/// of its endpoint context encodes them (xHCI Table 6-9).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EndpointType {
+    IsochOut,
+    BulkOut,
+    InterruptOut,
+    /// The bidirectional control endpoint.
     Control,
+    IsochIn,
     BulkIn,
-    BulkOut,
     InterruptIn,
 }
 
+impl EndpointType {
+    /// Decode the EP Type field of an endpoint context.
+    ///
+    /// Returns `None` for the invalid type 0. Only the three low bits of
+    /// `ep_type` are considered.
+    pub const fn from_context_value(ep_type: u8) -> Option<Self> {
+        match ep_type & 0x7 {
+            1 => Some(Self::IsochOut),
+            2 => Some(Self::BulkOut),
+            3 => Some(Self::InterruptOut),
+            4 => Some(Self::Control),
+            5 => Some(Self::IsochIn),
+            6 => Some(Self::BulkIn),
+            7 => Some(Self::InterruptIn),
+            _ => None,
+        }
+    }
+
+    /// Whether we can run endpoints of this type.
+    ///
+    /// Isochronous and interrupt OUT endpoints have no workers yet.
+    pub const fn is_supported(self) -> bool {
+        matches!(
+            self,
+            Self::Control | Self::BulkOut | Self::BulkIn | Self::InterruptIn
+        )
+    }
+}
+
 /// This struct provides all required information to a worker thread to handle
 /// TRBs on an endpoint.
 #[derive(Debug)]
 pub struct EndpointWorkerInfo {
     /// The slot ID of the device.
+    #[cfg(feature = "nusb-backend")]
     pub slot_id: u8,
     /// The endpoint the worker should service.
-    pub endpoint_id: u8,
-    /// Transfer ring of the endpoint to retrieve TRBs.
-    pub transfer_ring: TransferRing,
-    /// Bus reference for DMAing the data the TRBs reference.
-    pub dma_bus: BusDeviceRef,
-    /// Event ring to enqueue transfer events.
-    pub event_ring: Arc<Mutex<EventRing>>,
-    /// Interrupt line to notify about enqueued transfer events.
-    pub interrupt_line: Arc<dyn InterruptLine>,
+    pub endpoint_id: Dci,
+    /// Processes the TDs on the transfer ring(s) of the endpoint.
+    pub engine: TdEngine,
+    /// Permits for outstanding bulk transfers on the device's host bus.
+    ///
+    /// Bulk workers hold a permit while a transfer is in flight; interrupt
+    /// workers do not need one.
+    #[cfg(feature = "nusb-backend")]
+    pub bulk_permits: Arc<BulkPermits>,
+    /// The packet and burst sizes the driver configured in the endpoint
+    /// context.
+    #[cfg(feature = "nusb-backend")]
+    pub transfer_unit: TransferUnit,
+    /// The service interval to pace the transfers of an Interrupt IN
+    /// endpoint to. `None` for other endpoints, or if the endpoint may be
+    /// polled as fast as the device completes transfers.
+    pub polling_interval: Option<Duration>,
+    /// The number of transfers the worker keeps in flight on the endpoint.
+    #[cfg(feature = "nusb-backend")]
+    pub queue_depth: NonZeroUsize,
+    /// Asks the VMM to release the controller when the device disappears
+    /// from the host.
+    #[cfg(feature = "nusb-backend")]
+    pub vmm_signals: Arc<VmmSignals>,
+    /// The device the endpoint belongs to.
+    pub device: Arc<DeviceIdentity>,
+}
+
+impl EndpointWorkerInfo {
+    /// The span the worker of the endpoint logs in.
+    pub fn span(&self) -> Span {
+        info_span!("endpoint", device = %self.device, endpoint = self.endpoint_id.get())
+    }
+}
+
+#[cfg(test)]
+pub mod testutils {
+    use std::{
+        collections::BTreeMap,
+        sync::{mpsc, Mutex},
+        thread,
+    };
+
+    use crate::device::pci::{
+        dci::Direction,
+        event_sink::EventSink,
+        pipes::{PipeReturn, Pipes},
+        td_engine::{write_in_data, TdEngine, TdOutcome},
+        trb::EventTrb,
+    };
+
+    use super::*;
+
+    /// Where every [`MockUsbDevice`] is on the host.
+    pub const MOCK_LOCATION: HostLocation = HostLocation {
+        bus_number: 1,
+        device_address: 2,
+    };
+
+    /// The device descriptor of every [`MockUsbDevice`]: a USB 2.0 device
+    /// with one configuration.
+    pub const MOCK_DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0, 0, 1,
+    ];
+
+    /// The configuration of every [`MockUsbDevice`], with EP1 IN as
+    /// Interrupt endpoint for 4 bytes, and EP1 OUT and EP2 IN as Bulk
+    /// endpoints for 512 bytes.
+    pub const MOCK_CONFIGURATION_DESCRIPTOR: [u8; 39] = [
+        9, 0x02, 39, 0, 1, 1, 0, 0x80, 50, //
+        9, 0x04, 0, 0, 3, 0xff, 0, 0, 0, //
+        7, 0x05, 0x81, 0x03, 4, 0, 10, //
+        7, 0x05, 0x01, 0x02, 0x00, 0x02, 0, //
+        7, 0x05, 0x82, 0x02, 0x00, 0x02, 0, //
+    ];
+
+    /// What the controller asked a [`MockUsbDevice`] to do.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MockCall {
+        Reset,
+        /// A control transfer with the given bRequest.
+        ControlTransfer(u8),
+        ClearHalt(u8),
+        StopEndpoint(u8),
+        DisableEndpoint(u8),
+        Release,
+    }
+
+    /// How the endpoint workers of a [`MockUsbDevice`] respond to a stop.
+    #[derive(Debug, Default)]
+    pub enum MockStop {
+        /// No transfer is in flight, so the workers acknowledge right away.
+        #[default]
+        Idle,
+        /// A worker interrupts the transfer of the TRB at `trb_address` and
+        /// reports it as stopped before it acknowledges.
+        InFlight {
+            event_sink: Arc<EventSink>,
+            slot_id: u8,
+            trb_address: u64,
+        },
+        /// The workers never acknowledge.
+        Unresponsive,
+    }
+
+    /// A pipe of a [`MockUsbDevice`], numbered in the order the device
+    /// opened them.
+    pub type MockPipe = usize;
+
+    /// An enabled endpoint of a [`MockUsbDevice`] and the pipe it borrowed.
+    #[derive(Debug)]
+    struct MockWorker {
+        engine: TdEngine,
+        pipe: MockPipe,
+        pipe_return: PipeReturn<MockPipe>,
+    }
+
+    /// A device that records the requests of the controller.
+    ///
+    /// Its endpoints complete all queued TDs when the doorbell rings, IN
+    /// TDs with zeros. Like host devices, it keeps the pipes of endpoints
+    /// across their workers.
+    #[derive(Debug)]
+    pub struct MockUsbDevice {
+        pub speed: Speed,
+        pub calls: Arc<Mutex<Vec<MockCall>>>,
+        pub stop: MockStop,
+        /// The data the device returns for device-to-host control requests.
+        pub control_in_data: Vec<u8>,
+        pub identity: Arc<DeviceIdentity>,
+        pub device_descriptor: DeviceDescriptor,
+        pub configurations: Vec<ConfigurationDescriptor>,
+        /// The DCI and pipe of every endpoint the controller enabled, in
+        /// order.
+        pub lent_pipes: Arc<Mutex<Vec<(u8, MockPipe)>>>,
+        workers: BTreeMap<Dci, MockWorker>,
+        pipes: Pipes<MockPipe>,
+        opened_pipes: usize,
+    }
+
+    impl MockUsbDevice {
+        /// Create a High Speed device and the log of its calls.
+        pub fn new() -> (Self, Arc<Mutex<Vec<MockCall>>>) {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let device = Self {
+                speed: Speed::High,
+                calls: calls.clone(),
+                stop: MockStop::default(),
+                control_in_data: Vec::new(),
+                identity: Arc::new(DeviceIdentity::new(
+                    MOCK_LOCATION,
+                    DeviceIdentification::default(),
+                )),
+                device_descriptor: DeviceDescriptor::parse(&MOCK_DEVICE_DESCRIPTOR).unwrap(),
+                configurations: vec![ConfigurationDescriptor::parse(
+                    &MOCK_CONFIGURATION_DESCRIPTOR,
+                )
+                .unwrap()],
+                lent_pipes: Arc::new(Mutex::new(Vec::new())),
+                workers: BTreeMap::new(),
+                pipes: Pipes::default(),
+                opened_pipes: 0,
+            };
+            (device, calls)
+        }
+
+        /// Shut down the worker of an endpoint and take back its pipe.
+        fn stop_worker(&mut self, endpoint_id: Dci) {
+            if let Some(worker) = self.workers.remove(&endpoint_id) {
+                worker.pipe_return.give_back(worker.pipe);
+                self.pipes.collect(endpoint_id, Duration::ZERO);
+            }
+        }
+    }
+
+    impl RealDevice for MockUsbDevice {
+        fn speed(&self) -> Option<Speed> {
+            Some(self.speed)
+        }
+
+        fn identity(&self) -> &Arc<DeviceIdentity> {
+            &self.identity
+        }
+
+        fn device_descriptor(&self) -> DeviceDescriptor {
+            self.device_descriptor
+        }
+
+        fn configuration_descriptors(&self) -> Vec<ConfigurationDescriptor> {
+            self.configurations.clone()
+        }
+
+        fn control_transfer(&self, request: &UsbRequest, dma_bus: &BusDeviceRef) -> CompletionCode {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(MockCall::ControlTransfer(request.request));
+            let written = request
+                .data
+                .filter(|_| request.request_type & 0x80 != 0)
+                .map_or(Ok(0), |buffer| {
+                    write_in_data(
+                        dma_bus,
+                        buffer.pointer,
+                        &self.control_in_data,
+                        buffer.length.into(),
+                    )
+                });
+            written.map_or(CompletionCode::DataBufferError, |_| CompletionCode::Success)
+        }
+
+        fn enable_endpoint(
+            &mut self,
+            worker_info: EndpointWorkerInfo,
+            _endpoint_type: EndpointType,
+        ) {
+            let endpoint_id = worker_info.endpoint_id;
+            self.stop_worker(endpoint_id);
+            let (pipe, pipe_return) = self.pipes.lend(endpoint_id);
+            let pipe = pipe.unwrap_or_else(|| {
+                self.opened_pipes += 1;
+                self.opened_pipes - 1
+            });
+            self.lent_pipes
+                .lock()
+                .unwrap()
+                .push((endpoint_id.get(), pipe));
+            self.workers.insert(
+                endpoint_id,
+                MockWorker {
+                    engine: worker_info.engine,
+                    pipe,
+                    pipe_return,
+                },
+            );
+        }
+
+        fn transfer(&mut self, endpoint_id: Dci, _stream_id: u16) {
+            let Some(MockWorker { engine, .. }) = self.workers.get_mut(&endpoint_id) else {
+                return;
+            };
+            while let Some(td) = engine.next_td() {
+                let data = vec![0; td.transfer_length as usize];
+                let outcome = match endpoint_id.direction() {
+                    Some(Direction::In) => TdOutcome::In {
+                        data: &data,
+                        stopped: false,
+                    },
+                    _ => TdOutcome::Out {
+                        sent: data.len(),
+                        stopped: false,
+                    },
+                };
+                engine.complete_td(td, outcome);
+            }
+            engine.flush_events();
+        }
+
+        fn reset(&mut self) {
+            self.calls.lock().unwrap().push(MockCall::Reset);
+            self.pipes.discard_all();
+        }
+
+        fn clear_halt(&mut self, endpoint_id: Dci) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(MockCall::ClearHalt(endpoint_id.get()));
+            self.pipes.discard(endpoint_id);
+        }
+
+        fn stop_endpoint(&mut self, endpoint_id: Dci, timeout: Duration) -> bool {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(MockCall::StopEndpoint(endpoint_id.get()));
+            match &self.stop {
+                MockStop::Idle => true,
+                MockStop::InFlight {
+                    event_sink,
+                    slot_id,
+                    trb_address,
+                } => {
+                    let (event_sink, slot_id, trb_address) =
+                        (event_sink.clone(), *slot_id, *trb_address);
+                    let (sender, acknowledgment) = mpsc::channel();
+                    thread::spawn(move || {
+                        // Take a while to cancel, so the controller has to
+                        // wait for us.
+                        thread::sleep(Duration::from_millis(10));
+                        event_sink.post(EventTrb::new_transfer_event_trb(
+                            trb_address,
+                            0,
+                            CompletionCode::Stopped,
+                            false,
+                            endpoint_id,
+                            slot_id,
+                        ));
+                        sender.send(()).unwrap();
+                    });
+                    acknowledgment.recv_timeout(timeout).is_ok()
+                }
+                MockStop::Unresponsive => false,
+            }
+        }
+
+        fn disable_endpoint(&mut self, endpoint_id: Dci, _timeout: Duration) -> bool {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(MockCall::DisableEndpoint(endpoint_id.get()));
+            self.stop_worker(endpoint_id);
+            !matches!(self.stop, MockStop::Unresponsive)
+        }
+
+        fn release(&mut self, _timeout: Duration) {
+            self.calls.lock().unwrap().push(MockCall::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testutils::MockUsbDevice, *};
+
+    #[test]
+    fn control_endpoint_max_packet_size_per_speed() {
+        for (speed, max_packet_size) in [
+            (Speed::Low, 8),
+            (Speed::Full, 64),
+            (Speed::High, 64),
+            (Speed::Super, 512),
+            (Speed::SuperPlus, 512),
+        ] {
+            assert_eq!(speed.max_packet_size_ep0(), max_packet_size, "{speed}");
+        }
+    }
+
+    #[test]
+    fn endpoint_types_from_context_value() {
+        use EndpointType::*;
+
+        assert_eq!(EndpointType::from_context_value(0), None);
+        assert_eq!(EndpointType::from_context_value(1), Some(IsochOut));
+        assert_eq!(EndpointType::from_context_value(2), Some(BulkOut));
+        assert_eq!(EndpointType::from_context_value(3), Some(InterruptOut));
+        assert_eq!(EndpointType::from_context_value(4), Some(Control));
+        assert_eq!(EndpointType::from_context_value(5), Some(IsochIn));
+        assert_eq!(EndpointType::from_context_value(6), Some(BulkIn));
+        assert_eq!(EndpointType::from_context_value(7), Some(InterruptIn));
+        // The field has three bits.
+        assert_eq!(EndpointType::from_context_value(0x8 | 6), Some(BulkIn));
+    }
+
+    #[test]
+    fn isochronous_and_interrupt_out_endpoints_are_not_supported() {
+        let supported: Vec<_> = (1..=7)
+            .filter_map(EndpointType::from_context_value)
+            .filter(|endpoint_type| endpoint_type.is_supported())
+            .collect();
+        assert_eq!(
+            supported,
+            [
+                EndpointType::BulkOut,
+                EndpointType::Control,
+                EndpointType::BulkIn,
+                EndpointType::InterruptIn
+            ]
+        );
+    }
+
+    #[test]
+    fn mock_devices_describe_their_endpoints() {
+        let (device, calls) = MockUsbDevice::new();
+
+        let descriptor = device.device_descriptor();
+        assert_eq!(descriptor.usb_version, 0x0200);
+        assert_eq!(
+            (descriptor.vendor_id, descriptor.product_id),
+            (0x1234, 0x5678)
+        );
+
+        let configurations = device.configuration_descriptors();
+        assert_eq!(configurations.len(), 1);
+        let endpoints: Vec<_> = configurations[0].interfaces[0]
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                (
+                    endpoint.dci().unwrap().get(),
+                    endpoint.endpoint_type(),
+                    endpoint.packet_size(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            endpoints,
+            [
+                (3, EndpointType::InterruptIn, 4),
+                (2, EndpointType::BulkOut, 512),
+                (5, EndpointType::BulkIn, 512),
+            ]
+        );
+
+        // Descriptors come from the device's state, not from requests.
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn speed_raw_values() {
+        assert_eq!(Speed::Full.raw(), 1);
+        assert_eq!(Speed::SuperPlus.raw(), 5);
+    }
+
+    fn identity(serial: Option<&str>) -> DeviceIdentity {
+        DeviceIdentity::new(
+            HostLocation {
+                bus_number: 3,
+                device_address: 112,
+            },
+            DeviceIdentification {
+                vendor_id: 0x1234,
+                product_id: 0xabcd,
+                serial: serial.map(str::to_string),
+            },
+        )
+    }
+
+    #[test]
+    fn identities_show_guest_port_and_slot_once_assigned() {
+        let identity = identity(Some("SN1"));
+        assert_eq!(
+            identity.to_string(),
+            "bus 003 device 112 (1234:abcd serial SN1)"
+        );
+
+        identity.set_port(Some(5));
+        identity.set_slot(Some(2));
+        assert_eq!((identity.port(), identity.slot()), (Some(5), Some(2)));
+        assert_eq!(
+            identity.to_string(),
+            "bus 003 device 112 (1234:abcd serial SN1, port 5, slot 2)"
+        );
+
+        identity.set_slot(None);
+        assert_eq!(identity.slot(), None);
+        assert_eq!(
+            identity.to_string(),
+            "bus 003 device 112 (1234:abcd serial SN1, port 5)"
+        );
+    }
+
+    #[test]
+    fn worker_thread_names_fit_the_kernel_limit() {
+        let identity = identity(None);
+        assert_eq!(
+            identity.worker_thread_name(Dci::new(3).unwrap()),
+            "003-112 ep3"
+        );
+        // Linux keeps 15 bytes of a thread name.
+        assert!(identity.worker_thread_name(Dci::new(31).unwrap()).len() <= 15);
+    }
 }
@@ -1,13 +1,28 @@
+use std::time::Instant;
+
+use super::constants::xhci::{
+    operational::{portpmsc, portsc},
+    runtime::{imod, IMOD_DEFAULT},
+};
+
+/// The PORTSC fields that keep what the driver writes.
+///
+/// The other RW fields and the RW1S fields request actions we do not
+/// emulate: we neither change link states nor disable ports, so writes to
+/// them have no effect. The controller handles PORTSC.PP and PORTSC.PR
+/// itself.
+const STORED_BITS: u32 = portsc::PIC | portsc::WCE | portsc::WDE | portsc::WOE;
+
 /// A simple PORTSC register implementation supporting RW1C bits.
 ///
 /// The PORTSC register requires us to initially set some bits and
 /// later react to 1-to-clear writes (RW1C) to get a device to show up.
-/// Perhaps later we need more fine-grained access to the bits or state
-/// handling, but we can use the simplistic implementation for now.
+/// The change bits are RW1C, the wake and indicator fields store the
+/// written value, and all other bits are read-only. Reserved bits always
+/// read 0.
 #[derive(Debug, Clone, Copy)]
 pub struct PortscRegister {
-    value: u64,
-    bitmask_rw1c: u64,
+    value: u32,
 }
 
 impl PortscRegister {
@@ -15,29 +30,143 @@ impl PortscRegister {
     ///
     /// # Parameters
     ///
-    /// - initial_value: the initial value of the register.
-    pub const fn new(initial_value: u64) -> Self {
+    /// - initial_value: the initial value of the register. Reserved bits
+    ///   are dropped.
+    pub const fn new(initial_value: u32) -> Self {
         Self {
-            value: initial_value,
-            bitmask_rw1c: 0x00260000,
+            value: initial_value & !portsc::RSVDZ,
         }
     }
 
     /// Read the current register value.
     ///
     /// This function should be called when an MMIO read happens.
-    pub const fn read(&self) -> u64 {
+    pub const fn read(&self) -> u32 {
         self.value
     }
 
+    /// The value that leaves the register unchanged when written.
+    ///
+    /// Writes narrower than the register take the bytes they do not cover
+    /// from this value, so they do not clear RW1C bits by accident.
+    pub const fn unchanged_write_value(&self) -> u32 {
+        self.value & !portsc::CHANGE_BITS
+    }
+
+    /// Enable the port after a reset and report the completed reset with
+    /// PRC.
+    pub const fn complete_reset(&mut self) {
+        self.value |= portsc::PED | portsc::PRC;
+    }
+
     /// Update the current register value.
     ///
     /// This function should be called when an MMIO write happens.
-    /// RW1C bits are updates according to RW1C semantics, all
-    /// other bits are treated as read-only.
-    pub const fn write(&mut self, new_value: u64) {
-        let bits_to_clear = new_value & self.bitmask_rw1c;
+    /// RW1C bits are updated according to RW1C semantics, the stored
+    /// fields take the new value, and all other bits are treated as
+    /// read-only.
+    pub const fn write(&mut self, new_value: u32) {
+        let bits_to_clear = new_value & portsc::CHANGE_BITS;
         self.value &= !bits_to_clear;
+        self.value = (self.value & !STORED_BITS) | (new_value & STORED_BITS);
+    }
+}
+
+/// A PORTPMSC register that only stores the values the driver writes.
+///
+/// We do not emulate link power management: the ports never enter U1, U2,
+/// or L1, so the timeouts and the other settings have no effect. Drivers
+/// still configure them and read them back, though.
+#[derive(Debug, Clone, Copy)]
+pub struct PortpmscRegister {
+    value: u64,
+    writable: u64,
+}
+
+impl PortpmscRegister {
+    /// Create a PORTPMSC register of a USB3 port.
+    pub const fn usb3() -> Self {
+        Self {
+            value: 0,
+            writable: portpmsc::usb3::WRITABLE,
+        }
+    }
+
+    /// Create a PORTPMSC register of a USB2 port.
+    pub const fn usb2() -> Self {
+        Self {
+            value: 0,
+            writable: portpmsc::usb2::WRITABLE,
+        }
+    }
+
+    /// Read the current register value.
+    pub const fn read(&self) -> u64 {
+        self.value
+    }
+
+    /// Store the writable fields of a new register value.
+    pub const fn write(&mut self, new_value: u64) {
+        self.value = (self.value & !self.writable) | (new_value & self.writable);
+    }
+
+    /// Restore the default value of the register.
+    pub const fn reset(&mut self) {
+        self.value = 0;
+    }
+}
+
+/// The Interrupter Moderation Register (IMOD).
+///
+/// IMODI holds the minimum interval between interrupts. IMODC is loaded
+/// with IMODI whenever the Interrupt Pending flag clears and counts down to
+/// 0 in steps of [`imod::TICK`]. The driver may write IMODC at any time to
+/// alter the interrupt rate.
+///
+/// We keep the counter as the value it was loaded with and the time it was
+/// loaded at, so it counts down without a timer. Interrupts are not held
+/// back while it runs: Transfer Event coalescing already limits their
+/// rate.
+#[derive(Debug, Clone, Copy)]
+pub struct ImodRegister {
+    interval: u16,
+    counter: u16,
+    loaded: Instant,
+}
+
+impl ImodRegister {
+    /// Create the register with its default value at `now`.
+    pub const fn new(now: Instant) -> Self {
+        Self {
+            interval: IMOD_DEFAULT,
+            counter: 0,
+            loaded: now,
+        }
+    }
+
+    /// The value of IMODC at `now`.
+    pub fn counter(&self, now: Instant) -> u16 {
+        let ticks = now.saturating_duration_since(self.loaded).as_nanos() / imod::TICK.as_nanos();
+        self.counter
+            .saturating_sub(u16::try_from(ticks).unwrap_or(u16::MAX))
+    }
+
+    /// Read the register at `now`.
+    pub fn read(&self, now: Instant) -> u64 {
+        u64::from(self.interval) | u64::from(self.counter(now)) << 16
+    }
+
+    /// Write both fields at `now`.
+    pub const fn write(&mut self, new_value: u64, now: Instant) {
+        self.interval = (new_value & imod::IMODI) as u16;
+        self.counter = ((new_value & imod::IMODC) >> 16) as u16;
+        self.loaded = now;
+    }
+
+    /// Load IMODC with IMODI at `now`, as when Interrupt Pending clears.
+    pub const fn reload(&mut self, now: Instant) {
+        self.counter = self.interval;
+        self.loaded = now;
     }
 }
 
@@ -78,4 +207,98 @@ mod tests {
             "writing 1 to bit 17 should clear the bit."
         );
     }
+
+    #[test]
+    fn portsc_bit_classes_cover_the_register() {
+        let classes = [
+            portsc::RO,
+            portsc::RW,
+            portsc::RW1C,
+            portsc::RW1S,
+            portsc::RSVDZ,
+        ];
+        for (i, class) in classes.iter().enumerate() {
+            for other in &classes[i + 1..] {
+                assert_eq!(class & other, 0, "{class:#x} and {other:#x} overlap");
+            }
+        }
+        assert_eq!(classes.iter().fold(0, |all, class| all | class), !0);
+        assert_eq!(portsc::CHANGE_BITS, portsc::RW1C & !portsc::PED);
+    }
+
+    #[test]
+    fn portsc_reserved_bits_stay_clear() {
+        let mut reg = PortscRegister::new(!0);
+        assert_eq!(reg.read() & portsc::RSVDZ, 0);
+
+        for bit in (0..32).map(|shift| 1 << shift) {
+            let mut reg = PortscRegister::new(portsc::PP);
+            reg.write(reg.unchanged_write_value() | bit);
+            assert_eq!(reg.read() & portsc::RSVDZ, 0, "writing bit {bit:#x}");
+        }
+
+        reg.write(!0);
+        assert_eq!(reg.read() & (portsc::RSVDZ | portsc::CHANGE_BITS), 0);
+    }
+
+    #[test]
+    fn portsc_stores_wake_and_indicator_fields() {
+        let connected = portsc::CCS | portsc::PED | portsc::PP | portsc::CSC;
+        let mut reg = PortscRegister::new(connected);
+
+        reg.write(reg.unchanged_write_value() | portsc::WCE | portsc::WDE | 1 << 14);
+        assert_eq!(reg.read(), connected | portsc::WCE | portsc::WDE | 1 << 14);
+
+        // Actions we do not emulate leave the port as it is. The
+        // controller carries out resets itself.
+        reg.write(portsc::PR | portsc::WPR | portsc::LWS | portsc::PLS | portsc::PED);
+        assert_eq!(reg.read(), connected);
+    }
+
+    #[test]
+    fn usb3_portpmsc_stores_timeouts() {
+        let mut reg = PortpmscRegister::usb3();
+        assert_eq!(reg.read(), 0);
+
+        reg.write(0xffff_ffff);
+        assert_eq!(reg.read(), portpmsc::usb3::WRITABLE);
+
+        reg.write(0x7f05);
+        assert_eq!(reg.read(), 0x7f05, "U2 timeout 0x7f, U1 timeout 0x05");
+
+        reg.reset();
+        assert_eq!(reg.read(), 0);
+    }
+
+    #[test]
+    fn usb2_portpmsc_keeps_l1_status_read_only() {
+        let mut reg = PortpmscRegister::usb2();
+
+        reg.write(0xffff_ffff);
+        assert_eq!(reg.read() & portpmsc::usb2::L1S, 0);
+        assert_eq!(reg.read(), portpmsc::usb2::WRITABLE);
+
+        reg.write(portpmsc::usb2::HLE | (4 << 4) | 0x8);
+        assert_eq!(reg.read(), 0x10048);
+    }
+
+    #[test]
+    fn imod_keeps_interval_and_counter_apart() {
+        let start = Instant::now();
+        let mut reg = ImodRegister::new(start);
+        assert_eq!(reg.read(start) & imod::IMODI, u64::from(IMOD_DEFAULT));
+
+        reg.write(0x0010_0fa0, start);
+        assert_eq!(reg.read(start), 0x0010_0fa0);
+
+        // The counter runs down in 250ns steps and stops at 0, the interval
+        // stays.
+        assert_eq!(reg.read(start + 4 * imod::TICK), 0x000c_0fa0);
+        assert_eq!(reg.read(start + 100 * imod::TICK), 0x0fa0);
+
+        let later = start + imod::TICK * 1000;
+        reg.reload(later);
+        assert_eq!(reg.counter(later), 0x0fa0);
+        assert_eq!(reg.counter(later + imod::TICK * 0xfa0), 0);
+    }
 }
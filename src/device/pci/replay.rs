@@ -0,0 +1,231 @@
+//! # Trace Replay
+//!
+//! Feeds a [trace](super::trace) that `--record` captured to a fresh
+//! controller over test memory, so a bug report with a recording becomes
+//! a test.
+//!
+//! Before each register access, the guest memory the controller read
+//! while handling it is in place. The replay checks that the controller
+//! posts the same event TRBs as during the recording. Register reads are
+//! repeated for their side effects, but their values may differ, e.g.,
+//! for MFINDEX. Time markers are ignored.
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::device::bus::{testutils::TestBusDevice, Request, RequestSize};
+
+use super::{
+    constants::xhci::{offset, rings::trb_types, rings::TRB_SIZE},
+    realdevice::RealDevice,
+    trace::{TraceEvent, TraceParseError, TraceRecorder},
+    traits::PciDevice,
+    xhci::{ControllerConfig, XhciController},
+};
+
+/// Guest memory is allocated in pages.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// An in-memory trace that stays accessible after it is handed to a
+/// [`TraceRecorder`].
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// The trace recorded so far.
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parse a trace, skipping empty lines and comments.
+///
+/// Errors come with the number of the offending line.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceEvent>, (usize, TraceParseError)> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| line.parse().map_err(|error| (number, error)))
+        .collect()
+}
+
+/// The event TRBs among the guest memory writes of a trace, in order.
+pub fn event_trbs(events: &[TraceEvent]) -> Vec<Vec<u8>> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::DmaWrite { data, .. }
+                if data.len() == TRB_SIZE
+                    && (trb_types::TRANSFER_EVENT..=trb_types::MFINDEX_WRAP_EVENT)
+                        .contains(&(data[13] >> 2)) =>
+            {
+                Some(data.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// A recording and what a fresh controller did with it.
+#[derive(Debug)]
+pub struct Replay {
+    pub recorded: Vec<TraceEvent>,
+    /// The trace of the replay itself.
+    pub replayed: Vec<TraceEvent>,
+}
+
+impl Replay {
+    /// Check that the replay posted the same event TRBs as the recording.
+    pub fn assert_same_events(&self) {
+        assert_eq!(event_trbs(&self.replayed), event_trbs(&self.recorded));
+    }
+}
+
+/// Replay the trace in `text` with `devices` attached to the controller in
+/// the same order as during the recording.
+pub fn replay(text: &str, devices: Vec<Box<dyn RealDevice>>) -> Replay {
+    let recorded = parse_trace(text)
+        .unwrap_or_else(|(line, error)| panic!("invalid trace line {line}: {error}"));
+
+    let memory_end = recorded
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::Dma { address, data } | TraceEvent::DmaWrite { address, data } => {
+                Some(address + data.len() as u64)
+            }
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let ram = Arc::new(TestBusDevice::new(&vec![
+        0;
+        memory_end.next_multiple_of(PAGE_SIZE).max(PAGE_SIZE)
+            as usize
+    ]));
+
+    let buffer = SharedBuffer::default();
+    let mut controller = XhciController::new(
+        ram.clone(),
+        ControllerConfig {
+            trace_recorder: Some(Arc::new(TraceRecorder::new(buffer.clone()))),
+            ..ControllerConfig::default()
+        },
+    );
+    for device in devices {
+        controller.set_device(device).unwrap();
+    }
+    let controller = Mutex::new(controller);
+
+    for event in &recorded {
+        match event {
+            TraceEvent::Write {
+                region,
+                offset,
+                size,
+                value,
+            } => controller.write_io(*region, Request::new(*offset, *size), *value),
+            TraceEvent::Read {
+                region,
+                offset,
+                size,
+                ..
+            } => {
+                let _ = controller.read_io(*region, Request::new(*offset, *size));
+            }
+            TraceEvent::Doorbell { target, value } => controller.write_io(
+                0,
+                Request::new(
+                    offset::DOORBELL_CONTROLLER + 4 * u64::from(*target),
+                    RequestSize::Size4,
+                ),
+                (*value).into(),
+            ),
+            TraceEvent::Dma { address, data } => ram.write_bulk(*address, data),
+            TraceEvent::DmaWrite { .. } | TraceEvent::Time(_) => {}
+        }
+    }
+
+    Replay {
+        recorded,
+        replayed: parse_trace(&buffer.text()).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::pci::{realdevice::testutils::MockUsbDevice, trb::CompletionCode};
+
+    use super::*;
+
+    /// The device descriptor of the mock device in the recording.
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x01,
+        0x02, 0x03, 0x01,
+    ];
+
+    /// A guest that enables a slot, addresses the mock device, and reads
+    /// its device descriptor.
+    const GET_DEVICE_DESCRIPTOR: &str = include_str!("testdata/get_device_descriptor.trace");
+
+    #[test]
+    fn comments_and_empty_lines_are_skipped() {
+        assert_eq!(
+            parse_trace("# recording\n\nt 0\n  db 0 0x0 \n"),
+            Ok(vec![
+                TraceEvent::Time(std::time::Duration::ZERO),
+                TraceEvent::Doorbell {
+                    target: 0,
+                    value: 0
+                }
+            ])
+        );
+        assert_eq!(
+            parse_trace("t 0\nt\n").unwrap_err(),
+            (2, TraceParseError::MissingField)
+        );
+    }
+
+    #[test]
+    fn recorded_descriptor_request_replays() {
+        let (mut device, _calls) = MockUsbDevice::new();
+        device.control_in_data = DEVICE_DESCRIPTOR.to_vec();
+        let replay = replay(GET_DEVICE_DESCRIPTOR, vec![Box::new(device)]);
+        replay.assert_same_events();
+
+        // Enable Slot, Address Device, and the descriptor request.
+        let events: Vec<_> = event_trbs(&replay.replayed)
+            .into_iter()
+            .filter(|trb| trb[13] >> 2 != trb_types::PORT_STATUS_CHANGE_EVENT)
+            .map(|trb| (trb[13] >> 2, trb[11]))
+            .collect();
+        let success = CompletionCode::Success as u8;
+        assert_eq!(
+            events,
+            [
+                (trb_types::COMMAND_COMPLETION_EVENT, success),
+                (trb_types::COMMAND_COMPLETION_EVENT, success),
+                (trb_types::TRANSFER_EVENT, success),
+            ]
+        );
+
+        // The descriptor reached guest memory.
+        assert!(replay.replayed.iter().any(|event| matches!(
+            event,
+            TraceEvent::DmaWrite { data, .. } if *data == DEVICE_DESCRIPTOR
+        )));
+    }
+}
@@ -7,23 +7,62 @@
 use thiserror::Error;
 use tracing::{debug, trace, warn};
 
+use std::{fmt, ops::Range, sync::Arc};
+
 use super::{
-    device_slots::EndpointContext,
+    device_slots::{StreamContextArray, TransferRingContext},
     trb::{CommandTrb, CommandTrbVariant, EventTrb, RawTrbBuffer, TransferTrb, TransferTrbVariant},
-    usbrequest::UsbRequest,
+    usbrequest::{DataStage, UsbRequest},
 };
 
 use crate::device::{
     bus::{BusDeviceRef, Request, RequestSize},
+    interval::Interval,
     pci::{
         constants::xhci::{
             operational::crcr,
             rings::{event_ring::segments_table_entry_offsets::*, trb_types, TRB_SIZE},
+            runtime::erdp,
         },
         trb::zeroed_trb_buffer,
+        trb_fields::{bits, TrbFields},
     },
 };
 
+/// How much of the Event Ring holds events the driver did not process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventRingOccupancy {
+    /// The number of TRBs with unprocessed events.
+    pub used: u32,
+    /// The number of TRBs of all segments.
+    pub capacity: u32,
+}
+
+impl EventRingOccupancy {
+    /// Whether the ring cannot take another event. One TRB always stays
+    /// free, as the driver could not tell a full ring from an empty one.
+    pub const fn is_full(self) -> bool {
+        self.used + 1 >= self.capacity
+    }
+
+    /// The occupied share of the ring in percent.
+    pub fn percent(self) -> u64 {
+        u64::from(self.used) * 100 / u64::from(self.capacity.max(1))
+    }
+}
+
+impl fmt::Display for EventRingOccupancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} TRBs ({}%)",
+            self.used,
+            self.capacity,
+            self.percent()
+        )
+    }
+}
+
 /// The Event Ring: A unidirectional means of communication, allowing the XHCI
 /// controller to send events to the driver.
 ///
@@ -53,6 +92,20 @@ pub struct EventRing {
     /// When the ring is empty, the pointer is equal to the enqueue pointer
     /// (EREP).
     dequeue_pointer: u64,
+    /// The Dequeue ERST Segment Index (DESI) of the last ERDP write.
+    ///
+    /// DESI holds the low bits of the index of the segment that the
+    /// dequeue pointer points into.
+    dequeue_desi: u32,
+    /// The segment index and the offset into that segment of the dequeue
+    /// pointer.
+    ///
+    /// Segments may be adjacent or even aliased in guest memory, so only
+    /// the segment index tells where the driver is in the ring. We only
+    /// know it if the DESI of the driver matches a segment that contains
+    /// the dequeue pointer. Otherwise, the full check falls back to
+    /// comparing addresses.
+    dequeue_position: Option<(u32, u64)>,
     /// The Event Ring Enqueue Pointer (EREP).
     ///
     /// The EREP is an internal variable of the XHCI controller.
@@ -60,6 +113,8 @@ pub struct EventRing {
     /// can conclude the ring is empty), when it detects a cycle-bit mismatch
     /// at ERDP.
     enqueue_pointer: u64,
+    /// The offset of the enqueue pointer into the current segment.
+    enqueue_offset: u64,
     /// The number of TRBs that fits into the current segment.
     ///
     /// The count is initialized from the size field of an Event Ring Segment
@@ -88,6 +143,19 @@ pub struct EventRing {
     /// segment access in the Event Ring Segment Table (valid indices
     /// are 0 to erst_size-1).
     erst_size: u32,
+    /// Whether the driver has written ERSTBA since the last reset.
+    ///
+    /// The driver may program ERSTSZ, ERSTBA and ERDP in any order. We only
+    /// derive the enqueue state once both ERSTSZ and ERSTBA are known.
+    base_address_written: bool,
+    /// Whether the enqueue state was derived from the segment table, i.e.,
+    /// the ring can take events.
+    configured: bool,
+    /// The number of dequeue pointers we rejected because they pointed
+    /// outside the segments of the ring.
+    rejected_dequeue_pointers: u64,
+    /// The number of events we dropped because the ring was not configured.
+    unconfigured_drops: u64,
 }
 
 impl EventRing {
@@ -101,48 +169,125 @@ impl EventRing {
             dma_bus,
             base_address: 0,
             dequeue_pointer: 0,
+            dequeue_desi: 0,
+            dequeue_position: None,
             enqueue_pointer: 0,
+            enqueue_offset: 0,
             trb_count: 0,
             erst_count: 0,
             cycle_state: false,
             erst_size: 0,
+            base_address_written: false,
+            configured: false,
+            rejected_dequeue_pointers: 0,
+            unconfigured_drops: 0,
         }
     }
 
+    /// Return the Event Ring to its state after controller reset.
+    ///
+    /// The driver has to program ERSTSZ and ERSTBA again before the ring
+    /// takes events. The statistics survive.
+    pub fn reset(&mut self) {
+        *self = Self {
+            rejected_dequeue_pointers: self.rejected_dequeue_pointers,
+            unconfigured_drops: self.unconfigured_drops,
+            ..Self::new(self.dma_bus.clone())
+        };
+        debug!("event ring reset");
+    }
+
     /// Configure the Event Ring.
     ///
     /// Call this function when the driver writes to the ERSTBA register (as
     /// part of setting up the controller).
     /// Besides setting the base address of the Event Ring Segment Table, this
     /// method initializes `enqueue_pointer` to the start of segment 0 and
-    /// sets `trb_count` from `ERST[0]`.
+    /// sets `trb_count` from `ERST[0]`. If the driver did not write ERSTSZ
+    /// yet, the initialization happens once it does.
+    ///
+    /// A driver may also relocate the segment table of a configured ring.
+    /// The ring then restarts empty at segment 0 of the new table, and
+    /// events the driver did not process are lost. A dequeue pointer into
+    /// the old ring is meaningless, so we only keep one that points at the
+    /// start of the new ring, i.e., that the driver wrote in preparation.
     ///
     /// # Parameters
     ///
     /// - `erstba`: base address of the Event Ring Segment Table (ERST).
-    // clippy does not complain with the last three debug logs disabled,
-    // so it's okay to allow. Reevaluate when changing this function!
-    #[allow(clippy::cognitive_complexity)]
+    ///   Bits 5:0 are reserved and ignored.
     pub fn configure(&mut self, erstba: u64) {
-        assert_eq!(erstba & 0x3f, 0, "unaligned event ring base address");
-
-        assert!(
-            self.erst_size > 0,
-            "ERSTSZ must be set before ERSTBA; misconfigured driver"
-        );
+        if erstba & 0x3f != 0 {
+            warn!(
+                "ignoring reserved bits of the event ring segment table address {:#x}; misconfigured driver",
+                erstba
+            );
+        }
+        let erstba = erstba & !0x3f;
 
+        if self.configured {
+            debug!(
+                "driver relocates the event ring segment table from {:#x} to {:#x}",
+                self.base_address, erstba
+            );
+        }
         self.base_address = erstba;
-        self.enqueue_pointer = self.dma_bus.read(Request::new(
-            erstba.wrapping_add(SEGMENT_BASE),
-            RequestSize::Size8,
-        ));
-        self.trb_count = self
-            .dma_bus
-            .read(Request::new(erstba.wrapping_add(SIZE), RequestSize::Size4))
-            as u32;
+        self.base_address_written = true;
+        debug!("event ring segment table is at {:#x}", erstba);
+
+        if self.configured && self.dequeue_pointer != self.segment(0).0 {
+            self.dequeue_pointer = 0;
+        }
+
+        if self.erst_size > 0 {
+            self.latch_configuration();
+        } else {
+            debug!("deferring event ring configuration until ERSTSZ is written");
+        }
+    }
+
+    /// Handle writes to the Event Ring Segment Table Size (ERSTSZ).
+    ///
+    /// Completes the configuration if the driver wrote ERSTBA before.
+    /// Changing the size of a configured ring keeps the enqueue state.
+    ///
+    /// A size of 0 leaves the ring unconfigured, e.g., when Linux removes
+    /// the interrupter as the driver unbinds. Events are dropped until the
+    /// driver writes a size again.
+    pub fn set_erst_size(&mut self, size: u32) {
+        self.erst_size = size;
+
+        if size == 0 {
+            debug!("ERSTSZ is 0, the event ring is unconfigured");
+            self.configured = false;
+            self.erst_count = 0;
+            return;
+        }
+
+        if self.erst_count >= self.erst_size {
+            self.erst_count = 0;
+        }
+
+        trace!("set ERST size (segment count) to {}", self.erst_size);
+
+        if self.base_address_written && !self.configured {
+            self.latch_configuration();
+        }
+    }
+
+    /// Derive the enqueue state from segment 0 of the segment table.
+    ///
+    /// Requires that the driver wrote both ERSTSZ and ERSTBA.
+    // clippy does not complain with the last two debug logs disabled,
+    // so it's okay to allow. Reevaluate when changing this function!
+    #[allow(clippy::cognitive_complexity)]
+    fn latch_configuration(&mut self) {
+        (self.enqueue_pointer, self.trb_count) = self.segment(0);
+        self.enqueue_offset = 0;
+        self.erst_count = 0;
         self.cycle_state = true;
+        self.configured = true;
 
-        debug!("event ring segment table is at {:#x}", erstba);
         debug!(
             "initializing event ring enqueue pointer from ERST[0] base: {:#x}",
             self.enqueue_pointer
@@ -151,27 +296,181 @@ impl EventRing {
             "retrieving TRB count of the first event ring segment from the segment table: {}",
             self.trb_count
         );
-    }
-
-    pub fn set_erst_size(&mut self, size: u32) {
-        assert!(size >= 1, "erst_size (ERSTSZ) must be >= 1");
-        self.erst_size = size;
 
-        if self.erst_count >= self.erst_size {
-            self.erst_count = 0;
+        if self.trb_count == 0 {
+            warn!("segment 0 of the event ring has no space for TRBs; misconfigured driver");
+        }
+        for (first, second) in self.overlapping_segments() {
+            warn!(
+                "event ring segments {} and {} overlap in guest memory; misconfigured driver",
+                first, second
+            );
+        }
+        if let Some(memory) = self.contiguous_memory() {
+            debug!(
+                "event ring occupies {:#x}..{:#x} contiguously",
+                memory.start, memory.end
+            );
+        }
+        // A driver that wrote ERDP already points it at the start of the
+        // empty ring. Anything else is only stored until the driver updates
+        // ERDP while consuming events. A pointer outside the segments, e.g.,
+        // from before a reconfiguration, would break the full check, so we
+        // use the start of the empty ring instead.
+        match self.find_segment(self.dequeue_pointer, self.dequeue_desi) {
+            None => {
+                if self.dequeue_pointer != 0 {
+                    warn!(
+                        "event ring dequeue pointer {:#x} is outside the event ring, using the start of segment 0 ({:#x})",
+                        self.dequeue_pointer, self.enqueue_pointer
+                    );
+                }
+                self.dequeue_pointer = self.enqueue_pointer;
+                self.dequeue_desi = 0;
+                self.dequeue_position = Some((0, 0));
+            }
+            Some(segment) => {
+                if self.dequeue_pointer != self.enqueue_pointer {
+                    warn!(
+                        "event ring dequeue pointer {:#x} does not match the start of segment 0 ({:#x})",
+                        self.dequeue_pointer, self.enqueue_pointer
+                    );
+                }
+                self.dequeue_position = self.position_in(segment, self.dequeue_pointer);
+            }
         }
-
-        trace!("set ERST size (segment count) to {}", self.erst_size);
     }
 
     /// Handle writes to the Event Ring Dequeue Pointer (ERDP).
     ///
+    /// The flag bits are not part of the pointer. Writes before the ring is
+    /// configured are only stored and do not influence the configuration.
+    ///
+    /// Once the ring is configured, the pointer has to point into one of
+    /// its segments. Otherwise, the full check would compare against a
+    /// nonsense address, so we keep the previous pointer and count the
+    /// write in [`rejected_dequeue_pointers`](Self::rejected_dequeue_pointers).
+    ///
     /// # Parameters
     ///
     /// - `erdp`: value that the driver has written to the ERDP register.
     pub fn update_dequeue_pointer(&mut self, erdp: u64) {
-        self.dequeue_pointer = erdp;
-        debug!("driver set event ring dequeue pointer to {:#x}", erdp);
+        let dequeue_pointer = erdp & erdp::DEQUEUE_POINTER_MASK;
+        let desi = (erdp & erdp::DESI) as u32;
+        if self.configured {
+            let Some(segment) = self.find_segment(dequeue_pointer, desi) else {
+                warn!(
+                    "ignoring event ring dequeue pointer {:#x} outside the event ring, keeping {:#x}",
+                    dequeue_pointer, self.dequeue_pointer
+                );
+                self.rejected_dequeue_pointers += 1;
+                return;
+            };
+            self.dequeue_position = self.position_in(segment, dequeue_pointer);
+        }
+
+        self.dequeue_pointer = dequeue_pointer;
+        self.dequeue_desi = desi;
+        debug!(
+            "driver set event ring dequeue pointer to {:#x}",
+            dequeue_pointer
+        );
+    }
+
+    /// The base address and the TRB count of segment `index`.
+    fn segment(&self, index: u32) -> (u64, u32) {
+        let entry_addr = self.base_address.wrapping_add(u64::from(index) * 16);
+        let base = self.dma_bus.read(Request::new(
+            entry_addr.wrapping_add(SEGMENT_BASE),
+            RequestSize::Size8,
+        ));
+        let size = self.dma_bus.read(Request::new(
+            entry_addr.wrapping_add(SIZE),
+            RequestSize::Size4,
+        ));
+        (base, size as u32)
+    }
+
+    /// The guest memory that segment `index` occupies.
+    fn segment_range(&self, index: u32) -> Range<u64> {
+        let (base, size) = self.segment(index);
+        base..base.wrapping_add(u64::from(size) * TRB_SIZE as u64)
+    }
+
+    /// Whether segment `index` contains the TRB at `address`.
+    fn segment_contains(&self, index: u32, address: u64) -> bool {
+        self.segment_range(index).contains(&address)
+    }
+
+    /// The pairs of segments that share guest memory.
+    ///
+    /// We keep working with such a table, as the segment index tells the
+    /// segments apart, but events in one segment overwrite the other.
+    fn overlapping_segments(&self) -> Vec<(u32, u32)> {
+        let ranges: Vec<_> = (0..self.erst_size)
+            .map(|index| self.segment_range(index))
+            .collect();
+        (0..self.erst_size)
+            .flat_map(|first| ((first + 1)..self.erst_size).map(move |second| (first, second)))
+            .filter(|&(first, second)| ranges[first as usize].overlaps(&ranges[second as usize]))
+            .collect()
+    }
+
+    /// The guest memory of all segments, if it forms a single range.
+    fn contiguous_memory(&self) -> Option<Range<u64>> {
+        let mut ranges: Vec<_> = (0..self.erst_size)
+            .map(|index| self.segment_range(index))
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+        ranges
+            .into_iter()
+            .try_fold(Range::default(), |memory, range| memory.merge(&range))
+    }
+
+    /// Find the segment that contains the TRB at `address`.
+    ///
+    /// Prefers the segments whose index matches `desi` in the low bits, as
+    /// the driver names the segment of the dequeue pointer that way.
+    /// Returns the index of the segment and whether it matches `desi`.
+    fn find_segment(&self, address: u64, desi: u32) -> Option<(u32, bool)> {
+        (desi..self.erst_size)
+            .step_by(erdp::DESI as usize + 1)
+            .find(|&index| self.segment_contains(index, address))
+            .map(|index| (index, true))
+            .or_else(|| {
+                (0..self.erst_size)
+                    .find(|&index| self.segment_contains(index, address))
+                    .map(|index| (index, false))
+            })
+    }
+
+    /// The segment index and offset of `address` in the segment that
+    /// [`find_segment`](Self::find_segment) found, if it matches the DESI.
+    fn position_in(
+        &self,
+        (segment, matches_desi): (u32, bool),
+        address: u64,
+    ) -> Option<(u32, u64)> {
+        if !matches_desi {
+            debug!(
+                "event ring dequeue pointer {:#x} is in segment {}, which does not match the DESI",
+                address, segment
+            );
+            return None;
+        }
+        Some((segment, address.wrapping_sub(self.segment(segment).0)))
+    }
+
+    /// The number of ERDP writes that were ignored because they pointed
+    /// outside the Event Ring.
+    pub const fn rejected_dequeue_pointers(&self) -> u64 {
+        self.rejected_dequeue_pointers
+    }
+
+    /// The number of events that were dropped because the driver had not
+    /// programmed ERSTSZ and ERSTBA yet.
+    pub const fn unconfigured_drops(&self) -> u64 {
+        self.unconfigured_drops
     }
 
     /// Handle reads to the Event Ring Segment Table Base Address (ERSTBA).
@@ -181,7 +480,7 @@ impl EventRing {
 
     /// Handle reads to the Event Ring Dequeue Pointer (ERDP).
     pub const fn read_dequeue_pointer(&self) -> u64 {
-        self.dequeue_pointer
+        self.dequeue_pointer | self.dequeue_desi as u64
     }
 
     /// Handle reads to the Event Ring Segment Table Size (ERSTSZ).
@@ -195,8 +494,20 @@ impl EventRing {
     /// - `trb`: the TRB to enqueue.
     ///
     /// # Limitations
-    /// The current implementation does not handle ring-full recovery and will panic (`todo!()`) in that case.
+    /// The ring does not handle ring-full recovery and will panic (`todo!()`)
+    /// in that case. Check [`is_full`](Self::is_full) first or post events
+    /// through the [`EventSink`](super::event_sink::EventSink), which defers
+    /// them until the driver made space.
     pub fn enqueue(&mut self, trb: &EventTrb) {
+        if !self.configured {
+            // There is no place for the event in guest memory yet. Before
+            // configuration, the enqueue pointer is 0, and writing there
+            // would corrupt guest memory.
+            warn!("dropping event for unconfigured event ring: {:?}", trb);
+            self.unconfigured_drops += 1;
+            return;
+        }
+
         // TODO: Proper handling of full Event Ring
         // According to xHCI §4.9.4, the xHC must:
         //
@@ -229,27 +540,84 @@ impl EventRing {
             self.advance_segment_or_wrap();
         } else {
             self.enqueue_pointer = self.enqueue_pointer.wrapping_add(TRB_SIZE as u64);
+            self.enqueue_offset += TRB_SIZE as u64;
+        }
+    }
+
+    /// Whether the ring cannot take the next event, because it would
+    /// overwrite an event that the driver did not process yet.
+    ///
+    /// An unconfigured ring has no space at all and counts as full, so
+    /// events wait in the [`EventSink`](super::event_sink::EventSink) until
+    /// the driver configured the ring. [`enqueue`](Self::enqueue) drops
+    /// events for it.
+    pub fn is_full(&self) -> bool {
+        !self.configured || self.check_event_ring_full()
+    }
+
+    /// Whether the driver programmed ERSTSZ and ERSTBA, so the ring takes
+    /// events.
+    pub const fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    /// Whether the driver processed all events on the ring.
+    pub fn is_empty(&self) -> bool {
+        self.dequeue_position.map_or(
+            self.dequeue_pointer == self.enqueue_pointer,
+            |dequeue_position| dequeue_position == (self.erst_count, self.enqueue_offset),
+        )
+    }
+
+    /// How many TRBs hold events the driver did not process, if we know
+    /// where the driver is in the ring.
+    ///
+    /// This reads the segment table, so it is too expensive for every
+    /// event.
+    pub fn occupancy(&self) -> Option<EventRingOccupancy> {
+        let (dequeue_segment, dequeue_offset) =
+            self.dequeue_position.filter(|_| self.configured)?;
+        let sizes: Vec<u32> = (0..self.erst_size)
+            .map(|index| self.segment(index).1)
+            .collect();
+        let capacity = sizes.iter().sum::<u32>();
+        if capacity == 0 {
+            return None;
         }
+        // The position of a TRB when counting through all segments. A
+        // shrunk segment table may have lost the segment of the driver.
+        let index = |segment: u32, offset: u64| {
+            let before = sizes.get(..segment as usize)?.iter().sum::<u32>();
+            Some(before + (offset / TRB_SIZE as u64) as u32)
+        };
+        let enqueue = index(self.erst_count, self.enqueue_offset)?;
+        let dequeue = index(dequeue_segment, dequeue_offset)?;
+        Some(EventRingOccupancy {
+            used: (enqueue + capacity - dequeue) % capacity,
+            capacity,
+        })
     }
 
     /// Checks whether the Event Ring is full, based on xHCI §4.9.4.
     ///
+    /// The ring is full if the position after the enqueue pointer is the
+    /// dequeue pointer. We compare segment indices and offsets if we know
+    /// the segment of the dequeue pointer, and addresses otherwise.
+    ///
     /// # Return
     /// - `true` if the Event Ring is full and an Event Ring Full Error Event should be enqueued at the current position.
     /// - `false` if there is at least one more slot available.
     fn check_event_ring_full(&self) -> bool {
-        if self.trb_count == 1 {
-            let next_seg = (self.erst_count + 1) % self.erst_size;
-
-            let entry_addr = self.base_address.wrapping_add((next_seg as u64) * 16);
-            let next_seg_pointer = self.dma_bus.read(Request::new(
-                entry_addr.wrapping_add(SEGMENT_BASE),
-                RequestSize::Size8,
-            ));
-
-            self.dequeue_pointer == next_seg_pointer
+        let next_position = if self.trb_count == 1 {
+            ((self.erst_count + 1) % self.erst_size, 0)
         } else {
-            self.dequeue_pointer == self.enqueue_pointer.wrapping_add(TRB_SIZE as u64)
+            (self.erst_count, self.enqueue_offset + TRB_SIZE as u64)
+        };
+
+        match self.dequeue_position {
+            Some(dequeue_position) => dequeue_position == next_position,
+            None if self.trb_count == 1 => self.dequeue_pointer == self.segment(next_position.0).0,
+            None => self.dequeue_pointer == self.enqueue_pointer.wrapping_add(TRB_SIZE as u64),
         }
     }
 
@@ -265,17 +633,8 @@ impl EventRing {
             self.cycle_state = !self.cycle_state;
             self.erst_count = 0;
         }
-        let entry_addr = self
-            .base_address
-            .wrapping_add((self.erst_count as u64) * 16);
-        self.enqueue_pointer = self.dma_bus.read(Request::new(
-            entry_addr.wrapping_add(SEGMENT_BASE),
-            RequestSize::Size8,
-        ));
-        self.trb_count = self.dma_bus.read(Request::new(
-            entry_addr.wrapping_add(SIZE),
-            RequestSize::Size4,
-        )) as u32;
+        (self.enqueue_pointer, self.trb_count) = self.segment(self.erst_count);
+        self.enqueue_offset = 0;
 
         if wrapped {
             trace!(
@@ -384,6 +743,27 @@ impl CommandRing {
         }
     }
 
+    /// Handle writes to the upper dword of the CRCR register.
+    ///
+    /// The dword holds the upper half of the dequeue pointer, which the
+    /// driver can only change while the ring is stopped. Writes while the
+    /// ring runs have no effect.
+    pub fn control_high(&mut self, value: u64) {
+        if self.running {
+            return;
+        }
+        self.dequeue_pointer = self.dequeue_pointer & 0xffff_ffff | value << 32;
+        debug!(
+            "configuring command ring with dp={:#x}",
+            self.dequeue_pointer
+        );
+    }
+
+    /// The address of the next command TRB.
+    pub const fn dequeue_pointer(&self) -> u64 {
+        self.dequeue_pointer
+    }
+
     /// Returns the current value of the `CRCR` register.
     ///
     /// All bits are zero except the CRR bit, which indicates whether the
@@ -404,44 +784,39 @@ impl CommandRing {
     /// Try to retrieve a new command from the command ring.
     ///
     /// This function only returns `CommandTrb`s that represent commands,
-    /// i.e., it will not return Link TRBs. Instead, Link TRBs are handled
-    /// correctly, which is the reason why the function might read two TRBs to
+    /// i.e., it will not return Link TRBs. Instead, Link TRBs are followed,
+    /// which is the reason why the function might read several TRBs to
     /// return a single one.
-    pub fn next_command_trb(&mut self) -> Option<CommandTrb> {
-        // retrieve TRB at dequeue pointer and return None if there is no fresh
-        // TRB
-        let first_trb_buffer = self.next_trb_buffer()?;
-        let first_trb = CommandTrbVariant::parse(first_trb_buffer);
-
-        let final_trb = match first_trb {
-            CommandTrbVariant::Link(link_data) => {
-                // encountered Link TRB
-                // update command ring status
-                self.dequeue_pointer = link_data.ring_segment_pointer;
-                if link_data.toggle_cycle {
-                    self.cycle_state = !self.cycle_state;
-                }
-                // lookup first TRB in the new memory segment
-                let second_trb_buffer = self.next_trb_buffer()?;
-                let second_trb = CommandTrbVariant::parse(second_trb_buffer);
-                if matches!(second_trb, CommandTrbVariant::Link(_)) {
-                    panic!("Link TRB should not follow directly after another Link TRB");
-                }
-                second_trb
+    ///
+    /// Chains of up to [`MAX_CONSECUTIVE_LINK_TRBS`] Link TRBs are
+    /// followed. For longer chains, the function returns an error and stops
+    /// in front of the Link TRB that exceeded the bound.
+    pub fn next_command_trb(&mut self) -> Option<Result<CommandTrb, CommandRingError>> {
+        let mut links_followed = 0;
+        loop {
+            // retrieve TRB at dequeue pointer and return None if there is no
+            // fresh TRB
+            let address = self.dequeue_pointer;
+            let variant = CommandTrbVariant::parse(self.next_trb_buffer()?);
+
+            let CommandTrbVariant::Link(link_data) = variant else {
+                // advance to next TRB
+                self.dequeue_pointer = address.wrapping_add(TRB_SIZE as u64);
+                return Some(Ok(CommandTrb { address, variant }));
+            };
+
+            if links_followed == MAX_CONSECUTIVE_LINK_TRBS {
+                return Some(Err(CommandRingError::LinkTrbChainTooLong { address }));
             }
-            _ => first_trb,
-        };
-
-        let address = self.dequeue_pointer;
-
-        // advance to next TRB
-        self.dequeue_pointer = self.dequeue_pointer.wrapping_add(TRB_SIZE as u64);
+            links_followed += 1;
 
-        // return parsed result
-        Some(CommandTrb {
-            address,
-            variant: final_trb,
-        })
+            // encountered Link TRB
+            // update command ring status and look at the TRB it points to
+            self.dequeue_pointer = link_data.ring_segment_pointer;
+            if link_data.toggle_cycle {
+                self.cycle_state = !self.cycle_state;
+            }
+        }
     }
 
     /// Try to retrieve a fresh command TRB buffer from the command ring.
@@ -457,7 +832,7 @@ impl CommandRing {
         );
 
         // check if the TRB is fresh
-        let cycle_bit = trb_buffer[12] & 0x1 != 0;
+        let cycle_bit = trb_buffer.get_bit(bits::CYCLE);
         if cycle_bit != self.cycle_state {
             // cycle-bit mismatch: no new command TRB available
             return None;
@@ -468,6 +843,19 @@ impl CommandRing {
     }
 }
 
+/// The boundary Transfer Ring segments must end at by default.
+///
+/// Drivers allocate ring segments page by page, so a segment that does not
+/// end with a Link TRB before the end of its page is broken. Walking past
+/// the page would interpret unrelated guest memory as TRBs.
+pub const PAGE_SEGMENT_BOUNDARY: u64 = 0x1000;
+
+/// The boundary no Transfer Ring segment may cross (XHCI spec 6.5).
+///
+/// Used instead of [`PAGE_SEGMENT_BOUNDARY`] for drivers that build
+/// segments from multiple contiguous pages.
+pub const MAX_SEGMENT_BOUNDARY: u64 = 0x10000;
+
 /// Transfer Rings: Unidirectional means of communication, allowing the
 /// driver to send requests over the XHCI controller to device endpoints.
 ///
@@ -475,8 +863,11 @@ impl CommandRing {
 /// convenient methods to access the rings.
 #[derive(Debug)]
 pub struct TransferRing {
-    /// The context of the endpoint that the ring belongs to.
-    endpoint_context: EndpointContext,
+    /// The context of the endpoint or stream that the ring belongs to.
+    context: TransferRingContext,
+    /// Segments have to end with a Link TRB before crossing a multiple of
+    /// this boundary.
+    segment_boundary: u64,
     /// A reference to guest memory.
     dma_bus: BusDeviceRef,
 }
@@ -486,83 +877,151 @@ impl TransferRing {
     ///
     /// # Parameters
     ///
-    /// - `endpoint_context`: the endpoint the rings belongs to.
+    /// - `context`: the endpoint or stream the rings belongs to.
     /// - `dma_bus`: a reference to guest memory.
-    pub fn new(endpoint_context: EndpointContext, dma_bus: BusDeviceRef) -> Self {
+    ///
+    /// The ring expects its segments to end within a page, see
+    /// [`with_segment_boundary`](Self::with_segment_boundary).
+    pub fn new(context: impl Into<TransferRingContext>, dma_bus: BusDeviceRef) -> Self {
         Self {
-            endpoint_context,
+            context: context.into(),
+            segment_boundary: PAGE_SEGMENT_BOUNDARY,
             dma_bus,
         }
     }
 
+    /// Expect segments to end with a Link TRB before crossing a multiple of
+    /// `segment_boundary` instead of a page.
+    pub const fn with_segment_boundary(mut self, segment_boundary: u64) -> Self {
+        assert!(segment_boundary.is_power_of_two() && segment_boundary >= TRB_SIZE as u64);
+        self.segment_boundary = segment_boundary;
+        self
+    }
+
     /// Try to retrieve a new TRB from a transfer ring.
     ///
     /// This function only returns `TransferTrb`s that are not Link TRBs.
-    /// Instead, Link TRBs are handled correctly, which is the reason why the
-    /// function might read two TRBs to return a single one.
-    pub fn next_transfer_trb(&self) -> Option<TransferTrb> {
-        let (mut dequeue_pointer, mut cycle_state) =
-            self.endpoint_context.get_dequeue_pointer_and_cycle_state();
-        // retrieve TRB at dequeue pointer and return None if there is no fresh
-        // TRB
-        let first_trb_buffer = self.next_trb_buffer()?;
-        let first_trb = TransferTrbVariant::parse(first_trb_buffer);
-
-        let final_trb = match first_trb {
-            TransferTrbVariant::Link(link_data) => {
-                // encountered Link TRB
-                // update transfer ring status
-                dequeue_pointer = link_data.ring_segment_pointer;
-                if link_data.toggle_cycle {
-                    cycle_state = !cycle_state;
-                }
-                self.endpoint_context
-                    .set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state);
-                // lookup first TRB in the new memory segment
-                let second_trb_buffer = self.next_trb_buffer()?;
-                let second_trb = TransferTrbVariant::parse(second_trb_buffer);
-                if matches!(second_trb, TransferTrbVariant::Link(_)) {
-                    panic!("Link TRB should not follow directly after another Link TRB");
-                }
-                second_trb
+    /// Instead, Link TRBs are followed, which is the reason why the function
+    /// might read several TRBs to return a single one. Like on the Command
+    /// Ring, chains of more than [`MAX_CONSECUTIVE_LINK_TRBS`] Link TRBs are
+    /// an error, and the ring stays in front of them.
+    ///
+    /// A non-Link TRB in the last slot before a segment boundary means that
+    /// the driver forgot the Link TRB. The ring then stays in front of the
+    /// TRB and reports an error instead of running into the memory behind
+    /// the segment.
+    pub fn next_transfer_trb(&self) -> Option<Result<TransferTrb, TransferRingError>> {
+        let mut position = self.position();
+        let mut window = TrbWindow::new(1);
+        let trb = self.next_transfer_trb_at(&mut position, &mut window)?;
+        if trb.is_ok() {
+            self.set_position(position);
+        }
+        Some(trb)
+    }
+
+    fn position(&self) -> RingPosition {
+        let (dequeue_pointer, cycle_state) = self.context.get_dequeue_pointer_and_cycle_state();
+        RingPosition {
+            dequeue_pointer,
+            cycle_state,
+        }
+    }
+
+    /// The address of the TRB the ring hands out next.
+    pub fn dequeue_pointer(&self) -> u64 {
+        self.position().dequeue_pointer
+    }
+
+    fn set_position(&self, position: RingPosition) {
+        self.context
+            .set_dequeue_pointer_and_cycle_state(position.dequeue_pointer, position.cycle_state);
+    }
+
+    /// Retrieve the TRB at `position` and advance `position` behind it.
+    ///
+    /// This is [`next_transfer_trb`](Self::next_transfer_trb) without the
+    /// accesses to the context, so callers that retrieve several TRBs can
+    /// write the dequeue pointer back once. `window` holds the TRBs read
+    /// ahead of `position`. `position` is only advanced on success.
+    fn next_transfer_trb_at(
+        &self,
+        position: &mut RingPosition,
+        window: &mut TrbWindow,
+    ) -> Option<Result<TransferTrb, TransferRingError>> {
+        let mut next_position = *position;
+        let mut links_followed = 0;
+        let final_trb = loop {
+            // retrieve TRB at dequeue pointer and return None if there is no
+            // fresh TRB
+            let trb = TransferTrbVariant::parse(self.next_trb_buffer(next_position, window)?);
+
+            let TransferTrbVariant::Link(link_data) = trb else {
+                break trb;
+            };
+
+            if links_followed == MAX_CONSECUTIVE_LINK_TRBS {
+                return Some(Err(TransferRingError::LinkTrbChainTooLong {
+                    address: next_position.dequeue_pointer,
+                }));
+            }
+            links_followed += 1;
+
+            // encountered Link TRB
+            // update transfer ring status and look at the TRB it points to
+            next_position.dequeue_pointer = link_data.ring_segment_pointer;
+            if link_data.toggle_cycle {
+                next_position.cycle_state = !next_position.cycle_state;
             }
-            _ => first_trb,
         };
 
-        let address = dequeue_pointer;
+        let address = next_position.dequeue_pointer;
+        if address
+            .wrapping_add(TRB_SIZE as u64)
+            .is_multiple_of(self.segment_boundary)
+        {
+            return Some(Err(TransferRingError::MissingLinkTrb { address }));
+        }
 
         // advance to next TRB
-        dequeue_pointer = dequeue_pointer.wrapping_add(TRB_SIZE as u64);
-        self.endpoint_context
-            .set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state);
+        next_position.dequeue_pointer = address.wrapping_add(TRB_SIZE as u64);
+        *position = next_position;
 
         // return parsed result
-        Some(TransferTrb {
+        Some(Ok(TransferTrb {
             address,
             variant: final_trb,
-        })
+        }))
     }
 
     /// Try to retrieve a new TRB from a transfer ring.
     ///
-    /// If there is a fresh TRB at the dequeue pointer, the function tries to
-    /// parse the transfer TRB and returns the result. If there is a fresh Link
-    /// TRB, this function will return it!
-    fn next_trb_buffer(&self) -> Option<RawTrbBuffer> {
-        let (dequeue_pointer, cycle_state) =
-            self.endpoint_context.get_dequeue_pointer_and_cycle_state();
-        // retrieve TRB at current dequeue_pointer
-        let mut trb_buffer = zeroed_trb_buffer();
-        self.dma_bus.read_bulk(dequeue_pointer, &mut trb_buffer);
+    /// If there is a fresh TRB at `position`, the function returns its raw
+    /// bytes. If there is a fresh Link TRB, this function will return it!
+    ///
+    /// The TRB comes from `window` if the window covers `position`.
+    /// Otherwise, the window is refilled starting at `position`.
+    fn next_trb_buffer(
+        &self,
+        position: RingPosition,
+        window: &mut TrbWindow,
+    ) -> Option<RawTrbBuffer> {
+        let trb_buffer = window.get(position.dequeue_pointer).unwrap_or_else(|| {
+            window.fill(
+                position.dequeue_pointer,
+                self.segment_boundary,
+                &self.dma_bus,
+            )
+        });
 
         debug!(
             "interpreting transfer TRB at dequeue pointer; cycle state = {}, TRB = {:?}",
-            cycle_state as u8, trb_buffer
+            position.cycle_state as u8, trb_buffer
         );
 
         // check if the TRB is fresh
-        let cycle_bit = trb_buffer[12] & 0x1 != 0;
-        if cycle_bit != cycle_state {
+        let cycle_bit = trb_buffer.get_bit(bits::CYCLE);
+        if cycle_bit != position.cycle_state {
             // cycle-bit mismatch: no new TRB available
             return None;
         }
@@ -576,6 +1035,17 @@ impl TransferRing {
     /// Takes setup+data+status TRBs or setup+status TRBs from transfer ring
     /// and extracts the information into a UsbRequest struct.
     ///
+    /// The data of the request is the smaller of the Setup Stage's
+    /// wLength and the Data Stage's TRB Transfer Length. A Data Stage of a
+    /// request with a wLength of 0 is skipped. A request with a wLength,
+    /// but without a Data Stage is returned as is, see
+    /// [`UsbRequest::lacks_data_stage`].
+    ///
+    /// The TRBs of a request are usually contiguous, so they are read from
+    /// guest memory at once, and the dequeue pointer is written back once
+    /// the whole request is parsed. If parsing fails, the ring stays in
+    /// front of the request.
+    ///
     /// # Limitations
     ///
     /// This function currently assumes that all TRBs are available on the
@@ -584,7 +1054,26 @@ impl TransferRing {
     /// partial requests is a valid scenario (and we would have to wait for
     /// the driver to write the missing TRBs).
     pub fn next_request(&self) -> Option<Result<UsbRequest, RequestParseError>> {
-        let first_trb = self.next_transfer_trb()?;
+        let mut position = self.position();
+        let mut window = TrbWindow::new(CONTROL_TRANSFER_MAX_TRBS);
+        let request = self.parse_request(&mut position, &mut window)?;
+        if request.is_ok() {
+            self.set_position(position);
+        }
+        Some(request)
+    }
+
+    /// Parse the control request at `position` and advance `position`
+    /// behind it.
+    fn parse_request(
+        &self,
+        position: &mut RingPosition,
+        window: &mut TrbWindow,
+    ) -> Option<Result<UsbRequest, RequestParseError>> {
+        let first_trb = match self.next_transfer_trb_at(position, window)? {
+            Ok(trb) => trb,
+            Err(err) => return Some(Err(err.into())),
+        };
 
         let setup_trb_data = match first_trb.variant {
             TransferTrbVariant::SetupStage(data) => {
@@ -600,7 +1089,10 @@ impl TransferRing {
             }
         };
 
-        let second_trb = self.next_transfer_trb();
+        let second_trb = match self.next_transfer_trb_at(position, window).transpose() {
+            Ok(trb) => trb,
+            Err(err) => return Some(Err(err.into())),
+        };
         let data_trb_or_address = match second_trb {
             None => {
                 // there should follow either Data or Status Stage
@@ -643,7 +1135,10 @@ impl TransferRing {
                 // the second TRB was a data stage.
                 // We need to retrieve the third TRB and make sure it is a status
                 // stage.
-                let third_trb = self.next_transfer_trb();
+                let third_trb = match self.next_transfer_trb_at(position, window).transpose() {
+                    Ok(trb) => trb,
+                    Err(err) => return Some(Err(err.into())),
+                };
                 let address = match third_trb {
                     None => {
                         // there should follow a Status Stage
@@ -670,6 +1165,20 @@ impl TransferRing {
                 // third TRB was Status Stage.
                 // build request with data pointer and return address of third
                 // TRB.
+                let data = match setup_trb_data.length {
+                    0 => {
+                        debug!(
+                            "skipping Data Stage of {} bytes of a request without data",
+                            data_trb_data.transfer_length
+                        );
+                        None
+                    }
+                    length => Some(DataStage {
+                        pointer: data_trb_data.data_pointer,
+                        length: length
+                            .min(u16::try_from(data_trb_data.transfer_length).unwrap_or(u16::MAX)),
+                    }),
+                };
                 UsbRequest {
                     address,
                     request_type: setup_trb_data.request_type,
@@ -677,7 +1186,7 @@ impl TransferRing {
                     value: setup_trb_data.value,
                     index: setup_trb_data.index,
                     length: setup_trb_data.length,
-                    data: Some(data_trb_data.data_pointer),
+                    data,
                 }
             }
             Err(address) => {
@@ -700,49 +1209,219 @@ impl TransferRing {
     }
 }
 
-#[derive(Error, Debug, PartialEq, Eq)]
-pub enum RequestParseError {
-    #[error("Encountered unexpected TRB type. Expected type(s) {0:?}, got TRB {1:?}")]
-    UnexpectedTrbType(Vec<u8>, TransferTrbVariant),
-    #[error("Expected another TRB, but there was none.")]
-    MissingTrb,
+/// The maximum number of TRBs of a control transfer: a Setup Stage, a Data
+/// Stage, and a Status Stage TRB.
+const CONTROL_TRANSFER_MAX_TRBS: usize = 3;
+
+/// A position on a transfer ring.
+#[derive(Debug, Clone, Copy)]
+struct RingPosition {
+    dequeue_pointer: u64,
+    cycle_state: bool,
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::device::bus::testutils::TestBusDevice;
-    use crate::device::pci::trb::CompletionCode;
-    use std::sync::Arc;
+/// Contiguous TRBs of a transfer ring, read from guest memory at once.
+#[derive(Debug)]
+struct TrbWindow {
+    /// The guest address of the first TRB.
+    address: u64,
+    /// The TRBs, of which only the first `len` are valid.
+    trbs: [RawTrbBuffer; CONTROL_TRANSFER_MAX_TRBS],
+    len: usize,
+    /// The number of TRBs to read on a refill.
+    capacity: usize,
+}
 
-    use super::*;
+impl TrbWindow {
+    fn new(capacity: usize) -> Self {
+        assert!((1..=CONTROL_TRANSFER_MAX_TRBS).contains(&capacity));
+        Self {
+            address: 0,
+            trbs: [zeroed_trb_buffer(); CONTROL_TRANSFER_MAX_TRBS],
+            len: 0,
+            capacity,
+        }
+    }
 
-    fn init_ram_and_ring() -> (Arc<TestBusDevice>, EventRing) {
-        let erste = [
-            // segment 0
-            // segment_base = 0x30
-            // trb_count = 3
-            0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00,
-            // segment 1
-            // segment_base = 0x60
-            // trb_count = 1
-            0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00,
-            // segment 2
-            // segment_base = 0x70
-            // trb_count = 2
-            0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00,
-        ];
+    /// The TRB at `address`, if the window covers it.
+    fn get(&self, address: u64) -> Option<RawTrbBuffer> {
+        let index = address.checked_sub(self.address)? / TRB_SIZE as u64;
+        let index = usize::try_from(index).ok()?;
+        (address.is_multiple_of(TRB_SIZE as u64) && index < self.len).then(|| self.trbs[index])
+    }
 
-        let ram = Arc::new(TestBusDevice::new(&[0; 0x90]));
-        ram.write_bulk(0x0, &erste);
-        let mut ring = EventRing::new(ram.clone());
-        ring.set_erst_size(3);
-        ring.configure(0x0);
-        ring.update_dequeue_pointer(
-            ring.dma_bus
-                .read(Request::new(ring.base_address, RequestSize::Size8)),
+    /// Read the TRBs starting at `address` and return the first one.
+    ///
+    /// The window does not extend across a multiple of `segment_boundary`,
+    /// because the segment ends with a Link TRB before it and the memory
+    /// behind it might not belong to the ring. Link TRBs within the window
+    /// are fine: the ring follows them and refills the window at their
+    /// target.
+    fn fill(
+        &mut self,
+        address: u64,
+        segment_boundary: u64,
+        dma_bus: &BusDeviceRef,
+    ) -> RawTrbBuffer {
+        let trbs_to_boundary = (segment_boundary - address % segment_boundary) / TRB_SIZE as u64;
+        let len = self
+            .capacity
+            .min(usize::try_from(trbs_to_boundary).unwrap_or(usize::MAX))
+            .max(1);
+
+        let mut bytes = [0; TRB_SIZE * CONTROL_TRANSFER_MAX_TRBS];
+        dma_bus.read_bulk(address, &mut bytes[..len * TRB_SIZE]);
+        for (trb, chunk) in self.trbs.iter_mut().zip(bytes.chunks_exact(TRB_SIZE)) {
+            trb.copy_from_slice(chunk);
+        }
+        self.address = address;
+        self.len = len;
+
+        self.trbs[0]
+    }
+}
+
+/// The number of Link TRBs the Command Ring and transfer rings follow in a
+/// row before they give up on finding a TRB.
+///
+/// The specification does not forbid Link TRBs pointing at Link TRBs, but
+/// a driver has no reason to build long chains of them. The bound keeps a
+/// ring that consists only of Link TRBs from stalling the controller.
+pub const MAX_CONSECUTIVE_LINK_TRBS: usize = 8;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TransferRingError {
+    #[error("Non-Link TRB at {address:#x} ends a transfer ring segment")]
+    MissingLinkTrb { address: u64 },
+    #[error("Followed more than {MAX_CONSECUTIVE_LINK_TRBS} consecutive Link TRBs, next one at {address:#x}")]
+    LinkTrbChainTooLong { address: u64 },
+}
+
+impl TransferRingError {
+    /// The address of the TRB the ring stopped in front of.
+    pub const fn address(&self) -> u64 {
+        match *self {
+            Self::MissingLinkTrb { address } | Self::LinkTrbChainTooLong { address } => address,
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CommandRingError {
+    #[error("Followed more than {MAX_CONSECUTIVE_LINK_TRBS} consecutive Link TRBs, next one at {address:#x}")]
+    LinkTrbChainTooLong { address: u64 },
+}
+
+/// The TRBs of an endpoint.
+#[derive(Debug)]
+pub enum EndpointRing {
+    /// An endpoint without streams has a single transfer ring.
+    Single(TransferRing),
+    /// An endpoint with streams has a transfer ring per stream.
+    Streams(Arc<StreamContextArray>),
+}
+
+impl EndpointRing {
+    /// Try to retrieve a new TRB for the endpoint.
+    ///
+    /// With streams, the TRB comes from any of the streams the driver rang
+    /// the doorbell for.
+    pub fn next_transfer_trb(&self) -> Option<Result<TransferTrb, TransferRingError>> {
+        match self {
+            Self::Single(transfer_ring) => transfer_ring.next_transfer_trb(),
+            Self::Streams(streams) => streams.next_transfer_trb().map(|(stream_id, trb)| {
+                trace!("fetched TRB from stream {}", stream_id);
+                trb
+            }),
+        }
+    }
+
+    /// The Stream Context Array, if the endpoint has streams.
+    #[cfg(feature = "nusb-backend")]
+    pub fn streams(&self) -> Option<Arc<StreamContextArray>> {
+        match self {
+            Self::Single(_) => None,
+            Self::Streams(streams) => Some(streams.clone()),
+        }
+    }
+
+    /// The dequeue pointer and cycle state of the transfer ring.
+    ///
+    /// Returns `None` for endpoints with streams, which have no single
+    /// position.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub fn position(&self) -> Option<(u64, bool)> {
+        match self {
+            Self::Single(transfer_ring) => {
+                let position = transfer_ring.position();
+                Some((position.dequeue_pointer, position.cycle_state))
+            }
+            Self::Streams(_) => None,
+        }
+    }
+
+    /// Move the transfer ring back to a [`position`](Self::position) it had
+    /// before, so the TRBs from there on are fetched again.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub fn rewind(&self, (dequeue_pointer, cycle_state): (u64, bool)) {
+        match self {
+            Self::Single(transfer_ring) => transfer_ring.set_position(RingPosition {
+                dequeue_pointer,
+                cycle_state,
+            }),
+            Self::Streams(_) => panic!("Cannot rewind an endpoint with streams"),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RequestParseError {
+    #[error("Encountered unexpected TRB type. Expected type(s) {0:?}, got {1}")]
+    UnexpectedTrbType(Vec<u8>, TransferTrbVariant),
+    #[error("Expected another TRB, but there was none.")]
+    MissingTrb,
+    #[error(transparent)]
+    TransferRing(#[from] TransferRingError),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::bus::testutils::{CountingBusDevice, TestBusDevice};
+    use crate::device::bus::BusDevice;
+    use crate::device::pci::dci::Dci;
+    use crate::device::pci::device_slots::EndpointContext;
+    use crate::device::pci::trb::CompletionCode;
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn init_ram_and_ring() -> (Arc<TestBusDevice>, EventRing) {
+        let erste = [
+            // segment 0
+            // segment_base = 0x30
+            // trb_count = 3
+            0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+            // segment 1
+            // segment_base = 0x60
+            // trb_count = 1
+            0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+            // segment 2
+            // segment_base = 0x70
+            // trb_count = 2
+            0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x90]));
+        ram.write_bulk(0x0, &erste);
+        let mut ring = EventRing::new(ram.clone());
+        ring.set_erst_size(3);
+        ring.configure(0x0);
+        ring.update_dequeue_pointer(
+            ring.dma_bus
+                .read(Request::new(ring.base_address, RequestSize::Size8)),
         );
 
         (ram, ring)
@@ -754,7 +1433,7 @@ mod tests {
             0,                       // trb_transfer_length
             CompletionCode::Success, // completion_code
             false,                   // event_data
-            1,                       // endpoint_id
+            Dci::CONTROL,            // endpoint_id
             1,                       // slot_id
         )
     }
@@ -878,23 +1557,310 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "ERSTSZ must be set before ERSTBA")]
-    fn configure_requires_erstsz_first() {
-        let erste = [
-            0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        ];
+    fn occupancy_counts_unprocessed_trbs_across_segments() {
+        let (_ram, mut ring) = init_ram_and_ring();
+        let occupancy = |used| Some(EventRingOccupancy { used, capacity: 6 });
+        assert!(ring.is_empty());
+        assert_eq!(ring.occupancy(), occupancy(0));
 
-        let ram = Arc::new(TestBusDevice::new(&[0; 0x90]));
-        ram.write_bulk(0x0, &erste);
-        let mut ring = EventRing::new(ram);
+        // segment 0 and 1
+        for _ in 0..4 {
+            ring.enqueue(&dummy_trb());
+        }
+        assert!(!ring.is_empty());
+        assert_eq!(ring.occupancy(), occupancy(4));
+
+        // The driver processed segment 0, we wrap around.
+        ring.update_dequeue_pointer(0x60 | 1);
+        ring.enqueue(&dummy_trb()); // segment 2, TRB 1
+        ring.enqueue(&dummy_trb()); // segment 2, TRB 2 and wraparound
+        ring.enqueue(&dummy_trb()); // segment 0, TRB 1
+        assert_eq!(ring.occupancy(), occupancy(4));
+
+        ring.enqueue(&dummy_trb()); // segment 0, TRB 2
+        let full = ring.occupancy().unwrap();
+        assert!(full.is_full());
+        assert_eq!(full.to_string(), "5/6 TRBs (83%)");
+
+        ring.update_dequeue_pointer(0x30 + 32);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn dequeue_pointer_outside_segments_is_ignored() {
+        let (ram, mut ring) = init_ram_and_ring();
+        ring.enqueue(&dummy_trb());
+        ring.enqueue(&dummy_trb());
+
+        // The driver consumed the first event. The flag bits are not part of
+        // the pointer.
+        ring.update_dequeue_pointer(0x40 | erdp::EHB);
+        assert_eq!(ring.read_dequeue_pointer(), 0x40);
+
+        // Neither memory behind the ring nor the segment table are part of
+        // a segment.
+        ring.update_dequeue_pointer(0x1000);
+        ring.update_dequeue_pointer(0x20);
+        assert_eq!(ring.read_dequeue_pointer(), 0x40);
+        assert_eq!(ring.rejected_dequeue_pointers(), 2);
+
+        // The full check still uses the last valid dequeue pointer.
+        for _ in 0..4 {
+            assert!(!ring.is_full());
+            ring.enqueue(&dummy_trb());
+        }
+        assert_trb_written(&ram, 0x80, true);
+        assert!(ring.is_full());
+    }
+
+    #[test]
+    fn dequeue_pointer_is_checked_against_reconfigured_segments() {
+        let (ram, mut ring) = init_ram_and_ring();
+        ring.enqueue(&dummy_trb());
+        ring.update_dequeue_pointer(0x40);
+
+        // The driver replaces the segment table by one with a single
+        // segment of 3 TRBs at 0x60. The old dequeue pointer is outside.
+        ram.write_bulk(0x0, &[0x60, 0, 0, 0, 0, 0, 0, 0, 0x03, 0, 0, 0, 0, 0, 0, 0]);
+        ring.set_erst_size(1);
         ring.configure(0x0);
-        ring.update_dequeue_pointer(
-            ring.dma_bus
-                .read(Request::new(ring.base_address, RequestSize::Size8)),
-        );
+        assert_eq!(ring.read_dequeue_pointer(), 0x60);
+
+        ring.update_dequeue_pointer(0x40);
+        assert_eq!(ring.read_dequeue_pointer(), 0x60);
+        assert_eq!(ring.rejected_dequeue_pointers(), 1);
+
+        ring.enqueue(&dummy_trb());
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x60, true);
+        assert_trb_written(&ram, 0x70, true);
+        assert!(ring.is_full());
+
+        // The driver consumes both events.
+        ring.update_dequeue_pointer(0x80);
+        assert!(!ring.is_full());
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x80, true);
+    }
+
+    /// Set up a ring with two segments of two TRBs each at the given
+    /// addresses.
+    fn two_segment_ring(segment_0: u64, segment_1: u64) -> (Arc<TestBusDevice>, EventRing) {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x80]));
+        for (entry, base) in [(0x0, segment_0), (0x10, segment_1)] {
+            ram.write_bulk(entry + SEGMENT_BASE, &base.to_le_bytes());
+            ram.write_bulk(entry + SIZE, &2u32.to_le_bytes());
+        }
+        let mut ring = EventRing::new(ram.clone());
+        ring.set_erst_size(2);
+        ring.configure(0x0);
+        (ram, ring)
+    }
+
+    #[test]
+    fn segments_in_reverse_address_order() {
+        // Segment 1 directly precedes segment 0 in memory.
+        let (ram, mut ring) = two_segment_ring(0x60, 0x40);
+        assert_eq!(ring.read_dequeue_pointer(), 0x60);
+
+        for _ in 0..3 {
+            assert!(!ring.is_full());
+            ring.enqueue(&dummy_trb());
+        }
+        assert_trb_written(&ram, 0x60, true);
+        assert_trb_written(&ram, 0x70, true);
+        assert_trb_written(&ram, 0x40, true);
+        assert!(ring.is_full());
+
+        // The driver consumed the events of segment 0.
+        ring.update_dequeue_pointer(0x40 | 1);
+        assert_eq!(ring.read_dequeue_pointer(), 0x40 | 1);
+        for _ in 0..2 {
+            assert!(!ring.is_full());
+            ring.enqueue(&dummy_trb());
+        }
+        assert_trb_written(&ram, 0x50, true);
+        assert_trb_written(&ram, 0x60, false);
+        assert!(ring.is_full());
+    }
+
+    #[test]
+    fn overlapping_segments_are_detected() {
+        let (_, ring) = init_ram_and_ring();
+        assert!(ring.overlapping_segments().is_empty());
+
+        // Segments with two TRBs each, one overlapping the other by one TRB.
+        let (_, ring) = two_segment_ring(0x40, 0x50);
+        assert_eq!(ring.overlapping_segments(), [(0, 1)]);
+        let (_, ring) = two_segment_ring(0x40, 0x40);
+        assert_eq!(ring.overlapping_segments(), [(0, 1)]);
+        // Adjacent segments do not overlap.
+        let (_, ring) = two_segment_ring(0x60, 0x40);
+        assert!(ring.overlapping_segments().is_empty());
+        assert_eq!(ring.contiguous_memory(), Some(0x40..0x80));
+    }
+
+    #[test]
+    fn aliased_segments_are_told_apart_by_desi() {
+        // Both segments use the same memory, so the address of the dequeue
+        // pointer does not tell the segments apart.
+        let (ram, mut ring) = two_segment_ring(0x40, 0x40);
+        for _ in 0..3 {
+            ring.enqueue(&dummy_trb());
+        }
+        // The driver consumed all events and is in the middle of segment 1.
+        ring.update_dequeue_pointer(0x50 | 1);
+
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x50, true);
+        // After the wrap, the next TRB is at the address of the dequeue
+        // pointer, but in segment 0.
+        assert!(!ring.is_full());
+    }
+
+    #[test]
+    fn low_bits_of_erdp_do_not_fake_a_full_ring() {
+        let (_, mut ring) = init_ram_and_ring();
+        for _ in 0..4 {
+            ring.enqueue(&dummy_trb());
+        }
+        // The driver consumed all events. DESI names segment 2, and EHB is
+        // written to clear the flag.
+        ring.update_dequeue_pointer(0x70 | 2 | erdp::EHB);
+        assert_eq!(ring.read_dequeue_pointer(), 0x70 | 2);
+        assert_eq!(ring.rejected_dequeue_pointers(), 0);
+
+        // One TRB stays free for the Event Ring Full Error Event.
+        for _ in 0..5 {
+            assert!(!ring.is_full());
+            ring.enqueue(&dummy_trb());
+        }
+        assert!(ring.is_full());
+
+        // A DESI that names no segment falls back to the address.
+        ring.update_dequeue_pointer(0x60 | 5 | erdp::EHB);
+        assert_eq!(ring.read_dequeue_pointer(), 0x60 | 5);
+        assert!(!ring.is_full());
+    }
+
+    /// The register writes of interrupter setup.
+    #[derive(Debug, Clone, Copy)]
+    enum SetupWrite {
+        Erstsz,
+        Erstba,
+        Erdp,
+    }
+
+    /// Set up a ring with the segment table of [`init_ram_and_ring`] in the
+    /// given register write order.
+    fn setup_in_order(order: [SetupWrite; 3]) -> (Arc<TestBusDevice>, EventRing) {
+        let (ram, mut ring) = init_ram_and_ring();
+        ring.reset();
+
+        for write in order {
+            match write {
+                SetupWrite::Erstsz => ring.set_erst_size(3),
+                SetupWrite::Erstba => ring.configure(0x0),
+                SetupWrite::Erdp => ring.update_dequeue_pointer(0x30),
+            }
+        }
+
+        (ram, ring)
+    }
+
+    /// Check that events land at the start of segment 0 and the ring does
+    /// not consider itself full early.
+    fn assert_ring_usable(ram: &TestBusDevice, ring: &mut EventRing) {
+        ring.enqueue(&dummy_trb());
+        ring.enqueue(&dummy_trb());
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(ram, 0x30, true);
+        assert_trb_written(ram, 0x30 + 16, true);
+        assert_trb_written(ram, 0x30 + 32, true);
+
+        ring.update_dequeue_pointer(0x30 + 32);
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(ram, 0x60, true);
+    }
+
+    #[test]
+    fn setup_erstsz_erstba_erdp() {
+        use SetupWrite::*;
+        let (ram, mut ring) = setup_in_order([Erstsz, Erstba, Erdp]);
+        assert_ring_usable(&ram, &mut ring);
+    }
+
+    #[test]
+    fn setup_erdp_before_segment_table() {
+        use SetupWrite::*;
+        let (ram, mut ring) = setup_in_order([Erdp, Erstsz, Erstba]);
+        assert_ring_usable(&ram, &mut ring);
+    }
+
+    #[test]
+    fn setup_erstba_before_erstsz() {
+        use SetupWrite::*;
+        let (ram, mut ring) = setup_in_order([Erstba, Erstsz, Erdp]);
+        assert_ring_usable(&ram, &mut ring);
+    }
+
+    #[test]
+    fn zero_erstsz_unconfigures_the_ring() {
+        use SetupWrite::*;
+        let (ram, mut ring) = setup_in_order([Erstsz, Erstba, Erdp]);
+        // Linux writes ERSTSZ = 0 when it removes the interrupter.
+        ring.set_erst_size(0);
+        assert_eq!(ring.read_erst_size(), 0);
+        assert!(ring.is_full());
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x30, false);
+        assert_eq!(ring.unconfigured_drops(), 1);
+
+        // The driver binds again and sets the ring up anew.
+        ring.set_erst_size(3);
+        assert_ring_usable(&ram, &mut ring);
+    }
+
+    #[test]
+    fn reserved_erstba_bits_are_ignored() {
+        use SetupWrite::*;
+        let (ram, mut ring) = setup_in_order([Erstsz, Erdp, Erdp]);
+        ring.configure(0x3f);
+        assert_eq!(ring.read_base_address(), 0x0);
+        assert_ring_usable(&ram, &mut ring);
+    }
+
+    #[test]
+    fn unconfigured_ring_drops_events() {
+        let (ram, mut ring) = setup_in_order([SetupWrite::Erstba; 3]);
+        assert!(ring.is_full());
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x30, false);
+        assert_eq!(ring.unconfigured_drops(), 1);
+
+        // Nothing lands at the enqueue pointer of the unconfigured ring.
+        ring.reset();
+        ring.enqueue(&dummy_trb());
+        assert_eq!(ram.read(Request::new(0, RequestSize::Size8)), 0x30);
+        assert_eq!(ring.unconfigured_drops(), 2);
+    }
+
+    #[test]
+    fn setup_again_after_reset() {
+        use SetupWrite::*;
+        let (ram, mut ring) = setup_in_order([Erstsz, Erstba, Erdp]);
+        ring.enqueue(&dummy_trb());
+        ring.enqueue(&dummy_trb());
+
+        // After controller reset, the driver clears the ring memory and
+        // programs the registers again, this time in a different order.
+        ram.write_bulk(0x30, &[0; 0x60]);
+        ring.reset();
+        ring.configure(0x0);
+        ring.update_dequeue_pointer(0x30);
+        ring.set_erst_size(3);
+
+        assert_ring_usable(&ram, &mut ring);
     }
 
     #[test]
@@ -997,6 +1963,45 @@ mod tests {
         assert_trb_written(&ram, 0x30, false);
     }
 
+    #[test]
+    fn relocated_segment_table_restarts_the_ring() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        // The initial table at 0x0 has a single segment of 3 TRBs at 0x40.
+        ram.write_bulk(0x0, &[0x40, 0, 0, 0, 0, 0, 0, 0, 0x03, 0, 0, 0]);
+        let mut ring = EventRing::new(ram.clone());
+        ring.set_erst_size(1);
+        ring.configure(0x0);
+        ring.update_dequeue_pointer(0x40);
+
+        ring.enqueue(&dummy_trb());
+        ring.enqueue(&dummy_trb());
+        ring.update_dequeue_pointer(0x40 + 16);
+
+        // The new table at 0x100 has a single segment of 2 TRBs at 0x140,
+        // which holds a stale TRB with the cycle bit set.
+        ram.write_bulk(0x100, &[0x40, 0x01, 0, 0, 0, 0, 0, 0, 0x02, 0, 0, 0]);
+        ram.write_bulk(0x140 + 16 + 12, &[0x1]);
+        ring.configure(0x100);
+        assert_eq!(ring.read_base_address(), 0x100);
+        assert_eq!(ring.read_dequeue_pointer() & !0xf, 0x140);
+
+        // The ring is empty, so one TRB fits before it is full.
+        assert!(!ring.is_full());
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x140, true);
+        assert!(ring.is_full());
+
+        // The old segment is no longer written.
+        assert_trb_written(&ram, 0x40 + 32, false);
+
+        ring.update_dequeue_pointer(0x140 + 16);
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x140 + 16, true);
+        ring.update_dequeue_pointer(0x140);
+        ring.enqueue(&dummy_trb());
+        assert_trb_written(&ram, 0x140, false);
+    }
+
     #[test]
     fn command_ring_single_segment_traversal() {
         let noop_command = [
@@ -1025,10 +2030,10 @@ mod tests {
         ram.write_bulk(12, &[0x1]);
 
         // ring abstraction should parse correctly
-        let expected = Some(CommandTrb {
+        let expected = Some(Ok(CommandTrb {
             address: 0,
             variant: CommandTrbVariant::NoOp,
-        });
+        }));
         assert_eq!(command_ring.next_command_trb(), expected);
 
         // no new command placed, should return no new command
@@ -1046,17 +2051,17 @@ mod tests {
         ram.write_bulk(32 + 12, &[0x1]);
 
         // parse first noop
-        let expected = Some(CommandTrb {
+        let expected = Some(Ok(CommandTrb {
             address: 16,
             variant: CommandTrbVariant::NoOp,
-        });
+        }));
         assert_eq!(command_ring.next_command_trb(), expected);
 
         // parse second noop
-        let expected = Some(CommandTrb {
+        let expected = Some(Ok(CommandTrb {
             address: 32,
             variant: CommandTrbVariant::NoOp,
-        });
+        }));
         assert_eq!(command_ring.next_command_trb(), expected);
 
         // no new command placed, should return no new command
@@ -1087,10 +2092,119 @@ mod tests {
         ram.write_bulk(12, &[0x0]);
 
         // parse refreshed noop
-        let expected = Some(CommandTrb {
+        let expected = Some(Ok(CommandTrb {
             address: 0,
             variant: CommandTrbVariant::NoOp,
-        });
+        }));
+        assert_eq!(command_ring.next_command_trb(), expected);
+    }
+
+    fn noop_command_trb(cycle: bool) -> [u8; 16] {
+        let mut trb = [0; 16];
+        trb[12] = cycle.into();
+        trb[13] = trb_types::NO_OP_COMMAND << 2;
+        trb
+    }
+
+    fn link_trb(target: u64, toggle_cycle: bool, cycle: bool) -> [u8; 16] {
+        let mut trb = [0; 16];
+        trb[0..8].copy_from_slice(&target.to_le_bytes());
+        trb[12] = u8::from(toggle_cycle) << 1 | u8::from(cycle);
+        trb[13] = trb_types::LINK << 2;
+        trb
+    }
+
+    fn noop_at(address: u64) -> Option<Result<CommandTrb, CommandRingError>> {
+        Some(Ok(CommandTrb {
+            address,
+            variant: CommandTrbVariant::NoOp,
+        }))
+    }
+
+    #[test]
+    fn command_ring_starting_with_link_trb() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let mut command_ring = CommandRing::new(ram.clone());
+        // Start with a cycle state of 0, so the zeroed TRBs after the jump
+        // are not fresh.
+        command_ring.control(0x0);
+
+        // The first TRB jumps to the actual segment and toggles the cycle
+        // state, so the command there has to carry the flipped cycle bit.
+        ram.write_bulk(0x0, &link_trb(0x40, true, false));
+        ram.write_bulk(0x40, &noop_command_trb(true));
+
+        assert_eq!(command_ring.next_command_trb(), noop_at(0x40));
+        assert_eq!(command_ring.next_command_trb(), None);
+
+        ram.write_bulk(0x50, &noop_command_trb(true));
+        assert_eq!(command_ring.next_command_trb(), noop_at(0x50));
+    }
+
+    #[test]
+    fn command_ring_follows_consecutive_link_trbs() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let mut command_ring = CommandRing::new(ram.clone());
+        command_ring.control(0x1);
+
+        ram.write_bulk(0x0, &link_trb(0x40, false, true));
+        ram.write_bulk(0x40, &link_trb(0x80, true, true));
+        ram.write_bulk(0x80, &noop_command_trb(false));
+
+        assert_eq!(command_ring.next_command_trb(), noop_at(0x80));
+    }
+
+    #[test]
+    fn command_ring_two_segments_multiple_passes() {
+        const SEGMENTS: [u64; 2] = [0x0, 0x100];
+        const COMMANDS_PER_SEGMENT: u64 = 3;
+
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        let mut command_ring = CommandRing::new(ram.clone());
+        command_ring.control(0x1);
+
+        for pass in 0..4 {
+            let cycle = pass % 2 == 0;
+
+            // Fill both segments. The first one links to the second, which
+            // links back and toggles the cycle state.
+            for (index, &segment) in SEGMENTS.iter().enumerate() {
+                for command in 0..COMMANDS_PER_SEGMENT {
+                    ram.write_bulk(segment + command * 16, &noop_command_trb(cycle));
+                }
+                let next = SEGMENTS[(index + 1) % SEGMENTS.len()];
+                ram.write_bulk(
+                    segment + COMMANDS_PER_SEGMENT * 16,
+                    &link_trb(next, next == SEGMENTS[0], cycle),
+                );
+            }
+
+            for segment in SEGMENTS {
+                for command in 0..COMMANDS_PER_SEGMENT {
+                    assert_eq!(
+                        command_ring.next_command_trb(),
+                        noop_at(segment + command * 16),
+                        "pass {pass}"
+                    );
+                }
+            }
+            assert_eq!(command_ring.next_command_trb(), None, "pass {pass}");
+        }
+    }
+
+    #[test]
+    fn command_ring_bounds_link_trb_chains() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let mut command_ring = CommandRing::new(ram.clone());
+        command_ring.control(0x1);
+
+        // A Link TRB pointing to itself never leads to a command.
+        ram.write_bulk(0x40, &link_trb(0x40, false, true));
+        ram.write_bulk(0x0, &link_trb(0x40, false, true));
+
+        let expected = Some(Err(CommandRingError::LinkTrbChainTooLong { address: 0x40 }));
+        assert_eq!(command_ring.next_command_trb(), expected);
+        // The ring stays in front of the chain.
         assert_eq!(command_ring.next_command_trb(), expected);
     }
 
@@ -1115,7 +2229,7 @@ mod tests {
             0x00, 0x00,
         ];
         let data = [
-            0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c,
+            0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x40, 0x00, 0x00, 0x00, 0x00, 0x0c,
             0x00, 0x00,
         ];
         let status = [
@@ -1164,7 +2278,10 @@ mod tests {
             value: 0x3344,
             index: 0x5566,
             length: 0x7788,
-            data: Some(0x1122334455667788),
+            data: Some(DataStage {
+                pointer: 0x1122334455667788,
+                length: 0x40,
+            }),
         }));
         assert_eq!(transfer_ring.next_request(), expected);
 
@@ -1213,4 +2330,261 @@ mod tests {
             request
         );
     }
+
+    /// Write a fresh TRB of the given type, with the Cycle bit set to
+    /// `cycle`, leaving the rest of the TRB to `trb`.
+    fn write_trb(ram: &TestBusDevice, address: u64, trb: [u8; 16], cycle: bool) {
+        let mut trb = trb;
+        trb[12] = trb[12] & !0x1 | u8::from(cycle);
+        ram.write_bulk(address, &trb);
+    }
+
+    fn control_trb(trb_type: u8) -> [u8; 16] {
+        let mut trb = [0; 16];
+        trb[13] = trb_type << 2;
+        trb
+    }
+
+    /// A control transfer ring of 5 TRBs at 0x0, whose endpoint context
+    /// lies behind the ring.
+    fn counting_control_ring() -> (Arc<CountingBusDevice>, TransferRing) {
+        let ram = Arc::new(CountingBusDevice::new(&[0; TRB_SIZE * 5 + 32]));
+        let ep = EndpointContext::new(TRB_SIZE as u64 * 5, ram.clone());
+        ep.set_dequeue_pointer_and_cycle_state(0, true);
+        let transfer_ring = TransferRing::new(ep, ram.clone());
+        (ram, transfer_ring)
+    }
+
+    #[test]
+    fn control_request_is_read_at_once() {
+        let (ram, transfer_ring) = counting_control_ring();
+        write_trb(&ram.memory, 0x0, control_trb(trb_types::SETUP_STAGE), true);
+        write_trb(&ram.memory, 0x10, control_trb(trb_types::DATA_STAGE), true);
+        write_trb(
+            &ram.memory,
+            0x20,
+            control_trb(trb_types::STATUS_STAGE),
+            true,
+        );
+        ram.take_operations();
+
+        let request = transfer_ring.next_request().unwrap().unwrap();
+        assert_eq!(request.address, 0x20);
+        // One read of the dequeue pointer, one of the TRBs, and one write of
+        // the dequeue pointer.
+        assert_eq!(ram.take_operations(), 3);
+    }
+
+    #[test]
+    fn control_request_across_link_trb_is_read_in_two_parts() {
+        let (ram, transfer_ring) = counting_control_ring();
+        let ep = EndpointContext::new(TRB_SIZE as u64 * 5, ram.clone());
+        ep.set_dequeue_pointer_and_cycle_state(0x30, true);
+        let mut link = control_trb(trb_types::LINK);
+        link[12] = 0x2;
+        write_trb(&ram.memory, 0x30, control_trb(trb_types::SETUP_STAGE), true);
+        write_trb(&ram.memory, 0x40, link, true);
+        write_trb(
+            &ram.memory,
+            0x0,
+            control_trb(trb_types::STATUS_STAGE),
+            false,
+        );
+        ram.take_operations();
+
+        let request = transfer_ring.next_request().unwrap().unwrap();
+        assert_eq!(request.address, 0x0);
+        // The TRBs behind the Link TRB need a second read.
+        assert_eq!(ram.take_operations(), 4);
+        assert_eq!(ep.get_dequeue_pointer_and_cycle_state(), (0x10, false));
+    }
+
+    #[test]
+    fn partial_control_request_leaves_dequeue_pointer_alone() {
+        let (ram, transfer_ring) = counting_control_ring();
+        let ep = EndpointContext::new(TRB_SIZE as u64 * 5, ram.clone());
+        write_trb(&ram.memory, 0x0, control_trb(trb_types::SETUP_STAGE), true);
+        ram.take_operations();
+
+        assert_eq!(
+            transfer_ring.next_request(),
+            Some(Err(RequestParseError::MissingTrb))
+        );
+        assert_eq!(ram.take_operations(), 2, "no write on failure");
+        assert_eq!(ep.get_dequeue_pointer_and_cycle_state(), (0x0, true));
+
+        // The driver completes the request, so we can parse it.
+        write_trb(
+            &ram.memory,
+            0x10,
+            control_trb(trb_types::STATUS_STAGE),
+            true,
+        );
+        let request = transfer_ring.next_request().unwrap().unwrap();
+        assert_eq!(request.address, 0x10);
+        assert_eq!(ep.get_dequeue_pointer_and_cycle_state(), (0x20, true));
+    }
+
+    /// A Setup Stage TRB with the given wLength.
+    fn setup_trb(length: u16) -> [u8; 16] {
+        let mut trb = control_trb(trb_types::SETUP_STAGE);
+        trb[0] = 0x80;
+        trb[1] = 0x06;
+        trb[6..8].copy_from_slice(&length.to_le_bytes());
+        trb
+    }
+
+    /// A Data Stage TRB with the given buffer.
+    fn data_trb(pointer: u64, transfer_length: u32) -> [u8; 16] {
+        let mut trb = control_trb(trb_types::DATA_STAGE);
+        trb[0..8].copy_from_slice(&pointer.to_le_bytes());
+        trb[8..12].copy_from_slice(&transfer_length.to_le_bytes());
+        trb
+    }
+
+    /// Parse a request of the given TRBs.
+    fn parse_control_trbs(trbs: &[[u8; 16]]) -> UsbRequest {
+        let (ram, transfer_ring) = counting_control_ring();
+        for (index, trb) in trbs.iter().enumerate() {
+            write_trb(&ram.memory, TRB_SIZE as u64 * index as u64, *trb, true);
+        }
+        transfer_ring.next_request().unwrap().unwrap()
+    }
+
+    #[test]
+    fn data_stage_of_request_without_data_is_skipped() {
+        let request = parse_control_trbs(&[
+            setup_trb(0),
+            data_trb(0x1000, 0x40),
+            control_trb(trb_types::STATUS_STAGE),
+        ]);
+        assert_eq!(request.address, 0x20);
+        assert_eq!(request.data, None);
+        assert!(!request.lacks_data_stage());
+    }
+
+    #[test]
+    fn request_without_data_stage_is_parsed() {
+        let request = parse_control_trbs(&[setup_trb(0x12), control_trb(trb_types::STATUS_STAGE)]);
+        assert_eq!(request.address, 0x10);
+        assert_eq!(request.length, 0x12);
+        assert_eq!(request.data, None);
+        assert!(request.lacks_data_stage());
+    }
+
+    #[test]
+    fn data_is_clamped_to_the_smaller_stage() {
+        for (length, transfer_length, expected) in [
+            (0x12, 0x40, 0x12),
+            (0xff, 0x40, 0x40),
+            (0x40, 0x1_0000, 0x40),
+        ] {
+            let request = parse_control_trbs(&[
+                setup_trb(length),
+                data_trb(0x1000, transfer_length),
+                control_trb(trb_types::STATUS_STAGE),
+            ]);
+            assert_eq!(request.length, length);
+            assert_eq!(
+                request.data,
+                Some(DataStage {
+                    pointer: 0x1000,
+                    length: expected
+                })
+            );
+        }
+    }
+
+    fn normal_trb() -> [u8; 16] {
+        let mut trb = [0; 16];
+        trb[12] = 0x1;
+        trb[13] = trb_types::NORMAL << 2;
+        trb
+    }
+
+    /// A transfer ring whose dequeue pointer is two TRBs before the end of
+    /// a page. The driver filled the last slot of the page with a Normal
+    /// TRB instead of a Link TRB, and the memory behind the page happens to
+    /// look like a fresh TRB.
+    fn transfer_ring_without_link_trb() -> (Arc<TestBusDevice>, EndpointContext) {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1100]));
+        for address in [0xfe0, 0xff0, 0x1000] {
+            ram.write_bulk(address, &normal_trb());
+        }
+        let ep = EndpointContext::new(0x1080, ram.clone());
+        ep.set_dequeue_pointer_and_cycle_state(0xfe0, true);
+        (ram, ep)
+    }
+
+    #[test]
+    fn transfer_ring_stops_at_missing_link_trb() {
+        let (ram, ep) = transfer_ring_without_link_trb();
+        let transfer_ring = TransferRing::new(ep, ram);
+
+        assert_eq!(
+            transfer_ring.next_transfer_trb().unwrap().unwrap().address,
+            0xfe0
+        );
+
+        let expected = Some(Err(TransferRingError::MissingLinkTrb { address: 0xff0 }));
+        assert_eq!(transfer_ring.next_transfer_trb(), expected);
+        // The ring does not advance into the memory behind the page.
+        assert_eq!(transfer_ring.next_transfer_trb(), expected);
+    }
+
+    #[test]
+    fn transfer_ring_bounds_link_trb_chains() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let ep = EndpointContext::new(0x80, ram.clone());
+        ep.set_dequeue_pointer_and_cycle_state(0x0, true);
+        let transfer_ring = TransferRing::new(ep, ram.clone());
+
+        // A Link TRB pointing to itself never leads to a transfer.
+        ram.write_bulk(0x40, &link_trb(0x40, false, true));
+        ram.write_bulk(0x0, &link_trb(0x40, false, true));
+
+        let expected = Some(Err(TransferRingError::LinkTrbChainTooLong {
+            address: 0x40,
+        }));
+        assert_eq!(transfer_ring.next_transfer_trb(), expected);
+        // The ring stays in front of the chain.
+        assert_eq!(transfer_ring.next_transfer_trb(), expected);
+
+        // Two Link TRBs in a row are fine.
+        ram.write_bulk(0x40, &link_trb(0x60, false, true));
+        ram.write_bulk(0x60, &normal_trb());
+        assert_eq!(
+            transfer_ring.next_transfer_trb().unwrap().unwrap().address,
+            0x60
+        );
+    }
+
+    #[test]
+    fn transfer_ring_segments_may_span_pages_when_allowed() {
+        let (ram, ep) = transfer_ring_without_link_trb();
+        let transfer_ring = TransferRing::new(ep, ram).with_segment_boundary(MAX_SEGMENT_BOUNDARY);
+
+        for address in [0xfe0, 0xff0, 0x1000] {
+            assert_eq!(
+                transfer_ring.next_transfer_trb().unwrap().unwrap().address,
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn control_request_stops_at_missing_link_trb() {
+        let (ram, ep) = transfer_ring_without_link_trb();
+        ep.set_dequeue_pointer_and_cycle_state(0xff0, true);
+        let mut setup = normal_trb();
+        setup[13] = trb_types::SETUP_STAGE << 2;
+        ram.write_bulk(0xff0, &setup);
+
+        assert_eq!(
+            TransferRing::new(ep, ram).next_request(),
+            Some(Err(RequestParseError::TransferRing(
+                TransferRingError::MissingLinkTrb { address: 0xff0 }
+            )))
+        );
+    }
 }
@@ -0,0 +1,206 @@
+//! # Run/Stop State
+//!
+//! The driver stops the controller by clearing USBCMD.R/S and may start it
+//! again later, e.g., around suspend or while it reconfigures the
+//! controller. A stopped controller must not process any ring, but must
+//! resume exactly where it left off once started again (xHCI 4.2, 5.4.1).
+//!
+//! [`RunState`] is the gate that the controller and all endpoint workers
+//! pass before they touch a ring. While the controller is stopped:
+//!
+//! - Doorbells are remembered instead of processed: a flag for the
+//!   Host Controller Doorbell and a bitmask of Endpoint IDs per slot.
+//!   Workers that find the gate closed remember their endpoint as well and
+//!   go idle like with an empty transfer ring.
+//! - Starting the controller hands out the remembered doorbells, which the
+//!   controller then rings again.
+//!
+//! The controller only reports itself halted (USBSTS.HCH) once no worker
+//! is in the middle of fetching from its transfer ring. Transfers that a
+//! worker already handed to the device still complete, like the
+//! transactions a real controller finishes before it halts.
+
+use std::sync::Mutex;
+
+use super::constants::xhci::MAX_SLOTS;
+
+/// The doorbells that rang while the controller was stopped.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PendingDoorbells {
+    /// Whether the Host Controller Doorbell rang.
+    pub command_ring: bool,
+    /// The Endpoint IDs whose doorbell rang, as a bitmask per slot.
+    pub endpoints: [u32; MAX_SLOTS as usize],
+}
+
+impl PendingDoorbells {
+    /// The slots and Endpoint IDs whose doorbell rang, in order.
+    pub fn endpoint_doorbells(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        (1..=MAX_SLOTS as u8).flat_map(move |slot_id| {
+            let mask = self.endpoints[usize::from(slot_id) - 1];
+            (1..=31)
+                .filter(move |&endpoint_id| mask & 1 << endpoint_id != 0)
+                .map(move |endpoint_id| (slot_id, endpoint_id))
+        })
+    }
+}
+
+/// Whether the controller runs, shared with all endpoint workers.
+#[derive(Debug, Default)]
+pub struct RunState {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: bool,
+    /// The workers that currently fetch from their transfer ring.
+    ring_accesses: usize,
+    pending: PendingDoorbells,
+}
+
+impl State {
+    /// Remember the doorbell of an endpoint if the controller is stopped.
+    fn defer(&mut self, slot_id: u8, endpoint_id: u8) -> bool {
+        if self.running {
+            return false;
+        }
+        if let Some(mask) = self
+            .pending
+            .endpoints
+            .get_mut(usize::from(slot_id).wrapping_sub(1))
+        {
+            *mask |= 1 << (endpoint_id & 0x1f);
+        }
+        true
+    }
+}
+
+impl RunState {
+    /// Whether the controller stopped and all ring processing ended
+    /// (USBSTS.HCH).
+    pub fn is_halted(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.running && state.ring_accesses == 0
+    }
+
+    /// Start the controller and take the doorbells that rang while it was
+    /// stopped.
+    pub fn start(&self) -> PendingDoorbells {
+        let mut state = self.state.lock().unwrap();
+        state.running = true;
+        std::mem::take(&mut state.pending)
+    }
+
+    /// Stop the controller. Workers finish fetching the TD at hand.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().running = false;
+    }
+
+    /// Forget the doorbells of a stopped controller, e.g., because the
+    /// driver reset it and the rings are gone.
+    pub fn forget_doorbells(&self) {
+        self.state.lock().unwrap().pending = PendingDoorbells::default();
+    }
+
+    /// Remember a ring of the Host Controller Doorbell if the controller
+    /// is stopped.
+    ///
+    /// Returns whether the doorbell was remembered, i.e., the caller must
+    /// not process the Command Ring.
+    pub fn defer_command_ring(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.running {
+            return false;
+        }
+        state.pending.command_ring = true;
+        true
+    }
+
+    /// Remember a ring of the doorbell of an endpoint if the controller is
+    /// stopped.
+    ///
+    /// Returns whether the doorbell was remembered, i.e., the caller must
+    /// not process the transfer ring.
+    pub fn defer_doorbell(&self, slot_id: u8, endpoint_id: u8) -> bool {
+        self.state.lock().unwrap().defer(slot_id, endpoint_id)
+    }
+
+    /// Begin fetching from the transfer ring of an endpoint.
+    ///
+    /// Returns `None` and remembers the endpoint if the controller is
+    /// stopped. The worker then has to go idle until the controller rings
+    /// its doorbell again.
+    pub fn enter(&self, slot_id: u8, endpoint_id: u8) -> Option<RingAccess<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.defer(slot_id, endpoint_id) {
+            return None;
+        }
+        state.ring_accesses += 1;
+        drop(state);
+        Some(RingAccess(self))
+    }
+}
+
+/// A worker's fetch from its transfer ring, which holds off USBSTS.HCH.
+#[derive(Debug)]
+pub struct RingAccess<'a>(&'a RunState);
+
+impl Drop for RingAccess<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().ring_accesses -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doorbells_of_a_stopped_controller_are_replayed_on_start() {
+        let run_state = RunState::default();
+        assert!(run_state.defer_command_ring());
+        assert!(run_state.defer_doorbell(1, 3));
+        assert!(run_state.defer_doorbell(2, 1));
+        assert!(run_state.defer_doorbell(1, 3));
+
+        let pending = run_state.start();
+        assert!(pending.command_ring);
+        assert_eq!(
+            pending.endpoint_doorbells().collect::<Vec<_>>(),
+            [(1, 3), (2, 1)]
+        );
+
+        // A running controller processes doorbells right away.
+        assert!(!run_state.defer_command_ring());
+        assert!(!run_state.defer_doorbell(1, 3));
+        run_state.stop();
+        assert_eq!(run_state.start(), PendingDoorbells::default());
+    }
+
+    #[test]
+    fn workers_pause_while_the_controller_is_stopped() {
+        let run_state = RunState::default();
+        assert!(run_state.enter(1, 4).is_none());
+        assert_eq!(
+            run_state.start().endpoint_doorbells().collect::<Vec<_>>(),
+            [(1, 4)]
+        );
+
+        let access = run_state.enter(1, 4).unwrap();
+        run_state.stop();
+        // The fetch at hand holds off the halt.
+        assert!(!run_state.is_halted());
+        drop(access);
+        assert!(run_state.is_halted());
+    }
+
+    #[test]
+    fn reset_forgets_pending_doorbells() {
+        let run_state = RunState::default();
+        run_state.defer_command_ring();
+        run_state.defer_doorbell(MAX_SLOTS as u8, 31);
+        run_state.forget_doorbells();
+        assert_eq!(run_state.start(), PendingDoorbells::default());
+    }
+}
@@ -0,0 +1,410 @@
+//! # Host Bus Transfer Scheduling
+//!
+//! Every enabled endpoint is serviced by its own worker, which submits
+//! transfers to the real device as fast as the guest fills the transfer
+//! ring. When several devices share a physical host bus, a bulk storage
+//! device can keep the bus busy and increase the latency of interrupt
+//! endpoints (e.g., HID devices) on the same bus.
+//!
+//! This module provides a small permit system to arbitrate host-side
+//! submissions. Each physical host bus gets a [`BulkPermits`] instance that
+//! caps the number of concurrently outstanding bulk transfers. Interrupt and
+//! control transfers never take a permit and are thus always admitted.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
+    sync::{Arc, Condvar, Mutex},
+    task::{Poll, Waker},
+};
+
+/// A fair, semaphore-like limit for outstanding bulk transfers on one host
+/// bus.
+///
+/// Waiters are admitted in the order they asked for a permit, so multiple
+/// bulk workers take turns instead of one worker re-acquiring the permit
+/// over and over. Permits can be acquired by blocking worker threads and
+/// by endpoint futures alike.
+#[derive(Debug)]
+pub struct BulkPermits {
+    /// The maximum number of outstanding bulk transfers. `None` means
+    /// unlimited.
+    limit: Option<NonZeroUsize>,
+    /// The mutable part of the permit state.
+    state: Mutex<PermitState>,
+    /// Signalled whenever a permit is released.
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PermitState {
+    /// The number of permits currently handed out.
+    outstanding: usize,
+    /// The ticket that is handed out to the next waiter.
+    next_ticket: u64,
+    /// Tickets of waiters in arrival order.
+    waiters: VecDeque<u64>,
+    /// Wakers of endpoint futures waiting for a permit.
+    wakers: Vec<Waker>,
+}
+
+impl PermitState {
+    /// Queue up a new waiter and return its ticket.
+    fn enqueue(&mut self) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiters.push_back(ticket);
+        ticket
+    }
+
+    /// Hand out a permit to the waiter with `ticket` if it is its turn and
+    /// the limit allows it.
+    fn try_admit(&mut self, limit: NonZeroUsize, ticket: u64) -> bool {
+        let admit = self.outstanding < limit.get() && self.waiters.front() == Some(&ticket);
+        if admit {
+            self.waiters.pop_front();
+            self.outstanding += 1;
+        }
+        admit
+    }
+}
+
+impl BulkPermits {
+    /// Create a new permit pool.
+    ///
+    /// # Parameters
+    ///
+    /// - `limit`: the maximum number of concurrently outstanding bulk
+    ///   transfers, or `None` to not limit bulk transfers at all.
+    pub fn new(limit: Option<NonZeroUsize>) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(PermitState::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a permit is available and take it.
+    ///
+    /// The permit is returned when the returned [`BulkPermit`] is dropped,
+    /// i.e., the caller should hold on to it until the transfer completed.
+    // The lint does not see that `wake_waiters` consumes the guard.
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn acquire(self: &Arc<Self>) -> BulkPermit {
+        let Some(limit) = self.limit else {
+            return BulkPermit { permits: None };
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.enqueue();
+
+        while !state.try_admit(limit, ticket) {
+            state = self.released.wait(state).unwrap();
+        }
+
+        // Other waiters might also fit within the limit now that the queue
+        // moved on.
+        self.wake_waiters(state);
+
+        BulkPermit {
+            permits: Some(self.clone()),
+        }
+    }
+
+    /// Wait until a permit is available and take it.
+    ///
+    /// This is the non-blocking variant of [`acquire`](Self::acquire) for
+    /// endpoint futures. Dropping the future before it completes gives up
+    /// the place in the queue.
+    pub async fn acquire_async(self: &Arc<Self>) -> BulkPermit {
+        let Some(limit) = self.limit else {
+            return BulkPermit { permits: None };
+        };
+
+        let mut queued = QueuedTicket {
+            permits: self,
+            ticket: self.state.lock().unwrap().enqueue(),
+            admitted: false,
+        };
+
+        std::future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.try_admit(limit, queued.ticket) {
+                queued.admitted = true;
+                self.wake_waiters(state);
+                Poll::Ready(())
+            } else {
+                state.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+
+        BulkPermit {
+            permits: Some(self.clone()),
+        }
+    }
+
+    /// Take a permit if one is available right away.
+    ///
+    /// Returns `None` if the limit is reached or others are waiting for a
+    /// permit, so workers that already have a transfer in flight do not
+    /// jump the queue.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<BulkPermit> {
+        let Some(limit) = self.limit else {
+            return Some(BulkPermit { permits: None });
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if !state.waiters.is_empty() || state.outstanding >= limit.get() {
+            return None;
+        }
+        state.outstanding += 1;
+        drop(state);
+
+        Some(BulkPermit {
+            permits: Some(self.clone()),
+        })
+    }
+
+    /// The number of permits that are currently handed out.
+    #[cfg(test)]
+    pub fn outstanding(&self) -> usize {
+        self.state.lock().unwrap().outstanding
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.outstanding -= 1;
+        self.wake_waiters(state);
+    }
+
+    /// Let all waiters re-check whether it is their turn.
+    fn wake_waiters(&self, mut state: std::sync::MutexGuard<'_, PermitState>) {
+        let wakers = std::mem::take(&mut state.wakers);
+        drop(state);
+
+        self.released.notify_all();
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// The place of an endpoint future in the queue of a [`BulkPermits`].
+///
+/// Removes the ticket from the queue if the future is dropped before it
+/// got its permit.
+struct QueuedTicket<'a> {
+    permits: &'a BulkPermits,
+    ticket: u64,
+    admitted: bool,
+}
+
+impl Drop for QueuedTicket<'_> {
+    fn drop(&mut self) {
+        if !self.admitted {
+            let mut state = self.permits.state.lock().unwrap();
+            state.waiters.retain(|&ticket| ticket != self.ticket);
+            self.permits.wake_waiters(state);
+        }
+    }
+}
+
+/// A permit for one outstanding bulk transfer.
+///
+/// Dropping the permit returns it to its [`BulkPermits`].
+#[derive(Debug)]
+pub struct BulkPermit {
+    permits: Option<Arc<BulkPermits>>,
+}
+
+impl Drop for BulkPermit {
+    fn drop(&mut self) {
+        if let Some(permits) = self.permits.take() {
+            permits.release();
+        }
+    }
+}
+
+/// Hands out the [`BulkPermits`] of each physical host bus.
+///
+/// All devices that sit on the same host bus share one permit pool.
+#[derive(Debug)]
+pub struct HostBusScheduler {
+    /// The limit applied to each host bus.
+    max_outstanding_bulk: Option<NonZeroUsize>,
+    /// Permit pools keyed by host bus number.
+    buses: Mutex<HashMap<u8, Arc<BulkPermits>>>,
+}
+
+impl HostBusScheduler {
+    /// Create a new scheduler.
+    ///
+    /// # Parameters
+    ///
+    /// - `max_outstanding_bulk`: the maximum number of concurrently
+    ///   outstanding bulk transfers per host bus. `None` disables the limit.
+    pub fn new(max_outstanding_bulk: Option<NonZeroUsize>) -> Self {
+        Self {
+            max_outstanding_bulk,
+            buses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Retrieve the permit pool for a host bus.
+    pub fn bulk_permits(&self, bus_number: u8) -> Arc<BulkPermits> {
+        self.buses
+            .lock()
+            .unwrap()
+            .entry(bus_number)
+            .or_insert_with(|| Arc::new(BulkPermits::new(self.max_outstanding_bulk)))
+            .clone()
+    }
+}
+
+impl Default for HostBusScheduler {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{self, Receiver, Sender},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// A mock endpoint whose transfers complete when the test says so.
+    struct MockEndpoint {
+        name: &'static str,
+        complete: Receiver<()>,
+        log: Sender<&'static str>,
+    }
+
+    impl MockEndpoint {
+        /// Run a worker loop that mirrors the nusb workers: take a permit
+        /// (bulk only), submit, wait for the completion, release.
+        fn run(self, permits: Option<Arc<BulkPermits>>, transfers: usize) {
+            for _ in 0..transfers {
+                let permit = permits.as_ref().map(|permits| permits.acquire());
+                self.log.send(self.name).unwrap();
+                self.complete.recv().unwrap();
+                drop(permit);
+            }
+        }
+    }
+
+    fn mock_endpoint(name: &'static str, log: &Sender<&'static str>) -> (MockEndpoint, Sender<()>) {
+        let (complete_sender, complete) = mpsc::channel();
+        (
+            MockEndpoint {
+                name,
+                complete,
+                log: log.clone(),
+            },
+            complete_sender,
+        )
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Wait until a worker queued up for a permit.
+    fn wait_for_waiter(permits: &BulkPermits) {
+        while permits.state.lock().unwrap().waiters.is_empty() {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn unlimited_permits_never_block() {
+        let permits = Arc::new(BulkPermits::new(None));
+        let first = permits.acquire();
+        let second = permits.acquire();
+
+        drop((first, second));
+    }
+
+    #[test]
+    fn buses_get_separate_permits() {
+        let scheduler = HostBusScheduler::new(NonZeroUsize::new(1));
+
+        let bus1 = scheduler.bulk_permits(1);
+        let bus2 = scheduler.bulk_permits(2);
+
+        assert!(Arc::ptr_eq(&bus1, &scheduler.bulk_permits(1)));
+        assert!(!Arc::ptr_eq(&bus1, &bus2));
+
+        // Both buses can have an outstanding transfer at the same time.
+        let _permit1 = bus1.acquire();
+        let _permit2 = bus2.acquire();
+        assert_eq!(bus1.outstanding(), 1);
+        assert_eq!(bus2.outstanding(), 1);
+    }
+
+    #[test]
+    fn try_acquire_respects_the_limit() {
+        let permits = Arc::new(BulkPermits::new(NonZeroUsize::new(2)));
+        let first = permits.try_acquire().unwrap();
+        let _second = permits.try_acquire().unwrap();
+        assert!(permits.try_acquire().is_none());
+
+        drop(first);
+        assert!(permits.try_acquire().is_some());
+    }
+
+    #[test]
+    fn bulk_workers_alternate_and_interrupt_is_not_blocked() {
+        let scheduler = HostBusScheduler::new(NonZeroUsize::new(1));
+        let permits = scheduler.bulk_permits(1);
+        let (log_sender, log) = mpsc::channel();
+
+        let (bulk_a, complete_a) = mock_endpoint("bulk a", &log_sender);
+        let (bulk_b, complete_b) = mock_endpoint("bulk b", &log_sender);
+        let (interrupt, complete_interrupt) = mock_endpoint("interrupt", &log_sender);
+
+        let permits_a = permits.clone();
+        let worker_a = thread::spawn(move || bulk_a.run(Some(permits_a), 3));
+
+        // Wait for worker a to hold the permit before starting worker b, so
+        // the submission order is deterministic.
+        assert_eq!(log.recv_timeout(TIMEOUT), Ok("bulk a"));
+
+        let permits_b = permits.clone();
+        let worker_b = thread::spawn(move || bulk_b.run(Some(permits_b), 3));
+
+        // Let worker b queue up behind worker a.
+        wait_for_waiter(&permits);
+
+        // The interrupt worker submits while worker a holds the only permit
+        // and worker b waits for it.
+        let worker_interrupt = thread::spawn(move || interrupt.run(None, 1));
+        assert_eq!(log.recv_timeout(TIMEOUT), Ok("interrupt"));
+        complete_interrupt.send(()).unwrap();
+
+        // Completing transfers alternates between both bulk workers.
+        let mut order = vec![];
+        let mut completions = [&complete_a, &complete_b].into_iter().cycle();
+        for _ in 0..5 {
+            // The worker that does not hold the permit always waits for it
+            // before the transfer completes.
+            wait_for_waiter(&permits);
+            completions.next().unwrap().send(()).unwrap();
+            order.push(log.recv_timeout(TIMEOUT).unwrap());
+        }
+        completions.next().unwrap().send(()).unwrap();
+
+        assert_eq!(
+            order,
+            ["bulk b", "bulk a", "bulk b", "bulk a", "bulk b"],
+            "bulk workers should take turns"
+        );
+
+        worker_a.join().unwrap();
+        worker_b.join().unwrap();
+        worker_interrupt.join().unwrap();
+        assert_eq!(permits.outstanding(), 0);
+    }
+}
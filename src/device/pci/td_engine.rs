@@ -0,0 +1,1477 @@
+//! # Transfer Descriptor Processing
+//!
+//! The driver queues Transfer Descriptors (TDs) on the transfer ring of an
+//! endpoint. Servicing them takes the same steps regardless of how the
+//! data reaches the device: fetch the TD, stage its data between guest
+//! memory and the transfer, and report the completion with a Transfer
+//! Event.
+//!
+//! A [`TdEngine`] implements these steps for one endpoint. Backends only
+//! move the data of each [`TdDescriptor`] to or from their device and
+//! report the [`TdOutcome`]. So far, every TD is a single Normal TRB.
+
+use std::{cmp::Ordering::*, num::NonZeroUsize, ops::Range, sync::Arc, time::Duration};
+#[cfg(any(feature = "nusb-backend", test))]
+use std::{collections::VecDeque, time::Instant};
+
+use tracing::{debug, trace, warn};
+
+use crate::device::bus::BusDeviceRef;
+
+#[cfg(feature = "nusb-backend")]
+use super::device_slots::StreamContextArray;
+#[cfg(any(feature = "nusb-backend", test))]
+use super::transfer_unit::{split_td, TransferUnit};
+use super::{
+    dci::Dci,
+    endpoint_stats::EndpointStats,
+    event_batch::TransferEventBatch,
+    event_sink::EventSink,
+    rings::EndpointRing,
+    run_state::RunState,
+    trb::{CompletionCode, EventTrb, TransferTrb, TransferTrbVariant},
+};
+
+/// A TD that the backend has to transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TdDescriptor {
+    /// The address of the TRB, which the Transfer Event refers to.
+    pub trb_address: u64,
+    /// The guest physical address of the data buffer.
+    pub data_pointer: u64,
+    /// The size of the data buffer in bytes.
+    pub transfer_length: u32,
+    /// Whether the driver asked for a Transfer Event on success.
+    pub interrupt_on_completion: bool,
+}
+
+/// How the transfer of a TD ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TdOutcome<'a> {
+    /// An IN transfer received `data`, which may be longer or shorter than
+    /// the TD's buffer.
+    In { data: &'a [u8], stopped: bool },
+    /// An OUT transfer sent `sent` bytes.
+    #[cfg(any(feature = "nusb-backend", test))]
+    Out { sent: usize, stopped: bool },
+}
+
+/// The settings of a [`TdEngine`].
+#[derive(Debug, Clone, Default)]
+pub struct TdEngineConfig {
+    /// The window in which Transfer Events are reported with a single
+    /// interrupt.
+    pub event_coalescing: Option<Duration>,
+    /// Bounds the TRBs processed per doorbell ring.
+    pub max_trbs_per_doorbell: Option<NonZeroUsize>,
+    /// Where the engine counts its work.
+    pub stats: Arc<EndpointStats>,
+    /// The engine only fetches TDs while this lets it.
+    pub run_state: Arc<RunState>,
+    /// Whether the endpoint is isochronous. The driver learns about a
+    /// starved isochronous transfer ring from a Ring Underrun or Ring
+    /// Overrun event.
+    pub isochronous: bool,
+}
+
+/// Processes the TDs on the transfer ring(s) of one endpoint.
+#[derive(Debug)]
+pub struct TdEngine {
+    slot_id: u8,
+    endpoint_id: Dci,
+    transfer_ring: EndpointRing,
+    dma_bus: BusDeviceRef,
+    event_sink: Arc<EventSink>,
+    events: TransferEventBatch,
+    budget: DoorbellBudget,
+    stats: Arc<EndpointStats>,
+    run_state: Arc<RunState>,
+    isochronous: bool,
+    /// Whether TDs were fetched since the transfer ring last ran empty.
+    busy: bool,
+}
+
+impl TdEngine {
+    /// Create the engine of an endpoint, which posts its Transfer Events
+    /// to `event_sink`.
+    pub fn new(
+        slot_id: u8,
+        endpoint_id: Dci,
+        transfer_ring: EndpointRing,
+        dma_bus: BusDeviceRef,
+        event_sink: Arc<EventSink>,
+        config: TdEngineConfig,
+    ) -> Self {
+        Self {
+            slot_id,
+            endpoint_id,
+            transfer_ring,
+            dma_bus,
+            events: TransferEventBatch::new(event_sink.clone(), config.event_coalescing),
+            event_sink,
+            budget: DoorbellBudget::new(endpoint_id, config.max_trbs_per_doorbell),
+            stats: config.stats,
+            run_state: config.run_state,
+            isochronous: config.isochronous,
+            busy: false,
+        }
+    }
+
+    /// The Stream Context Array, if the endpoint has streams.
+    #[cfg(feature = "nusb-backend")]
+    pub fn streams(&self) -> Option<Arc<StreamContextArray>> {
+        self.transfer_ring.streams()
+    }
+
+    /// Fetch the next TD from the transfer ring.
+    ///
+    /// Returns `None` when the transfer ring is empty or the TRBs for the
+    /// current doorbell ring are used up. A broken transfer ring is
+    /// reported to the driver with a TRB Error and treated as empty as well.
+    /// While the controller is stopped, the transfer ring is not touched
+    /// and the controller rings the doorbell again once it starts.
+    ///
+    /// A transfer ring that runs empty after handing out TDs counts as
+    /// starved, as the device has to wait for the driver to queue more. On
+    /// isochronous endpoints, this is reported to the driver.
+    pub fn next_td(&mut self) -> Option<TdDescriptor> {
+        let Some(_access) = self.run_state.enter(self.slot_id, self.endpoint_id.get()) else {
+            trace!(
+                "worker ep {}: Controller stopped, pausing transfer ring",
+                self.endpoint_id
+            );
+            return None;
+        };
+        let trb = self.budget.fetch(|| {
+            let trb = next_normal_trb(
+                &self.transfer_ring,
+                &mut self.events,
+                &self.stats,
+                self.slot_id,
+                self.endpoint_id,
+            );
+            if trb.is_none() && std::mem::take(&mut self.busy) {
+                trace!("worker ep {}: Transfer ring starved", self.endpoint_id);
+                self.stats.record_starved();
+                if self.isochronous {
+                    report_ring_starved(
+                        &mut self.events,
+                        &self.stats,
+                        self.slot_id,
+                        self.endpoint_id,
+                    );
+                }
+            }
+            trb
+        })?;
+        self.busy = true;
+        self.stats.record_trb();
+        let TransferTrbVariant::Normal(data) = trb.variant else {
+            // next_normal_trb guarantees that the TRB is a normal TRB.
+            unreachable!();
+        };
+        Some(TdDescriptor {
+            trb_address: trb.address,
+            data_pointer: data.data_pointer,
+            transfer_length: data.transfer_length,
+            interrupt_on_completion: data.interrupt_on_completion,
+        })
+    }
+
+    /// Where the transfer ring would hand out the next TD again after a
+    /// [`rewind`](Self::rewind). `None` for endpoints with streams.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub fn ring_position(&self) -> Option<(u64, bool)> {
+        self.transfer_ring.position()
+    }
+
+    /// Move the transfer ring back to `position`, so the TDs fetched since
+    /// are fetched again.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub fn rewind(&self, position: (u64, bool)) {
+        debug!(
+            "worker ep {}: Rewinding transfer ring to {:#x}",
+            self.endpoint_id, position.0
+        );
+        self.transfer_ring.rewind(position);
+    }
+
+    /// Start over after a doorbell ring.
+    pub const fn refill(&mut self) {
+        self.budget.refill();
+    }
+
+    /// Read the data of an OUT TD from guest memory.
+    ///
+    /// Returns `None` when the TD was already completed with an error
+    /// because its buffer is not backed by guest memory.
+    ///
+    /// A TD without data is a zero-length packet. The xHC never adds one on
+    /// its own, so drivers queue such a TD behind a transfer whose length
+    /// is a multiple of the Max Packet Size when the protocol asks for it.
+    /// Its data pointer is meaningless and guest memory is not touched.
+    #[cfg(any(feature = "nusb-backend", test))]
+    pub fn out_data(&mut self, td: &TdDescriptor) -> Option<Vec<u8>> {
+        if td.transfer_length == 0 {
+            trace!(
+                "worker ep {}: Sending zero-length packet for TRB at {:#x}",
+                self.endpoint_id,
+                td.trb_address
+            );
+            return Some(Vec::new());
+        }
+        let data = match read_out_data(&self.dma_bus, td.data_pointer, td.transfer_length as usize)
+        {
+            Ok(data) => data,
+            Err(unmapped) => {
+                // Sending the data anyway would ship the default device's
+                // fill pattern to the real device (e.g., as garbage blocks on
+                // a storage device), so we fail the transfer instead.
+                warn!(
+                    "worker ep {}: OUT buffer {:#x}..{:#x} is not fully backed by guest memory (unmapped: {:#x}..{:#x}); reporting Data Buffer Error",
+                    self.endpoint_id,
+                    td.data_pointer,
+                    td.data_pointer.wrapping_add(td.transfer_length as u64),
+                    unmapped.start,
+                    unmapped.end
+                );
+                self.send_transfer_event(td, td.transfer_length, CompletionCode::DataBufferError);
+                return None;
+            }
+        };
+        if td.transfer_length == 31 {
+            debug!("OUT data: {:?}", data);
+        }
+        Some(data)
+    }
+
+    /// Finish a TD and report its completion to the driver.
+    ///
+    /// The data of IN transfers is copied to guest memory first.
+    pub fn complete_td(&mut self, td: TdDescriptor, outcome: TdOutcome) {
+        let (transferred, stopped) = match outcome {
+            TdOutcome::In { data, stopped } => {
+                match write_in_data(
+                    &self.dma_bus,
+                    td.data_pointer,
+                    data,
+                    td.transfer_length as usize,
+                ) {
+                    Ok(written) => (written, stopped),
+                    Err(unmapped) => {
+                        // The data that missed guest memory is lost, so the
+                        // driver must not believe it received it.
+                        warn!(
+                            "worker ep {}: IN buffer {:#x}..{:#x} is not fully backed by guest memory (unmapped: {:#x}..{:#x}); reporting Data Buffer Error",
+                            self.endpoint_id,
+                            td.data_pointer,
+                            td.data_pointer.wrapping_add(td.transfer_length as u64),
+                            unmapped.start,
+                            unmapped.end
+                        );
+                        self.send_transfer_event(
+                            &td,
+                            td.transfer_length,
+                            CompletionCode::DataBufferError,
+                        );
+                        return;
+                    }
+                }
+            }
+            #[cfg(any(feature = "nusb-backend", test))]
+            TdOutcome::Out { sent, stopped } => (sent.min(td.transfer_length as usize), stopped),
+        };
+        self.stats.record_bytes(transferred);
+
+        if stopped {
+            let residual_bytes = td.transfer_length - transferred as u32;
+            self.send_stopped_event(&td, residual_bytes);
+            return;
+        }
+
+        if !td.interrupt_on_completion {
+            trace!("Processed TRB without IOC flag; sending no transfer event");
+            return;
+        }
+
+        self.send_transfer_event(&td, 0, CompletionCode::Success);
+    }
+
+    /// Post all pending Transfer Events.
+    pub fn flush_events(&mut self) {
+        self.events.flush();
+    }
+
+    /// The point in time at which the pending Transfer Events have to be
+    /// posted, if any.
+    #[cfg(feature = "nusb-backend")]
+    pub fn event_deadline(&self) -> Option<Instant> {
+        self.events.deadline()
+    }
+
+    /// Block while the Event Ring has no space for further Transfer Events.
+    ///
+    /// The pending events are posted first, so the driver sees them before
+    /// it frees space.
+    pub fn wait_for_event_space(&mut self) {
+        if !self.event_sink.is_congested() {
+            return;
+        }
+        debug!(
+            "worker ep {}: Event Ring congested, pausing transfer ring",
+            self.endpoint_id
+        );
+        self.events.flush();
+        self.event_sink.wait_for_space();
+    }
+
+    /// The async counterpart of [`Self::wait_for_event_space`].
+    #[cfg(feature = "nusb-backend")]
+    pub async fn event_space(&mut self) {
+        if !self.event_sink.is_congested() {
+            return;
+        }
+        debug!(
+            "endpoint task ep {}: Event Ring congested, pausing transfer ring",
+            self.endpoint_id
+        );
+        self.events.flush();
+        self.event_sink.space().await;
+    }
+
+    /// Report a TD whose transfer was interrupted by a stop.
+    ///
+    /// The Transfer Event is sent regardless of the TD's IOC flag, because
+    /// the driver needs it to tell where the endpoint stopped.
+    fn send_stopped_event(&mut self, td: &TdDescriptor, residual_bytes: u32) {
+        debug!(
+            "worker ep {}: Stopped TRB at {:#x} with {} bytes left",
+            self.endpoint_id, td.trb_address, residual_bytes
+        );
+        self.send_transfer_event(td, residual_bytes, CompletionCode::Stopped);
+    }
+
+    /// Report the completion of `td` with a Transfer Event.
+    ///
+    /// The event is sent as part of the next event batch.
+    fn send_transfer_event(
+        &mut self,
+        td: &TdDescriptor,
+        residual_bytes: u32,
+        completion_code: CompletionCode,
+    ) {
+        self.stats.record_transfer_event(completion_code);
+        self.events.push(EventTrb::new_transfer_event_trb(
+            td.trb_address,
+            residual_bytes,
+            completion_code,
+            false,
+            self.endpoint_id,
+            self.slot_id,
+        ));
+    }
+}
+
+/// The TDs of an endpoint whose transfers are in flight, oldest first.
+///
+/// Keeping several transfers in flight lets the device start on the next
+/// transfer while we report the previous one. The device completes them in
+/// order. When a transfer ends early, i.e., it is stopped or fails, the
+/// transfers behind it have not started yet. Their TDs go back to the
+/// transfer ring, where the endpoint picks them up again once the driver
+/// restarts it.
+///
+/// Large TDs are transferred with several host submissions, see
+/// [`split_td`]. The TD completes once all of them did, or as soon as one
+/// ends early or short, which ends the transfer on the bus as well.
+///
+/// Every TD holds a `P`, e.g., a bulk permit, until it completes.
+#[cfg(any(feature = "nusb-backend", test))]
+#[derive(Debug)]
+pub struct InFlightTds<P> {
+    depth: NonZeroUsize,
+    unit: TransferUnit,
+    tds: VecDeque<InFlightTd<P>>,
+    /// Whether a transfer in the queue ended early, so the ones behind it
+    /// are dropped when they complete.
+    ended: bool,
+}
+
+#[cfg(any(feature = "nusb-backend", test))]
+#[derive(Debug)]
+struct InFlightTd<P> {
+    td: TdDescriptor,
+    /// The position of the transfer ring before the TD was fetched. Only
+    /// known for TDs queued behind others, as only those can go back.
+    ring_position: Option<(u64, bool)>,
+    /// The lengths of the submissions of the TD that did not complete yet.
+    submissions: VecDeque<usize>,
+    /// The data the completed submissions of the TD received so far.
+    received: Vec<u8>,
+    permit: P,
+}
+
+#[cfg(any(feature = "nusb-backend", test))]
+impl<P> InFlightTds<P> {
+    /// Create an empty queue that holds up to `depth` TDs, whose transfers
+    /// are split according to `unit`.
+    pub const fn new(depth: NonZeroUsize, unit: TransferUnit) -> Self {
+        Self {
+            depth,
+            unit,
+            tds: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    /// Whether no transfer is in flight.
+    pub fn is_empty(&self) -> bool {
+        self.tds.is_empty()
+    }
+
+    /// `submit` the transfer of `td` to an empty queue and track it.
+    ///
+    /// `submit` receives the buffer size of every host submission.
+    pub fn push(&mut self, td: TdDescriptor, permit: P, submit: impl FnMut(usize)) {
+        assert!(self.is_empty(), "Only the first transfer is pushed");
+        self.submit(td, None, permit, submit);
+    }
+
+    /// Fetch further TDs and `submit` their transfers while the queue has
+    /// room.
+    ///
+    /// Stops early when the transfer ring runs dry, `permit` refuses a
+    /// transfer, the Event Ring is congested, or a transfer in flight ended
+    /// early.
+    pub fn top_up(
+        &mut self,
+        engine: &mut TdEngine,
+        mut permit: impl FnMut() -> Option<P>,
+        mut submit: impl FnMut(usize),
+    ) {
+        while !self.ended && self.tds.len() < self.depth.get() && !engine.event_sink.is_congested()
+        {
+            let Some(permit) = permit() else {
+                return;
+            };
+            let ring_position = engine.ring_position();
+            let Some(td) = engine.next_td() else {
+                return;
+            };
+            self.submit(td, ring_position, permit, &mut submit);
+        }
+    }
+
+    fn submit(
+        &mut self,
+        td: TdDescriptor,
+        ring_position: Option<(u64, bool)>,
+        permit: P,
+        mut submit: impl FnMut(usize),
+    ) {
+        let submissions: VecDeque<_> = split_td(td.transfer_length as usize, self.unit).into();
+        if submissions.len() > 1 {
+            trace!(
+                "Splitting TD at {:#x} into submissions of {:?} bytes",
+                td.trb_address,
+                submissions
+            );
+        }
+        for &length in &submissions {
+            submit(self.unit.buffer_size(length));
+        }
+        self.tds.push_back(InFlightTd {
+            td,
+            ring_position,
+            submissions,
+            received: Vec::new(),
+            permit,
+        });
+    }
+
+    /// Account the completion of the oldest submission in flight, which
+    /// ended with `outcome`.
+    ///
+    /// `ended_early` tells whether the transfer was stopped or failed.
+    /// Finishes the TD of the submission once it is complete. Returns
+    /// whether transfers behind it are still in flight, which the caller
+    /// has to cancel.
+    pub fn complete_oldest(
+        &mut self,
+        engine: &mut TdEngine,
+        outcome: TdOutcome,
+        ended_early: bool,
+    ) -> bool {
+        let tds_behind = self.tds.len().saturating_sub(1);
+        let oldest = self
+            .tds
+            .front_mut()
+            .expect("a transfer completed without being in flight");
+        let length = oldest
+            .submissions
+            .pop_front()
+            .expect("a finished TD stayed in flight");
+
+        let mut cancel = false;
+        if self.ended {
+            trace!(
+                "Dropping a cancelled submission of the TD at {:#x}",
+                oldest.td.trb_address
+            );
+        } else {
+            let outcome = match outcome {
+                TdOutcome::In { data, stopped } => {
+                    let full = data.len() >= length;
+                    if !oldest.submissions.is_empty() && full && !ended_early {
+                        // The TD continues with the next submission.
+                        oldest.received.extend_from_slice(&data[..length]);
+                        return false;
+                    }
+                    oldest.received.extend_from_slice(data);
+                    TdOutcome::In {
+                        data: &oldest.received,
+                        stopped,
+                    }
+                }
+                #[cfg(any(feature = "nusb-backend", test))]
+                outcome @ TdOutcome::Out { .. } => outcome,
+            };
+            engine.complete_td(oldest.td, outcome);
+
+            // The submissions of the TD that are still in flight would
+            // receive the data of the next transfer, so they have to go.
+            let ended = ended_early || !oldest.submissions.is_empty();
+            if ended && (tds_behind > 0 || !oldest.submissions.is_empty()) {
+                // Fetching the TDs of the queued transfers again restores
+                // the ring to where the driver expects the endpoint to be.
+                if let Some(position) = self.tds.get(1).and_then(|next| next.ring_position) {
+                    engine.rewind(position);
+                }
+                self.ended = true;
+                cancel = true;
+            }
+        }
+        if self.tds.front().is_some_and(|td| td.submissions.is_empty()) {
+            let finished = self.tds.pop_front().unwrap();
+            drop(finished.permit);
+        }
+        if self.tds.is_empty() {
+            self.ended = false;
+        }
+        cancel
+    }
+}
+
+/// Paces the transfers of an Interrupt IN endpoint to the service interval
+/// the driver configured.
+///
+/// Devices complete interrupt transfers whenever they have data, so
+/// submitting the next transfer right away polls them far more often than
+/// the interval asks for. That drains the batteries of wireless HID devices
+/// and trips up the firmware of some. After a transfer that returned data,
+/// the next one waits for the rest of the interval, unless the driver had
+/// queued its TD in advance.
+#[cfg(any(feature = "nusb-backend", test))]
+#[derive(Debug)]
+pub struct IntervalPacer {
+    /// The service interval, or `None` to disable pacing.
+    interval: Option<Duration>,
+    /// When the interval after the last transfer with data ends.
+    due: Option<Instant>,
+    /// Whether the transfer ring ran dry since that transfer.
+    ran_dry: bool,
+}
+
+#[cfg(any(feature = "nusb-backend", test))]
+impl IntervalPacer {
+    /// Create a pacer for the given service interval. `None` disables
+    /// pacing.
+    pub const fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            due: None,
+            ran_dry: false,
+        }
+    }
+
+    /// Note that a transfer completed at `now`, with data if `with_data`.
+    pub fn completed(&mut self, with_data: bool, now: Instant) {
+        self.due = self
+            .interval
+            .filter(|_| with_data)
+            .map(|interval| now + interval);
+        self.ran_dry = false;
+    }
+
+    /// Note that the transfer ring had no TD to submit.
+    pub const fn ran_dry(&mut self) {
+        self.ran_dry = true;
+    }
+
+    /// How long to wait at `now` before fetching the next TD.
+    pub fn delay(&self, now: Instant) -> Option<Duration> {
+        if !self.ran_dry {
+            return None;
+        }
+        self.due
+            .filter(|&due| due > now)
+            .map(|due| due.duration_since(now))
+    }
+}
+
+/// Bounds the number of TRBs a worker processes per doorbell ring.
+///
+/// A transfer ring that never runs dry, e.g., because stale TRBs behind a
+/// missing Link TRB happen to look fresh, would otherwise keep the worker
+/// busy forever. Once the budget is used up, the worker waits for the next
+/// doorbell as if the ring was empty.
+#[derive(Debug)]
+struct DoorbellBudget {
+    endpoint_id: Dci,
+    limit: Option<NonZeroUsize>,
+    /// The TRBs fetched since the last doorbell.
+    used: usize,
+    /// How often the budget ran out.
+    exhausted: u64,
+}
+
+impl DoorbellBudget {
+    const fn new(endpoint_id: Dci, limit: Option<NonZeroUsize>) -> Self {
+        Self {
+            endpoint_id,
+            limit,
+            used: 0,
+            exhausted: 0,
+        }
+    }
+
+    /// Fetch a TRB with `fetch`, unless the budget is used up.
+    fn fetch<T>(&mut self, fetch: impl FnOnce() -> Option<T>) -> Option<T> {
+        if self.limit.is_some_and(|limit| self.used >= limit.get()) {
+            self.exhausted += 1;
+            warn!(
+                "worker ep {}: fetched {} TRBs for a single doorbell, waiting for the next one ({} times so far)",
+                self.endpoint_id, self.used, self.exhausted
+            );
+            return None;
+        }
+        let trb = fetch()?;
+        self.used += 1;
+        Some(trb)
+    }
+
+    /// Start over after a doorbell ring.
+    const fn refill(&mut self) {
+        self.used = 0;
+    }
+}
+
+/// Report that the transfer ring of an isochronous endpoint was empty
+/// when the endpoint was serviced.
+///
+/// The Ring Overrun or Ring Underrun event is only informational. The
+/// endpoint keeps running and continues with the TDs that the driver
+/// queues next.
+fn report_ring_starved(
+    events: &mut TransferEventBatch,
+    stats: &EndpointStats,
+    slot_id: u8,
+    endpoint_id: Dci,
+) {
+    debug!("worker ep {endpoint_id}: Isochronous transfer ring empty at service time");
+    stats.record_transfer_event(CompletionCode::ring_starved(endpoint_id));
+    events.push(EventTrb::new_ring_starved_event_trb(endpoint_id, slot_id));
+}
+
+/// Fetch the next TRB from the transfer ring of an endpoint.
+///
+/// Returns `None` when the transfer ring is empty. A broken transfer ring
+/// is reported to the driver with a TRB Error and treated as empty as
+/// well.
+fn next_normal_trb(
+    transfer_ring: &EndpointRing,
+    events: &mut TransferEventBatch,
+    stats: &EndpointStats,
+    slot_id: u8,
+    endpoint_id: Dci,
+) -> Option<TransferTrb> {
+    let trb = match transfer_ring.next_transfer_trb()? {
+        Ok(trb) => trb,
+        Err(err) => {
+            warn!("worker ep {}: {}", endpoint_id, err);
+            stats.record_transfer_event(CompletionCode::TrbError);
+            events.push(EventTrb::new_transfer_event_trb(
+                err.address(),
+                0,
+                CompletionCode::TrbError,
+                false,
+                endpoint_id,
+                slot_id,
+            ));
+            return None;
+        }
+    };
+    assert!(
+        matches!(trb.variant, TransferTrbVariant::Normal(_)),
+        "Expected Normal TRB but got {} at {:#x}",
+        trb.variant,
+        trb.address
+    );
+    Some(trb)
+}
+
+/// Write the data of an IN transfer to guest memory.
+///
+/// `length` is the size of the guest's buffer as requested by the driver.
+/// Returns the number of bytes written if they all landed in guest memory.
+/// Otherwise, returns the guest physical address range of the written data
+/// that is not backed by guest memory, starting from the first unmapped
+/// byte. The mapped part is written in either case.
+pub fn write_in_data(
+    dma_bus: &BusDeviceRef,
+    address: u64,
+    data: &[u8],
+    length: usize,
+) -> Result<usize, Range<u64>> {
+    let byte_count_dma = match data.len().cmp(&length) {
+        Greater => {
+            // Got more data than requested. We must not write more data than
+            // the guest driver requested with the transfer length, otherwise
+            // we might write out of the buffer.
+            //
+            // Why does this case happen? Sometimes the driver asks for, e.g.,
+            // 36 bytes. We have to request max_packet_size (e.g., 1024 bytes).
+            // The real device then provides 1024 bytes of data (looks like
+            // zero padding).
+            length
+        }
+        Less => {
+            // Got less data than requested. That case happens for example when
+            // the driver sends a Mode Sense(6) SCSI command. The response size
+            // is variable, so the driver asks for 192 bytes but is also fine
+            // with less.
+            //
+            // We copy all the data over that we got.
+            // TODO: currently, we just report success and 0 residual bytes,
+            // even though we probably should report something like short
+            // packet and the difference between requested and actual byte
+            // count. We get away with the simplified handling for now.
+            // The Mode Sense(6) response encodes the size of the response in
+            // the first byte, so the driver is not unhappy that we reported
+            // 192 bytes but only deliver, e.g., 36 bytes.
+            data.len()
+        }
+        Equal => {
+            // We got exactly the right amount of bytes.
+            length
+        }
+    };
+    if dma_bus.try_write_bulk(address, &data[..byte_count_dma]) == byte_count_dma {
+        Ok(byte_count_dma)
+    } else {
+        Err(first_unmapped(dma_bus, address, byte_count_dma)
+            ..address.wrapping_add(byte_count_dma as u64))
+    }
+}
+
+/// Read the data of an OUT transfer from guest memory.
+///
+/// Returns the data if the whole buffer is backed by guest memory.
+/// Otherwise, returns the guest physical address range that is not backed
+/// by guest memory, starting from the first unmapped byte.
+#[cfg(any(feature = "nusb-backend", test))]
+pub fn read_out_data(
+    dma_bus: &BusDeviceRef,
+    address: u64,
+    length: usize,
+) -> Result<Vec<u8>, Range<u64>> {
+    let mut data = vec![0; length];
+    let mapped = dma_bus.try_read_bulk(address, &mut data);
+
+    if mapped == length {
+        Ok(data)
+    } else {
+        Err(first_unmapped(dma_bus, address, length)..address.wrapping_add(length as u64))
+    }
+}
+
+/// Find the first byte of a buffer that is not backed by guest memory, to
+/// report a helpful range.
+///
+/// The mapped part of a buffer is a prefix, so this bisects its length with
+/// mapping queries instead of probing every byte. Returns the start of the
+/// buffer if every byte is mapped after all, e.g., because guest memory
+/// changed in the meantime.
+fn first_unmapped(dma_bus: &BusDeviceRef, address: u64, length: usize) -> u64 {
+    let mut unmapped = length as u64;
+    if dma_bus.is_mapped(address, unmapped) {
+        return address;
+    }
+
+    let mut mapped = 0;
+    while unmapped - mapped > 1 {
+        let middle = mapped + (unmapped - mapped) / 2;
+        if dma_bus.is_mapped(address, middle) {
+            mapped = middle;
+        } else {
+            unmapped = middle;
+        }
+    }
+    address.wrapping_add(mapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::bus::testutils::TestBusDevice;
+    use crate::device::bus::{BusDevice, Request, RequestSize};
+    use crate::device::pci::constants::xhci::rings::trb_types;
+    use crate::device::pci::device_slots::EndpointContext;
+    use crate::device::pci::endpoint_stats::EndpointCounters;
+    use crate::device::pci::event_sink::testutils::event_sink;
+    use crate::device::pci::rings::TransferRing;
+    use crate::dynamic_bus::DynamicBus;
+
+    use super::*;
+
+    /// Single packets of 64 bytes.
+    const PACKETS: TransferUnit = TransferUnit {
+        max_packet_size: 0x40,
+        max_burst_size: 0,
+        max_esit_payload: 0,
+    };
+
+    /// Create an engine for EP2 of slot 1.
+    ///
+    /// The Event Ring is set up as in [`event_sink`], the endpoint context
+    /// is at 0x300 and the transfer ring at 0x400. Guest memory ends at
+    /// 0x1000.
+    fn engine(max_trbs_per_doorbell: Option<NonZeroUsize>) -> (TdEngine, Arc<TestBusDevice>) {
+        engine_with_memory(0x1000, max_trbs_per_doorbell)
+    }
+
+    /// Like [`engine`], but with `memory_size` bytes of guest memory.
+    fn engine_with_memory(
+        memory_size: usize,
+        max_trbs_per_doorbell: Option<NonZeroUsize>,
+    ) -> (TdEngine, Arc<TestBusDevice>) {
+        let ram = Arc::new(TestBusDevice::new(&vec![0; memory_size]));
+        let bus = DynamicBus::new();
+        bus.add(0x0, ram.clone()).unwrap();
+        let dma_bus: BusDeviceRef = Arc::new(bus);
+        // Dequeue pointer 0x400, consumer cycle state 1.
+        ram.write_bulk(0x308, &0x401u64.to_le_bytes());
+        let transfer_ring = TransferRing::new(
+            EndpointContext::new(0x300, dma_bus.clone()),
+            dma_bus.clone(),
+        );
+        let run_state = Arc::new(RunState::default());
+        run_state.start();
+        let engine = TdEngine::new(
+            1,
+            Dci::new(2).unwrap(),
+            EndpointRing::Single(transfer_ring),
+            dma_bus,
+            Arc::new(event_sink(ram.clone())),
+            TdEngineConfig {
+                max_trbs_per_doorbell,
+                run_state,
+                ..TdEngineConfig::default()
+            },
+        );
+        (engine, ram)
+    }
+
+    /// Place a Normal TRB in slot `index` of the transfer ring.
+    fn place_normal_trb(
+        ram: &TestBusDevice,
+        index: u64,
+        data_pointer: u64,
+        transfer_length: u32,
+        interrupt_on_completion: bool,
+    ) {
+        let address = 0x400 + index * 16;
+        ram.write_bulk(address, &data_pointer.to_le_bytes());
+        ram.write_bulk(address + 8, &transfer_length.to_le_bytes());
+        let control =
+            u32::from(trb_types::NORMAL) << 10 | u32::from(interrupt_on_completion) << 5 | 1;
+        ram.write_bulk(address + 12, &control.to_le_bytes());
+    }
+
+    /// The TRB pointer, completion code, and residual bytes of the Transfer
+    /// Event at `index` in the Event Ring.
+    fn transfer_event(ram: &TestBusDevice, index: u64) -> (u64, u8, u32) {
+        let address = 0x100 + index * 16;
+        let status = ram.read(Request::new(address + 8, RequestSize::Size4));
+        (
+            ram.read(Request::new(address, RequestSize::Size8)),
+            (status >> 24) as u8,
+            (status & 0xff_ffff) as u32,
+        )
+    }
+
+    #[test]
+    fn pacer_delays_transfers_after_data() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(8);
+        let mut pacer = IntervalPacer::new(Some(interval));
+        assert_eq!(pacer.delay(start), None);
+
+        pacer.completed(true, start);
+        pacer.ran_dry();
+
+        assert_eq!(pacer.delay(start), Some(interval));
+        assert_eq!(
+            pacer.delay(start + Duration::from_millis(3)),
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(pacer.delay(start + interval), None);
+    }
+
+    #[test]
+    fn pacer_does_not_delay_queued_tds() {
+        let start = Instant::now();
+        let mut pacer = IntervalPacer::new(Some(Duration::from_millis(8)));
+
+        // The driver queued the next TD before the transfer completed.
+        pacer.completed(true, start);
+
+        assert_eq!(pacer.delay(start), None);
+    }
+
+    #[test]
+    fn pacer_does_not_delay_after_empty_transfers() {
+        let start = Instant::now();
+        let mut pacer = IntervalPacer::new(Some(Duration::from_millis(8)));
+
+        pacer.completed(false, start);
+        pacer.ran_dry();
+
+        assert_eq!(pacer.delay(start), None);
+    }
+
+    #[test]
+    fn disabled_pacer_never_delays() {
+        let start = Instant::now();
+        let mut pacer = IntervalPacer::new(None);
+
+        pacer.completed(true, start);
+        pacer.ran_dry();
+
+        assert_eq!(pacer.delay(start), None);
+    }
+
+    #[test]
+    fn running_empty_after_tds_counts_as_starved() {
+        let (mut engine, ram) = engine(None);
+        // An idle endpoint is not starved.
+        assert_eq!(engine.next_td(), None);
+        assert_eq!(engine.stats.counters().starved, 0);
+
+        place_normal_trb(&ram, 0, 0x800, 0x40, true);
+        assert!(engine.next_td().is_some());
+        assert_eq!(engine.next_td(), None);
+        assert_eq!(engine.next_td(), None);
+        assert_eq!(engine.stats.counters().starved, 1);
+    }
+
+    #[test]
+    fn starved_isoch_ring_reports_without_trb_pointer() {
+        let (mut engine, ram) = engine(None);
+        engine.isochronous = true;
+        // An idle endpoint is not starved.
+        assert_eq!(engine.next_td(), None);
+        engine.flush_events();
+        assert_eq!(transfer_event(&ram, 0), (0, 0, 0));
+
+        place_normal_trb(&ram, 0, 0x800, 0x40, false);
+        assert!(engine.next_td().is_some());
+        assert_eq!(engine.next_td(), None);
+        engine.flush_events();
+
+        // EP2 is an OUT endpoint.
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0, CompletionCode::RingUnderrun as u8, 0)
+        );
+        assert_eq!(engine.stats.counters().errors, 0);
+
+        // The endpoint keeps serving the TDs that the driver queues next.
+        place_normal_trb(&ram, 1, 0x800, 0x40, true);
+        assert!(engine.next_td().is_some());
+    }
+
+    #[test]
+    fn next_td_describes_normal_trbs() {
+        let (mut engine, ram) = engine(None);
+        assert_eq!(engine.next_td(), None);
+
+        place_normal_trb(&ram, 0, 0x800, 0x40, true);
+        place_normal_trb(&ram, 1, 0x900, 0x10, false);
+
+        assert_eq!(
+            engine.next_td(),
+            Some(TdDescriptor {
+                trb_address: 0x400,
+                data_pointer: 0x800,
+                transfer_length: 0x40,
+                interrupt_on_completion: true,
+            })
+        );
+        assert_eq!(
+            engine.next_td(),
+            Some(TdDescriptor {
+                trb_address: 0x410,
+                data_pointer: 0x900,
+                transfer_length: 0x10,
+                interrupt_on_completion: false,
+            })
+        );
+        assert_eq!(engine.next_td(), None);
+    }
+
+    #[test]
+    fn next_td_respects_doorbell_budget() {
+        let (mut engine, ram) = engine(NonZeroUsize::new(1));
+        place_normal_trb(&ram, 0, 0x800, 0x40, true);
+        place_normal_trb(&ram, 1, 0x900, 0x40, true);
+
+        assert!(engine.next_td().is_some());
+        assert_eq!(engine.next_td(), None);
+
+        engine.refill();
+        assert_eq!(engine.next_td().map(|td| td.trb_address), Some(0x410));
+    }
+
+    #[test]
+    fn stopped_controller_pauses_the_transfer_ring() {
+        let (mut engine, ram) = engine(None);
+        place_normal_trb(&ram, 0, 0x800, 0x40, true);
+
+        engine.run_state.stop();
+        assert_eq!(engine.next_td(), None);
+
+        // Starting the controller rings the doorbell of EP2 again, and the
+        // TD is still there.
+        let pending = engine.run_state.start();
+        assert_eq!(pending.endpoint_doorbells().collect::<Vec<_>>(), [(1, 2)]);
+        assert_eq!(engine.next_td().map(|td| td.trb_address), Some(0x400));
+    }
+
+    #[test]
+    fn completed_in_td_copies_data_and_reports_success() {
+        let (mut engine, ram) = engine(None);
+        place_normal_trb(&ram, 0, 0x800, 4, true);
+        let td = engine.next_td().unwrap();
+
+        engine.complete_td(
+            td,
+            TdOutcome::In {
+                data: &[0x11, 0x22, 0x33, 0x44, 0x55],
+                stopped: false,
+            },
+        );
+
+        let mut guest = [0; 5];
+        ram.read_bulk(0x800, &mut guest);
+        assert_eq!(guest, [0x11, 0x22, 0x33, 0x44, 0]);
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x400, CompletionCode::Success as u8, 0)
+        );
+    }
+
+    #[test]
+    fn processed_tds_show_up_in_the_stats() {
+        let (mut engine, ram) = engine(None);
+        place_normal_trb(&ram, 0, 0x800, 0x10, true);
+        place_normal_trb(&ram, 1, 0x900, 0x20, false);
+        place_normal_trb(&ram, 2, 0xff8, 0x10, true);
+
+        let td = engine.next_td().unwrap();
+        engine.complete_td(
+            td,
+            TdOutcome::In {
+                data: &[0x11; 0x8],
+                stopped: false,
+            },
+        );
+        let td = engine.next_td().unwrap();
+        engine.complete_td(
+            td,
+            TdOutcome::Out {
+                sent: 0x20,
+                stopped: false,
+            },
+        );
+        // The buffer runs past the end of guest memory.
+        let td = engine.next_td().unwrap();
+        engine.complete_td(
+            td,
+            TdOutcome::In {
+                data: &[0x22; 0x10],
+                stopped: false,
+            },
+        );
+
+        assert_eq!(
+            engine.stats.counters(),
+            EndpointCounters {
+                bytes: 0x28,
+                trbs: 3,
+                transfer_events: 2,
+                errors: 1,
+                starved: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn completed_td_without_ioc_reports_nothing() {
+        let (mut engine, ram) = engine(None);
+        place_normal_trb(&ram, 0, 0x800, 4, false);
+        let td = engine.next_td().unwrap();
+
+        engine.complete_td(
+            td,
+            TdOutcome::Out {
+                sent: 4,
+                stopped: false,
+            },
+        );
+
+        assert_eq!(transfer_event(&ram, 0), (0, 0, 0));
+    }
+
+    #[test]
+    fn stopped_tds_report_residual_bytes() {
+        let (mut engine, ram) = engine(None);
+        place_normal_trb(&ram, 0, 0x800, 0x40, false);
+        place_normal_trb(&ram, 1, 0x900, 0x40, false);
+
+        let td = engine.next_td().unwrap();
+        engine.complete_td(
+            td,
+            TdOutcome::Out {
+                sent: 0x10,
+                stopped: true,
+            },
+        );
+        let td = engine.next_td().unwrap();
+        engine.complete_td(
+            td,
+            TdOutcome::In {
+                data: &[0; 0x8],
+                stopped: true,
+            },
+        );
+
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x400, CompletionCode::Stopped as u8, 0x30)
+        );
+        assert_eq!(
+            transfer_event(&ram, 1),
+            (0x410, CompletionCode::Stopped as u8, 0x38)
+        );
+    }
+
+    #[test]
+    fn queued_transfers_are_submitted_before_the_first_completes() {
+        let (mut engine, ram) = engine(None);
+        for index in 0..4 {
+            place_normal_trb(&ram, index, 0x800 + index * 0x40, 0x40, true);
+        }
+        let mut in_flight = InFlightTds::new(NonZeroUsize::new(3).unwrap(), PACKETS);
+        let mut submitted = vec![];
+
+        let td = engine.next_td().unwrap();
+        in_flight.push(td, (), |size| submitted.push(size));
+        in_flight.top_up(&mut engine, || Some(()), |size| submitted.push(size));
+
+        assert_eq!(submitted, [0x40; 3]);
+        assert_eq!(transfer_event(&ram, 0), (0, 0, 0));
+
+        let cancel = in_flight.complete_oldest(
+            &mut engine,
+            TdOutcome::In {
+                data: &[0; 0x40],
+                stopped: false,
+            },
+            false,
+        );
+        assert!(!cancel);
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x400, CompletionCode::Success as u8, 0)
+        );
+
+        // The freed place goes to the next TD.
+        in_flight.top_up(&mut engine, || Some(()), |size| submitted.push(size));
+        assert_eq!(submitted, [0x40; 4]);
+        assert_eq!(engine.next_td(), None);
+    }
+
+    #[test]
+    fn large_td_completes_after_all_submissions() {
+        let (mut engine, ram) = engine_with_memory(0x3_0000, None);
+        place_normal_trb(&ram, 0, 0x1_0000, 0x1_0040, true);
+        let mut in_flight = InFlightTds::new(NonZeroUsize::MIN, PACKETS);
+        let mut submitted = vec![];
+
+        in_flight.push(engine.next_td().unwrap(), (), |size| submitted.push(size));
+        assert_eq!(submitted, [0x1_0000, 0x40]);
+
+        let first = TdOutcome::In {
+            data: &[0x11; 0x1_0000],
+            stopped: false,
+        };
+        assert!(!in_flight.complete_oldest(&mut engine, first, false));
+        assert!(!in_flight.is_empty());
+        assert_eq!(transfer_event(&ram, 0), (0, 0, 0));
+
+        let second = TdOutcome::In {
+            data: &[0x22; 0x40],
+            stopped: false,
+        };
+        assert!(!in_flight.complete_oldest(&mut engine, second, false));
+        assert!(in_flight.is_empty());
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x400, CompletionCode::Success as u8, 0)
+        );
+        assert_eq!(ram.read(Request::new(0x1_ffff, RequestSize::Size1)), 0x11);
+        assert_eq!(ram.read(Request::new(0x2_0000, RequestSize::Size1)), 0x22);
+    }
+
+    #[test]
+    fn short_submission_ends_the_td() {
+        let (mut engine, ram) = engine_with_memory(0x3_0000, None);
+        place_normal_trb(&ram, 0, 0x1_0000, 0x1_0040, true);
+        place_normal_trb(&ram, 1, 0x800, 0x40, true);
+        let mut in_flight = InFlightTds::new(NonZeroUsize::new(2).unwrap(), PACKETS);
+        in_flight.push(engine.next_td().unwrap(), (), |_| {});
+        in_flight.top_up(&mut engine, || Some(()), |_| {});
+
+        // The second submission of the TD would receive the data of the
+        // next transfer, so it is cancelled along with the next TD.
+        let short = TdOutcome::In {
+            data: &[0x11; 0x80],
+            stopped: false,
+        };
+        assert!(in_flight.complete_oldest(&mut engine, short, false));
+        let cancelled = TdOutcome::In {
+            data: &[],
+            stopped: true,
+        };
+        assert!(!in_flight.complete_oldest(&mut engine, cancelled, true));
+        assert!(!in_flight.complete_oldest(&mut engine, cancelled, true));
+        assert!(in_flight.is_empty());
+
+        assert_eq!(transfer_event(&ram, 0).0, 0x400);
+        assert_eq!(transfer_event(&ram, 1), (0, 0, 0));
+        assert_eq!(engine.next_td().unwrap().trb_address, 0x410);
+    }
+
+    #[test]
+    fn top_up_stops_without_permit() {
+        let (mut engine, ram) = engine(None);
+        for index in 0..3 {
+            place_normal_trb(&ram, index, 0x800, 0x40, true);
+        }
+        let mut in_flight = InFlightTds::new(NonZeroUsize::new(3).unwrap(), PACKETS);
+        let mut submitted = 0;
+
+        in_flight.push(engine.next_td().unwrap(), (), |_| {});
+        in_flight.top_up(&mut engine, || None, |_| submitted += 1);
+
+        assert_eq!(submitted, 0);
+        assert_eq!(engine.next_td().unwrap().trb_address, 0x410);
+    }
+
+    #[test]
+    fn transfers_behind_a_stopped_one_go_back_to_the_ring() {
+        let (mut engine, ram) = engine(None);
+        for index in 0..3 {
+            place_normal_trb(&ram, index, 0x800 + index * 0x40, 0x40, true);
+        }
+        let mut in_flight = InFlightTds::new(NonZeroUsize::new(3).unwrap(), PACKETS);
+        in_flight.push(engine.next_td().unwrap(), (), |_| {});
+        in_flight.top_up(&mut engine, || Some(()), |_| {});
+
+        let stopped = TdOutcome::In {
+            data: &[0; 0x8],
+            stopped: true,
+        };
+        assert!(in_flight.complete_oldest(&mut engine, stopped, true));
+        let cancelled = TdOutcome::In {
+            data: &[],
+            stopped: true,
+        };
+        assert!(!in_flight.complete_oldest(&mut engine, cancelled, true));
+        assert!(!in_flight.complete_oldest(&mut engine, cancelled, true));
+        assert!(in_flight.is_empty());
+
+        // Only the stopped TD is reported.
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x400, CompletionCode::Stopped as u8, 0x38)
+        );
+        assert_eq!(transfer_event(&ram, 1), (0, 0, 0));
+        assert_eq!(engine.next_td().unwrap().trb_address, 0x410);
+        assert_eq!(engine.next_td().unwrap().trb_address, 0x420);
+    }
+
+    #[test]
+    fn max_packet_multiple_transfer_is_terminated_by_zlp() {
+        let (mut engine, ram) = engine(None);
+        // Linux queues the zero-length TD with a null data pointer. Point
+        // it past guest memory to show that it is not read.
+        place_normal_trb(&ram, 0, 0x800, 0x200, true);
+        place_normal_trb(&ram, 1, 0xdead_0000, 0, true);
+
+        let data = engine.next_td().unwrap();
+        assert_eq!(engine.out_data(&data).map(|data| data.len()), Some(0x200));
+        let zlp = engine.next_td().unwrap();
+        assert_eq!(engine.out_data(&zlp), Some(vec![]));
+
+        engine.complete_td(
+            zlp,
+            TdOutcome::Out {
+                sent: 0,
+                stopped: false,
+            },
+        );
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x410, CompletionCode::Success as u8, 0)
+        );
+    }
+
+    #[test]
+    fn out_data_of_unmapped_buffer_fails_td() {
+        let (mut engine, ram) = engine(None);
+        place_normal_trb(&ram, 0, 0xf80, 0x100, false);
+        place_normal_trb(&ram, 1, 0x800, 4, false);
+        ram.write_bulk(0x800, &[1, 2, 3, 4]);
+
+        let td = engine.next_td().unwrap();
+        assert_eq!(engine.out_data(&td), None);
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x400, CompletionCode::DataBufferError as u8, 0x100)
+        );
+
+        let td = engine.next_td().unwrap();
+        assert_eq!(engine.out_data(&td), Some(vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn in_data_to_buffer_spanning_a_gap_fails_td() {
+        let (mut engine, ram) = engine(None);
+        // Guest memory ends at 0x1000.
+        place_normal_trb(&ram, 0, 0xff8, 0x10, true);
+        let td = engine.next_td().unwrap();
+
+        engine.complete_td(
+            td,
+            TdOutcome::In {
+                data: &[0x11; 0x10],
+                stopped: false,
+            },
+        );
+
+        assert_eq!(
+            transfer_event(&ram, 0),
+            (0x400, CompletionCode::DataBufferError as u8, 0x10)
+        );
+    }
+
+    fn guest_memory() -> BusDeviceRef {
+        let bus = DynamicBus::new();
+        bus.add(0x1000, Arc::new(TestBusDevice::new(&[0x42; 0x1000])))
+            .unwrap();
+        Arc::new(bus)
+    }
+
+    #[test]
+    fn out_data_from_mapped_buffer() {
+        assert_eq!(
+            read_out_data(&guest_memory(), 0x1800, 0x100),
+            Ok(vec![0x42; 0x100])
+        );
+    }
+
+    #[test]
+    fn out_data_from_partially_mapped_buffer() {
+        assert_eq!(
+            read_out_data(&guest_memory(), 0x1f80, 0x100),
+            Err(0x2000..0x2080)
+        );
+    }
+
+    #[test]
+    fn out_data_from_unmapped_buffer() {
+        assert_eq!(
+            read_out_data(&guest_memory(), 0x4000, 0x100),
+            Err(0x4000..0x4100)
+        );
+    }
+
+    #[test]
+    fn in_data_to_partially_mapped_buffer() {
+        let memory = guest_memory();
+        assert_eq!(
+            write_in_data(&memory, 0x1f80, &[0x11; 0x100], 0x100),
+            Err(0x2000..0x2080)
+        );
+
+        // The mapped part is written nonetheless.
+        let mut data = [0; 0x80];
+        memory.read_bulk(0x1f80, &mut data);
+        assert_eq!(data, [0x11; 0x80]);
+    }
+
+    #[test]
+    fn in_data_is_clamped_to_requested_length() {
+        let memory = Arc::new(TestBusDevice::new(&[0xff; 0x10]));
+        let dma_bus: BusDeviceRef = memory.clone();
+
+        // The device returned more than requested.
+        assert_eq!(write_in_data(&dma_bus, 0x4, &[0x11; 8], 4), Ok(4));
+        // The device returned less than requested.
+        assert_eq!(write_in_data(&dma_bus, 0xc, &[0x22; 2], 4), Ok(2));
+
+        let mut guest = [0; 0x10];
+        memory.read_bulk(0, &mut guest);
+        assert_eq!(
+            guest,
+            [
+                0xff, 0xff, 0xff, 0xff, 0x11, 0x11, 0x11, 0x11, 0xff, 0xff, 0xff, 0xff, 0x22, 0x22,
+                0xff, 0xff
+            ]
+        );
+    }
+
+    #[test]
+    fn doorbell_budget_bounds_trbs_per_doorbell() {
+        let mut budget = DoorbellBudget::new(Dci::new(2).unwrap(), NonZeroUsize::new(2));
+
+        assert_eq!(budget.fetch(|| Some(1)), Some(1));
+        // An empty ring does not use up the budget.
+        assert_eq!(budget.fetch(|| None::<u32>), None);
+        assert_eq!(budget.fetch(|| Some(2)), Some(2));
+        assert_eq!(budget.fetch(|| Some(3)), None);
+        assert_eq!(budget.exhausted, 1);
+
+        budget.refill();
+        assert_eq!(budget.fetch(|| Some(3)), Some(3));
+    }
+
+    #[test]
+    fn doorbell_budget_is_unlimited_by_default() {
+        let mut budget = DoorbellBudget::new(Dci::new(2).unwrap(), None);
+
+        assert!((0..1000).all(|i| budget.fetch(|| Some(i)) == Some(i)));
+        assert_eq!(budget.exhausted, 0);
+    }
+}
@@ -0,0 +1,554 @@
+//! # Trace Recording
+//!
+//! Bugs in the interaction with a guest driver are hard to reproduce
+//! without the guest. With `--record`, the controller appends what it sees
+//! of the guest to a trace: the register accesses, the guest memory it
+//! reads, and what it writes to guest memory. The replay test module
+//! feeds such a trace to a fresh controller, which turns a bug report
+//! into a test.
+//!
+//! A trace is text with one [`TraceEvent`] per line:
+//!
+//! ```text
+//! w <region> <offset> <size> <value>   register write
+//! r <region> <offset> <size> <value>   register read and its result
+//! db <target> <value>                  doorbell write
+//! dma <address> <hex bytes>            guest memory the controller read
+//! dmaw <address> <hex bytes>           guest memory the controller wrote
+//! t <microseconds>                     time since the recording started
+//! ```
+//!
+//! Lines starting with `#` are comments. Register accesses are recorded
+//! once they are handled, so the guest memory an access reads precedes
+//! it. Endpoint workers access guest memory concurrently, so their
+//! accesses interleave with those of the controller in no particular
+//! order.
+//!
+//! Recording formats every access, so it is only meant for capturing a
+//! bug.
+
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    num::ParseIntError,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tracing::warn;
+
+use crate::device::bus::{BusDevice, BusDeviceRef, Request, RequestSize};
+
+use super::{constants::xhci::offset, mmio_profile::MmioAccess};
+
+/// Time markers are recorded at most this often.
+const TIME_MARKER_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Something the controller saw of the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// The guest wrote `value` to a register.
+    Write {
+        region: u32,
+        offset: u64,
+        size: RequestSize,
+        value: u64,
+    },
+    /// The guest read `value` from a register.
+    Read {
+        region: u32,
+        offset: u64,
+        size: RequestSize,
+        value: u64,
+    },
+    /// The guest wrote `value` to a doorbell register. Target 0 is the Host
+    /// Controller Doorbell, all others are the doorbells of Slot IDs.
+    Doorbell { target: u8, value: u32 },
+    /// The controller read `data` from guest memory at `address`.
+    Dma { address: u64, data: Vec<u8> },
+    /// The controller wrote `data` to guest memory at `address`.
+    DmaWrite { address: u64, data: Vec<u8> },
+    /// The time since the recording started.
+    Time(Duration),
+}
+
+impl TraceEvent {
+    /// The event for a register access of the guest.
+    ///
+    /// Dword writes to the doorbell array are doorbells.
+    pub fn access(access: MmioAccess, region: u32, req: Request, value: u64) -> Self {
+        let (offset, size) = (req.addr, req.size);
+        match access {
+            MmioAccess::Write
+                if region == 0
+                    && size == RequestSize::Size4
+                    && offset % 4 == 0
+                    && (offset::DOORBELL_CONTROLLER..offset::DOORBELL_DEVICE_END)
+                        .contains(&offset) =>
+            {
+                Self::Doorbell {
+                    target: ((offset - offset::DOORBELL_CONTROLLER) / 4) as u8,
+                    value: value as u32,
+                }
+            }
+            MmioAccess::Write => Self::Write {
+                region,
+                offset,
+                size,
+                value,
+            },
+            MmioAccess::Read => Self::Read {
+                region,
+                offset,
+                size,
+                value,
+            },
+        }
+    }
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Write {
+                region,
+                offset,
+                size,
+                value,
+            } => write!(f, "w {region} {offset:#x} {size} {value:#x}"),
+            Self::Read {
+                region,
+                offset,
+                size,
+                value,
+            } => write!(f, "r {region} {offset:#x} {size} {value:#x}"),
+            Self::Doorbell { target, value } => write!(f, "db {target} {value:#x}"),
+            Self::Dma { address, data } => write!(f, "dma {address:#x} {}", Hex(data)),
+            Self::DmaWrite { address, data } => write!(f, "dmaw {address:#x} {}", Hex(data)),
+            Self::Time(time) => write!(f, "t {}", time.as_micros()),
+        }
+    }
+}
+
+/// Why a line of a trace is no [`TraceEvent`].
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum TraceParseError {
+    #[error("unknown event {0:?}")]
+    UnknownEvent(String),
+    #[error("missing field")]
+    MissingField,
+    #[error("unexpected field {0:?}")]
+    UnexpectedField(String),
+    #[error("invalid number: {0}")]
+    InvalidNumber(#[from] ParseIntError),
+    #[error("number {0:#x} is out of range")]
+    OutOfRange(u64),
+    #[error("invalid access size {0}")]
+    InvalidSize(u64),
+    #[error("invalid hex bytes {0:?}")]
+    InvalidBytes(String),
+}
+
+impl FromStr for TraceEvent {
+    type Err = TraceParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_whitespace();
+        let mut next = || fields.next().ok_or(TraceParseError::MissingField);
+        let kind = next()?;
+        let event = match kind {
+            "w" | "r" => {
+                let region = parse_int(next()?)?;
+                let offset = parse_number(next()?)?;
+                let size = parse_number(next()?)?;
+                let size =
+                    RequestSize::try_from(size).map_err(|_| TraceParseError::InvalidSize(size))?;
+                let value = parse_number(next()?)?;
+                match kind {
+                    "w" => Self::Write {
+                        region,
+                        offset,
+                        size,
+                        value,
+                    },
+                    _ => Self::Read {
+                        region,
+                        offset,
+                        size,
+                        value,
+                    },
+                }
+            }
+            "db" => Self::Doorbell {
+                target: parse_int(next()?)?,
+                value: parse_int(next()?)?,
+            },
+            "dma" | "dmaw" => {
+                let address = parse_number(next()?)?;
+                // Accesses without data have no bytes field.
+                let data = fields.next().map_or(Ok(Vec::new()), parse_hex)?;
+                match kind {
+                    "dma" => Self::Dma { address, data },
+                    _ => Self::DmaWrite { address, data },
+                }
+            }
+            "t" => Self::Time(Duration::from_micros(parse_number(next()?)?)),
+            kind => return Err(TraceParseError::UnknownEvent(kind.to_string())),
+        };
+        fields.next().map_or(Ok(event), |field| {
+            Err(TraceParseError::UnexpectedField(field.to_string()))
+        })
+    }
+}
+
+/// Parse a decimal number or a hexadecimal one with `0x` prefix.
+fn parse_number(field: &str) -> Result<u64, ParseIntError> {
+    field
+        .strip_prefix("0x")
+        .map_or_else(|| field.parse(), |hex| u64::from_str_radix(hex, 16))
+}
+
+/// Like [`parse_number`], for narrower integers.
+fn parse_int<T: TryFrom<u64>>(field: &str) -> Result<T, TraceParseError> {
+    let number = parse_number(field)?;
+    T::try_from(number).map_err(|_| TraceParseError::OutOfRange(number))
+}
+
+/// Parse bytes written as pairs of hex digits.
+fn parse_hex(field: &str) -> Result<Vec<u8>, TraceParseError> {
+    let invalid = || TraceParseError::InvalidBytes(field.to_string());
+    if !field.len().is_multiple_of(2) || !field.is_ascii() {
+        return Err(invalid());
+    }
+    (0..field.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&field[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+/// Bytes formatted as pairs of hex digits.
+struct Hex<'a>(&'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+/// Appends [`TraceEvent`]s to a trace.
+pub struct TraceRecorder {
+    output: Mutex<Output>,
+    start: Instant,
+    /// Whether writing the trace failed, which we only report once.
+    failed: AtomicBool,
+}
+
+struct Output {
+    writer: Box<dyn Write + Send>,
+    /// When the next time marker is due, relative to the start.
+    next_marker: Duration,
+}
+
+impl Debug for TraceRecorder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceRecorder")
+            .field("start", &self.start)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TraceRecorder {
+    /// Record a trace to `writer`.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            output: Mutex::new(Output {
+                writer: Box::new(writer),
+                next_marker: Duration::ZERO,
+            }),
+            start: Instant::now(),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Append a trace to the file at `path`, which is created if it does
+    /// not exist yet.
+    pub fn append_to(path: &Path) -> io::Result<Self> {
+        let mut file = LineWriter::new(OpenOptions::new().create(true).append(true).open(path)?);
+        writeln!(
+            file,
+            "# usbvfiod {} recording started",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        Ok(Self::new(file))
+    }
+
+    /// Append `event` to the trace, preceded by a time marker if one is
+    /// due.
+    pub fn record(&self, event: &TraceEvent) {
+        let now = self.start.elapsed();
+        let result = {
+            let mut output = self.output.lock().unwrap();
+            output.write(now, event)
+        };
+        if let Err(error) = result {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                warn!("failed to record trace, further events are lost: {error}");
+            }
+        }
+    }
+}
+
+impl Output {
+    fn write(&mut self, now: Duration, event: &TraceEvent) -> io::Result<()> {
+        if now >= self.next_marker {
+            writeln!(self.writer, "{}", TraceEvent::Time(now))?;
+            self.next_marker = now + TIME_MARKER_INTERVAL;
+        }
+        writeln!(self.writer, "{event}")
+    }
+}
+
+/// Record the guest memory accesses through `dma_bus` with `recorder`.
+///
+/// Without a recorder, this is `dma_bus` itself.
+pub fn tag(dma_bus: &BusDeviceRef, recorder: Option<&Arc<TraceRecorder>>) -> BusDeviceRef {
+    match recorder {
+        Some(recorder) => Arc::new(RecordingDma {
+            inner: dma_bus.clone(),
+            recorder: recorder.clone(),
+        }),
+        None => dma_bus.clone(),
+    }
+}
+
+/// A view of the DMA bus that records all accesses.
+#[derive(Debug)]
+pub struct RecordingDma {
+    inner: BusDeviceRef,
+    recorder: Arc<TraceRecorder>,
+}
+
+impl RecordingDma {
+    fn record_read(&self, address: u64, data: &[u8]) {
+        self.recorder.record(&TraceEvent::Dma {
+            address,
+            data: data.to_vec(),
+        });
+    }
+
+    fn record_write(&self, address: u64, data: &[u8]) {
+        self.recorder.record(&TraceEvent::DmaWrite {
+            address,
+            data: data.to_vec(),
+        });
+    }
+}
+
+/// The bytes of a `value` that an access of `size` transfers.
+fn access_bytes(size: RequestSize, value: u64) -> Vec<u8> {
+    value.to_le_bytes()[..u64::from(size) as usize].to_vec()
+}
+
+impl BusDevice for RecordingDma {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read(&self, req: Request) -> u64 {
+        let value = self.inner.read(req);
+        self.record_read(req.addr, &access_bytes(req.size, value));
+        value
+    }
+
+    fn write(&self, req: Request, value: u64) {
+        self.inner.write(req, value);
+        self.record_write(req.addr, &access_bytes(req.size, value));
+    }
+
+    fn read_bulk(&self, offset: u64, data: &mut [u8]) {
+        self.inner.read_bulk(offset, data);
+        self.record_read(offset, data);
+    }
+
+    #[cfg(any(feature = "nusb-backend", test))]
+    fn try_read_bulk(&self, offset: u64, data: &mut [u8]) -> usize {
+        let served = self.inner.try_read_bulk(offset, data);
+        self.record_read(offset, data);
+        served
+    }
+
+    fn write_bulk(&self, offset: u64, data: &[u8]) {
+        self.inner.write_bulk(offset, data);
+        self.record_write(offset, data);
+    }
+
+    fn try_write_bulk(&self, offset: u64, data: &[u8]) -> usize {
+        let served = self.inner.try_write_bulk(offset, data);
+        self.record_write(offset, data);
+        served
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{
+        bus::testutils::TestBusDevice,
+        pci::replay::{parse_trace, SharedBuffer},
+    };
+
+    use super::*;
+
+    #[test]
+    fn events_survive_a_round_trip() {
+        let events = [
+            TraceEvent::Write {
+                region: 0,
+                offset: 0x40,
+                size: RequestSize::Size4,
+                value: 0x1,
+            },
+            TraceEvent::Read {
+                region: 3,
+                offset: 0x1000,
+                size: RequestSize::Size8,
+                value: u64::MAX,
+            },
+            TraceEvent::Doorbell {
+                target: 1,
+                value: 0x3,
+            },
+            TraceEvent::Dma {
+                address: 0x3000,
+                data: vec![0x00, 0x5a, 0xff],
+            },
+            TraceEvent::DmaWrite {
+                address: 0x5000,
+                data: vec![],
+            },
+            TraceEvent::Time(Duration::from_micros(1500)),
+        ];
+        for event in events {
+            let line = event.to_string();
+            assert_eq!(line.parse(), Ok(event), "{line}");
+        }
+        assert_eq!(
+            TraceEvent::Doorbell {
+                target: 0,
+                value: 0
+            }
+            .to_string(),
+            "db 0 0x0"
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let parse = |line: &str| line.parse::<TraceEvent>().unwrap_err();
+        assert_eq!(parse("x 1"), TraceParseError::UnknownEvent("x".to_string()));
+        assert_eq!(parse("w 0 0x40 4"), TraceParseError::MissingField);
+        assert_eq!(
+            parse("t 5 6"),
+            TraceParseError::UnexpectedField("6".to_string())
+        );
+        assert_eq!(parse("w 0 0x40 3 0"), TraceParseError::InvalidSize(3));
+        assert_eq!(parse("db 256 0"), TraceParseError::OutOfRange(256));
+        assert!(matches!(
+            parse("r 0 0x4g 4 0"),
+            TraceParseError::InvalidNumber(_)
+        ));
+        assert_eq!(
+            parse("dma 0x0 abc"),
+            TraceParseError::InvalidBytes("abc".to_string())
+        );
+    }
+
+    #[test]
+    fn doorbell_writes_are_recognized() {
+        let access = |access, region, addr, size| {
+            TraceEvent::access(access, region, Request::new(addr, size), 2)
+        };
+        assert_eq!(
+            access(
+                MmioAccess::Write,
+                0,
+                offset::DOORBELL_DEVICE + 4,
+                RequestSize::Size4
+            ),
+            TraceEvent::Doorbell {
+                target: 2,
+                value: 2
+            }
+        );
+        for (access, region, addr, size) in [
+            (
+                MmioAccess::Read,
+                0,
+                offset::DOORBELL_CONTROLLER,
+                RequestSize::Size4,
+            ),
+            (
+                MmioAccess::Write,
+                3,
+                offset::DOORBELL_CONTROLLER,
+                RequestSize::Size4,
+            ),
+            (
+                MmioAccess::Write,
+                0,
+                offset::DOORBELL_CONTROLLER + 1,
+                RequestSize::Size1,
+            ),
+            (
+                MmioAccess::Write,
+                0,
+                offset::DOORBELL_DEVICE_END,
+                RequestSize::Size4,
+            ),
+        ] {
+            assert!(!matches!(
+                TraceEvent::access(access, region, Request::new(addr, size), 0),
+                TraceEvent::Doorbell { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn dma_accesses_are_recorded() {
+        let buffer = SharedBuffer::default();
+        let recorder = Arc::new(TraceRecorder::new(buffer.clone()));
+        let ram: BusDeviceRef = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let dma_bus = tag(&ram, Some(&recorder));
+
+        dma_bus.write_bulk(0x10, &[1, 2, 3]);
+        assert_eq!(dma_bus.read(Request::new(0x10, RequestSize::Size2)), 0x0201);
+
+        let events = parse_trace(&buffer.text()).unwrap();
+        let accesses: Vec<_> = events
+            .into_iter()
+            .filter(|event| !matches!(event, TraceEvent::Time(_)))
+            .collect();
+        assert_eq!(
+            accesses,
+            [
+                TraceEvent::DmaWrite {
+                    address: 0x10,
+                    data: vec![1, 2, 3]
+                },
+                TraceEvent::Dma {
+                    address: 0x10,
+                    data: vec![1, 2]
+                },
+            ]
+        );
+
+        // Without a recorder, the bus is used as is.
+        assert!(Arc::ptr_eq(&tag(&ram, None), &ram));
+    }
+}
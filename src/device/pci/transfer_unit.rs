@@ -0,0 +1,250 @@
+//! # Transfer Sizing
+//!
+//! SuperSpeed endpoints move data in bursts of up to Max Burst Size + 1
+//! packets before the device has to acknowledge them. Host transfers whose
+//! sizes are multiples of a burst keep the bursts intact, which storage
+//! devices reward with throughput and a few picky devices insist on.
+//!
+//! A [`TransferUnit`] captures the packet and burst sizes the driver
+//! configured in the endpoint context. `split_td` divides a TD into the
+//! host submissions that transfer it, which the worker completes as a single
+//! TD towards the driver.
+
+use std::fmt;
+
+/// The most data a single host submission transfers.
+///
+/// Larger TDs are split into several submissions, each of which is a
+/// multiple of the transfer unit.
+pub const MAX_SUBMISSION_SIZE: usize = 64 * 1024;
+
+/// The sizes the driver configured in an endpoint context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferUnit {
+    /// The Max Packet Size field.
+    pub max_packet_size: u16,
+    /// The Max Burst Size field, i.e., the number of additional packets
+    /// per burst. Always 0 below SuperSpeed.
+    pub max_burst_size: u8,
+    /// The Max ESIT Payload of a periodic endpoint, i.e., the most data it
+    /// moves per service interval. 0 for other endpoints.
+    pub max_esit_payload: u32,
+}
+
+impl TransferUnit {
+    /// The Max Packet Size in bytes. A size of 0, which only a broken
+    /// endpoint context has, counts as a single byte.
+    pub fn packet_bytes(&self) -> usize {
+        usize::from(self.max_packet_size).max(1)
+    }
+
+    /// The bytes of a full burst.
+    ///
+    /// A periodic endpoint never moves more than its Max ESIT Payload at
+    /// once, so its unit is that payload rounded up to whole packets.
+    pub fn burst_bytes(&self) -> usize {
+        let burst = self.packet_bytes() * (usize::from(self.max_burst_size) + 1);
+        match self.max_esit_payload {
+            0 => burst,
+            payload => burst.min(self.buffer_size(payload as usize)),
+        }
+    }
+
+    /// The size of the largest host submission, which is the largest
+    /// multiple of the burst that fits [`MAX_SUBMISSION_SIZE`], but at
+    /// least one burst.
+    pub fn submission_limit(&self) -> usize {
+        let burst = self.burst_bytes();
+        (MAX_SUBMISSION_SIZE / burst).max(1) * burst
+    }
+
+    /// The size of the buffer that receives `length` bytes of IN data.
+    ///
+    /// Devices always send whole packets, so the buffer is rounded up to
+    /// whole packets and holds at least one.
+    pub fn buffer_size(&self, length: usize) -> usize {
+        let packet = self.packet_bytes();
+        length.div_ceil(packet).max(1) * packet
+    }
+}
+
+impl fmt::Display for TransferUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "max packet size {}, max burst size {}",
+            self.max_packet_size, self.max_burst_size
+        )?;
+        if self.max_esit_payload != 0 {
+            write!(f, ", max ESIT payload {}", self.max_esit_payload)?;
+        }
+        write!(
+            f,
+            " ({} bytes per burst, up to {} per submission)",
+            self.burst_bytes(),
+            self.submission_limit()
+        )
+    }
+}
+
+/// The lengths of the host submissions that transfer a TD of `length`
+/// bytes, in order.
+///
+/// All submissions but the last have the [`submission
+/// limit`](TransferUnit::submission_limit), which is a multiple of the
+/// burst, and the last carries the rest. A TD without data is a single
+/// zero-length submission.
+#[cfg(any(feature = "nusb-backend", test))]
+pub fn split_td(length: usize, unit: TransferUnit) -> Vec<usize> {
+    let limit = unit.submission_limit();
+    if length == 0 {
+        return vec![0];
+    }
+    let mut lengths = vec![limit; (length - 1) / limit];
+    lengths.push(length - lengths.len() * limit);
+    lengths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_PACKET_SIZES: [u16; 5] = [8, 64, 512, 1023, 1024];
+    const MAX_BURST_SIZES: [u8; 4] = [0, 1, 3, 15];
+
+    /// A unit of single packets of `max_packet_size` bytes.
+    const fn packets(max_packet_size: u16) -> TransferUnit {
+        TransferUnit {
+            max_packet_size,
+            max_burst_size: 0,
+            max_esit_payload: 0,
+        }
+    }
+
+    /// The lengths around all boundaries that matter for `unit`.
+    fn interesting_lengths(unit: TransferUnit) -> Vec<usize> {
+        let packet = unit.packet_bytes();
+        let burst = unit.burst_bytes();
+        let limit = unit.submission_limit();
+        let mut lengths = vec![0, 1, 0x1_ffff];
+        for boundary in [packet, burst, limit, 2 * limit, 3 * burst + packet] {
+            lengths.extend([boundary - 1, boundary, boundary + 1]);
+        }
+        lengths
+    }
+
+    #[test]
+    fn burst_multiplies_the_packet_size() {
+        let unit = TransferUnit {
+            max_packet_size: 1024,
+            max_burst_size: 15,
+            max_esit_payload: 0,
+        };
+        assert_eq!(unit.burst_bytes(), 16 * 1024);
+        assert_eq!(unit.submission_limit(), 64 * 1024);
+
+        // A burst of three 1023 byte packets does not divide 64 KiB.
+        let unit = TransferUnit {
+            max_packet_size: 1023,
+            max_burst_size: 2,
+            max_esit_payload: 0,
+        };
+        assert_eq!(unit.submission_limit(), 21 * 3 * 1023);
+
+        assert_eq!(packets(512).burst_bytes(), 512);
+    }
+
+    #[test]
+    fn periodic_endpoints_burst_at_most_their_esit_payload() {
+        let unit = TransferUnit {
+            max_packet_size: 1024,
+            max_burst_size: 2,
+            max_esit_payload: 1500,
+        };
+        assert_eq!(unit.burst_bytes(), 2048);
+
+        let unit = TransferUnit {
+            max_esit_payload: 8192,
+            ..unit
+        };
+        assert_eq!(unit.burst_bytes(), 3072);
+    }
+
+    #[test]
+    fn bursts_larger_than_a_submission_are_not_split() {
+        let unit = TransferUnit {
+            max_packet_size: 0xffff,
+            max_burst_size: 15,
+            max_esit_payload: 0,
+        };
+        assert_eq!(unit.submission_limit(), 16 * 0xffff);
+        assert_eq!(split_td(0x1_ffff, unit), [0x1_ffff]);
+    }
+
+    #[test]
+    fn td_without_data_is_a_single_zero_length_submission() {
+        assert_eq!(split_td(0, packets(512)), [0]);
+    }
+
+    #[test]
+    fn large_td_is_split_at_burst_boundaries() {
+        let unit = TransferUnit {
+            max_packet_size: 1024,
+            max_burst_size: 2,
+            max_esit_payload: 0,
+        };
+        // 21 bursts of 3 KiB fit into 64 KiB.
+        assert_eq!(split_td(0x1_0000, unit), [63 * 1024, 1024]);
+        assert_eq!(split_td(63 * 1024, unit), [63 * 1024]);
+    }
+
+    #[test]
+    fn splits_cover_the_td_in_bursts() {
+        for max_packet_size in MAX_PACKET_SIZES {
+            for max_burst_size in MAX_BURST_SIZES {
+                for max_esit_payload in [0, 3000] {
+                    let unit = TransferUnit {
+                        max_packet_size,
+                        max_burst_size,
+                        max_esit_payload,
+                    };
+                    for length in interesting_lengths(unit) {
+                        let lengths = split_td(length, unit);
+                        let context = format!("{unit}, TD of {length} bytes: {lengths:?}");
+
+                        assert_eq!(lengths.iter().sum::<usize>(), length, "{context}");
+                        assert_eq!(
+                            lengths.len(),
+                            length.div_ceil(unit.submission_limit()).max(1),
+                            "{context}"
+                        );
+                        let (last, full) = lengths.split_last().unwrap();
+                        assert!(*last <= unit.submission_limit(), "{context}");
+                        assert!(length == 0 || *last > 0, "{context}");
+                        for length in full {
+                            assert_eq!(*length, unit.submission_limit(), "{context}");
+                            assert_eq!(length % unit.burst_bytes(), 0, "{context}");
+                            assert_eq!(length % unit.packet_bytes(), 0, "{context}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn buffers_hold_whole_packets() {
+        for max_packet_size in MAX_PACKET_SIZES {
+            let unit = packets(max_packet_size);
+            for length in interesting_lengths(unit) {
+                let size = unit.buffer_size(length);
+                assert!(size >= length.max(1), "{unit}, {length} bytes");
+                assert_eq!(size % unit.packet_bytes(), 0, "{unit}, {length} bytes");
+                assert!(size - length.max(1) < unit.packet_bytes());
+            }
+        }
+
+        assert_eq!(packets(512).buffer_size(0), 512);
+        assert_eq!(packets(0).buffer_size(3), 3);
+    }
+}
@@ -3,9 +3,15 @@
 //! The specification is available
 //! [here](https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf).
 
+use std::fmt;
+
 use thiserror::Error;
 
-use super::constants::xhci::rings::trb_types::{self, *};
+use super::{
+    constants::xhci::rings::trb_types::{self, *},
+    dci::{Dci, Direction},
+    trb_fields::{bits, TrbBuilder, TrbFields},
+};
 
 /// Dedicated type to indicate that a 16 byte array represents the contents
 /// of a Transfer Request Block.
@@ -19,14 +25,14 @@ pub const fn zeroed_trb_buffer() -> RawTrbBuffer {
 /// Represents a TRB that the XHCI controller can place on the event ring.
 ///
 /// See XHCI specification Section 6.4.2 for detailed event TRB type descriptions.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EventTrb {
     Transfer(TransferEventTrbData),
     CommandCompletion(CommandCompletionEventTrbData),
     PortStatusChange(PortStatusChangeEventTrbData),
     //BandwidthRequest,
     //Doorbell,
-    //HostController,
+    HostController(HostControllerEventTrbData),
     //DeviceNotification,
     //MfIndexWrap,
 }
@@ -41,17 +47,17 @@ impl EventTrb {
     ///
     /// - `cycle_bit`: value to set the cycle bit to. Has to match the ring
     ///   where the caller will write the TRB on.
-    pub fn to_bytes(&self, cycle_bit: bool) -> RawTrbBuffer {
+    pub const fn to_bytes(&self, cycle_bit: bool) -> RawTrbBuffer {
         // layout the event-type-specific data
-        let mut trb_data = match self {
+        let trb_data = match self {
             Self::Transfer(data) => data.to_bytes(),
             Self::CommandCompletion(data) => data.to_bytes(),
             Self::PortStatusChange(data) => data.to_bytes(),
+            Self::HostController(data) => data.to_bytes(),
         };
-        // set cycle bit
-        trb_data[12] = (trb_data[12] & !0x1) | cycle_bit as u8;
-
-        trb_data
+        TrbBuilder::from_bytes(trb_data)
+            .bit(bits::CYCLE, cycle_bit)
+            .build()
     }
 }
 
@@ -59,7 +65,7 @@ impl EventTrb {
 ///
 /// Do not use this struct directly, use EventTrb::new_command_completion_event_trb
 /// instead.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CommandCompletionEventTrbData {
     command_trb_pointer: u64,
     command_completion_parameter: u32,
@@ -110,16 +116,16 @@ impl EventTrb {
 }
 
 impl CommandCompletionEventTrbData {
-    fn to_bytes(&self) -> RawTrbBuffer {
-        let mut trb = zeroed_trb_buffer();
-
-        trb[0..8].copy_from_slice(&self.command_trb_pointer.to_le_bytes());
-        trb[8..11].copy_from_slice(&self.command_completion_parameter.to_le_bytes()[0..3]);
-        trb[11] = self.completion_code as u8;
-        trb[13] = COMMAND_COMPLETION_EVENT << 2;
-        trb[15] = self.slot_id;
-
-        trb
+    const fn to_bytes(&self) -> RawTrbBuffer {
+        TrbBuilder::new(COMMAND_COMPLETION_EVENT)
+            .u64_le(0, self.command_trb_pointer)
+            .bits(
+                bits::COMMAND_COMPLETION_PARAMETER,
+                self.command_completion_parameter,
+            )
+            .bits(bits::COMPLETION_CODE, self.completion_code as u32)
+            .bits(bits::SLOT_ID, self.slot_id as u32)
+            .build()
     }
 }
 
@@ -127,7 +133,7 @@ impl CommandCompletionEventTrbData {
 ///
 /// Do not use this struct directly, use EventTrb::new_port_status_change_event_trb
 /// instead.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PortStatusChangeEventTrbData {
     port_id: u8,
 }
@@ -147,19 +153,52 @@ impl EventTrb {
 }
 
 impl PortStatusChangeEventTrbData {
+    /// The number of the root hub port that generated the event.
+    pub const fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
     const fn to_bytes(&self) -> RawTrbBuffer {
-        let mut bytes = zeroed_trb_buffer();
+        TrbBuilder::new(PORT_STATUS_CHANGE_EVENT)
+            .bits(bits::PORT_ID, self.port_id as u32)
+            .bits(bits::COMPLETION_CODE, CompletionCode::Success as u32)
+            .build()
+    }
+}
+
+/// Stores the relevant data for a Host Controller Event.
+///
+/// Do not use this struct directly, use EventTrb::new_host_controller_event_trb
+/// instead.
+#[derive(Debug, Clone)]
+pub struct HostControllerEventTrbData {
+    completion_code: CompletionCode,
+}
 
-        bytes[3] = self.port_id;
-        bytes[11] = CompletionCode::Success as u8;
-        bytes[13] = PORT_STATUS_CHANGE_EVENT << 2;
+impl EventTrb {
+    /// Create a new Host Controller Event TRB.
+    ///
+    /// The XHCI spec describes this structure in Section 6.4.2.6.
+    ///
+    /// # Parameters
+    ///
+    /// - `completion_code`: The condition the controller reports, e.g.,
+    ///   an Event Ring Full Error.
+    pub const fn new_host_controller_event_trb(completion_code: CompletionCode) -> Self {
+        Self::HostController(HostControllerEventTrbData { completion_code })
+    }
+}
 
-        bytes
+impl HostControllerEventTrbData {
+    const fn to_bytes(&self) -> RawTrbBuffer {
+        TrbBuilder::new(HOST_CONTROLLER_EVENT)
+            .bits(bits::COMPLETION_CODE, self.completion_code as u32)
+            .build()
     }
 }
 
 /// Stores the relevant data for a Transfer Event.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TransferEventTrbData {
     trb_pointer: u64,
     trb_transfer_length: u32,
@@ -188,7 +227,7 @@ impl EventTrb {
         trb_transfer_length: u32,
         completion_code: CompletionCode,
         event_data: bool,
-        endpoint_id: u8,
+        endpoint_id: Dci,
         slot_id: u8,
     ) -> Self {
         Self::Transfer(TransferEventTrbData {
@@ -196,25 +235,43 @@ impl EventTrb {
             trb_transfer_length,
             completion_code,
             event_data,
-            endpoint_id,
+            endpoint_id: endpoint_id.get(),
             slot_id,
         })
     }
+
+    /// Create a Transfer Event that reports an isochronous transfer ring
+    /// that was empty when the endpoint was serviced.
+    ///
+    /// The event refers to no TRB, so the TRB pointer and the transfer
+    /// length are 0 (XHCI spec, Section 4.10.3.1).
+    ///
+    /// # Parameters
+    ///
+    /// - `endpoint_id`: The endpoint whose transfer ring ran empty.
+    /// - `slot_id`: The slot of the endpoint.
+    pub const fn new_ring_starved_event_trb(endpoint_id: Dci, slot_id: u8) -> Self {
+        Self::new_transfer_event_trb(
+            0,
+            0,
+            CompletionCode::ring_starved(endpoint_id),
+            false,
+            endpoint_id,
+            slot_id,
+        )
+    }
 }
 
 impl TransferEventTrbData {
-    fn to_bytes(&self) -> RawTrbBuffer {
-        let mut trb = zeroed_trb_buffer();
-
-        trb[0..8].copy_from_slice(&self.trb_pointer.to_le_bytes());
-        trb[8..11].copy_from_slice(&self.trb_transfer_length.to_le_bytes()[0..3]);
-        trb[11] = self.completion_code as u8;
-        trb[12] = (self.event_data as u8) << 2;
-        trb[13] = TRANSFER_EVENT << 2;
-        trb[14] = self.endpoint_id;
-        trb[15] = self.slot_id;
-
-        trb
+    const fn to_bytes(&self) -> RawTrbBuffer {
+        TrbBuilder::new(TRANSFER_EVENT)
+            .u64_le(0, self.trb_pointer)
+            .bits(bits::EVENT_TRB_TRANSFER_LENGTH, self.trb_transfer_length)
+            .bits(bits::COMPLETION_CODE, self.completion_code as u32)
+            .bit(bits::EVENT_DATA, self.event_data)
+            .bits(bits::ENDPOINT_ID, self.endpoint_id as u32)
+            .bits(bits::SLOT_ID, self.slot_id as u32)
+            .build()
     }
 }
 
@@ -222,7 +279,7 @@ impl TransferEventTrbData {
 ///
 /// Refer to Table 6-90 in the XHCI specification for detailed descriptions of each code.
 #[allow(dead_code)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum CompletionCode {
     Invalid = 0,
     Success,
@@ -263,6 +320,66 @@ pub enum CompletionCode {
     SplitTransactionError,
 }
 
+impl CompletionCode {
+    /// The code for an isochronous transfer ring that was empty when the
+    /// endpoint was serviced: Ring Overrun for IN endpoints and Ring
+    /// Underrun for OUT endpoints.
+    pub const fn ring_starved(endpoint_id: Dci) -> Self {
+        if matches!(endpoint_id.direction(), Some(Direction::In)) {
+            Self::RingOverrun
+        } else {
+            Self::RingUnderrun
+        }
+    }
+}
+
+impl fmt::Display for CompletionCode {
+    /// Print the name of the code as in Table 6-90 of the XHCI
+    /// specification, followed by its value.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Invalid => "Invalid",
+            Self::Success => "Success",
+            Self::DataBufferError => "Data Buffer Error",
+            Self::BabbleDetectedError => "Babble Detected Error",
+            Self::UsbTransactionError => "USB Transaction Error",
+            Self::TrbError => "TRB Error",
+            Self::StallError => "Stall Error",
+            Self::ResourceError => "Resource Error",
+            Self::BandwidthError => "Bandwidth Error",
+            Self::NoSlotsAvailableError => "No Slots Available Error",
+            Self::InvalidStreamTypeError => "Invalid Stream Type Error",
+            Self::SlotNotEnabledError => "Slot Not Enabled Error",
+            Self::EndpointNotEnabledError => "Endpoint Not Enabled Error",
+            Self::ShortPacket => "Short Packet",
+            Self::RingUnderrun => "Ring Underrun",
+            Self::RingOverrun => "Ring Overrun",
+            Self::VfEventRingFullError => "VF Event Ring Full Error",
+            Self::ParameterError => "Parameter Error",
+            Self::BandwidthOverrunError => "Bandwidth Overrun Error",
+            Self::ContextStateError => "Context State Error",
+            Self::NoPingResponseError => "No Ping Response Error",
+            Self::EventRingFullError => "Event Ring Full Error",
+            Self::IncompatibleDeviceError => "Incompatible Device Error",
+            Self::MissedServiceError => "Missed Service Error",
+            Self::CommandRingStopped => "Command Ring Stopped",
+            Self::CommandAborted => "Command Aborted",
+            Self::Stopped => "Stopped",
+            Self::StoppedLengthInvalid => "Stopped - Length Invalid",
+            Self::StoppedShortedPacket => "Stopped - Short Packet",
+            Self::MaxExitLatencyTooLargeError => "Max Exit Latency Too Large Error",
+            Self::Reserved => "Reserved",
+            Self::IsochBufferOverrun => "Isoch Buffer Overrun",
+            Self::EventLostError => "Event Lost Error",
+            Self::UndefinedError => "Undefined Error",
+            Self::InvalidStreamIdError => "Invalid Stream ID Error",
+            Self::SecondaryBandwidthError => "Secondary Bandwidth Error",
+            Self::SplitTransactionError => "Split Transaction Error",
+        };
+        write!(f, "{name} ({})", *self as u8)
+    }
+}
+
 /// A trait for types offering a higher-level view of raw TRB bytes.
 ///
 /// All types representing data of TRBs should implement this trait to be
@@ -350,13 +467,13 @@ pub struct CommandTrb {
 #[derive(Debug, PartialEq, Eq)]
 pub enum CommandTrbVariant {
     EnableSlot,
-    DisableSlot,
+    DisableSlot(DisableSlotCommandTrbData),
     AddressDevice(AddressDeviceCommandTrbData),
     ConfigureEndpoint(ConfigureEndpointCommandTrbData),
-    EvaluateContext,
-    ResetEndpoint,
+    EvaluateContext(EvaluateContextCommandTrbData),
+    ResetEndpoint(ResetEndpointCommandTrbData),
     StopEndpoint(StopEndpointCommandTrbData),
-    SetTrDequeuePointer,
+    SetTrDequeuePointer(SetTrDequeuePointerCommandTrbData),
     ResetDevice(ResetDeviceCommandTrbData),
     ForceHeader,
     NoOp,
@@ -364,7 +481,48 @@ pub enum CommandTrbVariant {
     Unrecognized(RawTrbBuffer, TrbParseError),
 }
 
+impl fmt::Display for CommandTrbVariant {
+    /// Print the name of the TRB type without the TRB's data.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::EnableSlot => "Enable Slot Command",
+            Self::DisableSlot(_) => "Disable Slot Command",
+            Self::AddressDevice(_) => "Address Device Command",
+            Self::ConfigureEndpoint(_) => "Configure Endpoint Command",
+            Self::EvaluateContext(_) => "Evaluate Context Command",
+            Self::ResetEndpoint(_) => "Reset Endpoint Command",
+            Self::StopEndpoint(_) => "Stop Endpoint Command",
+            Self::SetTrDequeuePointer(_) => "Set TR Dequeue Pointer Command",
+            Self::ResetDevice(_) => "Reset Device Command",
+            Self::ForceHeader => "Force Header Command",
+            Self::NoOp => "No Op Command",
+            Self::Link(_) => "Link TRB",
+            Self::Unrecognized(_, err) => return write!(f, "unrecognized TRB ({err})"),
+        };
+        f.write_str(name)
+    }
+}
+
 impl CommandTrbVariant {
+    /// The Slot ID the command refers to, or 0 for commands without one.
+    pub const fn slot_id(&self) -> u8 {
+        match self {
+            Self::DisableSlot(data) => data.slot_id,
+            Self::AddressDevice(data) => data.slot_id,
+            Self::ConfigureEndpoint(data) => data.slot_id,
+            Self::EvaluateContext(data) => data.slot_id,
+            Self::ResetEndpoint(data) => data.slot_id,
+            Self::StopEndpoint(data) => data.slot_id,
+            Self::SetTrDequeuePointer(data) => data.slot_id,
+            Self::ResetDevice(data) => data.slot_id,
+            Self::EnableSlot
+            | Self::ForceHeader
+            | Self::NoOp
+            | Self::Link(_)
+            | Self::Unrecognized(..) => 0,
+        }
+    }
+
     /// Parse command-specific TRB data from a 16-byte buffer.
     ///
     /// If any errors occur during parsing, the function returns
@@ -379,20 +537,20 @@ impl CommandTrbVariant {
     /// particular command is not yet implemented. EnableSlotCommand is an
     /// exception, because the TRB does not contain any additional information.
     pub fn parse(bytes: RawTrbBuffer) -> Self {
-        let trb_type = bytes[13] >> 2;
+        let trb_type = bytes.trb_type();
         match trb_type {
             trb_types::LINK => parse(Self::Link, bytes),
             // EnableSlotCommand does not contain information apart from the
             // type; thus, no further parsing is necessary and we can just
             // return the enum variant.
             trb_types::ENABLE_SLOT_COMMAND => Self::EnableSlot,
-            trb_types::DISABLE_SLOT_COMMAND => Self::DisableSlot,
+            trb_types::DISABLE_SLOT_COMMAND => parse(Self::DisableSlot, bytes),
             trb_types::ADDRESS_DEVICE_COMMAND => parse(Self::AddressDevice, bytes),
             trb_types::CONFIGURE_ENDPOINT_COMMAND => parse(Self::ConfigureEndpoint, bytes),
-            trb_types::EVALUATE_CONTEXT_COMMAND => Self::EvaluateContext,
-            trb_types::RESET_ENDPOINT_COMMAND => Self::ResetEndpoint,
+            trb_types::EVALUATE_CONTEXT_COMMAND => parse(Self::EvaluateContext, bytes),
+            trb_types::RESET_ENDPOINT_COMMAND => parse(Self::ResetEndpoint, bytes),
             trb_types::STOP_ENDPOINT_COMMAND => parse(Self::StopEndpoint, bytes),
-            trb_types::SET_TR_DEQUEUE_POINTER_COMMAND => Self::SetTrDequeuePointer,
+            trb_types::SET_TR_DEQUEUE_POINTER_COMMAND => parse(Self::SetTrDequeuePointer, bytes),
             trb_types::RESET_DEVICE_COMMAND => parse(Self::ResetDevice, bytes),
             trb_types::FORCE_EVENT_COMMAND => Self::Unrecognized(
                 bytes,
@@ -448,7 +606,7 @@ impl TrbData for LinkTrbData {
     /// The function currently does not check if the slice respects all RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::LINK,
             trb_type,
@@ -456,10 +614,8 @@ impl TrbData for LinkTrbData {
             trb_type
         );
 
-        // SAFETY: range matches array length
-        let rsp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
-        let ring_segment_pointer = u64::from_le_bytes(rsp_bytes);
-        let toggle_cycle = trb_bytes[12] & 0x2 != 0;
+        let ring_segment_pointer = trb_bytes.get_u64_le(0);
+        let toggle_cycle = trb_bytes.get_bit(bits::TOGGLE_CYCLE);
 
         // the lowest four bit of the pointer are RsvdZ to ensure 16-byte
         // alignment.
@@ -474,6 +630,39 @@ impl TrbData for LinkTrbData {
     }
 }
 
+/// Disable Slot Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.3 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DisableSlotCommandTrbData {
+    /// The slot to disable.
+    pub slot_id: u8,
+}
+
+impl TrbData for DisableSlotCommandTrbData {
+    /// Parse data of a Disable Slot Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes.trb_type();
+        assert_eq!(
+            trb_types::DISABLE_SLOT_COMMAND,
+            trb_type,
+            "DisableSlotCommandTrbData::parse called on TRB data with incorrect TRB type ({:#x})",
+            trb_type
+        );
+
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
+
+        Ok(Self { slot_id })
+    }
+}
+
 /// Address Device Command TRB data structure.
 ///
 /// See XHCI specification Section 6.4.3.4 for detailed field descriptions.
@@ -498,7 +687,7 @@ impl TrbData for AddressDeviceCommandTrbData {
     /// The function currently does not check if the slice respects all RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::ADDRESS_DEVICE_COMMAND,
             trb_type,
@@ -506,9 +695,7 @@ impl TrbData for AddressDeviceCommandTrbData {
             trb_type
         );
 
-        // SAFETY: range matches array length
-        let icp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
-        let input_context_pointer = u64::from_le_bytes(icp_bytes);
+        let input_context_pointer = trb_bytes.get_u64_le(0);
 
         // the lowest four bit of the pointer are RsvdZ to ensure 16-byte
         // alignment.
@@ -516,8 +703,8 @@ impl TrbData for AddressDeviceCommandTrbData {
             return Err(TrbParseError::RsvdZViolation);
         }
 
-        let block_set_address_request = trb_bytes[13] & 0x2 != 0;
-        let slot_id = trb_bytes[15];
+        let block_set_address_request = trb_bytes.get_bit(bits::BLOCK_SET_ADDRESS_REQUEST);
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
 
         Ok(Self {
             input_context_pointer,
@@ -547,7 +734,7 @@ impl TrbData for ConfigureEndpointCommandTrbData {
     /// The function currently does not check if the slice respects all RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::CONFIGURE_ENDPOINT_COMMAND,
             trb_type,
@@ -555,9 +742,7 @@ impl TrbData for ConfigureEndpointCommandTrbData {
             trb_type
         );
 
-        // SAFETY: range matches array length
-        let icp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
-        let input_context_pointer = u64::from_le_bytes(icp_bytes);
+        let input_context_pointer = trb_bytes.get_u64_le(0);
 
         // the lowest four bit of the pointer are RsvdZ to ensure 16-byte
         // alignment.
@@ -565,8 +750,8 @@ impl TrbData for ConfigureEndpointCommandTrbData {
             return Err(TrbParseError::RsvdZViolation);
         }
 
-        let deconfigure = trb_bytes[13] & 0x2 != 0;
-        let slot_id = trb_bytes[15];
+        let deconfigure = trb_bytes.get_bit(bits::DECONFIGURE);
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
 
         Ok(Self {
             input_context_pointer,
@@ -576,6 +761,52 @@ impl TrbData for ConfigureEndpointCommandTrbData {
     }
 }
 
+/// Evaluate Context Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.6 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EvaluateContextCommandTrbData {
+    /// The address of the input context.
+    pub input_context_pointer: u64,
+    /// The associated Slot ID.
+    pub slot_id: u8,
+}
+
+impl TrbData for EvaluateContextCommandTrbData {
+    /// Parse data of an Evaluate Context Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes.trb_type();
+        assert_eq!(
+            trb_types::EVALUATE_CONTEXT_COMMAND,
+            trb_type,
+            "EvaluateContextCommandTrbData::parse called on TRB data with incorrect TRB type ({:#x})",
+            trb_type
+        );
+
+        let input_context_pointer = trb_bytes.get_u64_le(0);
+
+        // the lowest four bit of the pointer are RsvdZ to ensure 16-byte
+        // alignment.
+        if input_context_pointer & 0xf != 0 {
+            return Err(TrbParseError::RsvdZViolation);
+        }
+
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
+
+        Ok(Self {
+            input_context_pointer,
+            slot_id,
+        })
+    }
+}
+
 /// Stop Endpoint Command TRB data structure.
 ///
 /// See XHCI specification Section 6.4.3.8 for detailed field descriptions.
@@ -597,7 +828,7 @@ impl TrbData for StopEndpointCommandTrbData {
     /// The function currently does not check if the slice respects all RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::STOP_ENDPOINT_COMMAND,
             trb_type,
@@ -605,10 +836,106 @@ impl TrbData for StopEndpointCommandTrbData {
             trb_type
         );
 
-        let endpoint_id = trb_bytes[14] & 0x1f;
-        let slot_id = trb_bytes[15];
+        let endpoint_id = trb_bytes.get_bits(bits::ENDPOINT_ID) as u8;
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
+
+        Ok(Self {
+            endpoint_id,
+            slot_id,
+        })
+    }
+}
+
+/// Reset Endpoint Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.7 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ResetEndpointCommandTrbData {
+    /// The endpoint to reset.
+    pub endpoint_id: u8,
+    /// Transfer State Preserve (TSP): whether the driver wants to keep the
+    /// transfer state, e.g., for a soft retry.
+    pub transfer_state_preserve: bool,
+    /// The associated Slot ID.
+    pub slot_id: u8,
+}
+
+impl TrbData for ResetEndpointCommandTrbData {
+    /// Parse data of a Reset Endpoint Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes.trb_type();
+        assert_eq!(
+            trb_types::RESET_ENDPOINT_COMMAND,
+            trb_type,
+            "ResetEndpointCommandTrbData::parse called on TRB data with incorrect TRB type ({:#x})",
+            trb_type
+        );
+
+        let transfer_state_preserve = trb_bytes.get_bit(bits::TRANSFER_STATE_PRESERVE);
+        let endpoint_id = trb_bytes.get_bits(bits::ENDPOINT_ID) as u8;
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
+
+        Ok(Self {
+            endpoint_id,
+            transfer_state_preserve,
+            slot_id,
+        })
+    }
+}
+
+/// Set TR Dequeue Pointer Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.9 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SetTrDequeuePointerCommandTrbData {
+    /// The new TR Dequeue Pointer.
+    pub dequeue_pointer: u64,
+    /// The Dequeue Cycle State (DCS) that goes with the pointer.
+    pub dequeue_cycle_state: bool,
+    /// The stream whose dequeue pointer is set, or 0 for endpoints without
+    /// streams.
+    pub stream_id: u16,
+    /// The endpoint whose dequeue pointer is set.
+    pub endpoint_id: u8,
+    /// The associated Slot ID.
+    pub slot_id: u8,
+}
+
+impl TrbData for SetTrDequeuePointerCommandTrbData {
+    /// Parse data of a Set TR Dequeue Pointer Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields, and it ignores the Stream Context Type (SCT).
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes.trb_type();
+        assert_eq!(
+            trb_types::SET_TR_DEQUEUE_POINTER_COMMAND,
+            trb_type,
+            "SetTrDequeuePointerCommandTrbData::parse called on TRB data with incorrect TRB type ({:#x})",
+            trb_type
+        );
+
+        let dequeue_pointer = trb_bytes.get_u64_le(0) & !0xf;
+        let dequeue_cycle_state = trb_bytes.get_bit(bits::DEQUEUE_CYCLE_STATE);
+        let stream_id = trb_bytes.get_bits(bits::STREAM_ID) as u16;
+        let endpoint_id = trb_bytes.get_bits(bits::ENDPOINT_ID) as u8;
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
 
         Ok(Self {
+            dequeue_pointer,
+            dequeue_cycle_state,
+            stream_id,
             endpoint_id,
             slot_id,
         })
@@ -634,7 +961,7 @@ impl TrbData for ResetDeviceCommandTrbData {
     /// The function currently does not check if the slice respects all RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::RESET_DEVICE_COMMAND,
             trb_type,
@@ -642,7 +969,7 @@ impl TrbData for ResetDeviceCommandTrbData {
             trb_type
         );
 
-        let slot_id = trb_bytes[15];
+        let slot_id = trb_bytes.get_bits(bits::SLOT_ID) as u8;
 
         Ok(Self { slot_id })
     }
@@ -666,7 +993,7 @@ pub enum TransferTrbVariant {
     SetupStage(SetupStageTrbData),
     DataStage(DataStageTrbData),
     StatusStage,
-    Isoch,
+    Isoch(IsochTrbData),
     Link(LinkTrbData),
     EventData,
     NoOp,
@@ -674,6 +1001,24 @@ pub enum TransferTrbVariant {
     Unrecognized(RawTrbBuffer, TrbParseError),
 }
 
+impl fmt::Display for TransferTrbVariant {
+    /// Print the name of the TRB type without the TRB's data.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Normal(_) => "Normal TRB",
+            Self::SetupStage(_) => "Setup Stage TRB",
+            Self::DataStage(_) => "Data Stage TRB",
+            Self::StatusStage => "Status Stage TRB",
+            Self::Isoch(_) => "Isoch TRB",
+            Self::Link(_) => "Link TRB",
+            Self::EventData => "Event Data TRB",
+            Self::NoOp => "No Op TRB",
+            Self::Unrecognized(_, err) => return write!(f, "unrecognized TRB ({err})"),
+        };
+        f.write_str(name)
+    }
+}
+
 impl TransferTrbVariant {
     /// Parse transfer-specific TRB data from a 16-byte buffer.
     ///
@@ -688,13 +1033,13 @@ impl TransferTrbVariant {
     /// enum variant without an associated struct, the parsing for the
     /// particular command is not yet implemented.
     pub fn parse(bytes: RawTrbBuffer) -> Self {
-        let trb_type = bytes[13] >> 2;
+        let trb_type = bytes.trb_type();
         match trb_type {
             trb_types::NORMAL => parse(Self::Normal, bytes),
             trb_types::SETUP_STAGE => parse(Self::SetupStage, bytes),
             trb_types::DATA_STAGE => parse(Self::DataStage, bytes),
             trb_types::STATUS_STAGE => Self::StatusStage,
-            trb_types::ISOCH => Self::Isoch,
+            trb_types::ISOCH => parse(Self::Isoch, bytes),
             trb_types::LINK => parse(Self::Link, bytes),
             trb_types::EVENT_DATA => Self::EventData,
             trb_types::NO_OP => Self::NoOp,
@@ -725,7 +1070,7 @@ impl TrbData for NormalTrbData {
     /// The function currently does not check if the slice respects RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::NORMAL,
             trb_type,
@@ -733,21 +1078,76 @@ impl TrbData for NormalTrbData {
             trb_type
         );
 
-        // SAFETY: range matches array length
-        let dp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
-        let data_pointer = u64::from_le_bytes(dp_bytes);
+        let data_pointer = trb_bytes.get_u64_le(0);
+
+        let transfer_length = trb_bytes.get_bits(bits::TRB_TRANSFER_LENGTH);
+
+        let chain = trb_bytes.get_bit(bits::CHAIN);
+        let interrupt_on_completion = trb_bytes.get_bit(bits::INTERRUPT_ON_COMPLETION);
+
+        Ok(Self {
+            data_pointer,
+            transfer_length,
+            chain,
+            interrupt_on_completion,
+        })
+    }
+}
+
+/// Isoch TRB data structure (simplified representation).
+///
+/// This struct contains only the fields needed to schedule isochronous
+/// transfers. See XHCI specification Section 6.4.1.3 for the complete TRB
+/// layout.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IsochTrbData {
+    pub data_pointer: u64,
+    pub transfer_length: u32,
+    pub chain: bool,
+    pub interrupt_on_completion: bool,
+    /// The (1ms) frame in which the transfer should happen. Only valid if
+    /// `start_isoch_asap` is not set.
+    pub frame_id: u16,
+    /// Start Isoch ASAP (SIA): schedule the transfer as soon as possible
+    /// and ignore `frame_id`.
+    pub start_isoch_asap: bool,
+}
+
+impl TrbData for IsochTrbData {
+    /// Parse data of an Isoch TRB.
+    ///
+    /// Only `TransferTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes.trb_type();
+        assert_eq!(
+            trb_types::ISOCH,
+            trb_type,
+            "IsochTrbData::parse called on TRB data with incorrect TRB type ({:#x})",
+            trb_type
+        );
+
+        let data_pointer = trb_bytes.get_u64_le(0);
+
+        let transfer_length = trb_bytes.get_bits(bits::TRB_TRANSFER_LENGTH);
 
-        let tl_bytes: [u8; 4] = [trb_bytes[8], trb_bytes[9], trb_bytes[10] & 0x01, 0];
-        let transfer_length = u32::from_le_bytes(tl_bytes);
+        let chain = trb_bytes.get_bit(bits::CHAIN);
+        let interrupt_on_completion = trb_bytes.get_bit(bits::INTERRUPT_ON_COMPLETION);
 
-        let chain = trb_bytes[12] & 0x10 != 0;
-        let interrupt_on_completion = trb_bytes[12] & 0x20 != 0;
+        let frame_id = trb_bytes.get_bits(bits::FRAME_ID) as u16;
+        let start_isoch_asap = trb_bytes.get_bit(bits::START_ISOCH_ASAP);
 
         Ok(Self {
             data_pointer,
             transfer_length,
             chain,
             interrupt_on_completion,
+            frame_id,
+            start_isoch_asap,
         })
     }
 }
@@ -774,7 +1174,7 @@ impl TrbData for SetupStageTrbData {
     /// The function currently does not check if the slice respects RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::SETUP_STAGE,
             trb_type,
@@ -782,11 +1182,11 @@ impl TrbData for SetupStageTrbData {
             trb_type
         );
 
-        let request_type = trb_bytes[0];
-        let request = trb_bytes[1];
-        let value = trb_bytes[2] as u16 + ((trb_bytes[3] as u16) << 8);
-        let index = trb_bytes[4] as u16 + ((trb_bytes[5] as u16) << 8);
-        let length = trb_bytes[6] as u16 + ((trb_bytes[7] as u16) << 8);
+        let request_type = trb_bytes.get_u8(0);
+        let request = trb_bytes.get_u8(1);
+        let value = trb_bytes.get_u16_le(2);
+        let index = trb_bytes.get_u16_le(4);
+        let length = trb_bytes.get_u16_le(6);
 
         Ok(Self {
             request_type,
@@ -804,6 +1204,7 @@ impl TrbData for SetupStageTrbData {
 #[derive(Debug, PartialEq, Eq)]
 pub struct DataStageTrbData {
     pub data_pointer: u64,
+    pub transfer_length: u32,
     pub chain: bool,
 }
 
@@ -817,7 +1218,7 @@ impl TrbData for DataStageTrbData {
     /// The function currently does not check if the slice respects RsvdZ
     /// fields.
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
-        let trb_type = trb_bytes[13] >> 2;
+        let trb_type = trb_bytes.trb_type();
         assert_eq!(
             trb_types::DATA_STAGE,
             trb_type,
@@ -825,14 +1226,15 @@ impl TrbData for DataStageTrbData {
             trb_type
         );
 
-        // SAFETY: range matches array length
-        let dp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
-        let data_pointer = u64::from_le_bytes(dp_bytes);
+        let data_pointer = trb_bytes.get_u64_le(0);
+
+        let transfer_length = trb_bytes.get_bits(bits::TRB_TRANSFER_LENGTH);
 
-        let chain = trb_bytes[12] & 0x10 != 0;
+        let chain = trb_bytes.get_bit(bits::CHAIN);
 
         Ok(Self {
             data_pointer,
+            transfer_length,
             chain,
         })
     }
@@ -851,6 +1253,8 @@ pub enum TrbParseError {
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -890,6 +1294,19 @@ mod tests {
         assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
     }
 
+    #[test]
+    fn parse_evaluate_context_command_trb() {
+        let trb_bytes = [
+            0x80, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34,
+            0x00, 0x13,
+        ];
+        let expected = CommandTrbVariant::EvaluateContext(EvaluateContextCommandTrbData {
+            input_context_pointer: 0x1122334455667780,
+            slot_id: 0x13,
+        });
+        assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
+    }
+
     #[test]
     fn parse_configure_endpoint_command_trb() {
         let trb_bytes = [
@@ -917,6 +1334,36 @@ mod tests {
         assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
     }
 
+    #[test]
+    fn parse_reset_endpoint_command_trb() {
+        let trb_bytes = [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3a,
+            0x03, 0x10,
+        ];
+        let expected = CommandTrbVariant::ResetEndpoint(ResetEndpointCommandTrbData {
+            endpoint_id: 0x03,
+            transfer_state_preserve: true,
+            slot_id: 0x10,
+        });
+        assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
+    }
+
+    #[test]
+    fn parse_set_tr_dequeue_pointer_command_trb() {
+        let trb_bytes = [
+            0x31, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x40,
+            0x05, 0x03,
+        ];
+        let expected = CommandTrbVariant::SetTrDequeuePointer(SetTrDequeuePointerCommandTrbData {
+            dequeue_pointer: 0x2230,
+            dequeue_cycle_state: true,
+            stream_id: 2,
+            endpoint_id: 0x05,
+            slot_id: 0x03,
+        });
+        assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
+    }
+
     #[test]
     fn command_completion_event_trb() {
         let trb = EventTrb::new_command_completion_event_trb(
@@ -946,6 +1393,18 @@ mod tests {
         )
     }
 
+    #[test]
+    fn host_controller_event_trb() {
+        let trb = EventTrb::new_host_controller_event_trb(CompletionCode::EventRingFullError);
+        assert_eq!(
+            [
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x01, 0x94,
+                0x00, 0x00,
+            ],
+            trb.to_bytes(true),
+        )
+    }
+
     #[test]
     fn test_parse_link_trb_as_transfer() {
         let trb_bytes = [
@@ -974,6 +1433,31 @@ mod tests {
         assert_eq!(TransferTrbVariant::parse(trb_bytes), expected);
     }
 
+    #[test]
+    fn test_parse_isoch_trb() {
+        let trb_bytes = [
+            0x11, 0x22, 0x44, 0x33, 0x66, 0x55, 0x88, 0x77, 0x00, 0x04, 0x00, 0x00, 0x20, 0x14,
+            0x30, 0x5a,
+        ];
+        let expected = TransferTrbVariant::Isoch(IsochTrbData {
+            data_pointer: 0x7788556633442211,
+            transfer_length: 0x400,
+            chain: false,
+            interrupt_on_completion: true,
+            frame_id: 0x5a3,
+            start_isoch_asap: false,
+        });
+        assert_eq!(TransferTrbVariant::parse(trb_bytes), expected);
+
+        let mut sia_bytes = trb_bytes;
+        sia_bytes[15] |= 0x80;
+        let TransferTrbVariant::Isoch(data) = TransferTrbVariant::parse(sia_bytes) else {
+            panic!("expected Isoch TRB");
+        };
+        assert!(data.start_isoch_asap);
+        assert_eq!(data.frame_id, 0x5a3);
+    }
+
     #[test]
     fn test_parse_setup_stage_trb() {
         let trb_bytes = [
@@ -993,13 +1477,478 @@ mod tests {
     #[test]
     fn test_parse_data_stage_trb() {
         let trb_bytes = [
-            0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c,
+            0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x12, 0x00, 0x00, 0x00, 0x00, 0x0c,
             0x00, 0x00,
         ];
         let expected = TransferTrbVariant::DataStage(DataStageTrbData {
             data_pointer: 0x1122334455667788,
+            transfer_length: 0x12,
             chain: false,
         });
         assert_eq!(TransferTrbVariant::parse(trb_bytes), expected);
     }
+
+    #[test]
+    fn completion_codes_display_spec_names() {
+        assert_eq!(CompletionCode::Success.to_string(), "Success (1)");
+        assert_eq!(CompletionCode::TrbError.to_string(), "TRB Error (5)");
+        assert_eq!(CompletionCode::StallError.to_string(), "Stall Error (6)");
+        assert_eq!(
+            CompletionCode::StoppedLengthInvalid.to_string(),
+            "Stopped - Length Invalid (27)"
+        );
+        assert_eq!(
+            CompletionCode::SplitTransactionError.to_string(),
+            "Split Transaction Error (36)"
+        );
+    }
+
+    #[test]
+    fn trb_variants_display_type_names() {
+        assert_eq!(
+            TransferTrbVariant::StatusStage.to_string(),
+            "Status Stage TRB"
+        );
+        assert_eq!(
+            TransferTrbVariant::parse([0; 16]).to_string(),
+            "unrecognized TRB (TRB type 0 does not refer to any command.)"
+        );
+        assert_eq!(CommandTrbVariant::NoOp.to_string(), "No Op Command");
+        assert_eq!(
+            CommandTrbVariant::ForceHeader.to_string(),
+            "Force Header Command"
+        );
+    }
+
+    /// The inverse of [`TrbData::parse`] for the round-trip tests.
+    trait TrbEncode {
+        fn to_bytes(&self) -> RawTrbBuffer;
+    }
+
+    impl TrbEncode for LinkTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(LINK)
+                .u64_le(0, self.ring_segment_pointer)
+                .bit(bits::TOGGLE_CYCLE, self.toggle_cycle)
+                .build()
+        }
+    }
+
+    impl TrbEncode for DisableSlotCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(DISABLE_SLOT_COMMAND)
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for AddressDeviceCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(ADDRESS_DEVICE_COMMAND)
+                .u64_le(0, self.input_context_pointer)
+                .bit(
+                    bits::BLOCK_SET_ADDRESS_REQUEST,
+                    self.block_set_address_request,
+                )
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for ConfigureEndpointCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(CONFIGURE_ENDPOINT_COMMAND)
+                .u64_le(0, self.input_context_pointer)
+                .bit(bits::DECONFIGURE, self.deconfigure)
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for EvaluateContextCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(EVALUATE_CONTEXT_COMMAND)
+                .u64_le(0, self.input_context_pointer)
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for StopEndpointCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(STOP_ENDPOINT_COMMAND)
+                .bits(bits::ENDPOINT_ID, self.endpoint_id.into())
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for ResetEndpointCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(RESET_ENDPOINT_COMMAND)
+                .bit(bits::TRANSFER_STATE_PRESERVE, self.transfer_state_preserve)
+                .bits(bits::ENDPOINT_ID, self.endpoint_id.into())
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for SetTrDequeuePointerCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(SET_TR_DEQUEUE_POINTER_COMMAND)
+                .u64_le(0, self.dequeue_pointer)
+                .bit(bits::DEQUEUE_CYCLE_STATE, self.dequeue_cycle_state)
+                .bits(bits::STREAM_ID, self.stream_id.into())
+                .bits(bits::ENDPOINT_ID, self.endpoint_id.into())
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for ResetDeviceCommandTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(RESET_DEVICE_COMMAND)
+                .bits(bits::SLOT_ID, self.slot_id.into())
+                .build()
+        }
+    }
+
+    impl TrbEncode for NormalTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(NORMAL)
+                .u64_le(0, self.data_pointer)
+                .bits(bits::TRB_TRANSFER_LENGTH, self.transfer_length)
+                .bit(bits::CHAIN, self.chain)
+                .bit(bits::INTERRUPT_ON_COMPLETION, self.interrupt_on_completion)
+                .build()
+        }
+    }
+
+    impl TrbEncode for IsochTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(ISOCH)
+                .u64_le(0, self.data_pointer)
+                .bits(bits::TRB_TRANSFER_LENGTH, self.transfer_length)
+                .bit(bits::CHAIN, self.chain)
+                .bit(bits::INTERRUPT_ON_COMPLETION, self.interrupt_on_completion)
+                .bits(bits::FRAME_ID, self.frame_id.into())
+                .bit(bits::START_ISOCH_ASAP, self.start_isoch_asap)
+                .build()
+        }
+    }
+
+    impl TrbEncode for SetupStageTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(SETUP_STAGE)
+                .u8(0, self.request_type)
+                .u8(1, self.request)
+                .u16_le(2, self.value)
+                .u16_le(4, self.index)
+                .u16_le(6, self.length)
+                .build()
+        }
+    }
+
+    impl TrbEncode for DataStageTrbData {
+        fn to_bytes(&self) -> RawTrbBuffer {
+            TrbBuilder::new(DATA_STAGE)
+                .u64_le(0, self.data_pointer)
+                .bits(bits::TRB_TRANSFER_LENGTH, self.transfer_length)
+                .bit(bits::CHAIN, self.chain)
+                .build()
+        }
+    }
+
+    /// A 16-byte-aligned pointer, as TRBs require for most pointers.
+    fn aligned_pointer() -> impl Strategy<Value = u64> {
+        any::<u64>().prop_map(|pointer| pointer & !0xf)
+    }
+
+    fn completion_code() -> impl Strategy<Value = CompletionCode> {
+        prop_oneof![
+            Just(CompletionCode::Success),
+            Just(CompletionCode::StallError),
+            Just(CompletionCode::ShortPacket),
+            Just(CompletionCode::EventRingFullError),
+            Just(CompletionCode::SplitTransactionError),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn link_trb_round_trips(ring_segment_pointer in aligned_pointer(), toggle_cycle: bool) {
+            let data = LinkTrbData { ring_segment_pointer, toggle_cycle };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(CommandTrbVariant::parse(bytes), CommandTrbVariant::Link(data.clone()));
+            prop_assert_eq!(TransferTrbVariant::parse(bytes), TransferTrbVariant::Link(data));
+        }
+
+        #[test]
+        fn disable_slot_command_trb_round_trips(slot_id: u8) {
+            let data = DisableSlotCommandTrbData { slot_id };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(CommandTrbVariant::parse(bytes), CommandTrbVariant::DisableSlot(data));
+        }
+
+        #[test]
+        fn address_device_command_trb_round_trips(
+            input_context_pointer in aligned_pointer(),
+            block_set_address_request: bool,
+            slot_id: u8,
+        ) {
+            let data = AddressDeviceCommandTrbData {
+                input_context_pointer,
+                block_set_address_request,
+                slot_id,
+            };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(CommandTrbVariant::parse(bytes), CommandTrbVariant::AddressDevice(data));
+        }
+
+        #[test]
+        fn configure_endpoint_command_trb_round_trips(
+            input_context_pointer in aligned_pointer(),
+            deconfigure: bool,
+            slot_id: u8,
+        ) {
+            let data = ConfigureEndpointCommandTrbData {
+                input_context_pointer,
+                deconfigure,
+                slot_id,
+            };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(
+                CommandTrbVariant::parse(bytes),
+                CommandTrbVariant::ConfigureEndpoint(data)
+            );
+        }
+
+        #[test]
+        fn evaluate_context_command_trb_round_trips(
+            input_context_pointer in aligned_pointer(),
+            slot_id: u8,
+        ) {
+            let data = EvaluateContextCommandTrbData { input_context_pointer, slot_id };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(
+                CommandTrbVariant::parse(bytes),
+                CommandTrbVariant::EvaluateContext(data)
+            );
+        }
+
+        #[test]
+        fn stop_endpoint_command_trb_round_trips(endpoint_id in 0u8..32, slot_id: u8) {
+            let data = StopEndpointCommandTrbData { endpoint_id, slot_id };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(CommandTrbVariant::parse(bytes), CommandTrbVariant::StopEndpoint(data));
+        }
+
+        #[test]
+        fn set_tr_dequeue_pointer_command_trb_round_trips(
+            dequeue_pointer in aligned_pointer(),
+            dequeue_cycle_state: bool,
+            stream_id: u16,
+            endpoint_id in 0u8..32,
+            slot_id: u8,
+        ) {
+            let data = SetTrDequeuePointerCommandTrbData {
+                dequeue_pointer,
+                dequeue_cycle_state,
+                stream_id,
+                endpoint_id,
+                slot_id,
+            };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(
+                CommandTrbVariant::parse(bytes),
+                CommandTrbVariant::SetTrDequeuePointer(data)
+            );
+        }
+
+        #[test]
+        fn reset_endpoint_command_trb_round_trips(
+            endpoint_id in 0u8..32,
+            transfer_state_preserve: bool,
+            slot_id: u8,
+        ) {
+            let data = ResetEndpointCommandTrbData {
+                endpoint_id,
+                transfer_state_preserve,
+                slot_id,
+            };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(CommandTrbVariant::parse(bytes), CommandTrbVariant::ResetEndpoint(data));
+        }
+
+        #[test]
+        fn reset_device_command_trb_round_trips(slot_id: u8) {
+            let data = ResetDeviceCommandTrbData { slot_id };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(CommandTrbVariant::parse(bytes), CommandTrbVariant::ResetDevice(data));
+        }
+
+        #[test]
+        fn normal_trb_round_trips(
+            data_pointer: u64,
+            transfer_length in 0u32..1 << 17,
+            chain: bool,
+            interrupt_on_completion: bool,
+        ) {
+            let data = NormalTrbData {
+                data_pointer,
+                transfer_length,
+                chain,
+                interrupt_on_completion,
+            };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(TransferTrbVariant::parse(bytes), TransferTrbVariant::Normal(data));
+        }
+
+        #[test]
+        fn isoch_trb_round_trips(
+            data_pointer: u64,
+            transfer_length in 0u32..1 << 17,
+            chain: bool,
+            interrupt_on_completion: bool,
+            frame_id in 0u16..1 << 11,
+            start_isoch_asap: bool,
+        ) {
+            let data = IsochTrbData {
+                data_pointer,
+                transfer_length,
+                chain,
+                interrupt_on_completion,
+                frame_id,
+                start_isoch_asap,
+            };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(TransferTrbVariant::parse(bytes), TransferTrbVariant::Isoch(data));
+        }
+
+        #[test]
+        fn setup_stage_trb_round_trips(
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            length: u16,
+        ) {
+            let data = SetupStageTrbData {
+                request_type,
+                request,
+                value,
+                index,
+                length,
+            };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(TransferTrbVariant::parse(bytes), TransferTrbVariant::SetupStage(data));
+        }
+
+        #[test]
+        fn data_stage_trb_round_trips(
+            data_pointer: u64,
+            transfer_length in 0u32..1 << 17,
+            chain: bool,
+        ) {
+            let data = DataStageTrbData { data_pointer, transfer_length, chain };
+            let bytes = data.to_bytes();
+            prop_assert_eq!(TransferTrbVariant::parse(bytes), TransferTrbVariant::DataStage(data));
+        }
+
+        #[test]
+        fn transfer_event_trb_fields_round_trip(
+            trb_pointer: u64,
+            trb_transfer_length in 0u32..1 << 24,
+            completion_code in completion_code(),
+            event_data: bool,
+            endpoint_id in 1u8..32,
+            slot_id: u8,
+            cycle_bit: bool,
+        ) {
+            let trb = EventTrb::new_transfer_event_trb(
+                trb_pointer,
+                trb_transfer_length,
+                completion_code,
+                event_data,
+                Dci::new(endpoint_id).unwrap(),
+                slot_id,
+            )
+            .to_bytes(cycle_bit);
+            prop_assert_eq!(trb.trb_type(), TRANSFER_EVENT);
+            prop_assert_eq!(trb.get_u64_le(0), trb_pointer);
+            prop_assert_eq!(trb.get_bits(bits::EVENT_TRB_TRANSFER_LENGTH), trb_transfer_length);
+            prop_assert_eq!(trb.get_bits(bits::COMPLETION_CODE), completion_code as u32);
+            prop_assert_eq!(trb.get_bit(bits::EVENT_DATA), event_data);
+            prop_assert_eq!(trb.get_bits(bits::ENDPOINT_ID), u32::from(endpoint_id));
+            prop_assert_eq!(trb.get_bits(bits::SLOT_ID), u32::from(slot_id));
+            prop_assert_eq!(trb.get_bit(bits::CYCLE), cycle_bit);
+        }
+
+        #[test]
+        fn ring_starved_event_trb_refers_to_no_trb(
+            endpoint_id in 2u8..32,
+            slot_id: u8,
+            cycle_bit: bool,
+        ) {
+            let dci = Dci::new(endpoint_id).unwrap();
+            let trb = EventTrb::new_ring_starved_event_trb(dci, slot_id).to_bytes(cycle_bit);
+            let completion_code = if endpoint_id % 2 == 1 {
+                CompletionCode::RingOverrun
+            } else {
+                CompletionCode::RingUnderrun
+            };
+            prop_assert_eq!(trb.trb_type(), TRANSFER_EVENT);
+            prop_assert_eq!(trb.get_u64_le(0), 0);
+            prop_assert_eq!(trb.get_bits(bits::EVENT_TRB_TRANSFER_LENGTH), 0);
+            prop_assert_eq!(trb.get_bits(bits::COMPLETION_CODE), completion_code as u32);
+            prop_assert_eq!(trb.get_bits(bits::ENDPOINT_ID), u32::from(endpoint_id));
+            prop_assert_eq!(trb.get_bits(bits::SLOT_ID), u32::from(slot_id));
+            prop_assert_eq!(trb.get_bit(bits::CYCLE), cycle_bit);
+        }
+
+        #[test]
+        fn command_completion_event_trb_fields_round_trip(
+            command_trb_pointer in aligned_pointer(),
+            command_completion_parameter in 0u32..1 << 24,
+            completion_code in completion_code(),
+            slot_id: u8,
+            cycle_bit: bool,
+        ) {
+            let trb = EventTrb::new_command_completion_event_trb(
+                command_trb_pointer,
+                command_completion_parameter,
+                completion_code,
+                slot_id,
+            )
+            .to_bytes(cycle_bit);
+            prop_assert_eq!(trb.trb_type(), COMMAND_COMPLETION_EVENT);
+            prop_assert_eq!(trb.get_u64_le(0), command_trb_pointer);
+            prop_assert_eq!(
+                trb.get_bits(bits::COMMAND_COMPLETION_PARAMETER),
+                command_completion_parameter
+            );
+            prop_assert_eq!(trb.get_bits(bits::COMPLETION_CODE), completion_code as u32);
+            prop_assert_eq!(trb.get_bits(bits::SLOT_ID), u32::from(slot_id));
+            prop_assert_eq!(trb.get_bit(bits::CYCLE), cycle_bit);
+        }
+
+        #[test]
+        fn port_status_change_event_trb_fields_round_trip(port_id: u8, cycle_bit: bool) {
+            let trb = EventTrb::new_port_status_change_event_trb(port_id).to_bytes(cycle_bit);
+            prop_assert_eq!(trb.trb_type(), PORT_STATUS_CHANGE_EVENT);
+            prop_assert_eq!(trb.get_bits(bits::PORT_ID), u32::from(port_id));
+            prop_assert_eq!(trb.get_bit(bits::CYCLE), cycle_bit);
+        }
+
+        #[test]
+        fn host_controller_event_trb_fields_round_trip(
+            completion_code in completion_code(),
+            cycle_bit: bool,
+        ) {
+            let trb = EventTrb::new_host_controller_event_trb(completion_code).to_bytes(cycle_bit);
+            prop_assert_eq!(trb.trb_type(), HOST_CONTROLLER_EVENT);
+            prop_assert_eq!(trb.get_bits(bits::COMPLETION_CODE), completion_code as u32);
+            prop_assert_eq!(trb.get_bit(bits::CYCLE), cycle_bit);
+        }
+    }
 }
@@ -0,0 +1,289 @@
+//! # Field Access for Raw TRBs
+//!
+//! TRBs are four little-endian dwords. The XHCI specification describes
+//! each field by its dword and bit positions, e.g., the TRB Type occupies
+//! bits 15:10 of dword 3. [`TrbFields`] reads such fields from a
+//! [`RawTrbBuffer`], and [`TrbBuilder`] writes them, so TRB parsers and
+//! event TRB construction do not need their own byte arithmetic.
+//!
+//! Bits are numbered across the whole TRB: bit `32 * n + m` is bit `m` of
+//! dword `n`. [`bits`] has the positions of the fields we use.
+
+use std::ops::Range;
+
+use super::trb::RawTrbBuffer;
+
+/// Positions of TRB fields, numbered across the whole TRB.
+///
+/// Single-bit fields are bit numbers, wider fields are bit ranges. See
+/// Section 6.4 of the XHCI specification for the TRB layouts.
+pub mod bits {
+    use std::ops::Range;
+
+    /// Dequeue Cycle State (DCS) of Set TR Dequeue Pointer Command TRBs.
+    pub const DEQUEUE_CYCLE_STATE: usize = 0;
+    /// The Cycle bit of all TRBs.
+    pub const CYCLE: usize = 96;
+    /// Toggle Cycle (TC) of Link TRBs.
+    pub const TOGGLE_CYCLE: usize = 97;
+    /// Event Data (ED) of Transfer Event TRBs.
+    pub const EVENT_DATA: usize = 98;
+    /// Chain (CH) of Normal, Isoch, and Data Stage TRBs.
+    pub const CHAIN: usize = 100;
+    /// Interrupt On Completion (IOC) of Normal and Isoch TRBs.
+    pub const INTERRUPT_ON_COMPLETION: usize = 101;
+    /// Block Set Address Request (BSR) of Address Device Command TRBs.
+    pub const BLOCK_SET_ADDRESS_REQUEST: usize = 105;
+    /// Deconfigure (DC) of Configure Endpoint Command TRBs.
+    pub const DECONFIGURE: usize = 105;
+    /// Transfer State Preserve (TSP) of Reset Endpoint Command TRBs.
+    pub const TRANSFER_STATE_PRESERVE: usize = 105;
+    /// Start Isoch ASAP (SIA) of Isoch TRBs.
+    pub const START_ISOCH_ASAP: usize = 127;
+
+    /// The TRB Type of all TRBs.
+    pub const TRB_TYPE: Range<usize> = 106..112;
+    /// The Port ID of Port Status Change Event TRBs.
+    pub const PORT_ID: Range<usize> = 24..32;
+    /// The TRB Transfer Length of Normal and Isoch TRBs.
+    pub const TRB_TRANSFER_LENGTH: Range<usize> = 64..81;
+    /// The residual TRB Transfer Length of Transfer Event TRBs.
+    pub const EVENT_TRB_TRANSFER_LENGTH: Range<usize> = 64..88;
+    /// The Command Completion Parameter of Command Completion Event TRBs.
+    pub const COMMAND_COMPLETION_PARAMETER: Range<usize> = 64..88;
+    /// The Stream ID of Set TR Dequeue Pointer Command TRBs.
+    pub const STREAM_ID: Range<usize> = 80..96;
+    /// The Completion Code of event TRBs.
+    pub const COMPLETION_CODE: Range<usize> = 88..96;
+    /// The Endpoint ID of endpoint commands and Transfer Event TRBs.
+    pub const ENDPOINT_ID: Range<usize> = 112..117;
+    /// The Frame ID of Isoch TRBs.
+    pub const FRAME_ID: Range<usize> = 116..127;
+    /// The Slot ID of commands and event TRBs.
+    pub const SLOT_ID: Range<usize> = 120..128;
+}
+
+/// Typed reads of the fields of a raw TRB.
+///
+/// Byte offsets are relative to the start of the TRB. Multi-byte values
+/// are little-endian, regardless of the host's byte order.
+pub trait TrbFields {
+    fn get_u8(&self, offset: usize) -> u8;
+    fn get_u16_le(&self, offset: usize) -> u16;
+    fn get_u32_le(&self, offset: usize) -> u32;
+    fn get_u64_le(&self, offset: usize) -> u64;
+    /// Read a single-bit field.
+    fn get_bit(&self, bit: usize) -> bool;
+    /// Read a field of up to 32 bits that lies within one dword.
+    fn get_bits(&self, bits: Range<usize>) -> u32;
+
+    /// The TRB Type field.
+    fn trb_type(&self) -> u8 {
+        self.get_bits(bits::TRB_TYPE) as u8
+    }
+}
+
+impl TrbFields for RawTrbBuffer {
+    fn get_u8(&self, offset: usize) -> u8 {
+        self[offset]
+    }
+
+    fn get_u16_le(&self, offset: usize) -> u16 {
+        // SAFETY: range matches array length
+        u16::from_le_bytes(self[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn get_u32_le(&self, offset: usize) -> u32 {
+        // SAFETY: range matches array length
+        u32::from_le_bytes(self[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn get_u64_le(&self, offset: usize) -> u64 {
+        // SAFETY: range matches array length
+        u64::from_le_bytes(self[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn get_bit(&self, bit: usize) -> bool {
+        self[bit / 8] & (1 << (bit % 8)) != 0
+    }
+
+    fn get_bits(&self, bits: Range<usize>) -> u32 {
+        let shift = check_field(&bits);
+        (self.get_u32_le(bits.start / 32 * 4) >> shift) & field_mask(&bits)
+    }
+}
+
+/// Builds a raw TRB field by field.
+///
+/// This is the counterpart of [`TrbFields`]. All fields start out as zero,
+/// which covers the RsvdZ fields.
+#[derive(Debug, Clone, Copy)]
+pub struct TrbBuilder(RawTrbBuffer);
+
+impl TrbBuilder {
+    /// Start a TRB of the given TRB Type.
+    pub const fn new(trb_type: u8) -> Self {
+        Self([0; 16]).bits(bits::TRB_TYPE, trb_type as u32)
+    }
+
+    /// Continue with the fields of an existing TRB.
+    pub const fn from_bytes(bytes: RawTrbBuffer) -> Self {
+        Self(bytes)
+    }
+
+    // The event TRBs we send have no byte-sized fields so far, but the
+    // tests build transfer TRBs with them.
+    #[cfg(test)]
+    pub const fn u8(mut self, offset: usize, value: u8) -> Self {
+        self.0[offset] = value;
+        self
+    }
+
+    #[cfg(test)]
+    pub const fn u16_le(self, offset: usize, value: u16) -> Self {
+        self.bytes(offset, &value.to_le_bytes())
+    }
+
+    pub const fn u32_le(self, offset: usize, value: u32) -> Self {
+        self.bytes(offset, &value.to_le_bytes())
+    }
+
+    pub const fn u64_le(self, offset: usize, value: u64) -> Self {
+        self.bytes(offset, &value.to_le_bytes())
+    }
+
+    /// Set or clear a single-bit field.
+    pub const fn bit(mut self, bit: usize, value: bool) -> Self {
+        let mask = 1 << (bit % 8);
+        self.0[bit / 8] = if value {
+            self.0[bit / 8] | mask
+        } else {
+            self.0[bit / 8] & !mask
+        };
+        self
+    }
+
+    /// Write a field of up to 32 bits that lies within one dword.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` does not fit into the field.
+    pub const fn bits(self, bits: Range<usize>, value: u32) -> Self {
+        let shift = check_field(&bits);
+        let mask = field_mask(&bits);
+        assert!(value & !mask == 0, "value does not fit into TRB field");
+
+        let offset = bits.start / 32 * 4;
+        let dword = u32::from_le_bytes([
+            self.0[offset],
+            self.0[offset + 1],
+            self.0[offset + 2],
+            self.0[offset + 3],
+        ]);
+        let dword = dword & !(mask << shift) | value << shift;
+        self.u32_le(offset, dword)
+    }
+
+    pub const fn build(self) -> RawTrbBuffer {
+        self.0
+    }
+
+    const fn bytes(mut self, offset: usize, bytes: &[u8]) -> Self {
+        let mut i = 0;
+        while i < bytes.len() {
+            self.0[offset + i] = bytes[i];
+            i += 1;
+        }
+        self
+    }
+}
+
+/// Check that a field is non-empty and lies within one dword.
+///
+/// Returns the position of the field's lowest bit in its dword.
+const fn check_field(bits: &Range<usize>) -> usize {
+    assert!(
+        bits.start < bits.end && bits.end <= 128,
+        "invalid TRB field"
+    );
+    assert!(
+        bits.start / 32 == (bits.end - 1) / 32,
+        "TRB field crosses a dword boundary"
+    );
+    bits.start % 32
+}
+
+/// The mask of a field's value, not shifted to its position.
+const fn field_mask(bits: &Range<usize>) -> u32 {
+    u32::MAX >> (32 - (bits.end - bits.start))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn fields_follow_the_specification_layout() {
+        // A Transfer Event TRB, see Section 6.4.2.1 of the XHCI specification.
+        let trb = [
+            0x80, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x56, 0x34, 0x12, 0x0d, 0x05, 0x80,
+            0x03, 0x02,
+        ];
+
+        assert_eq!(trb.get_u64_le(0), 0x1122_3344_5566_7780);
+        assert_eq!(trb.get_bits(bits::EVENT_TRB_TRANSFER_LENGTH), 0x12_3456);
+        assert_eq!(trb.get_bits(bits::COMPLETION_CODE), 0x0d);
+        assert!(trb.get_bit(bits::CYCLE));
+        assert!(trb.get_bit(bits::EVENT_DATA));
+        assert_eq!(trb.trb_type(), 32);
+        assert_eq!(trb.get_bits(bits::ENDPOINT_ID), 3);
+        assert_eq!(trb.get_bits(bits::SLOT_ID), 2);
+        assert_eq!(trb.get_u16_le(14), 0x0203);
+        assert_eq!(trb.get_u32_le(12), 0x0203_8005);
+    }
+
+    #[test]
+    #[should_panic(expected = "value does not fit into TRB field")]
+    fn oversized_values_are_rejected() {
+        TrbBuilder::new(1).bits(bits::ENDPOINT_ID, 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "TRB field crosses a dword boundary")]
+    fn fields_across_dwords_are_rejected() {
+        TrbBuilder::new(1).bits(60..70, 0);
+    }
+
+    proptest! {
+        #[test]
+        fn bits_round_trip_and_leave_other_bits_alone(
+            bytes: [u8; 16],
+            start in 0usize..128,
+            len in 1usize..=32,
+            value: u32,
+        ) {
+            let dword_end = (start / 32 + 1) * 32;
+            let field = start..(start + len).min(dword_end);
+            let value = value & field_mask(&field);
+
+            let trb = TrbBuilder::from_bytes(bytes).bits(field.clone(), value).build();
+
+            prop_assert_eq!(trb.get_bits(field.clone()), value);
+            for bit in (0..128).filter(|bit| !field.contains(bit)) {
+                prop_assert_eq!(trb.get_bit(bit), bytes.get_bit(bit));
+            }
+        }
+
+        #[test]
+        fn integers_round_trip(value: u64, offset in 0usize..=8) {
+            let trb = TrbBuilder::new(1)
+                .u64_le(offset, value)
+                .build();
+            prop_assert_eq!(trb.get_u64_le(offset), value);
+            prop_assert_eq!(trb.get_u32_le(offset), value as u32);
+            prop_assert_eq!(trb.get_u16_le(offset), value as u16);
+        }
+    }
+}
@@ -1,3 +1,23 @@
+/// Standard requests, see Table 9-4 of the USB 2.0 specification.
+pub mod request {
+    pub const GET_STATUS: u8 = 0;
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const SET_FEATURE: u8 = 3;
+    pub const SET_ADDRESS: u8 = 5;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const GET_CONFIGURATION: u8 = 8;
+    pub const SET_CONFIGURATION: u8 = 9;
+    pub const GET_INTERFACE: u8 = 10;
+    pub const SET_INTERFACE: u8 = 11;
+}
+
+/// Standard feature selectors, see Table 9-6 of the USB 2.0 specification.
+pub mod feature {
+    pub const ENDPOINT_HALT: u16 = 0;
+}
+
+use super::dci::Dci;
+
 /// Represent a USB control request.
 ///
 /// For documentation of the fields other than `address`, see Section "9.3 USB
@@ -7,9 +27,13 @@
 /// Status Stage). `data` should then be `None`.
 ///
 /// A request with data is packaged in three TRBs (a Setup Stage, a Data
-/// Stage and a Status Stage). `data` should then contain the pointer
-/// from the Data Stage).
+/// Stage and a Status Stage). `data` should then describe the buffer of
+/// the Data Stage.
 ///
+/// Drivers do not always keep both consistent: a Data Stage may follow a
+/// Setup Stage with a `length` of 0, which we ignore, and a Setup Stage
+/// with a `length` may lack the Data Stage, see
+/// [`lacks_data_stage`](Self::lacks_data_stage).
 #[derive(Debug, PartialEq, Eq)]
 pub struct UsbRequest {
     /// The guest address of the Status Stage of this request.
@@ -19,5 +43,65 @@ pub struct UsbRequest {
     pub value: u16,
     pub index: u16,
     pub length: u16,
-    pub data: Option<u64>,
+    pub data: Option<DataStage>,
+}
+
+/// The buffer of the Data Stage of a control request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataStage {
+    /// The guest address of the buffer.
+    pub pointer: u64,
+    /// The bytes transferred to or from the buffer, which is the smaller
+    /// of the request's `length` and the TRB Transfer Length.
+    pub length: u16,
+}
+
+impl UsbRequest {
+    /// Whether the controller answers the request itself instead of
+    /// forwarding it to the device.
+    ///
+    /// The root hub of an xHC is no USB device, drivers emulate its hub
+    /// requests with the port registers. The only standard request the
+    /// controller takes over is SET_ADDRESS: the xHC addresses devices
+    /// itself when the driver issues an Address Device Command (xHCI 4.6.5).
+    /// Forwarding the request would change the address of a passed-through
+    /// device behind the back of the host, so it completes without effect.
+    pub const fn is_answered_by_controller(&self) -> bool {
+        self.request_type == 0x00 && self.request == request::SET_ADDRESS
+    }
+
+    /// The endpoint whose halt a CLEAR_FEATURE(ENDPOINT_HALT) request
+    /// clears, unless it is the Default Control Endpoint.
+    pub fn cleared_endpoint_halt(&self) -> Option<Dci> {
+        if self.request_type != 0x02
+            || self.request != request::CLEAR_FEATURE
+            || self.value != feature::ENDPOINT_HALT
+        {
+            return None;
+        }
+        u8::try_from(self.index)
+            .ok()
+            .and_then(Dci::from_address)
+            .filter(|&endpoint_id| endpoint_id != Dci::CONTROL)
+    }
+
+    /// The descriptor type a standard GET_DESCRIPTOR request for the
+    /// device asks for.
+    pub const fn requested_descriptor_type(&self) -> Option<u8> {
+        if self.request_type == 0x80 && self.request == request::GET_DESCRIPTOR {
+            Some((self.value >> 8) as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the request announces data, but the driver queued no Data
+    /// Stage for it.
+    ///
+    /// The request cannot be carried out as the device expects it, so the
+    /// controller completes it with a Stall Error, like a device that
+    /// stalls the missing Data Stage.
+    pub const fn lacks_data_stage(&self) -> bool {
+        self.length != 0 && self.data.is_none()
+    }
 }
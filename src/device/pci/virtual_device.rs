@@ -0,0 +1,580 @@
+//! # Virtual USB Devices
+//!
+//! For demos and CI, usbvfiod can attach devices that exist only in
+//! software instead of passing through real ones. A virtual device answers
+//! control requests from canned descriptors and produces canned input on
+//! its interrupt endpoint, so the guest enumerates and uses it like any
+//! other device.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use tracing::{debug, warn};
+
+use crate::{
+    affinity::{spawn_thread, ThreadPolicy},
+    device::{
+        bus::BusDeviceRef,
+        pci::{
+            dci::Dci,
+            descriptors::{descriptor_type, ConfigurationDescriptor, DeviceDescriptor},
+            realdevice::{
+                DeviceIdentification, DeviceIdentity, EndpointType, EndpointWorkerInfo,
+                HostLocation, RealDevice, Speed,
+            },
+            td_engine::{write_in_data, TdOutcome},
+            trb::CompletionCode,
+            usbrequest::{request, UsbRequest},
+        },
+    },
+};
+
+/// The kinds of virtual devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VirtualDeviceKind {
+    /// A Full Speed HID mouse that moves the pointer along a square.
+    Mouse,
+}
+
+impl VirtualDeviceKind {
+    /// Create a device of this kind.
+    pub fn create(self) -> Box<dyn RealDevice> {
+        match self {
+            Self::Mouse => Box::new(VirtualMouse::default()),
+        }
+    }
+}
+
+impl fmt::Display for VirtualDeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mouse => write!(f, "virtual mouse"),
+        }
+    }
+}
+
+/// The pid.codes vendor ID, which is free to use for test devices.
+const VENDOR_ID: u16 = 0x1209;
+/// The pid.codes test product ID.
+const PRODUCT_ID: u16 = 0x0001;
+
+/// HID class requests, see Section 7.2 of the HID 1.11 specification.
+mod hid_request {
+    pub const GET_REPORT: u8 = 1;
+    pub const GET_IDLE: u8 = 2;
+    pub const GET_PROTOCOL: u8 = 3;
+    pub const SET_IDLE: u8 = 0x0a;
+    pub const SET_PROTOCOL: u8 = 0x0b;
+}
+
+const MOUSE_DEVICE_DESCRIPTOR: [u8; 18] = [
+    18,   // bLength
+    0x01, // bDescriptorType: Device
+    0x00, // bcdUSB: 2.00
+    0x02,
+    0x00,            // bDeviceClass: per interface
+    0x00,            // bDeviceSubClass
+    0x00,            // bDeviceProtocol
+    64,              // bMaxPacketSize0
+    VENDOR_ID as u8, // idVendor
+    (VENDOR_ID >> 8) as u8,
+    PRODUCT_ID as u8, // idProduct
+    (PRODUCT_ID >> 8) as u8,
+    0x00, // bcdDevice: 1.00
+    0x01,
+    1, // iManufacturer
+    2, // iProduct
+    0, // iSerialNumber
+    1, // bNumConfigurations
+];
+
+/// A boot protocol compatible mouse with three buttons, X, Y, and a wheel.
+const MOUSE_REPORT_DESCRIPTOR: [u8; 52] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xc0, //         End Collection
+    0xc0, //       End Collection
+];
+
+/// The HID descriptor, which is part of the configuration descriptor.
+const MOUSE_HID_DESCRIPTOR: [u8; 9] = [
+    9,    // bLength
+    0x21, // bDescriptorType: HID
+    0x11, // bcdHID: 1.11
+    0x01,
+    0x00,                                // bCountryCode
+    1,                                   // bNumDescriptors
+    0x22,                                // bDescriptorType: Report
+    MOUSE_REPORT_DESCRIPTOR.len() as u8, // wDescriptorLength
+    0x00,
+];
+
+/// The size of an input report and the Max Packet Size of EP1 IN.
+const MOUSE_REPORT_SIZE: usize = 4;
+
+/// The Endpoint ID of EP1 IN, which delivers the input reports.
+const MOUSE_ENDPOINT_ID: Dci = match Dci::from_address(0x81) {
+    Some(endpoint_id) => endpoint_id,
+    None => unreachable!(),
+};
+
+/// The configuration descriptor with the interface, HID, and endpoint
+/// descriptors that follow it.
+fn mouse_configuration_descriptor() -> Vec<u8> {
+    let mut descriptor = vec![
+        9,    // bLength
+        0x02, // bDescriptorType: Configuration
+        0, 0,    // wTotalLength, see below
+        1,    // bNumInterfaces
+        1,    // bConfigurationValue
+        0,    // iConfiguration
+        0xa0, // bmAttributes: bus-powered, remote wakeup
+        50,   // bMaxPower: 100 mA
+        // Interface descriptor
+        9,    // bLength
+        0x04, // bDescriptorType: Interface
+        0,    // bInterfaceNumber
+        0,    // bAlternateSetting
+        1,    // bNumEndpoints
+        0x03, // bInterfaceClass: HID
+        0x01, // bInterfaceSubClass: boot interface
+        0x02, // bInterfaceProtocol: mouse
+        0,    // iInterface
+    ];
+    descriptor.extend_from_slice(&MOUSE_HID_DESCRIPTOR);
+    descriptor.extend_from_slice(&[
+        7,                       // bLength
+        0x05,                    // bDescriptorType: Endpoint
+        0x81,                    // bEndpointAddress: EP1 IN
+        0x03,                    // bmAttributes: interrupt
+        MOUSE_REPORT_SIZE as u8, // wMaxPacketSize
+        0x00,
+        10, // bInterval: 10 ms
+    ]);
+    let total_length = (descriptor.len() as u16).to_le_bytes();
+    descriptor[2..4].copy_from_slice(&total_length);
+    descriptor
+}
+
+/// A string descriptor for `s`.
+fn string_descriptor(s: &str) -> Vec<u8> {
+    let mut descriptor = vec![0, descriptor_type::STRING];
+    descriptor.extend(s.encode_utf16().flat_map(u16::to_le_bytes));
+    descriptor[0] = descriptor.len() as u8;
+    descriptor
+}
+
+/// The number of reports after which the pointer is back where it started.
+const SQUARE_REPORTS: usize = 100;
+
+/// The input report number `step`.
+///
+/// The pointer moves along the sides of a square, one side per quarter of
+/// [`SQUARE_REPORTS`]. No buttons are pressed and the wheel stays still.
+const fn mouse_report(step: usize) -> [u8; MOUSE_REPORT_SIZE] {
+    const SPEED: i8 = 4;
+    let (dx, dy) = match step % SQUARE_REPORTS / (SQUARE_REPORTS / 4) {
+        0 => (SPEED, 0),
+        1 => (0, SPEED),
+        2 => (-SPEED, 0),
+        _ => (0, -SPEED),
+    };
+    [0, dx as u8, dy as u8, 0]
+}
+
+/// The interval of input reports if the driver did not configure one.
+const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A HID mouse that is emulated in software.
+#[derive(Debug)]
+pub struct VirtualMouse {
+    identity: Arc<DeviceIdentity>,
+    /// The active configuration, 0 while unconfigured.
+    configuration: AtomicU8,
+    /// The HID protocol, 0 for the boot protocol and 1 for the report
+    /// protocol.
+    protocol: AtomicU8,
+    /// The worker of EP1 IN, if the driver enabled the endpoint.
+    endpoint: Option<Sender<WorkerMessage>>,
+}
+
+/// What the controller asks the worker of EP1 IN to do.
+#[derive(Debug)]
+enum WorkerMessage {
+    /// The driver rang the doorbell.
+    Doorbell,
+    /// Stop, acknowledge, and exit afterwards if `exit`.
+    Stop {
+        exit: bool,
+        acknowledgment: Sender<()>,
+    },
+}
+
+impl Default for VirtualMouse {
+    fn default() -> Self {
+        // Virtual devices are not on any host bus. Host buses and device
+        // addresses are numbered from 1.
+        let location = HostLocation {
+            bus_number: 0,
+            device_address: 0,
+        };
+        let identification = DeviceIdentification {
+            vendor_id: VENDOR_ID,
+            product_id: PRODUCT_ID,
+            serial: None,
+        };
+        Self {
+            identity: Arc::new(DeviceIdentity::new(location, identification)),
+            configuration: AtomicU8::default(),
+            protocol: AtomicU8::default(),
+            endpoint: None,
+        }
+    }
+}
+
+impl VirtualMouse {
+    /// The response to a control request, or `None` to stall it.
+    fn respond(&self, request: &UsbRequest) -> Option<Vec<u8>> {
+        match (request.request_type, request.request) {
+            (0x80, request::GET_DESCRIPTOR) => {
+                let [index, descriptor_type] = request.value.to_le_bytes();
+                match (descriptor_type, index) {
+                    (descriptor_type::DEVICE, 0) => Some(MOUSE_DEVICE_DESCRIPTOR.to_vec()),
+                    (descriptor_type::CONFIGURATION, 0) => Some(mouse_configuration_descriptor()),
+                    // The supported languages: US English.
+                    (descriptor_type::STRING, 0) => {
+                        Some(vec![4, descriptor_type::STRING, 0x09, 0x04])
+                    }
+                    (descriptor_type::STRING, 1) => Some(string_descriptor("usbvfiod")),
+                    (descriptor_type::STRING, 2) => Some(string_descriptor("Virtual Mouse")),
+                    _ => None,
+                }
+            }
+            (0x81, request::GET_DESCRIPTOR) if request.index == 0 => {
+                match request.value.to_le_bytes()[1] {
+                    descriptor_type::HID => Some(MOUSE_HID_DESCRIPTOR.to_vec()),
+                    descriptor_type::REPORT => Some(MOUSE_REPORT_DESCRIPTOR.to_vec()),
+                    _ => None,
+                }
+            }
+            (0x80..=0x82, request::GET_STATUS) => Some(vec![0, 0]),
+            (0x80, request::GET_CONFIGURATION) => {
+                Some(vec![self.configuration.load(Ordering::Relaxed)])
+            }
+            (0x00, request::SET_CONFIGURATION) if request.value <= 1 => {
+                self.configuration
+                    .store(request.value as u8, Ordering::Relaxed);
+                Some(Vec::new())
+            }
+            (0x00 | 0x02, request::CLEAR_FEATURE | request::SET_FEATURE) => Some(Vec::new()),
+            (0x01, request::SET_INTERFACE) if request.value == 0 => Some(Vec::new()),
+            (0x81, request::GET_INTERFACE) => Some(vec![0]),
+            // Reports are only sent when the host polls, so the idle rate
+            // does not matter.
+            (0x21, hid_request::SET_IDLE) => Some(Vec::new()),
+            (0x21, hid_request::SET_PROTOCOL) if request.value <= 1 => {
+                self.protocol.store(request.value as u8, Ordering::Relaxed);
+                Some(Vec::new())
+            }
+            (0xa1, hid_request::GET_IDLE) => Some(vec![0]),
+            (0xa1, hid_request::GET_PROTOCOL) => Some(vec![self.protocol.load(Ordering::Relaxed)]),
+            (0xa1, hid_request::GET_REPORT) => Some(vec![0; MOUSE_REPORT_SIZE]),
+            _ => None,
+        }
+    }
+
+    /// Ask the worker of EP1 IN to stop and wait up to `timeout` for it.
+    fn stop_worker(worker: &Sender<WorkerMessage>, exit: bool, timeout: Duration) -> bool {
+        let (acknowledgment, acknowledged) = mpsc::channel();
+        // A worker that is gone has nothing left to stop.
+        if worker
+            .send(WorkerMessage::Stop {
+                exit,
+                acknowledgment,
+            })
+            .is_err()
+        {
+            return true;
+        }
+        acknowledged.recv_timeout(timeout).is_ok()
+    }
+}
+
+impl RealDevice for VirtualMouse {
+    fn speed(&self) -> Option<Speed> {
+        Some(Speed::Full)
+    }
+
+    fn identity(&self) -> &Arc<DeviceIdentity> {
+        &self.identity
+    }
+
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor::parse(&MOUSE_DEVICE_DESCRIPTOR).expect("canned descriptor is valid")
+    }
+
+    fn configuration_descriptors(&self) -> Vec<ConfigurationDescriptor> {
+        vec![
+            ConfigurationDescriptor::parse(&mouse_configuration_descriptor())
+                .expect("canned descriptor is valid"),
+        ]
+    }
+
+    fn control_transfer(&self, request: &UsbRequest, dma_bus: &BusDeviceRef) -> CompletionCode {
+        let Some(response) = self.respond(request) else {
+            debug!("virtual mouse stalls control request {:?}", request);
+            return CompletionCode::StallError;
+        };
+        let Some(buffer) = request.data.filter(|_| request.request_type & 0x80 != 0) else {
+            return CompletionCode::Success;
+        };
+        match write_in_data(dma_bus, buffer.pointer, &response, buffer.length.into()) {
+            Ok(_) => CompletionCode::Success,
+            Err(unmapped) => {
+                warn!(
+                    "control in buffer is not fully backed by guest memory (unmapped: {:#x}..{:#x}); reporting Data Buffer Error",
+                    unmapped.start, unmapped.end
+                );
+                CompletionCode::DataBufferError
+            }
+        }
+    }
+
+    fn enable_endpoint(&mut self, worker_info: EndpointWorkerInfo, endpoint_type: EndpointType) {
+        if worker_info.endpoint_id != MOUSE_ENDPOINT_ID
+            || endpoint_type != EndpointType::InterruptIn
+        {
+            warn!(
+                "virtual mouse has no {:?} endpoint with ID {}",
+                endpoint_type, worker_info.endpoint_id
+            );
+            return;
+        }
+        if self.endpoint.is_some() {
+            // Like for real devices, drivers may configure the endpoint
+            // twice in a row.
+            return;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let name = self.identity.worker_thread_name(worker_info.endpoint_id);
+        let span = worker_info.span();
+        spawn_thread(name.clone(), ThreadPolicy::default(), move || {
+            let _span = span.entered();
+            mouse_worker(worker_info, receiver)
+        })
+        .unwrap_or_else(|_| panic!("Failed to launch endpoint worker thread {name}"));
+        self.endpoint = Some(sender);
+        debug!("enabled EP{} on virtual mouse", MOUSE_ENDPOINT_ID);
+    }
+
+    fn transfer(&mut self, endpoint_id: Dci, _stream_id: u16) {
+        match self.endpoint.as_ref() {
+            Some(worker) if endpoint_id == MOUSE_ENDPOINT_ID => {
+                // The worker only exits after we dropped its sender or asked
+                // it to, so sending should never fail.
+                let _ = worker.send(WorkerMessage::Doorbell);
+            }
+            _ => debug!("ignoring transfer for disabled EP{}", endpoint_id),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.configuration.store(0, Ordering::Relaxed);
+    }
+
+    fn clear_halt(&mut self, _endpoint_id: Dci) {
+        // The virtual mouse never halts its endpoint.
+    }
+
+    fn stop_endpoint(&mut self, endpoint_id: Dci, timeout: Duration) -> bool {
+        match self.endpoint.as_ref() {
+            Some(worker) if endpoint_id == MOUSE_ENDPOINT_ID => {
+                Self::stop_worker(worker, false, timeout)
+            }
+            _ => true,
+        }
+    }
+
+    fn disable_endpoint(&mut self, endpoint_id: Dci, timeout: Duration) -> bool {
+        if endpoint_id != MOUSE_ENDPOINT_ID {
+            return true;
+        }
+        self.endpoint
+            .take()
+            .is_none_or(|worker| Self::stop_worker(&worker, true, timeout))
+    }
+
+    fn release(&mut self, timeout: Duration) {
+        if !self.disable_endpoint(MOUSE_ENDPOINT_ID, timeout) {
+            warn!(
+                "worker of the virtual mouse did not exit within {:?}",
+                timeout
+            );
+        }
+    }
+}
+
+/// Serve the TDs of EP1 IN with one input report per interval.
+///
+/// Like a real mouse, the worker only answers once the interval since the
+/// last report has passed, so a driver that keeps TDs queued receives
+/// reports at the rate it configured.
+fn mouse_worker(mut worker_info: EndpointWorkerInfo, messages: Receiver<WorkerMessage>) {
+    let interval = worker_info
+        .polling_interval
+        .unwrap_or(DEFAULT_REPORT_INTERVAL);
+    let mut step = 0;
+    let mut next_report = Instant::now();
+    // Whether we wait for a doorbell, because the ring ran dry or the
+    // endpoint was stopped.
+    let mut idle = true;
+    loop {
+        let message = if idle {
+            messages.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            messages.recv_timeout(next_report.saturating_duration_since(Instant::now()))
+        };
+        match message {
+            Ok(WorkerMessage::Doorbell) => {
+                worker_info.engine.refill();
+                idle = false;
+            }
+            Ok(WorkerMessage::Stop {
+                exit,
+                acknowledgment,
+            }) => {
+                worker_info.engine.flush_events();
+                // The controller may have given up waiting already.
+                let _ = acknowledgment.send(());
+                if exit {
+                    debug!("virtual mouse worker: Disabled");
+                    return;
+                }
+                idle = true;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                worker_info.engine.wait_for_event_space();
+                match worker_info.engine.next_td() {
+                    Some(td) => {
+                        let report = mouse_report(step);
+                        worker_info.engine.complete_td(
+                            td,
+                            TdOutcome::In {
+                                data: &report,
+                                stopped: false,
+                            },
+                        );
+                        worker_info.engine.flush_events();
+                        step += 1;
+                        next_report = Instant::now() + interval;
+                    }
+                    None => {
+                        worker_info.engine.flush_events();
+                        idle = true;
+                    }
+                }
+            }
+            // The device is gone.
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors_are_consistent() {
+        let configuration = mouse_configuration_descriptor();
+        assert_eq!(
+            usize::from(u16::from_le_bytes([configuration[2], configuration[3]])),
+            configuration.len()
+        );
+        // Configuration, interface, HID, and endpoint descriptors.
+        assert_eq!(configuration.len(), 9 + 9 + 9 + 7);
+        assert_eq!(
+            usize::from(MOUSE_HID_DESCRIPTOR[7]),
+            MOUSE_REPORT_DESCRIPTOR.len()
+        );
+        assert_eq!(
+            string_descriptor("usbvfiod"),
+            [18, 3, b'u', 0, b's', 0, b'b', 0, b'v', 0, b'f', 0, b'i', 0, b'o', 0, b'd', 0]
+        );
+    }
+
+    #[test]
+    fn pointer_returns_to_its_start() {
+        let (x, y) = (0..SQUARE_REPORTS)
+            .map(mouse_report)
+            .fold((0, 0), |(x, y), report| {
+                (
+                    x + i32::from(report[1] as i8),
+                    y + i32::from(report[2] as i8),
+                )
+            });
+        assert_eq!((x, y), (0, 0));
+        assert_ne!(mouse_report(0), mouse_report(SQUARE_REPORTS / 2));
+    }
+
+    #[test]
+    fn unknown_requests_stall() {
+        let mouse = VirtualMouse::default();
+        let request = |request_type, request, value| UsbRequest {
+            address: 0,
+            request_type,
+            request,
+            value,
+            index: 0,
+            length: 0,
+            data: None,
+        };
+
+        // Vendor requests and a second configuration.
+        assert_eq!(mouse.respond(&request(0x40, 0x01, 0)), None);
+        assert_eq!(
+            mouse.respond(&request(0x00, request::SET_CONFIGURATION, 2)),
+            None
+        );
+
+        assert_eq!(
+            mouse.respond(&request(0x00, request::SET_CONFIGURATION, 1)),
+            Some(Vec::new())
+        );
+        assert_eq!(
+            mouse.respond(&request(0x80, request::GET_CONFIGURATION, 0)),
+            Some(vec![1])
+        );
+    }
+}
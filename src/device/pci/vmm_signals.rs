@@ -0,0 +1,107 @@
+//! # Signals to the VMM
+//!
+//! Besides the interrupts for the guest, vfio-user devices have two
+//! interrupts that address the VMM itself. The error interrupt
+//! (`VFIO_PCI_ERR_IRQ_INDEX`) reports that the device failed, so the VMM
+//! can surface the error to its management layer. The request interrupt
+//! (`VFIO_PCI_REQ_IRQ_INDEX`) asks the VMM to release the device, e.g.,
+//! because usbvfiod shuts down or lost the USB device it passes through.
+//!
+//! The VMM connects an eventfd to each of them, which [`VmmSignals`]
+//! keeps. Signals without a connected eventfd are only logged.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use tracing::{error, info, warn};
+
+use crate::device::interrupt_line::InterruptLine;
+
+/// The error and request interrupts of the VMM.
+#[derive(Debug, Default)]
+pub struct VmmSignals {
+    error: Mutex<Option<Arc<dyn InterruptLine>>>,
+    request: Mutex<Option<Arc<dyn InterruptLine>>>,
+    /// Whether we already asked the VMM to release the device.
+    release_requested: AtomicBool,
+}
+
+impl VmmSignals {
+    /// Connect the error interrupt, or disconnect it with `None`.
+    pub fn connect_error(&self, line: Option<Arc<dyn InterruptLine>>) {
+        *self.error.lock().unwrap() = line;
+    }
+
+    /// Connect the request interrupt, or disconnect it with `None`.
+    ///
+    /// A newly connected VMM has not been asked for anything yet.
+    pub fn connect_request(&self, line: Option<Arc<dyn InterruptLine>>) {
+        *self.request.lock().unwrap() = line;
+        self.release_requested.store(false, Ordering::Relaxed);
+    }
+
+    /// Report that the device failed for `reason`.
+    pub fn signal_error(&self, reason: &str) {
+        error!("device error: {reason}");
+        match self.error.lock().unwrap().as_ref() {
+            Some(line) => line.interrupt(),
+            None => warn!("the VMM did not connect the error interrupt"),
+        }
+    }
+
+    /// Ask the VMM to release the device because of `reason`.
+    ///
+    /// The VMM is only asked once. Returns `false` if no VMM can be asked,
+    /// because none connected the request interrupt.
+    pub fn request_release(&self, reason: &str) -> bool {
+        let Some(line) = self.request.lock().unwrap().clone() else {
+            return false;
+        };
+        if !self.release_requested.swap(true, Ordering::Relaxed) {
+            info!("asking the VMM to release the device: {reason}");
+            line.interrupt();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::pci::event_sink::testutils::CountingInterruptLine;
+
+    use super::*;
+
+    #[test]
+    fn release_is_requested_once_per_connection() {
+        let signals = VmmSignals::default();
+        assert!(!signals.request_release("shutdown"));
+
+        let first = Arc::new(CountingInterruptLine::default());
+        signals.connect_request(Some(first.clone()));
+        assert!(signals.request_release("shutdown"));
+        assert!(signals.request_release("device gone"));
+        assert_eq!(first.count(), 1);
+
+        let second = Arc::new(CountingInterruptLine::default());
+        signals.connect_request(Some(second.clone()));
+        assert!(signals.request_release("shutdown"));
+        assert_eq!((first.count(), second.count()), (1, 1));
+    }
+
+    #[test]
+    fn errors_only_reach_the_error_line() {
+        let signals = VmmSignals::default();
+        // Without a VMM, errors are only logged.
+        signals.signal_error("worker stuck");
+
+        let error = Arc::new(CountingInterruptLine::default());
+        let request = Arc::new(CountingInterruptLine::default());
+        signals.connect_error(Some(error.clone()));
+        signals.connect_request(Some(request.clone()));
+        signals.signal_error("worker stuck");
+
+        assert_eq!((error.count(), request.count()), (1, 0));
+    }
+}
@@ -3,39 +3,370 @@
 //! The specification is available
 //! [here](https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf).
 
-use std::sync::{
-    atomic::{fence, Ordering},
-    Arc, Mutex,
+use std::{
+    num::NonZeroUsize,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
+use thiserror::Error;
 use tracing::{debug, info, trace, warn};
 
 use crate::device::{
-    bus::{BusDeviceRef, Request, SingleThreadedBusDevice},
+    bus::{BusDeviceRef, Request, RequestSize, ScratchMemory, SingleThreadedBusDevice},
     interrupt_line::{DummyInterruptLine, InterruptLine},
+    interval::Interval,
     pci::{
-        config_space::{ConfigSpace, ConfigSpaceBuilder},
+        config_space::{ConfigSpace, ConfigSpaceBuilder, PciIdentity},
+        constants::config_space::identification,
         constants::xhci::{
-            capability, offset, operational::portsc, runtime, MAX_INTRS, MAX_SLOTS, NUM_USB3_PORTS,
-            OP_BASE, RUN_BASE,
+            capability, msix_bar, offset, operational::portsc, MAX_INTRS, MAX_SLOTS,
+            NUM_USB3_PORTS, OP_BASE, RUN_BASE,
         },
         traits::PciDevice,
         trb::{CommandTrbVariant, CompletionCode, EventTrb},
     },
+    register_set::{RegisterSet, RegisterSetBuilder},
 };
 
+#[cfg(feature = "nusb-backend")]
+use super::scheduler::HostBusScheduler;
 use super::{
+    commands::{CommandError, CommandOutcome, CommandPolicy, CommandResult},
     config_space::BarInfo,
-    constants::xhci::{device_slots::endpoint_state, operational::usbsts, MAX_PORTS},
-    device_slots::DeviceSlotManager,
-    realdevice::{EndpointWorkerInfo, RealDevice, Speed},
-    registers::PortscRegister,
-    rings::{CommandRing, EventRing},
+    constants::xhci::{
+        device_slots::endpoint_state,
+        operational::{usbcmd, usbsts},
+        MAX_PORTS,
+    },
+    dci::Dci,
+    descriptors::{self, descriptor_type},
+    device_slots::{DeviceSlotManager, EndpointContext},
+    doorbell::{DoorbellValue, IgnoredDoorbell, IgnoredDoorbells},
+    endpoint_stats::EndpointStatsTable,
+    erdp_watch::DEFAULT_STUCK_ERDP_TIMEOUT,
+    event_sink::{EventRingStatus, EventSink, DEFAULT_MAX_DEFERRED_EVENTS},
+    isoch::MicroframeClock,
+    lifecycle::{ControllerLifecycle, ResetKind, Step},
+    mmio_profile::{MmioAccess, MmioProfile},
+    msix_pba::{MaskableInterruptLine, PendingBitArray},
+    msix_table::{MsixTable, MSIX_ENTRY_SIZE},
+    paranoid_dma::{self, DmaOrigin},
+    realdevice::{DeviceIdentification, EndpointType, EndpointWorkerInfo, RealDevice, Speed},
+    registers::{PortpmscRegister, PortscRegister},
+    rings::{
+        CommandRing, CommandRingError, RequestParseError, MAX_SEGMENT_BOUNDARY,
+        PAGE_SEGMENT_BOUNDARY,
+    },
+    run_state::PendingDoorbells,
+    td_engine::{write_in_data, TdEngine, TdEngineConfig},
+    trace::{self, TraceEvent, TraceRecorder},
     trb::{
         AddressDeviceCommandTrbData, CommandTrb, ConfigureEndpointCommandTrbData,
-        StopEndpointCommandTrbData,
+        DisableSlotCommandTrbData, EvaluateContextCommandTrbData, ResetDeviceCommandTrbData,
+        ResetEndpointCommandTrbData, StopEndpointCommandTrbData,
     },
+    usbrequest::{DataStage, UsbRequest},
+    vmm_signals::VmmSignals,
 };
 
+/// Encode a string for the identification capability.
+///
+/// Non-ASCII characters become `?`. A string that fills the field has no
+/// terminating zero.
+fn identification_string(s: &str) -> [u8; identification::STRING_SIZE] {
+    let mut field = [0; identification::STRING_SIZE];
+    for (byte, c) in field.iter_mut().zip(s.chars()) {
+        *byte = if c.is_ascii() { c as u8 } else { b'?' };
+    }
+    field
+}
+
+/// Build the read-only registers in front of the operational registers.
+fn capability_registers() -> RegisterSet<{ OP_BASE as usize }> {
+    use capability::{supported_protocols, supported_protocols_usb2, PROTOCOL_NAME};
+
+    let mut builder = RegisterSetBuilder::<{ OP_BASE as usize }>::new();
+    // Reserved fields and the Protocol Slot Types read as zero.
+    for offset in (0..OP_BASE as usize).step_by(4) {
+        builder.u32_le_ro_at(offset, 0);
+    }
+    let at = |offset: u64| offset as usize;
+    builder
+        .u8_ro_at(at(offset::CAPLENGTH), OP_BASE as u8)
+        .u16_le_ro_at(at(offset::HCIVERSION), capability::HCIVERSION as u16)
+        .u32_le_ro_at(at(offset::HCSPARAMS1), capability::HCSPARAMS1 as u32)
+        .u32_le_ro_at(at(offset::HCSPARAMS2), capability::HCSPARAMS2 as u32)
+        .u32_le_ro_at(at(offset::HCSPARAMS3), capability::HCSPARAMS3 as u32)
+        .u32_le_ro_at(at(offset::HCCPARAMS1), capability::HCCPARAMS1 as u32)
+        .u32_le_ro_at(at(offset::DBOFF), offset::DOORBELL_CONTROLLER as u32)
+        .u32_le_ro_at(at(offset::RTSOFF), RUN_BASE as u32)
+        // xHC Extended Capabilities ("Supported Protocols Capability")
+        .u32_le_ro_at(
+            at(offset::SUPPORTED_PROTOCOLS),
+            supported_protocols::CAP_INFO as u32,
+        )
+        .u32_le_ro_at(at(offset::SUPPORTED_PROTOCOLS_NAME), PROTOCOL_NAME as u32)
+        .u32_le_ro_at(
+            at(offset::SUPPORTED_PROTOCOLS_CONFIG),
+            supported_protocols::CONFIG as u32,
+        )
+        .u32_le_ro_at(
+            at(offset::SUPPORTED_PROTOCOLS_USB2),
+            supported_protocols_usb2::CAP_INFO as u32,
+        )
+        .u32_le_ro_at(
+            at(offset::SUPPORTED_PROTOCOLS_USB2_NAME),
+            PROTOCOL_NAME as u32,
+        )
+        .u32_le_ro_at(
+            at(offset::SUPPORTED_PROTOCOLS_USB2_CONFIG),
+            supported_protocols_usb2::CONFIG as u32,
+        );
+    builder.into()
+}
+
+/// The size of the identification capability without the ID and next
+/// pointer, which [`ConfigSpaceBuilder::capability`] adds.
+const IDENTIFICATION_BODY_SIZE: usize = identification::SIZE - 2;
+
+/// Build the identification capability with the given label.
+///
+/// The device fields stay zero until a device is attached.
+fn identification_capability(label: &str) -> RegisterSet<IDENTIFICATION_BODY_SIZE> {
+    // Field offsets count from the capability ID.
+    let at = |offset: u64| usize::try_from(offset).unwrap() - 2;
+
+    let mut builder = RegisterSetBuilder::<IDENTIFICATION_BODY_SIZE>::new();
+    for pos in 0..IDENTIFICATION_BODY_SIZE {
+        builder.u8_ro_at(pos, 0);
+    }
+    builder
+        .u8_ro_at(at(identification::LENGTH), identification::SIZE as u8)
+        .u8_ro_at(at(identification::VERSION), identification::CURRENT_VERSION);
+    for (i, &byte) in identification_string(label).iter().enumerate() {
+        builder.u8_ro_at(at(identification::LABEL) + i, byte);
+    }
+    builder.into()
+}
+
+/// The PCI identity of the controller unless configured otherwise.
+///
+/// Guests bind their generic XHCI driver to QEMU's XHCI controller by
+/// class code, so we borrow its IDs.
+pub const DEFAULT_PCI_IDENTITY: PciIdentity = PciIdentity::new(
+    crate::device::pci::constants::config_space::vendor::REDHAT,
+    crate::device::pci::constants::config_space::device::REDHAT_XHCI,
+);
+
+/// The settings of an [`XhciController`].
+///
+/// The defaults match the defaults of the command line.
+#[derive(Debug, Clone)]
+pub struct ControllerConfig {
+    /// Limits the number of bulk transfers that can be in flight at the
+    /// same time on each physical host bus. `None` disables the limit.
+    #[cfg(feature = "nusb-backend")]
+    pub max_outstanding_bulk: Option<NonZeroUsize>,
+    /// The time endpoint workers may hold back Transfer Events to report
+    /// them with a single interrupt. `None` disables coalescing.
+    pub event_coalescing: Option<Duration>,
+    /// Bounds the number of events waiting for the driver to make space on
+    /// a full Event Ring.
+    pub max_deferred_events: NonZeroUsize,
+    /// We warn when the driver does not process its events for this long.
+    pub stuck_erdp_timeout: Duration,
+    /// The IDs in the PCI Configuration Space.
+    pub identity: PciIdentity,
+    /// Tells instances apart and is reported in a vendor-specific
+    /// capability, see
+    /// [`identification`](crate::device::pci::constants::config_space::identification).
+    pub label: String,
+    /// Transfer ring segments have to end with a Link TRB within a page,
+    /// unless this allows segments of up to 64 KiB.
+    pub multi_page_transfer_rings: bool,
+    /// Bounds the TRBs an endpoint processes per doorbell ring. `None`
+    /// disables the limit.
+    pub max_trbs_per_doorbell: Option<NonZeroUsize>,
+    /// Decides whether commands we only carry out in part complete
+    /// successfully.
+    pub command_policy: CommandPolicy,
+    /// Forward the guest's CLEAR_FEATURE(ENDPOINT_HALT) requests to the
+    /// device after the host cleared the halt, see
+    /// [`clear_halt_on_request`](XhciController::clear_halt_on_request).
+    pub forward_clear_halt: bool,
+    /// Records the guest's register accesses and all DMA, see [`trace`].
+    pub trace_recorder: Option<Arc<TraceRecorder>>,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "nusb-backend")]
+            max_outstanding_bulk: None,
+            event_coalescing: None,
+            max_deferred_events: DEFAULT_MAX_DEFERRED_EVENTS,
+            stuck_erdp_timeout: DEFAULT_STUCK_ERDP_TIMEOUT,
+            identity: DEFAULT_PCI_IDENTITY,
+            label: String::new(),
+            multi_page_transfer_rings: false,
+            max_trbs_per_doorbell: None,
+            command_policy: CommandPolicy::default(),
+            forward_clear_halt: false,
+            trace_recorder: None,
+        }
+    }
+}
+
+/// Replace the low half of a 64-bit register with a write to its low dword.
+///
+/// Drivers without 64-bit MMIO write 64-bit registers as two dwords, in
+/// either order, so a 4-byte write keeps the upper half. An 8-byte write
+/// sets the whole register.
+const fn with_low_dword(current: u64, req: Request, value: u64) -> u64 {
+    match req.size {
+        RequestSize::Size8 => value,
+        _ => current & !0xffff_ffff | value & 0xffff_ffff,
+    }
+}
+
+/// Replace the upper half of a 64-bit register with a write to its high
+/// dword.
+const fn with_high_dword(current: u64, value: u64) -> u64 {
+    current & 0xffff_ffff | value << 32
+}
+
+/// Merge a write of `size` bytes at byte `offset` into a 32-bit register.
+///
+/// The bytes the write does not cover come from `unchanged`, the value that
+/// writes the register back as it is. The result is what a driver would
+/// write with a read-modify-write of the whole dword.
+const fn merge_register_bytes(unchanged: u64, offset: u64, size: RequestSize, value: u64) -> u64 {
+    let mask = byte_mask(size) << (offset * 8);
+    (unchanged & !mask | (value << (offset * 8)) & mask) & 0xffff_ffff
+}
+
+const fn byte_mask(size: RequestSize) -> u64 {
+    match size {
+        RequestSize::Size1 => 0xff,
+        RequestSize::Size2 => 0xffff,
+        RequestSize::Size4 | RequestSize::Size8 => 0xffff_ffff,
+    }
+}
+
+/// How long a Stop Endpoint Command waits for the endpoint's worker.
+///
+/// The worker only has to cancel its in-flight transfer, which takes a few
+/// milliseconds.
+const STOP_ENDPOINT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The size of the MSI-X table in bytes.
+const MSIX_TABLE_SIZE: usize = MAX_INTRS as usize * MSIX_ENTRY_SIZE;
+
+/// The size of the MSI-X Pending Bit Array in bytes, in whole qwords.
+const MSIX_PBA_SIZE: u64 = MAX_INTRS.div_ceil(64) * 8;
+
+/// A range of registers or MSI-X structures in a BAR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BarRegion {
+    name: &'static str,
+    bar_no: u8,
+    range: Range<u64>,
+}
+
+/// Why the registers and MSI-X structures do not fit the BARs.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+enum BarLayoutError {
+    #[error("{0} should be in BAR {1}, which does not exist")]
+    MissingBar(&'static str, u8),
+    #[error("{name} at {range:#x?} exceed BAR {bar_no} of {size:#x} bytes")]
+    OutsideBar {
+        name: &'static str,
+        bar_no: u8,
+        range: Range<u64>,
+        size: u32,
+    },
+    #[error("{0} and {1} overlap")]
+    Overlap(&'static str, &'static str),
+}
+
+/// The registers and MSI-X structures in the controller's BARs.
+fn bar_regions() -> [BarRegion; 6] {
+    let region = |name, bar_no, range| BarRegion {
+        name,
+        bar_no,
+        range,
+    };
+    let table_offset = u64::from(msix_bar::TABLE_OFFSET);
+    let pba_offset = u64::from(msix_bar::PBA_OFFSET);
+    [
+        region("capability registers", 0, 0..OP_BASE),
+        region(
+            "operational registers",
+            0,
+            OP_BASE..offset::PORTSC + MAX_PORTS * offset::PORT_STRIDE,
+        ),
+        region(
+            "doorbell registers",
+            0,
+            offset::DOORBELL_CONTROLLER..offset::DOORBELL_DEVICE_END,
+        ),
+        region(
+            "runtime registers",
+            0,
+            RUN_BASE..offset::IR0 + MAX_INTRS * 0x20,
+        ),
+        region(
+            "MSI-X table",
+            msix_bar::NUMBER,
+            table_offset..table_offset + MSIX_TABLE_SIZE as u64,
+        ),
+        region(
+            "MSI-X PBA",
+            msix_bar::NUMBER,
+            pba_offset..pba_offset + MSIX_PBA_SIZE,
+        ),
+    ]
+}
+
+/// Check that each of the `regions` lies within its BAR in `layout` and
+/// that no two regions in the same BAR overlap.
+fn check_bar_regions(
+    layout: &[(u8, BarInfo)],
+    regions: &[BarRegion],
+) -> Result<(), BarLayoutError> {
+    for (index, region) in regions.iter().enumerate() {
+        let (_, bar) = layout
+            .iter()
+            .find(|(bar_no, _)| *bar_no == region.bar_no)
+            .ok_or(BarLayoutError::MissingBar(region.name, region.bar_no))?;
+        if !(0..u64::from(bar.size)).contains_interval(&region.range) {
+            return Err(BarLayoutError::OutsideBar {
+                name: region.name,
+                bar_no: region.bar_no,
+                range: region.range.clone(),
+                size: bar.size,
+            });
+        }
+        if let Some(other) = regions[index + 1..]
+            .iter()
+            .find(|other| other.bar_no == region.bar_no && other.range.overlaps(&region.range))
+        {
+            return Err(BarLayoutError::Overlap(region.name, other.name));
+        }
+    }
+    Ok(())
+}
+
+/// Why [`XhciController::set_device`] could not attach a device.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachError {
+    #[error("unable to determine the device speed")]
+    UnknownSpeed,
+    #[error("no free port for a {0} device")]
+    NoFreePort(Speed),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum UsbVersion {
     USB2,
@@ -68,64 +399,220 @@ pub struct XhciController {
     /// The PCI Configuration Space of the controller.
     config_space: ConfigSpace,
 
-    /// The current Run/Stop status of the controller.
-    running: bool,
+    /// The capability registers and the extended capabilities, which
+    /// precede the operational registers.
+    capability_registers: RegisterSet<{ OP_BASE as usize }>,
+
+    /// Whether the controller runs, halts, or resets, with the Run/Stop
+    /// state that endpoint workers check before they fetch TDs.
+    lifecycle: ControllerLifecycle,
+
+    /// Whether the controller hit an internal error that only a reset
+    /// recovers from (USBSTS.HCE).
+    host_controller_error: bool,
+
+    /// The time base of the Microframe Index register (MFINDEX).
+    microframe_clock: MicroframeClock,
+
+    /// Whether a Port Status Change Event is outstanding for each port.
+    port_status_change_pending: [bool; MAX_PORTS as usize],
+
+    /// Whether a port changed since the driver last cleared USBSTS.PCD.
+    ///
+    /// The bit is latched like the change bits of PORTSC, so it stays set
+    /// after the driver acknowledged the changes of the ports.
+    port_change_detect: bool,
 
     /// The Command Ring.
     command_ring: CommandRing,
 
-    /// The Event Ring of the single Interrupt Register Set.
-    event_ring: Arc<Mutex<EventRing>>,
+    /// Posts events to the Event Ring of the single Interrupt Register
+    /// Set.
+    event_sink: Arc<EventSink>,
 
-    /// Device Slot Management
-    device_slot_manager: DeviceSlotManager,
+    /// The MSI-X table in BAR 3.
+    msix_table: MsixTable<MSIX_TABLE_SIZE>,
 
-    /// Interrupt management register
-    interrupt_management: u64,
+    /// The mask state and pending interrupts of the MSI-X vectors.
+    pending_bits: Arc<PendingBitArray>,
 
-    /// The minimum interval in 250ns increments between interrupts.
-    interrupt_moderation_interval: u64,
+    /// The interrupt lines of the MSI-X vectors, which do not know about
+    /// masking. Used to send pending interrupts on unmask.
+    msix_lines: Vec<Arc<dyn InterruptLine>>,
 
-    /// The interrupt line triggered to signal device events.
-    interrupt_line: Arc<dyn InterruptLine>,
+    /// Device Slot Management
+    device_slot_manager: DeviceSlotManager,
 
     /// PORTSC registers array
     portsc: [PortscRegister; MAX_PORTS as usize],
+
+    /// PORTPMSC registers array
+    portpmsc: [PortpmscRegister; MAX_PORTS as usize],
+
+    /// Arbitrates bulk transfers of devices sharing a host bus.
+    #[cfg(feature = "nusb-backend")]
+    host_bus_scheduler: HostBusScheduler,
+
+    /// The window in which endpoint workers coalesce Transfer Events.
+    event_coalescing: Option<Duration>,
+
+    /// Per-register access counts and latencies, if enabled.
+    mmio_profile: Arc<MmioProfile>,
+
+    /// Records the guest's register and memory accesses, if enabled.
+    trace_recorder: Option<Arc<TraceRecorder>>,
+
+    /// The transfer statistics of every endpoint, by slot and endpoint ID.
+    endpoint_stats: Arc<EndpointStatsTable>,
+
+    /// The boundary before which transfer ring segments have to end with a
+    /// Link TRB.
+    transfer_ring_segment_boundary: u64,
+
+    /// The maximum number of TRBs an endpoint worker processes per doorbell
+    /// ring.
+    max_trbs_per_doorbell: Option<NonZeroUsize>,
+
+    /// Which partially implemented commands fail.
+    command_policy: CommandPolicy,
+
+    /// Whether CLEAR_FEATURE(ENDPOINT_HALT) requests reach the device
+    /// besides clearing the halt on the host.
+    forward_clear_halt: bool,
+
+    /// The error and request interrupts of the VMM.
+    vmm_signals: Arc<VmmSignals>,
+
+    /// The doorbells the driver rang with invalid values.
+    ignored_doorbells: IgnoredDoorbells,
 }
 
 impl XhciController {
-    /// Create a new XHCI controller with default settings.
+    /// Create a new XHCI controller with the given settings.
     ///
     /// `dma_bus` is the device on which we will perform DMA
     /// operations. This is typically VM guest memory.
     #[must_use]
-    pub fn new(dma_bus: BusDeviceRef) -> Self {
+    pub fn new(dma_bus: BusDeviceRef, config: ControllerConfig) -> Self {
         use crate::device::pci::constants::config_space::*;
 
-        let dma_bus_for_command_ring = dma_bus.clone();
-        let dma_bus_for_event_ring = dma_bus.clone();
-        let dma_bus_for_device_slot_manager = dma_bus.clone();
+        let ControllerConfig {
+            #[cfg(feature = "nusb-backend")]
+            max_outstanding_bulk,
+            event_coalescing,
+            max_deferred_events,
+            stuck_erdp_timeout,
+            identity,
+            label,
+            multi_page_transfer_rings,
+            max_trbs_per_doorbell,
+            command_policy,
+            forward_clear_halt,
+            trace_recorder,
+        } = config;
+        let dma_bus = trace::tag(&dma_bus, trace_recorder.as_ref());
+        let dma_bus_for_command_ring = paranoid_dma::tag(&dma_bus, DmaOrigin::CommandRing);
+        let dma_bus_for_event_sink = paranoid_dma::tag(&dma_bus, DmaOrigin::EventRing);
+        let dma_bus_for_device_slot_manager = paranoid_dma::tag(&dma_bus, DmaOrigin::DeviceContext);
 
-        Self {
+        let config_space = ConfigSpaceBuilder::new(identity.vendor_id, identity.device_id)
+            .subsystem(identity.subsystem_vendor_id, identity.subsystem_id)
+            .revision(identity.revision)
+            .class(class::SERIAL, subclass::SERIAL_USB, progif::USB_XHCI)
+            // TODO Should be a 64-bit BAR.
+            .mem32_nonprefetchable_bar(0, 4 * 0x1000)
+            .mem32_nonprefetchable_bar(3, 2 * 0x1000)
+            .msix_capability(
+                MAX_INTRS.try_into().unwrap(),
+                msix_bar::NUMBER,
+                msix_bar::TABLE_OFFSET,
+                msix_bar::NUMBER,
+                msix_bar::PBA_OFFSET,
+            )
+            .capability(
+                capability_id::VENDOR_SPECIFIC,
+                &identification_capability(&label),
+            )
+            .config_space();
+        if let Err(error) = check_bar_regions(&config_space.bar_layout(), &bar_regions()) {
+            panic!("the controller's BARs should hold its registers: {error}");
+        }
+
+        let mut controller = Self {
             devices: [const { None }; MAX_PORTS as usize],
             slot_to_port: [None; MAX_SLOTS as usize],
             dma_bus,
-            config_space: ConfigSpaceBuilder::new(vendor::REDHAT, device::REDHAT_XHCI)
-                .class(class::SERIAL, subclass::SERIAL_USB, progif::USB_XHCI)
-                // TODO Should be a 64-bit BAR.
-                .mem32_nonprefetchable_bar(0, 4 * 0x1000)
-                .mem32_nonprefetchable_bar(3, 2 * 0x1000)
-                .msix_capability(MAX_INTRS.try_into().unwrap(), 3, 0, 3, 0x1000)
-                .config_space(),
-            running: false,
+            config_space,
+            capability_registers: capability_registers(),
+            lifecycle: ControllerLifecycle::default(),
+            host_controller_error: false,
+            microframe_clock: MicroframeClock::default(),
             command_ring: CommandRing::new(dma_bus_for_command_ring),
-            event_ring: Arc::new(Mutex::new(EventRing::new(dma_bus_for_event_ring))),
+            event_sink: Arc::new(EventSink::new(
+                dma_bus_for_event_sink,
+                max_deferred_events,
+                stuck_erdp_timeout,
+            )),
+            msix_table: MsixTable::new(),
+            pending_bits: Arc::new(PendingBitArray::new(MAX_INTRS.try_into().unwrap())),
+            msix_lines: (0..MAX_INTRS)
+                .map(|_| Arc::new(DummyInterruptLine::default()) as Arc<dyn InterruptLine>)
+                .collect(),
             device_slot_manager: DeviceSlotManager::new(MAX_SLOTS, dma_bus_for_device_slot_manager),
-            interrupt_management: 0,
-            interrupt_moderation_interval: runtime::IMOD_DEFAULT,
-            interrupt_line: Arc::new(DummyInterruptLine::default()),
             portsc: [PortscRegister::new(portsc::PP); MAX_PORTS as usize],
-        }
+            port_status_change_pending: [false; MAX_PORTS as usize],
+            port_change_detect: false,
+            portpmsc: std::array::from_fn(|index| match Self::port_index_to_id(index) {
+                Some((UsbVersion::USB3, _)) => PortpmscRegister::usb3(),
+                _ => PortpmscRegister::usb2(),
+            }),
+            #[cfg(feature = "nusb-backend")]
+            host_bus_scheduler: HostBusScheduler::new(max_outstanding_bulk),
+            event_coalescing,
+            mmio_profile: Arc::new(MmioProfile::new()),
+            trace_recorder,
+            endpoint_stats: Arc::new(EndpointStatsTable::default()),
+            transfer_ring_segment_boundary: if multi_page_transfer_rings {
+                MAX_SEGMENT_BOUNDARY
+            } else {
+                PAGE_SEGMENT_BOUNDARY
+            },
+            max_trbs_per_doorbell,
+            command_policy,
+            forward_clear_halt,
+            vmm_signals: Arc::new(VmmSignals::default()),
+            ignored_doorbells: IgnoredDoorbells::default(),
+        };
+        controller.lifecycle.initialized();
+        controller
+    }
+
+    /// The profile of register accesses. It is disabled until
+    /// [`MmioProfile::enable`] is called.
+    pub fn mmio_profile(&self) -> Arc<MmioProfile> {
+        self.mmio_profile.clone()
+    }
+
+    /// The transfer statistics of all endpoints that were configured so
+    /// far.
+    pub fn endpoint_stats(&self) -> Arc<EndpointStatsTable> {
+        self.endpoint_stats.clone()
+    }
+
+    /// The error and request interrupts of the VMM, which endpoint
+    /// workers and the backend use as well.
+    pub fn vmm_signals(&self) -> Arc<VmmSignals> {
+        self.vmm_signals.clone()
+    }
+
+    /// The number of doorbells the driver rang with invalid values.
+    pub const fn ignored_doorbells(&self) -> u64 {
+        self.ignored_doorbells.count()
+    }
+
+    /// The state of the Event Ring and of the driver processing it.
+    pub fn event_ring_status(&self) -> EventRingStatus {
+        self.event_sink.event_ring_status()
     }
 
     fn device_by_slot(&self, slot_id: u8) -> Option<&dyn RealDevice> {
@@ -168,46 +655,123 @@ impl XhciController {
     /// for the guest driver to interact with. The port's status is updated to reflect
     /// the device's connection and speed.
     ///
+    /// SuperSpeed devices take a USB 2 port once all USB 3 ports are taken.
+    /// They show up as High Speed devices there, see
+    /// [`usb2_descriptor_type`](Self::usb2_descriptor_type).
+    ///
     /// # Parameters
     ///
     /// * `device` - The real USB device to attach
     ///
-    /// # Panics
-    ///
-    /// Currently panics if no USB port is available for the device.
-    // TODO: Replace the panic (expect) with logic that does nothing if there is no space
-    // and indicates with the return value that the attachment failed. There is no good reason
-    // for us to crash here, we can continue running as before, it is up to the caller to
-    // decide how to handle the failed attachment attempt.
-    pub fn set_device(&mut self, device: Box<dyn RealDevice>) {
-        if let Some(speed) = device.speed() {
-            let version = UsbVersion::from_speed(speed);
-            let available_port_index = (0..MAX_PORTS as usize)
-                .find(|&i| {
-                    self.devices[i].is_none()
-                        && matches!(Self::port_index_to_id(i), Some((v, _)) if v == version)
-                }) // filter USB2/3
-                .unwrap(); // crash if there is no free suitable port
-
-            self.devices[available_port_index] = Some(device);
-            self.portsc[available_port_index] = PortscRegister::new(
-                portsc::CCS
-                    | portsc::PED
-                    | portsc::PP
-                    | portsc::CSC
-                    | portsc::PEC
-                    | portsc::PRC
-                    | (speed as u64) << 10,
+    /// # Errors
+    ///
+    /// Fails if the speed of the device is unknown or all ports it can use
+    /// are taken. The device is dropped in that case, which hands
+    /// it back to the host.
+    pub fn set_device(&mut self, device: Box<dyn RealDevice>) -> Result<(), AttachError> {
+        let speed = device.speed().ok_or(AttachError::UnknownSpeed)?;
+        let identity = device.identity().clone();
+        let version = UsbVersion::from_speed(speed);
+        let descriptor = device.device_descriptor();
+        debug!(
+            "{} reports USB {:x}.{:02x} with {} configuration(s)",
+            identity,
+            descriptor.usb_version >> 8,
+            descriptor.usb_version & 0xff,
+            descriptor.num_configurations
+        );
+        if !speed.is_usb2_speed() && descriptor.usb_version < 0x0300 {
+            warn!(
+                "{} runs at {} but reports USB {:#06x} in its device descriptor",
+                identity, speed, descriptor.usb_version
             );
+        }
+        let free_port = |version| {
+            (0..MAX_PORTS as usize).find(|&i| {
+                self.devices[i].is_none()
+                    && matches!(Self::port_index_to_id(i), Some((v, _)) if v == version)
+            })
+        };
+        let available_port_index = free_port(version)
+            .or_else(|| match version {
+                UsbVersion::USB3 => free_port(UsbVersion::USB2),
+                UsbVersion::USB2 => None,
+            })
+            .ok_or(AttachError::NoFreePort(speed))?;
+
+        self.devices[available_port_index] = Some(device);
+        if self.devices.iter().flatten().count() == 1 {
+            self.report_identification(&identity.identification);
+        }
 
-            // Safety: the call for the same index succeeded before in the filter.
-            let port_id = Self::port_index_to_id(available_port_index).unwrap().1;
-            info!(
-                "Attached {} device to {:?} port {}",
-                speed, version, port_id
+        // Safety: the call for the same index succeeded before in the filter.
+        let (port_version, port_id) = Self::port_index_to_id(available_port_index).unwrap();
+        identity.set_port(Some(available_port_index as u8 + 1));
+        info!(
+            "Attached {} device {} to {:?} port {}",
+            speed, identity, port_version, port_id
+        );
+        if port_version != version {
+            warn!(
+                "all USB 3 ports are taken, {} shows up as High Speed device",
+                identity
             );
+        }
+
+        if self.is_port_powered(available_port_index) {
+            // Safety: the port has a device with a known speed.
+            let port_speed = self.port_speed(available_port_index).unwrap();
+            self.announce_connection(available_port_index, port_speed);
         } else {
-            warn!("Failed to attach device: Unable to determine speed");
+            debug!("port is powered off, the device shows up when the driver powers it on");
+        }
+        Ok(())
+    }
+
+    /// Fill the device fields of the identification capability.
+    ///
+    /// The capability has room for one device, so it reports the first
+    /// device that was attached.
+    fn report_identification(&mut self, device: &DeviceIdentification) {
+        use crate::device::pci::constants::config_space::capability_id;
+
+        let Some(cap) = self
+            .config_space
+            .iter_capability_offsets()
+            .map(u64::from)
+            .find(|&cap| {
+                self.config_space
+                    .read(Request::new(cap, RequestSize::Size1))
+                    == u64::from(capability_id::VENDOR_SPECIFIC)
+            })
+        else {
+            return;
+        };
+
+        self.config_space.write_direct(
+            Request::new(cap + identification::VENDOR_ID, RequestSize::Size2),
+            device.vendor_id.into(),
+        );
+        self.config_space.write_direct(
+            Request::new(cap + identification::PRODUCT_ID, RequestSize::Size2),
+            device.product_id.into(),
+        );
+        let serial = identification_string(device.serial.as_deref().unwrap_or_default());
+        for (offset, &byte) in (cap + identification::SERIAL..).zip(&serial) {
+            self.config_space
+                .write_direct(Request::new(offset, RequestSize::Size1), byte.into());
+        }
+    }
+
+    /// The speed of the device on a port, as the guest sees it.
+    ///
+    /// SuperSpeed devices on USB 2 ports run at High Speed as far as the
+    /// guest can tell.
+    fn port_speed(&self, port_index: usize) -> Option<Speed> {
+        let speed = self.devices.get(port_index)?.as_ref()?.speed()?;
+        match Self::port_index_to_id(port_index)? {
+            (UsbVersion::USB2, _) if !speed.is_usb2_speed() => Some(Speed::High),
+            _ => Some(speed),
         }
     }
 
@@ -229,8 +793,9 @@ impl XhciController {
         register_offset: u64,
     ) -> Option<usize> {
         if addr >= base_addr && addr < base_addr + (port_count * offset::PORT_STRIDE) {
-            // Check if this is the correct register within the port's PORT_STRIDE byte range
-            if (addr - base_addr) % offset::PORT_STRIDE == register_offset {
+            // Check if this is the correct register within the port's PORT_STRIDE byte range.
+            // Drivers may access any byte of the 32-bit register.
+            if ((addr - base_addr) % offset::PORT_STRIDE) & !0x3 == register_offset {
                 Some(((addr - base_addr) / offset::PORT_STRIDE) as usize)
             } else {
                 None
@@ -244,28 +809,297 @@ impl XhciController {
         Self::get_port_index_from_addr(addr, offset::PORTSC, MAX_PORTS, 0)
     }
 
+    const fn get_portpmsc_index(&self, addr: u64) -> Option<usize> {
+        Self::get_port_index_from_addr(addr, offset::PORTSC, MAX_PORTS, 0x4)
+    }
+
     const fn get_portli_index(&self, addr: u64) -> Option<usize> {
         Self::get_port_index_from_addr(addr, offset::PORTSC, MAX_PORTS, 0x8)
     }
 
-    fn write_portsc(&mut self, port_index: usize, value: u64) {
-        self.portsc[port_index].write(value);
-        let status = Self::describe_portsc_status(value);
+    fn write_portsc(&mut self, port_index: usize, value: u32) {
+        match (self.is_port_powered(port_index), value & portsc::PP != 0) {
+            (true, false) => self.power_off_port(port_index),
+            (false, true) => self.power_on_port(port_index),
+            _ => {
+                self.portsc[port_index].write(value);
+                if value & portsc::PR != 0 {
+                    self.reset_port(port_index);
+                }
+            }
+        }
+        // The driver has seen all changes of the port, so the next change
+        // needs another event.
+        if self.portsc[port_index].read() & portsc::CHANGE_BITS == 0 {
+            self.port_status_change_pending[port_index] = false;
+        }
+        let status = Self::describe_portsc_status(self.portsc[port_index].read());
         let (version, id) = Self::port_index_to_id(port_index).unwrap();
         trace!("{:?} port {} status: {}", version, id, status);
     }
 
+    const fn is_port_powered(&self, port_index: usize) -> bool {
+        self.portsc[port_index].read() & portsc::PP != 0
+    }
+
+    /// Show a device on a powered port and tell the driver about it.
+    ///
+    /// A USB3 port enables itself once the link is up, so it shows the
+    /// device enabled and in U0. A USB2 port stays disabled until the
+    /// driver resets it, see [`Self::reset_port`].
+    fn announce_connection(&mut self, port_index: usize, speed: Speed) {
+        let enabled = match Self::port_index_to_id(port_index) {
+            Some((UsbVersion::USB3, _)) => {
+                portsc::PED | portsc::link_state::U0 << portsc::PLS_SHIFT
+            }
+            _ => 0,
+        };
+        self.portsc[port_index] = PortscRegister::new(
+            portsc::CCS
+                | enabled
+                | portsc::PP
+                | portsc::CSC
+                | (u32::from(speed.raw()) << portsc::PORT_SPEED_SHIFT) & portsc::PORT_SPEED,
+        );
+        // The change bits are set, even if a halted controller reports
+        // them later.
+        self.port_change_detect = true;
+
+        // A running controller has to tell the driver about the new
+        // connection. Otherwise, the driver sees the port when it first
+        // inspects the PORTSC registers.
+        if self.lifecycle.is_running() {
+            self.post_port_status_change(port_index);
+        }
+    }
+
+    /// Handle the driver setting PORTSC.PR.
+    ///
+    /// The reset completes right away: the port of a connected device is
+    /// enabled and reports the completion with PRC. On a USB2 port, this
+    /// is how the driver enables the port. On a USB3 port, it is a Hot
+    /// Reset of an enabled port, which stays in U0. The device keeps its
+    /// state, the driver resets it with the Address Device Command.
+    fn reset_port(&mut self, port_index: usize) {
+        if self.portsc[port_index].read() & portsc::CCS == 0 {
+            debug!("ignoring reset of empty port {}", port_index + 1);
+            return;
+        }
+        debug!("port {} reset", port_index + 1);
+        self.portsc[port_index].complete_reset();
+        self.post_port_status_change(port_index);
+    }
+
+    /// Tell the driver to look at the changes of a port.
+    ///
+    /// Only one Port Status Change Event per port is outstanding at a
+    /// time. Changes that happen before the driver cleared all change bits
+    /// of the port are covered by the pending event.
+    fn post_port_status_change(&mut self, port_index: usize) {
+        self.port_change_detect = true;
+        if std::mem::replace(&mut self.port_status_change_pending[port_index], true) {
+            trace!(
+                "port {} already has a Port Status Change Event pending",
+                port_index + 1
+            );
+            return;
+        }
+        self.event_sink
+            .post(EventTrb::new_port_status_change_event_trb(
+                port_index as u8 + 1,
+            ));
+    }
+
+    /// Handle the driver clearing PORTSC.PP.
+    ///
+    /// An unpowered port shows no device and reports no changes, so the
+    /// driver treats the device as disconnected. The endpoint workers of
+    /// the device exit, and doorbells for its slot are ignored. The device
+    /// stays attached on the host side.
+    fn power_off_port(&mut self, port_index: usize) {
+        self.portsc[port_index] = PortscRegister::new(0);
+        debug!("port {} powered off", port_index + 1);
+
+        let slot_ids = (1..=MAX_SLOTS as u8)
+            .filter(|&slot_id| self.slot_to_port[slot_id as usize - 1] == Some(port_index))
+            .collect::<Vec<_>>();
+        for slot_id in slot_ids {
+            self.disable_endpoint_workers(slot_id);
+        }
+    }
+
+    /// Shut down the workers of all enabled endpoints of an addressed slot.
+    fn disable_endpoint_workers(&mut self, slot_id: u8) {
+        let device_context = self.device_slot_manager.get_device_context(slot_id);
+        let Some(device) = Self::device_by_slot_mut(&self.slot_to_port, &mut self.devices, slot_id)
+        else {
+            return;
+        };
+        // The control endpoint has no worker.
+        let stuck = device_context
+            .enabled_endpoints()
+            .into_iter()
+            .filter(|&endpoint_id| endpoint_id != Dci::CONTROL)
+            .filter(|&endpoint_id| !device.disable_endpoint(endpoint_id, STOP_ENDPOINT_TIMEOUT))
+            .collect::<Vec<_>>();
+        if !stuck.is_empty() {
+            self.stuck_workers_error(slot_id, &stuck);
+        }
+    }
+
+    /// Enter the Host Controller Error state because the workers of the
+    /// given endpoints did not exit.
+    ///
+    /// They may still access the guest memory of a slot that the driver
+    /// considers gone.
+    fn stuck_workers_error(&mut self, slot_id: u8, endpoint_ids: &[Dci]) {
+        self.host_controller_error(&format!(
+            "workers of endpoints {:?} of slot {} did not exit within {:?}",
+            endpoint_ids, slot_id, STOP_ENDPOINT_TIMEOUT
+        ));
+    }
+
+    /// Release all slots, as if the driver disabled each of them.
+    ///
+    /// The endpoint workers exit first, as they still use the device
+    /// contexts of the slots.
+    fn release_all_slots(&mut self) {
+        self.disable_all_endpoint_workers();
+        self.forget_all_slots();
+    }
+
+    /// Shut down the endpoint workers of all addressed slots.
+    fn disable_all_endpoint_workers(&mut self) {
+        for slot_id in 1..=MAX_SLOTS as u8 {
+            if self.slot_to_port[slot_id as usize - 1].is_some() {
+                self.disable_endpoint_workers(slot_id);
+            }
+        }
+    }
+
+    /// Release all slots, whose endpoint workers have to be gone already.
+    fn forget_all_slots(&mut self) {
+        self.slot_to_port = [None; MAX_SLOTS as usize];
+        for device in self.devices.iter().flatten() {
+            device.identity().set_slot(None);
+        }
+        self.device_slot_manager.release_all_slots();
+    }
+
+    /// Handle the driver setting PORTSC.PP.
+    ///
+    /// A device that is still attached connects again, and the driver
+    /// enumerates it from scratch.
+    fn power_on_port(&mut self, port_index: usize) {
+        debug!("port {} powered on", port_index + 1);
+        match self.port_speed(port_index) {
+            Some(speed) => self.announce_connection(port_index, speed),
+            None => self.portsc[port_index] = PortscRegister::new(portsc::PP),
+        }
+    }
+
     /// Configure the interrupt line for the controller.
     ///
     /// The [`XhciController`] uses this to issue interrupts for events.
+    /// Interrupts are held back while their MSI-X vector is masked.
     pub fn connect_irq(&mut self, irq: Arc<dyn InterruptLine>) {
-        self.interrupt_line = irq.clone();
+        // The controller has a single interrupter, which uses vector 0.
+        self.msix_lines[0] = irq.clone();
+        self.event_sink
+            .connect_irq(Arc::new(MaskableInterruptLine::new(
+                0,
+                self.pending_bits.clone(),
+                irq,
+            )));
+    }
+
+    /// Handle reads of the MSI-X table and the PBA in BAR 3.
+    fn read_msix_bar(&mut self, req: Request) -> u64 {
+        let pba_offset = u64::from(msix_bar::PBA_OFFSET);
+        if req.addr >= pba_offset {
+            self.pending_bits
+                .read(Request::new(req.addr - pba_offset, req.size))
+        } else if req.addr < MSIX_TABLE_SIZE as u64 {
+            self.msix_table.read(req)
+        } else {
+            0
+        }
+    }
+
+    /// Handle writes of the MSI-X table in BAR 3.
+    ///
+    /// Unmasking a vector sends its pending interrupt. The PBA is
+    /// read-only.
+    fn write_msix_bar(&mut self, req: Request, value: u64) {
+        if req.addr >= MSIX_TABLE_SIZE as u64 {
+            debug!("ignoring MSI-X BAR write at {:#x}", req.addr);
+            return;
+        }
+
+        self.msix_table.write(req, value);
+        let vector = (req.addr / MSIX_ENTRY_SIZE as u64) as u16;
+        let masked = self.msix_table.vector(vector).is_none();
+        if self.pending_bits.set_vector_masked(vector, masked) {
+            debug!("sending pending interrupt of unmasked MSI-X vector {vector}");
+            self.msix_lines[usize::from(vector)].interrupt();
+        }
+    }
+
+    /// Apply the MSI-X Function Mask after configuration space writes.
+    fn update_msix_function_mask(&self) {
+        let masked = self.config_space.msix_function_masked();
+        for vector in self.pending_bits.set_function_masked(masked) {
+            debug!("sending pending interrupt of MSI-X vector {vector} after function unmask");
+            self.msix_lines[usize::from(vector)].interrupt();
+        }
     }
 
     /// Obtain the current host controller status as defined for the `USBSTS` register.
     #[must_use]
     pub fn status(&self) -> u64 {
-        !u64::from(self.running) & usbsts::HCH | usbsts::EINT | usbsts::PCD
+        let hch = if self.lifecycle.is_halted() {
+            usbsts::HCH
+        } else {
+            0
+        };
+        let cnr = if self.lifecycle.is_ready() {
+            0
+        } else {
+            usbsts::CNR
+        };
+        let hce = if self.host_controller_error {
+            usbsts::HCE
+        } else {
+            0
+        };
+        let pcd = if self.port_change_detect {
+            usbsts::PCD
+        } else {
+            0
+        };
+        hch | cnr | hce | pcd | self.event_sink.usbsts()
+    }
+
+    /// Handle writes to `USBSTS`, whose writable bits are all RW1C.
+    ///
+    /// The driver clears `EINT` and `PCD` once it handled the interrupt
+    /// and the port changes. We never set `HSE` and `SRE`.
+    fn write_usbsts(&mut self, value: u64) {
+        if value & usbsts::PCD != 0 {
+            self.port_change_detect = false;
+        }
+        self.event_sink.write_usbsts(value);
+    }
+
+    /// Enter the Host Controller Error state after an internal error.
+    ///
+    /// The driver has to reset the controller to recover, so we report the
+    /// error to the VMM as well.
+    fn host_controller_error(&mut self, reason: &str) {
+        if !self.host_controller_error {
+            self.host_controller_error = true;
+            self.vmm_signals.signal_error(reason);
+        }
     }
 
     /// Obtain the current host controller configuration as defined for the `CONFIG` register.
@@ -285,11 +1119,25 @@ impl XhciController {
     }
 
     /// Configure the device context array from the array base pointer.
+    ///
+    /// A driver that moves the array while slots are in use starts over,
+    /// e.g., after it was unbound and bound again. The slots of the old
+    /// array are released, so the driver can enumerate the devices again.
     pub fn configure_device_contexts(&mut self, device_context_base_array_ptr: u64) {
         debug!(
             "configuring device contexts from pointer {:#x}",
             device_context_base_array_ptr
         );
+        if device_context_base_array_ptr != self.device_slot_manager.get_dcbaap()
+            && self.device_slot_manager.has_reserved_slots()
+        {
+            warn!(
+                "DCBAA moved from {:#x} to {:#x} while slots are in use; releasing all slots",
+                self.device_slot_manager.get_dcbaap(),
+                device_context_base_array_ptr
+            );
+            self.release_all_slots();
+        }
         self.device_slot_manager
             .set_dcbaap(device_context_base_array_ptr);
     }
@@ -298,33 +1146,163 @@ impl XhciController {
     ///
     /// This is called for writes of the `USBCMD` register.
     pub fn run(&mut self, usbcmd: u64) {
-        self.running = usbcmd & 0x1 == 0x1;
-        if self.running {
+        if usbcmd & usbcmd::HCRST != 0 {
+            self.reset(ResetKind::Soft);
+        } else if usbcmd & usbcmd::LHCRST != 0 {
+            self.reset(ResetKind::Light);
+        }
+
+        if usbcmd & usbcmd::RS == 0 {
+            self.halt();
+        } else if self.lifecycle.is_running() {
+            trace!("controller kept running with cmd {usbcmd:#x}");
+        } else {
             debug!("controller started with cmd {usbcmd:#x}");
+            self.resume();
+        }
+    }
 
-            // Send a port status change event, which signals the driver to
-            // inspect the PORTSC status register.
-            let trb = EventTrb::new_port_status_change_event_trb(0);
-            self.event_ring.lock().unwrap().enqueue(&trb);
+    /// Stop the controller, like clearing USBCMD.R/S does.
+    ///
+    /// Endpoint workers finish the TD at hand. USBSTS.HCH reports when
+    /// they are done.
+    pub fn halt(&mut self) {
+        let steps = self.lifecycle.halt();
+        if !steps.is_empty() {
+            debug!("controller halted");
+        }
+        self.carry_out(steps);
+    }
 
-            // XXX: This is just a test to see if we can generate interrupts.
-            // This will be removed once we generate interrupts in the right
-            // place, (e.g. generate a Port Connect Status Event) and test it.
-            self.interrupt_line.interrupt();
-            debug!("signalled a bogus interrupt");
-        } else {
-            debug!("controller stopped with cmd {usbcmd:#x}");
+    /// Start the controller, like setting USBCMD.R/S does, and continue
+    /// where it stopped.
+    pub fn resume(&mut self) {
+        let steps = self.lifecycle.resume();
+        self.carry_out(steps);
+    }
+
+    /// Reset the controller. It is halted afterwards.
+    ///
+    /// The driver sets up the Interrupter and the Command Ring again, see
+    /// [`ResetKind`] for what else it has to set up again.
+    pub fn reset(&mut self, kind: ResetKind) {
+        debug!("controller reset: {kind:?}");
+        let steps = self.lifecycle.reset(kind);
+        self.carry_out(steps);
+        self.lifecycle.reset_done();
+    }
+
+    /// Carry out the steps of a lifecycle transition in order.
+    fn carry_out(&mut self, steps: &[Step]) {
+        let mut pending = PendingDoorbells::default();
+        for &step in steps {
+            trace!("lifecycle step: {step:?}");
+            match step {
+                Step::StopWorkers => {
+                    self.lifecycle.run_state().stop();
+                    self.microframe_clock.stop();
+                }
+                Step::ClearHostControllerError => self.host_controller_error = false,
+                Step::DisableWorkers => self.disable_all_endpoint_workers(),
+                Step::ResetEventRing => {
+                    self.event_sink.reset();
+                    // The events went away with the Event Ring.
+                    self.port_status_change_pending = [false; MAX_PORTS as usize];
+                }
+                // The rings of the remembered doorbells are gone.
+                Step::ForgetDoorbells => self.lifecycle.run_state().forget_doorbells(),
+                Step::PowerOnPorts => {
+                    self.reset_port_registers();
+                    // Ports come out of reset powered, like before the
+                    // driver switched any of them off.
+                    for port_index in 0..MAX_PORTS as usize {
+                        if !self.is_port_powered(port_index) {
+                            self.power_on_port(port_index);
+                        }
+                    }
+                }
+                Step::ReconnectPorts => {
+                    self.reset_port_registers();
+                    for port_index in 0..MAX_PORTS as usize {
+                        self.power_on_port(port_index);
+                    }
+                }
+                // The driver addresses all devices again.
+                Step::ReleaseUsbAddresses => self.device_slot_manager.release_all_usb_addresses(),
+                Step::InvalidateDeviceContexts => {
+                    self.device_slot_manager.invalidate_all_device_contexts();
+                }
+                Step::ReleaseSlots => self.forget_all_slots(),
+                Step::StartWorkers => {
+                    pending = self.lifecycle.run_state().start();
+                    self.microframe_clock.start();
+                }
+                Step::ReportPortChanges => {
+                    // Changes that happened while the controller was
+                    // halted, e.g., devices attached at startup, have not
+                    // been reported yet. If the driver did not configure
+                    // the Interrupter yet, the events wait for it in the
+                    // event sink.
+                    for port_index in 0..MAX_PORTS as usize {
+                        if self.portsc[port_index].read() & portsc::CHANGE_BITS != 0 {
+                            self.post_port_status_change(port_index);
+                        }
+                    }
+                }
+                Step::ReplayDoorbells => self.replay_doorbells(&pending),
+            }
+        }
+    }
+
+    /// Reset the port registers that keep their value across power
+    /// cycles, and forget the port changes the driver has not seen.
+    fn reset_port_registers(&mut self) {
+        self.portpmsc.iter_mut().for_each(PortpmscRegister::reset);
+        self.port_change_detect = false;
+    }
+
+    /// Ring the doorbells again that the driver rang while the controller
+    /// was stopped, so processing resumes where it left off.
+    fn replay_doorbells(&mut self, pending: &PendingDoorbells) {
+        if pending.command_ring {
+            debug!("processing the commands queued while stopped");
+            self.process_commands();
+        }
+        for (slot_id, endpoint_id) in pending.endpoint_doorbells() {
+            debug!(
+                "ringing the doorbell of slot {} endpoint {} again",
+                slot_id, endpoint_id
+            );
+            self.doorbell_device(slot_id, u32::from(endpoint_id));
         }
     }
 
-    fn doorbell_controller(&mut self) {
-        debug!("Ding Dong!");
+    /// Handle the commands on the Command Ring until it runs dry.
+    ///
+    /// This is what a ring of the Host Controller Doorbell does, but tests
+    /// can drive the Command Ring without going through MMIO.
+    pub fn process_commands(&mut self) {
         while let Some(cmd) = self.command_ring.next_command_trb() {
-            self.handle_command(cmd);
+            match cmd {
+                Ok(cmd) => self.handle_command(cmd),
+                Err(error @ CommandRingError::LinkTrbChainTooLong { address }) => {
+                    // Following the ring again would only run into the same
+                    // chain, so we stop until the next doorbell.
+                    warn!("stopping command ring: {}", error);
+                    self.event_sink
+                        .post(EventTrb::new_command_completion_event_trb(
+                            address,
+                            0,
+                            CompletionCode::TrbError,
+                            0,
+                        ));
+                    break;
+                }
+            }
         }
     }
 
-    const fn describe_portsc_status(value: u64) -> &'static str {
+    const fn describe_portsc_status(value: u32) -> &'static str {
         if value & portsc::CCS != 0 {
             "device connected"
         } else if value & portsc::PP != 0 {
@@ -335,170 +1313,449 @@ impl XhciController {
     }
 
     fn handle_command(&mut self, cmd: CommandTrb) {
-        debug!("handling command {:?} at {:#x}", cmd, cmd.address);
-        let completion_event = match cmd.variant {
-            CommandTrbVariant::EnableSlot => {
-                let (completion_code, slot_id) = self.handle_enable_slot();
-                EventTrb::new_command_completion_event_trb(cmd.address, 0, completion_code, slot_id)
-            }
-            CommandTrbVariant::DisableSlot => {
-                // TODO this command probably requires more handling.
-                // Currently, we just acknowledge to not crash usbvfiod in the
-                // integration test.
-                EventTrb::new_command_completion_event_trb(
-                    cmd.address,
-                    0,
-                    CompletionCode::Success,
-                    1,
-                )
-            }
-            CommandTrbVariant::AddressDevice(data) => {
-                self.handle_address_device(&data);
-                EventTrb::new_command_completion_event_trb(
-                    cmd.address,
-                    0,
-                    CompletionCode::Success,
-                    data.slot_id,
-                )
-            }
-            CommandTrbVariant::ConfigureEndpoint(data) => {
-                self.handle_configure_endpoint(&data);
-                EventTrb::new_command_completion_event_trb(
-                    cmd.address,
-                    0,
-                    CompletionCode::Success,
-                    data.slot_id,
-                )
-            }
-            CommandTrbVariant::EvaluateContext => todo!(),
-            CommandTrbVariant::ResetEndpoint => todo!(),
-            CommandTrbVariant::StopEndpoint(data) => {
-                self.handle_stop_endpoint(&data);
-                EventTrb::new_command_completion_event_trb(
-                    cmd.address,
-                    0,
-                    CompletionCode::Success,
-                    data.slot_id,
-                )
-            }
-            CommandTrbVariant::SetTrDequeuePointer => todo!(),
-            CommandTrbVariant::ResetDevice(data) => {
-                // TODO this command probably requires more handling. The guest
-                // driver will attempt resets when descriptors do not match what
-                // the virtual port announces.
-                // Currently, we just acknowledge to not crash usbvfiod when
-                // testing with unsupported devices.
-                warn!("device reset! the driver probably didn't like it.");
+        debug!("handling {} at {:#x}", cmd.variant, cmd.address);
+        trace!("command TRB: {:?}", cmd);
+        let result = self
+            .command_policy
+            .check(&cmd.variant)
+            .and_then(|()| match &cmd.variant {
+                CommandTrbVariant::EnableSlot => self.handle_enable_slot(),
+                CommandTrbVariant::DisableSlot(data) => self.handle_disable_slot(data),
+                CommandTrbVariant::AddressDevice(data) => self.handle_address_device(data),
+                CommandTrbVariant::ConfigureEndpoint(data) => self.handle_configure_endpoint(data),
+                CommandTrbVariant::EvaluateContext(data) => self.handle_evaluate_context(data),
+                CommandTrbVariant::ResetEndpoint(data) => self.handle_reset_endpoint(data),
+                CommandTrbVariant::StopEndpoint(data) => self.handle_stop_endpoint(data),
+                CommandTrbVariant::ResetDevice(data) => {
+                    // TODO this command probably requires more handling of the
+                    // slot and endpoint contexts. The guest driver will attempt
+                    // resets when descriptors do not match what the virtual port
+                    // announces.
+                    warn!("device reset! the driver probably didn't like it.");
+                    self.handle_reset_device(data)
+                }
+                CommandTrbVariant::NoOp => Ok(CommandOutcome::new(0)),
+                CommandTrbVariant::SetTrDequeuePointer(_) | CommandTrbVariant::ForceHeader => {
+                    Err(CommandError::UnsupportedCommand(cmd.variant.to_string()))
+                }
+                CommandTrbVariant::Unrecognized(trb_buffer, _) => {
+                    debug!("unrecognized command TRB: {:?}", trb_buffer);
+                    Err(CommandError::UnsupportedCommand(cmd.variant.to_string()))
+                }
+                // The Command Ring follows Link TRBs itself.
+                CommandTrbVariant::Link(_) => unreachable!(),
+            });
+
+        let completion_event = match result {
+            Ok(outcome) => EventTrb::new_command_completion_event_trb(
+                cmd.address,
+                outcome.parameter,
+                CompletionCode::Success,
+                outcome.slot_id,
+            ),
+            Err(error) => {
+                warn!("{} at {:#x} failed: {}", cmd.variant, cmd.address, error);
                 EventTrb::new_command_completion_event_trb(
                     cmd.address,
                     0,
-                    CompletionCode::Success,
-                    data.slot_id,
+                    error.completion_code(),
+                    cmd.variant.slot_id(),
                 )
             }
-            CommandTrbVariant::ForceHeader => todo!(),
-            CommandTrbVariant::NoOp => todo!(),
-            CommandTrbVariant::Link(_) => unreachable!(),
-            CommandTrbVariant::Unrecognized(trb_buffer, error) => todo!(
-                "encountered unrecognized command (error: {}, trb: {:?})",
-                error,
-                trb_buffer
-            ),
         };
-        // Command handlers might have performed stores to guest memory.
-        // The stores have to be finished before the command completion
-        // event is written (essentially releasing the data to the driver).
-        //
-        // Not all commands write to guest memory, so this fence is sometimes
-        // not necessary. However, because it declutters the code and avoids
-        // missing a fence where it is needed, we choose to place a release
-        // barrier before every event enqueue.
-        fence(Ordering::Release);
-        self.event_ring.lock().unwrap().enqueue(&completion_event);
-        self.interrupt_line.interrupt();
+        // Command handlers might have performed stores to guest memory. The
+        // sink orders them before the command completion event.
+        self.event_sink.post(completion_event);
     }
 
-    fn handle_enable_slot(&mut self) -> (CompletionCode, u8) {
-        // try to reserve a device slot
-        let reservation = self.device_slot_manager.reserve_slot();
-        reservation.map_or_else(
-            || {
-                debug!("Answering driver that no free slot is available");
-                (CompletionCode::NoSlotsAvailableError, 0)
-            },
-            |slot_id| {
-                debug!("Answering driver to use Slot ID {}", slot_id);
-                (CompletionCode::Success, slot_id as u8)
-            },
-        )
+    /// Check that a command refers to a slot the driver enabled.
+    fn check_slot_enabled(&self, slot_id: u8) -> Result<(), CommandError> {
+        if self.device_slot_manager.is_reserved(slot_id) {
+            Ok(())
+        } else {
+            Err(CommandError::SlotNotEnabled(slot_id))
+        }
+    }
+
+    /// Look up the device of a slot for a command that needs it.
+    ///
+    /// Slots only get a device with the Address Device Command, so a slot
+    /// without one is in the wrong state for the command.
+    fn addressed_device_mut<'a>(
+        slot_to_port: &[Option<usize>; MAX_SLOTS as usize],
+        devices: &'a mut [Option<Box<dyn RealDevice>>; MAX_PORTS as usize],
+        slot_id: u8,
+    ) -> Result<&'a mut Box<dyn RealDevice>, CommandError> {
+        Self::device_by_slot_mut(slot_to_port, devices, slot_id)
+            .ok_or(CommandError::InvalidSlotState(slot_id))
+    }
+
+    /// Check that an endpoint the driver adds exists on the device with the
+    /// type the driver configured.
+    ///
+    /// The driver builds the endpoint context from the descriptors it read
+    /// through us, so a mismatch means that the driver is broken, we
+    /// forwarded the descriptors wrongly, or the device changed its
+    /// configuration behind our back. We could not start a worker for such
+    /// an endpoint. A differing max packet size only earns a warning, as
+    /// the workers transfer with the one of the device.
+    fn check_endpoint_descriptor(
+        device: &dyn RealDevice,
+        endpoint_id: Dci,
+        endpoint_context: &EndpointContext,
+    ) -> Result<(), CommandError> {
+        let Some(endpoint_type) = endpoint_context.get_endpoint_type() else {
+            return Err(CommandError::ParameterError(format!(
+                "EP{endpoint_id} has an invalid endpoint type"
+            )));
+        };
+        if !endpoint_type.is_supported() {
+            return Err(CommandError::ParameterError(format!(
+                "EP{endpoint_id} is configured as {endpoint_type:?}, which we do not support"
+            )));
+        }
+        let configurations = device.configuration_descriptors();
+        let Some(descriptor) = configurations
+            .iter()
+            .find_map(|configuration| configuration.endpoint(endpoint_id))
+        else {
+            return Err(CommandError::ParameterError(format!(
+                "EP{endpoint_id} is configured as {endpoint_type:?}, but the device does not describe it"
+            )));
+        };
+
+        if descriptor.endpoint_type() != endpoint_type {
+            return Err(CommandError::ParameterError(format!(
+                "EP{} is configured as {:?}, but the device describes it as {:?} {:?}",
+                endpoint_id,
+                endpoint_type,
+                descriptor.transfer_type(),
+                descriptor.direction()
+            )));
+        }
+        let max_packet_size = endpoint_context.get_transfer_unit().max_packet_size;
+        if descriptor.packet_size() != max_packet_size {
+            warn!(
+                "EP{} is configured with max packet size {}, but the device describes {}",
+                endpoint_id,
+                max_packet_size,
+                descriptor.packet_size()
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that an endpoint command does not target the slot context.
+    fn check_endpoint_id(endpoint_id: u8) -> Result<Dci, CommandError> {
+        // The Endpoint ID field has five bits, so only 0 is not a DCI.
+        Dci::new(endpoint_id).ok_or_else(|| {
+            CommandError::ParameterError("endpoint ID 0 refers to the slot context".to_string())
+        })
+    }
+
+    fn handle_enable_slot(&mut self) -> CommandResult {
+        // try to reserve a device slot
+        let slot_id = self
+            .device_slot_manager
+            .reserve_slot()
+            .ok_or(CommandError::NoSlotsAvailable)?;
+        debug!("Answering driver to use Slot ID {}", slot_id);
+        Ok(CommandOutcome::new(slot_id as u8))
+    }
+
+    fn handle_disable_slot(&mut self, data: &DisableSlotCommandTrbData) -> CommandResult {
+        self.check_slot_enabled(data.slot_id)?;
+        if let Some(device) = self.device_by_slot(data.slot_id) {
+            device.identity().set_slot(None);
+        }
+        // TODO this command probably requires more handling.
+        // Currently, we only release the USB device address.
+        self.device_slot_manager.release_usb_address(data.slot_id);
+        self.device_slot_manager
+            .invalidate_device_context(data.slot_id);
+        Ok(CommandOutcome::new(data.slot_id))
     }
 
-    fn handle_address_device(&mut self, data: &AddressDeviceCommandTrbData) {
+    fn handle_address_device(&mut self, data: &AddressDeviceCommandTrbData) -> CommandResult {
+        self.check_slot_enabled(data.slot_id)?;
+        // With BSR set, the driver only wants the slot in the default state,
+        // so the device gets no address yet.
+        let usb_device_address = if data.block_set_address_request {
+            None
+        } else {
+            let address = self
+                .device_slot_manager
+                .assign_usb_address(data.slot_id)
+                .ok_or(CommandError::ResourceError("USB device addresses"))?;
+            debug!(
+                "assigned USB device address {} to slot {}",
+                address, data.slot_id
+            );
+            Some(address)
+        };
+
+        // The driver just set up the DCBAA entry of the slot.
+        self.device_slot_manager
+            .invalidate_device_context(data.slot_id);
         let device_context = self.device_slot_manager.get_device_context(data.slot_id);
-        let root_hub_port_number = device_context.initialize(data.input_context_pointer);
+        let root_hub_port_number = device_context.initialize(
+            data.input_context_pointer,
+            usb_device_address,
+            |port_number| self.port_speed(usize::from(port_number).checked_sub(1)?),
+        )?;
         if root_hub_port_number < 1 || root_hub_port_number as u64 > MAX_PORTS {
-            panic!(
-                "address device reported invalid root hub port number: {}",
-                root_hub_port_number
-            );
+            return Err(CommandError::ParameterError(format!(
+                "invalid root hub port number {root_hub_port_number}"
+            )));
         }
         let port_index = root_hub_port_number as usize - 1;
         self.slot_to_port[data.slot_id as usize - 1] = Some(port_index);
+        if let Some(device) = &self.devices[port_index] {
+            device.identity().set_slot(Some(data.slot_id));
+        }
+        Ok(CommandOutcome::new(data.slot_id))
     }
 
-    fn handle_configure_endpoint(&mut self, data: &ConfigureEndpointCommandTrbData) {
+    fn handle_evaluate_context(&self, data: &EvaluateContextCommandTrbData) -> CommandResult {
+        self.check_slot_enabled(data.slot_id)?;
+        let device_context = self.device_slot_manager.get_device_context(data.slot_id);
+        device_context.evaluate(data.input_context_pointer);
+        Ok(CommandOutcome::new(data.slot_id))
+    }
+
+    fn handle_configure_endpoint(
+        &mut self,
+        data: &ConfigureEndpointCommandTrbData,
+    ) -> CommandResult {
+        self.check_slot_enabled(data.slot_id)?;
         if data.deconfigure {
-            todo!("encountered Configure Endpoint Command with deconfigure set");
+            return Err(CommandError::UnsupportedCommand(
+                "Configure Endpoint Command with Deconfigure set".to_string(),
+            ));
         }
         let device_context = self.device_slot_manager.get_device_context(data.slot_id);
-        let enabled_endpoints = device_context.configure_endpoints(data.input_context_pointer);
-        // Program requires real USB device for all XHCI operations (pattern used throughout file)
         let device =
-            Self::device_by_slot_mut_expect(&self.slot_to_port, &mut self.devices, data.slot_id);
+            Self::addressed_device_mut(&self.slot_to_port, &mut self.devices, data.slot_id)?;
+        let identity = device.identity().clone();
+        let _span = identity.span().entered();
+        // A failed command leaves the device context alone, so we check the
+        // added endpoints before we change it.
+        for (endpoint_id, endpoint_context) in
+            device_context.added_endpoint_contexts(data.input_context_pointer)
+        {
+            Self::check_endpoint_descriptor(device.as_ref(), endpoint_id, &endpoint_context)?;
+        }
+        let mut stuck = Vec::new();
+        let enabled_endpoints =
+            device_context.configure_endpoints(data.input_context_pointer, |endpoint_id| {
+                if !device.disable_endpoint(endpoint_id, STOP_ENDPOINT_TIMEOUT) {
+                    stuck.push(endpoint_id);
+                }
+            })?;
+        #[cfg(feature = "nusb-backend")]
+        let bulk_permits = self
+            .host_bus_scheduler
+            .bulk_permits(identity.location.bus_number);
 
         for (i, ep_type) in enabled_endpoints {
+            let Some(transfer_ring) =
+                device_context.get_endpoint_ring(i, self.transfer_ring_segment_boundary)
+            else {
+                warn!(
+                    "EP{} of slot {} is disabled despite the Configure Endpoint Command",
+                    i, data.slot_id
+                );
+                continue;
+            };
+            // The context is a copy of the input context, but the driver
+            // owns the memory and may have changed it since.
+            let endpoint_context = device_context.get_endpoint_context(i);
+            if endpoint_context.get_endpoint_type() != Some(ep_type) {
+                warn!(
+                    "EP{} of slot {} changed its type after the Configure Endpoint Command",
+                    i, data.slot_id
+                );
+            }
+            #[cfg(feature = "nusb-backend")]
+            let transfer_unit = endpoint_context.get_transfer_unit();
             let worker_info = EndpointWorkerInfo {
+                #[cfg(feature = "nusb-backend")]
                 slot_id: data.slot_id,
                 endpoint_id: i,
-                transfer_ring: device_context.get_transfer_ring(i as u64),
-                dma_bus: self.dma_bus.clone(),
-                event_ring: self.event_ring.clone(),
-                interrupt_line: self.interrupt_line.clone(),
+                engine: TdEngine::new(
+                    data.slot_id,
+                    i,
+                    transfer_ring,
+                    paranoid_dma::tag(&self.dma_bus, DmaOrigin::TdData),
+                    self.event_sink.clone(),
+                    TdEngineConfig {
+                        event_coalescing: self.event_coalescing,
+                        max_trbs_per_doorbell: self.max_trbs_per_doorbell,
+                        stats: self.endpoint_stats.endpoint(data.slot_id, i),
+                        run_state: self.lifecycle.run_state().clone(),
+                        isochronous: matches!(
+                            ep_type,
+                            EndpointType::IsochOut | EndpointType::IsochIn
+                        ),
+                    },
+                ),
+                #[cfg(feature = "nusb-backend")]
+                bulk_permits: bulk_permits.clone(),
+                #[cfg(feature = "nusb-backend")]
+                transfer_unit,
+                polling_interval: (ep_type == EndpointType::InterruptIn)
+                    .then(|| endpoint_context.get_interval()),
+                #[cfg(feature = "nusb-backend")]
+                queue_depth: NonZeroUsize::MIN,
+                #[cfg(feature = "nusb-backend")]
+                vmm_signals: self.vmm_signals.clone(),
+                device: identity.clone(),
             };
             device.enable_endpoint(worker_info, ep_type);
         }
+        if !stuck.is_empty() {
+            self.stuck_workers_error(data.slot_id, &stuck);
+        }
+        Ok(CommandOutcome::new(data.slot_id))
+    }
+
+    fn handle_stop_endpoint(&mut self, data: &StopEndpointCommandTrbData) -> CommandResult {
+        self.check_slot_enabled(data.slot_id)?;
+        let endpoint_id = Self::check_endpoint_id(data.endpoint_id)?;
+        // The worker posts the Transfer Event of an interrupted transfer
+        // before it acknowledges the stop, so the driver sees it before the
+        // Command Completion Event.
+        let device =
+            Self::addressed_device_mut(&self.slot_to_port, &mut self.devices, data.slot_id)?;
+        if !device.stop_endpoint(endpoint_id, STOP_ENDPOINT_TIMEOUT) {
+            warn!(
+                "EP{} of slot {} did not stop within {:?}",
+                endpoint_id, data.slot_id, STOP_ENDPOINT_TIMEOUT
+            );
+            // The endpoint is still running, which the driver can check in
+            // the endpoint context before it retries.
+            return Err(CommandError::InvalidEndpointState {
+                slot_id: data.slot_id,
+                endpoint_id,
+            });
+        }
+
+        let device_context = self.device_slot_manager.get_device_context(data.slot_id);
+        device_context.set_endpoint_state(endpoint_id, endpoint_state::STOPPED);
+        Ok(CommandOutcome::new(data.slot_id))
+    }
+
+    fn handle_reset_endpoint(&mut self, data: &ResetEndpointCommandTrbData) -> CommandResult {
+        self.check_slot_enabled(data.slot_id)?;
+        let endpoint_id = Self::check_endpoint_id(data.endpoint_id)?;
+        // The driver resets an endpoint to recover from a halt, so the halt
+        // on the real device has to go as well.
+        let device =
+            Self::addressed_device_mut(&self.slot_to_port, &mut self.devices, data.slot_id)?;
+        device.clear_halt(endpoint_id);
+
+        let device_context = self.device_slot_manager.get_device_context(data.slot_id);
+        device_context.set_endpoint_state(endpoint_id, endpoint_state::STOPPED);
+        Ok(CommandOutcome::new(data.slot_id))
     }
 
-    fn handle_stop_endpoint(&self, data: &StopEndpointCommandTrbData) {
+    fn handle_reset_device(&mut self, data: &ResetDeviceCommandTrbData) -> CommandResult {
+        self.check_slot_enabled(data.slot_id)?;
+        let device =
+            Self::addressed_device_mut(&self.slot_to_port, &mut self.devices, data.slot_id)?;
+        device.reset();
+
+        // The device is back at address 0 until the driver addresses it
+        // again.
+        self.device_slot_manager.release_usb_address(data.slot_id);
+        self.device_slot_manager
+            .invalidate_device_context(data.slot_id);
         let device_context = self.device_slot_manager.get_device_context(data.slot_id);
-        device_context.set_endpoint_state(data.endpoint_id, endpoint_state::STOPPED);
+        device_context.reset();
+        Ok(CommandOutcome::new(data.slot_id))
     }
 
     fn doorbell_device(&mut self, slot_id: u8, value: u32) {
         debug!("Ding Dong Device Slot {} with value {}!", slot_id, value);
+        let value = DoorbellValue::new(value);
+
+        // The driver enables all slots we report, see `enable_slots`, so
+        // MaxSlotsEn is the number of slots.
+        if slot_id == 0 || u64::from(slot_id) > self.device_slot_manager.num_slots {
+            self.ignored_doorbells
+                .record(slot_id, value, IgnoredDoorbell::SlotNotEnabled);
+            return;
+        }
 
-        match value {
-            ep if ep == 0 || ep > 31 => panic!("invalid value {} on doorbell write", ep),
-            1 => self.check_control_endpoint(slot_id),
-            ep => {
+        // The specification leaves doorbells for slots that are not
+        // addressed undefined. Drivers only ring them on teardown races, so
+        // we ignore them instead of acting on a stale device context.
+        if !self.slot_accepts_doorbells(slot_id) {
+            self.ignored_doorbells
+                .record(slot_id, value, IgnoredDoorbell::InactiveSlot);
+            return;
+        }
+
+        // The doorbell names the endpoint in the DB Target field and, for
+        // endpoints with streams, the stream in the DB Stream ID field.
+        let stream_id = value.stream_id();
+        if stream_id != 0 {
+            debug!("doorbell of slot {} names stream {}", slot_id, stream_id);
+        }
+        match Dci::new(value.target()) {
+            None => {
+                self.ignored_doorbells
+                    .record(slot_id, value, IgnoredDoorbell::ReservedTarget);
+            }
+            Some(Dci::CONTROL) => self.check_control_endpoint(slot_id),
+            // Doorbells for disabled endpoints fail with a Transfer Event.
+            // Devices with only a Default Control Endpoint, e.g., billboard
+            // devices, have no other endpoint at all.
+            Some(endpoint_id)
+                if !self
+                    .device_slot_manager
+                    .get_device_context(slot_id)
+                    .is_endpoint_enabled(endpoint_id) =>
+            {
+                warn!(
+                    "doorbell for EP{} of slot {}, which is disabled",
+                    endpoint_id, slot_id
+                );
+                self.event_sink.post(EventTrb::new_transfer_event_trb(
+                    0,
+                    0,
+                    CompletionCode::EndpointNotEnabledError,
+                    false,
+                    endpoint_id,
+                    slot_id,
+                ));
+            }
+            Some(endpoint_id) => {
                 // When the driver rings the doorbell with a non-control
                 // endpoint id, a lot must have happened before (e.g., descriptor
-                // reads on the control endpoint), so we never reach this point
-                // when no device is available (except for an invalid doorbell
-                // write, in which case panicking is the right thing to do.
-                assert!(
-                    u64::from(slot_id) <= MAX_SLOTS,
-                    "invalid slot_id {} in doorbell",
-                    slot_id
-                );
+                // reads on the control endpoint), so the addressed slot has
+                // a device.
                 let device =
                     Self::device_by_slot_mut_expect(&self.slot_to_port, &mut self.devices, slot_id);
-                device.transfer(ep as u8);
+                let _span = device.identity().span().entered();
+                device.transfer(endpoint_id, stream_id);
             }
         };
     }
 
-    fn check_control_endpoint(&self, slot: u8) {
+    /// Check whether the driver enabled the slot and addressed its device,
+    /// and the device's port is powered.
+    fn slot_accepts_doorbells(&self, slot_id: u8) -> bool {
+        self.device_slot_manager.is_reserved(slot_id)
+            && self
+                .slot_to_port
+                .get(usize::from(slot_id).wrapping_sub(1))
+                .copied()
+                .flatten()
+                .is_some_and(|port_index| self.is_port_powered(port_index))
+    }
+
+    fn check_control_endpoint(&mut self, slot: u8) {
         // check request available
         let transfer_ring = self
             .device_slot_manager
@@ -507,20 +1764,27 @@ impl XhciController {
 
         let request = match transfer_ring.next_request() {
             None => {
-                // XXX currently, we expect that a doorbell ring always
-                // notifies us about a new control request. We want to
-                // clearly see when another case occurs, so we want to panic
-                // here.
-                // Once we know all behaviors, the panic can probably be
-                // removed.
-                panic!(
-                "Device doorbell was rang, but there is no request on the control transfer ring"
-            );
+                // The driver may ring the doorbell of an empty ring, and so
+                // do replayed doorbells.
+                debug!("control transfer ring of slot {} is empty", slot);
+                return;
+            }
+            Some(Err(err)) => {
+                warn!("slot {}: malformed control request: {}", slot, err);
+                let address = match err {
+                    RequestParseError::TransferRing(err) => err.address(),
+                    _ => transfer_ring.dequeue_pointer(),
+                };
+                self.event_sink.post(EventTrb::new_transfer_event_trb(
+                    address,
+                    0,
+                    CompletionCode::TrbError,
+                    false,
+                    Dci::CONTROL,
+                    slot,
+                ));
+                return;
             }
-            Some(Err(err)) => panic!(
-                "Failed to retrieve request from control transfer ring: {:?}",
-                err
-            ),
             Some(Ok(res)) => res,
         };
 
@@ -537,148 +1801,2594 @@ impl XhciController {
         // Port status change events are suggestions for the driver to check portsc registers.
         // If no device is found, the driver won't start device initialization. Therefore,
         // when we reach this control transfer path, we should assume a device is present.
-        let device = self.device_by_slot_expect(slot);
-        device.control_transfer(&request, &self.dma_bus);
+        let completion_code = if request.is_answered_by_controller() {
+            debug!("answering SET_ADDRESS for slot {} without the device", slot);
+            CompletionCode::Success
+        } else if request.lacks_data_stage() {
+            warn!(
+                "request of slot {} announces {} bytes, but has no Data Stage; reporting Stall Error",
+                slot, request.length
+            );
+            CompletionCode::StallError
+        } else if self.clear_halt_on_request(slot, &request) && !self.forward_clear_halt {
+            CompletionCode::Success
+        } else if let Some(descriptor_type) = self.usb2_descriptor_type(slot, &request) {
+            self.get_usb2_descriptor(slot, &request, descriptor_type)
+        } else {
+            let device = self.device_by_slot_expect(slot);
+            device.control_transfer(
+                &request,
+                &paranoid_dma::tag(&self.dma_bus, DmaOrigin::ControlData),
+            )
+        };
 
         // send transfer event
         let trb = EventTrb::new_transfer_event_trb(
             request.address,
             0,
-            CompletionCode::Success,
+            completion_code,
             false,
-            1,
+            Dci::CONTROL,
             slot,
         );
-        self.event_ring.lock().unwrap().enqueue(&trb);
-        self.interrupt_line.interrupt();
-        debug!("sent Transfer Event and signaled interrupt");
+        self.event_sink.post(trb);
+        debug!("sent Transfer Event");
     }
-}
 
-impl PciDevice for Mutex<XhciController> {
-    fn write_cfg(&self, req: Request, value: u64) {
-        self.lock().unwrap().config_space.write(req, value);
+    /// The type of the descriptor a GET_DESCRIPTOR request asks for, if we
+    /// have to rewrite it for a SuperSpeed device on a USB 2 port.
+    ///
+    /// The driver enumerates such a device as a High Speed device, so the
+    /// device and configuration descriptors must not describe SuperSpeed.
+    /// Devices on USB 3 ports get their descriptors as they are.
+    fn usb2_descriptor_type(&self, slot: u8, request: &UsbRequest) -> Option<u8> {
+        let descriptor_type = request
+            .requested_descriptor_type()
+            .filter(|&descriptor_type| {
+                matches!(
+                    descriptor_type,
+                    descriptor_type::DEVICE | descriptor_type::CONFIGURATION
+                )
+            })?;
+        request.data?;
+        let port_index = (*self.slot_to_port.get(usize::from(slot).checked_sub(1)?)?)?;
+        let device_speed = self.devices.get(port_index)?.as_ref()?.speed()?;
+        (!device_speed.is_usb2_speed() && self.port_speed(port_index)? == Speed::High)
+            .then_some(descriptor_type)
     }
 
-    fn read_cfg(&self, req: Request) -> u64 {
-        self.lock().unwrap().config_space.read(req)
+    /// Answer a GET_DESCRIPTOR request of a SuperSpeed device on a USB 2
+    /// port with the descriptor a High Speed device would return.
+    ///
+    /// The device writes the complete descriptor to a scratch buffer, as
+    /// the rewritten `wTotalLength` of a configuration depends on all of it
+    /// even if the driver only reads the first bytes. The driver then gets
+    /// as much of the rewritten descriptor as it asked for.
+    fn get_usb2_descriptor(
+        &self,
+        slot: u8,
+        request: &UsbRequest,
+        descriptor_type: u8,
+    ) -> CompletionCode {
+        // Safety: the caller made sure the request has a Data Stage.
+        let buffer = request.data.unwrap();
+        let scratch = Arc::new(ScratchMemory::new(usize::from(u16::MAX)));
+        let staged = UsbRequest {
+            length: u16::MAX,
+            data: Some(DataStage {
+                pointer: 0,
+                length: u16::MAX,
+            }),
+            ..*request
+        };
+        let device = self.device_by_slot_expect(slot);
+        let completion_code = device.control_transfer(&staged, &(scratch.clone() as BusDeviceRef));
+        if completion_code != CompletionCode::Success {
+            return completion_code;
+        }
+
+        let bytes = scratch.to_bytes();
+        let rewritten = match descriptor_type {
+            descriptor_type::DEVICE => descriptors::usb2_device_descriptor(&bytes),
+            _ => descriptors::usb2_configuration_descriptor(&bytes),
+        };
+        let rewritten = match rewritten {
+            Ok(rewritten) => rewritten,
+            Err(error) => {
+                warn!(
+                    "cannot rewrite descriptor of slot {} for its USB 2 port: {}; reporting Stall Error",
+                    slot, error
+                );
+                return CompletionCode::StallError;
+            }
+        };
+        let dma_bus = paranoid_dma::tag(&self.dma_bus, DmaOrigin::ControlData);
+        match write_in_data(&dma_bus, buffer.pointer, &rewritten, buffer.length.into()) {
+            Ok(_) => CompletionCode::Success,
+            Err(unmapped) => {
+                warn!(
+                    "control in buffer is not fully backed by guest memory (unmapped: {:#x}..{:#x}); reporting Data Buffer Error",
+                    unmapped.start, unmapped.end
+                );
+                CompletionCode::DataBufferError
+            }
+        }
+    }
+
+    /// Clear the halt of the endpoint a CLEAR_FEATURE(ENDPOINT_HALT)
+    /// request of the driver targets.
+    ///
+    /// Forwarding the request would reset the data toggle of the device,
+    /// but not the one of the host, so the next transfers could fail with
+    /// toggle mismatches. Instead, the worker of the endpoint clears the
+    /// halt on the host, which sends the request and resets both. The
+    /// worker does so once its in-flight transfers completed and before it
+    /// fetches the next TRB, so transfers the driver queues after the
+    /// request see the cleared endpoint.
+    ///
+    /// Returns whether the request was taken care of. Requests for
+    /// endpoints without a worker are left to the device, which may have
+    /// halted them after a SET_FEATURE(ENDPOINT_HALT).
+    fn clear_halt_on_request(&mut self, slot: u8, request: &UsbRequest) -> bool {
+        let Some(endpoint_id) = request.cleared_endpoint_halt() else {
+            return false;
+        };
+        if !self
+            .device_slot_manager
+            .get_device_context(slot)
+            .is_endpoint_enabled(endpoint_id)
+        {
+            return false;
+        }
+        debug!(
+            "clearing halt of slot {} endpoint {} on behalf of CLEAR_FEATURE(ENDPOINT_HALT)",
+            slot, endpoint_id
+        );
+        Self::device_by_slot_mut_expect(&self.slot_to_port, &mut self.devices, slot)
+            .clear_halt(endpoint_id);
+        true
     }
 
+    /// Handle a register write of the driver.
     #[allow(clippy::cognitive_complexity)]
-    fn write_io(&self, region: u32, req: Request, value: u64) {
-        // The XHCI Controller has a single MMIO BAR.
+    fn handle_write_io(&mut self, region: u32, req: Request, value: u64) {
+        if region == u32::from(msix_bar::NUMBER) {
+            self.write_msix_bar(req, value);
+            return;
+        }
+        // All XHCI registers are in BAR 0.
         assert_eq!(region, 0);
 
-        let mut guard = self.lock().unwrap();
         match req.addr {
             // xHC Operational Registers
-            offset::USBCMD => guard.run(value),
+            offset::USBCMD => self.run(value),
             offset::DNCTL => assert_eq!(value, 2, "debug notifications not supported"),
-            offset::CRCR => guard.command_ring.control(value),
-            offset::CRCR_HI => assert_eq!(value, 0, "no support for configuration above 4G"),
-            offset::DCBAAP => guard.configure_device_contexts(value),
-            offset::DCBAAP_HI => assert_eq!(value, 0, "no support for configuration above 4G"),
-            offset::CONFIG => guard.enable_slots(value),
-            // USBSTS writes occur but we can ignore them (to get a device enumerated)
-            offset::USBSTS => {}
+            offset::CRCR => self.command_ring.control(with_low_dword(
+                self.command_ring.dequeue_pointer(),
+                req,
+                value,
+            )),
+            offset::CRCR_HI => self.command_ring.control_high(value),
+            offset::DCBAAP => self.configure_device_contexts(with_low_dword(
+                self.device_slot_manager.get_dcbaap(),
+                req,
+                value,
+            )),
+            offset::DCBAAP_HI => self.configure_device_contexts(with_high_dword(
+                self.device_slot_manager.get_dcbaap(),
+                value,
+            )),
+            offset::CONFIG => self.enable_slots(value),
+            offset::USBSTS => self.write_usbsts(value),
             // xHC Runtime Registers (moved up for performance)
-            offset::IMAN => guard.interrupt_management = value,
-            offset::IMOD => guard.interrupt_moderation_interval = value,
+            offset::IMAN => self.event_sink.write_iman(value),
+            offset::IMOD => self.event_sink.write_imod(value),
             offset::ERSTSZ => {
                 let sz = (value as u32) & 0xFFFF;
-                guard.event_ring.lock().unwrap().set_erst_size(sz);
-            }
-            offset::ERSTBA => guard.event_ring.lock().unwrap().configure(value),
-            offset::ERSTBA_HI => assert_eq!(value, 0, "no support for configuration above 4G"),
-            offset::ERDP => guard
-                .event_ring
-                .lock()
-                .unwrap()
-                .update_dequeue_pointer(value),
-            offset::ERDP_HI => assert_eq!(value, 0, "no support for configuration above 4G"),
-            offset::DOORBELL_CONTROLLER => guard.doorbell_controller(),
+                self.event_sink.set_segment_table_size(sz);
+            }
+            offset::ERSTBA => {
+                let current = self.event_sink.event_ring().read_base_address();
+                self.event_sink
+                    .configure_segment_table(with_low_dword(current, req, value));
+            }
+            offset::ERSTBA_HI => {
+                let current = self.event_sink.event_ring().read_base_address();
+                // The low half configured the ring already, unless the
+                // upper half changes.
+                if current >> 32 != value {
+                    self.event_sink
+                        .configure_segment_table(with_high_dword(current, value));
+                }
+            }
+            offset::ERDP => {
+                let current = self.event_sink.event_ring().read_dequeue_pointer();
+                self.event_sink
+                    .update_dequeue_pointer(with_low_dword(current, req, value));
+            }
+            offset::ERDP_HI => {
+                let current = self.event_sink.event_ring().read_dequeue_pointer();
+                if current >> 32 != value {
+                    self.event_sink
+                        .update_dequeue_pointer(with_high_dword(current, value));
+                }
+            }
+            offset::DOORBELL_CONTROLLER => {
+                debug!("Ding Dong!");
+                let value = DoorbellValue::new(value as u32);
+                if value.target() != DoorbellValue::COMMAND_TARGET {
+                    self.ignored_doorbells
+                        .record(0, value, IgnoredDoorbell::ReservedTarget);
+                } else if self.lifecycle.run_state().defer_command_ring() {
+                    debug!("controller stopped, processing commands once it starts");
+                } else {
+                    self.process_commands();
+                }
+            }
             // Device Doorbell Registers (DOORBELL_DEVICE)
             offset::DOORBELL_DEVICE..offset::DOORBELL_DEVICE_END => {
                 let slot_id = ((req.addr - offset::DOORBELL_CONTROLLER) / 4) as u8;
-                guard.doorbell_device(slot_id, value as u32);
+                // We serve the Default Control Endpoint ourselves. The
+                // workers of the other endpoints check the run state on
+                // their own, so their doorbells still note pending streams.
+                if DoorbellValue::new(value as u32).target() == Dci::CONTROL.get()
+                    && self.lifecycle.run_state().defer_doorbell(slot_id, 1)
+                {
+                    debug!(
+                        "controller stopped, serving slot {} once it starts",
+                        slot_id
+                    );
+                } else {
+                    self.doorbell_device(slot_id, value as u32);
+                }
             }
 
-            addr if guard.get_portsc_index(addr).is_some() => {
+            addr if self.get_portsc_index(addr).is_some() => {
+                // SAFETY: unwrap() is safe because we already checked is_some() in the match guard above
+                let port_idx = self.get_portsc_index(addr).unwrap();
+                let unchanged = self.portsc[port_idx].unchanged_write_value();
+                // The merged value has 32 bits, like the register.
+                let merged = merge_register_bytes(unchanged.into(), addr % 4, req.size, value);
+                self.write_portsc(port_idx, merged as u32);
+            }
+            // Port Power Management Status and Control Register (PORTPMSC)
+            addr if self.get_portpmsc_index(addr).is_some() => {
                 // SAFETY: unwrap() is safe because we already checked is_some() in the match guard above
-                let port_idx = guard.get_portsc_index(addr).unwrap();
-                guard.write_portsc(port_idx, value);
+                let port_idx = self.get_portpmsc_index(addr).unwrap();
+                let unchanged = self.portpmsc[port_idx].read();
+                self.portpmsc[port_idx].write(merge_register_bytes(
+                    unchanged,
+                    addr % 4,
+                    req.size,
+                    value,
+                ));
+            }
+            // Port Link Info Register (PORTLI_USB3), which is read-only
+            addr if self.get_portli_index(addr).is_some() => {
+                debug!("ignoring write to read-only PORTLI at {:#x}", addr);
             }
             addr => {
                 todo!("unknown write {}", addr);
             }
         }
-        // Drop the guard early to reduce resource contention as suggested by clippy
-        drop(guard);
     }
 
-    fn read_io(&self, region: u32, req: Request) -> u64 {
-        // The XHCI Controller has a single MMIO BAR.
+    /// Handle a register read of the driver.
+    ///
+    /// The capability registers are read-only and can be read with any
+    /// size and alignment. Linux, for example, reads CAPLENGTH and
+    /// HCIVERSION with a single 32-bit access. All other registers are
+    /// dwords, and reads that do not match a register are composed of the
+    /// dwords they touch.
+    fn handle_read_io(&mut self, region: u32, req: Request) -> u64 {
+        if region == u32::from(msix_bar::NUMBER) {
+            return self.read_msix_bar(req);
+        }
+        // All XHCI registers are in BAR 0.
         assert_eq!(region, 0);
 
-        let guard = self.lock().unwrap();
-        match req.addr {
-            // xHC Capability Registers
-            offset::CAPLENGTH => OP_BASE,
-            offset::HCIVERSION => capability::HCIVERSION,
-            offset::HCSPARAMS1 => capability::HCSPARAMS1,
-            offset::HCSPARAMS2 => capability::HCSPARAMS2,
-            offset::HCSPARAMS3 => 0,
-            offset::HCCPARAMS1 => capability::HCCPARAMS1,
-            offset::DBOFF => offset::DOORBELL_CONTROLLER,
-            offset::RTSOFF => RUN_BASE,
-            offset::HCCPARAMS2 => 0,
-
-            // xHC Extended Capability ("Supported Protocols Capability")
-            offset::SUPPORTED_PROTOCOLS => capability::supported_protocols::CAP_INFO,
-            offset::SUPPORTED_PROTOCOLS_CONFIG => capability::supported_protocols::CONFIG,
-            offset::SUPPORTED_PROTOCOLS_USB2 => capability::supported_protocols_usb2::CAP_INFO,
-            offset::SUPPORTED_PROTOCOLS_USB2_CONFIG => capability::supported_protocols_usb2::CONFIG,
+        let size = u64::from(u8::from(req.size));
+        if req.addr + size <= OP_BASE {
+            return self.capability_registers.read(req);
+        }
+        if req.addr.is_multiple_of(4) && size == 4 {
+            return self.read_dword(req.addr);
+        }
 
+        let first_dword = req.addr & !0x3;
+        let dwords = (req.addr + size).div_ceil(4) - first_dword / 4;
+        let value = (0..dwords).fold(0u128, |value, index| {
+            let dword = u128::from(self.read_dword(first_dword + index * 4));
+            value | dword << (index * 32)
+        });
+        let mask = u64::MAX >> (64 - size * 8);
+        (value >> ((req.addr - first_dword) * 8)) as u64 & mask
+    }
+
+    /// Read the dword at `addr`, which has to be 4-byte aligned.
+    fn read_dword(&self, addr: u64) -> u64 {
+        debug_assert!(addr.is_multiple_of(4));
+        if addr < OP_BASE {
+            return self
+                .capability_registers
+                .read(Request::new(addr, RequestSize::Size4));
+        }
+
+        let value = match addr {
             // xHC Operational Registers
             offset::USBCMD => 0,
-            offset::USBSTS => guard.status(),
+            offset::USBSTS => self.status(),
             offset::DNCTL => 2,
-            offset::CRCR => guard.command_ring.status(),
+            offset::CRCR => self.command_ring.status(),
             offset::CRCR_HI => 0,
-            offset::DCBAAP => guard.device_slot_manager.get_dcbaap(),
-            offset::DCBAAP_HI => 0,
+            offset::DCBAAP => self.device_slot_manager.get_dcbaap(),
+            offset::DCBAAP_HI => self.device_slot_manager.get_dcbaap() >> 32,
             offset::PAGESIZE => 0x1, /* 4k Pages */
-            offset::CONFIG => guard.config(),
+            offset::CONFIG => self.config(),
 
             // xHC Runtime Registers (moved up for performance)
-            offset::IMAN => guard.interrupt_management,
-            offset::IMOD => guard.interrupt_moderation_interval,
-            offset::ERSTSZ => guard.event_ring.lock().unwrap().read_erst_size(),
-            offset::ERSTBA => guard.event_ring.lock().unwrap().read_base_address(),
-            offset::ERSTBA_HI => 0,
-            offset::ERDP => guard.event_ring.lock().unwrap().read_dequeue_pointer(),
-            offset::ERDP_HI => 0,
+            offset::MFINDEX => self.microframe_clock.mfindex().into(),
+            offset::IMAN => self.event_sink.read_iman(),
+            offset::IMOD => self.event_sink.read_imod(),
+            offset::ERSTSZ => self.event_sink.event_ring().read_erst_size(),
+            offset::ERSTBA => self.event_sink.event_ring().read_base_address(),
+            offset::ERSTBA_HI => self.event_sink.event_ring().read_base_address() >> 32,
+            offset::ERDP => self.event_sink.read_erdp(),
+            offset::ERDP_HI => self.event_sink.read_erdp() >> 32,
             offset::DOORBELL_CONTROLLER => 0, // kernel reads the doorbell after write
             // Device Doorbell Registers (DOORBELL_DEVICE)
             offset::DOORBELL_DEVICE..offset::DOORBELL_DEVICE_END => 0,
 
             // Port Status and Control Register (PORTSC)
-            addr if guard.get_portsc_index(addr).is_some() => {
+            addr if self.get_portsc_index(addr).is_some() => {
                 // SAFETY: unwrap() is safe because we already checked is_some() in the match guard above
-                let port_idx = guard.get_portsc_index(addr).unwrap();
-                guard.portsc[port_idx].read()
+                let port_idx = self.get_portsc_index(addr).unwrap();
+                self.portsc[port_idx].read().into()
+            }
+            // Port Power Management Status and Control Register (PORTPMSC)
+            addr if self.get_portpmsc_index(addr).is_some() => {
+                // SAFETY: unwrap() is safe because we already checked is_some() in the match guard above
+                let port_idx = self.get_portpmsc_index(addr).unwrap();
+                self.portpmsc[port_idx].read()
             }
             // Port Link Info Register (PORTLI_USB3)
-            addr if guard.get_portli_index(addr).is_some() => 0,
+            addr if self.get_portli_index(addr).is_some() => 0,
 
             // Everything else is Reserved Zero
             addr => {
                 todo!("unknown read {}", addr);
             }
+        };
+        // The 64-bit registers report their full value at their low dword.
+        value & 0xffff_ffff
+    }
+}
+
+impl Drop for XhciController {
+    fn drop(&mut self) {
+        // The VMM went away, so nobody looks at the rings anymore.
+        self.halt();
+        // Leave the devices to the host in a usable state.
+        for device in self.devices.iter_mut().flatten() {
+            device.release(STOP_ENDPOINT_TIMEOUT);
+        }
+    }
+}
+
+impl PciDevice for Mutex<XhciController> {
+    fn write_cfg(&self, req: Request, value: u64) {
+        let mut guard = self.lock().unwrap();
+        guard.config_space.write(req, value);
+        guard.update_msix_function_mask();
+    }
+
+    fn read_cfg(&self, req: Request) -> u64 {
+        self.lock().unwrap().config_space.read(req)
+    }
+
+    fn write_io(&self, region: u32, req: Request, value: u64) {
+        let mut guard = self.lock().unwrap();
+        let start = guard.mmio_profile.start();
+        guard.handle_write_io(region, req, value);
+        guard
+            .mmio_profile
+            .record(MmioAccess::Write, region, req.addr, start);
+        if let Some(recorder) = &guard.trace_recorder {
+            recorder.record(&TraceEvent::access(MmioAccess::Write, region, req, value));
+        }
+    }
+
+    fn read_io(&self, region: u32, req: Request) -> u64 {
+        let mut guard = self.lock().unwrap();
+        let start = guard.mmio_profile.start();
+        let value = guard.handle_read_io(region, req);
+        guard
+            .mmio_profile
+            .record(MmioAccess::Read, region, req.addr, start);
+        if let Some(recorder) = &guard.trace_recorder {
+            recorder.record(&TraceEvent::access(MmioAccess::Read, region, req, value));
         }
+        drop(guard);
+        value
     }
 
     fn bar(&self, bar_no: u8) -> Option<BarInfo> {
         self.lock().unwrap().config_space.bar(bar_no)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        device::{
+            bus::{testutils::TestBusDevice, BusDevice, RequestSize},
+            pci::{
+                commands::PartialCommand,
+                constants::config_space::msix::{self as msix_cap, control},
+                constants::xhci::{
+                    device_slots::slot_state, operational::portpmsc, rings::trb_types, runtime,
+                },
+                event_sink::testutils::CountingInterruptLine,
+                msix_table::{self, CONTROL_MASKED},
+                realdevice::{
+                    testutils::{MockCall, MockStop, MockUsbDevice, MOCK_LOCATION},
+                    DeviceIdentity,
+                },
+                traits::RequestKind,
+                trb::SetTrDequeuePointerCommandTrbData,
+                usbrequest::request,
+            },
+        },
+        dynamic_bus::DynamicBus,
+    };
+
+    use super::*;
+
+    #[test]
+    fn bar_layout_is_self_consistent() {
+        let (controller, _, _) = controller_with_mock_device();
+        let layout = controller.config_space.bar_layout();
+        assert_eq!(
+            layout.iter().map(|&(bar_no, _)| bar_no).collect::<Vec<_>>(),
+            [0, msix_bar::NUMBER]
+        );
+        assert!(layout
+            .iter()
+            .all(|(_, bar)| bar.kind == RequestKind::Memory));
+        assert_eq!(check_bar_regions(&layout, &bar_regions()), Ok(()));
+    }
+
+    #[test]
+    fn colliding_bar_regions_are_rejected() {
+        let (controller, _, _) = controller_with_mock_device();
+        let layout = controller.config_space.bar_layout();
+        let mut regions = bar_regions();
+
+        // The PBA on top of the MSI-X table.
+        regions[5].range = 0..MSIX_PBA_SIZE;
+        assert_eq!(
+            check_bar_regions(&layout, &regions),
+            Err(BarLayoutError::Overlap("MSI-X table", "MSI-X PBA"))
+        );
+
+        // Regions in different BARs may share offsets.
+        regions[5].bar_no = 0;
+        regions[5].range = offset::DOORBELL_DEVICE_END..offset::DOORBELL_DEVICE_END + 8;
+        assert_eq!(check_bar_regions(&layout, &regions), Ok(()));
+
+        // The doorbells running into the runtime registers.
+        regions[2].range = offset::DOORBELL_CONTROLLER..RUN_BASE + 4;
+        assert_eq!(
+            check_bar_regions(&layout, &regions),
+            Err(BarLayoutError::Overlap(
+                "doorbell registers",
+                "runtime registers"
+            ))
+        );
+
+        let mut regions = bar_regions();
+        regions[4].range = 0x1800..0x2010;
+        assert_eq!(
+            check_bar_regions(&layout, &regions),
+            Err(BarLayoutError::OutsideBar {
+                name: "MSI-X table",
+                bar_no: msix_bar::NUMBER,
+                range: 0x1800..0x2010,
+                size: 0x2000,
+            })
+        );
+
+        regions[4].bar_no = 1;
+        assert_eq!(
+            check_bar_regions(&layout, &regions),
+            Err(BarLayoutError::MissingBar("MSI-X table", 1))
+        );
+    }
+
+    #[test]
+    fn imod_fields_read_back() {
+        let (controller, _, _) = controller_with_mock_device();
+        let controller = Mutex::new(controller);
+        let imod = Request::new(offset::IMOD, RequestSize::Size4);
+        assert_eq!(
+            controller.read_io(0, imod),
+            u64::from(runtime::IMOD_DEFAULT)
+        );
+
+        // IMODC counts down from what the driver wrote, IMODI stays.
+        controller.write_io(0, imod, 0xfff0_0200);
+        let value = controller.read_io(0, imod);
+        assert_eq!(value & runtime::imod::IMODI, 0x200);
+        assert!(value >> 16 <= 0xfff0);
+
+        controller.write_io(0, imod, 0x0000_0020);
+        assert_eq!(controller.read_io(0, imod), 0x20);
+    }
+
+    /// Create a controller with a mock device that is assigned to slot 1.
+    fn controller_with_mock_device() -> (XhciController, Arc<TestBusDevice>, MockCallLog) {
+        controller_with_stopping_device(|_| MockStop::Idle)
+    }
+
+    /// Like [`controller_with_mock_device`], but the device stops endpoints
+    /// as `stop` describes, given the controller's event sink.
+    fn controller_with_stopping_device(
+        stop: impl FnOnce(Arc<EventSink>) -> MockStop,
+    ) -> (XhciController, Arc<TestBusDevice>, MockCallLog) {
+        controller_with_configured_device(|device, event_sink| device.stop = stop(event_sink))
+    }
+
+    /// Like [`controller_with_mock_device`], but `configure` sets up the
+    /// device, given the controller's event sink.
+    fn controller_with_configured_device(
+        configure: impl FnOnce(&mut MockUsbDevice, Arc<EventSink>),
+    ) -> (XhciController, Arc<TestBusDevice>, MockCallLog) {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        let mut controller = XhciController::new(ram.clone(), ControllerConfig::default());
+        // The DCBAA entry of slot 1 is zero, so its device context is at 0x0.
+        controller.device_slot_manager.set_dcbaap(0xf00);
+        let (mut device, calls) = MockUsbDevice::new();
+        configure(&mut device, controller.event_sink.clone());
+        controller.set_device(Box::new(device)).unwrap();
+
+        let slot_id = controller.handle_enable_slot().unwrap().slot_id;
+        let port_index = controller.devices.iter().position(Option::is_some);
+        controller.slot_to_port[slot_id as usize - 1] = port_index;
+
+        (controller, ram, calls)
+    }
+
+    type MockCallLog = Arc<Mutex<Vec<MockCall>>>;
+
+    #[test]
+    fn reset_device_command_resets_real_device() {
+        let (mut controller, _ram, calls) = controller_with_mock_device();
+
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::ResetDevice(ResetDeviceCommandTrbData { slot_id: 1 }),
+        });
+
+        assert_eq!(*calls.lock().unwrap(), [MockCall::Reset]);
+    }
+
+    #[test]
+    fn reset_device_completes_according_to_the_command_policy() {
+        let reset_device =
+            || CommandTrbVariant::ResetDevice(ResetDeviceCommandTrbData { slot_id: 1 });
+
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        assert_eq!(
+            complete_command(&mut controller, &ram, reset_device()),
+            (CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(*calls.lock().unwrap(), [MockCall::Reset]);
+
+        // A strict policy leaves the device alone.
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        controller.command_policy = CommandPolicy::strict([PartialCommand::ResetDevice]);
+        assert_eq!(
+            complete_command(&mut controller, &ram, reset_device()),
+            (CompletionCode::TrbError as u8, 1)
+        );
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reset_endpoint_command_clears_halt() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        // The DCBAA entry of slot 1 is zero, so the device context is at 0x0.
+        let endpoint_state_address = 3 * 32;
+        ram.write(
+            Request::new(endpoint_state_address, RequestSize::Size1),
+            endpoint_state::HALTED.into(),
+        );
+
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::ResetEndpoint(ResetEndpointCommandTrbData {
+                endpoint_id: 3,
+                transfer_state_preserve: false,
+                slot_id: 1,
+            }),
+        });
+
+        assert_eq!(*calls.lock().unwrap(), [MockCall::ClearHalt(3)]);
+        assert_eq!(
+            ram.read(Request::new(endpoint_state_address, RequestSize::Size1)),
+            u64::from(endpoint_state::STOPPED)
+        );
+    }
+
+    /// Queue a control request without data on the Default Control
+    /// Endpoint at `address`.
+    fn queue_control_request(ram: &TestBusDevice, address: u64, setup: [u8; 8]) {
+        let mut setup_stage = [0; 16];
+        setup_stage[..8].copy_from_slice(&setup);
+        setup_stage[8] = 8;
+        setup_stage[12] = 1 | 1 << 6;
+        setup_stage[13] = trb_types::SETUP_STAGE << 2;
+        ram.write_bulk(address, &setup_stage);
+        let mut status_stage = [0; 16];
+        status_stage[12] = 1 | 1 << 5;
+        status_stage[13] = trb_types::STATUS_STAGE << 2;
+        ram.write_bulk(address + 0x10, &status_stage);
+    }
+
+    /// Queue a device-to-host control request on the Default Control
+    /// Endpoint at `address`, with a Data Stage for `buffer`.
+    fn queue_control_in_request(ram: &TestBusDevice, address: u64, setup: [u8; 8], buffer: u64) {
+        queue_control_request(ram, address, setup);
+        // Transfer Type: IN Data Stage
+        ram.write(Request::new(address + 14, RequestSize::Size1), 3);
+        let mut data_stage = [0; 16];
+        data_stage[..8].copy_from_slice(&buffer.to_le_bytes());
+        data_stage[8..10].copy_from_slice(&setup[6..8]);
+        data_stage[12] = 1;
+        data_stage[13] = trb_types::DATA_STAGE << 2;
+        data_stage[14] = 1;
+        ram.write_bulk(address + 0x10, &data_stage);
+        let mut status_stage = [0; 16];
+        status_stage[12] = 1 | 1 << 5;
+        status_stage[13] = trb_types::STATUS_STAGE << 2;
+        ram.write_bulk(address + 0x20, &status_stage);
+    }
+
+    #[test]
+    fn superspeed_devices_on_usb2_ports_show_up_as_high_speed() {
+        const CONFIGURATION: [u8; 31] = [
+            9, 0x02, 31, 0, 1, 1, 0, 0x80, 112, //
+            9, 0x04, 0, 0, 1, 0x08, 0x06, 0x62, 0, //
+            // EP1 IN, Bulk, 1024 bytes, and its companion
+            7, 0x05, 0x81, 0x02, 0x00, 0x04, 0, //
+            6, 0x30, 3, 0, 0, 0, //
+        ];
+        let superspeed = |device: &mut MockUsbDevice| {
+            device.speed = Speed::Super;
+            device.control_in_data = CONFIGURATION.to_vec();
+        };
+        let (mut controller, ram, _calls) =
+            controller_with_configured_device(|device, _| superspeed(device));
+        configure_event_ring(&controller, &ram);
+        ram.write_bulk(32 + 8, &(0x600u64 | 1).to_le_bytes());
+        // The second SuperSpeed device takes the last USB 3 port, the third
+        // one ends up on a USB 2 port.
+        for _ in 0..NUM_USB3_PORTS {
+            let (mut device, _) = MockUsbDevice::new();
+            superspeed(&mut device);
+            controller.set_device(Box::new(device)).unwrap();
+        }
+        let usb2_port_index = NUM_USB3_PORTS as usize;
+        assert_eq!(
+            controller.portsc[usb2_port_index].read() & portsc::PORT_SPEED,
+            u32::from(Speed::High.raw()) << portsc::PORT_SPEED_SHIFT
+        );
+        let usb2_slot = controller.handle_enable_slot().unwrap().slot_id;
+        controller.slot_to_port[usb2_slot as usize - 1] = Some(usb2_port_index);
+
+        let get_configuration = |length: u16| {
+            let [low, high] = length.to_le_bytes();
+            [0x80, request::GET_DESCRIPTOR, 0, 0x02, 0, 0, low, high]
+        };
+        let read_buffer = |length| {
+            let mut buffer = vec![0; length];
+            ram.read_bulk(0xc00, &mut buffer);
+            buffer
+        };
+        let success = (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8);
+
+        // The USB 3 port gets the companion.
+        queue_control_in_request(&ram, 0x600, get_configuration(64), 0xc00);
+        controller.check_control_endpoint(1);
+        assert_eq!(event_type_and_code(&ram, 0x500), success);
+        assert_eq!(read_buffer(31), CONFIGURATION);
+
+        // The USB 2 port does not, even if the driver only asks for the
+        // header first.
+        ram.write_bulk(0xc00, &[0; 64]);
+        queue_control_in_request(&ram, 0x630, get_configuration(9), 0xc00);
+        controller.check_control_endpoint(usb2_slot);
+        assert_eq!(event_type_and_code(&ram, 0x510), success);
+        assert_eq!(read_buffer(10), [9, 0x02, 25, 0, 1, 1, 0, 0x80, 112, 0]);
+
+        queue_control_in_request(&ram, 0x660, get_configuration(25), 0xc00);
+        controller.check_control_endpoint(usb2_slot);
+        assert_eq!(event_type_and_code(&ram, 0x520), success);
+        assert_eq!(
+            read_buffer(25),
+            [
+                9, 0x02, 25, 0, 1, 1, 0, 0x80, 112, //
+                9, 0x04, 0, 0, 1, 0x08, 0x06, 0x62, 0, //
+                7, 0x05, 0x81, 0x02, 0x00, 0x02, 0, //
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_or_malformed_control_ring_does_not_abort() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        ram.write_bulk(32 + 8, &(0x600u64 | 1).to_le_bytes());
+
+        // A doorbell on the empty ring has nothing to do.
+        controller.check_control_endpoint(1);
+        assert_eq!(ram.read(Request::new(0x500, RequestSize::Size8)), 0);
+
+        // A Status Stage without a Setup Stage is a TRB Error.
+        let mut status_stage = [0; 16];
+        status_stage[12] = 1 | 1 << 5;
+        status_stage[13] = trb_types::STATUS_STAGE << 2;
+        ram.write_bulk(0x600, &status_stage);
+        controller.check_control_endpoint(1);
+        assert_eq!(
+            event_type_and_code(&ram, 0x500),
+            (trb_types::TRANSFER_EVENT, CompletionCode::TrbError as u8)
+        );
+        assert_eq!(ram.read(Request::new(0x500, RequestSize::Size8)), 0x600);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn clear_feature_endpoint_halt_clears_the_halt_on_the_host() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        // The Default Control Endpoint has its ring at 0x600, and EP2 IN
+        // (DCI 5) runs.
+        ram.write_bulk(32 + 8, &(0x600u64 | 1).to_le_bytes());
+        ram.write(
+            Request::new(5 * 32, RequestSize::Size1),
+            endpoint_state::RUNNING.into(),
+        );
+        let clear_halt = |address| [0x02, request::CLEAR_FEATURE, 0, 0, address, 0, 0, 0];
+        let success = (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8);
+
+        queue_control_request(&ram, 0x600, clear_halt(0x82));
+        controller.check_control_endpoint(1);
+        assert_eq!(*calls.lock().unwrap(), [MockCall::ClearHalt(5)]);
+        assert_eq!(event_type_and_code(&ram, 0x500), success);
+
+        // EP3 IN is disabled, so the device clears its halt itself.
+        queue_control_request(&ram, 0x620, clear_halt(0x83));
+        controller.check_control_endpoint(1);
+        assert_eq!(
+            calls.lock().unwrap()[1..],
+            [MockCall::ControlTransfer(request::CLEAR_FEATURE)]
+        );
+        assert_eq!(event_type_and_code(&ram, 0x510), success);
+
+        // With the quirk, the device sees the request as well.
+        controller.forward_clear_halt = true;
+        queue_control_request(&ram, 0x640, clear_halt(0x82));
+        controller.check_control_endpoint(1);
+        assert_eq!(
+            calls.lock().unwrap()[2..],
+            [
+                MockCall::ClearHalt(5),
+                MockCall::ControlTransfer(request::CLEAR_FEATURE)
+            ]
+        );
+        assert_eq!(event_type_and_code(&ram, 0x520), success);
+    }
+
+    #[test]
+    fn reset_endpoint_command_targets_device_of_slot() {
+        let (mut controller, _ram, first_calls) = controller_with_mock_device();
+        let (device, second_calls) = MockUsbDevice::new();
+        controller.set_device(Box::new(device)).unwrap();
+        let slot_id = controller.handle_enable_slot().unwrap().slot_id;
+        let port_index = controller.devices.iter().rposition(Option::is_some);
+        controller.slot_to_port[slot_id as usize - 1] = port_index;
+
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::ResetEndpoint(ResetEndpointCommandTrbData {
+                endpoint_id: 4,
+                transfer_state_preserve: false,
+                slot_id,
+            }),
+        });
+
+        assert!(first_calls.lock().unwrap().is_empty());
+        assert_eq!(*second_calls.lock().unwrap(), [MockCall::ClearHalt(4)]);
+    }
+
+    #[test]
+    fn configure_endpoint_disables_dropped_endpoints() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        // Drop EP1 IN (D3), keep the slot context (A0).
+        ram.write(Request::new(0x600, RequestSize::Size4), 1 << 3);
+        ram.write(Request::new(0x604, RequestSize::Size4), 0b1);
+
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::ConfigureEndpoint(ConfigureEndpointCommandTrbData {
+                input_context_pointer: 0x600,
+                deconfigure: false,
+                slot_id: 1,
+            }),
+        });
+
+        assert_eq!(*calls.lock().unwrap(), [MockCall::DisableEndpoint(3)]);
+    }
+
+    #[test]
+    fn endpoints_keep_their_pipe_until_a_reset() {
+        let mut lent_pipes = None;
+        let (mut controller, ram, _calls) = controller_with_configured_device(|device, _| {
+            lent_pipes = Some(device.lent_pipes.clone());
+        });
+        let lent_pipes = lent_pipes.unwrap();
+        // EP1 OUT (DCI 2) as Bulk endpoint with its transfer ring at 0xa00.
+        ram.write(Request::new(0x664, RequestSize::Size1), 2 << 3 | 3 << 1);
+        ram.write(Request::new(0x666, RequestSize::Size2), 512);
+        ram.write(Request::new(0x668, RequestSize::Size8), 0xa01);
+        let configure = |controller: &mut XhciController, drop_flags: u64| {
+            ram.write(Request::new(0x600, RequestSize::Size4), drop_flags);
+            // Add DCI 2 (A2) and the slot context (A0).
+            ram.write(Request::new(0x604, RequestSize::Size4), 0b101);
+            assert_eq!(
+                complete_command(
+                    controller,
+                    &ram,
+                    CommandTrbVariant::ConfigureEndpoint(ConfigureEndpointCommandTrbData {
+                        input_context_pointer: 0x600,
+                        deconfigure: false,
+                        slot_id: 1,
+                    }),
+                ),
+                (CompletionCode::Success as u8, 1)
+            );
+        };
+
+        // The driver configures the endpoint, and then drops and adds it
+        // again, which restarts its worker on the same pipe.
+        configure(&mut controller, 0);
+        configure(&mut controller, 1 << 2);
+        assert_eq!(*lent_pipes.lock().unwrap(), [(2, 0), (2, 0)]);
+
+        // Resetting the endpoint resets its toggle, so the next worker
+        // starts on a new pipe.
+        assert_eq!(
+            complete_command(
+                &mut controller,
+                &ram,
+                CommandTrbVariant::ResetEndpoint(ResetEndpointCommandTrbData {
+                    endpoint_id: 2,
+                    transfer_state_preserve: false,
+                    slot_id: 1,
+                }),
+            ),
+            (CompletionCode::Success as u8, 1)
+        );
+        configure(&mut controller, 1 << 2);
+        configure(&mut controller, 1 << 2);
+        assert_eq!(lent_pipes.lock().unwrap()[2..], [(2, 1), (2, 1)]);
+
+        // So does resetting the device.
+        assert_eq!(
+            complete_command(
+                &mut controller,
+                &ram,
+                CommandTrbVariant::ResetDevice(ResetDeviceCommandTrbData { slot_id: 1 }),
+            ),
+            (CompletionCode::Success as u8, 1)
+        );
+        configure(&mut controller, 1 << 2);
+        assert_eq!(lent_pipes.lock().unwrap()[4..], [(2, 2)]);
+    }
+
+    #[test]
+    fn endpoints_the_device_does_not_describe_are_refused() {
+        let mut lent_pipes = None;
+        let (mut controller, ram, _calls) = controller_with_configured_device(|device, _| {
+            lent_pipes = Some(device.lent_pipes.clone());
+        });
+        let lent_pipes = lent_pipes.unwrap();
+        // The mock device has EP1 OUT (DCI 2) as Bulk endpoint, but neither
+        // EP3 OUT (DCI 6) nor EP1 IN (DCI 3) as Bulk endpoint.
+        for (endpoint_id, endpoint_type) in [(6, 2), (3, 6)] {
+            ram.write(Request::new(0x600, RequestSize::Size4), 0);
+            // Add EP1 OUT, the endpoint, and the slot context.
+            ram.write(
+                Request::new(0x604, RequestSize::Size4),
+                1 << endpoint_id | 0b101,
+            );
+            for (endpoint_id, endpoint_type) in [(2, 2), (endpoint_id, endpoint_type)] {
+                let context = 0x620 + endpoint_id * 32;
+                ram.write(
+                    Request::new(context + 4, RequestSize::Size1),
+                    endpoint_type << 3 | 3 << 1,
+                );
+                ram.write(Request::new(context + 6, RequestSize::Size2), 512);
+                ram.write(Request::new(context + 8, RequestSize::Size8), 0xa01);
+            }
+
+            assert_eq!(
+                complete_command(
+                    &mut controller,
+                    &ram,
+                    CommandTrbVariant::ConfigureEndpoint(ConfigureEndpointCommandTrbData {
+                        input_context_pointer: 0x600,
+                        deconfigure: false,
+                        slot_id: 1,
+                    }),
+                ),
+                (CompletionCode::ParameterError as u8, 1)
+            );
+            // The command did not touch the device context.
+            let device_context = controller.device_slot_manager.get_device_context(1);
+            assert_eq!(device_context.enabled_endpoints(), []);
+        }
+        assert!(lent_pipes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stuck_worker_is_a_host_controller_error() {
+        let (mut controller, ram, _calls) =
+            controller_with_stopping_device(|_| MockStop::Unresponsive);
+        let error_line = Arc::new(CountingInterruptLine::default());
+        controller
+            .vmm_signals()
+            .connect_error(Some(error_line.clone()));
+        // Drop EP1 IN (D3), keep the slot context (A0).
+        ram.write(Request::new(0x600, RequestSize::Size4), 1 << 3);
+        ram.write(Request::new(0x604, RequestSize::Size4), 0b1);
+
+        for _ in 0..2 {
+            controller.handle_command(CommandTrb {
+                address: 0x800,
+                variant: CommandTrbVariant::ConfigureEndpoint(ConfigureEndpointCommandTrbData {
+                    input_context_pointer: 0x600,
+                    deconfigure: false,
+                    slot_id: 1,
+                }),
+            });
+        }
+
+        assert_ne!(controller.status() & usbsts::HCE, 0);
+        // The VMM hears about the error once.
+        assert_eq!(error_line.count(), 1);
+
+        controller.run(usbcmd::HCRST);
+        assert_eq!(controller.status() & usbsts::HCE, 0);
+    }
+
+    #[test]
+    fn devices_are_released_on_teardown() {
+        let (controller, _ram, calls) = controller_with_mock_device();
+
+        drop(controller);
+
+        assert_eq!(*calls.lock().unwrap(), [MockCall::Release]);
+    }
+
+    #[test]
+    fn dcbaap_rewrite_releases_slots_in_use() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        // EP3 of slot 1 is running.
+        ram.write(
+            Request::new(3 * 32, RequestSize::Size1),
+            endpoint_state::RUNNING.into(),
+        );
+
+        // The rebound driver sets up a new DCBAA at 0xe00 with the device
+        // context of slot 1 at 0x200.
+        ram.write(Request::new(0xe08, RequestSize::Size8), 0x200);
+        controller.configure_device_contexts(0xe00);
+
+        assert_eq!(*calls.lock().unwrap(), [MockCall::DisableEndpoint(3)]);
+        assert!(!controller.device_slot_manager.has_reserved_slots());
+        assert!(controller.slot_to_port.iter().all(Option::is_none));
+
+        // The driver enumerates the device from scratch.
+        assert_eq!(
+            complete_command(&mut controller, &ram, CommandTrbVariant::EnableSlot),
+            (CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(
+            address_device(&mut controller, &ram, 1, false),
+            CompletionCode::Success as u8
+        );
+        // The new device context holds the USB device address.
+        assert_eq!(ram.read(Request::new(0x200 + 12, RequestSize::Size1)), 1);
+    }
+
+    /// Give the controller of [`controller_with_mock_device`] a second slot.
+    ///
+    /// The device context of the second slot is at 0x200.
+    fn enable_second_slot(controller: &mut XhciController, ram: &TestBusDevice) -> u8 {
+        ram.write(Request::new(0xf10, RequestSize::Size8), 0x200);
+        controller.handle_enable_slot().unwrap().slot_id
+    }
+
+    /// Address a slot with an input context at 0x600 that points to the
+    /// port of the mock device.
+    ///
+    /// Returns the completion code of the command.
+    fn address_device(
+        controller: &mut XhciController,
+        ram: &TestBusDevice,
+        slot_id: u8,
+        block_set_address_request: bool,
+    ) -> u8 {
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        ram.write(Request::new(0x600, RequestSize::Size8), 0x3_0000_0000);
+        ram.write(
+            Request::new(0x600 + 32 + 6, RequestSize::Size1),
+            port_index as u64 + 1,
+        );
+        ram.write(Request::new(0x600 + 64 + 6, RequestSize::Size2), 64);
+        configure_event_ring(controller, ram);
+
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::AddressDevice(AddressDeviceCommandTrbData {
+                input_context_pointer: 0x600,
+                block_set_address_request,
+                slot_id,
+            }),
+        });
+
+        let (trb_type, completion_code) = event_type_and_code(ram, 0x500);
+        assert_eq!(trb_type, trb_types::COMMAND_COMPLETION_EVENT);
+        completion_code
+    }
+
+    /// Handle a single command and return the Completion Code and Slot ID
+    /// of its Command Completion Event.
+    fn complete_command(
+        controller: &mut XhciController,
+        ram: &TestBusDevice,
+        variant: CommandTrbVariant,
+    ) -> (u8, u8) {
+        configure_event_ring(controller, ram);
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant,
+        });
+
+        let (trb_type, completion_code) = event_type_and_code(ram, 0x500);
+        assert_eq!(trb_type, trb_types::COMMAND_COMPLETION_EVENT);
+        let slot_id = ram.read(Request::new(0x500 + 15, RequestSize::Size1));
+        (completion_code, slot_id as u8)
+    }
+
+    #[test]
+    fn commands_for_disabled_slots_fail() {
+        for variant in [
+            CommandTrbVariant::DisableSlot(DisableSlotCommandTrbData { slot_id: 5 }),
+            CommandTrbVariant::ResetDevice(ResetDeviceCommandTrbData { slot_id: 5 }),
+            CommandTrbVariant::EvaluateContext(EvaluateContextCommandTrbData {
+                input_context_pointer: 0x600,
+                slot_id: 5,
+            }),
+            CommandTrbVariant::StopEndpoint(StopEndpointCommandTrbData {
+                endpoint_id: 3,
+                slot_id: 5,
+            }),
+            CommandTrbVariant::ResetEndpoint(ResetEndpointCommandTrbData {
+                endpoint_id: 3,
+                transfer_state_preserve: false,
+                slot_id: 5,
+            }),
+        ] {
+            let (mut controller, ram, calls) = controller_with_mock_device();
+
+            assert_eq!(
+                complete_command(&mut controller, &ram, variant),
+                (CompletionCode::SlotNotEnabledError as u8, 5)
+            );
+            assert!(calls.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn endpoint_commands_for_unaddressed_slots_fail() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        controller.slot_to_port = [None; MAX_SLOTS as usize];
+
+        assert_eq!(
+            complete_command(
+                &mut controller,
+                &ram,
+                CommandTrbVariant::StopEndpoint(StopEndpointCommandTrbData {
+                    endpoint_id: 3,
+                    slot_id: 1,
+                })
+            ),
+            (CompletionCode::ContextStateError as u8, 1)
+        );
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stop_endpoint_for_slot_context_fails() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+
+        assert_eq!(
+            complete_command(
+                &mut controller,
+                &ram,
+                CommandTrbVariant::StopEndpoint(StopEndpointCommandTrbData {
+                    endpoint_id: 0,
+                    slot_id: 1,
+                })
+            ),
+            (CompletionCode::ParameterError as u8, 1)
+        );
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn address_device_with_unexpected_context_flags_fails() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        // A0, A1, and A2 instead of only A0 and A1.
+        ram.write(Request::new(0x600, RequestSize::Size8), 0x7_0000_0000);
+
+        assert_eq!(
+            complete_command(
+                &mut controller,
+                &ram,
+                CommandTrbVariant::AddressDevice(AddressDeviceCommandTrbData {
+                    input_context_pointer: 0x600,
+                    block_set_address_request: false,
+                    slot_id: 1,
+                }),
+            ),
+            (CompletionCode::ParameterError as u8, 1)
+        );
+    }
+
+    #[test]
+    fn enable_slot_fails_without_free_slots() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        for _ in 1..MAX_SLOTS {
+            controller.handle_enable_slot().unwrap();
+        }
+
+        assert_eq!(
+            complete_command(&mut controller, &ram, CommandTrbVariant::EnableSlot),
+            (CompletionCode::NoSlotsAvailableError as u8, 0)
+        );
+    }
+
+    #[test]
+    fn unsupported_commands_fail_with_trb_error() {
+        for (variant, slot_id) in [
+            (CommandTrbVariant::ForceHeader, 0),
+            (
+                CommandTrbVariant::SetTrDequeuePointer(SetTrDequeuePointerCommandTrbData {
+                    dequeue_pointer: 0x600,
+                    dequeue_cycle_state: true,
+                    stream_id: 0,
+                    endpoint_id: 3,
+                    slot_id: 1,
+                }),
+                1,
+            ),
+            (
+                CommandTrbVariant::ConfigureEndpoint(ConfigureEndpointCommandTrbData {
+                    input_context_pointer: 0x600,
+                    deconfigure: true,
+                    slot_id: 1,
+                }),
+                1,
+            ),
+        ] {
+            let (mut controller, ram, _calls) = controller_with_mock_device();
+            assert_eq!(
+                complete_command(&mut controller, &ram, variant),
+                (CompletionCode::TrbError as u8, slot_id)
+            );
+        }
+
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        assert_eq!(
+            complete_command(&mut controller, &ram, CommandTrbVariant::NoOp),
+            (CompletionCode::Success as u8, 0)
+        );
+    }
+
+    /// The USB device address and slot state in the slot context at
+    /// `device_context`.
+    fn usb_address_and_slot_state(ram: &TestBusDevice, device_context: u64) -> (u8, u8) {
+        let dword3 = ram.read(Request::new(device_context + 12, RequestSize::Size4));
+        (dword3 as u8, (dword3 >> 27) as u8)
+    }
+
+    #[test]
+    fn address_device_assigns_usb_addresses() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        let second_slot_id = enable_second_slot(&mut controller, &ram);
+
+        assert_eq!(
+            address_device(&mut controller, &ram, 1, false),
+            CompletionCode::Success as u8
+        );
+        assert_eq!(
+            address_device(&mut controller, &ram, second_slot_id, false),
+            CompletionCode::Success as u8
+        );
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x0),
+            (1, slot_state::ADDRESSED)
+        );
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x200),
+            (2, slot_state::ADDRESSED)
+        );
+
+        // Disabling slot 1 frees its address for the next device.
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::DisableSlot(DisableSlotCommandTrbData { slot_id: 1 }),
+        });
+        controller.handle_command(CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::ResetDevice(ResetDeviceCommandTrbData {
+                slot_id: second_slot_id,
+            }),
+        });
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x200),
+            (0, slot_state::DEFAULT)
+        );
+
+        address_device(&mut controller, &ram, second_slot_id, false);
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x200),
+            (1, slot_state::ADDRESSED)
+        );
+    }
+
+    #[test]
+    fn address_device_with_bsr_keeps_default_address() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+
+        address_device(&mut controller, &ram, 1, true);
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x0),
+            (0, slot_state::DEFAULT)
+        );
+
+        address_device(&mut controller, &ram, 1, false);
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x0),
+            (1, slot_state::ADDRESSED)
+        );
+    }
+
+    #[test]
+    fn controller_reset_releases_usb_addresses() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        let second_slot_id = enable_second_slot(&mut controller, &ram);
+        address_device(&mut controller, &ram, 1, false);
+
+        controller.run(usbcmd::HCRST);
+
+        address_device(&mut controller, &ram, second_slot_id, false);
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x200),
+            (1, slot_state::ADDRESSED)
+        );
+    }
+
+    #[test]
+    fn light_reset_keeps_ports_and_usb_addresses() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        let second_slot_id = enable_second_slot(&mut controller, &ram);
+        address_device(&mut controller, &ram, 1, false);
+        controller.run(usbcmd::RS);
+        let connected = controller.portsc[port_index].read();
+
+        controller.run(usbcmd::LHCRST);
+
+        assert_eq!(
+            controller.status() & (usbsts::HCH | usbsts::CNR | usbsts::PCD),
+            usbsts::HCH | usbsts::PCD
+        );
+        assert_eq!(controller.portsc[port_index].read(), connected);
+        assert!(calls.lock().unwrap().is_empty());
+        // Slot 1 still holds the first USB device address.
+        address_device(&mut controller, &ram, second_slot_id, false);
+        assert_eq!(
+            usb_address_and_slot_state(&ram, 0x200),
+            (2, slot_state::ADDRESSED)
+        );
+    }
+
+    #[test]
+    fn halted_controller_keeps_its_workers() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        // EP3 of slot 1 is running.
+        ram.write(
+            Request::new(3 * 32, RequestSize::Size1),
+            endpoint_state::RUNNING.into(),
+        );
+        controller.run(usbcmd::RS);
+        assert_eq!(controller.status() & usbsts::HCH, 0);
+
+        controller.halt();
+        assert_eq!(
+            controller.status() & (usbsts::HCH | usbsts::CNR),
+            usbsts::HCH
+        );
+        assert!(controller.device_slot_manager.is_reserved(1));
+
+        controller.resume();
+        assert_eq!(controller.status() & usbsts::HCH, 0);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn function_reset_releases_slots_and_reconnects_ports() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        configure_event_ring(&controller, &ram);
+        // EP3 of slot 1 is running.
+        ram.write(
+            Request::new(3 * 32, RequestSize::Size1),
+            endpoint_state::RUNNING.into(),
+        );
+        controller.run(usbcmd::RS);
+        // The driver acknowledged the connection.
+        let connected = controller.portsc[port_index].read();
+        controller.write_portsc(port_index, connected);
+        controller.write_usbsts(usbsts::PCD);
+
+        controller.reset(ResetKind::Function);
+
+        assert_eq!(
+            controller.status() & (usbsts::HCH | usbsts::CNR | usbsts::HCE),
+            usbsts::HCH
+        );
+        // The workers exit once, before the slots are gone.
+        assert_eq!(*calls.lock().unwrap(), [MockCall::DisableEndpoint(3)]);
+        assert!(!controller.device_slot_manager.has_reserved_slots());
+        assert!(controller.slot_to_port.iter().all(Option::is_none));
+        assert_eq!(
+            controller.portsc[port_index].read() & (portsc::CCS | portsc::PP | portsc::CSC),
+            portsc::CCS | portsc::PP | portsc::CSC
+        );
+
+        // The driver finds the device once it set up the controller again.
+        ram.write(Request::new(0x500, RequestSize::Size8), 0);
+        ram.write(Request::new(0x508, RequestSize::Size8), 0);
+        configure_event_ring(&controller, &ram);
+        controller.run(usbcmd::RS);
+        assert_eq!(
+            port_events(&ram, 1),
+            [(trb_types::PORT_STATUS_CHANGE_EVENT, port_index as u64 + 1)]
+        );
+    }
+
+    #[test]
+    fn function_reset_shuts_down_workers_first() {
+        let (mut controller, ram, calls) =
+            controller_with_stopping_device(|_| MockStop::Unresponsive);
+        let error_line = Arc::new(CountingInterruptLine::default());
+        controller
+            .vmm_signals()
+            .connect_error(Some(error_line.clone()));
+        ram.write(
+            Request::new(3 * 32, RequestSize::Size1),
+            endpoint_state::RUNNING.into(),
+        );
+
+        controller.reset(ResetKind::Function);
+
+        assert_eq!(*calls.lock().unwrap(), [MockCall::DisableEndpoint(3)]);
+        // The worker did not exit, which the reset must not hide.
+        assert_ne!(controller.status() & usbsts::HCE, 0);
+        assert_eq!(error_line.count(), 1);
+    }
+
+    /// Give the controller of [`controller_with_stopping_device`] an Event
+    /// Ring with a single segment of 16 TRBs at 0x500.
+    fn configure_event_ring(controller: &XhciController, ram: &TestBusDevice) {
+        ram.write_bulk(
+            0x400,
+            &[0x00, 0x05, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let mut ring = controller.event_sink.event_ring();
+        ring.set_erst_size(1);
+        ring.configure(0x400);
+        ring.update_dequeue_pointer(0x500);
+    }
+
+    #[test]
+    fn process_commands_drains_the_command_ring() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        // Two Enable Slot Commands with the cycle bit set, then a TRB the
+        // driver has not handed over yet.
+        for address in [0xa00, 0xa10] {
+            ram.write_bulk(
+                address,
+                &[
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    1,
+                    trb_types::ENABLE_SLOT_COMMAND << 2,
+                    0,
+                    0,
+                ],
+            );
+        }
+        controller.command_ring.control(0xa00 | 1);
+
+        controller.process_commands();
+
+        for (event_address, slot_id) in [(0x500, 2), (0x510, 3)] {
+            assert_eq!(
+                event_type_and_code(&ram, event_address),
+                (
+                    trb_types::COMMAND_COMPLETION_EVENT,
+                    CompletionCode::Success as u8
+                )
+            );
+            assert_eq!(
+                ram.read(Request::new(event_address + 15, RequestSize::Size1)),
+                slot_id
+            );
+        }
+        assert_eq!(event_type_and_code(&ram, 0x520), (0, 0));
+
+        // Nothing new on the ring, so nothing happens.
+        controller.process_commands();
+        assert_eq!(event_type_and_code(&ram, 0x520), (0, 0));
+    }
+
+    fn stop_endpoint_command(endpoint_id: u8) -> CommandTrb {
+        CommandTrb {
+            address: 0x800,
+            variant: CommandTrbVariant::StopEndpoint(StopEndpointCommandTrbData {
+                endpoint_id,
+                slot_id: 1,
+            }),
+        }
+    }
+
+    /// The TRB Type and Completion Code of the event TRB at `address`.
+    fn event_type_and_code(ram: &TestBusDevice, address: u64) -> (u8, u8) {
+        let trb_type = ram.read(Request::new(address + 13, RequestSize::Size1)) >> 2;
+        let completion_code = ram.read(Request::new(address + 11, RequestSize::Size1));
+        (trb_type as u8, completion_code as u8)
+    }
+
+    #[test]
+    fn stopped_transfer_is_reported_before_stop_endpoint_completes() {
+        let (mut controller, ram, calls) =
+            controller_with_stopping_device(|event_sink| MockStop::InFlight {
+                event_sink,
+                slot_id: 1,
+                trb_address: 0xa00,
+            });
+        configure_event_ring(&controller, &ram);
+
+        controller.handle_command(stop_endpoint_command(3));
+
+        assert_eq!(*calls.lock().unwrap(), [MockCall::StopEndpoint(3)]);
+        assert_eq!(
+            event_type_and_code(&ram, 0x500),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Stopped as u8)
+        );
+        assert_eq!(ram.read(Request::new(0x500, RequestSize::Size8)), 0xa00);
+        assert_eq!(
+            event_type_and_code(&ram, 0x510),
+            (
+                trb_types::COMMAND_COMPLETION_EVENT,
+                CompletionCode::Success as u8
+            )
+        );
+        assert_eq!(
+            ram.read(Request::new(3 * 32, RequestSize::Size1)),
+            u64::from(endpoint_state::STOPPED)
+        );
+    }
+
+    #[test]
+    fn unacknowledged_stop_fails_stop_endpoint_command() {
+        let (mut controller, ram, _calls) =
+            controller_with_stopping_device(|_| MockStop::Unresponsive);
+        configure_event_ring(&controller, &ram);
+        let endpoint_state_address = 3 * 32;
+        ram.write(
+            Request::new(endpoint_state_address, RequestSize::Size1),
+            endpoint_state::RUNNING.into(),
+        );
+
+        controller.handle_command(stop_endpoint_command(3));
+
+        assert_eq!(
+            event_type_and_code(&ram, 0x500),
+            (
+                trb_types::COMMAND_COMPLETION_EVENT,
+                CompletionCode::ContextStateError as u8
+            )
+        );
+        assert_eq!(
+            ram.read(Request::new(endpoint_state_address, RequestSize::Size1)),
+            u64::from(endpoint_state::RUNNING)
+        );
+    }
+
+    /// Create a controller whose Event Ring has a single segment of 16 TRBs
+    /// at 0x100 and whose Interrupter is enabled.
+    fn controller_with_event_ring() -> (Mutex<XhciController>, Arc<CountingInterruptLine>) {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        ram.write_bulk(
+            0x0,
+            &[0x00, 0x01, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let controller = Mutex::new(XhciController::new(ram, ControllerConfig::default()));
+        let line = Arc::new(CountingInterruptLine::default());
+        controller.lock().unwrap().connect_irq(line.clone());
+
+        for (offset, value) in [
+            (offset::ERSTSZ, 1),
+            (offset::ERSTBA, 0x0),
+            (offset::ERDP, 0x100),
+            (offset::IMAN, runtime::iman::IE),
+        ] {
+            controller.write_io(0, Request::new(offset, RequestSize::Size4), value);
+        }
+
+        (controller, line)
+    }
+
+    fn post_event(controller: &Mutex<XhciController>) {
+        controller
+            .lock()
+            .unwrap()
+            .event_sink
+            .post(EventTrb::new_port_status_change_event_trb(1));
+    }
+
+    fn read_pba(controller: &Mutex<XhciController>) -> u64 {
+        controller.read_io(
+            msix_bar::NUMBER.into(),
+            Request::new(msix_bar::PBA_OFFSET.into(), RequestSize::Size8),
+        )
+    }
+
+    fn write_vector_0_control(controller: &Mutex<XhciController>, control: u32) {
+        controller.write_io(
+            msix_bar::NUMBER.into(),
+            Request::new(msix_table::offset::CONTROL as u64, RequestSize::Size4),
+            control.into(),
+        );
+    }
+
+    #[test]
+    fn zero_erstsz_unconfigures_the_event_ring() {
+        let (controller, line) = controller_with_event_ring();
+        // Linux writes ERSTSZ = 0 when the driver unbinds, and may leave
+        // garbage in the reserved bits of ERSTBA.
+        for (offset, value) in [(offset::ERSTSZ, 0), (offset::ERSTBA, 0x3f)] {
+            controller.write_io(0, Request::new(offset, RequestSize::Size4), value);
+        }
+        assert_eq!(
+            controller.read_io(0, Request::new(offset::ERSTSZ, RequestSize::Size4)),
+            0
+        );
+        post_event(&controller);
+        assert_eq!(line.count(), 0);
+    }
+
+    #[test]
+    fn masked_vector_sets_pending_bit_until_unmasked() {
+        let (controller, line) = controller_with_event_ring();
+        post_event(&controller);
+        assert_eq!(line.count(), 1);
+        assert_eq!(read_pba(&controller), 0);
+
+        write_vector_0_control(&controller, CONTROL_MASKED);
+        post_event(&controller);
+        post_event(&controller);
+        assert_eq!(line.count(), 1);
+        assert_eq!(read_pba(&controller), 0b1);
+
+        // The PBA is read-only.
+        controller.write_io(
+            msix_bar::NUMBER.into(),
+            Request::new(msix_bar::PBA_OFFSET.into(), RequestSize::Size8),
+            0,
+        );
+        assert_eq!(read_pba(&controller), 0b1);
+
+        write_vector_0_control(&controller, 0);
+        assert_eq!(line.count(), 2);
+        assert_eq!(read_pba(&controller), 0);
+
+        post_event(&controller);
+        assert_eq!(line.count(), 3);
+    }
+
+    #[test]
+    fn function_mask_holds_back_interrupts() {
+        let (controller, line) = controller_with_event_ring();
+        let msix_ptr = controller
+            .lock()
+            .unwrap()
+            .config_space
+            .iter_capability_offsets()
+            .next()
+            .unwrap();
+        let control_offset = u64::from(msix_ptr) + msix_cap::CONTROL;
+        let write_control = |value: u16| {
+            controller.write_cfg(
+                Request::new(control_offset, RequestSize::Size2),
+                value.into(),
+            );
+        };
+
+        write_control(control::ENABLE | control::FUNCTION_MASK);
+        post_event(&controller);
+        assert_eq!(line.count(), 0);
+        assert_eq!(read_pba(&controller), 0b1);
+
+        write_control(control::ENABLE);
+        assert_eq!(line.count(), 1);
+        assert_eq!(read_pba(&controller), 0);
+    }
+
+    #[test]
+    fn register_accesses_are_profiled() {
+        let (controller, _line) = controller_with_event_ring();
+        let profile = controller.lock().unwrap().mmio_profile();
+        // The profile is disabled by default.
+        assert!(profile.summary().is_empty());
+
+        profile.enable();
+        for _ in 0..3 {
+            let _ = controller.read_io(0, Request::new(offset::USBSTS, RequestSize::Size4));
+        }
+        for _ in 0..2 {
+            controller.write_io(0, Request::new(offset::IMAN, RequestSize::Size4), 0);
+        }
+        read_pba(&controller);
+
+        let counts: Vec<_> = profile
+            .summary()
+            .iter()
+            .map(|p| (p.offset, p.reads, p.writes))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (Some(offset::USBSTS), 3, 0),
+                (Some(offset::IMAN), 0, 2),
+                (None, 1, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn doorbells_for_unaddressed_slots_are_ignored() {
+        let (mut controller, _ram, calls) = controller_with_mock_device();
+        // Slot 1 is enabled but not addressed, the others are not even
+        // enabled.
+        controller.slot_to_port = [None; MAX_SLOTS as usize];
+
+        for slot_id in [1, 2, MAX_SLOTS as u8] {
+            for endpoint_id in [1, 3] {
+                controller.doorbell_device(slot_id, endpoint_id);
+            }
+        }
+
+        assert!(calls.lock().unwrap().is_empty());
+        assert_eq!(controller.ignored_doorbells(), 6);
+    }
+
+    /// Start the controller without reporting the port changes that
+    /// attaching the device caused.
+    fn start_without_port_events(controller: &mut XhciController) {
+        assert_eq!(controller.lifecycle.resume()[0], Step::StartWorkers);
+        controller.carry_out(&[Step::StartWorkers]);
+    }
+
+    #[test]
+    fn invalid_doorbell_values_are_counted_and_ignored() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        start_without_port_events(&mut controller);
+        let controller = Mutex::new(controller);
+        let write = |addr, value| {
+            controller.write_io(0, Request::new(addr, RequestSize::Size4), value);
+        };
+
+        // DB Target 0 is reserved for device doorbells, as is everything
+        // beyond the last DCI. The stream ID must not hide the target.
+        write(offset::DOORBELL_DEVICE, 0);
+        write(offset::DOORBELL_DEVICE, 32);
+        write(offset::DOORBELL_DEVICE, 0xffff_00ff);
+        // The Host Controller Doorbell only takes DB Target 0.
+        write(offset::DOORBELL_CONTROLLER, 1);
+        // Slot 2 is not enabled.
+        write(offset::DOORBELL_DEVICE + 4, 1);
+
+        assert_eq!(controller.lock().unwrap().ignored_doorbells(), 5);
+        assert!(calls.lock().unwrap().is_empty());
+        assert_eq!(event_type_and_code(&ram, 0x500), (0, 0));
+    }
+
+    #[test]
+    fn power_cycle_of_occupied_port_reconnects_device() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        start_without_port_events(&mut controller);
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        // EP3 of slot 1 is running.
+        ram.write(
+            Request::new(3 * 32, RequestSize::Size1),
+            endpoint_state::RUNNING.into(),
+        );
+
+        let powered_on = controller.portsc[port_index].read();
+        controller.write_portsc(port_index, powered_on & !portsc::PP);
+
+        assert_eq!(controller.portsc[port_index].read(), 0);
+        assert_eq!(*calls.lock().unwrap(), [MockCall::DisableEndpoint(3)]);
+        // The slot takes no doorbells. Otherwise, this would panic on the
+        // empty control transfer ring.
+        controller.doorbell_device(1, 1);
+        assert_eq!(event_type_and_code(&ram, 0x500), (0, 0));
+
+        controller.write_portsc(port_index, portsc::PP);
+
+        let connected = controller.portsc[port_index].read();
+        assert_eq!(
+            connected & (portsc::CCS | portsc::PP | portsc::CSC),
+            portsc::CCS | portsc::PP | portsc::CSC
+        );
+        assert_eq!(
+            event_type_and_code(&ram, 0x500),
+            (
+                trb_types::PORT_STATUS_CHANGE_EVENT,
+                CompletionCode::Success as u8
+            )
+        );
+        assert_eq!(
+            ram.read(Request::new(0x503, RequestSize::Size1)),
+            port_index as u64 + 1
+        );
+    }
+
+    /// The types and port IDs of the first `count` events on the Event
+    /// Ring of [`configure_event_ring`].
+    fn port_events(ram: &TestBusDevice, count: u64) -> Vec<(u8, u64)> {
+        (0..count)
+            .map(|index| {
+                let address = 0x500 + index * 16;
+                (
+                    event_type_and_code(ram, address).0,
+                    ram.read(Request::new(address + 3, RequestSize::Size1)),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn port_status_changes_wait_for_the_driver_to_acknowledge() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        let port_id = port_index as u64 + 1;
+
+        // The connection of the device is reported once the controller
+        // runs, and reconnecting before the driver looked adds nothing.
+        controller.run(usbcmd::RS);
+        controller.announce_connection(port_index, Speed::High);
+        controller.announce_connection(port_index, Speed::High);
+        assert_eq!(
+            port_events(&ram, 2),
+            [(trb_types::PORT_STATUS_CHANGE_EVENT, port_id), (0, 0)]
+        );
+
+        // Resetting the port adds nothing either, and clearing only some
+        // change bits leaves the event pending.
+        let status = controller.portsc[port_index].read() & !portsc::CHANGE_BITS;
+        controller.write_portsc(port_index, status | portsc::PR);
+        controller.write_portsc(port_index, status | portsc::CSC);
+        assert_ne!(controller.portsc[port_index].read() & portsc::PRC, 0);
+        controller.announce_connection(port_index, Speed::High);
+        assert_eq!(port_events(&ram, 2)[1], (0, 0));
+
+        let status = controller.portsc[port_index].read();
+        controller.write_portsc(port_index, status);
+        assert_eq!(
+            controller.portsc[port_index].read() & portsc::CHANGE_BITS,
+            0
+        );
+        controller.announce_connection(port_index, Speed::High);
+        assert_eq!(
+            port_events(&ram, 3),
+            [
+                (trb_types::PORT_STATUS_CHANGE_EVENT, port_id),
+                (trb_types::PORT_STATUS_CHANGE_EVENT, port_id),
+                (0, 0)
+            ]
+        );
+    }
+
+    /// Place an Enable Slot Command at 0xa00 on the Command Ring.
+    fn queue_enable_slot_command(controller: &mut XhciController, ram: &TestBusDevice) {
+        ram.write_bulk(0xa08, &[0, 0, 0, 0, 1, trb_types::ENABLE_SLOT_COMMAND << 2]);
+        controller.command_ring.control(0xa00 | 1);
+    }
+
+    #[test]
+    fn commands_queued_while_stopped_run_once_started() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        controller.run(usbcmd::RS);
+        controller.run(0);
+        assert_ne!(controller.status() & usbsts::HCH, 0);
+        // The Port Status Change Event of the mock device.
+        assert_eq!(
+            event_type_and_code(&ram, 0x500).0,
+            trb_types::PORT_STATUS_CHANGE_EVENT
+        );
+
+        queue_enable_slot_command(&mut controller, &ram);
+        let controller = Mutex::new(controller);
+        let write = |addr, value| {
+            controller.write_io(0, Request::new(addr, RequestSize::Size4), value);
+        };
+        write(offset::DOORBELL_CONTROLLER, 0);
+        assert_eq!(event_type_and_code(&ram, 0x510), (0, 0));
+
+        write(offset::USBCMD, usbcmd::RS);
+        assert_eq!(
+            event_type_and_code(&ram, 0x510),
+            (
+                trb_types::COMMAND_COMPLETION_EVENT,
+                CompletionCode::Success as u8
+            )
+        );
+        assert_eq!(controller.lock().unwrap().status() & usbsts::HCH, 0);
+    }
+
+    #[test]
+    fn control_doorbell_while_stopped_is_served_once_started() {
+        let (controller, ram, calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        // SET_CONFIGURATION(1) on the control transfer ring of slot 1 at
+        // 0xc00: a Setup Stage and a Status Stage with IOC.
+        ram.write_bulk(0x28, &0xc01u64.to_le_bytes());
+        ram.write_bulk(0xc00, &[0x00, 0x09, 0x01, 0, 0, 0, 0, 0, 8]);
+        ram.write_bulk(
+            0xc0c,
+            &(u32::from(trb_types::SETUP_STAGE) << 10 | 1 << 6 | 1).to_le_bytes(),
+        );
+        ram.write_bulk(
+            0xc1c,
+            &(u32::from(trb_types::STATUS_STAGE) << 10 | 1 << 16 | 1 << 5 | 1).to_le_bytes(),
+        );
+        let controller = Mutex::new(controller);
+        let write = |addr, value| {
+            controller.write_io(0, Request::new(addr, RequestSize::Size4), value);
+        };
+
+        // The stopped controller leaves the control transfer ring alone.
+        write(offset::DOORBELL_DEVICE, 1);
+        assert!(calls.lock().unwrap().is_empty());
+        assert_eq!(event_type_and_code(&ram, 0x500), (0, 0));
+
+        write(offset::USBCMD, usbcmd::RS);
+        assert_eq!(*calls.lock().unwrap(), [MockCall::ControlTransfer(0x09)]);
+        assert_eq!(
+            event_type_and_code(&ram, 0x510),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8)
+        );
+
+        // Stopping and starting again replays nothing.
+        write(offset::USBCMD, 0);
+        write(offset::USBCMD, usbcmd::RS);
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn port_status_change_waits_for_the_event_ring() {
+        let (controller, ram, _calls) = controller_with_mock_device();
+        let controller = Mutex::new(controller);
+        let write = |addr, value| {
+            controller.write_io(0, Request::new(addr, RequestSize::Size4), value);
+        };
+        let mut memory = [0; 0x1000];
+        ram.read_bulk(0, &mut memory);
+
+        // The driver starts the controller before it set up the
+        // Interrupter, so the Port Status Change Event of the mock device
+        // has nowhere to go.
+        write(offset::USBCMD, usbcmd::RS);
+        let mut after = [0; 0x1000];
+        ram.read_bulk(0, &mut after);
+        assert_eq!(after, memory, "event written to unconfigured ring");
+
+        ram.write_bulk(
+            0x400,
+            &[0x00, 0x05, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0],
+        );
+        write(offset::ERSTSZ, 1);
+        write(offset::ERDP, 0x500);
+        write(offset::ERSTBA, 0x400);
+        assert_eq!(
+            event_type_and_code(&ram, 0x500).0,
+            trb_types::PORT_STATUS_CHANGE_EVENT
+        );
+        assert_eq!(event_type_and_code(&ram, 0x510), (0, 0));
+    }
+
+    #[test]
+    fn usbsts_bits_are_cleared_by_writing_one() {
+        let (controller, ram, _calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        let controller = Mutex::new(controller);
+        let write = |addr, value| {
+            controller.write_io(0, Request::new(addr, RequestSize::Size4), value);
+        };
+        let usbsts = || controller.read_io(0, Request::new(offset::USBSTS, RequestSize::Size4));
+
+        // The driver resets the controller, which forgets the attachment
+        // of the mock device.
+        write(offset::USBCMD, usbcmd::HCRST);
+        configure_event_ring(&controller.lock().unwrap(), &ram);
+        assert_eq!(usbsts() & (usbsts::EINT | usbsts::PCD), 0);
+        // Starting the controller reports the mock device.
+        write(offset::IMAN, runtime::iman::IE);
+        write(offset::USBCMD, usbcmd::RS);
+        assert_eq!(
+            usbsts() & (usbsts::EINT | usbsts::PCD),
+            usbsts::EINT | usbsts::PCD
+        );
+
+        // Writing zeros changes nothing.
+        write(offset::USBSTS, 0);
+        assert_eq!(
+            usbsts() & (usbsts::EINT | usbsts::PCD),
+            usbsts::EINT | usbsts::PCD
+        );
+
+        write(offset::USBSTS, usbsts::EINT);
+        assert_eq!(usbsts() & (usbsts::EINT | usbsts::PCD), usbsts::PCD);
+        write(offset::USBSTS, usbsts::PCD);
+        assert_eq!(usbsts() & (usbsts::EINT | usbsts::PCD), 0);
+        // Read-only bits ignore the write.
+        write(offset::USBSTS, usbsts::HCH);
+        assert_eq!(usbsts() & usbsts::HCH, 0);
+    }
+
+    #[test]
+    fn port_changes_set_pcd_until_the_driver_clears_it() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+
+        // Attaching the device changed its port, although the halted
+        // controller did not report it yet.
+        assert_ne!(controller.status() & usbsts::PCD, 0);
+        controller.write_usbsts(usbsts::PCD);
+        assert_eq!(controller.status() & usbsts::PCD, 0);
+
+        // Power cycling the port connects the device again.
+        start_without_port_events(&mut controller);
+        let powered_on = controller.portsc[port_index].read();
+        controller.write_portsc(port_index, powered_on & !portsc::PP);
+        controller.write_portsc(port_index, portsc::PP);
+        assert_ne!(controller.status() & usbsts::PCD, 0);
+
+        // Acknowledging the port changes or clearing other bits keeps PCD.
+        let changed = controller.portsc[port_index].read();
+        controller.write_portsc(port_index, changed);
+        controller.write_usbsts(usbsts::EINT);
+        assert_ne!(controller.status() & usbsts::PCD, 0);
+
+        controller.write_usbsts(usbsts::PCD);
+        assert_eq!(controller.status() & usbsts::PCD, 0);
+    }
+
+    #[test]
+    fn connected_usb2_port_is_enabled_by_a_reset() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        controller.run(usbcmd::RS);
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        let port_id = port_index as u64 + 1;
+        let portsc_addr = offset::PORTSC + port_index as u64 * offset::PORT_STRIDE;
+        let controller = Mutex::new(controller);
+        let read = || controller.read_io(0, Request::new(portsc_addr, RequestSize::Size4)) as u32;
+        let write = |value: u32| {
+            controller.write_io(
+                0,
+                Request::new(portsc_addr, RequestSize::Size4),
+                value.into(),
+            );
+        };
+
+        // The device shows up on a disabled port.
+        let connected = read();
+        assert_eq!(
+            connected & (portsc::CCS | portsc::PED | portsc::CHANGE_BITS),
+            portsc::CCS | portsc::CSC
+        );
+        write(connected);
+
+        // Resetting the port enables it.
+        write(portsc::PP | portsc::PR);
+        let enabled = read();
+        assert_eq!(
+            enabled & (portsc::CCS | portsc::PED | portsc::PR | portsc::CHANGE_BITS),
+            portsc::CCS | portsc::PED | portsc::PRC
+        );
+        assert_eq!(
+            port_events(&ram, 3),
+            [
+                (trb_types::PORT_STATUS_CHANGE_EVENT, port_id),
+                (trb_types::PORT_STATUS_CHANGE_EVENT, port_id),
+                (0, 0)
+            ]
+        );
+        write(enabled);
+        assert_eq!(read() & portsc::CHANGE_BITS, 0);
+    }
+
+    #[test]
+    fn connected_usb3_port_is_enabled_right_away() {
+        let (mut controller, ram, _calls) =
+            controller_with_configured_device(|device, _| device.speed = Speed::Super);
+        configure_event_ring(&controller, &ram);
+        controller.run(usbcmd::RS);
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        assert!(matches!(
+            XhciController::port_index_to_id(port_index),
+            Some((UsbVersion::USB3, _))
+        ));
+        let port_id = port_index as u64 + 1;
+        let portsc_addr = offset::PORTSC + port_index as u64 * offset::PORT_STRIDE;
+        let controller = Mutex::new(controller);
+        let read = || controller.read_io(0, Request::new(portsc_addr, RequestSize::Size4)) as u32;
+        let write = |value: u32| {
+            controller.write_io(
+                0,
+                Request::new(portsc_addr, RequestSize::Size4),
+                value.into(),
+            );
+        };
+
+        // The device shows up on an enabled port in U0, without a reset.
+        let connected = read();
+        assert_eq!(
+            connected & (portsc::CCS | portsc::PED | portsc::PLS | portsc::CHANGE_BITS),
+            portsc::CCS | portsc::PED | portsc::link_state::U0 << portsc::PLS_SHIFT | portsc::CSC
+        );
+        write(connected);
+
+        // A Hot Reset completes with PRC and leaves the port enabled.
+        write(portsc::PP | portsc::PR);
+        assert_eq!(
+            read() & (portsc::CCS | portsc::PED | portsc::PLS | portsc::PR | portsc::CHANGE_BITS),
+            portsc::CCS | portsc::PED | portsc::link_state::U0 << portsc::PLS_SHIFT | portsc::PRC
+        );
+        assert_eq!(
+            port_events(&ram, 3),
+            [
+                (trb_types::PORT_STATUS_CHANGE_EVENT, port_id),
+                (trb_types::PORT_STATUS_CHANGE_EVENT, port_id),
+                (0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn restarting_the_controller_does_not_repeat_port_status_changes() {
+        let (mut controller, ram, _calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+
+        controller.run(usbcmd::RS);
+        // Writes that keep the controller running start nothing.
+        controller.run(usbcmd::RS | usbcmd::INTE);
+        controller.run(0);
+        controller.run(usbcmd::RS);
+
+        assert_eq!(
+            port_events(&ram, 2),
+            [
+                (trb_types::PORT_STATUS_CHANGE_EVENT, port_index as u64 + 1),
+                (0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn power_cycle_of_empty_port_shows_no_device() {
+        let (mut controller, ram, calls) = controller_with_mock_device();
+        configure_event_ring(&controller, &ram);
+        start_without_port_events(&mut controller);
+        let port_index = controller.devices.iter().position(Option::is_none).unwrap();
+
+        controller.write_portsc(port_index, 0);
+        assert_eq!(controller.portsc[port_index].read(), 0);
+
+        controller.write_portsc(port_index, portsc::PP);
+        assert_eq!(controller.portsc[port_index].read(), portsc::PP);
+
+        assert_eq!(event_type_and_code(&ram, 0x500), (0, 0));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hcsparams3_reports_exit_latencies() {
+        let (controller, _ram, _calls) = controller_with_mock_device();
+        let controller = Mutex::new(controller);
+
+        let hcsparams3 =
+            controller.read_io(0, Request::new(offset::HCSPARAMS3, RequestSize::Size4));
+
+        assert_ne!(hcsparams3 & 0xff, 0, "U1 Device Exit Latency");
+        assert!(hcsparams3 & 0xff <= 0xa);
+        assert_ne!(hcsparams3 >> 16, 0, "U2 Device Exit Latency");
+        assert!(hcsparams3 >> 16 <= 0x7ff);
+    }
+
+    #[test]
+    fn capability_registers_serve_linux_access_patterns() {
+        let (controller, _ram, _calls) = controller_with_mock_device();
+        let controller = Mutex::new(controller);
+        let read = |addr, size| controller.read_io(0, Request::new(addr, size));
+
+        // Linux reads CAPLENGTH and HCIVERSION with one 32-bit access.
+        assert_eq!(read(offset::CAPLENGTH, RequestSize::Size4), 0x0100_0040);
+        assert_eq!(read(offset::CAPLENGTH, RequestSize::Size1), OP_BASE);
+        assert_eq!(read(offset::HCIVERSION, RequestSize::Size2), 0x100);
+        assert_eq!(
+            read(offset::HCCPARAMS1, RequestSize::Size4),
+            capability::HCCPARAMS1
+        );
+        assert_eq!(
+            read(offset::DBOFF, RequestSize::Size8),
+            RUN_BASE << 32 | offset::DOORBELL_CONTROLLER
+        );
+        // The extended capabilities are walked dword by dword, including
+        // the Name String.
+        assert_eq!(
+            read(offset::SUPPORTED_PROTOCOLS_NAME, RequestSize::Size4),
+            u64::from(u32::from_le_bytes(*b"USB "))
+        );
+        assert_eq!(
+            read(offset::SUPPORTED_PROTOCOLS + 0xc, RequestSize::Size4),
+            0
+        );
+    }
+
+    #[test]
+    fn reads_spanning_registers_are_composed() {
+        let (mut controller, _ram, _calls) = controller_with_mock_device();
+        controller.configure_device_contexts(0x1234_5678_9abc_d000);
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap();
+        let portsc = controller.portsc[port_index].read();
+        let controller = Mutex::new(controller);
+        let read = |addr, size| controller.read_io(0, Request::new(addr, size));
+
+        assert_eq!(
+            read(offset::DCBAAP, RequestSize::Size8),
+            0x1234_5678_9abc_d000
+        );
+        assert_eq!(read(offset::DCBAAP + 2, RequestSize::Size4), 0x5678_9abc);
+        assert_eq!(read(offset::DCBAAP_HI + 1, RequestSize::Size2), 0x3456);
+        // The last capability dword and USBCMD.
+        assert_eq!(read(OP_BASE - 2, RequestSize::Size4), 0);
+        let portsc_addr = offset::PORTSC + port_index as u64 * offset::PORT_STRIDE;
+        assert_eq!(
+            read(portsc_addr + 2, RequestSize::Size1),
+            u64::from(portsc >> 16 & 0xff)
+        );
+        assert_eq!(
+            read(portsc_addr + 2, RequestSize::Size4),
+            u64::from(portsc >> 16 & 0xffff)
+        );
+    }
+
+    #[test]
+    fn portpmsc_stores_writes_per_protocol() {
+        let (controller, _ram, _calls) = controller_with_mock_device();
+        let controller = Mutex::new(controller);
+        let portpmsc = |port_index: u64| {
+            Request::new(
+                offset::PORTPMSC + port_index * offset::PORT_STRIDE,
+                RequestSize::Size4,
+            )
+        };
+
+        let portli = |port_index: u64| {
+            Request::new(
+                offset::PORTLI + port_index * offset::PORT_STRIDE,
+                RequestSize::Size4,
+            )
+        };
+
+        for port_index in 0..MAX_PORTS {
+            controller.write_io(0, portpmsc(port_index), 0xffff_ffff);
+            // PORTLI is read-only.
+            controller.write_io(0, portli(port_index), 0xffff_ffff);
+            assert_eq!(controller.read_io(0, portli(port_index)), 0);
+        }
+
+        for port_index in 0..MAX_PORTS {
+            let expected = if port_index < NUM_USB3_PORTS {
+                portpmsc::usb3::WRITABLE
+            } else {
+                portpmsc::usb2::WRITABLE
+            };
+            assert_eq!(controller.read_io(0, portpmsc(port_index)), expected);
+        }
+
+        // Each port has its own register.
+        controller.write_io(0, portpmsc(0), 0x7f05);
+        assert_eq!(controller.read_io(0, portpmsc(0)), 0x7f05);
+        assert_eq!(controller.read_io(0, portpmsc(1)), portpmsc::usb3::WRITABLE);
+
+        controller.write_io(
+            0,
+            Request::new(offset::USBCMD, RequestSize::Size4),
+            usbcmd::HCRST,
+        );
+        for port_index in 0..MAX_PORTS {
+            assert_eq!(controller.read_io(0, portpmsc(port_index)), 0);
+        }
+    }
+
+    #[test]
+    fn portsc_accepts_sub_dword_accesses() {
+        let (controller, _ram, _calls) = controller_with_mock_device();
+        let port_index = controller.devices.iter().position(Option::is_some).unwrap() as u64;
+        let controller = Mutex::new(controller);
+        let portsc_addr = offset::PORTSC + port_index * offset::PORT_STRIDE;
+        let read = |byte: u64, size| controller.read_io(0, Request::new(portsc_addr + byte, size));
+        let write = |byte: u64, size, value| {
+            controller.write_io(0, Request::new(portsc_addr + byte, size), value)
+        };
+
+        // Reset the port with a byte write, which keeps PP.
+        let low_byte = read(0, RequestSize::Size1);
+        write(0, RequestSize::Size1, low_byte | u64::from(portsc::PR));
+
+        let dword = read(0, RequestSize::Size4);
+        let change_bits = u64::from(portsc::CSC | portsc::PRC);
+        assert_eq!(dword & change_bits, change_bits);
+        assert_eq!(read(0, RequestSize::Size2), dword & 0xffff);
+        assert_eq!(read(2, RequestSize::Size2), dword >> 16);
+        for byte in 0..4 {
+            assert_eq!(read(byte, RequestSize::Size1), dword >> (byte * 8) & 0xff);
+        }
+
+        // Writing back the low half leaves the change bits in the upper
+        // half alone.
+        write(0, RequestSize::Size2, dword & 0xffff);
+        assert_eq!(read(0, RequestSize::Size4), dword);
+
+        // Clear PRC (bit 21) with a byte write to byte 2.
+        write(2, RequestSize::Size1, u64::from(portsc::PRC >> 16));
+        assert_eq!(read(0, RequestSize::Size4), dword & !u64::from(portsc::PRC));
+
+        // Clear CSC with a word write to the upper half.
+        write(2, RequestSize::Size2, u64::from(portsc::CSC) >> 16);
+        assert_eq!(read(0, RequestSize::Size4), dword & !change_bits);
+        assert_eq!(
+            read(0, RequestSize::Size4) & u64::from(portsc::PP),
+            u64::from(portsc::PP)
+        );
+    }
+
+    #[test]
+    fn portpmsc_accepts_sub_dword_accesses() {
+        let (controller, _ram, _calls) = controller_with_mock_device();
+        let controller = Mutex::new(controller);
+        let request = |byte: u64, size| Request::new(offset::PORTPMSC + byte, size);
+
+        controller.write_io(0, request(0, RequestSize::Size4), 0x7f05);
+        controller.write_io(0, request(1, RequestSize::Size1), 0x20);
+
+        assert_eq!(
+            controller.read_io(0, request(0, RequestSize::Size4)),
+            0x2005
+        );
+        assert_eq!(controller.read_io(0, request(1, RequestSize::Size1)), 0x20);
+        assert_eq!(controller.read_io(0, request(2, RequestSize::Size2)), 0);
+    }
+
+    #[test]
+    fn rings_above_4g_work_with_dword_writes() {
+        const HIGH: u64 = 0x10_0000_0000;
+        let dma_bus = Arc::new(DynamicBus::new());
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x1000]));
+        dma_bus.add(HIGH, ram.clone()).unwrap();
+        let controller = Mutex::new(XhciController::new(dma_bus, ControllerConfig::default()));
+        let write_dwords = |low_offset: u64, value: u64| {
+            controller.write_io(
+                0,
+                Request::new(low_offset, RequestSize::Size4),
+                value & 0xffff_ffff,
+            );
+            controller.write_io(
+                0,
+                Request::new(low_offset + 4, RequestSize::Size4),
+                value >> 32,
+            );
+        };
+
+        // An Enable Slot Command at the start of the Command Ring and an
+        // Event Ring with a single segment of 16 TRBs.
+        ram.write_bulk(
+            0x0,
+            &[
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                1,
+                trb_types::ENABLE_SLOT_COMMAND << 2,
+                0,
+                0,
+            ],
+        );
+        ram.write(Request::new(0x400, RequestSize::Size8), HIGH + 0x500);
+        ram.write(Request::new(0x408, RequestSize::Size4), 16);
+
+        write_dwords(offset::DCBAAP, HIGH + 0x800);
+        write_dwords(offset::CRCR, HIGH | 1);
+        controller.write_io(0, Request::new(offset::ERSTSZ, RequestSize::Size4), 1);
+        write_dwords(offset::ERSTBA, HIGH + 0x400);
+        write_dwords(offset::ERDP, HIGH + 0x500);
+        controller.write_io(
+            0,
+            Request::new(offset::USBCMD, RequestSize::Size4),
+            usbcmd::RS,
+        );
+        controller.write_io(
+            0,
+            Request::new(offset::DOORBELL_CONTROLLER, RequestSize::Size4),
+            0,
+        );
+
+        assert_eq!(
+            event_type_and_code(&ram, 0x500),
+            (
+                trb_types::COMMAND_COMPLETION_EVENT,
+                CompletionCode::Success as u8
+            )
+        );
+        assert_eq!(ram.read(Request::new(0x500, RequestSize::Size8)), HIGH);
+
+        for (low_offset, value) in [
+            (offset::DCBAAP, HIGH + 0x800),
+            (offset::ERSTBA, HIGH + 0x400),
+            // The Command Completion Event set Event Handler Busy.
+            (offset::ERDP, HIGH + 0x500 + runtime::erdp::EHB),
+        ] {
+            let read = |offset| controller.read_io(0, Request::new(offset, RequestSize::Size4));
+            assert_eq!(read(low_offset) & 0xffff_ffff, value & 0xffff_ffff);
+            assert_eq!(read(low_offset + 4), value >> 32);
+        }
+    }
+
+    #[test]
+    fn low_dword_writes_keep_the_upper_half() {
+        let current = 0x1234_5678_0000_0000;
+
+        assert_eq!(
+            with_low_dword(current, Request::new(0, RequestSize::Size4), 0x9abc_def0),
+            0x1234_5678_9abc_def0
+        );
+        assert_eq!(
+            with_low_dword(current, Request::new(0, RequestSize::Size8), 0x9abc_def0),
+            0x9abc_def0
+        );
+        assert_eq!(with_high_dword(0x9abc_def0, 0x1), 0x1_9abc_def0);
+    }
+
+    #[test]
+    fn pci_identity_appears_in_config_space() {
+        use crate::device::pci::{
+            constants::config_space::{device, offset, vendor},
+            traits::PciDevice,
+        };
+
+        let identity = PciIdentity {
+            subsystem_vendor_id: 0x1af4,
+            subsystem_id: 0x1100,
+            revision: 0x02,
+            ..DEFAULT_PCI_IDENTITY
+        };
+        let controller = Mutex::new(XhciController::new(
+            Arc::new(TestBusDevice::new(&[0; 0x100])),
+            ControllerConfig {
+                identity,
+                ..ControllerConfig::default()
+            },
+        ));
+        let read = |offset: usize, size| controller.read_cfg(Request::new(offset as u64, size));
+
+        assert_eq!(
+            read(offset::VENDOR, RequestSize::Size2),
+            u64::from(vendor::REDHAT)
+        );
+        assert_eq!(
+            read(offset::DEVICE, RequestSize::Size2),
+            u64::from(device::REDHAT_XHCI)
+        );
+        assert_eq!(
+            read(offset::SUBSYSTEM_VENDOR_ID, RequestSize::Size2),
+            0x1af4
+        );
+        assert_eq!(read(offset::SUBSYSTEM_ID, RequestSize::Size2), 0x1100);
+        // Revision ID and class code share a register.
+        assert_eq!(read(offset::REVISION, RequestSize::Size4), 0x0c03_3002);
+    }
+
+    fn controller_with_label(label: &str) -> XhciController {
+        XhciController::new(
+            Arc::new(TestBusDevice::new(&[0; 0x100])),
+            ControllerConfig {
+                label: label.to_string(),
+                ..ControllerConfig::default()
+            },
+        )
+    }
+
+    /// Read the string field of the identification capability at `field`.
+    fn read_identification_string(controller: &XhciController, field: u64) -> Vec<u8> {
+        (field..field + identification::STRING_SIZE as u64)
+            .map(|offset| {
+                controller
+                    .config_space
+                    .read(Request::new(offset, RequestSize::Size1)) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identification_capability_is_chained_after_msix() {
+        use crate::device::pci::constants::config_space::{capability_id, offset};
+
+        let controller = controller_with_label("vm-42");
+        let read = |offset: u64, size| controller.config_space.read(Request::new(offset, size));
+
+        // Walk the chain by hand to check the next pointers themselves.
+        let msix = read(offset::CAPABILITIES_POINTER as u64, RequestSize::Size1);
+        assert_eq!(
+            read(msix, RequestSize::Size1),
+            u64::from(capability_id::MSI_X)
+        );
+        let cap = read(msix + 1, RequestSize::Size1);
+        assert_eq!(
+            read(cap, RequestSize::Size1),
+            u64::from(capability_id::VENDOR_SPECIFIC)
+        );
+        assert_eq!(read(cap + 1, RequestSize::Size1), 0);
+        assert_eq!(controller.config_space.iter_capability_offsets().count(), 2);
+
+        assert_eq!(
+            read(cap + identification::LENGTH, RequestSize::Size1),
+            identification::SIZE as u64
+        );
+        assert_eq!(
+            read(cap + identification::VERSION, RequestSize::Size1),
+            u64::from(identification::CURRENT_VERSION)
+        );
+        let mut label = b"vm-42".to_vec();
+        label.resize(identification::STRING_SIZE, 0);
+        assert_eq!(
+            read_identification_string(&controller, cap + identification::LABEL),
+            label
+        );
+
+        // Without a device, there is nothing to identify.
+        assert_eq!(read(cap + identification::VENDOR_ID, RequestSize::Size4), 0);
+        assert_eq!(
+            read_identification_string(&controller, cap + identification::SERIAL),
+            [0; identification::STRING_SIZE]
+        );
+    }
+
+    #[test]
+    fn identification_capability_reports_the_first_device() {
+        let mut controller = controller_with_label("a label that is longer than the field");
+        let cap = u64::from(
+            controller
+                .config_space
+                .iter_capability_offsets()
+                .nth(1)
+                .unwrap(),
+        );
+        let read = |controller: &XhciController, offset: u64| {
+            controller
+                .config_space
+                .read(Request::new(cap + offset, RequestSize::Size2))
+        };
+
+        assert_eq!(
+            read_identification_string(&controller, cap + identification::LABEL),
+            b"a label that is longer t"
+        );
+
+        let (mut first, _) = MockUsbDevice::new();
+        first.identity = Arc::new(DeviceIdentity::new(
+            MOCK_LOCATION,
+            DeviceIdentification {
+                vendor_id: 0x1234,
+                product_id: 0x5678,
+                serial: Some("SN-\u{e9}1".to_string()),
+            },
+        ));
+        let first_identity = first.identity.clone();
+        controller.set_device(Box::new(first)).unwrap();
+        let (mut second, _) = MockUsbDevice::new();
+        second.identity = Arc::new(DeviceIdentity::new(
+            MOCK_LOCATION,
+            DeviceIdentification {
+                vendor_id: 0xabcd,
+                product_id: 0xef01,
+                serial: None,
+            },
+        ));
+        let second_identity = second.identity.clone();
+        controller.set_device(Box::new(second)).unwrap();
+
+        // Both are High Speed devices on the USB2 ports, which follow the
+        // USB3 ports.
+        assert_eq!(first_identity.port(), Some(NUM_USB3_PORTS as u8 + 1));
+        assert_eq!(second_identity.port(), Some(NUM_USB3_PORTS as u8 + 2));
+
+        assert_eq!(read(&controller, identification::VENDOR_ID), 0x1234);
+        assert_eq!(read(&controller, identification::PRODUCT_ID), 0x5678);
+        let mut serial = b"SN-?1".to_vec();
+        serial.resize(identification::STRING_SIZE, 0);
+        assert_eq!(
+            read_identification_string(&controller, cap + identification::SERIAL),
+            serial
+        );
+    }
+}
@@ -1,6 +1,9 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+};
 
-use crate::device::bus::{AddBusDeviceError, Bus, BusDevice, BusDeviceRef, Request};
+use crate::device::bus::{AddBusDeviceError, Bus, BusDevice, BusDeviceRef, DefaultDevice, Request};
 use arc_swap::ArcSwap;
 
 #[derive(Debug)]
@@ -9,19 +12,46 @@ struct DeviceEntry {
     device: BusDeviceRef,
 }
 
-#[derive(Default, Debug)]
+impl DeviceEntry {
+    /// The guest addresses the entry claims.
+    fn range(&self) -> Range<u64> {
+        self.start_addr..self.start_addr + self.device.size()
+    }
+}
+
+/// The name of the bus in logs, e.g., of accesses to unmapped memory.
+const NAME: &str = "DMA bus";
+
+/// A bus without guest memory.
+///
+/// DMA reads of unmapped memory return zeros, like on most hardware, rather
+/// than the all-ones of unclaimed MMIO. The default device logs them.
+fn empty_bus() -> Bus {
+    Bus::new_with_default(NAME, Arc::new(DefaultDevice::new(NAME).with_fill(0)))
+}
+
+#[derive(Debug)]
 pub struct DynamicBus {
     segments: Mutex<Vec<DeviceEntry>>,
     bus: Arc<ArcSwap<Bus>>,
 }
 
+impl Default for DynamicBus {
+    fn default() -> Self {
+        Self {
+            segments: Mutex::default(),
+            bus: Arc::new(ArcSwap::from_pointee(empty_bus())),
+        }
+    }
+}
+
 impl DynamicBus {
     pub fn new() -> Self {
         Default::default()
     }
 
     pub fn add(&self, start_addr: u64, device: BusDeviceRef) -> Result<(), AddBusDeviceError> {
-        let mut new_bus = Bus::new("DMA bus", u64::MAX);
+        let mut new_bus = empty_bus();
         let mut segments = self.segments.lock().unwrap();
 
         segments.push(DeviceEntry { start_addr, device });
@@ -40,6 +70,30 @@ impl DynamicBus {
 
         Ok(())
     }
+
+    /// Describe which region `addr` falls in, for logs and assertion
+    /// messages about bad guest pointers.
+    ///
+    /// Regions are numbered in the order they were added.
+    pub fn describe_address(&self, addr: u64) -> String {
+        let segments = self.segments.lock().unwrap();
+        segments
+            .iter()
+            .map(DeviceEntry::range)
+            .enumerate()
+            .find(|(_, range)| range.contains(&addr))
+            .map_or_else(
+                || format!("{addr:#x} (unmapped)"),
+                |(index, range)| {
+                    format!(
+                        "{addr:#x} (region {index} at {:#x}..{:#x}, offset {:#x})",
+                        range.start,
+                        range.end,
+                        addr - range.start
+                    )
+                },
+            )
+    }
 }
 
 impl BusDevice for DynamicBus {
@@ -59,12 +113,41 @@ impl BusDevice for DynamicBus {
         self.bus.load().read_bulk(offset, data)
     }
 
+    #[cfg(any(feature = "nusb-backend", test))]
+    fn try_read_bulk(&self, offset: u64, data: &mut [u8]) -> usize {
+        self.bus.load().try_read_bulk(offset, data)
+    }
+
     fn write_bulk(&self, offset: u64, data: &[u8]) {
         self.bus.load().write_bulk(offset, data)
     }
 
-    fn compare_exchange_request(&self, req: Request, current: u64, new: u64) -> Result<u64, u64> {
-        self.bus.load().compare_exchange_request(req, current, new)
+    fn try_write_bulk(&self, offset: u64, data: &[u8]) -> usize {
+        self.bus.load().try_write_bulk(offset, data)
+    }
+
+    /// Whether guest memory backs all `len` bytes from `addr`.
+    ///
+    /// The bytes may span several adjacent regions. An empty range is
+    /// always mapped.
+    fn is_mapped(&self, addr: u64, len: u64) -> bool {
+        let Some(end) = addr.checked_add(len) else {
+            return false;
+        };
+        let segments = self.segments.lock().unwrap();
+
+        let mut cursor = addr;
+        while cursor < end {
+            match segments
+                .iter()
+                .map(DeviceEntry::range)
+                .find(|range| range.contains(&cursor))
+            {
+                Some(range) => cursor = range.end,
+                None => return false,
+            }
+        }
+        true
     }
 }
 
@@ -80,9 +163,130 @@ mod tests {
         let bus = DynamicBus::default();
         let device1 = Arc::new(TestBusDevice::new(&[42u8; 0x1000]));
 
-        assert_eq!(bus.read(Request::new(0x1000, RequestSize::Size1)), 0xFF);
+        assert_eq!(bus.read(Request::new(0x1000, RequestSize::Size1)), 0);
 
         bus.add(0x1000, device1).unwrap();
         assert_eq!(bus.read(Request::new(0x1000, RequestSize::Size1)), 42);
     }
+
+    #[test]
+    fn can_add_devices_above_4g() {
+        let bus = DynamicBus::default();
+        let high = 0x7f_0000_0000;
+        bus.add(high, Arc::new(TestBusDevice::new(&[0u8; 0x1000])))
+            .unwrap();
+
+        bus.write(
+            Request::new(high + 0x8, RequestSize::Size8),
+            0x1122_3344_5566_7788,
+        );
+        assert_eq!(
+            bus.read(Request::new(high + 0x8, RequestSize::Size8)),
+            0x1122_3344_5566_7788
+        );
+        // The address does not alias into the low 4 GiB.
+        assert_eq!(
+            bus.read(Request::new((high + 0x8) & 0xffff_ffff, RequestSize::Size8)),
+            0
+        );
+
+        let mut data = [0u8; 4];
+        bus.read_bulk(high + 0xa, &mut data);
+        assert_eq!(data, [0x66, 0x55, 0x44, 0x33]);
+    }
+
+    #[test]
+    fn unmapped_dma_reads_return_zeros() {
+        let bus = DynamicBus::new();
+        assert_eq!(bus.read(Request::new(0x1000, RequestSize::Size8)), 0);
+
+        bus.add(0x1000, Arc::new(TestBusDevice::new(&[42u8; 0x1000])))
+            .unwrap();
+        assert_eq!(bus.read(Request::new(0x3000, RequestSize::Size4)), 0);
+
+        // Reads that cover a gap get zeros for the gap only.
+        let mut data = [0xaau8; 0x10];
+        bus.read_bulk(0xff8, &mut data);
+        assert_eq!(data[..8], [0u8; 8]);
+        assert_eq!(data[8..], [42u8; 8]);
+    }
+
+    #[test]
+    fn ranges_are_mapped_only_when_fully_backed() {
+        let bus = DynamicBus::default();
+        bus.add(0x1000, Arc::new(TestBusDevice::new(&[0u8; 0x1000])))
+            .unwrap();
+        bus.add(0x2000, Arc::new(TestBusDevice::new(&[0u8; 0x1000])))
+            .unwrap();
+        bus.add(0x5000, Arc::new(TestBusDevice::new(&[0u8; 0x1000])))
+            .unwrap();
+
+        assert!(bus.is_mapped(0x1000, 0x1000));
+        assert!(bus.is_mapped(0x1fff, 1));
+        // Adjacent regions back a range together.
+        assert!(bus.is_mapped(0x1ff8, 0x10));
+        assert!(bus.is_mapped(0x1000, 0x2000));
+        // Ranges that leave the regions or cross the hole between them are not.
+        assert!(!bus.is_mapped(0x2ff8, 0x10));
+        assert!(!bus.is_mapped(0x2000, 0x3001));
+        assert!(!bus.is_mapped(0xfff, 2));
+        assert!(!bus.is_mapped(0x3000, 1));
+        assert!(bus.is_mapped(0x5000, 0x1000));
+        assert!(!bus.is_mapped(0x5000, 0x1001));
+
+        assert!(bus.is_mapped(0x3000, 0));
+        assert!(!bus.is_mapped(u64::MAX, 2));
+    }
+
+    #[test]
+    fn addresses_are_described_by_their_region() {
+        let bus = DynamicBus::default();
+        bus.add(0x1000, Arc::new(TestBusDevice::new(&[0u8; 0x1000])))
+            .unwrap();
+        bus.add(0x2000, Arc::new(TestBusDevice::new(&[0u8; 0x800])))
+            .unwrap();
+
+        assert_eq!(
+            bus.describe_address(0x1fff),
+            "0x1fff (region 0 at 0x1000..0x2000, offset 0xfff)"
+        );
+        assert_eq!(
+            bus.describe_address(0x2000),
+            "0x2000 (region 1 at 0x2000..0x2800, offset 0x0)"
+        );
+        assert_eq!(bus.describe_address(0x2800), "0x2800 (unmapped)");
+        assert_eq!(bus.describe_address(0xfff), "0xfff (unmapped)");
+    }
+
+    #[test]
+    fn try_read_bulk_reports_mapped_bytes() {
+        let bus = DynamicBus::default();
+        bus.add(0x1000, Arc::new(TestBusDevice::new(&[42u8; 0x1000])))
+            .unwrap();
+
+        let mut data = [0u8; 0x10];
+        assert_eq!(bus.try_read_bulk(0x1000, &mut data), data.len());
+        assert_eq!(data, [42u8; 0x10]);
+
+        assert_eq!(bus.try_read_bulk(0x1ff8, &mut data), 8);
+        assert_eq!(data[..8], [42u8; 8]);
+        assert_eq!(data[8..], [0u8; 8]);
+
+        assert_eq!(bus.try_read_bulk(0x3000, &mut data), 0);
+        assert_eq!(data, [0u8; 0x10]);
+    }
+
+    #[test]
+    fn try_write_bulk_reports_mapped_bytes() {
+        let bus = DynamicBus::default();
+        let memory = Arc::new(TestBusDevice::new(&[0u8; 0x1000]));
+        bus.add(0x1000, memory.clone()).unwrap();
+
+        assert_eq!(bus.try_write_bulk(0x1ff8, &[42u8; 0x10]), 8);
+        let mut data = [0u8; 8];
+        memory.read_bulk(0xff8, &mut data);
+        assert_eq!(data, [42u8; 8]);
+
+        assert_eq!(bus.try_write_bulk(0x3000, &[42u8; 0x10]), 0);
+    }
 }
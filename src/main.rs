@@ -11,18 +11,107 @@
 
 //! usbvfiod
 
+mod affinity;
 mod cli;
 mod device;
 mod dynamic_bus;
 mod memory_segment;
 mod xhci_backend;
 
+use std::{io, mem::MaybeUninit, sync::Arc, thread};
+
 use anyhow::{Context, Result};
 use clap::Parser;
 use cli::Cli;
-use tracing::{info, Level};
+use device::pci::{
+    mmio_profile::MmioProfile, trace::TraceRecorder, vmm_signals::VmmSignals,
+    xhci::ControllerConfig,
+};
+use tracing::{info, info_span, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 use vfio_user::Server;
+use xhci_backend::BackendConfig;
+
+/// The signals that ask us to shut down.
+const SHUTDOWN_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// The signal set that contains exactly `signals`.
+fn signal_set(signals: &[libc::c_int]) -> libc::sigset_t {
+    let mut set = MaybeUninit::uninit();
+    // SAFETY: sigemptyset initializes the set, and we only add valid
+    // signal numbers.
+    unsafe {
+        libc::sigemptyset(set.as_mut_ptr());
+        for &signal in signals {
+            libc::sigaddset(set.as_mut_ptr(), signal);
+        }
+        set.assume_init()
+    }
+}
+
+/// Block `signals` in the calling thread and all threads it starts later.
+///
+/// The signals have to be blocked in all threads for `sigwait` to receive
+/// them, see [`log_mmio_profile_on_sigusr1`] and
+/// [`request_release_on_shutdown`].
+fn block_signals(signals: &[libc::c_int]) -> Result<()> {
+    let set = signal_set(signals);
+    // SAFETY: The set is initialized and we do not ask for the old mask.
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err))
+            .with_context(|| format!("Failed to block signals {signals:?}")),
+    }
+}
+
+/// Log the MMIO profile whenever we receive SIGUSR1.
+fn log_mmio_profile_on_sigusr1(profile: Arc<MmioProfile>) -> Result<()> {
+    let set = signal_set(&[libc::SIGUSR1]);
+    thread::Builder::new()
+        .name("mmio profile".to_string())
+        .spawn(move || loop {
+            let mut signal = 0;
+            // SAFETY: The set is initialized and SIGUSR1 is blocked, so
+            // sigwait only returns once the signal is pending.
+            if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+                profile.log_summary();
+            }
+        })
+        .context("Failed to spawn MMIO profile thread")?;
+
+    Ok(())
+}
+
+/// Ask the VMM to release the device when we are asked to shut down.
+///
+/// The VMM closes the connection once it released the device, after which
+/// we exit normally. Without a VMM to ask, or on a second signal, we exit
+/// right away.
+fn request_release_on_shutdown(vmm_signals: Arc<VmmSignals>) -> Result<()> {
+    let set = signal_set(&SHUTDOWN_SIGNALS);
+    thread::Builder::new()
+        .name("shutdown".to_string())
+        .spawn(move || {
+            let mut asked = false;
+            loop {
+                let mut signal = 0;
+                // SAFETY: The set is initialized and the signals are
+                // blocked, so sigwait only returns once one is pending.
+                if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                    continue;
+                }
+                if asked || !vmm_signals.request_release("usbvfiod is shutting down") {
+                    info!("Exiting on signal {signal}");
+                    std::process::exit(128 + signal);
+                }
+                info!("Waiting for the VMM to release the device, signal again to exit");
+                asked = true;
+            }
+        })
+        .context("Failed to spawn shutdown thread")?;
+
+    Ok(())
+}
 
 fn main() -> Result<()> {
     let args = Cli::parse();
@@ -41,8 +130,73 @@ fn main() -> Result<()> {
     // Log messages from the log crate as well.
     tracing_log::LogTracer::init()?;
 
-    let mut backend = xhci_backend::XhciBackend::new(&args.devices)
+    // Endpoint workers started via `affinity::spawn_thread` log within
+    // this span as well.
+    let _label_span = args
+        .label
+        .as_deref()
+        .map(|label| info_span!("usbvfiod", label).entered());
+
+    let pci_identity = args.pci_identity().context("Invalid PCI identity")?;
+
+    if args.paranoid_dma {
+        device::pci::paranoid_dma::enable();
+    }
+
+    let trace_recorder = args
+        .record
+        .as_deref()
+        .map(TraceRecorder::append_to)
+        .transpose()
+        .context("Failed to open the trace recording")?
+        .map(Arc::new);
+
+    // Threads inherit the signal mask, so this has to happen before the
+    // backend starts any threads.
+    block_signals(&SHUTDOWN_SIGNALS)?;
+    if args.mmio_profile {
+        block_signals(&[libc::SIGUSR1])?;
+    }
+
+    let config = BackendConfig {
+        controller: ControllerConfig {
+            #[cfg(feature = "nusb-backend")]
+            max_outstanding_bulk: args.max_outstanding_bulk,
+            event_coalescing: args.event_coalescing(),
+            max_deferred_events: args.max_deferred_events,
+            stuck_erdp_timeout: args.stuck_erdp_timeout(),
+            identity: pci_identity,
+            label: args.label.clone().unwrap_or_default(),
+            multi_page_transfer_rings: args.multi_page_transfer_rings,
+            max_trbs_per_doorbell: args.max_trbs_per_doorbell,
+            command_policy: args.command_policy(),
+            forward_clear_halt: args.forward_clear_halt,
+            trace_recorder,
+        },
+        #[cfg(feature = "nusb-backend")]
+        async_endpoints: args.async_endpoints,
+        mmio_profile: args.mmio_profile,
+        #[cfg(feature = "nusb-backend")]
+        worker_policy: args.worker_policy(),
+        #[cfg(feature = "nusb-backend")]
+        interface_claim: args.interface_claim(),
+        #[cfg(feature = "nusb-backend")]
+        interrupt_pacing: !args.no_interrupt_pacing,
+        #[cfg(feature = "nusb-backend")]
+        bulk_in_queue_depth: args.bulk_in_queue_depth,
+    };
+    let mut backend = xhci_backend::XhciBackend::new(&args.devices, config)
         .context("Failed to create virtual XHCI controller")?;
+    for &kind in &args.virtual_devices {
+        backend.add_virtual_device(kind);
+    }
+
+    request_release_on_shutdown(backend.vmm_signals())?;
+
+    let mmio_profile = backend.mmio_profile();
+    if args.mmio_profile {
+        log_mmio_profile_on_sigusr1(mmio_profile.clone())?;
+    }
 
     let server = if let cli::ServerSocket::Path(socket_path) = args.server_socket() {
         Server::new(socket_path, true, backend.irqs(), backend.regions())
@@ -53,8 +207,45 @@ fn main() -> Result<()> {
 
     info!("We're up!");
 
-    server
-        .run(&mut backend)
-        .context("Failed to start vfio-user server")?;
+    let result = server.run(&mut backend);
+
+    if args.mmio_profile {
+        mmio_profile.log_summary();
+    }
+    backend.endpoint_stats().log_summary();
+    let ignored_doorbells = backend.ignored_doorbells();
+    if ignored_doorbells > 0 {
+        warn!(
+            "ignored {} doorbells with invalid values",
+            ignored_doorbells
+        );
+    }
+    let event_ring = backend.event_ring_status();
+    if event_ring.stalls > 0 {
+        warn!(
+            "the driver stopped processing events {} times",
+            event_ring.stalls
+        );
+    }
+    if event_ring.rejected_dequeue_pointers > 0 {
+        warn!(
+            "ignored {} ERDP writes outside the Event Ring",
+            event_ring.rejected_dequeue_pointers
+        );
+    }
+    if event_ring.unconfigured_drops > 0 {
+        warn!(
+            "dropped {} events before the driver set up the Event Ring",
+            event_ring.unconfigured_drops
+        );
+    }
+    if event_ring.dropped_events > 0 {
+        warn!(
+            "dropped {} events that did not fit on the full Event Ring",
+            event_ring.dropped_events
+        );
+    }
+
+    result.context("Failed to start vfio-user server")?;
     Ok(())
 }
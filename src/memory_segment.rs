@@ -109,6 +109,21 @@ impl MemorySegment {
             }),
         })
     }
+
+    /// Return a pointer to `len` bytes at offset `addr` of the segment.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range does not fit into the segment.
+    fn pointer(&self, addr: u64, len: u64) -> *const u8 {
+        assert!(
+            addr.checked_add(len).is_some_and(|end| end <= self.size),
+            "address overflow or out of bounds"
+        );
+
+        // SAFETY: We check whether the range fits into the memory region above.
+        unsafe { self.mapping.as_ptr().add(addr.try_into().unwrap()) }
+    }
 }
 
 impl BusDevice for MemorySegment {
@@ -117,15 +132,7 @@ impl BusDevice for MemorySegment {
     }
 
     fn read(&self, req: Request) -> u64 {
-        assert!(
-            req.addr
-                .checked_add(req.size.into())
-                .is_some_and(|end| end <= self.size),
-            "address overflow or out of bounds"
-        );
-
-        // SAFETY: We check whether the request fits into the memory region above.
-        let ptr = unsafe { self.mapping.as_ptr().add(req.addr.try_into().unwrap()) };
+        let ptr = self.pointer(req.addr, req.size.into());
 
         match req.size {
             RequestSize::Size1 => {
@@ -161,20 +168,12 @@ impl BusDevice for MemorySegment {
     }
 
     fn write(&self, req: Request, value: u64) {
-        assert!(
-            req.addr
-                .checked_add(req.size.into())
-                .is_some_and(|end| end <= self.size),
-            "address overflow or out of bounds"
-        );
+        let ptr = self.pointer(req.addr, req.size.into());
 
         if !self.mapping.is_writable() {
             return;
         }
 
-        // SAFETY: We check whether the request fits into the memory region above.
-        let ptr = unsafe { self.mapping.as_ptr().add(req.addr.try_into().unwrap()) };
-
         match req.size {
             RequestSize::Size1 => {
                 // SAFETY:
@@ -208,7 +207,82 @@ impl BusDevice for MemorySegment {
         }
     }
 
-    // TODO Implement read_bulk/write_bulk for efficiency.
+    fn read_bulk(&self, offset: u64, data: &mut [u8]) {
+        let ptr = self.pointer(offset, data.len() as u64);
+
+        // SAFETY (for all accesses below): We ensure above that the
+        // range points to valid memory, and split_for_words aligns the
+        // words. Bulk reads are not atomic as a whole, so racing with the
+        // guest only gives torn data, which the BusDevice contract allows.
+        let (head, words) = split_for_words(ptr, data.len());
+        let tail = head + 8 * words;
+        for (i, byte) in data[..head].iter_mut().enumerate() {
+            *byte = unsafe { atomic_u8(ptr, i) }.load(Ordering::Relaxed);
+        }
+        for (i, chunk) in data[head..tail].chunks_exact_mut(8).enumerate() {
+            let word = unsafe { atomic_u64(ptr, head + 8 * i) }.load(Ordering::Relaxed);
+            chunk.copy_from_slice(&word.to_ne_bytes());
+        }
+        for (i, byte) in data[tail..].iter_mut().enumerate() {
+            *byte = unsafe { atomic_u8(ptr, tail + i) }.load(Ordering::Relaxed);
+        }
+    }
+
+    fn write_bulk(&self, offset: u64, data: &[u8]) {
+        let ptr = self.pointer(offset, data.len() as u64);
+
+        if !self.mapping.is_writable() {
+            return;
+        }
+
+        // SAFETY (for all accesses below): See read_bulk.
+        let (head, words) = split_for_words(ptr, data.len());
+        let tail = head + 8 * words;
+        for (i, &byte) in data[..head].iter().enumerate() {
+            unsafe { atomic_u8(ptr, i) }.store(byte, Ordering::Relaxed);
+        }
+        for (i, chunk) in data[head..tail].chunks_exact(8).enumerate() {
+            let word = u64::from_ne_bytes(chunk.try_into().unwrap());
+            unsafe { atomic_u64(ptr, head + 8 * i) }.store(word, Ordering::Relaxed);
+        }
+        for (i, &byte) in data[tail..].iter().enumerate() {
+            unsafe { atomic_u8(ptr, tail + i) }.store(byte, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Split a bulk access of `len` bytes at `ptr` into a head of single
+/// bytes up to the next 8-byte boundary and a number of aligned 8-byte
+/// words. The remaining bytes form a tail of single bytes.
+///
+/// The guest may access its memory concurrently, so bulk accesses have to
+/// go through atomics like all other accesses. Word-sized atomics keep them
+/// fast.
+fn split_for_words(ptr: *const u8, len: usize) -> (usize, usize) {
+    let head = ptr.align_offset(8).min(len);
+    (head, (len - head) / 8)
+}
+
+/// The byte at `ptr + index` as an atomic.
+///
+/// # Safety
+///
+/// The byte has to be within the mapping of a [`MemorySegment`].
+const unsafe fn atomic_u8<'a>(ptr: *const u8, index: usize) -> &'a AtomicU8 {
+    // SAFETY: The caller ensures that the pointer points to valid memory.
+    // We make sure all accesses to the memory happen via atomics.
+    unsafe { &*(ptr.add(index) as *const AtomicU8) }
+}
+
+/// The 8-byte word at `ptr + index` as an atomic.
+///
+/// # Safety
+///
+/// The word has to be aligned and within the mapping of a
+/// [`MemorySegment`].
+const unsafe fn atomic_u64<'a>(ptr: *const u8, index: usize) -> &'a AtomicU64 {
+    // SAFETY: See atomic_u8. Additionally, the caller ensures alignment.
+    unsafe { &*(ptr.add(index) as *const AtomicU64) }
 }
 
 #[cfg(test)]
@@ -262,6 +336,71 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn bulk_accesses_copy_memory() -> Result<(), std::io::Error> {
+        let memfd = create_memfd(0x1000)?;
+        let mseg = MemorySegment::new_from_fd(&memfd, 0, 0x1000, AccessRights::ReadWrite)?;
+
+        let data: Vec<u8> = (0..=0xff).collect();
+        mseg.write_bulk(0xf00, &data);
+        assert_eq!(mseg.read(Request::new(0xf01, RequestSize::Size1)), 0x01);
+        assert_eq!(
+            mseg.read(Request::new(0xf08, RequestSize::Size8)),
+            0x0f0e0d0c0b0a0908
+        );
+
+        let mut check_data = [0; 0x100];
+        mseg.read_bulk(0xf00, &mut check_data);
+        assert_eq!(check_data[..], data[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unaligned_bulk_accesses_copy_memory() -> Result<(), std::io::Error> {
+        let memfd = create_memfd(0x1000)?;
+        let mseg = MemorySegment::new_from_fd(&memfd, 0, 0x1000, AccessRights::ReadWrite)?;
+
+        let data: Vec<u8> = (1..=0x1b).collect();
+        mseg.write_bulk(0x103, &data);
+        assert_eq!(mseg.read(Request::new(0x102, RequestSize::Size1)), 0);
+        assert_eq!(
+            mseg.read(Request::new(0x108, RequestSize::Size8)),
+            0x0d0c0b0a09080706
+        );
+        assert_eq!(mseg.read(Request::new(0x11e, RequestSize::Size1)), 0);
+
+        let mut check_data = [0; 0x1d];
+        mseg.read_bulk(0x102, &mut check_data);
+        assert_eq!(check_data[0], 0);
+        assert_eq!(check_data[1..0x1c], data[..]);
+        assert_eq!(check_data[0x1c], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn bulk_accesses_beyond_the_segment_panic() {
+        let memfd = create_memfd(0x1000).unwrap();
+        let mseg = MemorySegment::new_from_fd(&memfd, 0, 0x1000, AccessRights::ReadWrite).unwrap();
+
+        mseg.read_bulk(0xff8, &mut [0; 16]);
+    }
+
+    #[test]
+    fn cant_bulk_write_to_read_only() -> Result<(), std::io::Error> {
+        let memfd = create_memfd(0x1000)?;
+        let mseg = MemorySegment::new_from_fd(&memfd, 0, 0x1000, AccessRights::ReadOnly)?;
+
+        mseg.write_bulk(0, &[0xff; 16]);
+        let mut check_data = [0xaa; 16];
+        mseg.read_bulk(0, &mut check_data);
+        assert_eq!(check_data, [0; 16]);
+
+        Ok(())
+    }
+
     #[test]
     fn file_offset_is_respected() -> Result<(), std::io::Error> {
         let mut memfd = create_memfd(0x2000)?;
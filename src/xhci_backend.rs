@@ -1,3 +1,5 @@
+#[cfg(feature = "nusb-backend")]
+use std::num::NonZeroUsize;
 use std::{
     fs::File,
     io::Write,
@@ -5,31 +7,64 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use anyhow::{Context, Result};
+#[cfg(not(feature = "nusb-backend"))]
+use anyhow::bail;
+#[cfg(any(feature = "nusb-backend", test))]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(feature = "nusb-backend")]
 use nusb::MaybeFuture;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
 use vfio_bindings::bindings::vfio::{
     vfio_region_info, VFIO_PCI_BAR0_REGION_INDEX, VFIO_PCI_BAR1_REGION_INDEX,
     VFIO_PCI_BAR2_REGION_INDEX, VFIO_PCI_BAR3_REGION_INDEX, VFIO_PCI_BAR4_REGION_INDEX,
-    VFIO_PCI_BAR5_REGION_INDEX, VFIO_PCI_CONFIG_REGION_INDEX, VFIO_PCI_MSIX_IRQ_INDEX,
-    VFIO_PCI_NUM_IRQS, VFIO_PCI_NUM_REGIONS, VFIO_REGION_INFO_FLAG_READ,
-    VFIO_REGION_INFO_FLAG_WRITE,
+    VFIO_PCI_BAR5_REGION_INDEX, VFIO_PCI_CONFIG_REGION_INDEX, VFIO_PCI_ERR_IRQ_INDEX,
+    VFIO_PCI_MSIX_IRQ_INDEX, VFIO_PCI_NUM_IRQS, VFIO_PCI_NUM_REGIONS, VFIO_PCI_REQ_IRQ_INDEX,
+    VFIO_REGION_INFO_FLAG_READ, VFIO_REGION_INFO_FLAG_WRITE,
 };
 use vfio_user::{IrqInfo, ServerBackend};
 
+#[cfg(any(feature = "nusb-backend", test))]
+use crate::device::pci::realdevice::HostLocation;
+#[cfg(feature = "nusb-backend")]
+use crate::device::pci::{
+    executor::Executor,
+    nusb::{NusbDeviceWrapper, WorkerModel},
+    realdevice::InterfaceClaim,
+};
 use crate::device::{
     bus::{Request, RequestSize},
     interrupt_line::{DummyInterruptLine, InterruptLine},
-    pci::{nusb::NusbDeviceWrapper, traits::PciDevice, xhci::XhciController},
+    pci::{
+        endpoint_stats::EndpointStatsTable,
+        event_sink::EventRingStatus,
+        lifecycle::ResetKind,
+        mmio_profile::MmioProfile,
+        realdevice::RealDevice,
+        traits::PciDevice,
+        virtual_device::VirtualDeviceKind,
+        vmm_signals::VmmSignals,
+        xhci::{ControllerConfig, XhciController},
+    },
 };
 
+#[cfg(feature = "nusb-backend")]
+use crate::affinity::WorkerPolicy;
 use crate::{dynamic_bus::DynamicBus, memory_segment::MemorySegment};
 
 #[derive(Debug)]
 pub struct XhciBackend {
     dma_bus: Arc<DynamicBus>,
     controller: Mutex<XhciController>,
+    #[cfg(feature = "nusb-backend")]
+    worker_model: WorkerModel,
+    #[cfg(feature = "nusb-backend")]
+    interface_claim: InterfaceClaim,
+    #[cfg(feature = "nusb-backend")]
+    interrupt_pacing: bool,
+    #[cfg(feature = "nusb-backend")]
+    bulk_in_queue_depth: NonZeroUsize,
 }
 
 #[derive(Debug)]
@@ -57,10 +92,59 @@ impl InterruptLine for InterruptEventFd {
     }
 }
 
+/// The settings of an [`XhciBackend`].
+///
+/// The defaults match the defaults of the command line.
+#[derive(Debug, Clone)]
+pub struct BackendConfig {
+    /// The settings of the controller.
+    pub controller: ControllerConfig,
+    /// Service the endpoints of all devices by a single executor thread
+    /// instead of one thread per endpoint.
+    #[cfg(feature = "nusb-backend")]
+    pub async_endpoints: bool,
+    /// Record register accesses in the controller's [`MmioProfile`].
+    pub mmio_profile: bool,
+    /// How endpoint workers are scheduled.
+    #[cfg(feature = "nusb-backend")]
+    pub worker_policy: WorkerPolicy,
+    /// Whether devices are taken from their host drivers.
+    #[cfg(feature = "nusb-backend")]
+    pub interface_claim: InterfaceClaim,
+    /// Poll Interrupt IN endpoints at their configured interval.
+    #[cfg(feature = "nusb-backend")]
+    pub interrupt_pacing: bool,
+    /// The number of transfers Bulk IN endpoints keep in flight.
+    #[cfg(feature = "nusb-backend")]
+    pub bulk_in_queue_depth: NonZeroUsize,
+}
+
+#[cfg_attr(not(feature = "nusb-backend"), allow(clippy::derivable_impls))]
+impl Default for BackendConfig {
+    fn default() -> Self {
+        Self {
+            controller: ControllerConfig::default(),
+            #[cfg(feature = "nusb-backend")]
+            async_endpoints: false,
+            mmio_profile: false,
+            #[cfg(feature = "nusb-backend")]
+            worker_policy: WorkerPolicy::default(),
+            #[cfg(feature = "nusb-backend")]
+            interface_claim: InterfaceClaim::Detach,
+            #[cfg(feature = "nusb-backend")]
+            interrupt_pacing: true,
+            #[cfg(feature = "nusb-backend")]
+            bulk_in_queue_depth: NonZeroUsize::MIN,
+        }
+    }
+}
+
 impl XhciBackend {
     /// Create a new virtual XHCI controller with the given USB
     /// devices attached at creation time.
-    pub fn new<I>(devices: I) -> Result<Self>
+    ///
+    /// Without the `nusb-backend` feature, passing any `devices` fails.
+    pub fn new<I>(devices: I, config: BackendConfig) -> Result<Self>
     where
         I: IntoIterator,
         I::Item: AsRef<Path>,
@@ -68,10 +152,28 @@ impl XhciBackend {
         let dma_bus = Arc::new(DynamicBus::new());
 
         let backend = Self {
-            controller: Mutex::new(XhciController::new(dma_bus.clone())),
+            controller: Mutex::new(XhciController::new(dma_bus.clone(), config.controller)),
             dma_bus,
+            #[cfg(feature = "nusb-backend")]
+            worker_model: match config.async_endpoints {
+                true => WorkerModel::Async(Arc::new(Executor::new(
+                    "endpoint executor",
+                    config.worker_policy.for_class(None),
+                ))),
+                false => WorkerModel::Threads(config.worker_policy),
+            },
+            #[cfg(feature = "nusb-backend")]
+            interface_claim: config.interface_claim,
+            #[cfg(feature = "nusb-backend")]
+            interrupt_pacing: config.interrupt_pacing,
+            #[cfg(feature = "nusb-backend")]
+            bulk_in_queue_depth: config.bulk_in_queue_depth,
         };
 
+        if config.mmio_profile {
+            backend.mmio_profile().enable();
+        }
+
         for device in devices {
             backend.add_device_from_path(device)?;
         }
@@ -79,18 +181,83 @@ impl XhciBackend {
         Ok(backend)
     }
 
+    /// The profile of the guest's register accesses.
+    pub fn mmio_profile(&self) -> Arc<MmioProfile> {
+        self.controller.lock().unwrap().mmio_profile()
+    }
+
+    /// The transfer statistics of all endpoints.
+    pub fn endpoint_stats(&self) -> Arc<EndpointStatsTable> {
+        self.controller.lock().unwrap().endpoint_stats()
+    }
+
+    /// The error and request interrupts of the VMM.
+    pub fn vmm_signals(&self) -> Arc<VmmSignals> {
+        self.controller.lock().unwrap().vmm_signals()
+    }
+
+    /// The number of doorbells the driver rang with invalid values.
+    pub fn ignored_doorbells(&self) -> u64 {
+        self.controller.lock().unwrap().ignored_doorbells()
+    }
+
+    /// The state of the Event Ring and of the driver processing it.
+    pub fn event_ring_status(&self) -> EventRingStatus {
+        self.controller.lock().unwrap().event_ring_status()
+    }
+
     /// Add a USB device to the virtual XHCI controller.
-    fn add_device(&self, device: nusb::Device) -> Result<()> {
-        // Add the device to the XHCI controller.
-        let wrapped_device = Box::new(NusbDeviceWrapper::new(device));
-        self.controller.lock().unwrap().set_device(wrapped_device);
+    ///
+    /// A device that cannot be claimed is skipped with a warning.
+    #[cfg(feature = "nusb-backend")]
+    fn add_device(&self, device: nusb::Device, location: HostLocation) -> Result<()> {
+        let wrapped_device = match NusbDeviceWrapper::new(
+            device,
+            location,
+            self.worker_model.clone(),
+            self.interface_claim,
+            self.interrupt_pacing,
+            self.bulk_in_queue_depth,
+        ) {
+            Ok(wrapped_device) => wrapped_device,
+            Err(error) => {
+                // The other devices are still of use to the guest.
+                warn!("skipping USB device at {}: {}", location, error);
+                return Ok(());
+            }
+        };
+        self.attach_device(Box::new(wrapped_device));
 
         Ok(())
     }
 
+    /// Attach a claimed USB device to the XHCI controller.
+    ///
+    /// A device without a free port is skipped with a warning and handed
+    /// back to the host.
+    fn attach_device(&self, device: Box<dyn RealDevice>) {
+        let identity = device.identity().clone();
+        let attached = self.controller.lock().unwrap().set_device(device);
+        match attached {
+            Ok(()) => info!("attached USB device {}", identity),
+            Err(error) => warn!("skipping USB device {}: {}", identity, error),
+        }
+    }
+
+    /// Attach a device that is emulated in software.
+    ///
+    /// Like passed-through devices, a virtual device without a free port
+    /// is skipped with a warning.
+    pub fn add_virtual_device(&self, kind: VirtualDeviceKind) {
+        info!("emulating a {}", kind);
+        self.attach_device(kind.create());
+    }
+
     /// Add a USB device via its path in `/dev/bus/usb`.
+    #[cfg(feature = "nusb-backend")]
     pub fn add_device_from_path(&self, path: impl AsRef<Path>) -> Result<()> {
         let path: &Path = path.as_ref();
+        let location = host_location_from_path(path)?;
         let open_file = |err_msg| {
             std::fs::OpenOptions::new()
                 .read(true)
@@ -106,10 +273,51 @@ impl XhciBackend {
         // After the reset, the device instance is no longer usable and we need
         // to reopen.
         let file = open_file("Failed to open USB device file after device reset")?;
-        self.add_device(nusb::Device::from_fd(file.into()).wait()?)
+        self.add_device(nusb::Device::from_fd(file.into()).wait()?, location)
+    }
+
+    /// Refuse to add a USB device, as there is no backend to pass it
+    /// through with.
+    #[cfg(not(feature = "nusb-backend"))]
+    pub fn add_device_from_path(&self, path: impl AsRef<Path>) -> Result<()> {
+        bail!(
+            "Cannot pass through {}: usbvfiod was built without the nusb-backend feature",
+            path.as_ref().display()
+        )
     }
 }
 
+/// Extract the host location of a device from its path.
+///
+/// Device paths have the form `/dev/bus/usb/BBB/DDD`, where `BBB` is the
+/// bus number and `DDD` the device number on that bus.
+#[cfg(any(feature = "nusb-backend", test))]
+fn host_location_from_path(path: &Path) -> Result<HostLocation> {
+    let number = |component: Option<&std::ffi::OsStr>| {
+        component
+            .and_then(|component| component.to_str())
+            .and_then(|component| component.parse().ok())
+    };
+    let bus_number =
+        number(path.parent().and_then(|bus_dir| bus_dir.file_name())).with_context(|| {
+            format!(
+                "Failed to determine the USB bus number from device path: {}",
+                path.display()
+            )
+        })?;
+    let device_address = number(path.file_name()).with_context(|| {
+        format!(
+            "Failed to determine the USB device number from device path: {}",
+            path.display()
+        )
+    })?;
+
+    Ok(HostLocation {
+        bus_number,
+        device_address,
+    })
+}
+
 impl XhciBackend {
     /// Return a list of regions for [`vfio_user::Server::new`].
     pub fn regions(&self) -> Vec<vfio_region_info> {
@@ -179,7 +387,7 @@ impl XhciBackend {
             .map(|index| IrqInfo {
                 index,
                 count: match index {
-                    VFIO_PCI_MSIX_IRQ_INDEX => 1,
+                    VFIO_PCI_MSIX_IRQ_INDEX | VFIO_PCI_ERR_IRQ_INDEX | VFIO_PCI_REQ_IRQ_INDEX => 1,
                     _ => 0,
                 },
                 flags: 0,
@@ -203,8 +411,8 @@ impl ServerBackend for XhciBackend {
                 RequestSize::try_from(data.len() as u64).expect("should use valid request size"),
             )),
 
-            0 => self.controller.read_io(
-                0,
+            VFIO_PCI_BAR0_REGION_INDEX | VFIO_PCI_BAR3_REGION_INDEX => self.controller.read_io(
+                region,
                 Request::new(
                     offset,
                     RequestSize::try_from(data.len() as u64)
@@ -256,8 +464,8 @@ impl ServerBackend for XhciBackend {
                 },
             ),
 
-            0 => self.controller.write_io(
-                0,
+            VFIO_PCI_BAR0_REGION_INDEX | VFIO_PCI_BAR3_REGION_INDEX => self.controller.write_io(
+                region,
                 Request::new(
                     offset,
                     RequestSize::try_from(data.len() as u64)
@@ -306,6 +514,10 @@ impl ServerBackend for XhciBackend {
 
             // Guest provided invalid memory region setup - no reasonable recovery possible
             self.dma_bus.add(address, Arc::new(mseg)).unwrap();
+            debug!(
+                "mapped guest memory {}",
+                self.dma_bus.describe_address(address)
+            );
         } else {
             todo!("Memory region without file descriptor");
         }
@@ -323,7 +535,9 @@ impl ServerBackend for XhciBackend {
     }
 
     fn reset(&mut self) -> Result<(), std::io::Error> {
-        todo!()
+        debug!("device reset requested by the VMM");
+        self.controller.lock().unwrap().reset(ResetKind::Function);
+        Ok(())
     }
 
     fn set_irqs(
@@ -338,30 +552,1001 @@ impl ServerBackend for XhciBackend {
             "set IRQs: {index} flags: {flags:#x} start: {start:#x} count: {count:#x} #fds: {}",
             fds.len()
         );
+        if count > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("IRQ index {index} only has a single interrupt"),
+            ));
+        }
+
+        // Without an eventfd, the VMM disconnects the interrupt.
+        let eventfd = fds.into_iter().next().map(|file| {
+            Arc::new(InterruptEventFd {
+                fd: Mutex::new(file),
+            }) as Arc<dyn InterruptLine>
+        });
+
+        match index {
+            VFIO_PCI_MSIX_IRQ_INDEX => self
+                .controller
+                .lock()
+                .unwrap()
+                .connect_irq(eventfd.unwrap_or_else(|| Arc::new(DummyInterruptLine::default()))),
+            VFIO_PCI_ERR_IRQ_INDEX => self.vmm_signals().connect_error(eventfd),
+            VFIO_PCI_REQ_IRQ_INDEX => self.vmm_signals().connect_request(eventfd),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("IRQ index {index} is not supported"),
+                ))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::fd::{AsRawFd, FromRawFd},
+        sync::mpsc,
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use memmap2::MmapMut;
+    use vfio_bindings::bindings::vfio::{
+        VFIO_IRQ_SET_ACTION_TRIGGER, VFIO_IRQ_SET_DATA_EVENTFD, VFIO_PCI_INTX_IRQ_INDEX,
+    };
+    use vfio_user::{Client, Server};
+
+    use crate::device::pci::{
+        constants::{
+            config_space::vendor,
+            xhci::{
+                device_slots::slot_state,
+                offset,
+                operational::{crcr, portsc, usbcmd, usbsts},
+                rings::trb_types,
+                runtime::iman,
+                MAX_PORTS, NUM_USB3_PORTS, OP_BASE, RUN_BASE,
+            },
+        },
+        realdevice::testutils::{MockCall, MockUsbDevice},
+        replay::{self, SharedBuffer},
+        trace::TraceRecorder,
+        trb::CompletionCode,
+    };
+
+    use super::*;
+
+    /// The size of the guest memory, which starts at guest address 0.
+    const RAM_SIZE: u64 = 0x10000;
+
+    // The layout of the guest memory.
+    const DCBAA: u64 = 0x1000;
+    const DEVICE_CONTEXT: u64 = 0x2000;
+    const COMMAND_RING: u64 = 0x3000;
+    const ERST: u64 = 0x4000;
+    const EVENT_RING: u64 = 0x5000;
+    const EVENT_RING_SIZE: u64 = 16;
+    const INPUT_CONTEXT: u64 = 0x6000;
+    const CONTROL_RING: u64 = 0x7000;
+    const DESCRIPTOR_BUFFER: u64 = 0x8000;
+    const INTERRUPT_RING: u64 = 0x9000;
+    const REPORT_BUFFER: u64 = 0xa000;
+    const BULK_OUT_RING: u64 = 0xb000;
+    const BULK_IN_RING: u64 = 0xc000;
+    const BULK_BUFFER: u64 = 0xd000;
+
+    /// The device descriptor of the mock device.
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x01,
+        0x02, 0x03, 0x01,
+    ];
+
+    #[test]
+    fn host_locations_tell_devices_on_a_bus_apart() {
+        let location = |path: &str| host_location_from_path(Path::new(path)).unwrap();
+        assert_eq!(
+            location("/dev/bus/usb/001/002"),
+            HostLocation {
+                bus_number: 1,
+                device_address: 2,
+            }
+        );
+        assert_ne!(
+            location("/dev/bus/usb/001/002"),
+            location("/dev/bus/usb/001/003")
+        );
         assert_eq!(
-            index, VFIO_PCI_MSIX_IRQ_INDEX,
-            "Only MSI-X interrupts are supported"
+            location("/dev/bus/usb/003/010").to_string(),
+            "bus 003 device 010"
         );
-        assert!(count <= 1, "Only a single interrupt is supported");
 
-        let irqs: Vec<Arc<InterruptEventFd>> = fds
-            .into_iter()
-            .map(|file| {
-                Arc::new(InterruptEventFd {
-                    fd: Mutex::new(file),
-                })
+        assert!(host_location_from_path(Path::new("/dev/bus/usb/001/mouse")).is_err());
+        assert!(host_location_from_path(Path::new("/dev/usb0")).is_err());
+    }
+
+    /// A backend without devices.
+    fn backend() -> XhciBackend {
+        recording_backend(None)
+    }
+
+    /// A backend without devices that records to `trace_recorder`.
+    fn recording_backend(trace_recorder: Option<Arc<TraceRecorder>>) -> XhciBackend {
+        XhciBackend::new(
+            Vec::<&Path>::new(),
+            BackendConfig {
+                controller: ControllerConfig {
+                    trace_recorder,
+                    ..ControllerConfig::default()
+                },
+                ..BackendConfig::default()
+            },
+        )
+        .unwrap()
+    }
+
+    /// Read the PORTSC register of each port like the guest would.
+    fn connected_ports(backend: &mut XhciBackend) -> Vec<bool> {
+        (0..MAX_PORTS)
+            .map(|i| {
+                let mut portsc = [0; 4];
+                backend
+                    .region_read(
+                        VFIO_PCI_BAR0_REGION_INDEX,
+                        offset::PORTSC + i * offset::PORT_STRIDE,
+                        &mut portsc,
+                    )
+                    .unwrap();
+                u32::from_le_bytes(portsc) & portsc::CCS != 0
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "nusb-backend"))]
+    #[test]
+    fn host_devices_are_refused_without_a_backend() {
+        let mut backend = backend();
+        let err = backend
+            .add_device_from_path("/dev/bus/usb/001/002")
+            .unwrap_err();
+        assert!(err.to_string().contains("nusb-backend"));
+        assert_eq!(connected_ports(&mut backend), [false; MAX_PORTS as usize]);
+    }
+
+    #[test]
+    fn device_reset_shows_devices_on_powered_off_ports_again() {
+        let mut backend = backend();
+        backend.attach_device(Box::new(MockUsbDevice::new().0));
+        // The driver switches off the port of the device.
+        backend
+            .region_write(
+                VFIO_PCI_BAR0_REGION_INDEX,
+                offset::PORTSC + 2 * offset::PORT_STRIDE,
+                &0u32.to_le_bytes(),
+            )
+            .unwrap();
+        assert_eq!(connected_ports(&mut backend), [false; MAX_PORTS as usize]);
+
+        ServerBackend::reset(&mut backend).unwrap();
+
+        assert_eq!(connected_ports(&mut backend), [false, false, true, false]);
+        let mut status = [0; 4];
+        backend
+            .region_read(VFIO_PCI_BAR0_REGION_INDEX, offset::USBSTS, &mut status)
+            .unwrap();
+        let status = u64::from(u32::from_le_bytes(status));
+        assert_eq!(status & (usbsts::HCH | usbsts::CNR), usbsts::HCH);
+    }
+
+    #[test]
+    fn attached_devices_show_up_on_a_port() {
+        let mut backend = backend();
+        assert_eq!(connected_ports(&mut backend), [false; MAX_PORTS as usize]);
+
+        backend.attach_device(Box::new(MockUsbDevice::new().0));
+        // High Speed devices go to the USB2 ports after the USB3 ones.
+        assert_eq!(connected_ports(&mut backend), [false, false, true, false]);
+    }
+
+    #[test]
+    fn devices_without_a_free_port_are_handed_back() {
+        let mut backend = backend();
+        let calls: Vec<_> = (0..3)
+            .map(|_| {
+                let (device, calls) = MockUsbDevice::new();
+                backend.attach_device(Box::new(device));
+                calls
             })
             .collect();
 
-        // The solution offered by clippy produces a type error.
-        #[allow(clippy::option_if_let_else)]
-        let irq: Arc<dyn InterruptLine> = match irqs.first() {
-            Some(eventfd) => eventfd.clone(),
-            _ => Arc::new(DummyInterruptLine::default()),
+        assert_eq!(connected_ports(&mut backend), [false, false, true, true]);
+        // The third device was dropped, which releases it to the host.
+        assert_eq!(Arc::strong_count(&calls[1]), 2);
+        assert_eq!(Arc::strong_count(&calls[2]), 1);
+    }
+
+    /// A pipe that stands in for an eventfd, as `(read end, write end)`.
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // SAFETY: The array has room for both file descriptors.
+        let result = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        assert_eq!(result, 0, "Failed to create pipe");
+        // SAFETY: We just created the file descriptors and own them.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    /// Whether the write end of `pipe` was signaled since the last call.
+    fn signaled(pipe: &mut File) -> bool {
+        let mut value = [0; 8];
+        match std::io::Read::read(pipe, &mut value) {
+            Ok(len) => len > 0,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => false,
+            Err(error) => panic!("Failed to read pipe: {error}"),
+        }
+    }
+
+    /// Connect a pipe to the IRQ `index` and return its read end.
+    fn connect_pipe(backend: &mut XhciBackend, index: u32) -> File {
+        let (read_end, write_end) = pipe();
+        backend
+            .set_irqs(
+                index,
+                VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+                0,
+                1,
+                vec![write_end],
+            )
+            .unwrap();
+        read_end
+    }
+
+    #[test]
+    fn error_and_request_irqs_are_advertised() {
+        let counts: Vec<_> = backend().irqs().iter().map(|irq| irq.count).collect();
+        let mut expected = vec![0; VFIO_PCI_NUM_IRQS as usize];
+        for index in [
+            VFIO_PCI_MSIX_IRQ_INDEX,
+            VFIO_PCI_ERR_IRQ_INDEX,
+            VFIO_PCI_REQ_IRQ_INDEX,
+        ] {
+            expected[index as usize] = 1;
+        }
+        assert_eq!(counts, expected);
+    }
+
+    #[test]
+    fn vmm_signals_reach_their_eventfds() {
+        let mut backend = backend();
+        let mut error = connect_pipe(&mut backend, VFIO_PCI_ERR_IRQ_INDEX);
+        let mut request = connect_pipe(&mut backend, VFIO_PCI_REQ_IRQ_INDEX);
+        let vmm_signals = backend.vmm_signals();
+
+        assert!(vmm_signals.request_release("shutdown"));
+        assert_eq!(
+            (signaled(&mut error), signaled(&mut request)),
+            (false, true)
+        );
+
+        vmm_signals.signal_error("worker stuck");
+        assert_eq!(
+            (signaled(&mut error), signaled(&mut request)),
+            (true, false)
+        );
+    }
+
+    #[test]
+    fn unsupported_irq_indices_are_refused() {
+        let mut backend = backend();
+        let (_read_end, write_end) = pipe();
+
+        let error = backend
+            .set_irqs(
+                VFIO_PCI_INTX_IRQ_INDEX,
+                VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+                0,
+                1,
+                vec![write_end],
+            )
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    /// A guest that drives an [`XhciBackend`] with a mock device over a
+    /// vfio-user connection, like a VMM would.
+    struct TestGuest {
+        client: Client,
+        ram: MmapMut,
+        interrupt: File,
+        server: Option<JoinHandle<()>>,
+        socket_path: std::path::PathBuf,
+    }
+
+    impl TestGuest {
+        /// Start the server with the mock device and connect to it.
+        fn connect() -> Self {
+            Self::connect_with(|| {
+                let (mut device, _calls) = MockUsbDevice::new();
+                device.control_in_data = DEVICE_DESCRIPTOR.to_vec();
+                Box::new(device)
+            })
+        }
+
+        /// Start the server with the device that `device` creates and
+        /// connect to it. Map the guest memory and connect the MSI-X
+        /// interrupt to an eventfd.
+        fn connect_with(device: impl FnOnce() -> Box<dyn RealDevice> + Send + 'static) -> Self {
+            Self::connect_recording(device, None)
+        }
+
+        /// Like [`Self::connect_with`], but the controller records to
+        /// `trace_recorder`.
+        fn connect_recording(
+            device: impl FnOnce() -> Box<dyn RealDevice> + Send + 'static,
+            trace_recorder: Option<Arc<TraceRecorder>>,
+        ) -> Self {
+            let socket_path = std::env::temp_dir().join(format!(
+                "usbvfiod-test-{}-{:?}.sock",
+                std::process::id(),
+                thread::current().id()
+            ));
+            let _ = std::fs::remove_file(&socket_path);
+
+            let (listening, wait_listening) = mpsc::channel();
+            let path = socket_path.clone();
+            let server = thread::spawn(move || {
+                let mut backend = recording_backend(trace_recorder);
+                backend.attach_device(device());
+
+                let server = Server::new(&path, true, backend.irqs(), backend.regions()).unwrap();
+                listening.send(()).unwrap();
+                server.run(&mut backend).unwrap();
+            });
+            wait_listening.recv().unwrap();
+
+            let mut client = Client::new(&socket_path).unwrap();
+
+            // SAFETY: The name is a valid C string.
+            let fd = unsafe { libc::memfd_create(c"guest-ram".as_ptr(), libc::MFD_CLOEXEC) };
+            assert!(fd >= 0, "Failed to create guest memory");
+            // SAFETY: We just created the file descriptor and own it.
+            let memory = unsafe { File::from_raw_fd(fd) };
+            memory.set_len(RAM_SIZE).unwrap();
+            // SAFETY: Only the backend modifies the memory besides us, and
+            // we only access it through volatile copies of plain bytes.
+            let ram = unsafe { MmapMut::map_mut(&memory) }.unwrap();
+            client.dma_map(0, 0, RAM_SIZE, memory.as_raw_fd()).unwrap();
+
+            // SAFETY: eventfd has no memory safety requirements.
+            let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+            assert!(fd >= 0, "Failed to create eventfd");
+            // SAFETY: We just created the file descriptor and own it.
+            let interrupt = unsafe { File::from_raw_fd(fd) };
+            client
+                .set_irqs(
+                    VFIO_PCI_MSIX_IRQ_INDEX,
+                    VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+                    0,
+                    1,
+                    &[interrupt.as_raw_fd()],
+                )
+                .unwrap();
+
+            Self {
+                client,
+                ram,
+                interrupt,
+                server: Some(server),
+                socket_path,
+            }
+        }
+
+        fn read_bar0(&mut self, offset: u64) -> u32 {
+            let mut data = [0; 4];
+            self.client
+                .region_read(VFIO_PCI_BAR0_REGION_INDEX, offset, &mut data)
+                .unwrap();
+            u32::from_le_bytes(data)
+        }
+
+        fn write_bar0(&mut self, offset: u64, value: u32) {
+            self.client
+                .region_write(VFIO_PCI_BAR0_REGION_INDEX, offset, &value.to_le_bytes())
+                .unwrap();
+        }
+
+        /// Write a 64-bit register as two dwords, low dword first.
+        fn write_bar0_u64(&mut self, offset: u64, value: u64) {
+            self.write_bar0(offset, value as u32);
+            self.write_bar0(offset + 4, (value >> 32) as u32);
+        }
+
+        fn read_ram(&self, address: u64, length: usize) -> Vec<u8> {
+            let start = address as usize;
+            (start..start + length)
+                // SAFETY: The index is within the mapping.
+                .map(|i| unsafe { std::ptr::read_volatile(self.ram.as_ptr().add(i)) })
+                .collect()
+        }
+
+        fn write_ram(&mut self, address: u64, data: &[u8]) {
+            let start = address as usize;
+            for (i, &byte) in data.iter().enumerate() {
+                // SAFETY: The index is within the mapping.
+                unsafe { std::ptr::write_volatile(self.ram.as_mut_ptr().add(start + i), byte) };
+            }
+        }
+
+        /// Place a TRB with the given fields and the cycle bit set.
+        fn write_trb(&mut self, address: u64, parameter: u64, status: u32, control: u32) {
+            let mut trb = [0; 16];
+            trb[0..8].copy_from_slice(&parameter.to_le_bytes());
+            trb[8..12].copy_from_slice(&status.to_le_bytes());
+            trb[12..16].copy_from_slice(&(control | 1).to_le_bytes());
+            self.write_ram(address, &trb);
+        }
+
+        /// The events on the Event Ring, without Port Status Change
+        /// Events.
+        fn events(&self) -> Vec<Vec<u8>> {
+            (0..EVENT_RING_SIZE)
+                .map(|i| self.read_ram(EVENT_RING + 16 * i, 16))
+                .take_while(|trb| trb[12] & 1 == 1)
+                .filter(|trb| trb[13] >> 2 != trb_types::PORT_STATUS_CHANGE_EVENT)
+                .collect()
+        }
+
+        /// Reset the controller, hand it its data structures, and start
+        /// it.
+        fn start_controller(&mut self) {
+            self.write_bar0(offset::USBCMD, usbcmd::HCRST as u32);
+            self.write_ram(DCBAA + 8, &DEVICE_CONTEXT.to_le_bytes());
+            self.write_bar0_u64(offset::DCBAAP, DCBAA);
+            self.write_bar0_u64(offset::CRCR, COMMAND_RING | crcr::RCS);
+            let mut erst_entry = EVENT_RING.to_le_bytes().to_vec();
+            erst_entry.extend_from_slice(&EVENT_RING_SIZE.to_le_bytes());
+            self.write_ram(ERST, &erst_entry);
+            self.write_bar0(offset::ERSTSZ, 1);
+            self.write_bar0_u64(offset::ERSTBA, ERST);
+            self.write_bar0_u64(offset::ERDP, EVENT_RING);
+            self.write_bar0(offset::IMAN, iman::IE as u32);
+            self.write_bar0(offset::USBCMD, usbcmd::RS as u32);
+        }
+
+        /// The Port ID of the port the device is connected to.
+        fn connected_port(&mut self) -> u64 {
+            (0..MAX_PORTS)
+                .position(|i| {
+                    self.read_bar0(offset::PORTSC + i * offset::PORT_STRIDE) & portsc::CCS != 0
+                })
+                .expect("the device should be connected to a port") as u64
+                + 1
+        }
+
+        /// Enable Slot 1 and address the device on `port_id` with a
+        /// Default Control Endpoint that has a max packet size of 64 and
+        /// its Transfer Ring at CONTROL_RING.
+        fn address_device(&mut self, port_id: u64) {
+            self.write_trb(
+                COMMAND_RING,
+                0,
+                0,
+                u32::from(trb_types::ENABLE_SLOT_COMMAND) << 10,
+            );
+            self.write_bar0(offset::DOORBELL_CONTROLLER, 0);
+            let events = self.events();
+            assert_eq!(
+                event_fields(&events[0]),
+                (
+                    trb_types::COMMAND_COMPLETION_EVENT,
+                    CompletionCode::Success as u8,
+                    1
+                )
+            );
+
+            self.write_ram(INPUT_CONTEXT + 4, &0x3u32.to_le_bytes());
+            self.write_ram(INPUT_CONTEXT + 0x20 + 3, &[1 << 3]);
+            self.write_ram(INPUT_CONTEXT + 0x20 + 6, &[port_id as u8]);
+            self.write_ram(INPUT_CONTEXT + 0x40 + 4, &[0x26]);
+            self.write_ram(INPUT_CONTEXT + 0x40 + 6, &64u16.to_le_bytes());
+            self.write_ram(INPUT_CONTEXT + 0x40 + 8, &(CONTROL_RING | 1).to_le_bytes());
+            self.write_trb(
+                COMMAND_RING + 0x10,
+                INPUT_CONTEXT,
+                0,
+                u32::from(trb_types::ADDRESS_DEVICE_COMMAND) << 10 | 1 << 24,
+            );
+            self.write_bar0(offset::DOORBELL_CONTROLLER, 0);
+            let events = self.events();
+            assert_eq!(
+                event_fields(&events[1]),
+                (
+                    trb_types::COMMAND_COMPLETION_EVENT,
+                    CompletionCode::Success as u8,
+                    1
+                )
+            );
+            // The device got a USB address.
+            assert_eq!(self.read_ram(DEVICE_CONTEXT + 12, 1), [1]);
+        }
+
+        /// Send the device-to-host control request `setup` with a Data
+        /// Stage of `length` bytes into DESCRIPTOR_BUFFER. The TRBs of
+        /// the `index`-th control transfer follow those of the previous
+        /// ones on the control ring.
+        fn control_in(&mut self, index: u64, setup: [u8; 8], length: u32) -> Vec<u8> {
+            let trbs = CONTROL_RING + index * 0x30;
+            self.write_trb(
+                trbs,
+                u64::from_le_bytes(setup),
+                8,
+                u32::from(trb_types::SETUP_STAGE) << 10 | 1 << 6 | 3 << 16,
+            );
+            self.write_trb(
+                trbs + 0x10,
+                DESCRIPTOR_BUFFER,
+                length,
+                u32::from(trb_types::DATA_STAGE) << 10 | 1 << 16,
+            );
+            self.write_trb(
+                trbs + 0x20,
+                0,
+                0,
+                u32::from(trb_types::STATUS_STAGE) << 10 | 1 << 5,
+            );
+            self.write_bar0(offset::DOORBELL_DEVICE, 1);
+            self.read_ram(DESCRIPTOR_BUFFER, length as usize)
+        }
+
+        /// Send the control request `setup` without a Data Stage as the
+        /// `index`-th control transfer, like [`Self::control_in`].
+        fn control_no_data(&mut self, index: u64, setup: [u8; 8]) {
+            let trbs = CONTROL_RING + index * 0x30;
+            self.write_trb(
+                trbs,
+                u64::from_le_bytes(setup),
+                8,
+                u32::from(trb_types::SETUP_STAGE) << 10 | 1 << 6,
+            );
+            // Without a Data Stage, the Status Stage is IN.
+            self.write_trb(
+                trbs + 0x10,
+                0,
+                0,
+                u32::from(trb_types::STATUS_STAGE) << 10 | 1 << 5 | 1 << 16,
+            );
+            self.write_bar0(offset::DOORBELL_DEVICE, 1);
+        }
+
+        /// Configure EP1 OUT (DCI 2) as Bulk OUT with its ring at
+        /// BULK_OUT_RING and EP2 IN (DCI 5) as Bulk IN with its ring at
+        /// BULK_IN_RING, both with a max packet size of 512.
+        fn configure_bulk_endpoints(&mut self, port_id: u64) {
+            self.write_ram(INPUT_CONTEXT, &[0; 0x100]);
+            self.write_ram(INPUT_CONTEXT + 4, &0b10_0101u32.to_le_bytes());
+            self.write_ram(INPUT_CONTEXT + 0x20 + 3, &[5 << 3]);
+            self.write_ram(INPUT_CONTEXT + 0x20 + 6, &[port_id as u8]);
+            for (context, endpoint_type, ring) in
+                [(0x60, 2, BULK_OUT_RING), (0xc0, 6, BULK_IN_RING)]
+            {
+                self.write_ram(INPUT_CONTEXT + context + 4, &[endpoint_type << 3 | 3 << 1]);
+                self.write_ram(INPUT_CONTEXT + context + 6, &512u16.to_le_bytes());
+                self.write_ram(INPUT_CONTEXT + context + 8, &(ring | 1).to_le_bytes());
+            }
+            self.write_trb(
+                COMMAND_RING + 0x20,
+                INPUT_CONTEXT,
+                0,
+                u32::from(trb_types::CONFIGURE_ENDPOINT_COMMAND) << 10 | 1 << 24,
+            );
+            self.write_bar0(offset::DOORBELL_CONTROLLER, 0);
+            assert_eq!(
+                event_fields(self.events().last().unwrap()),
+                (
+                    trb_types::COMMAND_COMPLETION_EVENT,
+                    CompletionCode::Success as u8,
+                    1
+                )
+            );
+        }
+
+        /// The number of interrupts since the last call.
+        fn interrupts(&mut self) -> u64 {
+            let mut count = [0; 8];
+            match std::io::Read::read(&mut self.interrupt, &mut count) {
+                Ok(_) => u64::from_le_bytes(count),
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => 0,
+                Err(error) => panic!("Failed to read eventfd: {error}"),
+            }
+        }
+    }
+
+    impl Drop for TestGuest {
+        fn drop(&mut self) {
+            // Closing the connection stops the server.
+            self.client.shutdown().unwrap();
+            let server = self.server.take().unwrap();
+            if !thread::panicking() {
+                server.join().unwrap();
+            }
+            let _ = std::fs::remove_file(&self.socket_path);
+        }
+    }
+
+    /// Split an event TRB into its type, Completion Code, and Slot ID.
+    fn event_fields(trb: &[u8]) -> (u8, u8, u8) {
+        (trb[13] >> 2, trb[11], trb[15])
+    }
+
+    #[test]
+    fn guest_reads_device_descriptor_over_vfio_user() {
+        let mut guest = TestGuest::connect();
+
+        // The regions and interrupts the server announced.
+        let config = guest.client.region(VFIO_PCI_CONFIG_REGION_INDEX).unwrap();
+        assert_eq!(config.size, 256);
+        let bar0 = guest.client.region(VFIO_PCI_BAR0_REGION_INDEX).unwrap();
+        assert!(bar0.size > RUN_BASE);
+        let msix = guest.client.get_irq_info(VFIO_PCI_MSIX_IRQ_INDEX).unwrap();
+        assert_eq!(msix.count, 1);
+
+        let mut vendor_id = [0; 2];
+        guest
+            .client
+            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, 0, &mut vendor_id)
+            .unwrap();
+        assert_eq!(u16::from_le_bytes(vendor_id), vendor::REDHAT);
+
+        let mut caplength = [0; 1];
+        guest
+            .client
+            .region_read(
+                VFIO_PCI_BAR0_REGION_INDEX,
+                offset::CAPLENGTH,
+                &mut caplength,
+            )
+            .unwrap();
+        assert_eq!(u64::from(caplength[0]), OP_BASE);
+        assert_eq!(
+            u64::from(guest.read_bar0(offset::DBOFF)),
+            offset::DOORBELL_CONTROLLER
+        );
+        assert_eq!(u64::from(guest.read_bar0(offset::RTSOFF)), RUN_BASE);
+
+        guest.start_controller();
+        let port_id = guest.connected_port();
+        guest.address_device(port_id);
+
+        // GET_DESCRIPTOR(Device) on the Default Control Endpoint
+        let descriptor = guest.control_in(0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00], 18);
+
+        assert_eq!(descriptor, DEVICE_DESCRIPTOR);
+        let events = guest.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            event_fields(&events[2]),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(events[2][0..8], (CONTROL_RING + 0x20).to_le_bytes());
+        // Endpoint ID 1 is the Default Control Endpoint.
+        assert_eq!(events[2][14] & 0x1f, 1);
+
+        assert!(guest.interrupts() > 0);
+    }
+
+    #[test]
+    fn recorded_session_replays() {
+        let buffer = SharedBuffer::default();
+        let recorder = Arc::new(TraceRecorder::new(buffer.clone()));
+        let mock_device = || {
+            let (mut device, _calls) = MockUsbDevice::new();
+            device.control_in_data = DEVICE_DESCRIPTOR.to_vec();
+            Box::new(device) as Box<dyn RealDevice>
         };
+        {
+            let mut guest = TestGuest::connect_recording(mock_device, Some(recorder));
+            guest.start_controller();
+            let port_id = guest.connected_port();
+            guest.address_device(port_id);
+            guest.control_in(0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00], 18);
+            assert_eq!(guest.events().len(), 3);
+        }
 
-        self.controller.lock().unwrap().connect_irq(irq);
+        let replay = replay::replay(&buffer.text(), vec![mock_device()]);
+        replay.assert_same_events();
+        assert!(replay::event_trbs(&replay.recorded).len() >= 3);
+    }
 
-        Ok(())
+    #[test]
+    fn set_address_is_answered_by_the_controller() {
+        let (mut device, calls) = MockUsbDevice::new();
+        device.control_in_data = vec![0x01, 0x00];
+        let mut guest = TestGuest::connect_with(move || Box::new(device));
+        guest.start_controller();
+        let port_id = guest.connected_port();
+        guest.address_device(port_id);
+
+        // GET_STATUS(Device) is for the device to answer.
+        let status = guest.control_in(0, [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 2, 0x00], 2);
+        assert_eq!(status, [0x01, 0x00]);
+        assert_eq!(*calls.lock().unwrap(), [MockCall::ControlTransfer(0x00)]);
+
+        // SET_ADDRESS(5) completes, but never reaches the device.
+        guest.control_no_data(1, [0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let events = guest.events();
+        assert_eq!(
+            event_fields(&events[3]),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(events[3][0..8], (CONTROL_RING + 0x40).to_le_bytes());
+        assert_eq!(*calls.lock().unwrap(), [MockCall::ControlTransfer(0x00)]);
+    }
+
+    #[test]
+    fn inconsistent_control_requests_complete() {
+        let (mut device, calls) = MockUsbDevice::new();
+        device.control_in_data = DEVICE_DESCRIPTOR.to_vec();
+        let mut guest = TestGuest::connect_with(move || Box::new(device));
+        guest.start_controller();
+        let port_id = guest.connected_port();
+        guest.address_device(port_id);
+
+        // A Data Stage of a request without data is skipped.
+        let data = guest.control_in(0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0, 0x00], 18);
+        assert_eq!(data, [0; 18]);
+        let events = guest.events();
+        assert_eq!(
+            event_fields(&events[2]),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(events[2][0..8], (CONTROL_RING + 0x20).to_le_bytes());
+
+        // A Data Stage larger than the request only receives wLength bytes.
+        let data = guest.control_in(1, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 8, 0x00], 18);
+        assert_eq!(data[..8], DEVICE_DESCRIPTOR[..8]);
+        assert_eq!(data[8..], [0; 10]);
+        assert_eq!(
+            event_fields(&guest.events()[3]),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+
+        // GET_DESCRIPTOR(Device) for 18 bytes without a Data Stage stalls
+        // without reaching the device.
+        guest.control_no_data(2, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00]);
+        let events = guest.events();
+        assert_eq!(
+            event_fields(&events[4]),
+            (
+                trb_types::TRANSFER_EVENT,
+                CompletionCode::StallError as u8,
+                1
+            )
+        );
+        assert_eq!(events[4][0..8], (CONTROL_RING + 0x70).to_le_bytes());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                MockCall::ControlTransfer(0x06),
+                MockCall::ControlTransfer(0x06)
+            ]
+        );
+    }
+
+    #[test]
+    fn guest_enumerates_virtual_mouse() {
+        let mut guest = TestGuest::connect_with(|| VirtualDeviceKind::Mouse.create());
+        guest.start_controller();
+        let port_id = guest.connected_port();
+        // Full Speed devices go to the USB2 ports.
+        assert!(port_id > NUM_USB3_PORTS);
+        guest.address_device(port_id);
+
+        // GET_DESCRIPTOR(Device): a device with its class per interface.
+        let device = guest.control_in(0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00], 18);
+        assert_eq!(device[0..2], [18, 1]);
+        assert_eq!(device[4], 0);
+
+        // GET_DESCRIPTOR(Configuration): a boot mouse with EP1 IN.
+        let configuration = guest.control_in(1, [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 34, 0x00], 34);
+        assert_eq!(u16::from_le_bytes([configuration[2], configuration[3]]), 34);
+        // bInterfaceClass, bInterfaceSubClass, bInterfaceProtocol
+        assert_eq!(configuration[9 + 5..9 + 8], [0x03, 0x01, 0x02]);
+        // bEndpointAddress and bmAttributes
+        assert_eq!(configuration[27 + 2..27 + 4], [0x81, 0x03]);
+
+        // Configure Endpoint for EP1 IN (Endpoint ID 3) as Interrupt IN
+        // with a max packet size of 4 and its Transfer Ring at
+        // INTERRUPT_RING.
+        guest.write_ram(INPUT_CONTEXT, &[0; 0x100]);
+        guest.write_ram(INPUT_CONTEXT + 4, &0b1001u32.to_le_bytes());
+        guest.write_ram(INPUT_CONTEXT + 0x20 + 3, &[3 << 3]);
+        guest.write_ram(INPUT_CONTEXT + 0x20 + 6, &[port_id as u8]);
+        guest.write_ram(INPUT_CONTEXT + 0x80 + 2, &[3]);
+        guest.write_ram(INPUT_CONTEXT + 0x80 + 4, &[0x3e]);
+        guest.write_ram(INPUT_CONTEXT + 0x80 + 6, &4u16.to_le_bytes());
+        guest.write_ram(
+            INPUT_CONTEXT + 0x80 + 8,
+            &(INTERRUPT_RING | 1).to_le_bytes(),
+        );
+        guest.write_trb(
+            COMMAND_RING + 0x20,
+            INPUT_CONTEXT,
+            0,
+            u32::from(trb_types::CONFIGURE_ENDPOINT_COMMAND) << 10 | 1 << 24,
+        );
+        guest.write_bar0(offset::DOORBELL_CONTROLLER, 0);
+        assert_eq!(
+            event_fields(&guest.events()[4]),
+            (
+                trb_types::COMMAND_COMPLETION_EVENT,
+                CompletionCode::Success as u8,
+                1
+            )
+        );
+
+        // Poll for an input report.
+        guest.write_trb(
+            INTERRUPT_RING,
+            REPORT_BUFFER,
+            4,
+            u32::from(trb_types::NORMAL) << 10 | 1 << 5,
+        );
+        guest.write_bar0(offset::DOORBELL_DEVICE, 3);
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while guest.events().len() < 6 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "no input report arrived"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        let events = guest.events();
+        assert_eq!(
+            event_fields(&events[5]),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(events[5][14] & 0x1f, 3);
+        // The pointer starts moving to the right.
+        assert_eq!(guest.read_ram(REPORT_BUFFER, 4), [0, 4, 0, 0]);
+    }
+
+    #[test]
+    fn guest_configures_device_without_endpoints() {
+        // A billboard device: a single interface without endpoints.
+        const CONFIGURATION: [u8; 18] = [
+            0x09, 0x02, 0x12, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, 0x09, 0x04, 0x00, 0x00, 0x00,
+            0x11, 0x00, 0x00, 0x00,
+        ];
+        let (mut device, calls) = MockUsbDevice::new();
+        device.control_in_data = CONFIGURATION.to_vec();
+        let mut guest = TestGuest::connect_with(move || Box::new(device));
+        guest.start_controller();
+        let port_id = guest.connected_port();
+        guest.address_device(port_id);
+
+        // GET_DESCRIPTOR(Configuration)
+        let configuration = guest.control_in(0, [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 18, 0x00], 18);
+        assert_eq!(configuration, CONFIGURATION);
+
+        // Configure Endpoint with only the Slot Context (A0).
+        guest.write_ram(INPUT_CONTEXT, &[0; 0x100]);
+        guest.write_ram(INPUT_CONTEXT + 4, &0b1u32.to_le_bytes());
+        guest.write_ram(INPUT_CONTEXT + 0x20 + 3, &[1 << 3]);
+        guest.write_ram(INPUT_CONTEXT + 0x20 + 6, &[port_id as u8]);
+        guest.write_trb(
+            COMMAND_RING + 0x20,
+            INPUT_CONTEXT,
+            0,
+            u32::from(trb_types::CONFIGURE_ENDPOINT_COMMAND) << 10 | 1 << 24,
+        );
+        guest.write_bar0(offset::DOORBELL_CONTROLLER, 0);
+        assert_eq!(
+            event_fields(&guest.events()[3]),
+            (
+                trb_types::COMMAND_COMPLETION_EVENT,
+                CompletionCode::Success as u8,
+                1
+            )
+        );
+        assert_eq!(
+            guest.read_ram(DEVICE_CONTEXT + 15, 1)[0] >> 3,
+            slot_state::CONFIGURED
+        );
+
+        // A stray doorbell for EP1 IN fails instead of reaching the device.
+        guest.write_bar0(offset::DOORBELL_DEVICE, 3);
+        let events = guest.events();
+        assert_eq!(
+            event_fields(&events[4]),
+            (
+                trb_types::TRANSFER_EVENT,
+                CompletionCode::EndpointNotEnabledError as u8,
+                1
+            )
+        );
+        assert_eq!(events[4][14] & 0x1f, 3);
+
+        // SET_CONFIGURATION(1) still works on the Default Control Endpoint.
+        guest.control_no_data(1, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            event_fields(&guest.events()[5]),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                MockCall::ControlTransfer(0x06),
+                MockCall::ControlTransfer(0x09)
+            ]
+        );
+    }
+
+    #[test]
+    fn transfer_events_name_endpoints_by_dci() {
+        let (device, _calls) = MockUsbDevice::new();
+        let mut guest = TestGuest::connect_with(move || Box::new(device));
+        guest.start_controller();
+        let port_id = guest.connected_port();
+        guest.address_device(port_id);
+
+        guest.configure_bulk_endpoints(port_id);
+
+        // One TD per endpoint. The doorbell target is the DCI as well.
+        for (ring, target) in [(BULK_OUT_RING, 2), (BULK_IN_RING, 5)] {
+            guest.write_trb(
+                ring,
+                BULK_BUFFER,
+                512,
+                u32::from(trb_types::NORMAL) << 10 | 1 << 5,
+            );
+            guest.write_bar0(offset::DOORBELL_DEVICE, target);
+        }
+
+        let events = guest.events();
+        let transfer_events = &events[events.len() - 2..];
+        for (event, dci) in transfer_events.iter().zip([2, 5]) {
+            assert_eq!(
+                event_fields(event),
+                (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+            );
+            assert_eq!(event[14] & 0x1f, dci);
+        }
+    }
+
+    #[test]
+    fn clear_feature_endpoint_halt_keeps_the_worker_running() {
+        let (device, calls) = MockUsbDevice::new();
+        let mut guest = TestGuest::connect_with(move || Box::new(device));
+        guest.start_controller();
+        let port_id = guest.connected_port();
+        guest.address_device(port_id);
+        guest.configure_bulk_endpoints(port_id);
+
+        // The class driver recovers EP2 IN from a stall.
+        guest.control_no_data(0, [0x02, 0x01, 0x00, 0x00, 0x82, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            event_fields(guest.events().last().unwrap()),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(*calls.lock().unwrap(), [MockCall::ClearHalt(5)]);
+
+        // The endpoint transfers again.
+        guest.write_trb(
+            BULK_IN_RING,
+            BULK_BUFFER,
+            512,
+            u32::from(trb_types::NORMAL) << 10 | 1 << 5,
+        );
+        guest.write_bar0(offset::DOORBELL_DEVICE, 5);
+        let event = guest.events().last().unwrap().clone();
+        assert_eq!(
+            event_fields(&event),
+            (trb_types::TRANSFER_EVENT, CompletionCode::Success as u8, 1)
+        );
+        assert_eq!(event[14] & 0x1f, 5);
     }
 }